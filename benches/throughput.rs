@@ -0,0 +1,171 @@
+// Throughput and latency of the copy loops, on their own and through a loopback
+// relay + connector + echo server
+use chacha20::{cipher::KeyIvInit, ChaCha20};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::net::SocketAddr;
+use tokio::{
+    io::{duplex, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    runtime::Runtime,
+    task,
+};
+use veloxid::{
+    protocol::encryption::generate_secret_from_string,
+    relay::{
+        copier::CipherCopier,
+        tunnel::{SessionOptions, Tunnel},
+    },
+};
+
+const PAYLOAD: usize = 4 * 1024 * 1024;
+const BUFFER_SIZES: [usize; 4] = [1024, 8192, 32 * 1024, 128 * 1024];
+const ECHO_SIZES: [usize; 3] = [1, 1024, 16 * 1024];
+
+fn ciphers(count: usize) -> Vec<ChaCha20> {
+    (0..count)
+        .map(|_| ChaCha20::new(&[7u8; 32].into(), &[3u8; 12].into()))
+        .collect()
+}
+
+// A single direction over an in-memory pipe, by buffer size and amount of ciphers
+// (0 for plain proxies, 1 for relays, 2 for joined tunnels)
+fn copier(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let data = vec![0x5au8; PAYLOAD];
+
+    let mut group = c.benchmark_group("copier");
+    group.throughput(Throughput::Bytes(PAYLOAD as u64));
+    for cipher_count in 0..=2 {
+        for buffer_size in BUFFER_SIZES {
+            let id = BenchmarkId::new(format!("{}_ciphers", cipher_count), buffer_size);
+            group.bench_with_input(id, &buffer_size, |b, &buffer_size| {
+                b.to_async(&runtime).iter(|| async {
+                    let (mut input, reader) = duplex(buffer_size);
+                    let (writer, mut output) = duplex(buffer_size);
+                    let copy = task::spawn(
+                        CipherCopier::with_buffer_size(ciphers(cipher_count), buffer_size)
+                            .copy(reader, writer),
+                    );
+                    let feed = task::spawn({
+                        let data = data.clone();
+                        async move {
+                            input.write_all(&data).await.unwrap();
+                            input.shutdown().await.unwrap();
+                        }
+                    });
+                    let mut sink = Vec::with_capacity(PAYLOAD);
+                    output.read_to_end(&mut sink).await.unwrap();
+                    feed.await.unwrap();
+                    copy.await.unwrap().unwrap();
+                });
+            });
+        }
+    }
+    group.finish();
+}
+
+// Relay + connector + echo server on loopback, each client is one session
+async fn loopback() -> SocketAddr {
+    let secret = generate_secret_from_string("bench".to_owned());
+    let client_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let tunnel_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let echo_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client_addr = client_listener.local_addr().unwrap();
+    let tunnel_addr = tunnel_listener.local_addr().unwrap();
+    let echo_addr = echo_listener.local_addr().unwrap();
+
+    // Echo server
+    task::spawn(async move {
+        while let Ok((mut stream, _)) = echo_listener.accept().await {
+            task::spawn(async move {
+                let (mut read, mut write) = stream.split();
+                let _ = tokio::io::copy(&mut read, &mut write).await;
+            });
+        }
+    });
+
+    // Relay: clients are attached to the next authenticated tunnel
+    task::spawn(async move {
+        while let Ok((client, _)) = client_listener.accept().await {
+            let (stream, peer) = tunnel_listener.accept().await.unwrap();
+            task::spawn(async move {
+                let tunnel = Tunnel::init(stream, peer.ip(), true, secret).await?;
+                tunnel.run(client, SessionOptions::default()).await
+            });
+        }
+    });
+
+    // Connector: keeps one tunnel waiting, connecting it to the echo server once attached
+    task::spawn(async move {
+        loop {
+            let stream = TcpStream::connect(tunnel_addr).await.unwrap();
+            // Idle tunnels time out until a client shows up, like the real connector
+            let Ok(tunnel) = Tunnel::init(stream, tunnel_addr.ip(), false, secret).await else {
+                continue;
+            };
+            task::spawn(async move {
+                let target = TcpStream::connect(echo_addr).await?;
+                tunnel.run(target, SessionOptions::default()).await
+            });
+        }
+    });
+
+    client_addr
+}
+
+// Bulk transfer through a session, the payload is echoed back
+fn session_throughput(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let addr = runtime.block_on(loopback());
+    let data = vec![0x5au8; PAYLOAD];
+
+    let mut group = c.benchmark_group("session");
+    group.throughput(Throughput::Bytes(PAYLOAD as u64 * 2));
+    group.sample_size(20);
+    group.bench_function("echo", |b| {
+        b.to_async(&runtime).iter(|| async {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let (mut read, mut write) = stream.into_split();
+            let feed = task::spawn({
+                let data = data.clone();
+                async move {
+                    write.write_all(&data).await.unwrap();
+                    write.shutdown().await.unwrap();
+                }
+            });
+            let mut sink = Vec::with_capacity(PAYLOAD);
+            read.read_to_end(&mut sink).await.unwrap();
+            feed.await.unwrap();
+            assert_eq!(sink.len(), PAYLOAD);
+        });
+    });
+    group.finish();
+}
+
+// Round trips of small messages through an established session
+fn session_latency(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut stream = runtime.block_on(async {
+        let stream = TcpStream::connect(loopback().await).await.unwrap();
+        stream.set_nodelay(true).unwrap();
+        stream
+    });
+
+    let mut group = c.benchmark_group("round_trip");
+    for size in ECHO_SIZES {
+        let message = vec![0x5au8; size];
+        let mut reply = vec![0u8; size];
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, _| {
+            b.iter(|| {
+                runtime.block_on(async {
+                    stream.write_all(&message).await.unwrap();
+                    stream.read_exact(&mut reply).await.unwrap();
+                })
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, copier, session_throughput, session_latency);
+criterion_main!(benches);
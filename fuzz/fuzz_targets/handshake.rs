@@ -0,0 +1,59 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use std::net::{IpAddr, Ipv4Addr};
+use tokio::io::{duplex, AsyncWriteExt};
+use veloxid::{
+    protocol::handshake::{InboundHandshake, OutboundEvent, OutboundHandshake},
+    relay::tunnel::Tunnel,
+};
+
+const SECRET: [u8; 32] = [0x42; 32];
+const NONCE: [u8; 12] = [0x24; 12];
+
+fuzz_target!(|data: &[u8]| {
+    // Inbound parser, byte by byte
+    let mut inbound = InboundHandshake::new(SECRET, NONCE);
+    let mut decided = None;
+    for byte in data {
+        let before = inbound.remaining();
+        let event = inbound.push(*byte);
+        match decided {
+            Some(previous) => assert_eq!(event, Some(previous)),
+            None => {
+                assert!(event.is_some() || inbound.remaining() == before - 1);
+                decided = event;
+            }
+        }
+    }
+
+    // Outbound parser, byte by byte
+    let mut outbound = OutboundHandshake::new(SECRET);
+    let mut sent_auth = false;
+    for byte in data {
+        match outbound.push(*byte) {
+            Some(OutboundEvent::SendAuth { .. }) => {
+                assert!(!sent_auth);
+                sent_auth = true;
+            }
+            Some(OutboundEvent::Ping) => assert!(sent_auth && outbound.remaining() > 0),
+            Some(_) => assert!(sent_auth && outbound.remaining() == 0),
+            None => assert!(!sent_auth),
+        }
+    }
+
+    // The real inbound side of Tunnel::init over an in-memory pipe, the peer sends
+    // the input and hangs up so it must always finish without hitting the timeouts
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(async {
+            let (stream, mut peer) = duplex(1024);
+            let peer_ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+            let init = tokio::spawn(Tunnel::init(stream, peer_ip, true, SECRET));
+            let _ = peer.write_all(data).await;
+            drop(peer);
+            let _ = init.await.unwrap();
+        });
+});
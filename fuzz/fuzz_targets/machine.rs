@@ -0,0 +1,70 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use std::time::Duration;
+use veloxid::protocol::{
+    machine::{
+        AcceptEvent, ConnectEvent, InboundMachine, InboundOptions, OutboundMachine,
+        OutboundOptions,
+    },
+    ticket::Tickets,
+};
+
+const SECRET: [u8; 32] = [0x42; 32];
+const NONCE: [u8; 12] = [0x24; 12];
+
+fuzz_target!(|data: &[u8]| {
+    // The first byte splits the input into chunks, the machines must take the same bytes
+    // whatever the chunks
+    let Some((chunk, data)) = data.split_first() else {
+        return;
+    };
+    let chunk = *chunk as usize % 16 + 1;
+
+    // Inbound side with every option on
+    let options = || InboundOptions {
+        cipher: 0,
+        tickets: Some(Tickets::new(&SECRET, Duration::from_secs(60))),
+        expect_service: true,
+        silence: None,
+        totp: None,
+    };
+    let mut inbound = InboundMachine::new(SECRET, NONCE, options());
+    let (mut taken, mut finished) = (0, false);
+    for bytes in data.chunks(chunk) {
+        let n = inbound.handle_input(bytes);
+        assert!(n <= bytes.len());
+        assert!(finished || n == bytes.len() || inbound.wants() == 0);
+        taken += n;
+        while let Some(event) = inbound.poll_event() {
+            assert!(!finished);
+            finished = !matches!(event, AcceptEvent::Authenticated { .. });
+        }
+        while inbound.poll_transmit().is_some() {}
+    }
+    assert_eq!(finished, inbound.wants() == 0);
+    let mut whole = InboundMachine::new(SECRET, NONCE, options());
+    assert_eq!(whole.handle_input(data), taken);
+
+    // Outbound side, an announcement included
+    let options = OutboundOptions {
+        announce: Some("fuzz".to_string()),
+        ..Default::default()
+    };
+    let mut outbound = OutboundMachine::new(SECRET, options);
+    let mut finished = false;
+    for bytes in data.chunks(chunk) {
+        let n = outbound.handle_input(bytes);
+        assert!(n == bytes.len() || outbound.wants() == 0);
+        while let Some(event) = outbound.poll_event() {
+            assert!(!finished);
+            assert!(matches!(
+                event,
+                ConnectEvent::Accepted(_) | ConnectEvent::Rejected { .. }
+            ));
+            finished = true;
+        }
+        while outbound.poll_transmit().is_some() {}
+    }
+    assert_eq!(finished, outbound.wants() == 0);
+});
@@ -0,0 +1,100 @@
+use log::warn;
+use std::{
+    net::{SocketAddr, TcpListener},
+    sync::{Mutex, OnceLock},
+};
+
+// Listening sockets passed by systemd socket activation (LISTEN_FDS), taken by the inbound
+// endpoints of their address instead of binding their own. The relay can then be started
+// on the first connection, and listen on privileged ports without running as root.
+pub struct Activated {
+    sockets: Mutex<Vec<(SocketAddr, TcpListener)>>,
+}
+
+impl Activated {
+    pub fn new(sockets: Vec<TcpListener>) -> Self {
+        let sockets = sockets
+            .into_iter()
+            .filter_map(|socket| Some((socket.local_addr().ok()?, socket)))
+            .collect();
+        Self {
+            sockets: Mutex::new(sockets),
+        }
+    }
+
+    // The sockets passed to this process, none if it wasn't socket activated
+    pub fn from_env() -> Self {
+        Self::new(passed())
+    }
+
+    // The socket bound to addr. Endpoints on every address take the one of their port,
+    // systemd binds "ListenStream=443" to all of them.
+    pub fn take(&self, addr: SocketAddr) -> Option<TcpListener> {
+        let mut sockets = self.sockets.lock().unwrap();
+        let idx = sockets.iter().position(|(bound, _)| {
+            bound.port() == addr.port() && (bound.ip() == addr.ip() || addr.ip().is_unspecified())
+        })?;
+        Some(sockets.remove(idx).1)
+    }
+
+    // Addresses of the sockets no endpoint took
+    pub fn remaining(&self) -> Vec<SocketAddr> {
+        let sockets = self.sockets.lock().unwrap();
+        sockets.iter().map(|(addr, _)| *addr).collect()
+    }
+}
+
+// Read from the environment once, the first time they're asked for
+pub fn sockets() -> &'static Activated {
+    static SOCKETS: OnceLock<Activated> = OnceLock::new();
+    SOCKETS.get_or_init(Activated::from_env)
+}
+
+// Descriptors from 3 on, see sd_listen_fds(3). Only TCP sockets are taken, they're closed
+// on exec so children don't inherit them.
+#[cfg(target_os = "linux")]
+fn passed() -> Vec<TcpListener> {
+    use socket2::{Socket, Type};
+    use std::os::fd::{FromRawFd, RawFd};
+
+    const LISTEN_FDS_START: RawFd = 3;
+
+    // Meant for another process, a parent that didn't clear them
+    let pid = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse().ok());
+    if pid != Some(std::process::id()) {
+        return Vec::new();
+    }
+    let count: RawFd = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(0);
+
+    let mut sockets = Vec::new();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        // SAFETY: systemd hands the descriptors over to this process, nothing else owns them
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+        let tcp = socket.r#type().ok() == Some(Type::STREAM)
+            && socket
+                .local_addr()
+                .is_ok_and(|addr| addr.as_socket().is_some());
+        if !tcp {
+            warn!(
+                "Passed descriptor {} isn't a TCP socket, leaving it alone",
+                fd
+            );
+            std::mem::forget(socket);
+            continue;
+        }
+        // SAFETY: fcntl on a descriptor owned by socket
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        sockets.push(socket.into());
+    }
+    sockets
+}
+
+#[cfg(not(target_os = "linux"))]
+fn passed() -> Vec<TcpListener> {
+    Vec::new()
+}
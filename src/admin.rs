@@ -0,0 +1,478 @@
+use crate::{
+    budget::Budget,
+    latency::LatencyTable,
+    relay::connection::ConnectionData,
+    scaling::Scaling,
+    services::ServiceTable,
+    sessions::SessionRegistry,
+    stun::NatTable,
+    transport::listener::{self, Listener},
+};
+use anyhow::{anyhow, Context, Result};
+use dashmap::DashMap;
+use log::{error, info};
+use std::{
+    collections::HashMap, io, net::IpAddr, os::unix::fs::FileTypeExt, sync::Arc, time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::watch,
+    task,
+    time::Instant,
+};
+
+const LOG_TARGET: &str = "admin";
+
+// Runtime handle of a route
+pub struct RouteControl {
+    // Name of the route, "route #<idx>" if it has none
+    pub name: String,
+    pub endpoints: [String; 2],
+    pub enabled: watch::Sender<bool>,
+    // Workers of the route, resized on reload
+    pub scaling: Scaling,
+}
+
+// Everything the admin socket can act on
+pub struct AdminState {
+    pub routes: Vec<RouteControl>,
+    pub endpoints: HashMap<String, ConnectionData>,
+    pub latency: LatencyTable,
+    // Registry endpoints by name
+    pub services: HashMap<String, ServiceTable>,
+    pub registry: SessionRegistry,
+    // Set by [limits]
+    pub budget: Option<Budget>,
+    // Connectors' public addresses on a relay, the connector's own on a connector
+    pub nat: NatTable,
+}
+
+impl AdminState {
+    // Listeners of the inbound endpoints, by endpoint name
+    pub fn listeners(&self) -> Vec<(&str, &Listener)> {
+        let mut listeners: Vec<_> = self
+            .endpoints
+            .iter()
+            .filter_map(|(name, data)| match data {
+                ConnectionData::Inbound { listener, .. } => Some((name.as_str(), &**listener)),
+                _ => None,
+            })
+            .collect();
+        listeners.sort_by_key(|(name, _)| *name);
+        listeners
+    }
+}
+
+pub fn bind(path: &str) -> io::Result<UnixListener> {
+    // Remove a stale socket from a previous run, nothing else: another instance's socket
+    // still takes connections
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            match std::os::unix::net::UnixStream::connect(path) {
+                Ok(_) => {
+                    let reason = format!("'{}' is the socket of a running instance", path);
+                    return Err(io::Error::new(io::ErrorKind::AddrInUse, reason));
+                }
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                    std::fs::remove_file(path)?
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(_) => {
+            let reason = format!("'{}' exists and isn't a socket", path);
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, reason));
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let listener = UnixListener::bind(path)?;
+    info!(target: LOG_TARGET, "Listening on '{}'", path);
+    Ok(listener)
+}
+
+// Serve the admin socket, one command per line:
+// routes                      -> list routes and their state
+// disable <route> [unbind]    -> stop accepting new sessions, optionally close the listeners
+// enable <route>              -> accept again, rebinding closed listeners
+// latency                     -> round trips of the inbound tunnels, last/smoothed/min
+// services                    -> services of the registry endpoints, tunnels waiting for each
+// clients                     -> endpoints limiting connections per client: the limit, clients
+//                                connected and connections turned away
+// sessions                    -> running sessions: id, peers, seconds running, bytes A->B
+//                                and B->A, and the worker running it
+// kill <id>                   -> end a session
+// listeners                   -> inbound endpoints' listeners: address, bound or not and the
+//                                accept errors so far, transient, out of resources and others
+// limits                      -> the [limits] budget: sessions running and the most allowed,
+//                                file descriptors open and the most allowed, times accepting
+//                                paused
+// nat                         -> public addresses learned over STUN, by connector ("self" for
+//                                this one): kind of NAT, local address, the addresses the
+//                                servers saw and seconds since the report
+// Routes are given by name or by index
+// Every reply ends with a line of "OK" or "ERR <reason>"
+pub async fn serve(listener: UnixListener, state: Arc<AdminState>) -> Result<()> {
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                listener::recover(e).await?;
+                continue;
+            }
+        };
+        task::spawn({
+            let state = state.clone();
+            async move {
+                if let Err(e) = handle_client(stream, &state).await {
+                    error!(target: LOG_TARGET, "Client failed: {}", e);
+                }
+            }
+        });
+    }
+}
+
+async fn handle_client(stream: UnixStream, state: &AdminState) -> Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let reply = match execute(state, line.trim()).await {
+            Ok(output) => format!("{}OK\n", output),
+            Err(e) => format!("ERR {}\n", e),
+        };
+        write.write_all(reply.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+pub async fn execute(state: &AdminState, command: &str) -> Result<String> {
+    let args: Vec<&str> = command.split_whitespace().collect();
+    match args.as_slice() {
+        ["routes"] => Ok(state
+            .routes
+            .iter()
+            .enumerate()
+            .map(|(idx, route)| {
+                format!(
+                    "{} {} {} {} -> {}\n",
+                    idx,
+                    route.name,
+                    match *route.enabled.borrow() {
+                        true => "enabled",
+                        false => "disabled",
+                    },
+                    route.endpoints[0],
+                    route.endpoints[1]
+                )
+            })
+            .collect()),
+        ["latency"] => Ok(latency(state)),
+        ["services"] => Ok(services(state)),
+        ["clients"] => Ok(clients(state)),
+        ["sessions"] => Ok(sessions(state)),
+        ["kill", id] => kill(state, id),
+        ["listeners"] => Ok(listeners(state)),
+        ["limits"] => limits(state),
+        ["nat"] => Ok(nat(state)),
+        ["disable", route] => disable(state, route, false),
+        ["disable", route, "unbind"] => disable(state, route, true),
+        ["enable", route] => enable(state, route).await,
+        _ => Err(anyhow!("Unknown command '{}'", command)),
+    }
+}
+
+fn latency(state: &AdminState) -> String {
+    let millis = |rtt: Option<Duration>| match rtt {
+        Some(rtt) => format!("{:.1}ms", rtt.as_secs_f64() * 1000.0),
+        None => "-".to_owned(),
+    };
+    state
+        .latency
+        .list()
+        .iter()
+        .map(|tunnel| {
+            format!(
+                "{} {} {} {}/{}/{} {}\n",
+                tunnel.route,
+                tunnel.endpoint,
+                tunnel.peer.map_or("-".to_owned(), |p| p.to_string()),
+                millis(tunnel.last),
+                millis(tunnel.smoothed),
+                millis(tunnel.min),
+                tunnel.samples
+            )
+        })
+        .collect()
+}
+
+fn services(state: &AdminState) -> String {
+    let mut endpoints: Vec<_> = state.services.iter().collect();
+    endpoints.sort_by_key(|(name, _)| *name);
+    endpoints
+        .into_iter()
+        .flat_map(|(endpoint, table)| {
+            table
+                .list()
+                .into_iter()
+                .map(move |(service, queued)| format!("{} {} {}\n", endpoint, service, queued))
+        })
+        .collect()
+}
+
+fn clients(state: &AdminState) -> String {
+    let mut endpoints: Vec<_> = state
+        .endpoints
+        .iter()
+        .filter_map(|(name, data)| match data {
+            ConnectionData::Inbound {
+                clients: Some(limit),
+                ..
+            } => Some((name, limit)),
+            _ => None,
+        })
+        .collect();
+    endpoints.sort_by_key(|(name, _)| *name);
+    endpoints
+        .into_iter()
+        .map(|(name, limit)| {
+            format!(
+                "{} {} {} {}\n",
+                name,
+                limit.max(),
+                limit.clients(),
+                limit.rejected()
+            )
+        })
+        .collect()
+}
+
+fn listeners(state: &AdminState) -> String {
+    state
+        .listeners()
+        .into_iter()
+        .map(|(name, listener)| {
+            let errors = listener.errors();
+            format!(
+                "{} {} {} {} {} {}\n",
+                name,
+                listener.addr(),
+                match listener.is_bound() {
+                    true => "bound",
+                    false => "unbound",
+                },
+                errors.transient,
+                errors.exhausted,
+                errors.failed
+            )
+        })
+        .collect()
+}
+
+fn limits(state: &AdminState) -> Result<String> {
+    let Some(budget) = &state.budget else {
+        return Err(anyhow!("No [limits] are set"));
+    };
+    let stats = budget.stats();
+    let or_none = |n: Option<usize>| n.map_or("-".to_owned(), |n| n.to_string());
+    let (open, limit) = stats.descriptors.unzip();
+    Ok(format!(
+        "sessions {} {}\ndescriptors {} {}\npauses {}\n",
+        stats.sessions,
+        or_none(stats.max_sessions),
+        or_none(open),
+        or_none(limit),
+        stats.pauses
+    ))
+}
+
+fn nat(state: &AdminState) -> String {
+    state
+        .nat
+        .list()
+        .into_iter()
+        .map(|(source, report, age)| {
+            let mapped: Vec<String> = report.mapped.iter().map(ToString::to_string).collect();
+            format!(
+                "{} {} {} {} {}\n",
+                source,
+                report.kind.as_str(),
+                report.local,
+                mapped.join(","),
+                age.as_secs()
+            )
+        })
+        .collect()
+}
+
+fn sessions(state: &AdminState) -> String {
+    let peer = |addr: Option<_>| addr.map_or("-".to_owned(), |addr| format!("{}", addr));
+    state
+        .registry
+        .snapshot()
+        .into_iter()
+        .map(|s| {
+            format!(
+                "{} {} {} {} {} {} {}\n",
+                s.info.id,
+                peer(s.info.peer_a),
+                peer(s.info.peer_b),
+                s.running().as_secs(),
+                s.traffic.a_to_b,
+                s.traffic.b_to_a,
+                s.info.route
+            )
+        })
+        .collect()
+}
+
+// Snapshot of the relay for people rather than scripts, logged on SIGUSR1: the sessions and
+// bytes since the start, each route with its sessions and workers, the listeners and the bans
+pub fn statistics(state: &AdminState, ban_list: &DashMap<IpAddr, Instant>) -> String {
+    let (total, traffic) = state.registry.totals();
+    let mut output = format!(
+        "Sessions: {} running, {} since the start
+Traffic: {} bytes A->B, {} bytes B->A since the start
+",
+        state.registry.len(),
+        total,
+        traffic.a_to_b,
+        traffic.b_to_a
+    );
+
+    for route in &state.routes {
+        let scaling = &route.scaling;
+        let workers = match scaling.size() {
+            (min, max) if min < max => format!(
+                "{} workers ({} busy, autoscaled between {} and {})",
+                scaling.workers(),
+                scaling.busy(),
+                min,
+                max
+            ),
+            _ => format!("{} workers ({} busy)", scaling.workers(), scaling.busy()),
+        };
+        output.push_str(&format!(
+            "Route '{}' ({} -> {}): {}, {} sessions, {}
+",
+            route.name,
+            route.endpoints[0],
+            route.endpoints[1],
+            match *route.enabled.borrow() {
+                true => "enabled",
+                false => "disabled",
+            },
+            state.registry.route_sessions(&route.name),
+            workers
+        ));
+    }
+
+    for (name, listener) in state.listeners() {
+        let errors = listener.errors();
+        output.push_str(&format!(
+            "Listener '{}' on {}: {}, {} accept errors
+",
+            name,
+            listener.addr(),
+            match listener.is_bound() {
+                true => "bound",
+                false => "unbound",
+            },
+            errors.transient + errors.exhausted + errors.failed
+        ));
+    }
+
+    let now = Instant::now();
+    let mut bans: Vec<_> = ban_list
+        .iter()
+        .filter(|ban| *ban.value() > now)
+        .map(|ban| (*ban.key(), *ban.value() - now))
+        .collect();
+    bans.sort();
+    output.push_str(&format!(
+        "Bans: {}
+",
+        bans.len()
+    ));
+    for (peer, left) in bans {
+        output.push_str(&format!(
+            "  {} for {}s
+",
+            peer,
+            left.as_secs()
+        ));
+    }
+    output
+}
+
+fn kill(state: &AdminState, id: &str) -> Result<String> {
+    let id = id
+        .parse()
+        .with_context(|| format!("Invalid session id '{}'", id))?;
+    match state.registry.kill(id) {
+        true => {
+            info!(target: LOG_TARGET, "Session #{} killed", id);
+            Ok(String::new())
+        }
+        false => Err(anyhow!("No session #{}", id)),
+    }
+}
+
+fn find_route(state: &AdminState, route: &str) -> Result<usize> {
+    if let Some(idx) = state.routes.iter().position(|r| r.name == route) {
+        return Ok(idx);
+    }
+    route
+        .trim_start_matches('#')
+        .parse::<usize>()
+        .ok()
+        .filter(|idx| *idx < state.routes.len())
+        .ok_or(anyhow!("No such route '{}'", route))
+}
+
+fn disable(state: &AdminState, route: &str, unbind: bool) -> Result<String> {
+    let idx = find_route(state, route)?;
+    state.routes[idx].enabled.send_replace(false);
+    info!(target: LOG_TARGET, "'{}' disabled", state.routes[idx].name);
+
+    if !unbind {
+        return Ok(String::new());
+    }
+
+    // Only close listeners no enabled route depends on
+    let mut output = String::new();
+    for name in &state.routes[idx].endpoints {
+        let ConnectionData::Inbound { listener, .. } = &state.endpoints[name] else {
+            continue;
+        };
+        let in_use = state
+            .routes
+            .iter()
+            .any(|r| *r.enabled.borrow() && r.endpoints.contains(name));
+        match in_use {
+            true => output.push_str(&format!("'{}' is kept, used by another route\n", name)),
+            false => {
+                listener.unbind();
+                info!(target: LOG_TARGET, "Unbound '{}' ({})", name, listener.addr());
+            }
+        }
+    }
+    Ok(output)
+}
+
+async fn enable(state: &AdminState, route: &str) -> Result<String> {
+    let idx = find_route(state, route)?;
+    for name in &state.routes[idx].endpoints {
+        if let ConnectionData::Inbound { listener, .. } = &state.endpoints[name] {
+            if !listener.is_bound() {
+                listener.rebind().await?;
+                info!(target: LOG_TARGET, "Rebound '{}' ({})", name, listener.addr());
+            }
+        }
+    }
+
+    state.routes[idx].enabled.send_replace(true);
+    info!(target: LOG_TARGET, "'{}' enabled", state.routes[idx].name);
+    Ok(String::new())
+}
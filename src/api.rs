@@ -0,0 +1,332 @@
+use crate::{
+    admin::AdminState,
+    budget::BudgetStats,
+    events::{EventHandler, EventHandlers},
+    relay::connection::BAN_LENGTH,
+    sessions::{SessionRegistry, SessionSnapshot},
+    stun::NatKind,
+};
+use anyhow::Result;
+use axum::{
+    extract::{Path, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get},
+    Json, Router,
+};
+use dashmap::DashMap;
+use log::info;
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use subtle::ConstantTimeEq;
+use tokio::{
+    net::TcpListener,
+    time::{Duration, Instant},
+};
+
+const LOG_TARGET: &str = "api";
+
+// Everything the API reads and acts on
+pub struct ApiState {
+    pub admin: Arc<AdminState>,
+    pub registry: SessionRegistry,
+    pub ban_list: Arc<DashMap<IpAddr, Instant>>,
+    // on_unban for lifted bans
+    pub events: EventHandlers,
+    // Expected as "Authorization: Bearer <token>"
+    pub token: String,
+}
+
+#[derive(serde::Serialize)]
+struct RouteView {
+    id: usize,
+    name: String,
+    enabled: bool,
+    endpoints: [String; 2],
+    sessions: usize,
+}
+
+#[derive(serde::Serialize)]
+struct SessionView {
+    id: u64,
+    route: String,
+    peer_a: Option<SocketAddr>,
+    peer_b: Option<SocketAddr>,
+    authenticated: bool,
+    // Unix time
+    started: u64,
+    // Unix time it is closed at, if limited
+    deadline: Option<u64>,
+    a_to_b: u64,
+    b_to_a: u64,
+}
+
+impl From<SessionSnapshot> for SessionView {
+    fn from(s: SessionSnapshot) -> Self {
+        let unix = |time: SystemTime| time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        Self {
+            id: s.info.id,
+            route: s.info.route,
+            peer_a: s.info.peer_a,
+            peer_b: s.info.peer_b,
+            authenticated: s.info.authenticated,
+            started: unix(s.info.started),
+            deadline: s.deadline.map(unix),
+            a_to_b: s.traffic.a_to_b,
+            b_to_a: s.traffic.b_to_a,
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct TunnelView {
+    route: String,
+    endpoint: String,
+    peer: Option<SocketAddr>,
+    // Milliseconds, unset until the first round trip is timed
+    rtt_ms: Option<f64>,
+    smoothed_rtt_ms: Option<f64>,
+    min_rtt_ms: Option<f64>,
+    samples: u64,
+    // Unix time of the last sample
+    updated: Option<u64>,
+}
+
+#[derive(serde::Serialize)]
+struct ListenerView {
+    endpoint: String,
+    addr: SocketAddr,
+    bound: bool,
+    // accept() errors so far: the connection was gone, out of resources, anything else
+    transient_errors: u64,
+    exhausted_errors: u64,
+    failed_errors: u64,
+}
+
+#[derive(serde::Serialize)]
+struct NatView {
+    // A connector's address, "self" for this connector
+    source: String,
+    kind: NatKind,
+    local: SocketAddr,
+    mapped: Vec<SocketAddr>,
+    // Seconds since the report
+    age: u64,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Ban {
+    ip: IpAddr,
+    // Seconds, defaults to the length of the bans of failed handshakes
+    seconds: Option<u64>,
+}
+
+#[derive(serde::Serialize)]
+struct Health {
+    status: &'static str,
+    routes: usize,
+    sessions: usize,
+}
+
+// Serve the REST API, JSON in and out:
+// GET    /routes          -> routes, their state and session count
+// GET    /sessions        -> running sessions with their traffic so far
+// GET    /sessions/{id}   -> one of them
+// DELETE /sessions/{id}   -> end a session
+// GET    /tunnels         -> inbound tunnels and the round trips to their peers
+// GET    /listeners       -> inbound listeners and their accept errors
+// GET    /limits          -> the [limits] budget and the times accepting paused
+// GET    /nat             -> public addresses learned over STUN and the kind of NAT
+// GET    /bans            -> running bans
+// POST   /bans            -> ban {"ip": ..., "seconds": ...}
+// DELETE /bans/{ip}       -> lift a ban
+// GET    /health          -> liveness, the only one without the token
+pub async fn serve(listener: TcpListener, state: Arc<ApiState>) -> Result<()> {
+    let app = Router::new()
+        .route("/routes", get(routes))
+        .route("/sessions", get(sessions))
+        .route("/sessions/{id}", get(session).delete(kill))
+        .route("/tunnels", get(tunnels))
+        .route("/listeners", get(listeners))
+        .route("/limits", get(limits))
+        .route("/nat", get(nat))
+        .route("/bans", get(bans).post(ban))
+        .route("/bans/{ip}", delete(unban))
+        .layer(middleware::from_fn_with_state(state.clone(), authorize))
+        .route("/health", get(health))
+        .with_state(state);
+
+    info!(target: LOG_TARGET, "Listening on {}", listener.local_addr()?);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+async fn authorize(State(state): State<Arc<ApiState>>, request: Request, next: Next) -> Response {
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    // How long the comparison takes tells nothing of the token
+    let matches = token.is_some_and(|token| token.as_bytes().ct_eq(state.token.as_bytes()).into());
+    if !matches {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    next.run(request).await
+}
+
+async fn routes(State(state): State<Arc<ApiState>>) -> Json<Vec<RouteView>> {
+    Json(
+        state
+            .admin
+            .routes
+            .iter()
+            .enumerate()
+            .map(|(id, route)| RouteView {
+                id,
+                name: route.name.clone(),
+                enabled: *route.enabled.borrow(),
+                endpoints: route.endpoints.clone(),
+                sessions: state.registry.route_sessions(&route.name),
+            })
+            .collect(),
+    )
+}
+
+async fn sessions(State(state): State<Arc<ApiState>>) -> Json<Vec<SessionView>> {
+    let sessions = state.registry.snapshot();
+    Json(sessions.into_iter().map(SessionView::from).collect())
+}
+
+async fn session(State(state): State<Arc<ApiState>>, Path(id): Path<u64>) -> Response {
+    match state.registry.get(id) {
+        Some(session) => Json(SessionView::from(session)).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn tunnels(State(state): State<Arc<ApiState>>) -> Json<Vec<TunnelView>> {
+    let millis = |rtt: Option<Duration>| rtt.map(|rtt| rtt.as_secs_f64() * 1000.0);
+    Json(
+        state
+            .admin
+            .latency
+            .list()
+            .into_iter()
+            .map(|tunnel| TunnelView {
+                route: tunnel.route,
+                endpoint: tunnel.endpoint,
+                peer: tunnel.peer,
+                rtt_ms: millis(tunnel.last),
+                smoothed_rtt_ms: millis(tunnel.smoothed),
+                min_rtt_ms: millis(tunnel.min),
+                samples: tunnel.samples,
+                updated: tunnel
+                    .updated
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs()),
+            })
+            .collect(),
+    )
+}
+
+async fn kill(State(state): State<Arc<ApiState>>, Path(id): Path<u64>) -> StatusCode {
+    match state.registry.kill(id) {
+        true => {
+            info!(target: LOG_TARGET, "Session #{} killed", id);
+            StatusCode::NO_CONTENT
+        }
+        false => StatusCode::NOT_FOUND,
+    }
+}
+
+async fn bans(State(state): State<Arc<ApiState>>) -> Json<Vec<Ban>> {
+    let now = Instant::now();
+    Json(
+        state
+            .ban_list
+            .iter()
+            .filter(|ban| *ban.value() > now)
+            .map(|ban| Ban {
+                ip: *ban.key(),
+                seconds: Some((*ban.value() - now).as_secs()),
+            })
+            .collect(),
+    )
+}
+
+async fn ban(State(state): State<Arc<ApiState>>, Json(ban): Json<Ban>) -> StatusCode {
+    let length = ban.seconds.map_or(BAN_LENGTH, Duration::from_secs);
+    state.ban_list.insert(ban.ip, Instant::now() + length);
+    info!(target: LOG_TARGET, "{} banned for {:?}", ban.ip, length);
+    StatusCode::NO_CONTENT
+}
+
+async fn unban(State(state): State<Arc<ApiState>>, Path(ip): Path<IpAddr>) -> StatusCode {
+    match state.ban_list.remove(&ip) {
+        Some(_) => {
+            info!(target: LOG_TARGET, "{} unbanned", ip);
+            state.events.on_unban(ip);
+            StatusCode::NO_CONTENT
+        }
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+async fn listeners(State(state): State<Arc<ApiState>>) -> Json<Vec<ListenerView>> {
+    Json(
+        state
+            .admin
+            .listeners()
+            .into_iter()
+            .map(|(name, listener)| {
+                let errors = listener.errors();
+                ListenerView {
+                    endpoint: name.to_owned(),
+                    addr: listener.addr(),
+                    bound: listener.is_bound(),
+                    transient_errors: errors.transient,
+                    exhausted_errors: errors.exhausted,
+                    failed_errors: errors.failed,
+                }
+            })
+            .collect(),
+    )
+}
+
+async fn limits(State(state): State<Arc<ApiState>>) -> Response {
+    match &state.admin.budget {
+        Some(budget) => Json::<BudgetStats>(budget.stats()).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn nat(State(state): State<Arc<ApiState>>) -> Json<Vec<NatView>> {
+    Json(
+        state
+            .admin
+            .nat
+            .list()
+            .into_iter()
+            .map(|(source, report, age)| NatView {
+                source,
+                kind: report.kind,
+                local: report.local,
+                mapped: report.mapped,
+                age: age.as_secs(),
+            })
+            .collect(),
+    )
+}
+
+async fn health(State(state): State<Arc<ApiState>>) -> Json<Health> {
+    Json(Health {
+        status: "ok",
+        routes: state.admin.routes.len(),
+        sessions: state.registry.len(),
+    })
+}
@@ -0,0 +1,66 @@
+use crate::{
+    events::{EventHandler, SessionInfo, SessionStats},
+    logfile::LineFile,
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{json, Value};
+use std::{io, net::IpAddr, time::SystemTime};
+
+// Append-only audit trail of sessions and authentication failures, one JSON object per line.
+// Kept apart from the operational log so it can be retained and shipped separately.
+pub struct AuditLog {
+    file: LineFile,
+}
+
+impl AuditLog {
+    pub fn open(path: &str) -> io::Result<Self> {
+        Ok(Self {
+            file: LineFile::open(path, "audit")?,
+        })
+    }
+
+    fn write(&self, record: Value) {
+        self.file.write_line(&record.to_string());
+    }
+}
+
+fn timestamp(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+impl EventHandler for AuditLog {
+    fn on_auth_failure(&self, route: &str, endpoint: &str, peer: IpAddr) {
+        self.write(json!({
+            "event": "auth_failure",
+            "time": timestamp(SystemTime::now()),
+            "route": route,
+            "endpoint": endpoint,
+            "peer": peer.to_string(),
+            "auth": "failed",
+        }));
+    }
+
+    // Recorded the same way, the peer is the one named either way
+    fn on_auth_rejected(&self, route: &str, endpoint: &str, peer: IpAddr) {
+        self.on_auth_failure(route, endpoint, peer);
+    }
+
+    fn on_session_end(&self, session: &SessionInfo, stats: &SessionStats) {
+        self.write(json!({
+            "event": "session",
+            "session": session.id,
+            "route": session.route,
+            "peer_a": session.peer_a.map(|addr| addr.to_string()),
+            "peer_b": session.peer_b.map(|addr| addr.to_string()),
+            "auth": match session.authenticated {
+                true => "ok",
+                false => "none",
+            },
+            "start": timestamp(session.started),
+            "end": timestamp(session.started + stats.duration),
+            "bytes_a_to_b": stats.traffic.a_to_b,
+            "bytes_b_to_a": stats.traffic.b_to_a,
+            "reason": stats.error.as_deref().unwrap_or("closed"),
+        }));
+    }
+}
@@ -0,0 +1,159 @@
+use rand::Rng;
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::watch;
+
+// Round trips below it are all as good, LAN peers aren't told apart by noise
+const RTT_FLOOR: Duration = Duration::from_millis(10);
+// Weight of each session's outcome in the error rate
+const ERROR_DECAY: f64 = 0.1;
+// Share of its weight a connector keeps however slow or failing, so it is still tried
+const MIN_FACTOR: f64 = 0.05;
+
+// Clients of a route whose tunnels come from several connectors, given to them by weight.
+// Waiting tunnels take turns accepting: one is picked at a time, the others stay out of the
+// way until the picked one has its client or is gone.
+#[derive(Clone)]
+pub struct Balancer {
+    inner: Arc<Mutex<Inner>>,
+    turn: watch::Sender<Option<u64>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    weights: HashMap<IpAddr, u32>,
+    connectors: HashMap<IpAddr, Health>,
+    // Waiting tunnels by id, the oldest first
+    waiting: BTreeMap<u64, IpAddr>,
+    next_id: u64,
+}
+
+#[derive(Default)]
+struct Health {
+    // Smoothed like the latency table's
+    rtt: Option<Duration>,
+    // Share of the latest sessions that failed, decaying
+    errors: f64,
+}
+
+impl Balancer {
+    pub fn new(weights: HashMap<IpAddr, u32>) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                weights,
+                ..Default::default()
+            })),
+            turn: watch::Sender::new(None),
+        }
+    }
+
+    // A tunnel of the connector starts waiting for a client, rtt is its last round trip
+    pub fn wait(&self, connector: IpAddr, rtt: Option<Duration>) -> Waiter {
+        let mut inner = self.inner.lock().unwrap();
+        let health = inner.connectors.entry(connector).or_default();
+        if let Some(rtt) = rtt {
+            health.rtt = Some(match health.rtt {
+                Some(smoothed) => smoothed * 7 / 8 + rtt / 8,
+                None => rtt,
+            });
+        }
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.waiting.insert(id, connector);
+        if self.turn.borrow().is_none() {
+            self.turn.send_replace(inner.pick());
+        }
+        Waiter {
+            balancer: self.clone(),
+            id,
+            turn: self.turn.subscribe(),
+        }
+    }
+
+    // Outcome of a session of the connector
+    pub fn report(&self, connector: IpAddr, ok: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        let health = inner.connectors.entry(connector).or_default();
+        let failed = if ok { 0.0 } else { 1.0 };
+        health.errors = health.errors * (1.0 - ERROR_DECAY) + failed * ERROR_DECAY;
+    }
+
+    // Configured weight, lowered for a connector slower than the fastest one or failing
+    pub fn weight(&self, connector: IpAddr) -> f64 {
+        self.inner.lock().unwrap().weight(connector)
+    }
+}
+
+impl Inner {
+    fn weight(&self, connector: IpAddr) -> f64 {
+        let weight = self.weights.get(&connector).copied().unwrap_or(1) as f64;
+        let Some(health) = self.connectors.get(&connector) else {
+            return weight;
+        };
+        let fastest = self.connectors.values().filter_map(|h| h.rtt).min();
+        let rtt_factor = match (health.rtt, fastest) {
+            (Some(rtt), Some(fastest)) => {
+                fastest.max(RTT_FLOOR).as_secs_f64() / rtt.max(RTT_FLOOR).as_secs_f64()
+            }
+            _ => 1.0,
+        };
+        let error_factor = 1.0 - health.errors;
+        weight * (rtt_factor * error_factor).max(MIN_FACTOR)
+    }
+
+    // The oldest waiting tunnel of a connector picked by weight
+    fn pick(&self) -> Option<u64> {
+        let mut connectors: Vec<IpAddr> = self.waiting.values().copied().collect();
+        if connectors.is_empty() {
+            return None;
+        }
+        connectors.sort();
+        connectors.dedup();
+        let weights: Vec<f64> = connectors.iter().map(|c| self.weight(*c)).collect();
+        let total: f64 = weights.iter().sum();
+
+        let mut point = rand::thread_rng().gen_range(0.0..total);
+        let mut picked = connectors[connectors.len() - 1];
+        for (connector, weight) in connectors.iter().zip(&weights) {
+            if point < *weight {
+                picked = *connector;
+                break;
+            }
+            point -= weight;
+        }
+        self.waiting
+            .iter()
+            .find(|(_, connector)| **connector == picked)
+            .map(|(id, _)| *id)
+    }
+}
+
+// A tunnel waiting for a client, it stops waiting once dropped
+pub struct Waiter {
+    balancer: Balancer,
+    id: u64,
+    turn: watch::Receiver<Option<u64>>,
+}
+
+impl Waiter {
+    // Resolves once it is the tunnel's turn to take the next client
+    pub async fn turn(&mut self) {
+        let id = self.id;
+        // The sender lives as long as the balancer, which the waiter holds
+        let _ = self.turn.wait_for(|turn| *turn == Some(id)).await;
+    }
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        let mut inner = self.balancer.inner.lock().unwrap();
+        inner.waiting.remove(&self.id);
+        if *self.balancer.turn.borrow() == Some(self.id) {
+            self.balancer.turn.send_replace(inner.pick());
+        }
+    }
+}
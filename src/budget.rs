@@ -0,0 +1,321 @@
+use crate::sessions::SessionRegistry;
+use log::{debug, info, warn};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    sync::Notify,
+    time::{sleep, Duration, Instant},
+};
+
+const LOG_TARGET: &str = "budget";
+
+// How often routes waiting for room look for idle sessions to reclaim
+const RECLAIM_INTERVAL: Duration = Duration::from_secs(1);
+
+// How often paused accept loops count the descriptors again, closing them wakes no one
+const DESCRIPTOR_POLL: Duration = Duration::from_millis(100);
+
+// Pauses are warned about at most this often
+const WARN_INTERVAL: Duration = Duration::from_secs(30);
+
+// Sessions of every route together. Each priority leaves reserved sessions to every priority
+// above it, so as the budget runs out the routes of low priority stop accepting first and the
+// highest ones keep the last sessions. Waiting routes may reclaim idle sessions of lower ones.
+// Every accept loop pauses while too few file descriptors are left, rather than fail with
+// EMFILE halfway through a handshake.
+#[derive(Clone)]
+pub struct Budget {
+    // Sessions, unlimited when unset
+    max: Option<usize>,
+    reserved: usize,
+    // Descriptors kept free under RLIMIT_NOFILE, Linux only
+    fd_headroom: Option<usize>,
+    // Priorities by route label, sessions are told apart by their workers' log targets
+    routes: Arc<HashMap<String, u8>>,
+    reclaim_idle: Option<Duration>,
+    registry: SessionRegistry,
+    state: Arc<Mutex<State>>,
+    released: Arc<Notify>,
+}
+
+#[derive(Default)]
+struct State {
+    used: usize,
+    // Times an accept loop had to wait
+    pauses: u64,
+    warned: Option<Instant>,
+    // Bytes of the sessions of low priority when they were last looked at, and since when
+    seen: HashMap<u64, (u64, Instant)>,
+}
+
+// What an accept loop waits for
+enum Shortage {
+    Sessions { used: usize, ceiling: usize },
+    Descriptors { open: usize, limit: usize },
+}
+
+// The budget at one point in time
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct BudgetStats {
+    pub sessions: usize,
+    pub max_sessions: Option<usize>,
+    // Open and most the process may open, unknown off Linux
+    pub descriptors: Option<(usize, usize)>,
+    pub fd_headroom: Option<usize>,
+    pub pauses: u64,
+}
+
+impl Budget {
+    pub fn new(
+        max: Option<usize>,
+        reserved: usize,
+        routes: impl IntoIterator<Item = (String, u8)>,
+        registry: SessionRegistry,
+    ) -> Self {
+        Self {
+            max,
+            reserved,
+            fd_headroom: None,
+            routes: Arc::new(routes.into_iter().collect()),
+            reclaim_idle: None,
+            registry,
+            state: Default::default(),
+            released: Default::default(),
+        }
+    }
+
+    // Sessions of lower priority moving no bytes for this long are closed for the higher ones
+    pub fn reclaim_idle(mut self, idle: Duration) -> Self {
+        self.reclaim_idle = Some(idle);
+        self
+    }
+
+    // Accept loops pause while fewer descriptors than this are left
+    pub fn fd_headroom(mut self, headroom: usize) -> Self {
+        self.fd_headroom = Some(headroom);
+        self
+    }
+
+    // Sessions routes of this priority may run, at least one
+    pub fn ceiling(&self, priority: u8) -> Option<usize> {
+        let mut above: Vec<_> = self.routes.values().filter(|&&p| p > priority).collect();
+        above.sort();
+        above.dedup();
+        self.max
+            .map(|max| max.saturating_sub(self.reserved * above.len()).max(1))
+    }
+
+    pub fn used(&self) -> usize {
+        self.state.lock().unwrap().used
+    }
+
+    pub fn stats(&self) -> BudgetStats {
+        let state = self.state.lock().unwrap();
+        BudgetStats {
+            sessions: state.used,
+            max_sessions: self.max,
+            descriptors: descriptors(),
+            fd_headroom: self.fd_headroom,
+            pauses: state.pauses,
+        }
+    }
+
+    // Resolves once a route of this priority may accept another session. Sessions of lower
+    // priority idle for too long are reclaimed meanwhile.
+    pub async fn room(&self, priority: u8) {
+        self.wait(Some(priority), false).await;
+    }
+
+    // Waits for room as above and counts a session until the slot is dropped
+    pub async fn slot(&self, priority: u8) -> BudgetSlot {
+        let slot = self.wait(Some(priority), true).await;
+        slot.expect("taken once there is room")
+    }
+
+    // Resolves once enough descriptors are left, for accept loops running no sessions
+    pub async fn headroom(&self) {
+        self.wait(None, false).await;
+    }
+
+    async fn wait(&self, priority: Option<u8>, take: bool) -> Option<BudgetSlot> {
+        let ceiling = priority.and_then(|priority| self.ceiling(priority));
+        let mut waiting = false;
+        loop {
+            let released = self.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+
+            let shortage = match self.descriptors_short() {
+                Some(shortage) => shortage,
+                None => {
+                    let mut state = self.state.lock().unwrap();
+                    match ceiling {
+                        Some(ceiling) if state.used >= ceiling => Shortage::Sessions {
+                            used: state.used,
+                            ceiling,
+                        },
+                        _ if take => {
+                            state.used += 1;
+                            return Some(BudgetSlot {
+                                budget: self.clone(),
+                            });
+                        }
+                        _ => return None,
+                    }
+                }
+            };
+            if !waiting {
+                waiting = true;
+                self.paused(priority, shortage);
+            }
+
+            let poll = match (priority, self.reclaim_idle) {
+                (Some(priority), Some(idle)) => {
+                    self.reclaim(priority, idle);
+                    idle.min(RECLAIM_INTERVAL)
+                }
+                _ => RECLAIM_INTERVAL,
+            };
+            let poll = match self.fd_headroom {
+                Some(_) => poll.min(DESCRIPTOR_POLL),
+                None => poll,
+            };
+            tokio::select! {
+                _ = released => {}
+                _ = sleep(poll) => {}
+            }
+        }
+    }
+
+    fn descriptors_short(&self) -> Option<Shortage> {
+        let headroom = self.fd_headroom?;
+        let (open, limit) = descriptors()?;
+        (limit.saturating_sub(open) < headroom).then_some(Shortage::Descriptors { open, limit })
+    }
+
+    // Counts the pause, warned about once in a while since every worker of every route pauses
+    fn paused(&self, priority: Option<u8>, shortage: Shortage) {
+        let mut state = self.state.lock().unwrap();
+        state.pauses += 1;
+        let quiet = state.warned.is_some_and(|at| at.elapsed() < WARN_INTERVAL);
+        match shortage {
+            Shortage::Descriptors { open, limit } if !quiet => {
+                state.warned = Some(Instant::now());
+                warn!(
+                    target: LOG_TARGET,
+                    "Accepting paused, {} of {} file descriptors are open and fd_headroom is {}",
+                    open,
+                    limit,
+                    self.fd_headroom.unwrap_or_default()
+                );
+            }
+            Shortage::Sessions { used, ceiling } if !quiet && Some(ceiling) == self.max => {
+                state.warned = Some(Instant::now());
+                warn!(
+                    target: LOG_TARGET,
+                    "Accepting paused, {} sessions are running and max_sessions is {}",
+                    used,
+                    ceiling
+                );
+            }
+            // Short of the sessions left to higher priorities as it should be, or warned already
+            Shortage::Sessions { used, ceiling } => debug!(
+                target: LOG_TARGET,
+                "Priority {} is out of sessions, {} of {} running, waiting",
+                priority.unwrap_or_default(),
+                used,
+                ceiling
+            ),
+            _ => {}
+        }
+    }
+
+    // Kills the session of lowest priority below this one idle for the longest, if idle enough
+    fn reclaim(&self, priority: u8, idle: Duration) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let mut live = Vec::new();
+        let mut candidate = None;
+        for session in self.registry.snapshot() {
+            let Some(lower) = self
+                .priority_of(&session.info.route)
+                .filter(|&p| p < priority)
+            else {
+                continue;
+            };
+            let id = session.info.id;
+            let bytes = session.traffic.a_to_b + session.traffic.b_to_a;
+            // Seen for the first time, a session that moved nothing yet is idle since it started
+            let since = now.checked_sub(session.running()).unwrap_or(now);
+            let seen = state
+                .seen
+                .entry(id)
+                .or_insert((bytes, if bytes == 0 { since } else { now }));
+            if seen.0 != bytes {
+                *seen = (bytes, now);
+            }
+            live.push(id);
+            if now.duration_since(seen.1) >= idle {
+                let key = (lower, seen.1, id);
+                candidate = Some(candidate.map_or(key, |c: (u8, Instant, u64)| c.min(key)));
+            }
+        }
+        state.seen.retain(|id, _| live.contains(id));
+        drop(state);
+
+        if let Some((lower, since, id)) = candidate {
+            if self.registry.kill(id) {
+                info!(
+                    target: LOG_TARGET,
+                    "Reclaimed session #{} of priority {}, idle for {:?}, for priority {}",
+                    id,
+                    lower,
+                    now.duration_since(since),
+                    priority
+                );
+            }
+        }
+    }
+
+    fn priority_of(&self, target: &str) -> Option<u8> {
+        let (route, _) = target.rsplit_once(" worker #")?;
+        self.routes.get(route).copied()
+    }
+}
+
+// A session counted against the budget while it runs
+pub struct BudgetSlot {
+    budget: Budget,
+}
+
+impl Drop for BudgetSlot {
+    fn drop(&mut self) {
+        self.budget.state.lock().unwrap().used -= 1;
+        self.budget.released.notify_waiters();
+    }
+}
+
+// File descriptors open and the most the process may open
+#[cfg(target_os = "linux")]
+pub fn descriptors() -> Option<(usize, usize)> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return None;
+    }
+    // Less the one listing them
+    let open = std::fs::read_dir("/proc/self/fd")
+        .ok()?
+        .count()
+        .saturating_sub(1);
+    Some((open, usize::try_from(limit.rlim_cur).unwrap_or(usize::MAX)))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn descriptors() -> Option<(usize, usize)> {
+    None
+}
@@ -0,0 +1,75 @@
+use dashmap::DashMap;
+use std::{
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+// Connections open at once from each client address on an inbound endpoint, shared by the
+// workers of its routes so one client can't take every session slot
+#[derive(Clone)]
+pub struct ClientLimit {
+    max: usize,
+    open: Arc<DashMap<IpAddr, usize>>,
+    rejected: Arc<AtomicU64>,
+}
+
+impl ClientLimit {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            open: Arc::new(DashMap::new()),
+            rejected: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    // A slot for one more connection of the client, none once it has max of them open
+    pub fn acquire(&self, client: IpAddr) -> Option<ClientSlot> {
+        let mut open = self.open.entry(client).or_insert(0);
+        if *open >= self.max {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        *open += 1;
+        Some(ClientSlot {
+            open: self.open.clone(),
+            client,
+        })
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    // Connections of the client open now
+    pub fn open(&self, client: IpAddr) -> usize {
+        self.open.get(&client).map_or(0, |open| *open)
+    }
+
+    // Clients with connections open
+    pub fn clients(&self) -> usize {
+        self.open.len()
+    }
+
+    // Connections turned away since startup
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+}
+
+// Held for as long as the connection is open
+pub struct ClientSlot {
+    open: Arc<DashMap<IpAddr, usize>>,
+    client: IpAddr,
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        if let Some(mut open) = self.open.get_mut(&self.client) {
+            *open -= 1;
+        }
+        self.open.remove_if(&self.client, |_, open| *open == 0);
+    }
+}
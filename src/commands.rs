@@ -0,0 +1,41 @@
+use anyhow::{anyhow, Result};
+use std::{io, process::Stdio};
+use tokio::{
+    io::AsyncWriteExt,
+    process::Command,
+    time::{timeout, Duration},
+};
+
+// Time a command gets before it is killed
+pub const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Runs a command the relay needs an answer from (a firewall entry added, a handshake key),
+// the input on its stdin. What it printed on stdout once it exited successfully.
+pub async fn run(argv: &[String], input: &[u8]) -> Result<Vec<u8>> {
+    let (program, args) = argv.split_first().ok_or(anyhow!("No program to run"))?;
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| anyhow!("'{}': {}", program, e))?;
+    let mut stdin = child
+        .stdin
+        .take()
+        .ok_or(anyhow!("'{}' was started without stdin", program))?;
+    // Commands may not read it, what they print tells how they did
+    match stdin.write_all(input).await {
+        Err(e) if e.kind() != io::ErrorKind::BrokenPipe => return Err(e.into()),
+        _ => {}
+    }
+    // Without stdin the command can tell the input is over
+    drop(stdin);
+    let output = timeout(COMMAND_TIMEOUT, child.wait_with_output())
+        .await
+        .map_err(|_| anyhow!("'{}' timed out", program))??;
+    match output.status.success() {
+        true => Ok(output.stdout),
+        false => Err(anyhow!("'{}' exited with {}", program, output.status)),
+    }
+}
@@ -1,12 +1,73 @@
-use anyhow::Result;
-use std::{collections::HashMap, fs};
-use toml;
+pub use crate::protocol::cipher::CipherKind;
+use crate::{
+    detect::DispatchRule,
+    error::ConfigError,
+    protocol::{encryption::sha256_from_hex, handshake::MAX_SERVICE_LEN},
+    schedule::Schedule,
+};
+use anyhow::{anyhow, Result};
+use log::LevelFilter;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, fs,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+};
 
 #[derive(Debug, serde::Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum ConnectionType {
     Tunnel,
     Direct,
+    // Inbound only, shared by routes by the protocol of the client
+    Auto,
+    // Inbound only and in no route, peers are banned as they connect
+    Honeypot,
+    // The process's stdin and stdout, a single session's client (inbound) or target (outbound)
+    Stdio,
+    // Outbound only, a process run for each session with the stream as its stdin and stdout
+    Exec,
+    // Datagrams of each client address (inbound), or to a target (outbound), as a session
+    Udp,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportKind {
+    #[default]
+    Tcp,
+    // Tunnels only, needs the "quic" feature
+    Quic,
+}
+
+// How a bonded tunnel spreads its traffic over the paths
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BondMode {
+    // Each frame on one path, for bandwidth
+    Stripe,
+    // Each frame on every path, for reliability
+    Duplicate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Tls,
+    Ssh,
+    Http,
+    Unknown,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Protocol::Tls => "TLS",
+            Protocol::Ssh => "SSH",
+            Protocol::Http => "HTTP",
+            Protocol::Unknown => "unknown protocol",
+        })
+    }
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -16,32 +77,1648 @@ pub enum Direction {
     Outbound,
 }
 
+#[derive(Debug, Clone, Copy, Default, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TapMode {
+    #[default]
+    Plaintext,
+    Ciphertext,
+}
+
+// Which of the targets of an outbound endpoint a session goes to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Affinity {
+    #[default]
+    RoundRobin,
+    // Hash of the client's address, so a client keeps landing on the same target
+    SourceIp,
+}
+
+// How a tunnel endpoint disguises its connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ObfuscationMode {
+    // Looks like a TLS 1.3 connection
+    Tls,
+}
+
+// How an inbound endpoint's port is mapped on the router
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PortMapping {
+    NatPmp,
+    Upnp,
+    // NAT-PMP when the router answers it, UPnP otherwise
+    Auto,
+}
+
+// Padding of a tunnel's traffic
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PaddingMode {
+    // Random padding, dummy frames while idle
+    Random,
+    // Fixed-size frames at padding_rate
+    Constant,
+}
+
 #[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct VeloxidConfig {
+    #[serde(default)]
     pub routes: Vec<Route>,
+    #[serde(default)]
     pub endpoints: HashMap<String, Endpoint>,
     pub log_level: Option<u8>,
+    // The log goes to a file instead of stderr
+    pub log: Option<LogConfig>,
+    pub admin: Option<AdminConfig>,
+    pub audit: Option<AuditConfig>,
+    pub security: Option<SecurityConfig>,
+    // Relay side: route table served to the connectors
+    pub control: Option<ControlConfig>,
+    // Connector side: relay whose route table is followed
+    pub relay: Option<RelayConfig>,
+    // Client side: local ports reaching the connectors end-to-end through the relay
+    pub agent: Option<AgentConfig>,
+    // Needs the "dashboard" feature
+    pub dashboard: Option<DashboardConfig>,
+    // Needs the "api" feature
+    pub api: Option<ApiConfig>,
+    pub probes: Option<ProbesConfig>,
+    // User and group to run as once every listener is bound, Linux only
+    pub user: Option<String>,
+    pub group: Option<String>,
+    // Needs the "sandbox" feature, Linux only
+    pub sandbox: Option<SandboxConfig>,
+    // Budget of the sessions of every route together
+    pub limits: Option<LimitsConfig>,
+    // Needs the "otel" feature
+    pub otel: Option<OtelConfig>,
+}
+
+// Restrictions applied once the relay is running, in case a parser bug is ever exploited
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SandboxConfig {
+    // Landlock: files are read only under the config's directory, the system's and the read
+    // paths, written only under the write ones, and never executed
+    pub landlock: Option<bool>,
+    #[serde(default)]
+    pub read: Vec<String>,
+    #[serde(default)]
+    pub write: Vec<String>,
+    // seccomp: what becomes of the syscalls the relay doesn't make, exec among them
+    pub seccomp: Option<SeccompMode>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SeccompMode {
+    // Fail with EPERM
+    Deny,
+    // Kill the process
+    Kill,
+    // Allowed, logged by the kernel, to find out what a deployment needs
+    Log,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProbesConfig {
+    // Address ("host:port") of the HTTP listener
+    pub listen: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiConfig {
+    // Address ("host:port") of the HTTP listener
+    pub listen: String,
+    // Bearer token of every request
+    pub token: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DashboardConfig {
+    // Address ("host:port") of the HTTP listener
+    pub listen: String,
+    // HTTP basic auth, any user name
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ControlConfig {
+    pub host: Option<String>,
+    pub port: u16,
+    pub secret: String,
+    #[serde(default)]
+    pub exports: Vec<Export>,
+    // Routes the connectors may expose on ephemeral ports, none if unset
+    pub max_exposed: Option<usize>,
+}
+
+// A service behind the connectors, exposed by the relay
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Export {
+    pub name: String,
+    // Public port on the relay
+    pub port: u16,
+    // Relay port the connectors' tunnels for it come in on
+    pub tunnel_port: u16,
+    // Address ("host:port") of the service, as seen from the connectors
+    pub target: String,
+    pub size: usize,
+    // The public port takes the tunnels of agents with this secret instead of clients, and
+    // forwards them to the connectors without decrypting them
+    pub agent_secret: Option<String>,
+    // Relay port agents ask at for the address of a connector, to punch through to it instead
+    // of going through the relay. Needs agent_secret.
+    pub punch_port: Option<u16>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RelayConfig {
+    pub host: String,
+    // Port of the relay's control listener
+    pub port: u16,
+    pub secret: String,
+    // Local services the relay is asked to expose on ports of its choice
+    #[serde(default)]
+    pub expose: Vec<Expose>,
+    // Local services fronted by this connector, only these routes of the table are followed
+    // when given. The exposed ones are always followed.
+    #[serde(default)]
+    pub services: Vec<Service>,
+    // Shared with the agents, needed for the routes of the table they reach end-to-end
+    pub e2e_secret: Option<String>,
+    // STUN servers ("host:port") the connector learns its public address from before fetching
+    // the table, reported to the relay. Two or more tell the kind of NAT.
+    #[serde(default)]
+    pub stun: Vec<String>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AgentConfig {
+    // The relay
+    pub host: String,
+    // Agent secret of the relay's exports
+    pub secret: String,
+    // Shared with the connectors, the relay can't read the sessions
+    pub e2e_secret: String,
+    pub services: Vec<AgentService>,
+}
+
+// A local port leading to one export of the relay
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AgentService {
+    pub name: String,
+    // Address ("host:port") the clients connect to
+    pub listen: String,
+    // Public port of the export on the relay
+    pub port: u16,
+    pub size: usize,
+    // Punch port of the export on the relay, tried before going through the relay
+    pub punch_port: Option<u16>,
+}
+
+// A local service taking the place of one route of the relay's table
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Service {
+    // The route, by its name or by its public port on the relay
+    pub name: Option<String>,
+    pub port: Option<u16>,
+    // Address ("host:port") of the service
+    pub target: String,
+    // Tunnels kept waiting on the relay, the route's size by default
+    pub size: Option<usize>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Expose {
+    pub name: String,
+    // Address ("host:port") of the local service
+    pub target: String,
+    pub size: usize,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuditConfig {
+    pub file: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SecurityConfig {
+    // Authentication failures and bans for fail2ban
+    pub log: Option<SecurityLogConfig>,
+    // Banned peers passed to the firewall
+    pub ban: Option<BanHookConfig>,
+    // Banned peers held open instead of turned away
+    pub tarpit: Option<TarpitConfig>,
 }
 
 #[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogConfig {
+    pub file: String,
+    // The file is rotated once it would grow past this many bytes, or as the period turns
+    pub max_bytes: Option<u64>,
+    pub rotate: Option<RotationPeriod>,
+    // Rotated files kept (file.1 being the newest), 7 by default
+    pub keep: Option<usize>,
+    // Rotated files are gzipped (file.1.gz)
+    pub compress: Option<bool>,
+}
+
+// Local time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RotationPeriod {
+    Hourly,
+    Daily,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SecurityLogConfig {
+    // The lines go to the operational log when unset
+    pub file: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BanHookConfig {
+    // Program and arguments run for every ban, "{ip}", "{family}" ("ip" or "ip6") and
+    // "{seconds}" are replaced in the arguments
+    pub command: Vec<String>,
+    // Run when a ban is lifted from the API or the dashboard, with "{ip}" and "{family}"
+    pub unban_command: Option<Vec<String>>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TarpitConfig {
+    // Sockets held at once, banned peers past it are turned away
+    pub max: usize,
+    // Seconds between the bytes trickled to them, 10 by default
+    pub interval: Option<u64>,
+}
+
+// OpenTelemetry export of the sessions, as spans and counters labelled by route, endpoints and
+// connector, to a collector speaking OTLP over HTTP
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OtelConfig {
+    // Base URL of the collector ("http://host:4318"), the signals go to /v1/traces and
+    // /v1/metrics under it
+    pub endpoint: String,
+    // service.name of the resource, "veloxid" by default
+    pub service_name: Option<String>,
+    // Seconds between the exports, 10 by default
+    pub interval: Option<u64>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimitsConfig {
+    // Sessions running at once over every route, unlimited when unset
+    pub max_sessions: Option<usize>,
+    // File descriptors kept free under RLIMIT_NOFILE, accept loops pause while fewer are left.
+    // Linux only.
+    pub fd_headroom: Option<usize>,
+    // Sessions each priority leaves to every priority above it, a tenth of max_sessions by default
+    pub reserved: Option<usize>,
+    // Seconds without traffic after which sessions of lower priority are closed for routes of
+    // higher priority waiting for room, never when unset
+    pub reclaim_idle: Option<u64>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    pub socket: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Endpoint {
     pub host: Option<String>,
+    // Inbound endpoints listen on any free port when unset
+    #[serde(default)]
     pub port: u16,
     #[serde(rename = "type")]
     pub kind: ConnectionType,
     pub direction: Direction,
     pub secret: Option<String>,
+    // Tunnels only, instead of secret: the command answering the HMAC requests of each
+    // handshake, so the key can stay in a TPM or a PKCS#11 token. See keys::KeyCommand.
+    pub key_command: Option<Vec<String>>,
+    // Tunnels only, defaults to chacha20
+    pub cipher: Option<CipherKind>,
+    pub transport: Option<TransportKind>,
+    // Tunnels only, both sides must agree on bonding
+    pub bonding: Option<BondMode>,
+    // Relay addresses ("host:port") of an outbound bonded tunnel, defaults to host and port
+    pub paths: Option<Vec<String>>,
+    // Tunnels only, seconds a session survives its connection dropping while the
+    // outbound side reconnects. Both sides must agree on it.
+    pub resume: Option<u64>,
+    // Addresses ("host:port") a plain outbound endpoint spreads its sessions over,
+    // defaults to host and port
+    pub targets: Option<Vec<String>>,
+    // TCP tunnels only, both sides must agree on it
+    pub obfuscation: Option<ObfuscationMode>,
+    // Server name an obfuscated outbound tunnel shows, like TLS SNI
+    pub server_name: Option<String>,
+    // Tunnels only, both sides must agree on it
+    pub padding: Option<PaddingMode>,
+    // KiB/s sent with constant padding, defaults to 64
+    pub padding_rate: Option<u32>,
+    // Bytes of the copy buffers of its sessions, the larger of the two endpoints' is used
+    pub buffer_size: Option<usize>,
+    // Seconds a peer of its sessions may stop reading before the session is torn down,
+    // and bytes of each direction held for it at once. The smaller of the two endpoints'.
+    pub write_timeout: Option<u64>,
+    pub max_in_flight: Option<usize>,
+    // Inbound TCP endpoints on an IPv6 host only, whether "::" also accepts IPv4 clients
+    // (false) or only IPv6 ones (true). The system default when unset.
+    pub ipv6_only: Option<bool>,
+    // Inbound TCP endpoints only, seconds binding keeps being retried while the port is in
+    // use, startup fails right away when unset
+    pub bind_retry: Option<u64>,
+    // Plain outbound TCP endpoints only, connections to the targets kept open for the
+    // sessions to come, checked and replaced in the background
+    pub pool: Option<usize>,
+    // TCP Fast Open, Linux only. Inbound TCP endpoints accept it, outbound tunnels use it
+    // when they speak first, obfuscated or resuming with a ticket.
+    pub fast_open: Option<bool>,
+    // Tunnels only, seconds an inbound side waits for the auth token (5 by default) and
+    // an outbound side for the nonce (5 by default)
+    pub auth_timeout: Option<u64>,
+    pub nonce_timeout: Option<u64>,
+    // Inbound tunnels only, seconds an accepted connection gets to authenticate, obfuscation
+    // included, 10 by default. Slow peers are banned rather than keeping the worker busy.
+    pub handshake_timeout: Option<u64>,
+    // Tunnels only, new session keys for every attachment, from a nonce the inbound side
+    // sends along with ATTACH. Both sides must agree on it.
+    pub rekey: Option<bool>,
+    // Tunnels only, bytes of each direction after which the session keys are replaced by the
+    // next ones, and seconds after which they are (AEAD ciphers only). Stream ciphers frame the
+    // session to signal it, both sides must set rekey_bytes then.
+    pub rekey_bytes: Option<u64>,
+    pub rekey_interval: Option<u64>,
+    // Tunnels only, seconds the resumption tickets an inbound side issues stay valid.
+    // Outbound sides connect again with them without waiting for the nonce, their own
+    // value isn't used. Both sides must agree on it.
+    pub tickets: Option<u64>,
+    // Tunnels only, inbound sides send nothing, not even the nonce, until the peer proves to
+    // have the secret, outbound sides do so first. Both sides must agree on it.
+    pub silent: Option<bool>,
+    // Tunnels only, seed of the time-based codes mixed into the secret of each handshake, so
+    // the secret alone doesn't get a peer in. Inbound sides take the codes of up to totp_skew
+    // 30 second steps off their clock (1 by default). Both sides must agree on the seed.
+    pub totp_seed: Option<String>,
+    pub totp_skew: Option<u64>,
+    // Inbound tunnels only, seconds between the heartbeats timing the peer while its tunnel
+    // waits to be attached. Peers missing one are dropped.
+    pub heartbeat: Option<u64>,
+    // Inbound TCP tunnels only, connectors announce a service for each tunnel they open and
+    // the tunnels go to the routes serving it
+    pub registry: Option<bool>,
+    // Outbound tunnels only, the service announced to a registry endpoint
+    pub service: Option<String>,
+    // Outbound TCP tunnels only, the outbound tunnel endpoint the tunnels are opened through
+    // instead of connecting to host and port. Its relay takes them on to the next hop, the
+    // handshake and cipher of this endpoint running inside of the first one's.
+    pub via: Option<String>,
+    // Outbound tunnels only, secrets of the relays past this endpoint's, nearest first. The
+    // tunnel to each one is opened inside of the one before, every layer sealed with
+    // ChaCha20-Poly1305, so a relay only reads the tunnel to the next. The service and
+    // e2e_secret are the last relay's.
+    pub hops: Option<Vec<String>>,
+    // Outbound TCP tunnels with an e2e_secret only, the relay's punch port for the route. The
+    // relay tells where a connector waits, and the tunnel is opened straight to it with the
+    // e2e_secret, going through the relay at host and port when that fails.
+    pub punch_port: Option<u16>,
+    // TCP tunnels only, UDP port on the inbound side's host taking knocks. Inbound sides close
+    // the connections of peers that didn't knock within knock_window seconds (30 by default),
+    // outbound sides knock before connecting.
+    pub knock_port: Option<u16>,
+    pub knock_window: Option<u64>,
+    // Outbound only, the address the inbound side sees the knocks come from when a NAT is in
+    // between. Knocks are bound to it, defaults to the address they are sent from.
+    pub knock_source: Option<IpAddr>,
+    // Inbound TCP tunnels with an e2e_secret only, the route of the relay at host and port
+    // (its control listener) whose agents punch through to this endpoint. Their tunnels are
+    // opened with the e2e_secret, the secret is the relay's.
+    pub rendezvous: Option<String>,
+    // Inbound direct and auto endpoints only, the name LAN clients find the listener by over
+    // mDNS, needs the "discovery" feature
+    pub advertise: Option<String>,
+    // DNS-SD service type it is advertised as, "_veloxid._tcp" by default
+    pub advertise_type: Option<String>,
+    // Inbound direct and auto endpoints only, connections open at once from each client
+    // address. Any more are closed right after being accepted.
+    pub max_conns_per_ip: Option<usize>,
+    // Honeypots only, bytes of what peers send logged
+    pub capture: Option<usize>,
+    // Exec endpoints only, the program and its arguments, and the processes running at once
+    // over all of its routes (unlimited when unset). Sessions beyond that are refused.
+    pub command: Option<Vec<String>>,
+    pub max_processes: Option<usize>,
+    // UDP endpoints only, seconds a flow lasts without a datagram either way, 60 by default
+    pub flow_timeout: Option<u64>,
+    // Inbound direct and auto endpoints only, PEM files of the certificate chain and its key.
+    // Clients talk TLS to veloxid and their routes get the plaintext, needs the "tls" feature.
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    // Direct outbound endpoints only, TLS opened to the targets for the plaintext of the route,
+    // checked against the CA certificates of tls_ca (PEM) or the system's
+    pub tls: Option<bool>,
+    pub tls_ca: Option<String>,
+    // Instead of tls_ca, the SHA-256 of the targets' public key (SPKI) in hex, whoever signed
+    // their certificate. For self-hosted ones with self-signed certificates.
+    pub pinned_sha256: Option<String>,
+    // Tunnels only, encrypts the sessions for the far end of a blind route on the peer rather
+    // than for the peer itself. The far end needs the same secret.
+    pub e2e_secret: Option<String>,
+    // Inbound endpoints with a port only, the port forwarded to them by the router over NAT-PMP
+    // or UPnP, for peers outside of the LAN. The mapping is renewed at half of its lease
+    // (mapping_lease seconds, 3600 by default) and removed on exit.
+    pub port_mapping: Option<PortMapping>,
+    pub mapping_lease: Option<u64>,
+    // Port asked for on the router's public address, the endpoint's by default
+    pub external_port: Option<u16>,
+    // The router, the default route's gateway (NAT-PMP) or found over SSDP (UPnP) when unset
+    pub mapping_gateway: Option<IpAddr>,
 }
 
 #[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Route {
+    // Used in logs and by the admin interfaces, stays the same as routes are added or removed
+    pub name: Option<String>,
+    // Overrides the global log_level for this route's workers
+    pub log_level: Option<u8>,
     pub endpoints: [String; 2],
+    // Workers accepting and connecting sessions, unless the route is autoscaled
+    #[serde(default)]
     pub size: usize,
+    // Autoscaled routes start min_size workers and add one whenever none is idle, up to
+    // max_size. Spare workers idle for a while are retired again.
+    pub min_size: Option<usize>,
+    pub max_size: Option<usize>,
+    // Sessions running at once, defaults to size, or to the workers of autoscaled routes
+    pub max_sessions: Option<usize>,
+    // Seconds a session may run before it is closed, unlimited when unset
+    pub max_session_duration: Option<u64>,
+    // Routes of higher priority keep accepting after lower ones run out of the [limits] budget,
+    // 0 by default
+    pub priority: Option<u8>,
+    pub schedule: Option<Schedule>,
+    // Protocol served by this route on an auto endpoint, unset for the fallback route
+    pub protocol: Option<Protocol>,
+    // Auto endpoints only, TLS clients by the server name and ALPN protocols of their ClientHello
+    pub sni: Option<Vec<String>>,
+    pub alpn: Option<Vec<String>>,
+    pub tap: Option<String>,
+    pub tap_mode: Option<TapMode>,
+    pub affinity: Option<Affinity>,
+    // Both endpoints are tunnels, joined without decrypting them. The sessions stay encrypted
+    // for the ends, which set e2e_secret.
+    pub blind: Option<bool>,
+    // The first endpoint is an inbound tunnel served by several connectors, clients are
+    // spread over them by weight. Slow and failing connectors get less.
+    pub balance: Option<bool>,
+    // Connectors by address, 1 by default
+    pub weights: Option<HashMap<IpAddr, u32>>,
+    // On a registry endpoint, the service whose tunnels the route takes. On an outbound tunnel,
+    // the service its tunnels announce instead of the endpoint's, so routes sharing the endpoint
+    // reach their own backends.
+    pub service: Option<String>,
+}
+
+// Merges the endpoints and routes of the files matching the include patterns, in the order of
+// their paths. Patterns are relative to dir.
+fn include(table: &mut toml::Table, dir: &Path) -> Result<()> {
+    let patterns = match table.remove("include") {
+        Some(toml::Value::Array(patterns)) => patterns,
+        Some(_) => {
+            let reason = "must be a list of file patterns".to_owned();
+            return Err(ConfigError::Invalid("include".to_owned(), reason).into());
+        }
+        None => return Ok(()),
+    };
+
+    let mut paths = Vec::new();
+    for pattern in patterns {
+        let toml::Value::String(pattern) = pattern else {
+            let reason = "must be a list of file patterns".to_owned();
+            return Err(ConfigError::Invalid("include".to_owned(), reason).into());
+        };
+        let pattern = dir.join(pattern);
+        for path in glob::glob(&pattern.to_string_lossy())? {
+            paths.push(path?);
+        }
+    }
+    paths.sort();
+    paths.dedup();
+
+    for path in paths {
+        let error = |e: &dyn std::fmt::Display| anyhow!("'{}': {}", path.display(), e);
+        let content = fs::read_to_string(&path).map_err(|e| error(&e))?;
+        let included: toml::Table = toml::from_str(&content).map_err(|e| error(&e))?;
+
+        for (key, value) in included {
+            match (key.as_str(), value) {
+                ("endpoints", toml::Value::Table(endpoints)) => {
+                    let toml::Value::Table(merged) = table
+                        .entry("endpoints")
+                        .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+                    else {
+                        return Err(anyhow!("endpoints must be a table"));
+                    };
+                    for (name, endpoint) in endpoints {
+                        if merged.contains_key(&name) {
+                            return Err(error(&format!("endpoints.{} is defined twice", name)));
+                        }
+                        merged.insert(name, endpoint);
+                    }
+                }
+                ("routes", toml::Value::Array(routes)) => {
+                    let toml::Value::Array(merged) = table
+                        .entry("routes")
+                        .or_insert_with(|| toml::Value::Array(Vec::new()))
+                    else {
+                        return Err(anyhow!("routes must be a list"));
+                    };
+                    merged.extend(routes);
+                }
+                (key, _) => {
+                    let reason = "included files only hold endpoints and routes";
+                    return Err(error(&format!("{}: {}", key, reason)));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Endpoint keys [defaults] only gives to tunnels
+const TUNNEL_KEYS: &[&str] = &[
+    "secret",
+    "key_command",
+    "cipher",
+    "transport",
+    "bonding",
+    "resume",
+    "obfuscation",
+    "padding",
+    "padding_rate",
+    "auth_timeout",
+    "nonce_timeout",
+    "handshake_timeout",
+    "e2e_secret",
+    "rekey",
+    "rekey_bytes",
+    "rekey_interval",
+    "tickets",
+    "silent",
+    "totp_seed",
+    "totp_skew",
+    "knock_port",
+    "knock_window",
+    "knock_source",
+];
+
+// Fills the keys an endpoint leaves out from the endpoints it extends, then from [defaults]
+fn apply_defaults(table: &mut toml::Table) -> Result<()> {
+    let invalid = |key: String, reason: &str| ConfigError::Invalid(key, reason.to_owned());
+    let defaults = match table.remove("defaults") {
+        Some(toml::Value::Table(defaults)) => defaults,
+        Some(_) => return Err(invalid("defaults".to_owned(), "must be a table").into()),
+        None => toml::Table::new(),
+    };
+    let Some(toml::Value::Table(endpoints)) = table.get_mut("endpoints") else {
+        return Ok(());
+    };
+    let originals = endpoints.clone();
+
+    for (name, endpoint) in endpoints.iter_mut() {
+        // Anything else is left to the deserializer to complain about
+        let toml::Value::Table(endpoint) = endpoint else {
+            continue;
+        };
+        let key = format!("endpoints.{}.extends", name);
+
+        // Closest ancestor first
+        let mut seen = HashSet::from([name.as_str()]);
+        let mut parent = endpoint.remove("extends");
+        while let Some(value) = parent {
+            let toml::Value::String(base_name) = value else {
+                return Err(invalid(key, "must be an endpoint name").into());
+            };
+            let Some((base_name, toml::Value::Table(base))) = originals.get_key_value(&base_name)
+            else {
+                return Err(invalid(key, &format!("no endpoint named '{}'", base_name)).into());
+            };
+            if !seen.insert(base_name) {
+                return Err(invalid(key, &format!("loops back through '{}'", base_name)).into());
+            }
+            for (k, v) in base.iter().filter(|(k, _)| *k != "extends") {
+                endpoint.entry(k).or_insert_with(|| v.clone());
+            }
+            parent = base.get("extends").cloned();
+        }
+
+        // The type may come from the defaults as well
+        for (k, v) in defaults
+            .iter()
+            .filter(|(k, _)| !TUNNEL_KEYS.contains(&k.as_str()))
+        {
+            endpoint.entry(k).or_insert_with(|| v.clone());
+        }
+        if endpoint.get("type").and_then(toml::Value::as_str) == Some("tunnel") {
+            for (k, v) in defaults
+                .iter()
+                .filter(|(k, _)| TUNNEL_KEYS.contains(&k.as_str()))
+            {
+                endpoint.entry(k).or_insert_with(|| v.clone());
+            }
+        }
+    }
+    Ok(())
+}
+
+// Level of a log_level setting
+pub fn level_filter(log_level: Option<u8>) -> LevelFilter {
+    match log_level {
+        Some(0) => LevelFilter::Off,
+        Some(1) => LevelFilter::Error,
+        Some(2) => LevelFilter::Warn,
+        Some(4) => LevelFilter::Debug,
+        Some(5) => LevelFilter::Trace,
+        _ => LevelFilter::Info, // Default
+    }
+}
+
+impl Route {
+    // Its name, or its position in the file
+    pub fn label(&self, idx: usize) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!("route #{}", idx),
+        }
+    }
+
+    // What it takes from its auto endpoint
+    pub fn dispatch_rule(&self) -> DispatchRule {
+        DispatchRule {
+            protocol: self.protocol,
+            sni: self.sni.clone().unwrap_or_default(),
+            alpn: self.alpn.clone().unwrap_or_default(),
+        }
+    }
+
+    // Log target of one of its workers
+    pub fn worker_target(&self, idx: usize, worker_idx: usize) -> String {
+        worker_target(&self.label(idx), worker_idx)
+    }
+
+    // Workers the route starts with, and the most it runs
+    pub fn workers(&self) -> (usize, usize) {
+        match (self.min_size, self.max_size) {
+            (Some(min), Some(max)) => (min, max),
+            _ => (self.size, self.size),
+        }
+    }
+}
+
+// Log target of a worker of the route labelled so
+pub fn worker_target(label: &str, worker_idx: usize) -> String {
+    format!("{} worker #{}", label, worker_idx)
+}
+
+impl Endpoint {
+    // Both inbound endpoints would bind the same address, or one of them a wildcard covering the
+    // other's. Hosts that aren't addresses are told apart by name only.
+    fn overlaps(&self, other: &Endpoint) -> bool {
+        let udp = |e: &Endpoint| {
+            e.transport.unwrap_or_default() == TransportKind::Quic
+                || matches!(e.kind, ConnectionType::Udp)
+        };
+        if self.port != other.port || udp(self) != udp(other) {
+            return false;
+        }
+        let host = |e: &Endpoint| e.host.clone().unwrap_or("0.0.0.0".to_owned());
+        let (a, b) = (host(self), host(other));
+        let (Ok(ip_a), Ok(ip_b)) = (a.parse::<IpAddr>(), b.parse::<IpAddr>()) else {
+            return a == b;
+        };
+        // "::" takes IPv4 clients too unless ipv6_only, which is the usual system default
+        let covers = |wildcard: IpAddr, wildcard_of: &Endpoint, ip: IpAddr| {
+            wildcard.is_unspecified()
+                && (wildcard.is_ipv4() == ip.is_ipv4()
+                    || (wildcard.is_ipv6() && wildcard_of.ipv6_only != Some(true)))
+        };
+        ip_a == ip_b || covers(ip_a, self, ip_b) || covers(ip_b, other, ip_a)
+    }
+
+    // Sessions can pass through it as they are, with no AEAD records or padding frames
+    fn blindable(&self) -> bool {
+        matches!(
+            self.cipher,
+            None | Some(CipherKind::ChaCha20 | CipherKind::XChaCha20)
+        ) && self.padding.is_none()
+    }
 }
 
 impl VeloxidConfig {
     pub fn load(file_path: &str) -> Result<Self> {
-        let file_content = fs::read_to_string(file_path)?;
-        Ok(toml::from_str(&file_content)?)
+        let file_content =
+            fs::read_to_string(file_path).map_err(|e| anyhow!("'{}': {}", file_path, e))?;
+        let dir = Path::new(file_path).parent().unwrap_or(Path::new("."));
+        Self::parse_in(&file_content, dir).map_err(|e| anyhow!("'{}': {}", file_path, e))
+    }
+
+    // Includes relative to the working directory
+    pub fn parse(content: &str) -> Result<Self> {
+        Self::parse_in(content, Path::new("."))
+    }
+
+    // Includes merged, defaults and inheritance applied, then validated
+    fn parse_in(content: &str, dir: &Path) -> Result<Self> {
+        let mut table: toml::Table = toml::from_str(content)?;
+        include(&mut table, dir)?;
+        apply_defaults(&mut table)?;
+        let config: Self = table.try_into()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Checks what the types alone don't, errors name the offending table and key
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |key: String, reason: &str| ConfigError::Invalid(key, reason.to_owned());
+
+        for (name, endpoint) in &self.endpoints {
+            let key = |field: &str| format!("endpoints.{}.{}", name, field);
+            let stdio = matches!(endpoint.kind, ConnectionType::Stdio);
+            let exec = matches!(endpoint.kind, ConnectionType::Exec);
+            if stdio || exec {
+                let network = [
+                    ("host", endpoint.host.is_some()),
+                    ("port", endpoint.port != 0),
+                    ("transport", endpoint.transport.is_some()),
+                    ("targets", endpoint.targets.is_some()),
+                    ("ipv6_only", endpoint.ipv6_only.is_some()),
+                    ("bind_retry", endpoint.bind_retry.is_some()),
+                    ("fast_open", endpoint.fast_open.is_some()),
+                    ("advertise", endpoint.advertise.is_some()),
+                    ("port_mapping", endpoint.port_mapping.is_some()),
+                ];
+                if let Some((field, _)) = network.iter().find(|(_, set)| *set) {
+                    let reason = match stdio {
+                        true => "stdio endpoints don't use the network",
+                        false => "exec endpoints don't use the network",
+                    };
+                    return Err(invalid(key(field), reason).into());
+                }
+            }
+            if exec && matches!(endpoint.direction, Direction::Inbound) {
+                return Err(invalid(key("direction"), "exec endpoints are outbound only").into());
+            }
+            match &endpoint.command {
+                Some(_) if !exec => {
+                    return Err(invalid(key("command"), "exec endpoints only").into());
+                }
+                Some(command) if command.first().is_none_or(String::is_empty) => {
+                    return Err(invalid(key("command"), "must start with a program").into());
+                }
+                None if exec => return Err(invalid(key("command"), "must be given").into()),
+                _ => {}
+            }
+            if endpoint.max_processes.is_some() && !exec {
+                return Err(invalid(key("max_processes"), "exec endpoints only").into());
+            }
+            if endpoint.max_processes == Some(0) {
+                return Err(invalid(key("max_processes"), "must be greater than 0").into());
+            }
+            let udp = matches!(endpoint.kind, ConnectionType::Udp);
+            if udp {
+                let tcp = [
+                    ("transport", endpoint.transport.is_some()),
+                    ("ipv6_only", endpoint.ipv6_only.is_some()),
+                    ("bind_retry", endpoint.bind_retry.is_some()),
+                    ("fast_open", endpoint.fast_open.is_some()),
+                    ("advertise", endpoint.advertise.is_some()),
+                ];
+                if let Some((field, _)) = tcp.iter().find(|(_, set)| *set) {
+                    return Err(invalid(key(field), "TCP endpoints only").into());
+                }
+            }
+            if endpoint.flow_timeout.is_some() && !udp {
+                return Err(invalid(key("flow_timeout"), "UDP endpoints only").into());
+            }
+            if endpoint.flow_timeout == Some(0) {
+                return Err(invalid(key("flow_timeout"), "must be greater than 0").into());
+            }
+            // Outbound endpoints with targets, paths or a via don't use their own port
+            let addressed =
+                endpoint.targets.is_some() || endpoint.paths.is_some() || endpoint.via.is_some();
+            if endpoint.port == 0
+                && matches!(endpoint.direction, Direction::Outbound)
+                && !addressed
+                && !stdio
+                && !exec
+            {
+                return Err(invalid(key("port"), "outbound endpoints need a port").into());
+            }
+            if endpoint.padding_rate == Some(0) {
+                return Err(invalid(key("padding_rate"), "must be greater than 0").into());
+            }
+            if endpoint.resume == Some(0) {
+                return Err(invalid(key("resume"), "must be greater than 0").into());
+            }
+            if endpoint.buffer_size == Some(0) {
+                return Err(invalid(key("buffer_size"), "must be greater than 0").into());
+            }
+            if endpoint.write_timeout == Some(0) {
+                return Err(invalid(key("write_timeout"), "must be greater than 0").into());
+            }
+            if endpoint.max_in_flight == Some(0) {
+                return Err(invalid(key("max_in_flight"), "must be greater than 0").into());
+            }
+            if endpoint.ipv6_only.is_some()
+                && (matches!(endpoint.direction, Direction::Outbound)
+                    || endpoint.transport.unwrap_or_default() == TransportKind::Quic)
+            {
+                return Err(invalid(key("ipv6_only"), "inbound TCP endpoints only").into());
+            }
+            if endpoint.bind_retry.is_some()
+                && (matches!(endpoint.direction, Direction::Outbound)
+                    || endpoint.transport.unwrap_or_default() == TransportKind::Quic)
+            {
+                return Err(invalid(key("bind_retry"), "inbound TCP endpoints only").into());
+            }
+            if endpoint.pool.is_some()
+                && (!matches!(endpoint.direction, Direction::Outbound)
+                    || !matches!(endpoint.kind, ConnectionType::Direct))
+            {
+                return Err(invalid(key("pool"), "direct outbound endpoints only").into());
+            }
+            if endpoint.fast_open.is_some() {
+                let tcp = endpoint.transport.unwrap_or_default() == TransportKind::Tcp
+                    && endpoint.bonding.is_none()
+                    && endpoint.resume.is_none();
+                let speaks_first = endpoint.obfuscation.is_some()
+                    || endpoint.tickets.is_some()
+                    || endpoint.silent == Some(true);
+                if !tcp {
+                    return Err(invalid(key("fast_open"), "plain TCP endpoints only").into());
+                }
+                if matches!(endpoint.direction, Direction::Outbound) && !speaks_first {
+                    let reason = "outbound tunnels with obfuscation, tickets or silent only";
+                    return Err(invalid(key("fast_open"), reason).into());
+                }
+            }
+            if endpoint.pool == Some(0) {
+                return Err(invalid(key("pool"), "must be greater than 0").into());
+            }
+            if endpoint.bind_retry == Some(0) {
+                return Err(invalid(key("bind_retry"), "must be greater than 0").into());
+            }
+            let timeouts = [
+                ("auth_timeout", endpoint.auth_timeout),
+                ("nonce_timeout", endpoint.nonce_timeout),
+                ("handshake_timeout", endpoint.handshake_timeout),
+            ];
+            if endpoint.rekey.is_some() && !matches!(endpoint.kind, ConnectionType::Tunnel) {
+                return Err(invalid(key("rekey"), "tunnel endpoints only").into());
+            }
+            let limits = [
+                ("rekey_bytes", endpoint.rekey_bytes),
+                ("rekey_interval", endpoint.rekey_interval),
+            ];
+            for (field, limit) in limits {
+                match limit {
+                    Some(_) if !matches!(endpoint.kind, ConnectionType::Tunnel) => {
+                        return Err(invalid(key(field), "tunnel endpoints only").into());
+                    }
+                    Some(_) if endpoint.e2e_secret.is_some() => {
+                        let reason = "end-to-end sessions aren't keyed by the tunnel";
+                        return Err(invalid(key(field), reason).into());
+                    }
+                    Some(0) => return Err(invalid(key(field), "must be greater than 0").into()),
+                    _ => {}
+                }
+            }
+            if endpoint.rekey_interval.is_some()
+                && !matches!(
+                    endpoint.cipher,
+                    Some(CipherKind::Aes256Gcm | CipherKind::ChaCha20Poly1305)
+                )
+            {
+                let reason = "needs cipher aes-256-gcm or chacha20-poly1305";
+                return Err(invalid(key("rekey_interval"), reason).into());
+            }
+            if endpoint.tickets.is_some() && !matches!(endpoint.kind, ConnectionType::Tunnel) {
+                return Err(invalid(key("tickets"), "tunnel endpoints only").into());
+            }
+            if endpoint.tickets == Some(0) {
+                return Err(invalid(key("tickets"), "must be greater than 0").into());
+            }
+            if endpoint.silent.is_some() && !matches!(endpoint.kind, ConnectionType::Tunnel) {
+                return Err(invalid(key("silent"), "tunnel endpoints only").into());
+            }
+            if endpoint.totp_seed.is_some() {
+                if !matches!(endpoint.kind, ConnectionType::Tunnel) {
+                    return Err(invalid(key("totp_seed"), "tunnel endpoints only").into());
+                }
+                // Resumed sessions would have the secret of the step the ticket was issued in
+                if endpoint.tickets.is_some() {
+                    let reason = "can't be used with tickets";
+                    return Err(invalid(key("totp_seed"), reason).into());
+                }
+            }
+            if endpoint.totp_skew.is_some() && endpoint.totp_seed.is_none() {
+                return Err(invalid(key("totp_skew"), "needs totp_seed").into());
+            }
+            if let Some(command) = &endpoint.key_command {
+                if !matches!(endpoint.kind, ConnectionType::Tunnel) {
+                    return Err(invalid(key("key_command"), "tunnel endpoints only").into());
+                }
+                if command.first().is_none_or(String::is_empty) {
+                    let reason = "must start with a program";
+                    return Err(invalid(key("key_command"), reason).into());
+                }
+                // These use the secret itself, or other secrets than the endpoint's
+                let quic = endpoint.transport.unwrap_or_default() == TransportKind::Quic;
+                let uses = [
+                    ("secret", endpoint.secret.is_some()),
+                    ("tickets", endpoint.tickets.is_some()),
+                    ("silent", endpoint.silent == Some(true)),
+                    ("totp_seed", endpoint.totp_seed.is_some()),
+                    ("knock_port", endpoint.knock_port.is_some()),
+                    ("via", endpoint.via.is_some()),
+                    ("hops", endpoint.hops.is_some()),
+                    ("punch_port", endpoint.punch_port.is_some()),
+                    ("rendezvous", endpoint.rendezvous.is_some()),
+                    ("transport = \"quic\"", quic),
+                ];
+                if let Some((other, _)) = uses.iter().find(|(_, used)| *used) {
+                    let reason = format!("can't be used with {}", other);
+                    return Err(invalid(key("key_command"), &reason).into());
+                }
+            }
+            if endpoint.heartbeat.is_some()
+                && (!matches!(endpoint.kind, ConnectionType::Tunnel)
+                    || matches!(endpoint.direction, Direction::Outbound))
+            {
+                return Err(invalid(key("heartbeat"), "inbound tunnel endpoints only").into());
+            }
+            if endpoint.heartbeat == Some(0) {
+                return Err(invalid(key("heartbeat"), "must be greater than 0").into());
+            }
+            if endpoint.registry == Some(true)
+                && (!matches!(endpoint.kind, ConnectionType::Tunnel)
+                    || matches!(endpoint.direction, Direction::Outbound)
+                    || endpoint.transport.unwrap_or_default() != TransportKind::Tcp
+                    || endpoint.bonding.is_some()
+                    || endpoint.resume.is_some())
+            {
+                let reason = "inbound TCP tunnel endpoints only";
+                return Err(invalid(key("registry"), reason).into());
+            }
+            if let Some(service) = &endpoint.service {
+                if !matches!(endpoint.kind, ConnectionType::Tunnel)
+                    || matches!(endpoint.direction, Direction::Inbound)
+                {
+                    return Err(invalid(key("service"), "outbound tunnel endpoints only").into());
+                }
+                if service.is_empty() || service.len() > MAX_SERVICE_LEN {
+                    let reason = format!("must be 1 to {} bytes", MAX_SERVICE_LEN);
+                    return Err(invalid(key("service"), &reason).into());
+                }
+            }
+            if let Some(via) = &endpoint.via {
+                if !matches!(endpoint.kind, ConnectionType::Tunnel)
+                    || matches!(endpoint.direction, Direction::Inbound)
+                    || endpoint.transport.unwrap_or_default() != TransportKind::Tcp
+                    || endpoint.bonding.is_some()
+                    || endpoint.resume.is_some()
+                    || endpoint.obfuscation.is_some()
+                {
+                    let reason = "outbound TCP tunnel endpoints only";
+                    return Err(invalid(key("via"), reason).into());
+                }
+                // Every hop is an outbound tunnel, and none comes back to an earlier one
+                let mut seen = HashSet::from([name.as_str()]);
+                let mut hop = Some(via);
+                while let Some(hop_name) = hop {
+                    let Some(next) = self.endpoints.get(hop_name) else {
+                        let reason = format!("no endpoint named '{}'", hop_name);
+                        return Err(invalid(key("via"), &reason).into());
+                    };
+                    if !matches!(next.kind, ConnectionType::Tunnel)
+                        || matches!(next.direction, Direction::Inbound)
+                    {
+                        let reason = format!("'{}' isn't an outbound tunnel", hop_name);
+                        return Err(invalid(key("via"), &reason).into());
+                    }
+                    if !seen.insert(hop_name) {
+                        let reason = format!("loops back through '{}'", hop_name);
+                        return Err(invalid(key("via"), &reason).into());
+                    }
+                    hop = next.via.as_ref();
+                }
+            }
+            if let Some(hops) = &endpoint.hops {
+                if !matches!(endpoint.kind, ConnectionType::Tunnel)
+                    || matches!(endpoint.direction, Direction::Inbound)
+                {
+                    return Err(invalid(key("hops"), "outbound tunnel endpoints only").into());
+                }
+                if endpoint.via.is_some() {
+                    return Err(invalid(key("hops"), "can't be used with via").into());
+                }
+                if hops.is_empty() || hops.iter().any(String::is_empty) {
+                    return Err(invalid(key("hops"), "must be a list of secrets").into());
+                }
+                if endpoint
+                    .cipher
+                    .is_some_and(|cipher| cipher != CipherKind::ChaCha20Poly1305)
+                {
+                    let reason = "hops are sealed with chacha20-poly1305";
+                    return Err(invalid(key("cipher"), reason).into());
+                }
+            }
+            let tcp_tunnel = matches!(endpoint.kind, ConnectionType::Tunnel)
+                && endpoint.transport.unwrap_or_default() == TransportKind::Tcp
+                && endpoint.bonding.is_none()
+                && endpoint.resume.is_none();
+            if let Some(port) = endpoint.punch_port {
+                if !tcp_tunnel || matches!(endpoint.direction, Direction::Inbound) {
+                    let reason = "outbound TCP tunnel endpoints only";
+                    return Err(invalid(key("punch_port"), reason).into());
+                }
+                if endpoint.via.is_some() || endpoint.hops.is_some() {
+                    let reason = "can't be used with via or hops";
+                    return Err(invalid(key("punch_port"), reason).into());
+                }
+                if endpoint.e2e_secret.is_none() {
+                    return Err(invalid(key("punch_port"), "needs e2e_secret").into());
+                }
+                if port == 0 {
+                    return Err(invalid(key("punch_port"), "must be greater than 0").into());
+                }
+            }
+            if let Some(port) = endpoint.knock_port {
+                if !tcp_tunnel {
+                    return Err(invalid(key("knock_port"), "TCP tunnel endpoints only").into());
+                }
+                let elsewhere = endpoint.via.is_some()
+                    || endpoint.hops.is_some()
+                    || endpoint.punch_port.is_some()
+                    || endpoint.rendezvous.is_some();
+                if elsewhere {
+                    let reason = "can't be used with via, hops, punch_port or rendezvous";
+                    return Err(invalid(key("knock_port"), reason).into());
+                }
+                if port == 0 {
+                    return Err(invalid(key("knock_port"), "must be greater than 0").into());
+                }
+            }
+            if endpoint.knock_window.is_some()
+                && (endpoint.knock_port.is_none()
+                    || matches!(endpoint.direction, Direction::Outbound))
+            {
+                let reason = "inbound endpoints with a knock_port only";
+                return Err(invalid(key("knock_window"), reason).into());
+            }
+            if endpoint.knock_window == Some(0) {
+                return Err(invalid(key("knock_window"), "must be greater than 0").into());
+            }
+            if endpoint.knock_source.is_some()
+                && (endpoint.knock_port.is_none()
+                    || matches!(endpoint.direction, Direction::Inbound))
+            {
+                let reason = "outbound endpoints with a knock_port only";
+                return Err(invalid(key("knock_source"), reason).into());
+            }
+            if let Some(route) = &endpoint.rendezvous {
+                if !tcp_tunnel
+                    || matches!(endpoint.direction, Direction::Outbound)
+                    || endpoint.obfuscation.is_some()
+                    || endpoint.registry.is_some()
+                {
+                    let reason = "inbound TCP tunnel endpoints only";
+                    return Err(invalid(key("rendezvous"), reason).into());
+                }
+                if endpoint.e2e_secret.is_none() {
+                    return Err(invalid(key("rendezvous"), "needs e2e_secret").into());
+                }
+                if route.is_empty() {
+                    return Err(invalid(key("rendezvous"), "must not be empty").into());
+                }
+                if endpoint.port == 0 {
+                    let reason = "the relay's control port must be given";
+                    return Err(invalid(key("port"), reason).into());
+                }
+            }
+            for (field, value) in timeouts {
+                if value.is_some() && !matches!(endpoint.kind, ConnectionType::Tunnel) {
+                    return Err(invalid(key(field), "tunnel endpoints only").into());
+                }
+                if value == Some(0) {
+                    return Err(invalid(key(field), "must be greater than 0").into());
+                }
+            }
+            if let Some(secret) = &endpoint.e2e_secret {
+                if !matches!(endpoint.kind, ConnectionType::Tunnel) {
+                    return Err(invalid(key("e2e_secret"), "tunnel endpoints only").into());
+                }
+                if secret.is_empty() {
+                    return Err(invalid(key("e2e_secret"), "must not be empty").into());
+                }
+                if !endpoint.blindable() {
+                    let reason = "needs a stream cipher and no padding";
+                    return Err(invalid(key("e2e_secret"), reason).into());
+                }
+            }
+            if let Some(advertise) = &endpoint.advertise {
+                if matches!(endpoint.kind, ConnectionType::Tunnel)
+                    || matches!(endpoint.direction, Direction::Outbound)
+                {
+                    let reason = "inbound direct or auto endpoints only";
+                    return Err(invalid(key("advertise"), reason).into());
+                }
+                // A single DNS label
+                if advertise.is_empty() || advertise.len() > 63 {
+                    return Err(invalid(key("advertise"), "must be 1 to 63 bytes").into());
+                }
+            }
+            if let Some(ty) = &endpoint.advertise_type {
+                if endpoint.advertise.is_none() {
+                    return Err(invalid(key("advertise_type"), "needs advertise").into());
+                }
+                let name = ty.strip_prefix('_').and_then(|t| t.strip_suffix("._tcp"));
+                let valid = name.is_some_and(|name| {
+                    (1..=15).contains(&name.len())
+                        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+                });
+                if !valid {
+                    let reason = "must look like \"_name._tcp\"";
+                    return Err(invalid(key("advertise_type"), reason).into());
+                }
+            }
+            if endpoint.port_mapping.is_some() {
+                if matches!(endpoint.direction, Direction::Outbound) {
+                    return Err(invalid(key("port_mapping"), "inbound endpoints only").into());
+                }
+                if endpoint.rendezvous.is_some() {
+                    let reason = "rendezvous endpoints don't listen";
+                    return Err(invalid(key("port_mapping"), reason).into());
+                }
+                if endpoint.port == 0 {
+                    return Err(invalid(key("port_mapping"), "needs a port").into());
+                }
+            }
+            let mapping = [
+                ("mapping_lease", endpoint.mapping_lease.is_some()),
+                ("external_port", endpoint.external_port.is_some()),
+                ("mapping_gateway", endpoint.mapping_gateway.is_some()),
+            ];
+            if let Some((field, _)) = mapping.iter().find(|(_, set)| *set) {
+                if endpoint.port_mapping.is_none() {
+                    return Err(invalid(key(field), "needs port_mapping").into());
+                }
+            }
+            if endpoint.mapping_lease.is_some_and(|lease| lease < 60) {
+                return Err(invalid(key("mapping_lease"), "must be at least 60").into());
+            }
+            if endpoint.external_port == Some(0) {
+                return Err(invalid(key("external_port"), "must be greater than 0").into());
+            }
+            if let Some(max) = endpoint.max_conns_per_ip {
+                if !matches!(endpoint.kind, ConnectionType::Direct | ConnectionType::Auto)
+                    || matches!(endpoint.direction, Direction::Outbound)
+                {
+                    let reason = "inbound direct or auto endpoints only";
+                    return Err(invalid(key("max_conns_per_ip"), reason).into());
+                }
+                if max == 0 {
+                    return Err(invalid(key("max_conns_per_ip"), "must be greater than 0").into());
+                }
+            }
+            let tls = [
+                ("tls_cert", &endpoint.tls_cert, "tls_key"),
+                ("tls_key", &endpoint.tls_key, "tls_cert"),
+            ];
+            for (field, value, other) in tls {
+                let Some(value) = value else {
+                    continue;
+                };
+                if !matches!(endpoint.kind, ConnectionType::Direct | ConnectionType::Auto)
+                    || matches!(endpoint.direction, Direction::Outbound)
+                {
+                    let reason = "inbound direct or auto endpoints only";
+                    return Err(invalid(key(field), reason).into());
+                }
+                if value.is_empty() {
+                    return Err(invalid(key(field), "must not be empty").into());
+                }
+                if endpoint.tls_cert.is_none() || endpoint.tls_key.is_none() {
+                    return Err(invalid(key(field), &format!("needs {}", other)).into());
+                }
+            }
+            if endpoint.tls.is_some()
+                && !(matches!(endpoint.kind, ConnectionType::Direct)
+                    && matches!(endpoint.direction, Direction::Outbound))
+            {
+                return Err(invalid(key("tls"), "direct outbound endpoints only").into());
+            }
+            if let Some(ca) = &endpoint.tls_ca {
+                if endpoint.tls != Some(true) {
+                    return Err(invalid(key("tls_ca"), "needs tls").into());
+                }
+                if ca.is_empty() {
+                    return Err(invalid(key("tls_ca"), "must not be empty").into());
+                }
+            }
+            if let Some(pin) = &endpoint.pinned_sha256 {
+                if endpoint.tls != Some(true) {
+                    return Err(invalid(key("pinned_sha256"), "needs tls").into());
+                }
+                if endpoint.tls_ca.is_some() {
+                    let reason = "can't be used with tls_ca";
+                    return Err(invalid(key("pinned_sha256"), reason).into());
+                }
+                if sha256_from_hex(pin).is_none() {
+                    let reason = "must be a SHA-256 digest in hex";
+                    return Err(invalid(key("pinned_sha256"), reason).into());
+                }
+            }
+            let honeypot = matches!(endpoint.kind, ConnectionType::Honeypot);
+            if honeypot && matches!(endpoint.direction, Direction::Outbound) {
+                return Err(invalid(key("direction"), "honeypots are inbound only").into());
+            }
+            if endpoint.capture.is_some() && !honeypot {
+                return Err(invalid(key("capture"), "honeypot endpoints only").into());
+            }
+            if endpoint.secret.as_deref() == Some("") {
+                return Err(invalid(key("secret"), "must not be empty").into());
+            }
+        }
+
+        // One socket per address, several routes share one through an auto endpoint
+        let mut inbound: Vec<_> = self
+            .endpoints
+            .iter()
+            .filter(|(_, e)| {
+                // Rendezvous endpoints' port is the relay's
+                matches!(e.direction, Direction::Inbound) && e.port != 0 && e.rendezvous.is_none()
+            })
+            .collect();
+        inbound.sort_by_key(|(name, _)| *name);
+        for (idx, (name, endpoint)) in inbound.iter().enumerate() {
+            if let Some((other, _)) = inbound[..idx].iter().find(|(_, e)| e.overlaps(endpoint)) {
+                let reason = format!(
+                    "listens where '{}' does, share it with an endpoint of type \"auto\"",
+                    other
+                );
+                return Err(invalid(format!("endpoints.{}.port", name), &reason).into());
+            }
+        }
+
+        let mut names = HashSet::new();
+        let mut stdio_taken = false;
+        for (idx, route) in self.routes.iter().enumerate() {
+            let key = |field: &str| format!("routes[{}].{}", idx, field);
+            if let Some(name) = &route.name {
+                if name.is_empty() || name.contains(char::is_whitespace) {
+                    return Err(invalid(key("name"), "must be a single word").into());
+                }
+                if !names.insert(name) {
+                    return Err(invalid(key("name"), "is used by another route").into());
+                }
+            }
+            match (route.min_size, route.max_size) {
+                (None, None) if route.size == 0 => {
+                    return Err(invalid(key("size"), "must be greater than 0").into());
+                }
+                (None, None) => {}
+                (None, Some(_)) => return Err(invalid(key("max_size"), "needs min_size").into()),
+                (Some(_), None) => return Err(invalid(key("min_size"), "needs max_size").into()),
+                (Some(_), Some(_)) if route.size != 0 => {
+                    let reason = "autoscaled routes take min_size and max_size instead";
+                    return Err(invalid(key("size"), reason).into());
+                }
+                (Some(0), Some(_)) => {
+                    return Err(invalid(key("min_size"), "must be greater than 0").into());
+                }
+                (Some(min), Some(max)) if max < min => {
+                    return Err(invalid(key("max_size"), "must be at least min_size").into());
+                }
+                (Some(_), Some(_)) => {}
+            }
+            if route.max_sessions == Some(0) {
+                return Err(invalid(key("max_sessions"), "must be greater than 0").into());
+            }
+            if route.max_session_duration == Some(0) {
+                let reason = "must be greater than 0";
+                return Err(invalid(key("max_session_duration"), reason).into());
+            }
+            for name in &route.endpoints {
+                if !self.endpoints.contains_key(name) {
+                    let reason = format!("no endpoint named '{}'", name);
+                    return Err(invalid(key("endpoints"), &reason).into());
+                }
+            }
+            let [a, b] = route.endpoints.each_ref().map(|name| &self.endpoints[name]);
+            if [a, b]
+                .iter()
+                .any(|e| matches!(e.kind, ConnectionType::Honeypot))
+            {
+                let reason = "honeypot endpoints take no routes";
+                return Err(invalid(key("endpoints"), reason).into());
+            }
+            let stdio = [a, b].map(|e| matches!(e.kind, ConnectionType::Stdio));
+            if stdio.contains(&true) {
+                if stdio_taken || stdio == [true, true] {
+                    let reason = "stdin and stdout are one end of a single route";
+                    return Err(invalid(key("endpoints"), reason).into());
+                }
+                stdio_taken = true;
+            }
+            for (field, values) in [("sni", &route.sni), ("alpn", &route.alpn)] {
+                let Some(values) = values else {
+                    continue;
+                };
+                if ![a, b]
+                    .iter()
+                    .any(|e| matches!(e.kind, ConnectionType::Auto))
+                {
+                    return Err(invalid(key(field), "routes on auto endpoints only").into());
+                }
+                if values.is_empty() || values.iter().any(String::is_empty) {
+                    return Err(invalid(key(field), "must be a list of names").into());
+                }
+                if route.protocol.is_some_and(|p| p != Protocol::Tls) {
+                    let reason = "TLS clients only, protocol must be tls or unset";
+                    return Err(invalid(key(field), reason).into());
+                }
+            }
+            let tunnels = [a, b].map(|e| matches!(e.kind, ConnectionType::Tunnel));
+            if tunnels == [true, true] && (a.e2e_secret.is_some() || b.e2e_secret.is_some()) {
+                let reason = "end-to-end tunnels can't be joined to other tunnels";
+                return Err(invalid(key("endpoints"), reason).into());
+            }
+            if route.balance == Some(true)
+                && (!tunnels[0] || matches!(a.direction, Direction::Outbound))
+            {
+                let reason = "the first endpoint must be an inbound tunnel";
+                return Err(invalid(key("balance"), reason).into());
+            }
+            if let Some(weights) = &route.weights {
+                if route.balance != Some(true) {
+                    return Err(invalid(key("weights"), "needs balance").into());
+                }
+                if weights.values().any(|weight| *weight == 0) {
+                    return Err(invalid(key("weights"), "must be greater than 0").into());
+                }
+            }
+            let registry = [a, b].iter().any(|e| e.registry == Some(true));
+            let announcing = [a, b].iter().any(|e| {
+                matches!(e.kind, ConnectionType::Tunnel)
+                    && matches!(e.direction, Direction::Outbound)
+            });
+            match &route.service {
+                Some(_) if !registry && !announcing => {
+                    let reason = "needs a registry endpoint or an outbound tunnel";
+                    return Err(invalid(key("service"), reason).into());
+                }
+                Some(service) if service.is_empty() => {
+                    return Err(invalid(key("service"), "must not be empty").into());
+                }
+                Some(service) if announcing && service.len() > MAX_SERVICE_LEN => {
+                    let reason = format!("must be 1 to {} bytes", MAX_SERVICE_LEN);
+                    return Err(invalid(key("service"), &reason).into());
+                }
+                None if registry => {
+                    let reason = "routes on a registry endpoint need one";
+                    return Err(invalid(key("service"), reason).into());
+                }
+                _ => {}
+            }
+            if route.blind == Some(true) {
+                if tunnels != [true, true] {
+                    return Err(invalid(key("blind"), "both endpoints must be tunnels").into());
+                }
+                if !a.blindable() || !b.blindable() {
+                    let reason = "the tunnels need a stream cipher and no padding";
+                    return Err(invalid(key("blind"), reason).into());
+                }
+            }
+        }
+
+        if let Some(control) = &self.control {
+            for (idx, export) in control.exports.iter().enumerate() {
+                let key = |field: &str| format!("control.exports[{}].{}", idx, field);
+                if export.size == 0 {
+                    return Err(invalid(key("size"), "must be greater than 0").into());
+                }
+                if export.port == 0 {
+                    return Err(invalid(key("port"), "must be given").into());
+                }
+                if export.tunnel_port == 0 {
+                    return Err(invalid(key("tunnel_port"), "must be given").into());
+                }
+                if export.agent_secret.as_deref() == Some("") {
+                    return Err(invalid(key("agent_secret"), "must not be empty").into());
+                }
+                match export.punch_port {
+                    Some(_) if export.agent_secret.is_none() => {
+                        return Err(invalid(key("punch_port"), "needs agent_secret").into());
+                    }
+                    Some(0) => {
+                        return Err(invalid(key("punch_port"), "must be greater than 0").into());
+                    }
+                    _ => {}
+                }
+            }
+        }
+        if let Some(relay) = &self.relay {
+            if relay.port == 0 {
+                return Err(invalid("relay.port".to_owned(), "must be given").into());
+            }
+            for (idx, service) in relay.services.iter().enumerate() {
+                let key = |field: &str| format!("relay.services[{}].{}", idx, field);
+                if service.name.is_some() == service.port.is_some() {
+                    return Err(invalid(key("name"), "give either name or port").into());
+                }
+                if service.size == Some(0) {
+                    return Err(invalid(key("size"), "must be greater than 0").into());
+                }
+            }
+            for (idx, expose) in relay.expose.iter().enumerate() {
+                if expose.size == 0 {
+                    let key = format!("relay.expose[{}].size", idx);
+                    return Err(invalid(key, "must be greater than 0").into());
+                }
+            }
+        }
+        if let Some(agent) = &self.agent {
+            if agent.secret.is_empty() {
+                return Err(invalid("agent.secret".to_owned(), "must not be empty").into());
+            }
+            if agent.e2e_secret.is_empty() {
+                return Err(invalid("agent.e2e_secret".to_owned(), "must not be empty").into());
+            }
+            for (idx, service) in agent.services.iter().enumerate() {
+                let key = |field: &str| format!("agent.services[{}].{}", idx, field);
+                if service.size == 0 {
+                    return Err(invalid(key("size"), "must be greater than 0").into());
+                }
+                if service.port == 0 {
+                    return Err(invalid(key("port"), "must be given").into());
+                }
+                if service.punch_port == Some(0) {
+                    return Err(invalid(key("punch_port"), "must be greater than 0").into());
+                }
+            }
+        }
+        if self.user.as_deref() == Some("") {
+            return Err(invalid("user".to_owned(), "must not be empty").into());
+        }
+        if self.group.as_deref() == Some("") {
+            return Err(invalid("group".to_owned(), "must not be empty").into());
+        }
+        if let Some(dashboard) = &self.dashboard {
+            if dashboard.password.as_deref() == Some("") {
+                return Err(invalid("dashboard.password".to_owned(), "must not be empty").into());
+            }
+            // Sessions can be killed and bans lifted from it, by anyone reaching it otherwise
+            let loopback = match dashboard.listen.parse::<SocketAddr>() {
+                Ok(addr) => addr.ip().is_loopback(),
+                Err(_) => dashboard.listen.starts_with("localhost:"),
+            };
+            if dashboard.password.is_none() && !loopback {
+                let reason = "needs a password unless on loopback";
+                return Err(invalid("dashboard.listen".to_owned(), reason).into());
+            }
+        }
+        let ban = self.security.as_ref().and_then(|s| s.ban.as_ref());
+        if let Some(ban) = ban {
+            if ban.command.first().is_none_or(String::is_empty) {
+                let reason = "must start with a program";
+                return Err(invalid("security.ban.command".to_owned(), reason).into());
+            }
+            if let Some(unban) = &ban.unban_command {
+                if unban.first().is_none_or(String::is_empty) {
+                    let reason = "must start with a program";
+                    return Err(invalid("security.ban.unban_command".to_owned(), reason).into());
+                }
+            }
+            // Programs can't be run from the sandbox
+            let sandboxed = self
+                .sandbox
+                .as_ref()
+                .is_some_and(|sandbox| sandbox.landlock == Some(true) || sandbox.seccomp.is_some());
+            if sandboxed {
+                let reason = "can't be run with landlock or seccomp";
+                return Err(invalid("security.ban.command".to_owned(), reason).into());
+            }
+        }
+        if let Some(tarpit) = self.security.as_ref().and_then(|s| s.tarpit.as_ref()) {
+            if tarpit.max == 0 {
+                return Err(
+                    invalid("security.tarpit.max".to_owned(), "must be greater than 0").into(),
+                );
+            }
+            if tarpit.interval == Some(0) {
+                let reason = "must be greater than 0";
+                return Err(invalid("security.tarpit.interval".to_owned(), reason).into());
+            }
+        }
+        if let Some(limits) = &self.limits {
+            if limits.max_sessions == Some(0) {
+                let reason = "must be greater than 0";
+                return Err(invalid("limits.max_sessions".to_owned(), reason).into());
+            }
+            if limits.fd_headroom == Some(0) {
+                let reason = "must be greater than 0";
+                return Err(invalid("limits.fd_headroom".to_owned(), reason).into());
+            }
+            let mut priorities: Vec<_> = self
+                .routes
+                .iter()
+                .map(|r| r.priority.unwrap_or(0))
+                .collect();
+            priorities.sort();
+            priorities.dedup();
+            let higher = priorities.len().saturating_sub(1);
+            match (limits.reserved, limits.max_sessions) {
+                (Some(_), None) => {
+                    let reason = "needs max_sessions";
+                    return Err(invalid("limits.reserved".to_owned(), reason).into());
+                }
+                (Some(reserved), Some(max)) if reserved * higher >= max => {
+                    let reason = "must leave sessions to the lowest priority";
+                    return Err(invalid("limits.reserved".to_owned(), reason).into());
+                }
+                _ => {}
+            }
+            if limits.reclaim_idle == Some(0) {
+                let reason = "must be greater than 0";
+                return Err(invalid("limits.reclaim_idle".to_owned(), reason).into());
+            }
+        }
+        if let Some(log) = &self.log {
+            if log.max_bytes == Some(0) {
+                let reason = "must be greater than 0";
+                return Err(invalid("log.max_bytes".to_owned(), reason).into());
+            }
+            if log.keep == Some(0) {
+                let reason = "must be greater than 0";
+                return Err(invalid("log.keep".to_owned(), reason).into());
+            }
+        }
+        if let Some(otel) = &self.otel {
+            if !otel.endpoint.starts_with("http://") {
+                let reason = "must be an http:// URL";
+                return Err(invalid("otel.endpoint".to_owned(), reason).into());
+            }
+            if otel.interval == Some(0) {
+                let reason = "must be greater than 0";
+                return Err(invalid("otel.interval".to_owned(), reason).into());
+            }
+        }
+        if let Some(sandbox) = &self.sandbox {
+            let paths = [("read", &sandbox.read), ("write", &sandbox.write)];
+            for (field, paths) in paths {
+                if !paths.is_empty() && sandbox.landlock != Some(true) {
+                    let key = format!("sandbox.{}", field);
+                    return Err(invalid(key, "needs landlock").into());
+                }
+                for (idx, path) in paths.iter().enumerate() {
+                    if !Path::new(path).is_absolute() {
+                        let key = format!("sandbox.{}[{}]", field, idx);
+                        return Err(invalid(key, "must be an absolute path").into());
+                    }
+                }
+            }
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,125 @@
+use crate::{
+    config::{self, Affinity, ConnectionType, Direction, Endpoint, VeloxidConfig},
+    error::StartupError,
+    relay::connection::{self, RouteContext},
+    table::{self, AGENT_PREFIX},
+    transport::stdio::{self, Stdio},
+};
+use anyhow::{anyhow, Result};
+use log::{debug, LevelFilter};
+use std::sync::Arc;
+use tokio::{sync::Semaphore, time::Duration};
+
+pub const USAGE: &str = "veloxid connect <route>";
+
+// The route by name or index, agent services by their name as well
+pub fn find_route(config: &VeloxidConfig, route: &str) -> Result<usize> {
+    let names = [route.to_owned(), format!("{}{}", AGENT_PREFIX, route)];
+    let mut labels = config
+        .routes
+        .iter()
+        .enumerate()
+        .map(|(idx, r)| r.label(idx));
+    if let Some(idx) = labels.position(|label| names.contains(&label)) {
+        return Ok(idx);
+    }
+    route
+        .trim_start_matches('#')
+        .parse::<usize>()
+        .ok()
+        .filter(|idx| *idx < config.routes.len())
+        .ok_or(anyhow!("No such route '{}'", route))
+}
+
+// The endpoint stdin and stdout stand in for, and the one connected to: routes from a plain
+// inbound endpoint to an outbound one only
+pub fn endpoints(config: &VeloxidConfig, idx: usize) -> Result<[&str; 2]> {
+    let route = &config.routes[idx];
+    let [a, b] = route
+        .endpoints
+        .each_ref()
+        .map(|name| &config.endpoints[name]);
+    let client = matches!(a.kind, ConnectionType::Direct | ConnectionType::Auto)
+        && matches!(a.direction, Direction::Inbound);
+    let target =
+        matches!(b.direction, Direction::Outbound) && !matches!(b.kind, ConnectionType::Stdio);
+    if !client || !target {
+        return Err(anyhow!(
+            "'{}' doesn't take clients to an outbound endpoint",
+            route.label(idx)
+        ));
+    }
+    Ok(route.endpoints.each_ref().map(String::as_str))
+}
+
+// A route of its own, with one session at a time, for the subcommands using the endpoint
+pub fn context(endpoint: &Endpoint, affinity: Affinity) -> RouteContext {
+    RouteContext {
+        ban_list: Default::default(),
+        events: Default::default(),
+        registry: Default::default(),
+        latency: Default::default(),
+        schedule: None,
+        affinity,
+        buffer_size: endpoint.buffer_size,
+        write_timeout: endpoint.write_timeout.map(Duration::from_secs),
+        max_in_flight: endpoint.max_in_flight,
+        blind: false,
+        sessions: Arc::new(Semaphore::new(1)),
+        max_session_duration: None,
+        budget: None,
+        priority: 0,
+        balancer: None,
+        tarpit: None,
+        scaling: None,
+        name: String::new(),
+        endpoints: Default::default(),
+        #[cfg(feature = "tap")]
+        tap: None,
+    }
+}
+
+// One session over a route with stdin and stdout as its client, like "nc" or "ssh -W". As an
+// ssh ProxyCommand, reaches the SSH server through the tunnel without listening anywhere.
+pub async fn run(args: &[String]) -> Result<()> {
+    let [route] = args else {
+        return Err(anyhow!("Usage: {}", USAGE));
+    };
+    let config_path = &std::env::var("VELOXID_CONFIG").unwrap_or("veloxid.toml".to_owned());
+    let mut config = VeloxidConfig::load(config_path).map_err(StartupError::Config)?;
+    table::add_agent(&mut config).map_err(StartupError::Config)?;
+    let idx = find_route(&config, route)?;
+    let [_, far] = endpoints(&config, idx)?;
+    let route = &config.routes[idx];
+
+    // Stdout is the session's, the log goes to stderr and only warnings unless asked for more
+    env_logger::builder()
+        .filter_level(match route.log_level {
+            Some(_) => config::level_filter(route.log_level),
+            None => LevelFilter::Warn,
+        })
+        .target(env_logger::Target::Stderr)
+        .init();
+
+    let endpoint = &config.endpoints[far];
+    let mut data = connection::get_chained_connection_data(far, &config.endpoints).await?;
+    if let Some(service) = &route.service {
+        data = data.announcing(service);
+    }
+    let ctx = context(endpoint, route.affinity.unwrap_or_default());
+    let log_target = format!("{} connect", route.label(idx));
+    let conn = connection::connect(&data, &ctx, None, &log_target, far).await?;
+
+    let stdio = Stdio::take().ok_or(anyhow!("Stdin and stdout are taken already"))?;
+    tokio::select! {
+        result = connection::bridge(stdio, conn, &ctx) => {
+            let traffic = result?;
+            debug!(
+                target: &log_target,
+                "Closed ({} bytes sent, {} bytes received)", traffic.a_to_b, traffic.b_to_a
+            );
+        }
+        _ = stdio::closed() => debug!(target: &log_target, "Closed by '{}'", far),
+    }
+    Ok(())
+}
@@ -0,0 +1,345 @@
+use crate::{
+    admin::AdminState,
+    events::{EventHandler, EventHandlers},
+    sessions::SessionRegistry,
+};
+use anyhow::Result;
+use axum::{
+    extract::{Path, Request, State},
+    http::{header, HeaderMap, Method, StatusCode},
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Router,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Local};
+use dashmap::DashMap;
+use log::info;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Write,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+use subtle::ConstantTimeEq;
+use tokio::{net::TcpListener, time::Instant};
+
+const LOG_TARGET: &str = "dashboard";
+
+// Failed handshakes kept for the page
+const RECENT_FAILURES: usize = 50;
+const REFRESH_SECS: u32 = 5;
+
+struct Connector {
+    route: String,
+    endpoint: String,
+    last_seen: SystemTime,
+}
+
+struct AuthFailure {
+    time: SystemTime,
+    route: String,
+    endpoint: String,
+    peer: IpAddr,
+}
+
+// Tunnel peers and failed handshakes, recorded from the session events
+#[derive(Default)]
+pub struct Activity {
+    connectors: Mutex<HashMap<IpAddr, Connector>>,
+    failures: Mutex<VecDeque<AuthFailure>>,
+}
+
+impl EventHandler for Activity {
+    fn on_handshake_success(&self, route: &str, endpoint: &str, peer: IpAddr) {
+        self.connectors.lock().unwrap().insert(
+            peer,
+            Connector {
+                route: route.to_owned(),
+                endpoint: endpoint.to_owned(),
+                last_seen: SystemTime::now(),
+            },
+        );
+    }
+
+    fn on_auth_failure(&self, route: &str, endpoint: &str, peer: IpAddr) {
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() == RECENT_FAILURES {
+            failures.pop_front();
+        }
+        failures.push_back(AuthFailure {
+            time: SystemTime::now(),
+            route: route.to_owned(),
+            endpoint: endpoint.to_owned(),
+            peer,
+        });
+    }
+
+    fn on_auth_rejected(&self, route: &str, endpoint: &str, peer: IpAddr) {
+        self.on_auth_failure(route, endpoint, peer);
+    }
+}
+
+// Everything the dashboard shows and acts on
+pub struct DashboardState {
+    pub admin: Arc<AdminState>,
+    pub registry: SessionRegistry,
+    pub ban_list: Arc<DashMap<IpAddr, Instant>>,
+    pub activity: Arc<Activity>,
+    // on_unban for lifted bans
+    pub events: EventHandlers,
+    // HTTP basic auth, any user name
+    pub password: Option<String>,
+}
+
+// Serve the dashboard:
+// GET  /                      -> the page, refreshing itself
+// POST /sessions/{id}/kill    -> end a session
+// POST /bans/{ip}/unban       -> lift a ban
+pub async fn serve(listener: TcpListener, state: Arc<DashboardState>) -> Result<()> {
+    let app = Router::new()
+        .route("/", get(index))
+        .route("/sessions/{id}/kill", post(kill))
+        .route("/bans/{ip}/unban", post(unban))
+        .layer(middleware::from_fn_with_state(state.clone(), authorize))
+        .with_state(state);
+
+    info!(target: LOG_TARGET, "Listening on {}", listener.local_addr()?);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+async fn authorize(
+    State(state): State<Arc<DashboardState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if let Some(password) = &state.password {
+        if !has_password(request.headers(), password) {
+            return (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, "Basic realm=\"veloxid\"")],
+            )
+                .into_response();
+        }
+    }
+
+    // Actions only from the page itself, browsers send the header along with every request
+    let cross_site = matches!(
+        request
+            .headers()
+            .get("sec-fetch-site")
+            .and_then(|v| v.to_str().ok()),
+        Some("cross-site" | "same-site")
+    );
+    if request.method() == Method::POST && cross_site {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    next.run(request).await
+}
+
+fn has_password(headers: &HeaderMap, password: &str) -> bool {
+    let credentials = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Basic "))
+        .and_then(|v| STANDARD.decode(v).ok())
+        .and_then(|v| String::from_utf8(v).ok());
+    match credentials.as_deref().and_then(|c| c.split_once(':')) {
+        Some((_, given)) => given.as_bytes().ct_eq(password.as_bytes()).into(),
+        None => false,
+    }
+}
+
+async fn kill(State(state): State<Arc<DashboardState>>, Path(id): Path<u64>) -> Response {
+    match state.registry.kill(id) {
+        true => {
+            info!(target: LOG_TARGET, "Session #{} killed", id);
+            Redirect::to("/").into_response()
+        }
+        false => (StatusCode::NOT_FOUND, "No such session").into_response(),
+    }
+}
+
+async fn unban(State(state): State<Arc<DashboardState>>, Path(ip): Path<IpAddr>) -> Response {
+    match state.ban_list.remove(&ip) {
+        Some(_) => {
+            info!(target: LOG_TARGET, "{} unbanned", ip);
+            state.events.on_unban(ip);
+            Redirect::to("/").into_response()
+        }
+        None => (StatusCode::NOT_FOUND, "Not banned").into_response(),
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn time(time: SystemTime) -> String {
+    DateTime::<Local>::from(time)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
+
+fn peer(addr: Option<std::net::SocketAddr>) -> String {
+    addr.map_or("-".to_owned(), |a| a.to_string())
+}
+
+async fn index(State(state): State<Arc<DashboardState>>) -> Html<String> {
+    let mut page = String::new();
+    let _ = write!(
+        page,
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+         <meta http-equiv=\"refresh\" content=\"{}\"><title>veloxid</title>\
+         <style>body{{font-family:sans-serif}}table{{border-collapse:collapse;margin-bottom:2em}}\
+         td,th{{border:1px solid #ccc;padding:4px 8px;text-align:left}}</style></head><body>",
+        REFRESH_SECS
+    );
+
+    // Routes
+    page.push_str(
+        "<h2>Routes</h2><table><tr><th>#</th><th>Name</th><th>State</th><th>Endpoints</th>\
+         <th>Sessions</th></tr>",
+    );
+    for (idx, route) in state.admin.routes.iter().enumerate() {
+        let _ = write!(
+            page,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{} &rarr; {}</td><td>{}</td></tr>",
+            idx,
+            escape(&route.name),
+            match *route.enabled.borrow() {
+                true => "enabled",
+                false => "disabled",
+            },
+            escape(&route.endpoints[0]),
+            escape(&route.endpoints[1]),
+            state.registry.route_sessions(&route.name)
+        );
+    }
+    page.push_str("</table>");
+    if let Some(budget) = &state.admin.budget {
+        let stats = budget.stats();
+        let or_none = |n: Option<usize>| n.map_or("-".to_owned(), |n| n.to_string());
+        let (open, limit) = stats.descriptors.unzip();
+        let _ = write!(
+            page,
+            "<p>Limits: {} of {} sessions, {} of {} file descriptors, accepting paused {} times</p>",
+            stats.sessions,
+            or_none(stats.max_sessions),
+            or_none(open),
+            or_none(limit),
+            stats.pauses
+        );
+    }
+
+    // Sessions
+    let total = state.registry.traffic();
+    let _ = write!(
+        page,
+        "<h2>Sessions</h2><p>{} bytes A&rarr;B, {} bytes B&rarr;A</p><table><tr><th>#</th>\
+         <th>Route</th><th>A</th><th>B</th><th>Started</th><th>Closes</th><th>A&rarr;B</th>\
+         <th>B&rarr;A</th><th></th></tr>",
+        total.a_to_b, total.b_to_a
+    );
+    for session in state.registry.snapshot() {
+        let (info, traffic) = (&session.info, session.traffic);
+        let _ = write!(
+            page,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td>\
+             <td>{}</td><td><form method=\"post\" action=\"/sessions/{}/kill\"><button>Kill</button>\
+             </form></td></tr>",
+            info.id,
+            escape(&info.route),
+            peer(info.peer_a),
+            peer(info.peer_b),
+            time(info.started),
+            session.deadline.map_or("-".to_owned(), time),
+            traffic.a_to_b,
+            traffic.b_to_a,
+            info.id
+        );
+    }
+    page.push_str("</table>");
+
+    // Connectors
+    page.push_str(
+        "<h2>Connectors</h2><table><tr><th>Peer</th><th>Route</th><th>Endpoint</th>\
+         <th>Last handshake</th></tr>",
+    );
+    for (addr, connector) in state.activity.connectors.lock().unwrap().iter() {
+        let _ = write!(
+            page,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            addr,
+            escape(&connector.route),
+            escape(&connector.endpoint),
+            time(connector.last_seen)
+        );
+    }
+    page.push_str("</table>");
+
+    // Round trips of the inbound tunnels
+    let millis = |rtt: Option<std::time::Duration>| {
+        rtt.map_or("-".to_owned(), |rtt| {
+            format!("{:.1} ms", rtt.as_secs_f64() * 1000.0)
+        })
+    };
+    page.push_str(
+        "<h2>Tunnels</h2><table><tr><th>Route</th><th>Endpoint</th><th>Peer</th><th>RTT</th>\
+         <th>Smoothed</th><th>Min</th></tr>",
+    );
+    for tunnel in state.admin.latency.list() {
+        let _ = write!(
+            page,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            escape(&tunnel.route),
+            escape(&tunnel.endpoint),
+            peer(tunnel.peer),
+            millis(tunnel.last),
+            millis(tunnel.smoothed),
+            millis(tunnel.min)
+        );
+    }
+    page.push_str("</table>");
+
+    // Authentication failures, latest first
+    page.push_str(
+        "<h2>Authentication failures</h2><table><tr><th>Time</th><th>Peer</th><th>Route</th>\
+         <th>Endpoint</th></tr>",
+    );
+    for failure in state.activity.failures.lock().unwrap().iter().rev() {
+        let _ = write!(
+            page,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            time(failure.time),
+            failure.peer,
+            escape(&failure.route),
+            escape(&failure.endpoint)
+        );
+    }
+    page.push_str("</table>");
+
+    // Bans still running
+    let now = Instant::now();
+    page.push_str("<h2>Bans</h2><table><tr><th>Peer</th><th>Remaining</th><th></th></tr>");
+    for ban in state.ban_list.iter().filter(|ban| *ban.value() > now) {
+        let _ = write!(
+            page,
+            "<tr><td>{}</td><td>{}s</td>\
+             <td><form method=\"post\" action=\"/bans/{}/unban\"><button>Unban</button></form></td></tr>",
+            ban.key(),
+            (*ban.value() - now).as_secs(),
+            ban.key()
+        );
+    }
+    page.push_str("</table></body></html>");
+
+    Html(page)
+}
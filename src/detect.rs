@@ -0,0 +1,351 @@
+#[cfg(feature = "tls")]
+use crate::transport::tls;
+use crate::{
+    config::Protocol,
+    relay::connection::{Connection, ConnectionData},
+};
+use log::{debug, error, warn};
+use std::{cmp::Reverse, net::SocketAddr, sync::Arc};
+use tokio::{
+    net::TcpStream,
+    sync::mpsc,
+    task,
+    time::{sleep, timeout, Duration},
+};
+
+// Clients of server-speaks-first protocols never send anything, they end up as unknown
+const DETECT_TIMEOUT: Duration = Duration::from_secs(3);
+const PEEK_RETRY: Duration = Duration::from_millis(10);
+const PEEK_SIZE: usize = 8;
+pub const QUEUE_SIZE: usize = 16;
+
+// TLS record header, and the most a record may carry
+const RECORD_HEADER_LEN: usize = 5;
+const MAX_RECORD_LEN: usize = 16384;
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+const EXT_SERVER_NAME: u16 = 0x0000;
+const EXT_ALPN: u16 = 0x0010;
+
+const HTTP_METHODS: [&[u8]; 10] = [
+    b"GET ",
+    b"HEAD ",
+    b"POST ",
+    b"PUT ",
+    b"DELETE ",
+    b"OPTIONS ",
+    b"PATCH ",
+    b"CONNECT ",
+    b"TRACE ",
+    b"PRI * HT", // HTTP/2 prior knowledge
+];
+
+// Plain, or decrypted when the endpoint terminates TLS
+pub type Accepted = (Connection, SocketAddr);
+
+// Classify the first bytes of a connection, None if more bytes are needed to decide
+pub fn classify(data: &[u8]) -> Option<Protocol> {
+    let matches = |prefix: &[u8]| {
+        let len = prefix.len().min(data.len());
+        prefix[..len] == data[..len]
+    };
+
+    // TLS record header: handshake (0x16), version 3.x
+    if matches(&[0x16, 0x03]) {
+        return (data.len() >= 2).then_some(Protocol::Tls);
+    }
+    if matches(b"SSH-") {
+        return (data.len() >= 4).then_some(Protocol::Ssh);
+    }
+    for method in HTTP_METHODS {
+        let len = method.len().min(PEEK_SIZE);
+        if matches(&method[..len]) {
+            return (data.len() >= len).then_some(Protocol::Http);
+        }
+    }
+    Some(Protocol::Unknown)
+}
+
+// What routes may be picked by in the ClientHello of a TLS client
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientHello {
+    pub server_name: Option<String>,
+    pub alpn: Vec<String>,
+}
+
+// Parse the ClientHello in the first TLS record, None if it isn't all there or is malformed
+pub fn client_hello(data: &[u8]) -> Option<ClientHello> {
+    let mut reader = Reader(data);
+    // Record: [0x16][u16 version][u16 len], handshake: [type][u24 len]
+    let (kind, _, record) = (reader.u8()?, reader.u16()?, reader.vec16()?);
+    let mut reader = Reader(record);
+    if kind != 0x16 || reader.u8()? != HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+    let len = (reader.u8()? as usize) << 16 | reader.u16()? as usize;
+    let mut reader = Reader(reader.take(len)?);
+    // [u16 version][32 random][session id][cipher suites][compression methods]
+    reader.take(2 + 32)?;
+    reader.vec8()?;
+    reader.vec16()?;
+    reader.vec8()?;
+
+    let mut hello = ClientHello::default();
+    let mut extensions = Reader(reader.vec16().unwrap_or_default());
+    while !extensions.0.is_empty() {
+        let (kind, mut data) = (extensions.u16()?, Reader(extensions.vec16()?));
+        match kind {
+            // [u16 list len][type 0: host name][u16 len][name]
+            EXT_SERVER_NAME => {
+                let mut names = Reader(data.vec16()?);
+                while !names.0.is_empty() {
+                    let (name_type, name) = (names.u8()?, names.vec16()?);
+                    if name_type == 0 {
+                        hello.server_name = Some(String::from_utf8_lossy(name).into_owned());
+                    }
+                }
+            }
+            // [u16 list len]([u8 len][protocol])*
+            EXT_ALPN => {
+                let mut protocols = Reader(data.vec16()?);
+                while !protocols.0.is_empty() {
+                    let protocol = protocols.vec8()?;
+                    hello
+                        .alpn
+                        .push(String::from_utf8_lossy(protocol).into_owned());
+                }
+            }
+            _ => {}
+        }
+    }
+    Some(hello)
+}
+
+// Big-endian fields off the front of a message
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let (taken, rest) = self.0.split_at_checked(len)?;
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()? as usize;
+        self.take(len)
+    }
+
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+}
+
+// Peek until the protocol can be told, without consuming anything
+async fn detect(stream: &TcpStream) -> Protocol {
+    let mut buffer = [0u8; PEEK_SIZE];
+    let peek = async {
+        loop {
+            let n = match stream.peek(&mut buffer).await {
+                Ok(0) | Err(_) => return Protocol::Unknown,
+                Ok(n) => n,
+            };
+            match classify(&buffer[..n]) {
+                Some(protocol) => return protocol,
+                None if n == PEEK_SIZE => return Protocol::Unknown,
+                None => sleep(PEEK_RETRY).await,
+            }
+        }
+    };
+
+    timeout(DETECT_TIMEOUT, peek)
+        .await
+        .unwrap_or(Protocol::Unknown)
+}
+
+// Peek until the first TLS record is all there, None if it never is
+async fn peek_client_hello(stream: &TcpStream) -> Option<ClientHello> {
+    let mut buffer = vec![0u8; RECORD_HEADER_LEN + MAX_RECORD_LEN];
+    let peek = async {
+        loop {
+            let n = stream.peek(&mut buffer).await.ok().filter(|&n| n > 0)?;
+            let record_len = match buffer.get(3..RECORD_HEADER_LEN) {
+                Some(len) if n >= RECORD_HEADER_LEN => u16::from_be_bytes([len[0], len[1]]),
+                _ => u16::MAX,
+            };
+            if n >= RECORD_HEADER_LEN + record_len as usize || n == buffer.len() {
+                return client_hello(&buffer[..n]);
+            }
+            sleep(PEEK_RETRY).await;
+        }
+    };
+    timeout(DETECT_TIMEOUT, peek).await.ok().flatten()
+}
+
+// What a route on an auto endpoint takes. Unset protocol, server names and ALPN protocols make
+// the fallback for anything the other routes don't take. Server names and ALPN are TLS's.
+#[derive(Debug, Clone, Default)]
+pub struct DispatchRule {
+    pub protocol: Option<Protocol>,
+    // Server names, "*.example.com" for any name right under example.com
+    pub sni: Vec<String>,
+    // Any one of them offered by the client
+    pub alpn: Vec<String>,
+}
+
+impl DispatchRule {
+    fn is_fallback(&self) -> bool {
+        self.protocol.is_none() && self.sni.is_empty() && self.alpn.is_empty()
+    }
+
+    fn needs_hello(&self) -> bool {
+        !self.sni.is_empty() || !self.alpn.is_empty()
+    }
+
+    // How closely the rule fits the client, higher for more of it checked. None if it doesn't
+    // fit at all.
+    pub fn matches(&self, protocol: Protocol, hello: Option<&ClientHello>) -> Option<usize> {
+        if self.is_fallback() {
+            return Some(0);
+        }
+        let wanted = self.protocol.unwrap_or(Protocol::Tls);
+        if wanted != protocol {
+            return None;
+        }
+        let mut fit = 1;
+        if !self.sni.is_empty() {
+            let name = hello?.server_name.as_deref()?;
+            self.sni
+                .iter()
+                .any(|pattern| server_name_matches(pattern, name))
+                .then_some(())?;
+            fit += 1;
+        }
+        if !self.alpn.is_empty() {
+            let offered = &hello?.alpn;
+            self.alpn
+                .iter()
+                .any(|p| offered.contains(p))
+                .then_some(())?;
+            fit += 1;
+        }
+        Some(fit)
+    }
+}
+
+fn server_name_matches(pattern: &str, name: &str) -> bool {
+    let name = name.trim_end_matches('.');
+    match pattern.strip_prefix("*.") {
+        Some(parent) => name
+            .split_once('.')
+            .is_some_and(|(label, rest)| !label.is_empty() && rest.eq_ignore_ascii_case(parent)),
+        None => pattern.eq_ignore_ascii_case(name),
+    }
+}
+
+// The route closest to fitting the client, the first one of those fitting as closely
+pub fn pick<T>(
+    table: &[(DispatchRule, T)],
+    protocol: Protocol,
+    hello: Option<&ClientHello>,
+) -> Option<usize> {
+    let fits = table
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, (rule, _))| Some((rule.matches(protocol, hello)?, idx)));
+    fits.max_by_key(|&(fit, idx)| (fit, Reverse(idx)))
+        .map(|(_, idx)| idx)
+}
+
+// Routes sharing an auto endpoint, by what they take
+pub type DispatchTable = Vec<(DispatchRule, mpsc::Sender<Accepted>)>;
+
+// Accept on an auto endpoint and hand each connection to the route of its protocol. TLS clients
+// of endpoints terminating TLS negotiate one of their route's ALPN protocols.
+pub async fn dispatch(name: String, endpoint: ConnectionData, table: DispatchTable) {
+    let ConnectionData::Inbound {
+        listener,
+        #[cfg(feature = "tls")]
+        termination,
+        ..
+    } = endpoint
+    else {
+        return;
+    };
+    let log_target = format!("auto '{}'", name);
+    #[cfg(feature = "tls")]
+    let acceptors: Option<Arc<Vec<_>>> = termination.map(|termination| {
+        let acceptors = table
+            .iter()
+            .map(|(rule, _)| termination.acceptor(&rule.alpn));
+        Arc::new(acceptors.collect())
+    });
+    let table = Arc::new(table);
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!(target: &log_target, "Accept failed: {}", e);
+                continue;
+            }
+        };
+
+        task::spawn({
+            let table = table.clone();
+            #[cfg(feature = "tls")]
+            let acceptors = acceptors.clone();
+            let log_target = log_target.clone();
+            async move {
+                let protocol = detect(&stream).await;
+                // The whole ClientHello is only waited for when a route looks into it
+                let hello = match protocol {
+                    Protocol::Tls if table.iter().any(|(rule, _)| rule.needs_hello()) => {
+                        peek_client_hello(&stream).await
+                    }
+                    _ => None,
+                };
+                let Some(idx) = pick(&table, protocol, hello.as_ref()) else {
+                    warn!(target: &log_target, "No route for {} from {}", protocol, addr);
+                    return;
+                };
+                let server_name = hello.as_ref().and_then(|h| h.server_name.as_deref());
+                match server_name {
+                    Some(name) => debug!(
+                        target: &log_target,
+                        "{} for '{}' from {}", protocol, name, addr
+                    ),
+                    None => debug!(target: &log_target, "{} from {}", protocol, addr),
+                }
+
+                let conn = match protocol {
+                    #[cfg(feature = "tls")]
+                    Protocol::Tls if acceptors.is_some() => {
+                        let acceptors = acceptors.unwrap_or_default();
+                        match tls::accept(&acceptors[idx], stream).await {
+                            Ok(stream) => {
+                                if let Some(alpn) = stream.alpn() {
+                                    debug!(target: &log_target, "'{}' agreed on with {}", alpn, addr);
+                                }
+                                Connection::Tls(Box::new(stream))
+                            }
+                            Err(e) => {
+                                debug!(target: &log_target, "{} from {}", e, addr);
+                                return;
+                            }
+                        }
+                    }
+                    _ => Connection::Direct(stream),
+                };
+                let _ = table[idx].1.send((conn, addr)).await;
+            }
+        });
+    }
+}
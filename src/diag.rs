@@ -0,0 +1,390 @@
+use crate::{
+    config::{Direction, Endpoint, VeloxidConfig},
+    error::StartupError,
+    keys::TunnelKey,
+    protocol::{
+        handshake::{NONCE_LEN, VERSION},
+        machine::{ConnectEvent, OutboundMachine, OutboundOptions},
+    },
+    relay::{
+        connection::{self, ConnectionData, TunnelSettings},
+        tunnel::{rejection, Registration},
+    },
+    table,
+    transport::Stream,
+};
+use anyhow::{anyhow, Result};
+use log::LevelFilter;
+use std::{fmt, io, net::SocketAddr};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::{sleep, timeout, Duration, Instant},
+};
+
+pub const USAGE: &str = "veloxid diag <endpoint>";
+
+// Handshakes timed on tunnel endpoints
+const ROUNDS: usize = 3;
+// Time a relay gets to reject the auth token, accepting it only comes with a session
+const REJECTION_WAIT: Duration = Duration::from_secs(1);
+// Sent to direct endpoints on a connection of their own each. The first one fits in a segment,
+// the others take segments as large as the path allows, and all of them fit in the receive
+// window of a peer that doesn't read.
+const PAYLOADS: [usize; 5] = [256, 1024, 4 * 1024, 16 * 1024, 32 * 1024];
+// Time the peer gets to acknowledge a payload
+const STALL_TIMEOUT: Duration = Duration::from_secs(5);
+const ACK_POLL: Duration = Duration::from_millis(1);
+
+// What the path to an outbound endpoint looks like from here
+pub struct Report {
+    pub endpoint: String,
+    pub targets: Vec<SocketAddr>,
+    pub tunnel: bool,
+    pub connects: Vec<Duration>,
+    // Tunnel endpoints only, from connected to the auth token sent
+    pub handshakes: Vec<Duration>,
+    // Direct endpoints only
+    pub probes: Vec<Probe>,
+}
+
+pub struct Probe {
+    pub size: usize,
+    pub target: SocketAddr,
+    pub outcome: Outcome,
+}
+
+pub enum Outcome {
+    // Every byte was acknowledged by the peer
+    Acked(Duration),
+    // Written, whether the peer acknowledged it can't be told on this platform
+    Written(Duration),
+    // Bytes still unacknowledged after STALL_TIMEOUT
+    Stalled(usize),
+    Failed(String),
+}
+
+// Tunnel endpoints are authenticated a few times, without a session: the relay takes anything
+// else for an attack, and a tunnel closed after authenticating only frees its worker. Direct
+// endpoints get payloads of growing sizes instead, the target reads them as any client's.
+pub async fn diagnose(name: &str, endpoint: &Endpoint) -> Result<Report> {
+    let not_tcp = || anyhow!("'{}' isn't an outbound TCP endpoint", name);
+    if !matches!(endpoint.direction, Direction::Outbound) {
+        return Err(not_tcp());
+    }
+    // Only the first hop of a chain is reached over TCP
+    if let Some(via) = &endpoint.via {
+        return Err(anyhow!(
+            "'{}' is reached through '{}', diagnose that one",
+            name,
+            via
+        ));
+    }
+    if endpoint.hops.is_some() {
+        return Err(anyhow!(
+            "'{}' has hops, diagnose an endpoint of its first relay",
+            name
+        ));
+    }
+    let data = match connection::get_connection_data(endpoint).await? {
+        // Punching is tried first, the path through the relay is the one that has to work
+        ConnectionData::Punched { relayed, .. } => *relayed,
+        data => data,
+    };
+    let ConnectionData::Outbound {
+        targets,
+        tunnel,
+        obfuscation,
+        ..
+    } = data
+    else {
+        return Err(not_tcp());
+    };
+    let mut report = Report {
+        endpoint: name.to_owned(),
+        targets: targets.addrs().to_vec(),
+        tunnel: tunnel.is_some(),
+        connects: Vec::new(),
+        handshakes: Vec::new(),
+        probes: Vec::new(),
+    };
+    let rounds = match tunnel {
+        Some(_) => ROUNDS,
+        None => PAYLOADS.len(),
+    };
+
+    for (round, target) in report
+        .targets
+        .clone()
+        .iter()
+        .cycle()
+        .take(rounds)
+        .enumerate()
+    {
+        let started = Instant::now();
+        let stream = TcpStream::connect(target)
+            .await
+            .map_err(|e| anyhow!("Couldn't connect to {}: {}", target, e))?;
+        report.connects.push(started.elapsed());
+
+        let Some(settings) = &tunnel else {
+            let size = PAYLOADS[round];
+            let outcome = send(&stream, size)
+                .await
+                .unwrap_or_else(|e| Outcome::Failed(e.to_string()));
+            report.probes.push(Probe {
+                size,
+                target: *target,
+                outcome,
+            });
+            continue;
+        };
+        let started = Instant::now();
+        let handshake = match &obfuscation {
+            Some(obfuscation) => {
+                let stream = obfuscation.connect(stream).await?;
+                authenticate(stream, settings, started).await
+            }
+            None => authenticate(stream, settings, started).await,
+        };
+        report
+            .handshakes
+            .push(handshake.map_err(|e| e.context(format!("Handshake with {}", target)))?);
+    }
+    Ok(report)
+}
+
+// Runs the outbound side of the handshake up to the auth token, then waits for a rejection.
+// Returns the time taken until the token was sent. Wrong secrets aren't rejected but left to
+// time out, as for any connector.
+async fn authenticate<S: Stream>(
+    mut stream: S,
+    settings: &TunnelSettings,
+    started: Instant,
+) -> Result<Duration> {
+    let options = OutboundOptions {
+        version: VERSION,
+        cipher: settings.cipher.id(),
+        // Tickets are for the connector's own tunnels
+        tickets: None,
+        announce: match &settings.registration {
+            Some(Registration::Announce(service)) => Some(service.clone()),
+            _ => None,
+        },
+        silent: settings.silence.is_some(),
+        totp: settings.totp.clone(),
+    };
+    let mut machine = match &settings.key {
+        // Keys of a command answer the nonce, nothing is sent before it then
+        TunnelKey::Provider(_) => {
+            let nonce = receive_nonce(&mut stream, settings).await?;
+            let key = settings.key.for_nonce(&nonce).await?;
+            let mut machine = OutboundMachine::new(*key, options);
+            machine.handle_input(&nonce);
+            machine
+        }
+        TunnelKey::Secret(secret) => {
+            let mut machine = OutboundMachine::new(**secret, options);
+            transmit(&mut stream, &mut machine).await?;
+            let nonce = receive_nonce(&mut stream, settings).await?;
+            machine.handle_input(&nonce);
+            machine
+        }
+    };
+    transmit(&mut stream, &mut machine).await?;
+    let handshake = started.elapsed();
+
+    let answer = timeout(REJECTION_WAIT, async {
+        while machine.wants() > 0 {
+            let mut frame = vec![0u8; machine.wants()];
+            stream.read_exact(&mut frame).await?;
+            machine.handle_input(&frame);
+        }
+        Ok::<_, io::Error>(machine.poll_event())
+    })
+    .await;
+    match answer {
+        Ok(Ok(Some(ConnectEvent::Rejected { reason }))) => Err(rejection(reason).into()),
+        Ok(Err(e)) => Err(e.into()),
+        _ => Ok(handshake),
+    }
+}
+
+async fn receive_nonce<S: Stream>(
+    stream: &mut S,
+    settings: &TunnelSettings,
+) -> Result<[u8; NONCE_LEN]> {
+    let mut nonce = [0u8; NONCE_LEN];
+    let nonce_timeout = settings.timeouts.nonce;
+    match timeout(nonce_timeout, stream.read_exact(&mut nonce)).await {
+        Ok(read) => read?,
+        Err(_) => return Err(anyhow!("No nonce from the peer within {:?}", nonce_timeout)),
+    };
+    Ok(nonce)
+}
+
+async fn transmit<S: Stream>(stream: &mut S, machine: &mut OutboundMachine) -> io::Result<()> {
+    while let Some(bytes) = machine.poll_transmit() {
+        stream.write_all(&bytes).await?;
+    }
+    stream.flush().await
+}
+
+async fn send(stream: &TcpStream, size: usize) -> io::Result<Outcome> {
+    let payload = vec![0u8; size];
+    let started = Instant::now();
+    let written = timeout(STALL_TIMEOUT, async {
+        let mut sent = 0;
+        while sent < size {
+            stream.writable().await?;
+            match stream.try_write(&payload[sent..]) {
+                Ok(written) => sent += written,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    })
+    .await;
+    match written {
+        Ok(written) => written?,
+        Err(_) => return Ok(Outcome::Stalled(unacked(stream)?.unwrap_or(size))),
+    }
+
+    loop {
+        match unacked(stream)? {
+            None => return Ok(Outcome::Written(started.elapsed())),
+            Some(0) => return Ok(Outcome::Acked(started.elapsed())),
+            Some(unacked) if started.elapsed() >= STALL_TIMEOUT => {
+                return Ok(Outcome::Stalled(unacked))
+            }
+            Some(_) => sleep(ACK_POLL).await,
+        }
+    }
+}
+
+// Bytes in the socket's send queue the peer hasn't acknowledged yet
+#[cfg(target_os = "linux")]
+fn unacked(stream: &TcpStream) -> io::Result<Option<usize>> {
+    use std::os::fd::AsRawFd;
+
+    let mut queued: libc::c_int = 0;
+    // SAFETY: TIOCOUTQ writes one int, the fd is the stream's
+    if unsafe { libc::ioctl(stream.as_raw_fd(), libc::TIOCOUTQ, &mut queued) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Some(queued as usize))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unacked(_stream: &TcpStream) -> io::Result<Option<usize>> {
+    Ok(None)
+}
+
+// Min, average and max
+fn spread(samples: &[Duration]) -> String {
+    let (Some(min), Some(max)) = (samples.iter().min(), samples.iter().max()) else {
+        return "-".to_owned();
+    };
+    let avg = samples.iter().sum::<Duration>() / samples.len() as u32;
+    format!("min {:.1?}, avg {:.1?}, max {:.1?}", min, avg, max)
+}
+
+fn rate(bytes: usize, elapsed: Duration) -> String {
+    let per_sec = bytes as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+    match per_sec {
+        r if r >= 1024.0 * 1024.0 => format!("{:.1} MiB/s", r / (1024.0 * 1024.0)),
+        r => format!("{:.1} KiB/s", r / 1024.0),
+    }
+}
+
+impl Report {
+    // Stalls of large payloads only, as MTU blackholes do: segments of a full size are
+    // dropped on the way while small ones get through
+    pub fn blackholed(&self) -> bool {
+        let first_stalled = self
+            .probes
+            .iter()
+            .find(|probe| matches!(probe.outcome, Outcome::Stalled(_)));
+        let Some(first_stalled) = first_stalled else {
+            return false;
+        };
+        self.probes.iter().any(|probe| {
+            matches!(probe.outcome, Outcome::Acked(_)) && probe.size < first_stalled.size
+        })
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let targets: Vec<String> = self.targets.iter().map(ToString::to_string).collect();
+        let kind = if self.tunnel { "tunnel" } else { "direct" };
+        writeln!(
+            f,
+            "'{}' at {} ({})",
+            self.endpoint,
+            targets.join(", "),
+            kind
+        )?;
+        writeln!(f, "Connect:   {}", spread(&self.connects))?;
+        if self.tunnel {
+            writeln!(
+                f,
+                "Handshake: {} (until the auth token is sent)",
+                spread(&self.handshakes)
+            )?;
+        }
+        for probe in &self.probes {
+            let outcome = match &probe.outcome {
+                Outcome::Acked(elapsed) => format!(
+                    "acknowledged in {:.1?}, {}",
+                    elapsed,
+                    rate(probe.size, *elapsed)
+                ),
+                Outcome::Written(elapsed) => format!("written in {:.1?}", elapsed),
+                Outcome::Stalled(unacked) => format!(
+                    "stalled, {} bytes unacknowledged after {:?}",
+                    unacked, STALL_TIMEOUT
+                ),
+                Outcome::Failed(e) => format!("failed: {}", e),
+            };
+            writeln!(
+                f,
+                "{:>6} bytes to {}: {}",
+                probe.size, probe.target, outcome
+            )?;
+        }
+        if self.blackholed() {
+            writeln!(
+                f,
+                "Small payloads got through and larger ones stalled: segments over the path MTU \
+                 are likely dropped on the way (an MTU blackhole). Lowering the MTU of the \
+                 interface or clamping the TCP MSS usually helps."
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Reports on the path to an outbound endpoint, for tunnels that connect but then hang
+pub async fn run(args: &[String]) -> Result<()> {
+    let [name] = args else {
+        return Err(anyhow!("Usage: {}", USAGE));
+    };
+    let config_path = &std::env::var("VELOXID_CONFIG").unwrap_or("veloxid.toml".to_owned());
+    let mut config = VeloxidConfig::load(config_path).map_err(StartupError::Config)?;
+    table::add_agent(&mut config).map_err(StartupError::Config)?;
+    let endpoint = config
+        .endpoints
+        .get(name)
+        .ok_or(anyhow!("No such endpoint '{}'", name))?;
+
+    // The report goes to stdout, the log to stderr
+    env_logger::builder()
+        .filter_level(LevelFilter::Warn)
+        .target(env_logger::Target::Stderr)
+        .init();
+
+    print!("{}", diagnose(name, endpoint).await?);
+    Ok(())
+}
@@ -0,0 +1,91 @@
+use crate::{config::Endpoint, relay::connection::ConnectionData};
+use anyhow::Result;
+use log::{info, warn};
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use std::net::IpAddr;
+
+const LOG_TARGET: &str = "discovery";
+
+// DNS-SD type of the listeners that don't give one
+pub const DEFAULT_TYPE: &str = "_veloxid._tcp";
+
+// Advertises client listeners on the local network over mDNS, so LAN clients find them by
+// name. The daemon answers on every interface until the advertiser is stopped.
+pub struct Advertiser {
+    daemon: ServiceDaemon,
+    host: String,
+    // Full names of the services registered
+    services: Vec<String>,
+}
+
+impl Advertiser {
+    pub fn start() -> Result<Self> {
+        Ok(Self {
+            daemon: ServiceDaemon::new()?,
+            host: format!("{}.local.", hostname()),
+            services: Vec::new(),
+        })
+    }
+
+    // The listener of an inbound endpoint, under the name it is advertised as. Listeners on
+    // every address are advertised with the addresses of every interface.
+    pub fn advertise(
+        &mut self,
+        name: &str,
+        endpoint: &Endpoint,
+        data: &ConnectionData,
+    ) -> Result<()> {
+        let (Some(instance), ConnectionData::Inbound { listener, .. }) =
+            (&endpoint.advertise, data)
+        else {
+            return Ok(());
+        };
+        let ty = endpoint.advertise_type.as_deref().unwrap_or(DEFAULT_TYPE);
+        let addr = listener.addr();
+        let properties = [("endpoint", name)];
+        // Unspecified addresses are filled in with the interfaces'
+        let ips: Vec<IpAddr> = Some(addr.ip())
+            .filter(|ip| !ip.is_unspecified())
+            .into_iter()
+            .collect();
+        let info = ServiceInfo::new(
+            &format!("{}.local.", ty),
+            instance,
+            &self.host,
+            &ips[..],
+            addr.port(),
+            &properties[..],
+        )?;
+        let info = match ips.is_empty() {
+            true => info.enable_addr_auto(),
+            false => info,
+        };
+        let fullname = info.get_fullname().to_owned();
+        self.daemon.register(info)?;
+        info!(target: LOG_TARGET, "Advertising '{}' on port {}", fullname, addr.port());
+        self.services.push(fullname);
+        Ok(())
+    }
+
+    // Clients are told the services are gone, rather than finding out once they expire
+    pub fn stop(self) {
+        for service in &self.services {
+            if let Err(e) = self.daemon.unregister(service) {
+                warn!(target: LOG_TARGET, "Couldn't withdraw '{}': {}", service, e);
+            }
+        }
+        if let Err(e) = self.daemon.shutdown() {
+            warn!(target: LOG_TARGET, "Couldn't stop: {}", e);
+        }
+    }
+}
+
+// Name of the machine, as the host of the services
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .map(|name| name.trim().to_owned())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "veloxid".to_owned())
+}
@@ -9,6 +9,31 @@ pub enum TunnelError {
     #[error("Secret rejected")]
     SecretRejected,
 
+    // Rejections by the inbound side, occur on outbound tunnels
+    #[error("Rejected, banned by the peer")]
+    RejectedBanned,
+
+    #[error("Rejected, the peer's route is full")]
+    RejectedRouteFull,
+
+    #[error("Rejected, the peer is draining")]
+    RejectedDraining,
+
+    #[error("Rejected, outside of the peer's schedule")]
+    RejectedOutsideSchedule,
+
+    #[error("Rejected, the peer uses another cipher")]
+    RejectedCipherMismatch,
+
+    #[error("Rejected, the resumption ticket is expired or used")]
+    RejectedTicket,
+
+    #[error("Rejected, the peer serves no such service")]
+    RejectedUnknownService,
+
+    #[error("Too many authentication failures, not connecting for a while")]
+    CircuitOpen,
+
     #[error("Timed out")]
     Timeout(std::net::IpAddr),
 
@@ -17,6 +42,58 @@ pub enum TunnelError {
 
     #[error("Connection attempt from banned IP")]
     ConnAttemptFromBannedIP,
+
+    #[error("Connection from {0} refused, outside of the route schedule")]
+    OutsideSchedule(std::net::IpAddr),
+
+    #[error("Connection from {0} refused, too many open from it")]
+    TooManyConnections(std::net::IpAddr),
+
+    #[error("Connection from {0} closed, it didn't knock")]
+    NotKnocked(std::net::IpAddr),
+
+    #[error("Connection from {0} asked for another cipher")]
+    CipherMismatch(std::net::IpAddr),
+
+    #[error("Connection from {0} resumed with an expired or used ticket")]
+    TicketRejected(std::net::IpAddr),
+
+    #[error("Connection from {0} announced no known service")]
+    UnknownService(std::net::IpAddr),
+
+    #[error(
+        "At most {} bytes of keying material are exported at once",
+        crate::protocol::cipher::MAX_EXPORT_LEN
+    )]
+    ExportTooLong,
+
+    #[error("Blind and end-to-end tunnels need a stream cipher and no padding")]
+    NotBlindable,
+
+    #[error("End-to-end tunnels can only run against plain streams")]
+    EndToEndJoined,
+
+    // Occurs on outbound tunnels, both sides must agree on rekeying
+    #[error("The peer doesn't agree on rekeying")]
+    RekeyMismatch,
+
+    // Occur on inbound tunnels waiting to be attached, the peer is dropped
+    #[error("The peer missed its heartbeat")]
+    HeartbeatMissed,
+
+    #[error("The peer sent data before the tunnel was attached")]
+    DataBeforeAttach,
+
+    // Occurs in sessions, the peer of one side stopped reading and the session is torn down
+    #[error("The peer stopped reading for {0:?}")]
+    WriteStalled(std::time::Duration),
+
+    // Occur on datagrams sent over a session's stream
+    #[error("Datagram of {0} bytes is larger than 65535")]
+    DatagramTooLarge(usize),
+
+    #[error("The stream ended within a datagram")]
+    DatagramCut,
 }
 
 #[derive(Debug, Error)]
@@ -29,4 +106,116 @@ pub enum ConfigError {
 
     #[error("Every tunnel requires a secret")]
     NoSecret,
+
+    #[error("QUIC transport and knocks need a secret, not a key command")]
+    KeyCommandNotSecret,
+
+    #[error("Endpoints with a via must be outbound tunnels")]
+    ViaNotTunnel,
+
+    #[error("Endpoints with hops must be outbound tunnels")]
+    HopsNotTunnel,
+
+    #[error("Punched tunnels need an e2e_secret")]
+    PunchNotEndToEnd,
+
+    #[error("Auto endpoints can only be inbound")]
+    AutoNotInbound,
+
+    #[error("Honeypot endpoints can only be inbound")]
+    HoneypotNotInbound,
+
+    #[error("Exec endpoints can only be outbound")]
+    ExecNotOutbound,
+
+    #[error("QUIC transport is only supported on tunnel endpoints")]
+    QuicNotTunnel,
+
+    #[error("QUIC transport requires the 'quic' feature")]
+    QuicNotBuilt,
+
+    #[error("TLS termination and origination require the 'tls' feature")]
+    TlsNotBuilt,
+
+    #[error("Bonding is only supported on TCP tunnel endpoints")]
+    BondingNotTcpTunnel,
+
+    #[error("Resumption is only supported on TCP tunnel endpoints")]
+    ResumeNotTcpTunnel,
+
+    #[error("Obfuscation is only supported on TCP tunnel endpoints")]
+    ObfuscationNotTcpTunnel,
+
+    #[error("Ciphers are only supported on tunnel endpoints")]
+    CipherNotTunnel,
+
+    #[error("Padding is only supported on tunnel endpoints")]
+    PaddingNotTunnel,
+
+    #[error("Targets are only supported on plain outbound endpoints")]
+    TargetsNotOutbound,
+
+    #[error("Route table entry '{0}' collides with an endpoint")]
+    DuplicateTableEntry(String),
+
+    #[error("{0}: {1}")]
+    Invalid(String, String),
+
+    #[error("Invalid schedule window: '{0}'")]
+    InvalidSchedule(String),
+}
+
+// Why the process couldn't start, each kind exits with its own status
+#[derive(Debug, Error)]
+pub enum StartupError {
+    // The config file can't be read, parsed or used as it is
+    #[error(transparent)]
+    Config(anyhow::Error),
+
+    #[error("Couldn't resolve '{0}'")]
+    Resolve(String),
+
+    #[error("Couldn't bind '{0}': {1}")]
+    Bind(String, std::io::Error),
+
+    #[error("Couldn't drop privileges: {0}")]
+    Privileges(String),
+
+    #[error("Couldn't sandbox the process: {0}")]
+    Sandbox(String),
+
+    // Every failure, when there are several
+    #[error("{} failures:\n{}", .0.len(), summary(.0))]
+    Failed(Vec<anyhow::Error>),
+}
+
+// Exit statuses, other failures exit with 1
+pub const EXIT_CONFIG: u8 = 2;
+pub const EXIT_BIND: u8 = 3;
+pub const EXIT_RESOLVE: u8 = 4;
+
+fn summary(errors: &[anyhow::Error]) -> String {
+    let lines: Vec<String> = errors.iter().map(|e| format!("  {:#}", e)).collect();
+    lines.join("\n")
+}
+
+// Several failures exit with the status of the most fundamental one
+pub fn exit_status(error: &anyhow::Error) -> u8 {
+    if error.downcast_ref::<ConfigError>().is_some() {
+        return EXIT_CONFIG;
+    }
+    match error.downcast_ref::<StartupError>() {
+        Some(StartupError::Config(_)) => EXIT_CONFIG,
+        Some(StartupError::Resolve(_)) => EXIT_RESOLVE,
+        Some(StartupError::Bind(..)) => EXIT_BIND,
+        Some(StartupError::Privileges(_) | StartupError::Sandbox(_)) => 1,
+        Some(StartupError::Failed(errors)) => {
+            let statuses: Vec<u8> = errors.iter().map(exit_status).collect();
+            [EXIT_CONFIG, EXIT_RESOLVE, EXIT_BIND]
+                .into_iter()
+                .find(|status| statuses.contains(status))
+                .unwrap_or(1)
+        }
+        None => 1,
+    }
 }
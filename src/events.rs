@@ -0,0 +1,111 @@
+use crate::relay::tunnel::Traffic;
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+// Identity of a session, from the moment both sides are connected
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    pub id: u64,
+    pub route: String,
+    // Name of the route and of its endpoints A and B, to label metrics with
+    pub route_name: String,
+    pub endpoints: [String; 2],
+    // Peer of the tunnel side, the connector on a relay
+    pub connector: Option<IpAddr>,
+    pub peer_a: Option<SocketAddr>,
+    pub peer_b: Option<SocketAddr>,
+    // At least one side is a tunnel which passed the handshake
+    pub authenticated: bool,
+    pub started: SystemTime,
+}
+
+// Statistics of a finished session
+#[derive(Debug, Clone)]
+pub struct SessionStats {
+    pub traffic: Traffic,
+    pub duration: Duration,
+    // Why the session ended, None if both sides closed cleanly
+    pub error: Option<String>,
+}
+
+// Hooks for session events (alerting, fail2ban-style integrations, audit logging...)
+// Every method has an empty default so handlers only implement what they need.
+// `route` is the log target of the worker the event happened on.
+pub trait EventHandler: Send + Sync {
+    fn on_handshake_success(&self, _route: &str, _endpoint: &str, _peer: IpAddr) {}
+
+    // The peer failed to authenticate to this side
+    fn on_auth_failure(&self, _route: &str, _endpoint: &str, _peer: IpAddr) {}
+
+    // The peer refused the secret of this side
+    fn on_auth_rejected(&self, _route: &str, _endpoint: &str, _peer: IpAddr) {}
+
+    // The peer, one reaching an inbound endpoint, is added to the ban list for length
+    fn on_ban(&self, _route: &str, _endpoint: &str, _peer: IpAddr, _length: Duration) {}
+
+    // The peer's ban is lifted before it runs out, from the API or the dashboard
+    fn on_unban(&self, _peer: IpAddr) {}
+
+    fn on_session_start(&self, _session: &SessionInfo) {}
+
+    fn on_session_end(&self, _session: &SessionInfo, _stats: &SessionStats) {}
+}
+
+// Registered handlers, cheap to clone and share between workers
+#[derive(Clone, Default)]
+pub struct EventHandlers {
+    handlers: Vec<Arc<dyn EventHandler>>,
+}
+
+impl EventHandlers {
+    pub fn register(&mut self, handler: Arc<dyn EventHandler>) {
+        self.handlers.push(handler);
+    }
+}
+
+impl EventHandler for EventHandlers {
+    fn on_handshake_success(&self, route: &str, endpoint: &str, peer: IpAddr) {
+        for handler in &self.handlers {
+            handler.on_handshake_success(route, endpoint, peer);
+        }
+    }
+
+    fn on_auth_failure(&self, route: &str, endpoint: &str, peer: IpAddr) {
+        for handler in &self.handlers {
+            handler.on_auth_failure(route, endpoint, peer);
+        }
+    }
+
+    fn on_auth_rejected(&self, route: &str, endpoint: &str, peer: IpAddr) {
+        for handler in &self.handlers {
+            handler.on_auth_rejected(route, endpoint, peer);
+        }
+    }
+
+    fn on_ban(&self, route: &str, endpoint: &str, peer: IpAddr, length: Duration) {
+        for handler in &self.handlers {
+            handler.on_ban(route, endpoint, peer, length);
+        }
+    }
+
+    fn on_unban(&self, peer: IpAddr) {
+        for handler in &self.handlers {
+            handler.on_unban(peer);
+        }
+    }
+
+    fn on_session_start(&self, session: &SessionInfo) {
+        for handler in &self.handlers {
+            handler.on_session_start(session);
+        }
+    }
+
+    fn on_session_end(&self, session: &SessionInfo, stats: &SessionStats) {
+        for handler in &self.handlers {
+            handler.on_session_end(session, stats);
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use crate::{commands, events::EventHandler};
+use anyhow::Result;
+use log::{debug, error};
+use std::{net::IpAddr, sync::Arc, time::Duration};
+use tokio::task;
+
+const LOG_TARGET: &str = "firewall";
+
+// Runs a command for every ban, to drop the peer in the kernel (an nftables set, an
+// iptables rule...) rather than turning it away once accepted. The firewall lets the
+// entry expire, bans lifted from the API or the dashboard run the unban command if any
+// and otherwise stay there until then.
+#[derive(Clone)]
+pub struct BanCommand {
+    command: Arc<[String]>,
+    unban_command: Option<Arc<[String]>>,
+}
+
+impl BanCommand {
+    pub fn new(command: Vec<String>, unban_command: Option<Vec<String>>) -> Self {
+        Self {
+            command: command.into(),
+            unban_command: unban_command.map(Into::into),
+        }
+    }
+
+    // The program and its arguments for a ban
+    pub fn expand(&self, peer: IpAddr, length: Duration) -> Vec<String> {
+        expand(&self.command, peer, length)
+    }
+
+    // The program and its arguments for a lifted ban, "{seconds}" is 0
+    pub fn expand_unban(&self, peer: IpAddr) -> Option<Vec<String>> {
+        let command = self.unban_command.as_ref()?;
+        Some(expand(command, peer, Duration::ZERO))
+    }
+
+    pub async fn run(&self, peer: IpAddr, length: Duration) -> Result<()> {
+        commands::run(&self.expand(peer, length), &[]).await?;
+        Ok(())
+    }
+
+    // Does nothing without an unban command
+    pub async fn run_unban(&self, peer: IpAddr) -> Result<()> {
+        if let Some(command) = self.expand_unban(peer) {
+            commands::run(&command, &[]).await?;
+        }
+        Ok(())
+    }
+}
+
+fn expand(command: &[String], peer: IpAddr, length: Duration) -> Vec<String> {
+    let family = match peer {
+        IpAddr::V4(_) => "ip",
+        IpAddr::V6(_) => "ip6",
+    };
+    command
+        .iter()
+        .map(|arg| {
+            arg.replace("{ip}", &peer.to_string())
+                .replace("{family}", family)
+                .replace("{seconds}", &length.as_secs().to_string())
+        })
+        .collect()
+}
+
+impl EventHandler for BanCommand {
+    fn on_ban(&self, _route: &str, _endpoint: &str, peer: IpAddr, length: Duration) {
+        let command = self.clone();
+        task::spawn(async move {
+            match command.run(peer, length).await {
+                Ok(()) => debug!(target: LOG_TARGET, "Added {} to the firewall", peer),
+                Err(e) => {
+                    error!(target: LOG_TARGET, "Couldn't add {} to the firewall: {}", peer, e)
+                }
+            }
+        });
+    }
+
+    fn on_unban(&self, peer: IpAddr) {
+        if self.unban_command.is_none() {
+            return;
+        }
+        let command = self.clone();
+        task::spawn(async move {
+            match command.run_unban(peer).await {
+                Ok(()) => debug!(target: LOG_TARGET, "Removed {} from the firewall", peer),
+                Err(e) => {
+                    error!(target: LOG_TARGET, "Couldn't remove {} from the firewall: {}", peer, e)
+                }
+            }
+        });
+    }
+}
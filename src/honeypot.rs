@@ -0,0 +1,84 @@
+use crate::{
+    events::{EventHandler, EventHandlers},
+    relay::connection::BAN_LENGTH,
+    transport::listener::Listener,
+};
+use dashmap::DashMap;
+use log::{error, warn};
+use std::{net::IpAddr, sync::Arc};
+use tokio::{
+    io::AsyncReadExt,
+    net::TcpStream,
+    task,
+    time::{timeout, Duration, Instant},
+};
+
+// Time a peer gets to send the bytes captured
+const CAPTURE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Ports nothing legitimate connects to. Whoever does is banned from every route right away,
+// scanners probing the relay are kept out of its real ports.
+pub struct Honeypot {
+    pub name: String,
+    // Bytes of what the peer sends logged, none if 0
+    pub capture: usize,
+    pub ban_list: Arc<DashMap<IpAddr, Instant>>,
+    pub events: EventHandlers,
+}
+
+pub async fn serve(listener: Arc<Listener>, honeypot: Honeypot) {
+    let log_target = format!("honeypot '{}'", honeypot.name);
+    let honeypot = Arc::new(honeypot);
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!(target: &log_target, "Accept failed: {}", e);
+                continue;
+            }
+        };
+        let peer = addr.ip();
+
+        // Peers still banned are only probing again
+        if honeypot.is_banned(peer) {
+            continue;
+        }
+        honeypot.ban_list.insert(peer, Instant::now() + BAN_LENGTH);
+        honeypot
+            .events
+            .on_ban(&log_target, &honeypot.name, peer, BAN_LENGTH);
+        warn!(target: &log_target, "Probe from {}, banned for {:?}", addr, BAN_LENGTH);
+
+        if honeypot.capture > 0 {
+            let (honeypot, log_target) = (honeypot.clone(), log_target.clone());
+            task::spawn(async move {
+                let sent = capture(stream, honeypot.capture).await;
+                warn!(target: &log_target, "{} sent \"{}\"", addr, sent.escape_ascii());
+            });
+        }
+    }
+}
+
+impl Honeypot {
+    fn is_banned(&self, peer: IpAddr) -> bool {
+        self.ban_list
+            .get(&peer)
+            .is_some_and(|until| *until > Instant::now())
+    }
+}
+
+// Up to len bytes, what arrived in time if the peer stops or is slow
+pub async fn capture(mut stream: TcpStream, len: usize) -> Vec<u8> {
+    let mut sent = Vec::with_capacity(len);
+    let _ = timeout(CAPTURE_TIMEOUT, async {
+        let mut buffer = vec![0u8; len];
+        while sent.len() < len {
+            match stream.read(&mut buffer[..len - sent.len()]).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => sent.extend_from_slice(&buffer[..n]),
+            }
+        }
+    })
+    .await;
+    sent
+}
@@ -0,0 +1,104 @@
+use crate::{
+    protocol::vectors::{self, hex, unhex, Vector},
+    transport::Stream,
+};
+use anyhow::{anyhow, Result};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::{timeout, Duration},
+};
+
+pub const USAGE: &str =
+    "veloxid interop vectors | inbound <address> <vector> | outbound <address> <vector>";
+
+// Time the peer gets for each of its parts of the exchange
+const PEER_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Reads exactly what the vector has the peer send, and tells where it differs
+async fn expect<S: Stream>(stream: &mut S, what: &str, expected: &str) -> Result<()> {
+    let mut received = vec![0u8; expected.len() / 2];
+    match timeout(PEER_TIMEOUT, stream.read_exact(&mut received)).await {
+        Ok(read) => read.map_err(|e| anyhow!("Reading {}: {}", what, e))?,
+        Err(_) => return Err(anyhow!("No {} within {:?}", what, PEER_TIMEOUT)),
+    };
+    match hex(&received) == expected {
+        true => Ok(()),
+        false => Err(anyhow!(
+            "{} differs from the vector's: {} instead of {}",
+            what,
+            hex(&received),
+            expected
+        )),
+    }
+}
+
+// Each side sends its session and shuts down, then reads the peer's up to the end
+async fn session<S: Stream>(mut stream: S, sent: &str, expected: &str) -> Result<()> {
+    stream.write_all(&unhex(sent)?).await?;
+    stream.shutdown().await?;
+    expect(&mut stream, "The peer's session", expected).await?;
+    let mut rest = Vec::new();
+    timeout(PEER_TIMEOUT, stream.read_to_end(&mut rest))
+        .await
+        .map_err(|_| anyhow!("The peer didn't end its session within {:?}", PEER_TIMEOUT))??;
+    match rest.is_empty() {
+        true => Ok(()),
+        false => Err(anyhow!("{} bytes after the peer's session", rest.len())),
+    }
+}
+
+// The inbound side of the vector, byte for byte, with the peer as the outbound side
+pub async fn inbound<S: Stream>(mut stream: S, vector: &Vector) -> Result<()> {
+    stream.write_all(&unhex(&vector.nonce)?).await?;
+    expect(&mut stream, "The hello", &vector.hello).await?;
+    if let Some(announce) = &vector.announce {
+        expect(&mut stream, "The announcement", announce).await?;
+    }
+    stream.write_all(&unhex(&vector.attach)?).await?;
+    session(stream, &vector.inbound_wire, &vector.outbound_wire).await
+}
+
+// The outbound side of the vector, byte for byte, with the peer as the inbound side
+pub async fn outbound<S: Stream>(mut stream: S, vector: &Vector) -> Result<()> {
+    expect(&mut stream, "The nonce", &vector.nonce).await?;
+    stream.write_all(&unhex(&vector.hello)?).await?;
+    if let Some(announce) = &vector.announce {
+        stream.write_all(&unhex(announce)?).await?;
+    }
+    expect(&mut stream, "ATTACH", &vector.attach).await?;
+    session(stream, &vector.outbound_wire, &vector.inbound_wire).await
+}
+
+// Prints the vectors, or plays one side of a vector against another implementation: inbound
+// sides listen for a single connection, outbound ones connect
+pub async fn run(args: &[String]) -> Result<()> {
+    let usage = || anyhow!("Usage: {}", USAGE);
+    let vectors = vectors::generate()?;
+    let (side, address, name) = match args {
+        [command] if command == "vectors" => {
+            println!("{}", serde_json::to_string_pretty(&vectors)?);
+            return Ok(());
+        }
+        [side, address, name] => (side.as_str(), address, name),
+        _ => return Err(usage()),
+    };
+    let vector = vectors
+        .iter()
+        .find(|vector| &vector.name == name)
+        .ok_or(anyhow!("No such vector '{}'", name))?;
+
+    let result = match side {
+        "inbound" => {
+            let listener = TcpListener::bind(address).await?;
+            eprintln!("Listening on {}", listener.local_addr()?);
+            let (stream, _) = listener.accept().await?;
+            inbound(stream, vector).await
+        }
+        "outbound" => outbound(TcpStream::connect(address).await?, vector).await,
+        _ => return Err(usage()),
+    };
+    result.map_err(|e| e.context(format!("Vector '{}'", vector.name)))?;
+    println!("'{}': the peer matched the vector", vector.name);
+    Ok(())
+}
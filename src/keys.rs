@@ -0,0 +1,144 @@
+use crate::{
+    commands,
+    protocol::{
+        encryption::{sha256_from_hex, Secret},
+        handshake::NONCE_LEN,
+    },
+};
+use anyhow::{anyhow, Result};
+use futures::future::BoxFuture;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+// Key commands running at once for an endpoint. Inbound sides run one for each connection
+// before the peer proves anything, so a flood of them is refused rather than forked.
+pub const MAX_KEY_COMMANDS: usize = 8;
+
+// Where the key of tunnel handshakes lives. Providers only answer HMAC-SHA256 requests, so a
+// hardware token (a TPM, a PKCS#11 one) can keep the key to itself:
+//
+// handshake key = HMAC-SHA256(key, "veloxid handshake" || nonce)
+//
+// The handshake then runs with that key instead of the secret, and the session keys derive
+// from it as usual. Only the keys of single handshakes are ever held in memory.
+pub trait KeyProvider: Send + Sync {
+    fn hmac<'a>(&'a self, data: &'a [u8]) -> BoxFuture<'a, Result<[u8; 32]>>;
+}
+
+// Key of the handshake answering the nonce, both sides must use a provider of the same key
+pub async fn handshake_key(
+    provider: &dyn KeyProvider,
+    nonce: &[u8; NONCE_LEN],
+) -> Result<[u8; 32]> {
+    let data = [b"veloxid handshake".as_slice(), nonce].concat();
+    provider.hmac(&data).await
+}
+
+// What keys the handshakes of a tunnel, one or the other. The secret is wiped from memory
+// along with the last copy of it.
+#[derive(Clone)]
+pub enum TunnelKey {
+    Secret(Secret),
+    Provider(Arc<dyn KeyProvider>),
+}
+
+impl TunnelKey {
+    // For what the secret itself keys (tickets, silent hellos, knocks, QUIC), none with a provider
+    pub fn secret(&self) -> Option<&[u8; 32]> {
+        match self {
+            TunnelKey::Secret(secret) => Some(&**secret),
+            TunnelKey::Provider(_) => None,
+        }
+    }
+
+    // Key of the handshake answering the nonce
+    pub async fn for_nonce(&self, nonce: &[u8; NONCE_LEN]) -> Result<Secret> {
+        match self {
+            TunnelKey::Secret(secret) => Ok(secret.clone()),
+            TunnelKey::Provider(provider) => {
+                Ok(Secret::new(handshake_key(&**provider, nonce).await?))
+            }
+        }
+    }
+}
+
+impl From<[u8; 32]> for TunnelKey {
+    fn from(secret: [u8; 32]) -> Self {
+        TunnelKey::Secret(Secret::new(secret))
+    }
+}
+
+// The key in memory, for peers without a token
+pub struct MemoryKey {
+    key: Secret,
+}
+
+impl MemoryKey {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self {
+            key: Secret::new(key),
+        }
+    }
+}
+
+impl KeyProvider for MemoryKey {
+    fn hmac<'a>(&'a self, data: &'a [u8]) -> BoxFuture<'a, Result<[u8; 32]>> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(self.key.as_slice())
+            .expect("HMAC takes keys of any length");
+        mac.update(data);
+        let code = mac.finalize().into_bytes().into();
+        Box::pin(async move { Ok(code) })
+    }
+}
+
+// Runs a command for every request, the data on its stdin. It prints the HMAC, 32 raw bytes or
+// 64 hex digits ending its output, e.g. tpm2_hmac or pkcs11-tool --sign -m SHA256-HMAC.
+#[derive(Clone)]
+pub struct KeyCommand {
+    command: Arc<[String]>,
+    // Shared by the clones, over every route of the endpoint
+    running: Arc<Semaphore>,
+}
+
+impl KeyCommand {
+    pub fn new(command: Vec<String>) -> Self {
+        Self::with_limit(command, MAX_KEY_COMMANDS)
+    }
+
+    pub fn with_limit(command: Vec<String>, max: usize) -> Self {
+        Self {
+            command: command.into(),
+            running: Arc::new(Semaphore::new(max)),
+        }
+    }
+
+    async fn run(&self, data: &[u8]) -> Result<[u8; 32]> {
+        let _permit = self
+            .running
+            .try_acquire()
+            .map_err(|_| anyhow!("Too many key commands running already"))?;
+        let output = commands::run(&self.command, data).await?;
+        parse_mac(&output).ok_or(anyhow!("'{}' printed no HMAC-SHA256", self.command[0]))
+    }
+}
+
+impl KeyProvider for KeyCommand {
+    fn hmac<'a>(&'a self, data: &'a [u8]) -> BoxFuture<'a, Result<[u8; 32]>> {
+        Box::pin(self.run(data))
+    }
+}
+
+// 32 raw bytes, or the last word of the output as 64 hex digits
+pub fn parse_mac(output: &[u8]) -> Option<[u8; 32]> {
+    if let Ok(raw) = output.try_into() {
+        return Some(raw);
+    }
+    let word = std::str::from_utf8(output)
+        .ok()?
+        .split_whitespace()
+        .last()?;
+    // openssl dgst prints "HMAC-SHA2-256(stdin)= <digits>"
+    sha256_from_hex(word.rsplit('=').next()?)
+}
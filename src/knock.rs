@@ -0,0 +1,219 @@
+use crate::protocol::{
+    encryption::{generate_random_nonce, Secret},
+    unix_time,
+};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305,
+};
+use dashmap::DashMap;
+use log::debug;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    net::UdpSocket,
+    task::{self, JoinHandle},
+    time::{sleep, Duration, Instant},
+};
+
+const LOG_TARGET: &str = "knock";
+
+// Single packet authorization in front of inbound tunnels: their listener closes every
+// connection right away, unless its peer sent a knock to the knock port (UDP) lately.
+//
+// outbound -> inbound: [12 byte nonce][u64 unix time][16 random bytes] the time and the random
+//                      bytes sealed with ChaCha20-Poly1305(knock key, nonce), the source
+//                      address of the knock (16 bytes, IPv4 mapped) as associated data
+//
+// Knocks are never answered, so the knock port looks as closed as the listener. Each one is
+// taken once, a replayed one is dropped, and one sent from elsewhere lets nobody in.
+
+const RANDOM_LEN: usize = 16;
+const PLAINTEXT_LEN: usize = 8 + RANDOM_LEN;
+pub const KNOCK_LEN: usize = 12 + PLAINTEXT_LEN + 16;
+
+// Seconds the clocks of both sides may be apart
+pub const KNOCK_SKEW: u64 = 30;
+// Seconds a peer may connect for after knocking, unless the endpoint says otherwise
+pub const DEFAULT_KNOCK_WINDOW: u64 = 30;
+// Head start of the knock over the connection
+const KNOCK_DELAY: Duration = Duration::from_millis(20);
+
+fn cipher(secret: &[u8; 32]) -> ChaCha20Poly1305 {
+    let key = Secret::new(
+        Sha256::new()
+            .chain_update(secret)
+            .chain_update(b"veloxid knock")
+            .finalize()
+            .into(),
+    );
+    ChaCha20Poly1305::new(&(*key).into())
+}
+
+// The same for both families, IPv4 peers of dual-stack sockets show up mapped
+fn source_bytes(source: IpAddr) -> [u8; 16] {
+    match source {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+// A knock from source, the address the inbound side sees it come from
+pub fn knock(secret: &[u8; 32], source: IpAddr) -> [u8; KNOCK_LEN] {
+    knock_at(secret, source, unix_time())
+}
+
+pub fn knock_at(secret: &[u8; 32], source: IpAddr, time: u64) -> [u8; KNOCK_LEN] {
+    let mut plaintext = [0u8; PLAINTEXT_LEN];
+    plaintext[..8].copy_from_slice(&time.to_be_bytes());
+    rand::thread_rng().fill(&mut plaintext[8..]);
+    let nonce = generate_random_nonce();
+    let payload = Payload {
+        msg: &plaintext,
+        aad: &source_bytes(source),
+    };
+    let sealed = cipher(secret)
+        .encrypt(&nonce.into(), payload)
+        .expect("knocks are far below the size limit");
+
+    let mut packet = [0u8; KNOCK_LEN];
+    packet[..12].copy_from_slice(&nonce);
+    packet[12..].copy_from_slice(&sealed);
+    packet
+}
+
+// Knock on an inbound side's knock port, before connecting to it. The knock is from the
+// address of the socket unless the source says otherwise, for sides with a NAT between them.
+pub async fn send(addr: SocketAddr, secret: &[u8; 32], source: Option<IpAddr>) -> io::Result<()> {
+    let local = match addr {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    let socket = UdpSocket::bind(local).await?;
+    // Connected, the socket has the address the system sends from
+    socket.connect(addr).await?;
+    let source = match source {
+        Some(source) => source,
+        None => socket.local_addr()?.ip(),
+    };
+    socket.send(&knock(secret, source)).await?;
+    sleep(KNOCK_DELAY).await;
+    Ok(())
+}
+
+// Peers an inbound endpoint lets connect, for the window after their knock
+#[derive(Clone)]
+pub struct Knocks {
+    cipher: ChaCha20Poly1305,
+    window: Duration,
+    allowed: Arc<DashMap<IpAddr, Instant>>,
+    // Nonce of each knock taken, until it is out of the skew
+    taken: Arc<Mutex<HashMap<[u8; 12], u64>>>,
+}
+
+impl Knocks {
+    pub fn new(secret: &[u8; 32], window: Duration) -> Self {
+        Self {
+            cipher: cipher(secret),
+            window,
+            allowed: Arc::default(),
+            taken: Arc::default(),
+        }
+    }
+
+    // Lets the peer connect if the packet is a knock from it not taken yet
+    pub fn admit(&self, peer: IpAddr, packet: &[u8]) -> bool {
+        self.admit_at(peer, packet, unix_time())
+    }
+
+    pub fn admit_at(&self, peer: IpAddr, packet: &[u8], now: u64) -> bool {
+        if packet.len() != KNOCK_LEN {
+            return false;
+        }
+        let (nonce, sealed) = packet.split_at(12);
+        let payload = Payload {
+            msg: sealed,
+            aad: &source_bytes(peer),
+        };
+        let Ok(plaintext) = self.cipher.decrypt(nonce.into(), payload) else {
+            return false;
+        };
+        let time = u64::from_be_bytes(plaintext[..8].try_into().unwrap());
+        if time.abs_diff(now) > KNOCK_SKEW {
+            return false;
+        }
+
+        let mut taken = self.taken.lock().unwrap();
+        taken.retain(|_, expires| *expires >= now);
+        if taken
+            .insert(nonce.try_into().unwrap(), time + KNOCK_SKEW)
+            .is_some()
+        {
+            return false;
+        }
+        let now = Instant::now();
+        self.allowed.retain(|_, until| *until > now);
+        self.allowed.insert(peer, now + self.window);
+        true
+    }
+
+    // Whether the peer knocked within the window
+    pub fn allows(&self, peer: IpAddr) -> bool {
+        self.allowed
+            .get(&peer)
+            .is_some_and(|until| *until > Instant::now())
+    }
+}
+
+// The knock port of an inbound endpoint, closed once dropped
+pub struct KnockListener {
+    knocks: Knocks,
+    addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl KnockListener {
+    pub async fn bind(addr: SocketAddr, knocks: Knocks) -> io::Result<Self> {
+        let socket = UdpSocket::bind(addr).await?;
+        let addr = socket.local_addr()?;
+        let task = task::spawn(listen(socket, knocks.clone()));
+        Ok(Self { knocks, addr, task })
+    }
+
+    pub fn knocks(&self) -> &Knocks {
+        &self.knocks
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for KnockListener {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn listen(socket: UdpSocket, knocks: Knocks) {
+    let mut packet = [0u8; KNOCK_LEN + 1];
+    loop {
+        let (len, peer) = match socket.recv_from(&mut packet).await {
+            Ok(received) => received,
+            Err(e) => {
+                debug!(target: LOG_TARGET, "Couldn't receive a knock: {}", e);
+                continue;
+            }
+        };
+        match knocks.admit(peer.ip(), &packet[..len]) {
+            true => debug!(target: LOG_TARGET, "{} knocked", peer.ip()),
+            false => debug!(target: LOG_TARGET, "Dropped a knock from {}", peer.ip()),
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use dashmap::DashMap;
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+
+// Round trip times of the inbound tunnels open right now, timed on their handshake and by
+// the heartbeats while they wait to be attached. Peers of the same route can be compared,
+// a tunnel stays listed with its last times while its session runs.
+#[derive(Clone, Default)]
+pub struct LatencyTable {
+    tunnels: Arc<DashMap<u64, TunnelLatency>>,
+    next_id: Arc<AtomicU64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TunnelLatency {
+    // Log target of the worker, like the sessions' route
+    pub route: String,
+    pub endpoint: String,
+    pub peer: Option<SocketAddr>,
+    // Unset until the first round trip is timed
+    pub last: Option<Duration>,
+    // Weighted like TCP's smoothed RTT, 1/8 of each new sample
+    pub smoothed: Option<Duration>,
+    pub min: Option<Duration>,
+    pub samples: u64,
+    pub updated: Option<SystemTime>,
+}
+
+impl TunnelLatency {
+    fn record(&mut self, rtt: Duration) {
+        self.smoothed = Some(match self.smoothed {
+            Some(smoothed) => smoothed * 7 / 8 + rtt / 8,
+            None => rtt,
+        });
+        self.min = Some(self.min.map_or(rtt, |min| min.min(rtt)));
+        self.last = Some(rtt);
+        self.samples += 1;
+        self.updated = Some(SystemTime::now());
+    }
+}
+
+impl LatencyTable {
+    // The tunnel stays listed until the handle is dropped
+    pub fn track(&self, route: &str, endpoint: &str, peer: Option<SocketAddr>) -> LatencyHandle {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.tunnels.insert(
+            id,
+            TunnelLatency {
+                route: route.to_owned(),
+                endpoint: endpoint.to_owned(),
+                peer,
+                last: None,
+                smoothed: None,
+                min: None,
+                samples: 0,
+                updated: None,
+            },
+        );
+        LatencyHandle {
+            table: self.clone(),
+            id,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tunnels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tunnels.is_empty()
+    }
+
+    // Every tunnel, by route and then by peer
+    pub fn list(&self) -> Vec<TunnelLatency> {
+        let mut tunnels: Vec<_> = self.tunnels.iter().map(|t| t.value().clone()).collect();
+        tunnels.sort_by(|a, b| (&a.route, a.peer).cmp(&(&b.route, b.peer)));
+        tunnels
+    }
+}
+
+// Held by the tunnel while it is open
+pub struct LatencyHandle {
+    table: LatencyTable,
+    id: u64,
+}
+
+impl LatencyHandle {
+    pub fn record(&self, rtt: Duration) {
+        if let Some(mut tunnel) = self.table.tunnels.get_mut(&self.id) {
+            tunnel.record(rtt);
+        }
+    }
+}
+
+impl Drop for LatencyHandle {
+    fn drop(&mut self) {
+        self.table.tunnels.remove(&self.id);
+    }
+}
@@ -0,0 +1,55 @@
+// Layers reusable on their own: transport (the streams tunnels run over), protocol (handshake
+// state machines, frames and ciphers, no I/O of their own) and relay (tunnels, copy loops and
+// the routes joining them). The rest is the relay's configuration and operation.
+pub mod protocol;
+pub mod relay;
+pub mod transport;
+
+pub mod activation;
+pub mod admin;
+#[cfg(feature = "api")]
+pub mod api;
+pub mod audit;
+pub mod balance;
+pub mod budget;
+pub mod clients;
+pub mod commands;
+pub mod config;
+pub mod connect;
+#[cfg(feature = "dashboard")]
+pub mod dashboard;
+pub mod detect;
+pub mod diag;
+#[cfg(feature = "discovery")]
+pub mod discovery;
+pub mod error;
+pub mod events;
+pub mod firewall;
+pub mod honeypot;
+pub mod interop;
+pub mod keys;
+pub mod knock;
+pub mod latency;
+pub mod logfile;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod portmap;
+pub mod privileges;
+pub mod probes;
+pub mod punch;
+pub mod reload;
+#[cfg(feature = "tap")]
+pub mod replay;
+#[cfg(all(target_os = "linux", feature = "sandbox"))]
+pub mod sandbox;
+pub mod scaling;
+pub mod schedule;
+pub mod security;
+pub mod selftest;
+pub mod services;
+pub mod sessions;
+pub mod stun;
+pub mod table;
+#[cfg(feature = "tap")]
+pub mod tap;
+pub mod tarpit;
@@ -0,0 +1,183 @@
+use crate::config::{LogConfig, RotationPeriod};
+use chrono::{Local, Timelike};
+use flate2::{write::GzEncoder, Compression};
+use log::error;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    thread::{self, JoinHandle},
+};
+
+pub const DEFAULT_KEEP: usize = 7;
+
+// The log written to a file, rotated by size or period: the file is renamed file.1 (file.1.gz
+// once compressed), the older ones move up by one and past keep they are removed
+pub struct LogFile {
+    path: PathBuf,
+    max_bytes: Option<u64>,
+    period: Option<RotationPeriod>,
+    keep: usize,
+    compress: bool,
+    file: File,
+    size: u64,
+    // Period the file was opened in
+    opened: Option<i64>,
+    // Gzipping the last rotated file, waited for before the next rotation
+    compressing: Option<JoinHandle<()>>,
+}
+
+// Hours or days since the epoch, local time
+fn current_period(period: RotationPeriod) -> i64 {
+    let now = Local::now().naive_local();
+    let days = now.and_utc().timestamp().div_euclid(86400);
+    match period {
+        RotationPeriod::Daily => days,
+        RotationPeriod::Hourly => days * 24 + now.hour() as i64,
+    }
+}
+
+fn gzip(path: &Path, gzipped: &Path) -> io::Result<()> {
+    let mut encoder = GzEncoder::new(File::create(gzipped)?, Compression::default());
+    io::copy(&mut File::open(path)?, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    fs::remove_file(path)
+}
+
+impl LogFile {
+    pub fn open(config: &LogConfig) -> io::Result<Self> {
+        let path = PathBuf::from(&config.file);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self {
+            size: file.metadata()?.len(),
+            file,
+            max_bytes: config.max_bytes,
+            period: config.rotate,
+            keep: config.keep.unwrap_or(DEFAULT_KEEP),
+            compress: config.compress.unwrap_or(false),
+            opened: config.rotate.map(current_period),
+            compressing: None,
+            path,
+        })
+    }
+
+    // Name of the rotated file at index
+    fn rotated(&self, idx: usize, gzipped: bool) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", idx));
+        if gzipped {
+            name.push(".gz");
+        }
+        PathBuf::from(name)
+    }
+
+    pub fn rotate(&mut self) -> io::Result<()> {
+        if let Some(compressing) = self.compressing.take() {
+            let _ = compressing.join();
+        }
+        self.file.flush()?;
+
+        // The oldest goes, either form of it
+        for gzipped in [false, true] {
+            match fs::remove_file(self.rotated(self.keep, gzipped)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        for idx in (1..self.keep).rev() {
+            for gzipped in [false, true] {
+                let from = self.rotated(idx, gzipped);
+                if from.exists() {
+                    fs::rename(&from, self.rotated(idx + 1, gzipped))?;
+                }
+            }
+        }
+        let last = self.rotated(1, false);
+        fs::rename(&self.path, &last)?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        self.opened = self.period.map(current_period);
+
+        if self.compress {
+            let gzipped = self.rotated(1, true);
+            self.compressing = Some(thread::spawn(move || {
+                if let Err(e) = gzip(&last, &gzipped) {
+                    // The log being this file, stderr is what is left
+                    eprintln!("Couldn't compress '{}': {}", last.display(), e);
+                }
+            }));
+        }
+        Ok(())
+    }
+
+    // Waits for the last rotated file to be compressed
+    pub fn wait(&mut self) {
+        if let Some(compressing) = self.compressing.take() {
+            let _ = compressing.join();
+        }
+    }
+
+    fn due(&self, len: usize) -> bool {
+        let full = self
+            .max_bytes
+            .is_some_and(|max| self.size > 0 && self.size + len as u64 > max);
+        let turned = match (self.period, self.opened) {
+            (Some(period), Some(opened)) => current_period(period) != opened,
+            _ => false,
+        };
+        full || turned
+    }
+}
+
+// Each record comes in a single write
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.due(buf.len()) {
+            if let Err(e) = self.rotate() {
+                eprintln!("Couldn't rotate '{}': {}", self.path.display(), e);
+                // Tried again at the next size or period rather than on every record
+                self.size = 0;
+                self.opened = self.period.map(current_period);
+            }
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+// A file other tools read line by line (the audit trail, the security log), appended to from
+// any task. A single write per line keeps lines whole with O_APPEND, even with other writers.
+pub struct LineFile {
+    path: String,
+    file: Mutex<File>,
+    // Log target failed writes are reported under
+    target: &'static str,
+}
+
+impl LineFile {
+    pub fn open(path: &str, target: &'static str) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            path: path.to_owned(),
+            file: Mutex::new(file),
+            target,
+        })
+    }
+
+    pub fn write_line(&self, line: &str) {
+        let line = format!("{}\n", line);
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            error!(target: self.target, "Couldn't write to '{}': {}", self.path, e);
+        }
+    }
+}
@@ -1,107 +1,729 @@
 use anyhow::Result;
-use config::{Endpoint, Route, VeloxidConfig};
-use connection::ConnectionData;
 use dashmap::DashMap;
-use error::ConfigError;
-use futures::future::try_join_all;
-use log::{info, warn, LevelFilter};
+use futures::future::{join_all, BoxFuture};
+use log::{error, info, warn};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeSet, HashMap, HashSet},
     net::IpAddr,
+    process::ExitCode,
+    sync::Arc,
+};
+use tokio::{
+    net::TcpListener,
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, watch, Mutex, Semaphore},
+    task,
+    time::{Duration, Instant},
+};
+#[cfg(feature = "api")]
+use veloxid::api::{self, ApiState};
+#[cfg(feature = "dashboard")]
+use veloxid::dashboard::{self, Activity, DashboardState};
+#[cfg(feature = "discovery")]
+use veloxid::discovery::Advertiser;
+#[cfg(feature = "otel")]
+use veloxid::otel::{self, Exporter};
+#[cfg(all(target_os = "linux", feature = "sandbox"))]
+use veloxid::sandbox;
+#[cfg(feature = "tap")]
+use veloxid::tap::Tap;
+use veloxid::{
+    activation,
+    admin::{self, AdminState, RouteControl},
+    audit::AuditLog,
+    balance::Balancer,
+    budget::Budget,
+    config::{self, ConnectionType, Endpoint, Route, VeloxidConfig},
+    detect::{self, DispatchTable},
+    error::{self, ConfigError, StartupError},
+    events::EventHandlers,
+    firewall::BanCommand,
+    honeypot::{self, Honeypot},
+    latency::LatencyTable,
+    logfile::LogFile,
+    portmap::{PortMapper, PortMappings},
+    privileges, probes,
+    protocol::encryption::generate_secret_from_string,
+    punch::{self, Broker},
+    relay::connection::{self, ConnectionData, RouteContext},
+    reload,
+    scaling::Scaling,
+    security::SecurityLog,
+    services::{self, ServiceTable},
+    sessions::SessionRegistry,
+    stun::NatTable,
+    table::{self, Exposures},
+    tarpit::{Tarpit, DEFAULT_INTERVAL},
+    transport::stdio,
 };
-use tokio::{task, time::Instant};
-
-mod config;
-mod connection;
-mod encryption;
-mod error;
-mod tunnel;
 
+// Endpoints that can't be set up are returned with their errors instead
 async fn build_conn_map(
     routes: &[Route],
     config_endpoints: &HashMap<String, Endpoint>,
-) -> Result<HashMap<String, ConnectionData>> {
-    // Get unique endpoint names
-    let mut names: HashSet<&str> = HashSet::new();
+) -> (HashMap<String, ConnectionData>, Vec<anyhow::Error>) {
+    // Get unique endpoint names, sorted for the failures to be reported in order
+    let mut names: BTreeSet<&str> = BTreeSet::new();
     for route in routes {
         names.extend(route.endpoints.iter().map(String::as_str));
     }
 
     // Get all connection data in parallel
     let futures = names.iter().map(|&name| async move {
-        let endpoint = config_endpoints
-            .get(name)
-            .ok_or(ConfigError::EndpointNotFound)?;
-        let conn_data = connection::get_connection_data(endpoint).await?;
+        let conn_data = connection::get_chained_connection_data(name, config_endpoints).await?;
         Ok::<_, anyhow::Error>((name.to_owned(), conn_data))
     });
 
     // Collect results
-    let results = try_join_all(futures).await?;
-    Ok(results.into_iter().collect())
+    let mut conn_map = HashMap::new();
+    let mut failures = Vec::new();
+    for (name, result) in names.iter().zip(join_all(futures).await) {
+        match result {
+            Ok((name, conn_data)) => {
+                conn_map.insert(name, conn_data);
+            }
+            Err(e) => failures.push(e.context(format!("Endpoint '{}'", name))),
+        }
+    }
+    (conn_map, failures)
+}
+
+// Advertise the listeners of the endpoints giving a name, in the order of the endpoints
+#[cfg(feature = "discovery")]
+fn advertise(
+    config_endpoints: &HashMap<String, Endpoint>,
+    conn_map: &HashMap<String, ConnectionData>,
+) -> Result<Advertiser> {
+    let mut advertiser = Advertiser::start()?;
+    let mut names: Vec<&String> = conn_map.keys().collect();
+    names.sort();
+    for name in names {
+        advertiser
+            .advertise(name, &config_endpoints[name], &conn_map[name])
+            .map_err(|e| e.context(format!("Endpoint '{}'", name)))?;
+    }
+    Ok(advertiser)
+}
+
+// Listeners of the servers are bound before they are spawned, so the startup fails with them
+async fn bind(listen: &str) -> Result<TcpListener> {
+    TcpListener::bind(listen)
+        .await
+        .map_err(|e| StartupError::Bind(listen.to_owned(), e).into())
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("replay") => replay(&args[1..]).await,
+        Some("connect") => veloxid::connect::run(&args[1..]).await,
+        Some("diag") => veloxid::diag::run(&args[1..]).await,
+        Some("selftest") => veloxid::selftest::run(&args[1..]).await,
+        Some("interop") => veloxid::interop::run(&args[1..]).await,
+        _ => run().await,
+    };
+    let status = match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Error: {:#}", e);
+            error::exit_status(&e)
+        }
+    };
+    // The runtime would wait for the thread still reading stdin
+    if stdio::taken() {
+        std::process::exit(status.into());
+    }
+    ExitCode::from(status)
+}
+
+// Sessions of a capture sent again, see replay::USAGE
+#[cfg(feature = "tap")]
+async fn replay(args: &[String]) -> Result<()> {
+    veloxid::replay::run(args).await
+}
+
+#[cfg(not(feature = "tap"))]
+async fn replay(_args: &[String]) -> Result<()> {
+    Err(anyhow::anyhow!("'replay' needs the 'tap' feature"))
+}
+
+async fn run() -> Result<()> {
     // Config
     let config_path = &std::env::var("VELOXID_CONFIG").unwrap_or("veloxid.toml".to_owned());
-    let config = VeloxidConfig::load(config_path)?;
+    let mut config = VeloxidConfig::load(config_path).map_err(StartupError::Config)?;
 
     // Logging
-    let log_level: LevelFilter = match config.log_level {
-        Some(0) => LevelFilter::Off,
-        Some(1) => LevelFilter::Error,
-        Some(2) => LevelFilter::Warn,
-        Some(4) => LevelFilter::Debug,
-        Some(5) => LevelFilter::Trace,
-        _ => LevelFilter::Info, // Default
+    let mut logger = env_logger::builder();
+    logger.filter_level(config::level_filter(config.log_level));
+    for (route_idx, route) in config.routes.iter().enumerate() {
+        if route.log_level.is_some() {
+            // Targets starting with it, the workers' ones
+            let prefix = format!("{} worker #", route.label(route_idx));
+            logger.filter(Some(&prefix), config::level_filter(route.log_level));
+        }
+    }
+    if let Some(log) = &config.log {
+        let file = LogFile::open(log)
+            .map_err(|e| anyhow::Error::from(e).context(format!("Log file '{}'", log.file)))?;
+        logger.target(env_logger::Target::Pipe(Box::new(file)));
+        logger.write_style(env_logger::WriteStyle::Never);
+    }
+    logger.init();
+
+    // Ban list, shared by every route
+    let ban_list: Arc<DashMap<IpAddr, Instant>> = Arc::new(DashMap::new());
+    let registry = SessionRegistry::default();
+    let latency = LatencyTable::default();
+    // Connectors' NAT reports on a relay, the connector's own on a connector
+    let nat = NatTable::default();
+    let tarpit = config
+        .security
+        .as_ref()
+        .and_then(|s| s.tarpit.as_ref())
+        .map(|tarpit| {
+            let interval = tarpit
+                .interval
+                .map_or(DEFAULT_INTERVAL, Duration::from_secs);
+            Tarpit::new(tarpit.max, interval, ban_list.clone())
+        });
+
+    // Event hooks
+    let mut events = EventHandlers::default();
+    if let Some(audit) = &config.audit {
+        events.register(Arc::new(AuditLog::open(&audit.file)?));
+    }
+    if let Some(log) = config.security.as_ref().and_then(|s| s.log.as_ref()) {
+        events.register(Arc::new(SecurityLog::open(log.file.as_deref())?));
+    }
+    if let Some(ban) = config.security.as_ref().and_then(|s| s.ban.as_ref()) {
+        events.register(Arc::new(BanCommand::new(
+            ban.command.clone(),
+            ban.unban_command.clone(),
+        )));
+    }
+    #[cfg(feature = "dashboard")]
+    let activity = Arc::new(Activity::default());
+    #[cfg(feature = "dashboard")]
+    if config.dashboard.is_some() {
+        events.register(activity.clone());
+    }
+    #[cfg(feature = "otel")]
+    let exporter = match &config.otel {
+        Some(otel) => Some(Arc::new(Exporter::new(otel)?)),
+        None => None,
     };
-    env_logger::builder().filter_level(log_level).init();
+    #[cfg(feature = "otel")]
+    if let Some(exporter) = &exporter {
+        events.register(exporter.clone());
+    }
+
+    // Route table: exports served to the connectors, the agent's services, or the relay's
+    // table followed
+    table::add_exports(&mut config).map_err(StartupError::Config)?;
+    table::add_agent(&mut config).map_err(StartupError::Config)?;
+
+    // Everything else that fails is collected, to be reported at once
+    let mut failures = Vec::new();
+    // Servers and workers, started once everything is bound and privileges are dropped
+    let mut tasks: Vec<BoxFuture<'static, ()>> = Vec::new();
+
+    // OpenTelemetry export
+    #[cfg(feature = "otel")]
+    if let Some(exporter) = exporter {
+        tasks.push(Box::pin(otel::run(exporter)));
+    }
+    #[cfg(not(feature = "otel"))]
+    if config.otel.is_some() {
+        warn!("'otel' is ignored, built without the 'otel' feature");
+    }
+
+    if let Some(control) = config.control.clone() {
+        // Agents ask on the punch ports of their exports where the connectors waiting on the
+        // control listener are
+        let punched: Vec<_> = control
+            .exports
+            .iter()
+            .filter_map(|export| Some((export, export.punch_port?, export.agent_secret.clone()?)))
+            .collect();
+        let routes = punched.iter().map(|(export, ..)| export.name.clone());
+        let broker = Arc::new(Broker::new(routes));
+        let punching = !punched.is_empty();
+        for (export, port, agent_secret) in punched {
+            let (name, broker) = (export.name.clone(), broker.clone());
+            let secret = generate_secret_from_string(agent_secret);
+            match bind(&table::punch_addr(&control, port)).await {
+                Ok(listener) => tasks.push(Box::pin(async move {
+                    if let Err(e) = punch::serve(listener, name, secret, broker).await {
+                        error!(target: "punch", "Punch listener failed: {}", e);
+                    }
+                })),
+                Err(e) => failures.push(e.context(format!("Punch listener of '{}'", name))),
+            }
+        }
+        let broker = punching.then_some(broker);
+        match bind(&table::control_addr(&control)).await {
+            Ok(listener) => {
+                let exposures = control.max_exposed.map(|limit| {
+                    let (ban_list, events) = (ban_list.clone(), events.clone());
+                    let (registry, latency) = (registry.clone(), latency.clone());
+                    let tarpit = tarpit.clone();
+                    Exposures::new(&control, limit, ban_list, events, registry, latency, tarpit)
+                });
+                let nat = nat.clone();
+                tasks.push(Box::pin(async move {
+                    if let Err(e) = table::serve(listener, &control, exposures, broker, nat).await {
+                        error!(target: "table", "Control listener failed: {}", e);
+                    }
+                }));
+            }
+            Err(e) => failures.push(e.context("Control listener")),
+        }
+    }
+    if let Some(relay) = &config.relay {
+        let entries = table::fetch(relay, &nat).await;
+        table::add_imports(&mut config, &entries).map_err(StartupError::Config)?;
+    }
 
-    // Ban list
-    let ban_list: DashMap<IpAddr, Instant> = DashMap::new();
+    // Shared by the routes by priority, imported ones included
+    let budget = config.limits.as_ref().map(|limits| {
+        let routes = config.routes.iter().enumerate();
+        let budget = Budget::new(
+            limits.max_sessions,
+            limits
+                .reserved
+                .unwrap_or(limits.max_sessions.unwrap_or_default() / 10),
+            routes.map(|(idx, route)| (route.label(idx), route.priority.unwrap_or(0))),
+            registry.clone(),
+        );
+        let budget = match limits.reclaim_idle {
+            Some(idle) => budget.reclaim_idle(Duration::from_secs(idle)),
+            None => budget,
+        };
+        match limits.fd_headroom {
+            Some(headroom) => budget.fd_headroom(headroom),
+            None => budget,
+        }
+    });
+    #[cfg(not(target_os = "linux"))]
+    if config
+        .limits
+        .as_ref()
+        .is_some_and(|l| l.fd_headroom.is_some())
+    {
+        warn!("'limits.fd_headroom' is ignored, Linux only");
+    }
 
     // Connection
-    let endpoint_conn_data = build_conn_map(&config.routes, &config.endpoints).await?;
+    let (endpoint_conn_data, endpoint_failures) =
+        build_conn_map(&config.routes, &config.endpoints).await;
+    failures.extend(endpoint_failures);
+    let mut route_controls = Vec::new();
+    let mut dispatch_tables: HashMap<String, DispatchTable> = HashMap::new();
+    // Registry endpoints, with the workers of their routes
+    let mut service_tables: HashMap<String, (ServiceTable, usize)> = HashMap::new();
     for (route_idx, route) in config.routes.iter().enumerate() {
         // Check if it is a RouteToSelf
         let [a, b] = &route.endpoints;
         if a == b {
-            return Err(ConfigError::RouteToSelf.into());
+            let error = anyhow::Error::from(ConfigError::RouteToSelf);
+            failures.push(error.context(route.label(route_idx)));
+            continue;
+        }
+        // Their failures are reported already
+        if !endpoint_conn_data.contains_key(a) || !endpoint_conn_data.contains_key(b) {
+            continue;
         }
 
-        // Get endpoint data
-        let endpoint_a = &endpoint_conn_data[a];
-        let endpoint_b = &endpoint_conn_data[b];
+        // Get endpoint data, routes on auto endpoints get their own queue from the dispatcher
+        // and routes on registry endpoints the queue of their service. Outbound tunnels announce
+        // the route's service, when it has one, instead of the endpoint's.
+        let mut endpoint_data = |name: &String| match config.endpoints[name].kind {
+            _ if config.endpoints[name].registry == Some(true) => {
+                let (table, size) = service_tables.entry(name.clone()).or_default();
+                *size += route.workers().1;
+                ConnectionData::Registered {
+                    queue: table.queue(route.service.as_deref().unwrap_or_default()),
+                }
+            }
+            ConnectionType::Auto => {
+                let (sender, receiver) = mpsc::channel(detect::QUEUE_SIZE);
+                dispatch_tables
+                    .entry(name.clone())
+                    .or_default()
+                    .push((route.dispatch_rule(), sender));
+                let clients = match &endpoint_conn_data[name] {
+                    ConnectionData::Inbound { clients, .. } => clients.clone(),
+                    _ => None,
+                };
+                ConnectionData::Dispatched {
+                    queue: Arc::new(Mutex::new(receiver)),
+                    clients,
+                }
+            }
+            _ => match &route.service {
+                Some(service) => endpoint_conn_data[name].announcing(service),
+                None => endpoint_conn_data[name].clone(),
+            },
+        };
+        let endpoint_a = endpoint_data(a);
+        let endpoint_b = endpoint_data(b);
 
-        // Generate worker tasks
-        for worker_idx in 0..route.size {
-            task::spawn({
+        // Runtime control (enable/disable)
+        let enabled = watch::Sender::new(true);
+
+        // Session slots follow the workers, as they are autoscaled or resized on reload,
+        // unless max_sessions is set
+        let (min_size, max_size) = route.workers();
+        let sessions = Arc::new(Semaphore::new(route.max_sessions.unwrap_or(min_size)));
+        let slots = route.max_sessions.is_none().then(|| sessions.clone());
+        let scaling = Scaling::new(min_size, max_size, slots);
+
+        // Shared by the workers
+        let ctx = RouteContext {
+            ban_list: ban_list.clone(),
+            events: events.clone(),
+            registry: registry.clone(),
+            latency: latency.clone(),
+            schedule: route.schedule.clone(),
+            affinity: route.affinity.unwrap_or_default(),
+            buffer_size: config.endpoints[a]
+                .buffer_size
+                .max(config.endpoints[b].buffer_size),
+            write_timeout: [a, b]
+                .iter()
+                .filter_map(|&idx| config.endpoints[idx].write_timeout)
+                .min()
+                .map(Duration::from_secs),
+            max_in_flight: [a, b]
+                .iter()
+                .filter_map(|&idx| config.endpoints[idx].max_in_flight)
+                .min(),
+            blind: route.blind.unwrap_or(false),
+            sessions: sessions.clone(),
+            max_session_duration: route.max_session_duration.map(Duration::from_secs),
+            budget: budget.clone(),
+            priority: route.priority.unwrap_or(0),
+            balancer: (route.balance == Some(true))
+                .then(|| Balancer::new(route.weights.clone().unwrap_or_default())),
+            tarpit: tarpit.clone(),
+            scaling: Some(scaling.clone()),
+            name: route.label(route_idx),
+            endpoints: [a.clone(), b.clone()],
+            #[cfg(feature = "tap")]
+            tap: match &route.tap {
+                Some(prefix) => match Tap::open(prefix, route.tap_mode.unwrap_or_default()) {
+                    Ok(tap) => Some(tap),
+                    Err(e) => {
+                        failures.push(anyhow::Error::from(e).context(route.label(route_idx)));
+                        continue;
+                    }
+                },
+                None => None,
+            },
+        };
+        #[cfg(not(feature = "tap"))]
+        if route.tap.is_some() {
+            warn!(
+                "{}: 'tap' is ignored, built without the 'tap' feature",
+                route.label(route_idx)
+            );
+        }
+
+        // Generate worker tasks, started and retired as the route is autoscaled or resized
+        let label = route.label(route_idx);
+        let workers = {
+            let enabled = enabled.subscribe();
+            let label = label.clone();
+            move |worker_idx| -> BoxFuture<'static, ()> {
                 let endpoint_a = endpoint_a.clone();
                 let endpoint_b = endpoint_b.clone();
-                let ban_list = ban_list.clone();
-                async move {
-                    connection::route(
-                        endpoint_a,
-                        endpoint_b,
-                        ban_list,
-                        &format!("route #{} worker #{}", route_idx, worker_idx),
-                    )
-                    .await;
-                }
-            });
+                let ctx = ctx.clone();
+                let enabled = enabled.clone();
+                let log_target = config::worker_target(&label, worker_idx);
+                Box::pin(async move {
+                    connection::route(endpoint_a, endpoint_b, ctx, enabled, &log_target).await;
+                })
+            }
+        };
+        tasks.push(Box::pin(scaling.clone().run(label, workers)));
+
+        route_controls.push(RouteControl {
+            name: route.label(route_idx),
+            endpoints: route.endpoints.clone(),
+            enabled,
+            scaling,
+        });
+    }
+
+    // Dispatchers of auto endpoints
+    for (name, table) in dispatch_tables {
+        let endpoint = endpoint_conn_data[&name].clone();
+        tasks.push(Box::pin(detect::dispatch(name, endpoint, table)));
+    }
+
+    // Accept loops of the registry endpoints, the handshakes ban and report like the routes'
+    let services = service_tables
+        .into_iter()
+        .map(|(name, (table, size))| {
+            let ctx = RouteContext {
+                ban_list: ban_list.clone(),
+                events: events.clone(),
+                registry: registry.clone(),
+                latency: latency.clone(),
+                schedule: None,
+                affinity: Default::default(),
+                buffer_size: None,
+                write_timeout: config.endpoints[&name]
+                    .write_timeout
+                    .map(Duration::from_secs),
+                max_in_flight: config.endpoints[&name].max_in_flight,
+                blind: false,
+                sessions: Arc::new(Semaphore::new(size)),
+                max_session_duration: None,
+                // Only paused with too few descriptors left, the tunnels aren't sessions yet
+                budget: budget.clone(),
+                priority: 0,
+                balancer: None,
+                tarpit: tarpit.clone(),
+                scaling: None,
+                name: name.clone(),
+                endpoints: [name.clone(), name.clone()],
+                #[cfg(feature = "tap")]
+                tap: None,
+            };
+            let endpoint = endpoint_conn_data[&name].clone();
+            tasks.push(Box::pin(services::serve(
+                name.clone(),
+                endpoint,
+                table.clone(),
+                ctx,
+                size,
+            )));
+            (name, table)
+        })
+        .collect();
+
+    // Honeypots, banning whoever connects
+    let mut honeypots: Vec<(&String, &Endpoint)> = config
+        .endpoints
+        .iter()
+        .filter(|(_, endpoint)| matches!(endpoint.kind, ConnectionType::Honeypot))
+        .collect();
+    honeypots.sort_by_key(|(name, _)| *name);
+    for (name, endpoint) in honeypots {
+        match connection::get_connection_data(endpoint).await {
+            Ok(ConnectionData::Inbound { listener, .. }) => {
+                let honeypot = Honeypot {
+                    name: name.clone(),
+                    capture: endpoint.capture.unwrap_or(0),
+                    ban_list: ban_list.clone(),
+                    events: events.clone(),
+                };
+                tasks.push(Box::pin(honeypot::serve(listener, honeypot)));
+            }
+            Ok(_) => {}
+            Err(e) => failures.push(e.context(format!("Endpoint '{}'", name))),
+        }
+    }
+
+    // mDNS advertisement of the client listeners
+    let advertised = config.endpoints.values().any(|e| e.advertise.is_some());
+    #[cfg(feature = "discovery")]
+    let advertiser = match advertised {
+        true => advertise(&config.endpoints, &endpoint_conn_data)
+            .map_err(|e| failures.push(e.context("Discovery")))
+            .ok(),
+        false => None,
+    };
+    #[cfg(not(feature = "discovery"))]
+    if advertised {
+        warn!("'advertise' is ignored, built without the 'discovery' feature");
+    }
+
+    // Ports the router forwards to the endpoints asking for it
+    let mut mappers = Vec::new();
+    for (name, endpoint) in &config.endpoints {
+        if !endpoint_conn_data.contains_key(name) {
+            continue;
+        }
+        match PortMapper::new(name, endpoint) {
+            Ok(mapper) => mappers.extend(mapper),
+            Err(e) => failures.push(e.context(format!("Port mapping of '{}'", name))),
         }
     }
 
-    // Warn about unused endpoints
-    for (key, _) in config.endpoints {
-        if !endpoint_conn_data.contains_key(&key) {
+    // Warn about sockets passed for no endpoint, and unused endpoints
+    for addr in activation::sockets().remaining() {
+        warn!("Unused socket passed for {}", addr);
+    }
+    let used: HashSet<&String> = config.routes.iter().flat_map(|r| &r.endpoints).collect();
+    for (key, endpoint) in &config.endpoints {
+        let honeypot = matches!(endpoint.kind, ConnectionType::Honeypot);
+        if !used.contains(key) && !honeypot {
             warn!("Unused endpoint: {}", key);
         }
     }
 
-    // Wait for Ctrl+C
-    tokio::signal::ctrl_c().await?;
+    // Reloads only touch the endpoints, the admin socket keeps its own handles
+    let reload_endpoints = endpoint_conn_data.clone();
+
+    // Admin socket
+    let admin_state = Arc::new(AdminState {
+        routes: route_controls,
+        endpoints: endpoint_conn_data,
+        latency,
+        services,
+        registry: registry.clone(),
+        budget: budget.clone(),
+        nat,
+    });
+    if let Some(admin) = &config.admin {
+        match admin::bind(&admin.socket) {
+            Ok(listener) => {
+                let state = admin_state.clone();
+                tasks.push(Box::pin(async move {
+                    if let Err(e) = admin::serve(listener, state).await {
+                        error!(target: "admin", "Admin socket failed: {}", e);
+                    }
+                }));
+            }
+            Err(e) => {
+                let error = StartupError::Bind(admin.socket.clone(), e);
+                failures.push(anyhow::Error::from(error).context("Admin socket"));
+            }
+        }
+    }
+
+    // Dashboard
+    #[cfg(feature = "dashboard")]
+    if let Some(dashboard) = &config.dashboard {
+        let state = Arc::new(DashboardState {
+            admin: admin_state.clone(),
+            registry: registry.clone(),
+            ban_list: ban_list.clone(),
+            activity,
+            events: events.clone(),
+            password: dashboard.password.clone(),
+        });
+        match bind(&dashboard.listen).await {
+            Ok(listener) => {
+                tasks.push(Box::pin(async move {
+                    if let Err(e) = dashboard::serve(listener, state).await {
+                        error!(target: "dashboard", "Dashboard failed: {}", e);
+                    }
+                }));
+            }
+            Err(e) => failures.push(e.context("Dashboard")),
+        }
+    }
+    #[cfg(not(feature = "dashboard"))]
+    if config.dashboard.is_some() {
+        warn!("'dashboard' is ignored, built without the 'dashboard' feature");
+    }
+
+    // REST API
+    #[cfg(feature = "api")]
+    if let Some(api) = &config.api {
+        let state = Arc::new(ApiState {
+            admin: admin_state.clone(),
+            registry: registry.clone(),
+            ban_list: ban_list.clone(),
+            events: events.clone(),
+            token: api.token.clone(),
+        });
+        match bind(&api.listen).await {
+            Ok(listener) => {
+                tasks.push(Box::pin(async move {
+                    if let Err(e) = api::serve(listener, state).await {
+                        error!(target: "api", "API failed: {}", e);
+                    }
+                }));
+            }
+            Err(e) => failures.push(e.context("API")),
+        }
+    }
+    #[cfg(not(feature = "api"))]
+    if config.api.is_some() {
+        warn!("'api' is ignored, built without the 'api' feature");
+    }
+
+    // Health probes
+    if let Some(probes) = &config.probes {
+        let endpoints = Arc::new(admin_state.endpoints.clone());
+        match bind(&probes.listen).await {
+            Ok(listener) => {
+                tasks.push(Box::pin(async move {
+                    if let Err(e) = probes::serve(listener, endpoints).await {
+                        error!(target: "probes", "Probe listener failed: {}", e);
+                    }
+                }));
+            }
+            Err(e) => failures.push(e.context("Probe listener")),
+        }
+    }
+
+    // A partial startup is no startup
+    match failures.len() {
+        0 => {}
+        1 => return Err(failures.remove(0)),
+        _ => return Err(StartupError::Failed(failures).into()),
+    }
+    let port_mappings = PortMappings::start(mappers);
+
+    // Every listener is bound, the traffic is handled as the configured user
+    if config.user.is_some() || config.group.is_some() {
+        privileges::drop_to(config.user.as_deref(), config.group.as_deref())?;
+        let ids = [("user", &config.user), ("group", &config.group)]
+            .into_iter()
+            .filter_map(|(kind, name)| Some(format!("{} '{}'", kind, name.as_deref()?)))
+            .collect::<Vec<_>>();
+        info!("Dropped privileges, running as {}", ids.join(" and "));
+    }
+    #[cfg(all(target_os = "linux", feature = "sandbox"))]
+    if let Some(config) = &config.sandbox {
+        sandbox::apply(config, config_path).await?;
+    }
+    #[cfg(not(all(target_os = "linux", feature = "sandbox")))]
+    if config.sandbox.is_some() {
+        warn!("'sandbox' is ignored, built without the 'sandbox' feature or not on Linux");
+    }
+    for task in tasks {
+        task::spawn(task);
+    }
+
+    // Reload on SIGHUP and log the statistics on SIGUSR1 until Ctrl+C, or the session of a
+    // stdio endpoint is over
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut user1 = signal(SignalKind::user_defined1())?;
+    loop {
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => {
+                result?;
+                break;
+            }
+            _ = stdio::closed() => {
+                info!("Stdin and stdout are closed");
+                break;
+            }
+            _ = hangup.recv() => {
+                let routes = &admin_state.routes;
+                if let Err(e) = reload::reload(config_path, &reload_endpoints, routes).await {
+                    error!(target: "reload", "Reload failed: {}", e);
+                }
+            }
+            _ = user1.recv() => {
+                for line in admin::statistics(&admin_state, &ban_list).lines() {
+                    info!(target: "stats", "{}", line);
+                }
+            }
+        }
+    }
     info!("Shutting down...");
+    port_mappings.stop().await;
+    #[cfg(feature = "discovery")]
+    if let Some(advertiser) = advertiser {
+        advertiser.stop();
+    }
     Ok(())
 }
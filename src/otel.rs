@@ -0,0 +1,316 @@
+use crate::{
+    config::OtelConfig,
+    events::{EventHandler, SessionInfo, SessionStats},
+};
+use anyhow::{anyhow, Result};
+use log::warn;
+use rand::Rng;
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::{sleep, timeout},
+};
+
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+// Spans kept between two exports, the oldest are dropped past it
+const MAX_PENDING_SPANS: usize = 4096;
+// Time the collector gets to take each export
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// What the counters are kept apart by
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Labels {
+    route: String,
+    endpoints: [String; 2],
+    connector: Option<IpAddr>,
+}
+
+#[derive(Default)]
+struct Counters {
+    sessions: u64,
+    errors: u64,
+    a_to_b: u64,
+    b_to_a: u64,
+}
+
+// Sessions exported to an OpenTelemetry collector over OTLP/HTTP with JSON bodies: a span for
+// each session once it ends, and cumulative counters of the sessions, their failures and
+// bytes. Both are labelled by route name, endpoints and connector.
+pub struct Exporter {
+    // "host:port" of the collector, and the path the signals go under
+    address: String,
+    prefix: String,
+    service_name: String,
+    interval: Duration,
+    // Start of the cumulative counters
+    started: SystemTime,
+    spans: Mutex<VecDeque<Value>>,
+    counters: Mutex<HashMap<Labels, Counters>>,
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    let nanos = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos());
+    nanos.to_string()
+}
+
+fn random_id<const N: usize>() -> String {
+    let mut id = [0u8; N];
+    rand::thread_rng().fill(&mut id[..]);
+    id.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn string(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+fn int(key: &str, value: u64) -> Value {
+    json!({ "key": key, "value": { "intValue": value.to_string() } })
+}
+
+impl Labels {
+    fn of(session: &SessionInfo) -> Self {
+        Self {
+            route: session.route_name.clone(),
+            endpoints: session.endpoints.clone(),
+            connector: session.connector,
+        }
+    }
+
+    fn attributes(&self) -> Vec<Value> {
+        let mut attributes = vec![
+            string("veloxid.route", &self.route),
+            string("veloxid.endpoint.a", &self.endpoints[0]),
+            string("veloxid.endpoint.b", &self.endpoints[1]),
+        ];
+        if let Some(connector) = self.connector {
+            attributes.push(string("veloxid.connector", &connector.to_string()));
+        }
+        attributes
+    }
+}
+
+impl Exporter {
+    pub fn new(config: &OtelConfig) -> Result<Self> {
+        let invalid = || anyhow!("Invalid collector URL '{}'", config.endpoint);
+        let rest = config
+            .endpoint
+            .strip_prefix("http://")
+            .ok_or_else(invalid)?;
+        let (authority, prefix) = match rest.find('/') {
+            Some(idx) => rest.split_at(idx),
+            None => (rest, ""),
+        };
+        if authority.is_empty() {
+            return Err(invalid());
+        }
+        // Bracketed IPv6 addresses end with the bracket when the port is left out
+        let address = match authority.rsplit_once(':') {
+            Some((_, port)) if !port.ends_with(']') => authority.to_owned(),
+            _ => format!("{}:80", authority),
+        };
+        Ok(Self {
+            address,
+            prefix: prefix.trim_end_matches('/').to_owned(),
+            service_name: config.service_name.clone().unwrap_or("veloxid".to_owned()),
+            interval: config
+                .interval
+                .map_or(DEFAULT_INTERVAL, Duration::from_secs),
+            started: SystemTime::now(),
+            spans: Mutex::new(VecDeque::new()),
+            counters: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn resource(&self) -> Value {
+        json!({ "attributes": [string("service.name", &self.service_name)] })
+    }
+
+    fn scope() -> Value {
+        json!({ "name": "veloxid", "version": env!("CARGO_PKG_VERSION") })
+    }
+
+    // ExportTraceServiceRequest of the spans ended since the last call, None without any
+    pub fn traces(&self) -> Option<Value> {
+        let spans: Vec<Value> = self
+            .spans
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .drain(..)
+            .collect();
+        if spans.is_empty() {
+            return None;
+        }
+        Some(json!({
+            "resourceSpans": [{
+                "resource": self.resource(),
+                "scopeSpans": [{ "scope": Self::scope(), "spans": spans }],
+            }]
+        }))
+    }
+
+    // ExportMetricsServiceRequest of the counters so far, None before the first session
+    pub fn metrics(&self) -> Option<Value> {
+        let counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+        if counters.is_empty() {
+            return None;
+        }
+        let (start, now) = (unix_nanos(self.started), unix_nanos(SystemTime::now()));
+        let point = |attributes: Vec<Value>, value: u64| {
+            json!({
+                "attributes": attributes,
+                "startTimeUnixNano": start,
+                "timeUnixNano": now,
+                "asInt": value.to_string(),
+            })
+        };
+        let mut sessions = Vec::new();
+        let mut errors = Vec::new();
+        let mut bytes = Vec::new();
+        for (labels, counters) in counters.iter() {
+            let attributes = labels.attributes();
+            sessions.push(point(attributes.clone(), counters.sessions));
+            errors.push(point(attributes.clone(), counters.errors));
+            for (direction, value) in [("a_to_b", counters.a_to_b), ("b_to_a", counters.b_to_a)] {
+                let mut attributes = attributes.clone();
+                attributes.push(string("veloxid.direction", direction));
+                bytes.push(point(attributes, value));
+            }
+        }
+        // Cumulative temporality, monotonic
+        let sum = |name: &str, unit: &str, description: &str, points: Vec<Value>| {
+            json!({
+                "name": name,
+                "unit": unit,
+                "description": description,
+                "sum": {
+                    "dataPoints": points,
+                    "aggregationTemporality": 2,
+                    "isMonotonic": true,
+                },
+            })
+        };
+        Some(json!({
+            "resourceMetrics": [{
+                "resource": self.resource(),
+                "scopeMetrics": [{
+                    "scope": Self::scope(),
+                    "metrics": [
+                        sum("veloxid.sessions", "{session}", "Sessions ended", sessions),
+                        sum("veloxid.session.errors", "{session}", "Sessions ended by an error", errors),
+                        sum("veloxid.bytes", "By", "Bytes copied by the sessions", bytes),
+                    ],
+                }],
+            }]
+        }))
+    }
+
+    // A single POST per connection, the collector's answer only tells whether it was taken
+    async fn post(&self, path: &str, body: &Value) -> Result<()> {
+        let body = body.to_string();
+        let request = format!(
+            "POST {}{} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.prefix,
+            path,
+            self.address,
+            body.len(),
+            body
+        );
+        let response = timeout(EXPORT_TIMEOUT, async {
+            let mut stream = TcpStream::connect(&self.address).await?;
+            stream.write_all(request.as_bytes()).await?;
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).await?;
+            Ok::<_, std::io::Error>(response)
+        })
+        .await
+        .map_err(|_| anyhow!("No answer within {:?}", EXPORT_TIMEOUT))??;
+
+        let response = String::from_utf8_lossy(&response);
+        let status = response.lines().next().unwrap_or_default();
+        match status.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(anyhow!("The collector answered '{}'", status)),
+        }
+    }
+
+    // Sends what is pending, a failed export of the spans loses them
+    pub async fn export(&self) {
+        if let Some(traces) = self.traces() {
+            if let Err(e) = self.post("/v1/traces", &traces).await {
+                warn!(target: "otel", "Couldn't export the spans to '{}': {}", self.address, e);
+            }
+        }
+        if let Some(metrics) = self.metrics() {
+            if let Err(e) = self.post("/v1/metrics", &metrics).await {
+                warn!(target: "otel", "Couldn't export the metrics to '{}': {}", self.address, e);
+            }
+        }
+    }
+}
+
+impl EventHandler for Exporter {
+    fn on_session_end(&self, session: &SessionInfo, stats: &SessionStats) {
+        let labels = Labels::of(session);
+        {
+            let mut counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+            let counters = counters.entry(labels.clone()).or_default();
+            counters.sessions += 1;
+            counters.errors += stats.error.is_some() as u64;
+            counters.a_to_b += stats.traffic.a_to_b;
+            counters.b_to_a += stats.traffic.b_to_a;
+        }
+
+        let mut attributes = labels.attributes();
+        attributes.extend([
+            string("veloxid.session.worker", &session.route),
+            int("veloxid.session.id", session.id),
+            int("veloxid.bytes.a_to_b", stats.traffic.a_to_b),
+            int("veloxid.bytes.b_to_a", stats.traffic.b_to_a),
+        ]);
+        for (key, peer) in [
+            ("veloxid.peer.a", session.peer_a),
+            ("veloxid.peer.b", session.peer_b),
+        ] {
+            if let Some(peer) = peer {
+                attributes.push(string(key, &peer.to_string()));
+            }
+        }
+        // Unset or error
+        let status = match &stats.error {
+            Some(error) => json!({ "code": 2, "message": error }),
+            None => json!({ "code": 0 }),
+        };
+        let span = json!({
+            "traceId": random_id::<16>(),
+            "spanId": random_id::<8>(),
+            "name": format!("session {}", labels.route),
+            // Server
+            "kind": 2,
+            "startTimeUnixNano": unix_nanos(session.started),
+            "endTimeUnixNano": unix_nanos(session.started + stats.duration),
+            "attributes": attributes,
+            "status": status,
+        });
+
+        let mut spans = self.spans.lock().unwrap_or_else(|e| e.into_inner());
+        if spans.len() == MAX_PENDING_SPANS {
+            spans.pop_front();
+        }
+        spans.push_back(span);
+    }
+}
+
+// Exports every interval for as long as the relay runs
+pub async fn run(exporter: Arc<Exporter>) {
+    loop {
+        sleep(exporter.interval).await;
+        exporter.export().await;
+    }
+}
@@ -0,0 +1,672 @@
+use crate::config::{ConnectionType, Endpoint, PortMapping, TransportKind};
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info, warn};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{lookup_host, TcpStream, UdpSocket},
+    sync::watch,
+    task::JoinHandle,
+    time::{sleep, timeout, Duration},
+};
+
+const LOG_TARGET: &str = "portmap";
+
+// RFC 6886
+pub const NAT_PMP_PORT: u16 = 5351;
+const NAT_PMP_VERSION: u8 = 0;
+const OP_EXTERNAL_ADDRESS: u8 = 0;
+const OP_MAP_UDP: u8 = 1;
+const OP_MAP_TCP: u8 = 2;
+const OP_RESPONSE: u8 = 128;
+
+// UPnP IGD, found over SSDP
+const SSDP_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(239, 255, 255, 250)), 1900);
+const SEARCH_TARGET: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+// Services able to map ports, preferred in this order
+const UPNP_SERVICES: &[&str] = &[
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+// The router only keeps mappings without a lease
+const ONLY_PERMANENT_LEASES: &str = "725";
+
+pub const DEFAULT_LEASE: Duration = Duration::from_secs(3600);
+// Time between attempts while the router doesn't map the port
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+// Renewals come no sooner than this, whatever lease the router grants
+const MIN_RENEWAL: Duration = Duration::from_secs(10);
+// Requests sent to the router before giving up, each one waited for twice as long
+const ATTEMPTS: u32 = 4;
+const FIRST_WAIT: Duration = Duration::from_millis(250);
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(3);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+// Time the mappings get to be removed on exit
+const UNMAP_TIMEOUT: Duration = Duration::from_secs(3);
+const MAX_HTTP_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpProtocol {
+    Tcp,
+    Udp,
+}
+
+impl IpProtocol {
+    fn as_str(self) -> &'static str {
+        match self {
+            IpProtocol::Tcp => "TCP",
+            IpProtocol::Udp => "UDP",
+        }
+    }
+}
+
+// A port of the router's public address forwarded to the endpoint, for as long as the lease.
+// A zero lease lasts until the mapping is removed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub external: SocketAddr,
+    pub lease: Duration,
+}
+
+// A router taking port mapping requests
+#[derive(Debug, Clone)]
+pub enum Gateway {
+    NatPmp(SocketAddr),
+    Upnp(Upnp),
+}
+
+impl Gateway {
+    // Finds the router over the method given, trying NAT-PMP before UPnP for auto. Without an
+    // address, NAT-PMP asks the default route's gateway and UPnP searches the LAN.
+    pub async fn find(method: PortMapping, gateway: Option<IpAddr>) -> Result<Self> {
+        let nat_pmp = |ip| Gateway::NatPmp(SocketAddr::new(ip, NAT_PMP_PORT));
+        match method {
+            PortMapping::NatPmp => Ok(nat_pmp(gateway.map_or_else(default_gateway, Ok)?)),
+            PortMapping::Upnp => Ok(Gateway::Upnp(Upnp::find(gateway).await?)),
+            PortMapping::Auto => {
+                if let Ok(ip) = gateway.map_or_else(default_gateway, Ok) {
+                    match nat_pmp::external_address(SocketAddr::new(ip, NAT_PMP_PORT)).await {
+                        Ok(_) => return Ok(nat_pmp(ip)),
+                        Err(e) => debug!(target: LOG_TARGET, "No NAT-PMP on {}: {}", ip, e),
+                    }
+                }
+                Ok(Gateway::Upnp(Upnp::find(gateway).await?))
+            }
+        }
+    }
+
+    pub async fn map(
+        &self,
+        protocol: IpProtocol,
+        internal: SocketAddr,
+        external_port: u16,
+        lease: Duration,
+        description: &str,
+    ) -> Result<Mapping> {
+        match self {
+            Gateway::NatPmp(addr) => {
+                nat_pmp::map(*addr, protocol, internal.port(), external_port, lease).await
+            }
+            Gateway::Upnp(upnp) => {
+                let mapping = upnp.map(protocol, internal, external_port, lease, description);
+                match mapping.await {
+                    Err(e) if e.to_string().contains(ONLY_PERMANENT_LEASES) => {
+                        let permanent = Duration::ZERO;
+                        upnp.map(protocol, internal, external_port, permanent, description)
+                            .await
+                    }
+                    mapping => mapping,
+                }
+            }
+        }
+    }
+
+    pub async fn unmap(
+        &self,
+        protocol: IpProtocol,
+        internal: SocketAddr,
+        external_port: u16,
+    ) -> Result<()> {
+        match self {
+            Gateway::NatPmp(addr) => {
+                nat_pmp::map(*addr, protocol, internal.port(), 0, Duration::ZERO).await?;
+                Ok(())
+            }
+            Gateway::Upnp(upnp) => upnp.unmap(protocol, external_port).await,
+        }
+    }
+}
+
+pub mod nat_pmp {
+    use super::*;
+
+    // The router's public address
+    pub async fn external_address(gateway: SocketAddr) -> Result<Ipv4Addr> {
+        let response = request(gateway, &[NAT_PMP_VERSION, OP_EXTERNAL_ADDRESS], 12).await?;
+        Ok(Ipv4Addr::new(
+            response[8],
+            response[9],
+            response[10],
+            response[11],
+        ))
+    }
+
+    // Maps the port, or removes its mapping with a zero lease and external port
+    pub async fn map(
+        gateway: SocketAddr,
+        protocol: IpProtocol,
+        internal_port: u16,
+        external_port: u16,
+        lease: Duration,
+    ) -> Result<Mapping> {
+        let op = match protocol {
+            IpProtocol::Udp => OP_MAP_UDP,
+            IpProtocol::Tcp => OP_MAP_TCP,
+        };
+        let mut packet = [0u8; 12];
+        packet[0] = NAT_PMP_VERSION;
+        packet[1] = op;
+        packet[4..6].copy_from_slice(&internal_port.to_be_bytes());
+        packet[6..8].copy_from_slice(&external_port.to_be_bytes());
+        let lifetime = u32::try_from(lease.as_secs()).unwrap_or(u32::MAX);
+        packet[8..12].copy_from_slice(&lifetime.to_be_bytes());
+
+        let response = request(gateway, &packet, 16).await?;
+        let port = u16::from_be_bytes([response[10], response[11]]);
+        let lifetime = u32::from_be_bytes([response[12], response[13], response[14], response[15]]);
+        // Removals don't need the address
+        let ip = match lease.is_zero() {
+            true => Ipv4Addr::UNSPECIFIED,
+            false => external_address(gateway).await?,
+        };
+        Ok(Mapping {
+            external: SocketAddr::new(IpAddr::V4(ip), port),
+            lease: Duration::from_secs(lifetime.into()),
+        })
+    }
+
+    // Sends the request until the router answers it, checking the result code
+    async fn request(gateway: SocketAddr, packet: &[u8], len: usize) -> Result<Vec<u8>> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        socket.connect(gateway).await?;
+        let op = packet[1] | OP_RESPONSE;
+
+        let mut wait = FIRST_WAIT;
+        for _ in 0..ATTEMPTS {
+            socket.send(packet).await?;
+            let answer = timeout(wait, async {
+                let mut response = [0u8; 16];
+                loop {
+                    let read = socket.recv(&mut response).await?;
+                    // Announcements of address changes go to the same port
+                    if read >= len && response[0] == NAT_PMP_VERSION && response[1] == op {
+                        return Ok::<_, anyhow::Error>(response[..len].to_vec());
+                    }
+                }
+            });
+            if let Ok(response) = answer.await {
+                let response = response?;
+                return match u16::from_be_bytes([response[2], response[3]]) {
+                    0 => Ok(response),
+                    code => Err(anyhow!("{} refused the request: {}", gateway, result(code))),
+                };
+            }
+            wait *= 2;
+        }
+        Err(anyhow!("{} didn't answer over NAT-PMP", gateway))
+    }
+
+    fn result(code: u16) -> &'static str {
+        match code {
+            1 => "unsupported version",
+            2 => "not authorized",
+            3 => "network failure",
+            4 => "out of resources",
+            5 => "unsupported opcode",
+            _ => "unknown result code",
+        }
+    }
+}
+
+// The control URL of a router's connection service
+#[derive(Debug, Clone)]
+pub struct Upnp {
+    addr: SocketAddr,
+    host: String,
+    path: String,
+    service: String,
+}
+
+impl Upnp {
+    // Searches for the router over SSDP, asking it alone when its address is given
+    pub async fn find(gateway: Option<IpAddr>) -> Result<Self> {
+        let target = gateway.map_or(SSDP_ADDR, |ip| SocketAddr::new(ip, SSDP_ADDR.port()));
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        let search = format!(
+            "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\n\r\n",
+            SSDP_ADDR, SEARCH_TARGET
+        );
+        socket.send_to(search.as_bytes(), target).await?;
+
+        let location = timeout(SEARCH_TIMEOUT, async {
+            let mut packet = [0u8; 2048];
+            loop {
+                let (len, from) = socket.recv_from(&mut packet).await?;
+                let response = String::from_utf8_lossy(&packet[..len]);
+                match location(&response) {
+                    Some(location) => return Ok::<_, anyhow::Error>(location.to_owned()),
+                    None => debug!(target: LOG_TARGET, "Dropped an SSDP answer of {}", from),
+                }
+            }
+        })
+        .await
+        .map_err(|_| anyhow!("No UPnP router answered within {:?}", SEARCH_TIMEOUT))??;
+        Self::from_location(&location).await
+    }
+
+    // Reads the device description of the router at the location, for its control URL
+    pub async fn from_location(location: &str) -> Result<Self> {
+        let (host, path) = split_url(location)?;
+        let addr = resolve(host).await?;
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            path, host
+        );
+        let (status, description) = http(addr, &request).await?;
+        if status != 200 {
+            return Err(anyhow!("{} answered with status {}", location, status));
+        }
+        let (service, control) =
+            control_url(&description).ok_or(anyhow!("{} has no connection service", location))?;
+        let (host, path) = match control.starts_with("http://") {
+            true => split_url(control)?,
+            false => (host, control),
+        };
+        let path = match path.starts_with('/') {
+            true => path.to_owned(),
+            false => format!("/{}", path),
+        };
+        Ok(Self {
+            addr: resolve(host).await?,
+            host: host.to_owned(),
+            path,
+            service: service.to_owned(),
+        })
+    }
+
+    async fn map(
+        &self,
+        protocol: IpProtocol,
+        internal: SocketAddr,
+        external_port: u16,
+        lease: Duration,
+        description: &str,
+    ) -> Result<Mapping> {
+        // The router forwards to the address it is reached from unless the endpoint has one
+        let client = match internal.ip().is_unspecified() {
+            true => local_ip(self.addr).await?,
+            false => internal.ip(),
+        };
+        let args = [
+            ("NewRemoteHost", String::new()),
+            ("NewExternalPort", external_port.to_string()),
+            ("NewProtocol", protocol.as_str().to_owned()),
+            ("NewInternalPort", internal.port().to_string()),
+            ("NewInternalClient", client.to_string()),
+            ("NewEnabled", "1".to_owned()),
+            ("NewPortMappingDescription", escape(description)),
+            ("NewLeaseDuration", lease.as_secs().to_string()),
+        ];
+        self.soap("AddPortMapping", &args).await?;
+
+        let response = self.soap("GetExternalIPAddress", &[]).await?;
+        let ip = tag(&response, "NewExternalIPAddress")
+            .and_then(|ip| ip.parse().ok())
+            .ok_or(anyhow!("The router didn't give its public address"))?;
+        Ok(Mapping {
+            external: SocketAddr::new(ip, external_port),
+            lease,
+        })
+    }
+
+    async fn unmap(&self, protocol: IpProtocol, external_port: u16) -> Result<()> {
+        let args = [
+            ("NewRemoteHost", String::new()),
+            ("NewExternalPort", external_port.to_string()),
+            ("NewProtocol", protocol.as_str().to_owned()),
+        ];
+        self.soap("DeletePortMapping", &args).await?;
+        Ok(())
+    }
+
+    // Calls the action on the service, returning the body of the response
+    async fn soap(&self, action: &str, args: &[(&str, String)]) -> Result<String> {
+        let args: String = args
+            .iter()
+            .map(|(name, value)| format!("<{0}>{1}</{0}>", name, value))
+            .collect();
+        let body = format!(
+            "<?xml version=\"1.0\"?>\r\n\
+             <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+             s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+             <s:Body><u:{0} xmlns:u=\"{1}\">{2}</u:{0}></s:Body></s:Envelope>",
+            action, self.service, args
+        );
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: text/xml; charset=\"utf-8\"\r\n\
+             SOAPAction: \"{}#{}\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path,
+            self.host,
+            self.service,
+            action,
+            body.len(),
+            body
+        );
+        let (status, response) = http(self.addr, &request).await?;
+        if status != 200 {
+            let code = tag(&response, "errorCode").unwrap_or("-");
+            let reason = tag(&response, "errorDescription").unwrap_or("no description");
+            return Err(anyhow!(
+                "{} failed with status {} (UPnP error {}, {})",
+                action,
+                status,
+                code,
+                reason
+            ));
+        }
+        Ok(response)
+    }
+}
+
+// The LOCATION header of an SSDP answer
+pub fn location(response: &str) -> Option<&str> {
+    response.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim()
+            .eq_ignore_ascii_case("location")
+            .then(|| value.trim())
+    })
+}
+
+// The first service of the device description able to map ports, and its control URL
+pub fn control_url(description: &str) -> Option<(&str, &str)> {
+    let services: Vec<(&str, &str)> = description
+        .split("<service>")
+        .skip(1)
+        .filter_map(|service| {
+            let service = service.split("</service>").next()?;
+            Some((tag(service, "serviceType")?, tag(service, "controlURL")?))
+        })
+        .collect();
+    UPNP_SERVICES
+        .iter()
+        .find_map(|wanted| services.iter().find(|(service, _)| service == wanted))
+        .copied()
+}
+
+// The text of the first element of the name, namespace prefixes aside
+fn tag<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let open = xml.find(&format!("{}>", name))?;
+    let start = open + name.len() + 1;
+    let text = &xml[start..];
+    // The element is either prefixed or not
+    if !xml[..open].ends_with('<') && !xml[..open].ends_with(':') {
+        return tag(text, name);
+    }
+    let end = text.find("</")?;
+    Some(text[..end].trim())
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+// The host and path of an http:// URL
+fn split_url(url: &str) -> Result<(&str, &str)> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or(anyhow!("'{}' isn't an http:// URL", url))?;
+    Ok(match rest.find('/') {
+        Some(idx) => (&rest[..idx], &rest[idx..]),
+        None => (rest, "/"),
+    })
+}
+
+async fn resolve(host: &str) -> Result<SocketAddr> {
+    let host = match host.contains(':') {
+        true => host.to_owned(),
+        false => format!("{}:80", host),
+    };
+    let addr = lookup_host(&host).await?.next();
+    addr.ok_or(anyhow!("'{}' didn't resolve", host))
+}
+
+// The status and body of the answer to a request closing the connection
+async fn http(addr: SocketAddr, request: &str) -> Result<(u16, String)> {
+    let response = timeout(HTTP_TIMEOUT, async {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        stream
+            .take(MAX_HTTP_SIZE as u64 + 1)
+            .read_to_end(&mut response)
+            .await?;
+        Ok::<_, anyhow::Error>(response)
+    })
+    .await
+    .map_err(|_| anyhow!("{} didn't answer within {:?}", addr, HTTP_TIMEOUT))??;
+    if response.len() > MAX_HTTP_SIZE {
+        return Err(anyhow!("The answer of {} is too large", addr));
+    }
+    let response = String::from_utf8_lossy(&response);
+
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or(anyhow!("Invalid HTTP answer from {}", addr))?;
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or(anyhow!("Invalid HTTP status from {}", addr))?;
+    let chunked = head.lines().any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("transfer-encoding")
+                && value.trim().eq_ignore_ascii_case("chunked")
+        })
+    });
+    let body = match chunked {
+        true => dechunk(body)?,
+        false => body.to_owned(),
+    };
+    Ok((status, body))
+}
+
+fn dechunk(mut body: &str) -> Result<String> {
+    let mut text = String::new();
+    loop {
+        let (size, rest) = body.split_once("\r\n").ok_or(anyhow!("Truncated chunk"))?;
+        let size = usize::from_str_radix(size.split(';').next().unwrap_or("").trim(), 16)
+            .context("Invalid chunk size")?;
+        if size == 0 {
+            return Ok(text);
+        }
+        text.push_str(rest.get(..size).ok_or(anyhow!("Truncated chunk"))?);
+        body = rest.get(size..).unwrap_or("").trim_start_matches("\r\n");
+    }
+}
+
+// The local address the peer is reached from
+async fn local_ip(peer: SocketAddr) -> Result<IpAddr> {
+    let probe = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    probe.connect(peer).await?;
+    Ok(probe.local_addr()?.ip())
+}
+
+// The gateway of the default IPv4 route
+#[cfg(target_os = "linux")]
+fn default_gateway() -> Result<IpAddr> {
+    let routes = std::fs::read_to_string("/proc/net/route")?;
+    routes
+        .lines()
+        .skip(1)
+        .find_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields[..] {
+                [_, "00000000", gateway, ..] => u32::from_str_radix(gateway, 16).ok(),
+                _ => None,
+            }
+        })
+        // In network order, printed as a little-endian number
+        .map(|gateway| IpAddr::V4(Ipv4Addr::from(gateway.to_le_bytes())))
+        .ok_or(anyhow!("No default route, set mapping_gateway"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn default_gateway() -> Result<IpAddr> {
+    Err(anyhow!(
+        "The default gateway isn't known, set mapping_gateway"
+    ))
+}
+
+// Keeps an endpoint's port mapped on the router, renewing the mapping at half of its lease and
+// asking again after the router loses it
+#[derive(Debug, Clone)]
+pub struct PortMapper {
+    name: String,
+    method: PortMapping,
+    gateway: Option<IpAddr>,
+    protocol: IpProtocol,
+    internal: SocketAddr,
+    external_port: u16,
+    lease: Duration,
+}
+
+impl PortMapper {
+    // The mapper of an endpoint with a port_mapping. NAT-PMP's gateway is looked up now, before
+    // a sandbox could hide the routes.
+    pub fn new(name: &str, endpoint: &Endpoint) -> Result<Option<Self>> {
+        let Some(method) = endpoint.port_mapping else {
+            return Ok(None);
+        };
+        let gateway = match (endpoint.mapping_gateway, method) {
+            (None, PortMapping::NatPmp) => Some(default_gateway()?),
+            (None, PortMapping::Auto) => default_gateway().ok(),
+            (gateway, _) => gateway,
+        };
+        let udp = matches!(endpoint.kind, ConnectionType::Udp)
+            || matches!(endpoint.transport, Some(TransportKind::Quic));
+        let ip = endpoint
+            .host
+            .as_deref()
+            .and_then(|host| host.parse().ok())
+            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        Ok(Some(Self {
+            name: name.to_owned(),
+            method,
+            gateway,
+            protocol: match udp {
+                true => IpProtocol::Udp,
+                false => IpProtocol::Tcp,
+            },
+            internal: SocketAddr::new(ip, endpoint.port),
+            external_port: endpoint.external_port.unwrap_or(endpoint.port),
+            lease: endpoint
+                .mapping_lease
+                .map_or(DEFAULT_LEASE, Duration::from_secs),
+        }))
+    }
+
+    // Until told to stop, removing the mapping then
+    pub async fn run(self, mut stop: watch::Receiver<bool>) {
+        let description = format!("veloxid {}", self.name);
+        let mut router: Option<Gateway> = None;
+        let mut mapped = None;
+        loop {
+            let wait = match self.renew(&mut router, &description).await {
+                Ok(mapping) => {
+                    if mapped != Some(mapping.external) {
+                        info!(
+                            target: LOG_TARGET,
+                            "'{}' is reachable at {} ({})",
+                            self.name,
+                            mapping.external,
+                            self.protocol.as_str()
+                        );
+                        mapped = Some(mapping.external);
+                    }
+                    let lease = match mapping.lease.is_zero() {
+                        true => self.lease,
+                        false => mapping.lease,
+                    };
+                    (lease / 2).max(MIN_RENEWAL)
+                }
+                Err(e) => {
+                    warn!(target: LOG_TARGET, "Couldn't map the port of '{}': {:#}", self.name, e);
+                    (router, mapped) = (None, None);
+                    RETRY_INTERVAL
+                }
+            };
+            tokio::select! {
+                _ = sleep(wait) => {}
+                _ = stop.changed() => break,
+            }
+        }
+
+        if let (Some(router), Some(_)) = (router, mapped) {
+            let unmap = router.unmap(self.protocol, self.internal, self.external_port);
+            match timeout(UNMAP_TIMEOUT, unmap).await {
+                Ok(Ok(())) => info!(target: LOG_TARGET, "Removed the mapping of '{}'", self.name),
+                Ok(Err(e)) => {
+                    warn!(target: LOG_TARGET, "Couldn't remove the mapping of '{}': {}", self.name, e)
+                }
+                Err(_) => {
+                    warn!(target: LOG_TARGET, "Couldn't remove the mapping of '{}': timed out", self.name)
+                }
+            }
+        }
+    }
+
+    async fn renew(&self, router: &mut Option<Gateway>, description: &str) -> Result<Mapping> {
+        let gateway = match router {
+            Some(gateway) => gateway,
+            None => router.insert(Gateway::find(self.method, self.gateway).await?),
+        };
+        gateway
+            .map(
+                self.protocol,
+                self.internal,
+                self.external_port,
+                self.lease,
+                description,
+            )
+            .await
+    }
+}
+
+// The mappers running, stopped on exit
+pub struct PortMappings {
+    stop: watch::Sender<bool>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl PortMappings {
+    pub fn start(mappers: Vec<PortMapper>) -> Self {
+        let stop = watch::Sender::new(false);
+        let tasks = mappers
+            .into_iter()
+            .map(|mapper| tokio::spawn(mapper.run(stop.subscribe())))
+            .collect();
+        Self { stop, tasks }
+    }
+
+    // Removes the mappings, each one getting a few seconds
+    pub async fn stop(self) {
+        self.stop.send_replace(true);
+        for task in self.tasks {
+            let _ = task.await;
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use crate::error::StartupError;
+use anyhow::Result;
+
+// Switch to the configured user and group once every listener is bound, so privileged ports
+// don't need the relay to keep running as root. The user brings its primary and supplementary
+// groups, a group given replaces them. A group alone keeps the user as it is.
+#[cfg(target_os = "linux")]
+pub fn drop_to(user: Option<&str>, group: Option<&str>) -> Result<()> {
+    use std::io;
+
+    let failed =
+        |what: &str| StartupError::Privileges(format!("{}: {}", what, io::Error::last_os_error()));
+    let user = user.map(lookup_user).transpose()?;
+    let gid = match group {
+        Some(group) => Some(lookup_group(group)?),
+        None => user.as_ref().map(|(_, _, gid)| *gid),
+    };
+
+    // Groups first, setuid takes the right to change them away
+    if let Some(gid) = gid {
+        // SAFETY: plain syscalls on ids, the name outlives the call
+        let result = match (&user, group) {
+            (Some((name, _, _)), None) => unsafe { libc::initgroups(name.as_ptr(), gid) },
+            _ => unsafe { libc::setgroups(1, &gid) },
+        };
+        if result != 0 {
+            return Err(failed("setgroups").into());
+        }
+        // SAFETY: as above
+        if unsafe { libc::setgid(gid) } != 0 {
+            return Err(failed("setgid").into());
+        }
+    }
+    if let Some((_, uid, _)) = user {
+        // SAFETY: as above
+        if unsafe { libc::setuid(uid) } != 0 {
+            return Err(failed("setuid").into());
+        }
+        // A process that can become root again hasn't dropped anything
+        if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            return Err(StartupError::Privileges("root could be regained".to_owned()).into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn drop_to(_user: Option<&str>, _group: Option<&str>) -> Result<()> {
+    Err(StartupError::Privileges("only supported on Linux".to_owned()).into())
+}
+
+// Name, id and primary group of a user from the passwd database
+#[cfg(target_os = "linux")]
+fn lookup_user(name: &str) -> Result<(std::ffi::CString, libc::uid_t, libc::gid_t)> {
+    let c_name = std::ffi::CString::new(name)?;
+    // SAFETY: passwd is plain data, filled in by getpwnam_r
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; LOOKUP_BUFFER];
+    let mut found = std::ptr::null_mut();
+    // SAFETY: every pointer is valid for the call, and the buffer as long as it says
+    unsafe {
+        libc::getpwnam_r(
+            c_name.as_ptr(),
+            &mut passwd,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut found,
+        )
+    };
+    match found.is_null() {
+        true => Err(StartupError::Privileges(format!("no user '{}'", name)).into()),
+        false => Ok((c_name, passwd.pw_uid, passwd.pw_gid)),
+    }
+}
+
+// Id of a group from the group database
+#[cfg(target_os = "linux")]
+fn lookup_group(name: &str) -> Result<libc::gid_t> {
+    let c_name = std::ffi::CString::new(name)?;
+    // SAFETY: group is plain data, filled in by getgrnam_r
+    let mut group: libc::group = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; LOOKUP_BUFFER];
+    let mut found = std::ptr::null_mut();
+    // SAFETY: as for getpwnam_r
+    unsafe {
+        libc::getgrnam_r(
+            c_name.as_ptr(),
+            &mut group,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut found,
+        )
+    };
+    match found.is_null() {
+        true => Err(StartupError::Privileges(format!("no group '{}'", name)).into()),
+        false => Ok(group.gr_gid),
+    }
+}
+
+// Room for the strings of a passwd or group entry, big groups list many members
+#[cfg(target_os = "linux")]
+const LOOKUP_BUFFER: usize = 64 * 1024;
@@ -0,0 +1,123 @@
+use crate::{relay::connection::ConnectionData, transport::listener};
+use anyhow::Result;
+use futures::future::join_all;
+use log::{debug, info};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task,
+    time::{timeout, Duration},
+};
+
+const LOG_TARGET: &str = "probes";
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+// Only the request line matters
+const MAX_REQUEST: usize = 1024;
+
+// Serve the probes over plain HTTP:
+// GET /healthz -> 200 while the process runs
+// GET /readyz  -> 200 if every inbound listener is bound and every outbound TCP endpoint
+//                 accepts connections, 503 with the reasons otherwise
+pub async fn serve(
+    listener: TcpListener,
+    endpoints: Arc<HashMap<String, ConnectionData>>,
+) -> Result<()> {
+    info!(target: LOG_TARGET, "Listening on {}", listener.local_addr()?);
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                listener::recover(e).await?;
+                continue;
+            }
+        };
+        let endpoints = endpoints.clone();
+        task::spawn(async move {
+            if let Err(e) = handle_client(stream, &endpoints).await {
+                debug!(target: LOG_TARGET, "{}: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle_client(
+    mut stream: TcpStream,
+    endpoints: &HashMap<String, ConnectionData>,
+) -> Result<()> {
+    // Up to the end of the request line
+    let mut request = Vec::new();
+    let mut buf = [0u8; 256];
+    timeout(REQUEST_TIMEOUT, async {
+        while !request.contains(&b'\n') && request.len() < MAX_REQUEST {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+        Ok::<_, std::io::Error>(())
+    })
+    .await??;
+
+    let line = String::from_utf8_lossy(&request);
+    let mut parts = line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/healthz")) => ("200 OK", "ok\n".to_owned()),
+        (Some("GET"), Some("/readyz")) => {
+            let problems = check(endpoints).await;
+            match problems.is_empty() {
+                true => ("200 OK", "ready\n".to_owned()),
+                false => ("503 Service Unavailable", problems.join("\n") + "\n"),
+            }
+        }
+        _ => ("404 Not Found", "not found\n".to_owned()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+// An address of the endpoint accepts connections
+async fn reachable(addrs: &[SocketAddr]) -> bool {
+    let attempts = addrs
+        .iter()
+        .map(|addr| timeout(CONNECT_TIMEOUT, TcpStream::connect(*addr)));
+    join_all(attempts)
+        .await
+        .into_iter()
+        .any(|result| matches!(result, Ok(Ok(_))))
+}
+
+// Reasons the instance isn't ready, sorted by endpoint. Outbound tunnels are only connected
+// to, their relays see it as a connection dropped before the handshake. QUIC endpoints and
+// bonded inbound tunnels aren't checked.
+pub async fn check(endpoints: &HashMap<String, ConnectionData>) -> Vec<String> {
+    let checks = endpoints.iter().map(|(name, data)| async move {
+        match data {
+            ConnectionData::Inbound { listener, .. } if !listener.is_bound() => {
+                Some(format!("'{}' isn't bound ({})", name, listener.addr()))
+            }
+            ConnectionData::Outbound { targets, .. } if !reachable(targets.addrs()).await => {
+                Some(format!("'{}' is unreachable", name))
+            }
+            ConnectionData::BondOutbound { paths, .. } if !reachable(paths).await => {
+                Some(format!("'{}' is unreachable", name))
+            }
+            _ => None,
+        }
+    });
+    let mut problems: Vec<String> = join_all(checks).await.into_iter().flatten().collect();
+    problems.sort();
+    problems
+}
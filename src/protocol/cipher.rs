@@ -0,0 +1,867 @@
+use crate::protocol::encryption::Secret;
+use crate::protocol::handshake::{
+    ATTACH_NONCE_LEN, CIPHER_AES_256_GCM, CIPHER_CHACHA20, CIPHER_CHACHA20_POLY1305,
+    CIPHER_XCHACHA20, SALT_LEN,
+};
+use aes_gcm::{
+    aead::{AeadInPlace, Error as AeadError, Tag},
+    Aes256Gcm, KeyInit,
+};
+use chacha20::{
+    cipher::{KeyIvInit, StreamCipher},
+    ChaCha20, XChaCha20,
+};
+use chacha20poly1305::ChaCha20Poly1305;
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use std::{
+    cmp, io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+use subtle::ConstantTimeEq;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use zeroize::Zeroize;
+
+// AEAD sessions are sent as sealed records: [u16 length][ciphertext][tag], the length being
+// authenticated along with the ciphertext
+const MAX_RECORD: usize = 16 * 1024;
+const TAG_LEN: usize = 16;
+// Set on the length of the record after which the sender's key is replaced, sealed with the
+// key being replaced and empty
+const KEY_UPDATE: u16 = 0x8000;
+
+// Stream cipher sessions replacing their keys are sent as frames: [u16 header][payload], all
+// of it under the keystream. The header is the length of the payload, or one of the flags of
+// a control frame: the sender's next frames are under its next key, or the peer's key update
+// arrived. No other update is sent before that. Control frames carry a tag of the key they
+// are about instead of a payload, so they can't be made up without it.
+const MAX_FRAME: usize = 0x3fff;
+const FRAME_KEY_UPDATE: u16 = 0x8000;
+const FRAME_KEY_UPDATE_ACK: u16 = 0x4000;
+const CONTROL_TAG_LEN: usize = 32;
+
+// Session cipher of a tunnel, both sides must agree on it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+pub enum CipherKind {
+    #[default]
+    #[serde(rename = "chacha20")]
+    ChaCha20,
+    // 24 byte nonces
+    #[serde(rename = "xchacha20")]
+    XChaCha20,
+    // Hardware accelerated on most servers
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm,
+    // Sealed records like AES-256-GCM, faster without AES instructions
+    #[serde(rename = "chacha20-poly1305")]
+    ChaCha20Poly1305,
+}
+
+impl CipherKind {
+    // Id sent in the handshake
+    pub fn id(self) -> u8 {
+        match self {
+            CipherKind::ChaCha20 => CIPHER_CHACHA20,
+            CipherKind::XChaCha20 => CIPHER_XCHACHA20,
+            CipherKind::Aes256Gcm => CIPHER_AES_256_GCM,
+            CipherKind::ChaCha20Poly1305 => CIPHER_CHACHA20_POLY1305,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            CIPHER_CHACHA20 => Some(CipherKind::ChaCha20),
+            CIPHER_XCHACHA20 => Some(CipherKind::XChaCha20),
+            CIPHER_AES_256_GCM => Some(CipherKind::Aes256Gcm),
+            CIPHER_CHACHA20_POLY1305 => Some(CipherKind::ChaCha20Poly1305),
+            _ => None,
+        }
+    }
+}
+
+// When the keys of a session are replaced by the next ones, in each direction on its own: a
+// key update is sent after the bytes or the time, whichever comes first, and the peer follows
+// whatever it is set to. AEAD ciphers send it as a record. Stream ciphers have none, so their
+// sessions are framed once either side sets limits, and both sides must set some then.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RekeyLimits {
+    pub bytes: Option<u64>,
+    pub interval: Option<Duration>,
+}
+
+// Each key of a direction is derived from the one before
+fn next_key(key: &[u8; 32]) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(key)
+        .chain_update(b"veloxid rekey")
+        .finalize()
+        .into()
+}
+
+// Stream ciphers applied by the copy loops
+pub enum Keystream {
+    ChaCha20(ChaCha20),
+    XChaCha20(XChaCha20),
+}
+
+impl Keystream {
+    // XChaCha20 takes the whole nonce, ChaCha20 its first 12 bytes
+    fn new(key: &[u8; 32], nonce: &[u8; 24], extended: bool) -> Self {
+        match extended {
+            true => Keystream::XChaCha20(XChaCha20::new(key.into(), nonce.into())),
+            false => {
+                let nonce: [u8; 12] = nonce[..12].try_into().unwrap();
+                Keystream::ChaCha20(ChaCha20::new(key.into(), &nonce.into()))
+            }
+        }
+    }
+
+    pub fn apply_keystream(&mut self, data: &mut [u8]) {
+        match self {
+            Keystream::ChaCha20(cipher) => cipher.apply_keystream(data),
+            Keystream::XChaCha20(cipher) => cipher.apply_keystream(data),
+        }
+    }
+}
+
+impl From<ChaCha20> for Keystream {
+    fn from(cipher: ChaCha20) -> Self {
+        Keystream::ChaCha20(cipher)
+    }
+}
+
+// Keys of one tunnel session, ChaCha20 keeps the keystream of versions 1 and 2, the
+// others get a key per direction from the secret, the nonce and the outbound side's salt.
+// Rekeyed tunnels add the nonce of the attachment, ChaCha20 included.
+pub struct SessionKeys {
+    pub cipher: CipherKind,
+    pub secret: [u8; 32],
+    pub nonce: [u8; 12],
+    pub salt: [u8; SALT_LEN],
+    pub is_inbound: bool,
+    pub attach: Option<[u8; ATTACH_NONCE_LEN]>,
+    pub limits: RekeyLimits,
+}
+
+impl SessionKeys {
+    fn key(&self, inbound_writes: bool) -> Secret {
+        let key = Sha256::new()
+            .chain_update(self.secret)
+            .chain_update(self.nonce)
+            .chain_update(self.salt)
+            .chain_update(self.attach.unwrap_or_default())
+            .chain_update(match inbound_writes {
+                true => b"veloxid session inbound".as_slice(),
+                false => b"veloxid session outbound".as_slice(),
+            })
+            .finalize();
+        Secret::new(key.into())
+    }
+
+    // Key and nonce of the keystream of a direction, and whether it is XChaCha20's
+    fn keystream_parts(&self, inbound_writes: bool) -> Option<(Secret, [u8; 24], bool)> {
+        Some(match self.cipher {
+            CipherKind::ChaCha20 if self.attach.is_some() => {
+                let mut nonce = [0u8; 24];
+                nonce[..12].copy_from_slice(&self.attach.unwrap());
+                (self.key(inbound_writes), nonce, false)
+            }
+            CipherKind::ChaCha20 => {
+                let mut nonce = [0u8; 24];
+                nonce[..12].copy_from_slice(&self.nonce);
+                (Secret::new(self.secret), nonce, false)
+            }
+            CipherKind::XChaCha20 => {
+                let mut nonce = [0u8; 24];
+                nonce[..12].copy_from_slice(&self.nonce);
+                nonce[12..].copy_from_slice(&self.salt);
+                (self.key(inbound_writes), nonce, true)
+            }
+            // Sealed by the stream instead
+            CipherKind::Aes256Gcm | CipherKind::ChaCha20Poly1305 => return None,
+        })
+    }
+
+    // Whether the session is framed by the stream instead of applied by the copy loops
+    fn framed(&self) -> bool {
+        self.limits != RekeyLimits::default()
+    }
+
+    fn keystream(&self, inbound_writes: bool) -> Option<Keystream> {
+        if self.framed() {
+            return None;
+        }
+        let (key, nonce, extended) = self.keystream_parts(inbound_writes)?;
+        Some(Keystream::new(&key, &nonce, extended))
+    }
+
+    // Keystream of the data coming from the peer
+    pub fn read_keystream(&self) -> Option<Keystream> {
+        self.keystream(!self.is_inbound)
+    }
+
+    // Keystream of the data sent to the peer
+    pub fn write_keystream(&self) -> Option<Keystream> {
+        self.keystream(self.is_inbound)
+    }
+
+    // Records of an AEAD cipher, the stream is given back as is for stream ciphers
+    pub fn seal<S>(&self, stream: S) -> Result<SealedStream<S>, S> {
+        let (read_key, write_key) = (self.key(!self.is_inbound), self.key(self.is_inbound));
+        let (Some(read_cipher), Some(write_cipher)) = (
+            RecordCipher::new(self.cipher, &read_key),
+            RecordCipher::new(self.cipher, &write_key),
+        ) else {
+            return Err(stream);
+        };
+        Ok(SealedStream {
+            stream,
+            cipher: self.cipher,
+            read_key,
+            write_key,
+            read_cipher,
+            write_cipher,
+            read_counter: 0,
+            write_counter: 0,
+            limits: self.limits,
+            sealed: 0,
+            keyed_at: Instant::now(),
+            record: Vec::new(),
+            record_read: 0,
+            plaintext: Vec::new(),
+            plaintext_read: 0,
+            pending: Vec::new(),
+            written: 0,
+            accepted: 0,
+        })
+    }
+
+    // Frames of a stream cipher replacing its keys, the stream is given back as is for AEAD
+    // ciphers and sessions without rekey limits
+    pub fn frame<S>(&self, stream: S) -> Result<FramedStream<S>, S> {
+        let (Some(read), Some(write), true) = (
+            self.keystream_parts(!self.is_inbound),
+            self.keystream_parts(self.is_inbound),
+            self.framed(),
+        ) else {
+            return Err(stream);
+        };
+        Ok(FramedStream {
+            stream,
+            read: Rekeying::new(read),
+            write: Rekeying::new(write),
+            limits: self.limits,
+            sent: 0,
+            keyed_at: Instant::now(),
+            unacknowledged: false,
+            ack_due: false,
+            peer_done: false,
+            shut: false,
+            header: [0u8; 2],
+            header_read: 0,
+            control: None,
+            tag: [0u8; CONTROL_TAG_LEN],
+            tag_read: 0,
+            payload_left: 0,
+            pending: Vec::new(),
+            written: 0,
+            accepted: 0,
+        })
+    }
+}
+
+impl Drop for SessionKeys {
+    fn drop(&mut self) {
+        self.secret.zeroize();
+        self.nonce.zeroize();
+    }
+}
+
+// AEAD of the records of a session, both use 12 byte nonces and 16 byte tags
+enum RecordCipher {
+    // Expanded key schedule, much larger than ChaCha20's key
+    Aes256Gcm(Box<Aes256Gcm>),
+    ChaCha20Poly1305(ChaCha20Poly1305),
+}
+
+impl RecordCipher {
+    fn new(cipher: CipherKind, key: &[u8; 32]) -> Option<Self> {
+        match cipher {
+            CipherKind::Aes256Gcm => Some(RecordCipher::Aes256Gcm(Box::new(Aes256Gcm::new(
+                key.into(),
+            )))),
+            CipherKind::ChaCha20Poly1305 => Some(RecordCipher::ChaCha20Poly1305(
+                ChaCha20Poly1305::new(key.into()),
+            )),
+            CipherKind::ChaCha20 | CipherKind::XChaCha20 => None,
+        }
+    }
+
+    // The length of the record is the associated data, its key update flag included
+    fn decrypt_in_place(
+        &self,
+        nonce: [u8; 12],
+        length: [u8; 2],
+        data: &mut Vec<u8>,
+    ) -> Result<(), AeadError> {
+        match self {
+            RecordCipher::Aes256Gcm(cipher) => {
+                cipher.decrypt_in_place(&nonce.into(), &length, data)
+            }
+            RecordCipher::ChaCha20Poly1305(cipher) => {
+                cipher.decrypt_in_place(&nonce.into(), &length, data)
+            }
+        }
+    }
+
+    fn encrypt_in_place_detached(
+        &self,
+        nonce: [u8; 12],
+        length: [u8; 2],
+        data: &mut [u8],
+    ) -> Result<Tag<Aes256Gcm>, AeadError> {
+        match self {
+            RecordCipher::Aes256Gcm(cipher) => {
+                cipher.encrypt_in_place_detached(&nonce.into(), &length, data)
+            }
+            RecordCipher::ChaCha20Poly1305(cipher) => {
+                cipher.encrypt_in_place_detached(&nonce.into(), &length, data)
+            }
+        }
+    }
+}
+
+// Each side of an end-to-end session sends its nonce first, the rest is XChaCha20 with a key
+// the relays in between don't have
+pub const END_TO_END_NONCE_LEN: usize = 24;
+
+pub fn end_to_end_keystream(secret: &[u8; 32], nonce: &[u8; END_TO_END_NONCE_LEN]) -> Keystream {
+    let key: [u8; 32] = Sha256::new()
+        .chain_update(secret)
+        .chain_update(b"veloxid end-to-end")
+        .finalize()
+        .into();
+    Keystream::XChaCha20(XChaCha20::new(&key.into(), nonce.into()))
+}
+
+// Longest keying material exported at once, HKDF-SHA256's limit
+pub const MAX_EXPORT_LEN: usize = 255 * 32;
+
+// Keying material for applications to bind their own authentication to a session, like TLS
+// exporters. HKDF-SHA256 of the secret, salted with the nonce and the outbound side's salt so
+// each session gets its own, and expanded with the label. Both sides get the same bytes.
+pub fn export_keying_material(
+    secret: &[u8; 32],
+    nonce: &[u8; 12],
+    salt: &[u8; SALT_LEN],
+    label: &[u8],
+    len: usize,
+) -> Option<Vec<u8>> {
+    let mut session = [0u8; 12 + SALT_LEN];
+    session[..12].copy_from_slice(nonce);
+    session[12..].copy_from_slice(salt);
+    let hkdf = Hkdf::<Sha256>::new(Some(&session), secret);
+    let mut material = vec![0u8; len];
+    hkdf.expand_multi_info(&[b"veloxid exporter ", label], &mut material)
+        .ok()?;
+    Some(material)
+}
+
+// Nonce of the nth record in one direction, keys are never shared between directions
+fn record_nonce(counter: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+fn invalid(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+pub struct SealedStream<S> {
+    stream: S,
+    cipher: CipherKind,
+    // Keys of the ciphers, for the next ones
+    read_key: Secret,
+    write_key: Secret,
+    read_cipher: RecordCipher,
+    write_cipher: RecordCipher,
+    // Records sealed with the current key of each direction
+    read_counter: u64,
+    write_counter: u64,
+    limits: RekeyLimits,
+    // Plaintext bytes sealed with the current write key, and since when it is used
+    sealed: u64,
+    keyed_at: Instant,
+    // Reading side: record being received, then its plaintext being handed out
+    record: Vec<u8>,
+    record_read: usize,
+    plaintext: Vec<u8>,
+    plaintext_read: usize,
+    // Writing side: record being written, and the bytes of the caller's it holds, reported
+    // once it is written out
+    pending: Vec<u8>,
+    written: usize,
+    accepted: usize,
+}
+
+impl<S> SealedStream<S> {
+    // Records not flushed yet are lost
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    // Whether the write key is to be replaced before sealing more
+    fn rekey_due(&self) -> bool {
+        self.sealed > 0
+            && (self.limits.bytes.is_some_and(|bytes| self.sealed >= bytes)
+                || self
+                    .limits
+                    .interval
+                    .is_some_and(|interval| self.keyed_at.elapsed() >= interval))
+    }
+
+    // The key update record, sealed with the current key before it is replaced
+    fn key_update(&mut self) -> io::Result<Vec<u8>> {
+        let length = (KEY_UPDATE | TAG_LEN as u16).to_be_bytes();
+        let mut record = Vec::with_capacity(2 + TAG_LEN);
+        record.extend_from_slice(&length);
+        let tag = self
+            .write_cipher
+            .encrypt_in_place_detached(record_nonce(self.write_counter), length, &mut [])
+            .map_err(|_| invalid("Record couldn't be sealed"))?;
+        record.extend_from_slice(&tag);
+
+        *self.write_key = next_key(&self.write_key);
+        self.write_cipher = RecordCipher::new(self.cipher, &self.write_key).unwrap();
+        self.write_counter = 0;
+        self.sealed = 0;
+        self.keyed_at = Instant::now();
+        Ok(record)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> SealedStream<S> {
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.pending.len() {
+            let n =
+                ready!(Pin::new(&mut self.stream).poll_write(cx, &self.pending[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> SealedStream<S> {
+    // Reads into the record buffer until it holds len bytes, false on EOF before any
+    fn poll_fill(&mut self, cx: &mut Context<'_>, len: usize) -> Poll<io::Result<bool>> {
+        if self.record.len() < len {
+            self.record.resize(len, 0);
+        }
+        while self.record_read < len {
+            let mut buf = ReadBuf::new(&mut self.record[self.record_read..len]);
+            ready!(Pin::new(&mut self.stream).poll_read(cx, &mut buf))?;
+            let n = buf.filled().len();
+            if n == 0 && self.record_read == 0 {
+                return Poll::Ready(Ok(false));
+            }
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            self.record_read += n;
+        }
+        Poll::Ready(Ok(true))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for SealedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        while this.plaintext_read == this.plaintext.len() {
+            // Length, then the sealed record
+            if !ready!(this.poll_fill(cx, 2))? {
+                return Poll::Ready(Ok(())); // EOF
+            }
+            let len = u16::from_be_bytes([this.record[0], this.record[1]]);
+            let update = len & KEY_UPDATE != 0;
+            let len = (len & !KEY_UPDATE) as usize;
+            if !(TAG_LEN..=MAX_RECORD + TAG_LEN).contains(&len) || update && len != TAG_LEN {
+                return Poll::Ready(Err(invalid("Invalid record length")));
+            }
+            if !ready!(this.poll_fill(cx, 2 + len))? {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            this.record_read = 0;
+
+            let mut plaintext = std::mem::take(&mut this.plaintext);
+            plaintext.clear();
+            plaintext.extend_from_slice(&this.record[2..2 + len]);
+            let nonce = record_nonce(this.read_counter);
+            this.read_counter += 1;
+            this.read_cipher
+                .decrypt_in_place(nonce, [this.record[0], this.record[1]], &mut plaintext)
+                .map_err(|_| invalid("Record failed authentication"))?;
+            this.plaintext = plaintext;
+            this.plaintext_read = 0;
+
+            // The peer's next records are sealed with its next key
+            if update {
+                *this.read_key = next_key(&this.read_key);
+                this.read_cipher = RecordCipher::new(this.cipher, &this.read_key).unwrap();
+                this.read_counter = 0;
+            }
+        }
+
+        let n = cmp::min(buf.remaining(), this.plaintext.len() - this.plaintext_read);
+        buf.put_slice(&this.plaintext[this.plaintext_read..this.plaintext_read + n]);
+        this.plaintext_read += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for SealedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // The caller writes the same bytes again after Pending, they are already sealed then
+        if this.accepted > 0 {
+            ready!(this.poll_pending(cx))?;
+            return Poll::Ready(Ok(std::mem::take(&mut this.accepted)));
+        }
+        ready!(this.poll_pending(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let n = cmp::min(buf.len(), MAX_RECORD);
+        let mut record = match this.rekey_due() {
+            true => this.key_update()?,
+            false => Vec::new(),
+        };
+        let start = record.len();
+        let length = ((n + TAG_LEN) as u16).to_be_bytes();
+        record.reserve(2 + n + TAG_LEN);
+        record.extend_from_slice(&length);
+        record.extend_from_slice(&buf[..n]);
+        let nonce = record_nonce(this.write_counter);
+        this.write_counter += 1;
+        let tag = this
+            .write_cipher
+            .encrypt_in_place_detached(nonce, length, &mut record[start + 2..])
+            .map_err(|_| invalid("Record couldn't be sealed"))?;
+        record.extend_from_slice(&tag);
+        this.sealed += n as u64;
+        this.pending = record;
+        this.written = 0;
+
+        // Nothing might flush the record after this write, it is only taken once written out
+        this.accepted = n;
+        ready!(this.poll_pending(cx))?;
+        Poll::Ready(Ok(std::mem::take(&mut this.accepted)))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+// Keystream of one direction of a framed session, started again with the next key on each
+// key update, the nonce staying the same
+struct Rekeying {
+    keystream: Keystream,
+    key: Secret,
+    nonce: [u8; 24],
+    extended: bool,
+}
+
+impl Rekeying {
+    fn new((key, nonce, extended): (Secret, [u8; 24], bool)) -> Self {
+        Rekeying {
+            keystream: Keystream::new(&key, &nonce, extended),
+            key,
+            nonce,
+            extended,
+        }
+    }
+
+    fn next(&mut self) {
+        *self.key = next_key(&self.key);
+        self.keystream = Keystream::new(&self.key, &self.nonce, self.extended);
+    }
+}
+
+// Tag of a control frame: a key update carries the key it replaces, its acknowledgement the
+// one replacing it
+fn control_tag(key: &[u8; 32], header: u16) -> [u8; CONTROL_TAG_LEN] {
+    Sha256::new()
+        .chain_update(key)
+        .chain_update(header.to_be_bytes())
+        .chain_update(b"veloxid frame control")
+        .finalize()
+        .into()
+}
+
+pub struct FramedStream<S> {
+    stream: S,
+    read: Rekeying,
+    write: Rekeying,
+    limits: RekeyLimits,
+    // Payload bytes sent with the current write key, and since when it is used
+    sent: u64,
+    keyed_at: Instant,
+    // A key update was sent and the peer hasn't acknowledged it yet
+    unacknowledged: bool,
+    // The peer sent a key update this side hasn't acknowledged yet
+    ack_due: bool,
+    // The peer won't send anything more, acknowledgements included
+    peer_done: bool,
+    // This side won't send anything more, acknowledgements included
+    shut: bool,
+    // Reading side: header of the next frame, then the tag of a control frame or what is left
+    // of the payload of a data frame
+    header: [u8; 2],
+    header_read: usize,
+    control: Option<u16>,
+    tag: [u8; CONTROL_TAG_LEN],
+    tag_read: usize,
+    payload_left: usize,
+    // Writing side: frames being written, and the bytes of the caller's they hold, reported
+    // once they are written out
+    pending: Vec<u8>,
+    written: usize,
+    accepted: usize,
+}
+
+impl<S> FramedStream<S> {
+    // Frames not flushed yet are lost
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    // Whether the write key is to be replaced before sending more, once the last update was
+    // acknowledged or can't be anymore
+    fn rekey_due(&self) -> bool {
+        self.sent > 0
+            && (!self.unacknowledged || self.peer_done)
+            && (self.limits.bytes.is_some_and(|bytes| self.sent >= bytes)
+                || self
+                    .limits
+                    .interval
+                    .is_some_and(|interval| self.keyed_at.elapsed() >= interval))
+    }
+
+    // Adds a frame to the ones being written, under the current write key
+    fn queue(&mut self, header: u16, payload: &[u8]) {
+        if self.written == self.pending.len() {
+            self.pending.clear();
+            self.written = 0;
+        }
+        let start = self.pending.len();
+        self.pending.extend_from_slice(&header.to_be_bytes());
+        self.pending.extend_from_slice(payload);
+        self.write
+            .keystream
+            .apply_keystream(&mut self.pending[start..]);
+    }
+
+    // The read key was replaced already, the peer checks the tag with its new write key
+    fn queue_ack(&mut self) {
+        if self.ack_due && !self.shut {
+            self.ack_due = false;
+            let tag = control_tag(&self.read.key, FRAME_KEY_UPDATE_ACK);
+            self.queue(FRAME_KEY_UPDATE_ACK, &tag);
+        }
+    }
+
+    // The key update frame, under the current key before it is replaced
+    fn queue_key_update(&mut self) {
+        let tag = control_tag(&self.write.key, FRAME_KEY_UPDATE);
+        self.queue(FRAME_KEY_UPDATE, &tag);
+        self.write.next();
+        self.unacknowledged = true;
+        self.sent = 0;
+        self.keyed_at = Instant::now();
+    }
+
+    // Acts on a control frame once its tag is read
+    fn control(&mut self, header: u16) -> io::Result<()> {
+        self.read.keystream.apply_keystream(&mut self.tag);
+        match header {
+            // The peer's next frames are under its next key
+            FRAME_KEY_UPDATE => {
+                let expected = control_tag(&self.read.key, header);
+                if !bool::from(expected.ct_eq(&self.tag)) {
+                    return Err(invalid("Key update failed authentication"));
+                }
+                self.read.next();
+                self.ack_due = true;
+            }
+            _ => {
+                let expected = control_tag(&self.write.key, header);
+                if !self.unacknowledged || !bool::from(expected.ct_eq(&self.tag)) {
+                    return Err(invalid("Key update acknowledgement failed authentication"));
+                }
+                self.unacknowledged = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: AsyncWrite + Unpin> FramedStream<S> {
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.pending.len() {
+            let n =
+                ready!(Pin::new(&mut self.stream).poll_write(cx, &self.pending[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for FramedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            // Acknowledgements go out as soon as the key update is read, not with the next
+            // write, what doesn't go out here does on the next read, write or flush
+            this.queue_ack();
+            if let Poll::Ready(Err(e)) = this.poll_pending(cx) {
+                return Poll::Ready(Err(e));
+            }
+
+            if this.payload_left > 0 {
+                let n = cmp::min(buf.remaining(), this.payload_left);
+                if n == 0 {
+                    return Poll::Ready(Ok(()));
+                }
+                let mut payload = ReadBuf::new(buf.initialize_unfilled_to(n));
+                ready!(Pin::new(&mut this.stream).poll_read(cx, &mut payload))?;
+                let read = payload.filled().len();
+                if read == 0 {
+                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                }
+                this.read.keystream.apply_keystream(payload.filled_mut());
+                buf.advance(read);
+                this.payload_left -= read;
+                return Poll::Ready(Ok(()));
+            }
+
+            if let Some(header) = this.control {
+                while this.tag_read < CONTROL_TAG_LEN {
+                    let mut tag = ReadBuf::new(&mut this.tag[this.tag_read..]);
+                    ready!(Pin::new(&mut this.stream).poll_read(cx, &mut tag))?;
+                    let read = tag.filled().len();
+                    if read == 0 {
+                        return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                    }
+                    this.tag_read += read;
+                }
+                this.tag_read = 0;
+                this.control = None;
+                this.control(header)?;
+                continue;
+            }
+
+            while this.header_read < 2 {
+                let mut header = ReadBuf::new(&mut this.header[this.header_read..]);
+                ready!(Pin::new(&mut this.stream).poll_read(cx, &mut header))?;
+                let read = header.filled().len();
+                if read == 0 && this.header_read == 0 {
+                    // The last acknowledgement goes out before the session ends
+                    this.peer_done = true;
+                    ready!(this.poll_pending(cx))?;
+                    return Poll::Ready(Ok(())); // EOF
+                }
+                if read == 0 {
+                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                }
+                this.header_read += read;
+            }
+            this.header_read = 0;
+            this.read.keystream.apply_keystream(&mut this.header);
+
+            match u16::from_be_bytes(this.header) {
+                header @ (FRAME_KEY_UPDATE | FRAME_KEY_UPDATE_ACK) => this.control = Some(header),
+                len if (1..=MAX_FRAME as u16).contains(&len) => this.payload_left = len as usize,
+                _ => return Poll::Ready(Err(invalid("Invalid frame header"))),
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for FramedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // The caller writes the same bytes again after Pending, they are already framed then
+        if this.accepted > 0 {
+            ready!(this.poll_pending(cx))?;
+            return Poll::Ready(Ok(std::mem::take(&mut this.accepted)));
+        }
+        ready!(this.poll_pending(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        this.queue_ack();
+        if this.rekey_due() {
+            this.queue_key_update();
+        }
+        let n = cmp::min(buf.len(), MAX_FRAME);
+        this.queue(n as u16, &buf[..n]);
+        this.sent += n as u64;
+
+        // Nothing might flush the frames after this write, they are only taken once written out
+        this.accepted = n;
+        ready!(this.poll_pending(cx))?;
+        Poll::Ready(Ok(std::mem::take(&mut this.accepted)))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.queue_ack();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.queue_ack();
+        ready!(this.poll_pending(cx))?;
+        this.shut = true;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
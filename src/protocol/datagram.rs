@@ -0,0 +1,76 @@
+use crate::error::TunnelError;
+use anyhow::Result;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// Datagrams over a session's stream, each after its length: [u16 length][datagram]. UDP
+// endpoints frame theirs the same way, so either end of a route may be one.
+pub const LENGTH_LEN: usize = 2;
+pub const MAX_DATAGRAM: usize = u16::MAX as usize;
+
+pub fn frame(datagram: &[u8]) -> Result<Vec<u8>> {
+    if datagram.len() > MAX_DATAGRAM {
+        return Err(TunnelError::DatagramTooLarge(datagram.len()).into());
+    }
+    let mut frame = Vec::with_capacity(LENGTH_LEN + datagram.len());
+    frame.extend_from_slice(&(datagram.len() as u16).to_be_bytes());
+    frame.extend_from_slice(datagram);
+    Ok(frame)
+}
+
+// Length of the frame at the start of the bytes, once all of it is there
+pub fn frame_len(bytes: &[u8]) -> Option<usize> {
+    let length = bytes.get(..LENGTH_LEN)?;
+    let len = LENGTH_LEN + u16::from_be_bytes([length[0], length[1]]) as usize;
+    (bytes.len() >= len).then_some(len)
+}
+
+// Datagrams sent and received over a stream, usually the plain side of a tunnel's session
+// (see Tunnel::datagrams)
+pub struct TunnelDatagram<S> {
+    stream: S,
+    // Bytes read that aren't a whole frame yet
+    received: Vec<u8>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> TunnelDatagram<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            received: Vec::new(),
+        }
+    }
+
+    pub async fn send_datagram(&mut self, datagram: &[u8]) -> Result<()> {
+        self.stream.write_all(&frame(datagram)?).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    // None once the peer is done sending. Cancel safe, no datagram is lost to a select.
+    pub async fn recv_datagram(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(len) = frame_len(&self.received) {
+                let datagram = self.received[LENGTH_LEN..len].to_vec();
+                self.received.drain(..len);
+                return Ok(Some(datagram));
+            }
+            self.received.reserve(LENGTH_LEN + MAX_DATAGRAM);
+            if self.stream.read_buf(&mut self.received).await? == 0 {
+                return match self.received.is_empty() {
+                    true => Ok(None),
+                    false => Err(TunnelError::DatagramCut.into()),
+                };
+            }
+        }
+    }
+
+    // No more datagrams are sent, those of the peer can still be received
+    pub async fn shutdown(&mut self) -> Result<()> {
+        self.stream.shutdown().await?;
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
@@ -0,0 +1,125 @@
+use crate::protocol::unix_time;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+use subtle::ConstantTimeEq;
+use zeroize::Zeroizing;
+
+// Secrets and the nonces they are used with, wiped from memory once dropped
+pub type Secret = Zeroizing<[u8; 32]>;
+pub type Nonce = Zeroizing<[u8; 12]>;
+
+pub fn generate_random_nonce() -> [u8; 12] {
+    let mut rng = rand::thread_rng();
+    let mut nonce = [0u8; 12];
+    rng.fill(&mut nonce);
+    nonce
+}
+
+pub fn generate_secret_from_string(secret_str: String) -> [u8; 32] {
+    let secret_str = Zeroizing::new(secret_str);
+    let mut hasher = Sha256::new();
+    hasher.update(secret_str.as_bytes());
+    hasher.finalize().into()
+}
+
+// Whether a decrypted token is the one expected, taking as long whichever byte differs
+pub fn token_matches(token: &[u8; 4], expected: &[u8; 4]) -> bool {
+    token.ct_eq(expected).into()
+}
+
+// A SHA-256 digest written as 64 hex digits
+pub fn sha256_from_hex(digits: &str) -> Option<[u8; 32]> {
+    let digits = digits.as_bytes();
+    if digits.len() != 64 {
+        return None;
+    }
+    let mut digest = [0u8; 32];
+    for (byte, pair) in digest.iter_mut().zip(digits.chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(digest)
+}
+
+// Seconds each code of a Totp holds for
+pub const TOTP_STEP: u64 = 30;
+// Steps the clocks of both sides may be apart, unless the endpoint says otherwise
+pub const DEFAULT_TOTP_SKEW: u64 = 1;
+
+// Handshake a code was taken in: the inbound side's nonce and the peer's hello sealed with it
+pub type TotpUse = [u8; 16];
+
+// Time-based codes mixed into the secret of each handshake, from a seed both sides share, so
+// the secret alone doesn't get a peer in. The code is HMAC-SHA256(seed, step) untruncated, a
+// 6 digit one would fall to an offline search by anyone holding the secret.
+#[derive(Clone)]
+pub struct Totp {
+    seed: Arc<Secret>,
+    // Steps the peer's clock may be off by
+    skew: u64,
+    // Handshakes each step within the skew was taken in, none of them is taken again. Every
+    // peer gets every step, whatever the others' clocks are at.
+    used: Arc<Mutex<HashMap<u64, HashSet<TotpUse>>>>,
+}
+
+impl Totp {
+    pub fn new(seed: [u8; 32], skew: u64) -> Self {
+        Self {
+            seed: Arc::new(Secret::new(seed)),
+            skew,
+            used: Arc::default(),
+        }
+    }
+
+    pub fn step(time: u64) -> u64 {
+        time / TOTP_STEP
+    }
+
+    // Secret of the handshakes during the step
+    pub fn mix(&self, secret: &[u8; 32], step: u64) -> [u8; 32] {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(self.seed.as_slice())
+            .expect("HMAC takes keys of any length");
+        mac.update(&step.to_be_bytes());
+        let code = Secret::new(mac.finalize().into_bytes().into());
+        Sha256::new()
+            .chain_update(secret)
+            .chain_update(code.as_slice())
+            .chain_update(b"veloxid totp")
+            .finalize()
+            .into()
+    }
+
+    // Outbound sides, the secret of the current step
+    pub fn current(&self, secret: &[u8; 32]) -> [u8; 32] {
+        self.mix(secret, Self::step(unix_time()))
+    }
+
+    // Inbound sides, the steps the peer may be at and their secrets
+    pub fn candidates(&self, secret: &[u8; 32]) -> Vec<(u64, [u8; 32])> {
+        self.candidates_at(secret, unix_time())
+    }
+
+    pub fn candidates_at(&self, secret: &[u8; 32], time: u64) -> Vec<(u64, [u8; 32])> {
+        let now = Self::step(time);
+        (now.saturating_sub(self.skew)..=now + self.skew)
+            .map(|step| (step, self.mix(secret, step)))
+            .collect()
+    }
+
+    // A peer got in with the step, false if the same handshake did already
+    pub fn accept(&self, step: u64, handshake: TotpUse) -> bool {
+        self.accept_at(step, handshake, unix_time())
+    }
+
+    pub fn accept_at(&self, step: u64, handshake: TotpUse, time: u64) -> bool {
+        let oldest = Self::step(time).saturating_sub(self.skew);
+        let mut used = self.used.lock().unwrap();
+        // Steps past the skew aren't candidates anymore
+        used.retain(|&step, _| step >= oldest);
+        used.entry(step).or_default().insert(handshake)
+    }
+}
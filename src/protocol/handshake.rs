@@ -0,0 +1,437 @@
+use crate::protocol::encryption::{token_matches, Secret};
+use crate::protocol::ticket::TicketState;
+use chacha20::{
+    cipher::{KeyIvInit, StreamCipher},
+    ChaCha20,
+};
+use sha2::{Digest, Sha256};
+use zeroize::Zeroize;
+
+// Byte-driven handshake parsers, free of any I/O so they can be fuzzed and
+// driven one byte at a time. The caller does the reads, writes and timeouts.
+//
+// Wire format:
+// inbound  -> outbound: 12 byte nonce
+// outbound -> inbound:  auth token of its version encrypted with ChaCha20(secret, nonce)
+//                       version 3 follows it with its offer: the session cipher, encrypted
+//                       with the same keystream, and a 12 byte salt
+// outbound -> inbound:  tunnels registering for a service announce it, [length][name]
+//                       encrypted with a key of their own, see service_cipher
+// inbound  -> outbound: control frames, ATTACH is sent once the tunnel is attached. Rekeyed
+//                       tunnels carry a fresh 12 byte nonce in it, the session keys of
+//                       every attachment are derived from it.
+//                       PING frames are sent while the tunnel waits, ones with a payload
+//                       are answered right away with a PONG frame carrying it back.
+//
+// Control frames are [kind][payload length][payload]. Version 1 outbound sides only
+// know a single starting byte instead, so they get a bare ATTACH byte. REJECT frames
+// start with the byte version 1 reads as a rejection, they are sent to both.
+
+pub const NONCE_LEN: usize = 12;
+pub const VERSION: u8 = 2;
+pub const AUTH: [u8; 4] = *b"AUTH";
+pub const AUTH_V2: [u8; 4] = *b"AUT2";
+pub const AUTH_V3: [u8; 4] = *b"AUT3";
+pub const SALT_LEN: usize = 12;
+pub const OFFER_LEN: usize = 1 + SALT_LEN;
+pub const ATTACH_NONCE_LEN: usize = 12;
+
+// Session ciphers, versions 1 and 2 only speak ChaCha20 and are never asked for it
+pub const CIPHER_CHACHA20: u8 = 0x00;
+pub const CIPHER_XCHACHA20: u8 = 0x01;
+pub const CIPHER_AES_256_GCM: u8 = 0x02;
+pub const CIPHER_CHACHA20_POLY1305: u8 = 0x03;
+
+// Control frame kinds
+pub const CONTROL_ATTACH: u8 = 0x01;
+pub const CONTROL_REJECT: u8 = 0x02; // payload: reason
+pub const CONTROL_PING: u8 = 0x03;
+pub const CONTROL_TICKET: u8 = 0x04; // payload: resumption ticket, see ticket.rs
+pub const CONTROL_PONG: u8 = 0x05; // payload: the ping's
+
+// Rejection reasons, so the outbound side can pick its backoff
+pub const REASON_UNKNOWN: u8 = 0x00;
+pub const REASON_SECRET_MISMATCH: u8 = 0x01;
+pub const REASON_BANNED: u8 = 0x02;
+pub const REASON_ROUTE_FULL: u8 = 0x03;
+pub const REASON_DRAINING: u8 = 0x04;
+pub const REASON_OUTSIDE_SCHEDULE: u8 = 0x05;
+pub const REASON_CIPHER_MISMATCH: u8 = 0x06;
+pub const REASON_TICKET_REJECTED: u8 = 0x07;
+pub const REASON_UNKNOWN_SERVICE: u8 = 0x08;
+
+// Longest service name a tunnel can announce
+pub const MAX_SERVICE_LEN: usize = u8::MAX as usize;
+
+pub fn auth_token(version: u8) -> [u8; 4] {
+    match version {
+        1 => AUTH,
+        2 => AUTH_V2,
+        _ => AUTH_V3,
+    }
+}
+
+// Sent by the inbound side once the tunnel is attached
+pub fn attach_frame(version: u8) -> &'static [u8] {
+    match version {
+        1 => &[CONTROL_ATTACH],
+        _ => &[CONTROL_ATTACH, 0],
+    }
+}
+
+// ATTACH of a rekeyed tunnel, versions 2 and up
+pub fn rekey_attach_frame(nonce: [u8; ATTACH_NONCE_LEN]) -> [u8; 2 + ATTACH_NONCE_LEN] {
+    let mut frame = [0u8; 2 + ATTACH_NONCE_LEN];
+    frame[0] = CONTROL_ATTACH;
+    frame[1] = ATTACH_NONCE_LEN as u8;
+    frame[2..].copy_from_slice(&nonce);
+    frame
+}
+
+pub fn ticket_frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![CONTROL_TICKET, payload.len() as u8];
+    frame.extend_from_slice(payload);
+    frame
+}
+
+// Heartbeat of a tunnel waiting to be attached, the sequence number comes back in the pong
+pub const HEARTBEAT_FRAME_LEN: usize = 2 + 8;
+
+pub fn ping_frame(seq: u64) -> [u8; HEARTBEAT_FRAME_LEN] {
+    heartbeat_frame(CONTROL_PING, seq)
+}
+
+pub fn pong_frame(seq: u64) -> [u8; HEARTBEAT_FRAME_LEN] {
+    heartbeat_frame(CONTROL_PONG, seq)
+}
+
+fn heartbeat_frame(kind: u8, seq: u64) -> [u8; HEARTBEAT_FRAME_LEN] {
+    let mut frame = [0u8; HEARTBEAT_FRAME_LEN];
+    frame[0] = kind;
+    frame[1] = 8;
+    frame[2..].copy_from_slice(&seq.to_be_bytes());
+    frame
+}
+
+// Both sides derive it from the nonce, the service doesn't show on the wire
+pub fn service_cipher(secret: &[u8; 32], nonce: &[u8; NONCE_LEN]) -> ChaCha20 {
+    let key: [u8; 32] = Sha256::new()
+        .chain_update(secret)
+        .chain_update(nonce)
+        .chain_update(b"veloxid service")
+        .finalize()
+        .into();
+    ChaCha20::new(&key.into(), nonce.into())
+}
+
+// Sent by the outbound side once it has the nonce, names are at most MAX_SERVICE_LEN bytes
+pub fn service_frame(secret: &[u8; 32], nonce: &[u8; NONCE_LEN], service: &str) -> Vec<u8> {
+    let mut frame = vec![service.len() as u8];
+    frame.extend_from_slice(service.as_bytes());
+    service_cipher(secret, nonce).apply_keystream(&mut frame);
+    frame
+}
+
+pub fn reject_frame(reason: u8) -> [u8; 3] {
+    [CONTROL_REJECT, 1, reason]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InboundEvent {
+    Authenticated { version: u8 },
+    SecretMismatch,
+}
+
+// Inbound side: the nonce is already sent, waits for the encrypted "AUTH"
+pub struct InboundHandshake {
+    cipher: ChaCha20,
+    auth: [u8; 4],
+    len: usize,
+    // Offer of a version 3 peer, once its token is verified
+    offer: Option<([u8; OFFER_LEN], usize)>,
+    outcome: Option<InboundEvent>,
+}
+
+impl InboundHandshake {
+    pub fn new(secret: [u8; 32], nonce: [u8; 12]) -> Self {
+        Self {
+            cipher: ChaCha20::new(&secret.into(), &nonce.into()),
+            auth: [0u8; 4],
+            len: 0,
+            offer: None,
+            outcome: None,
+        }
+    }
+
+    // Bytes still needed before an outcome is known
+    pub fn remaining(&self) -> usize {
+        match (self.outcome, self.offer) {
+            (Some(_), _) => 0,
+            (None, Some((_, len))) => OFFER_LEN - len,
+            (None, None) => AUTH.len() - self.len,
+        }
+    }
+
+    // Cipher asked for by the peer
+    pub fn cipher(&self) -> u8 {
+        match self.offer {
+            Some((offer, _)) => offer[0],
+            None => CIPHER_CHACHA20,
+        }
+    }
+
+    pub fn salt(&self) -> [u8; SALT_LEN] {
+        let mut salt = [0u8; SALT_LEN];
+        if let Some((offer, _)) = self.offer {
+            salt.copy_from_slice(&offer[1..]);
+        }
+        salt
+    }
+
+    // Push several bytes, returns the last event
+    pub fn feed(&mut self, bytes: &[u8]) -> Option<InboundEvent> {
+        bytes.iter().fold(None, |_, byte| self.push(*byte))
+    }
+
+    pub fn push(&mut self, byte: u8) -> Option<InboundEvent> {
+        if self.outcome.is_some() {
+            return self.outcome;
+        }
+
+        // Offer
+        if let Some((offer, len)) = &mut self.offer {
+            offer[*len] = byte;
+            *len += 1;
+            if *len < OFFER_LEN {
+                return None;
+            }
+            self.cipher.apply_keystream(&mut offer[..1]);
+            self.outcome = Some(InboundEvent::Authenticated { version: 3 });
+            return self.outcome;
+        }
+
+        self.auth[self.len] = byte;
+        self.len += 1;
+        if self.len < AUTH.len() {
+            return None;
+        }
+
+        // Every token is compared in full, how long it takes tells nothing of the secret
+        self.cipher.apply_keystream(&mut self.auth);
+        let matches = [AUTH, AUTH_V2, AUTH_V3].map(|token| token_matches(&self.auth, &token));
+        self.auth.zeroize();
+        self.outcome = match matches {
+            [true, _, _] => Some(InboundEvent::Authenticated { version: 1 }),
+            [_, true, _] => Some(InboundEvent::Authenticated { version: 2 }),
+            [_, _, true] => {
+                self.offer = Some(([0u8; OFFER_LEN], 0));
+                None
+            }
+            _ => Some(InboundEvent::SecretMismatch),
+        };
+        self.outcome
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutboundEvent {
+    // Nonce is received, the encrypted auth token has to be sent back, followed by the
+    // offer on version 3
+    SendAuth {
+        nonce: [u8; 12],
+        auth: [u8; 4],
+        offer: Option<[u8; OFFER_LEN]>,
+    },
+    Accepted,
+    Rejected {
+        reason: u8,
+    },
+    // Keepalive while waiting to be attached
+    Ping,
+}
+
+// Outbound side: receives the nonce, answers with its auth token, then waits for ATTACH
+pub struct OutboundHandshake {
+    secret: Secret,
+    version: u8,
+    cipher: u8,
+    salt: [u8; SALT_LEN],
+    nonce: [u8; 12],
+    len: usize,
+    // Control frame being received
+    frame: Vec<u8>,
+    // Sent along with ATTACH by rekeyed inbound sides
+    attach_nonce: Option<[u8; ATTACH_NONCE_LEN]>,
+    // Payload of the last TICKET frame
+    ticket: Option<Vec<u8>>,
+    // Answer to the last PING frame, until it is sent
+    pong: Option<Vec<u8>>,
+    outcome: Option<OutboundEvent>,
+}
+
+impl OutboundHandshake {
+    pub fn new(secret: [u8; 32]) -> Self {
+        Self::with_version(secret, VERSION)
+    }
+
+    // Speak an older version, for inbound sides that don't know the current one
+    pub fn with_version(secret: [u8; 32], version: u8) -> Self {
+        Self {
+            secret: Secret::new(secret),
+            version,
+            cipher: CIPHER_CHACHA20,
+            salt: [0u8; SALT_LEN],
+            nonce: [0u8; 12],
+            len: 0,
+            frame: Vec::new(),
+            attach_nonce: None,
+            ticket: None,
+            pong: None,
+            outcome: None,
+        }
+    }
+
+    // Ask for another session cipher than ChaCha20, which takes version 3
+    pub fn with_cipher(secret: [u8; 32], cipher: u8) -> Self {
+        if cipher == CIPHER_CHACHA20 {
+            return Self::new(secret);
+        }
+        Self::with_salt(
+            secret,
+            cipher,
+            crate::protocol::encryption::generate_random_nonce(),
+        )
+    }
+
+    // Like with_cipher, with the salt given instead of a random one, for test vectors
+    pub fn with_salt(secret: [u8; 32], cipher: u8, salt: [u8; SALT_LEN]) -> Self {
+        Self {
+            cipher,
+            salt,
+            ..Self::with_version(secret, 3)
+        }
+    }
+
+    // Pick up where a ticket left off, the auth token is replaced by the ticket then and
+    // isn't sent
+    pub fn resuming(secret: [u8; 32], state: &TicketState) -> Self {
+        Self {
+            cipher: state.cipher,
+            salt: state.salt,
+            ..Self::with_version(secret, state.version)
+        }
+    }
+
+    // Version spoken, 3 when asking for another cipher
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn salt(&self) -> [u8; SALT_LEN] {
+        self.salt
+    }
+
+    pub fn ticket(&self) -> Option<&[u8]> {
+        self.ticket.as_deref()
+    }
+
+    // PONG frame to send after a Ping event, if the ping asked for one
+    pub fn take_pong(&mut self) -> Option<Vec<u8>> {
+        self.pong.take()
+    }
+
+    // Nonce of the attachment, once accepted by a rekeyed inbound side
+    pub fn attach_nonce(&self) -> Option<[u8; ATTACH_NONCE_LEN]> {
+        self.attach_nonce
+    }
+
+    // Bytes still needed before the next event
+    pub fn remaining(&self) -> usize {
+        match self.outcome {
+            Some(_) => 0,
+            None if self.len < NONCE_LEN => NONCE_LEN - self.len,
+            None if self.version == 1 || self.frame.len() < 2 => 1,
+            None => 2 + self.frame[1] as usize - self.frame.len(),
+        }
+    }
+
+    // Push several bytes, returns the last event
+    pub fn feed(&mut self, bytes: &[u8]) -> Option<OutboundEvent> {
+        bytes.iter().fold(None, |_, byte| self.push(*byte))
+    }
+
+    pub fn push(&mut self, byte: u8) -> Option<OutboundEvent> {
+        if self.outcome.is_some() {
+            return self.outcome;
+        }
+
+        // Nonce
+        if self.len < NONCE_LEN {
+            self.nonce[self.len] = byte;
+            self.len += 1;
+            if self.len < NONCE_LEN {
+                return None;
+            }
+
+            let mut auth = auth_token(self.version);
+            let mut cipher = ChaCha20::new(&(*self.secret).into(), &self.nonce.into());
+            cipher.apply_keystream(&mut auth);
+            let offer = (self.version >= 3).then(|| {
+                let mut offer = [0u8; OFFER_LEN];
+                offer[0] = self.cipher;
+                cipher.apply_keystream(&mut offer[..1]);
+                offer[1..].copy_from_slice(&self.salt);
+                offer
+            });
+            return Some(OutboundEvent::SendAuth {
+                nonce: self.nonce,
+                auth,
+                offer,
+            });
+        }
+
+        // Starting byte
+        if self.version == 1 {
+            self.outcome = Some(match byte {
+                CONTROL_REJECT => OutboundEvent::Rejected {
+                    reason: REASON_SECRET_MISMATCH,
+                },
+                _ => OutboundEvent::Accepted,
+            });
+            return self.outcome;
+        }
+
+        // Control frames
+        self.frame.push(byte);
+        if self.frame.len() < 2 || self.frame.len() < 2 + self.frame[1] as usize {
+            return None;
+        }
+        let frame = std::mem::take(&mut self.frame);
+        match frame[0] {
+            CONTROL_ATTACH => {
+                self.attach_nonce = frame[2..].try_into().ok();
+                self.outcome = Some(OutboundEvent::Accepted)
+            }
+            CONTROL_REJECT => {
+                self.outcome = Some(OutboundEvent::Rejected {
+                    reason: frame.get(2).copied().unwrap_or(REASON_UNKNOWN),
+                })
+            }
+            CONTROL_PING => {
+                if frame.len() > 2 {
+                    let mut pong = frame;
+                    pong[0] = CONTROL_PONG;
+                    self.pong = Some(pong);
+                }
+                return Some(OutboundEvent::Ping);
+            }
+            CONTROL_TICKET => {
+                self.ticket = Some(frame[2..].to_vec());
+                return None;
+            }
+            // Unknown frames are skipped, for newer inbound sides
+            _ => return None,
+        }
+        self.outcome
+    }
+}
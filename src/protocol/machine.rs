@@ -0,0 +1,489 @@
+use crate::protocol::{
+    encryption::{Secret, Totp},
+    handshake::{
+        reject_frame, service_cipher, service_frame, ticket_frame, InboundEvent, InboundHandshake,
+        OutboundEvent, OutboundHandshake, ATTACH_NONCE_LEN, AUTH, NONCE_LEN,
+        REASON_CIPHER_MISMATCH, REASON_SECRET_MISMATCH, REASON_TICKET_REJECTED,
+        REASON_UNKNOWN_SERVICE, SALT_LEN, VERSION,
+    },
+    silent::{silent_hello, Silence, SILENT_HELLO_LEN},
+    ticket::{resume_hello, resumption_key, Redeemed, TicketState, Tickets, RESUME, RESUME_LEN},
+};
+use chacha20::{cipher::StreamCipher, ChaCha20};
+use std::collections::VecDeque;
+
+// The whole handshake of either side without I/O: bytes read are handed to handle_input,
+// bytes to write come out of poll_transmit and the outcome out of poll_event. The caller
+// reads at most wants() bytes at a time so none of the session's is taken, and keeps the
+// time, the timeouts are its own. The randomness is the nonces, given by the caller on the
+// inbound side, and the salt and resumption nonce of the outbound side.
+
+// What a finished handshake leaves the session with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Handshaken {
+    pub nonce: [u8; NONCE_LEN],
+    pub version: u8,
+    pub salt: [u8; SALT_LEN],
+    // Sent along with ATTACH by rekeyed inbound sides, outbound only
+    pub attach_nonce: Option<[u8; ATTACH_NONCE_LEN]>,
+    // Announced by the outbound side, inbound sides expecting one only
+    pub service: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AcceptEvent {
+    // The peer has the secret, or a ticket if resumed. An announcement may follow.
+    Authenticated { resumed: bool },
+    Done(Handshaken),
+    // The REJECT frame telling the peer why is queued
+    Refused { reason: u8 },
+}
+
+#[derive(Default)]
+pub struct InboundOptions {
+    // Id of the session cipher the peer must ask for
+    pub cipher: u8,
+    // Peers may resume with a ticket, the next one is issued to them
+    pub tickets: Option<Tickets>,
+    // The peer announces a service once authenticated
+    pub expect_service: bool,
+    // Nothing is sent before the peer's silent hello is admitted
+    pub silence: Option<Silence>,
+    // The peer's auth token is taken with the secret of a step it may be at
+    pub totp: Option<Totp>,
+}
+
+enum AcceptState {
+    // Silent hello of the peer, before the nonce is sent
+    Silent(Box<[u8; SILENT_HELLO_LEN]>, usize),
+    // The auth token, or the marker of a resume hello
+    Hello([u8; AUTH.len()], usize),
+    // Offer of a version 3 peer, read by the handshake parser
+    Offer,
+    Resume(Box<[u8; RESUME_LEN - RESUME.len()]>, usize),
+    ServiceLen,
+    ServiceName(Vec<u8>, usize),
+    Finished,
+}
+
+pub struct InboundMachine {
+    secret: Secret,
+    nonce: [u8; NONCE_LEN],
+    options: InboundOptions,
+    handshake: InboundHandshake,
+    state: AcceptState,
+    version: u8,
+    salt: [u8; SALT_LEN],
+    service: Option<ChaCha20>,
+    transmit: VecDeque<Vec<u8>>,
+    events: VecDeque<AcceptEvent>,
+}
+
+impl InboundMachine {
+    // The nonce is the first thing to send, unless the peer has to be admitted first
+    pub fn new(secret: [u8; 32], nonce: [u8; NONCE_LEN], options: InboundOptions) -> Self {
+        let (state, transmit) = match options.silence {
+            Some(_) => (
+                AcceptState::Silent(Box::new([0u8; SILENT_HELLO_LEN]), 0),
+                VecDeque::new(),
+            ),
+            None => (
+                AcceptState::Hello([0u8; AUTH.len()], 0),
+                VecDeque::from([nonce.to_vec()]),
+            ),
+        };
+        Self {
+            secret: Secret::new(secret),
+            nonce,
+            options,
+            handshake: InboundHandshake::new(secret, nonce),
+            state,
+            version: 0,
+            salt: [0u8; SALT_LEN],
+            service: None,
+            transmit,
+            events: VecDeque::new(),
+        }
+    }
+
+    // Bytes needed before anything more can happen, 0 once finished
+    pub fn wants(&self) -> usize {
+        match &self.state {
+            AcceptState::Silent(hello, len) => hello.len() - len,
+            AcceptState::Hello(_, len) => AUTH.len() - len,
+            AcceptState::Offer => self.handshake.remaining(),
+            AcceptState::Resume(hello, len) => hello.len() - len,
+            AcceptState::ServiceLen => 1,
+            AcceptState::ServiceName(name, len) => name.len() - len,
+            AcceptState::Finished => 0,
+        }
+    }
+
+    // Bytes taken, the rest belong to the session
+    pub fn handle_input(&mut self, bytes: &[u8]) -> usize {
+        let mut taken = 0;
+        while taken < bytes.len() && self.wants() > 0 {
+            self.push(bytes[taken]);
+            taken += 1;
+        }
+        taken
+    }
+
+    pub fn poll_transmit(&mut self) -> Option<Vec<u8>> {
+        self.transmit.pop_front()
+    }
+
+    pub fn poll_event(&mut self) -> Option<AcceptEvent> {
+        self.events.pop_front()
+    }
+
+    fn push(&mut self, byte: u8) {
+        match &mut self.state {
+            AcceptState::Silent(hello, len) => {
+                hello[*len] = byte;
+                *len += 1;
+                if *len < hello.len() {
+                    return;
+                }
+                let admitted = self
+                    .options
+                    .silence
+                    .as_ref()
+                    .is_some_and(|s| s.admit(hello));
+                match admitted {
+                    true => {
+                        self.transmit.push_back(self.nonce.to_vec());
+                        self.state = AcceptState::Hello([0u8; AUTH.len()], 0);
+                    }
+                    // Not even a rejection is sent
+                    false => {
+                        self.state = AcceptState::Finished;
+                        self.events.push_back(AcceptEvent::Refused {
+                            reason: REASON_SECRET_MISMATCH,
+                        });
+                    }
+                }
+            }
+            AcceptState::Hello(hello, len) => {
+                hello[*len] = byte;
+                *len += 1;
+                if *len < hello.len() {
+                    return;
+                }
+                let hello = *hello;
+                if self.options.tickets.is_some() && hello == RESUME {
+                    self.state = AcceptState::Resume(Box::new([0u8; RESUME_LEN - RESUME.len()]), 0);
+                    return;
+                }
+                let event = match self.options.totp.clone() {
+                    Some(totp) => self.pick(&totp, &hello),
+                    None => self.handshake.feed(&hello),
+                };
+                match event {
+                    Some(event) => self.verify(event),
+                    None => self.state = AcceptState::Offer,
+                }
+            }
+            AcceptState::Offer => {
+                if let Some(event) = self.handshake.push(byte) {
+                    self.verify(event);
+                }
+            }
+            AcceptState::Resume(hello, len) => {
+                hello[*len] = byte;
+                *len += 1;
+                if *len < hello.len() {
+                    return;
+                }
+                let redeemed = self.options.tickets.as_ref().map(|t| t.redeem(hello));
+                match redeemed {
+                    Some(Redeemed::Valid(state)) if state.cipher == self.options.cipher => {
+                        self.authenticated(state.version, state.salt, true)
+                    }
+                    Some(Redeemed::Valid(_)) => self.refuse(REASON_CIPHER_MISMATCH),
+                    Some(Redeemed::Stale) => self.refuse(REASON_TICKET_REJECTED),
+                    _ => self.refuse(REASON_SECRET_MISMATCH),
+                }
+            }
+            AcceptState::ServiceLen => {
+                let mut len = [byte];
+                if let Some(cipher) = &mut self.service {
+                    cipher.apply_keystream(&mut len);
+                }
+                match len[0] {
+                    0 => self.refuse(REASON_UNKNOWN_SERVICE),
+                    len => self.state = AcceptState::ServiceName(vec![0u8; len as usize], 0),
+                }
+            }
+            AcceptState::ServiceName(name, len) => {
+                name[*len] = byte;
+                *len += 1;
+                if *len < name.len() {
+                    return;
+                }
+                let mut name = std::mem::take(name);
+                if let Some(cipher) = &mut self.service {
+                    cipher.apply_keystream(&mut name);
+                }
+                match String::from_utf8(name) {
+                    Ok(service) => self.finish(Some(service)),
+                    Err(_) => self.refuse(REASON_UNKNOWN_SERVICE),
+                }
+            }
+            AcceptState::Finished => {}
+        }
+    }
+
+    // Takes the auth token with the secret of each step the peer may be at, the handshake goes
+    // on with the one it was sealed with
+    fn pick(&mut self, totp: &Totp, hello: &[u8; AUTH.len()]) -> Option<InboundEvent> {
+        let mut picked = None;
+        for (step, secret) in totp.candidates(&self.secret) {
+            let mut handshake = InboundHandshake::new(secret, self.nonce);
+            let event = handshake.feed(hello);
+            if event != Some(InboundEvent::SecretMismatch) && picked.is_none() {
+                picked = Some((step, secret, handshake, event));
+            }
+        }
+        let Some((step, secret, handshake, event)) = picked else {
+            return Some(InboundEvent::SecretMismatch);
+        };
+        // A hello replayed against the same nonce is refused like any wrong one
+        let mut taken = [0u8; 16];
+        taken[..NONCE_LEN].copy_from_slice(&self.nonce);
+        taken[NONCE_LEN..].copy_from_slice(hello);
+        if !totp.accept(step, taken) {
+            return Some(InboundEvent::SecretMismatch);
+        }
+        self.secret = Secret::new(secret);
+        self.handshake = handshake;
+        event
+    }
+
+    // The handshake's secret, mixed with the code of the peer's step with a Totp
+    pub fn secret(&self) -> [u8; 32] {
+        *self.secret
+    }
+
+    fn verify(&mut self, event: InboundEvent) {
+        match event {
+            InboundEvent::Authenticated { version }
+                if self.handshake.cipher() == self.options.cipher =>
+            {
+                self.authenticated(version, self.handshake.salt(), false)
+            }
+            InboundEvent::Authenticated { .. } => self.refuse(REASON_CIPHER_MISMATCH),
+            InboundEvent::SecretMismatch => self.refuse(REASON_SECRET_MISMATCH),
+        }
+    }
+
+    fn authenticated(&mut self, version: u8, salt: [u8; SALT_LEN], resumed: bool) {
+        self.version = version;
+        self.salt = salt;
+        self.events
+            .push_back(AcceptEvent::Authenticated { resumed });
+        match self.options.expect_service {
+            true => {
+                self.service = Some(service_cipher(&self.secret, &self.nonce));
+                self.state = AcceptState::ServiceLen;
+            }
+            false => self.finish(None),
+        }
+    }
+
+    fn finish(&mut self, service: Option<String>) {
+        // The next ticket, version 1 has no control frames to send it in
+        if let Some(tickets) = self.options.tickets.as_ref().filter(|_| self.version >= 2) {
+            let state = TicketState {
+                version: self.version,
+                cipher: self.options.cipher,
+                salt: self.salt,
+                key: resumption_key(&self.secret, &self.nonce, &self.salt),
+            };
+            self.transmit
+                .push_back(ticket_frame(&tickets.issue(&state)));
+        }
+        self.state = AcceptState::Finished;
+        self.events.push_back(AcceptEvent::Done(Handshaken {
+            nonce: self.nonce,
+            version: self.version,
+            salt: self.salt,
+            attach_nonce: None,
+            service,
+        }));
+    }
+
+    fn refuse(&mut self, reason: u8) {
+        self.transmit.push_back(reject_frame(reason).to_vec());
+        self.state = AcceptState::Finished;
+        self.events.push_back(AcceptEvent::Refused { reason });
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectEvent {
+    // The inbound side attached the tunnel
+    Accepted(Handshaken),
+    Rejected { reason: u8 },
+}
+
+pub struct OutboundOptions {
+    // Older versions for inbound sides that don't know the current one
+    pub version: u8,
+    // Id of the session cipher asked for
+    pub cipher: u8,
+    // Resume with a ticket of the cipher if one is held, keep the next one
+    pub tickets: Option<Tickets>,
+    // Service announced to a registry endpoint
+    pub announce: Option<String>,
+    // Send a silent hello first, for inbound sides that stay silent until then
+    pub silent: bool,
+    // The secret is mixed with the code of the current step
+    pub totp: Option<Totp>,
+}
+
+impl Default for OutboundOptions {
+    fn default() -> Self {
+        Self {
+            version: VERSION,
+            cipher: 0,
+            tickets: None,
+            announce: None,
+            silent: false,
+            totp: None,
+        }
+    }
+}
+
+pub struct OutboundMachine {
+    secret: Secret,
+    options: OutboundOptions,
+    handshake: OutboundHandshake,
+    resumed: bool,
+    nonce: Option<[u8; NONCE_LEN]>,
+    finished: bool,
+    transmit: VecDeque<Vec<u8>>,
+    events: VecDeque<ConnectEvent>,
+}
+
+impl OutboundMachine {
+    // Resuming sends its hello right away, before the nonce arrives, after the silent hello
+    pub fn new(secret: [u8; 32], options: OutboundOptions) -> Self {
+        // The silent hello is checked before the inbound side picks a step
+        let silent = options.silent.then(|| silent_hello(&secret).to_vec());
+        let secret = match &options.totp {
+            Some(totp) => totp.current(&secret),
+            None => secret,
+        };
+        // Tickets of another cipher are left to expire
+        let ticket = options
+            .tickets
+            .as_ref()
+            .and_then(Tickets::take)
+            .filter(|ticket| ticket.state.cipher == options.cipher);
+        let handshake = match (&ticket, options.version) {
+            (Some(ticket), _) => OutboundHandshake::resuming(secret, &ticket.state),
+            (None, VERSION) => OutboundHandshake::with_cipher(secret, options.cipher),
+            (None, version) => OutboundHandshake::with_version(secret, version),
+        };
+        let resume = ticket.iter().map(|t| resume_hello(t).to_vec());
+        let transmit = silent.into_iter().chain(resume).collect();
+        Self {
+            secret: Secret::new(secret),
+            resumed: ticket.is_some(),
+            options,
+            handshake,
+            nonce: None,
+            finished: false,
+            transmit,
+            events: VecDeque::new(),
+        }
+    }
+
+    // Bytes needed before anything more can happen, 0 once finished
+    pub fn wants(&self) -> usize {
+        match self.finished {
+            true => 0,
+            false => self.handshake.remaining(),
+        }
+    }
+
+    // The handshake's secret, mixed with the code of the current step with a Totp
+    pub fn secret(&self) -> [u8; 32] {
+        *self.secret
+    }
+
+    // The inbound side's, once received
+    pub fn nonce(&self) -> Option<[u8; NONCE_LEN]> {
+        self.nonce
+    }
+
+    // Bytes taken, the rest belong to the session
+    pub fn handle_input(&mut self, bytes: &[u8]) -> usize {
+        let mut taken = 0;
+        while taken < bytes.len() && self.wants() > 0 {
+            self.push(bytes[taken]);
+            taken += 1;
+        }
+        taken
+    }
+
+    pub fn poll_transmit(&mut self) -> Option<Vec<u8>> {
+        self.transmit.pop_front()
+    }
+
+    pub fn poll_event(&mut self) -> Option<ConnectEvent> {
+        self.events.pop_front()
+    }
+
+    fn push(&mut self, byte: u8) {
+        match self.handshake.push(byte) {
+            Some(OutboundEvent::SendAuth { nonce, auth, offer }) => {
+                self.nonce = Some(nonce);
+                // A resume hello stands for the auth token
+                if !self.resumed {
+                    let mut hello = auth.to_vec();
+                    hello.extend(offer.iter().flatten());
+                    self.transmit.push_back(hello);
+                }
+                if let Some(service) = &self.options.announce {
+                    let frame = service_frame(&self.secret, &nonce, service);
+                    self.transmit.push_back(frame);
+                }
+            }
+            Some(OutboundEvent::Accepted) => self.accepted(),
+            Some(OutboundEvent::Rejected { reason }) => {
+                self.finished = true;
+                self.events.push_back(ConnectEvent::Rejected { reason });
+            }
+            Some(OutboundEvent::Ping) => {
+                if let Some(pong) = self.handshake.take_pong() {
+                    self.transmit.push_back(pong);
+                }
+            }
+            None => {}
+        }
+    }
+
+    fn accepted(&mut self) {
+        let nonce = self.nonce.unwrap_or_default();
+        let salt = self.handshake.salt();
+        if let (Some(tickets), Some(payload)) = (&self.options.tickets, self.handshake.ticket()) {
+            let state = TicketState {
+                version: self.handshake.version(),
+                cipher: self.options.cipher,
+                salt,
+                key: resumption_key(&self.secret, &nonce, &salt),
+            };
+            tickets.keep(payload, state);
+        }
+        self.finished = true;
+        self.events.push_back(ConnectEvent::Accepted(Handshaken {
+            nonce,
+            version: self.handshake.version(),
+            salt,
+            attach_nonce: self.handshake.attach_nonce(),
+            service: None,
+        }));
+    }
+}
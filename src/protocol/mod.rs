@@ -0,0 +1,21 @@
+// The tunnel protocol: handshake state machines, frames, session ciphers and resumption
+// tickets. Bytes in, bytes out, the streams are the caller's.
+pub mod cipher;
+pub mod datagram;
+pub mod encryption;
+pub mod handshake;
+pub mod machine;
+pub mod padding;
+pub mod silent;
+pub mod ticket;
+pub mod vectors;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Seconds since the epoch, what hellos, knocks, tickets and TOTP steps are stamped with
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -0,0 +1,267 @@
+use crate::transport::Stream;
+use chacha20::{
+    cipher::{KeyIvInit, StreamCipher},
+    ChaCha20,
+};
+use futures::FutureExt;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::{
+    cmp, io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tokio::{
+    io::{
+        duplex, split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf,
+        ReadHalf, WriteHalf,
+    },
+    task,
+    time::{interval, sleep, Duration, MissedTickBehavior},
+};
+
+// The tunnel side of a session sent as frames, so the traffic can carry padding and
+// dummy frames. Frames are encrypted with a keystream of their own:
+// [u16 data length][u16 padding length][data][padding]
+const HEADER_LEN: usize = 4;
+const MAX_DATA: usize = 16 * 1024;
+const MAX_PADDING: usize = 256;
+// Random dummy frames while idle
+const MIN_IDLE: Duration = Duration::from_millis(500);
+const MAX_IDLE: Duration = Duration::from_secs(3);
+// Size of every frame at a constant bitrate
+const CONSTANT_FRAME_LEN: usize = 1024;
+const PIPE_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Padding {
+    // Random padding on each frame, dummy frames at random intervals while idle
+    Random,
+    // Frames of one size at a fixed rate, whether there is data or not
+    Constant { bytes_per_sec: u32 },
+}
+
+// Stream of frames: reads are deframed, writes go to the task writing the frames
+pub struct PaddedStream<S> {
+    reader: Deframer<ReadHalf<S>>,
+    writer: DuplexStream,
+}
+
+// Both sides derive the same keystreams, one per direction
+pub fn wrap<S: Stream>(
+    stream: S,
+    padding: Padding,
+    secret: &[u8; 32],
+    nonce: &[u8; 12],
+    is_inbound: bool,
+) -> PaddedStream<S> {
+    let (read_half, write_half) = split(stream);
+    let (writer, pipe) = duplex(PIPE_SIZE);
+    let cipher = cipher(secret, nonce, is_inbound);
+    task::spawn(async move {
+        let _ = match padding {
+            Padding::Random => write_random(pipe, write_half, cipher).await,
+            Padding::Constant { bytes_per_sec } => {
+                write_constant(pipe, write_half, cipher, bytes_per_sec).await
+            }
+        };
+    });
+
+    PaddedStream {
+        reader: Deframer::new(read_half, self::cipher(secret, nonce, !is_inbound)),
+        writer,
+    }
+}
+
+// Keystream of the frames written by one side
+fn cipher(secret: &[u8; 32], nonce: &[u8; 12], inbound_writes: bool) -> ChaCha20 {
+    let key: [u8; 32] = Sha256::new()
+        .chain_update(secret)
+        .chain_update(nonce)
+        .chain_update(match inbound_writes {
+            true => b"veloxid padding inbound".as_slice(),
+            false => b"veloxid padding outbound".as_slice(),
+        })
+        .finalize()
+        .into();
+    ChaCha20::new(&key.into(), &(*nonce).into())
+}
+
+fn frame(cipher: &mut ChaCha20, data: &[u8], padding: usize) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_LEN + data.len() + padding);
+    frame.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    frame.extend_from_slice(&(padding as u16).to_be_bytes());
+    frame.extend_from_slice(data);
+    frame.resize(frame.len() + padding, 0);
+    cipher.apply_keystream(&mut frame);
+    frame
+}
+
+async fn write_random<S: Stream>(
+    mut pipe: DuplexStream,
+    mut stream: WriteHalf<S>,
+    mut cipher: ChaCha20,
+) -> io::Result<()> {
+    let mut buffer = vec![0u8; MAX_DATA];
+    loop {
+        let idle = rand::thread_rng().gen_range(MIN_IDLE..MAX_IDLE);
+        let n = tokio::select! {
+            read = pipe.read(&mut buffer) => read?,
+            _ = sleep(idle) => {
+                let padding = rand::thread_rng().gen_range(1..=MAX_PADDING);
+                stream.write_all(&frame(&mut cipher, &[], padding)).await?;
+                continue;
+            }
+        };
+        if n == 0 {
+            return stream.shutdown().await;
+        }
+        let padding = rand::thread_rng().gen_range(0..=MAX_PADDING);
+        stream
+            .write_all(&frame(&mut cipher, &buffer[..n], padding))
+            .await?;
+    }
+}
+
+async fn write_constant<S: Stream>(
+    mut pipe: DuplexStream,
+    mut stream: WriteHalf<S>,
+    mut cipher: ChaCha20,
+    bytes_per_sec: u32,
+) -> io::Result<()> {
+    let frames_per_sec = (bytes_per_sec as f64 / CONSTANT_FRAME_LEN as f64).max(0.1);
+    let mut ticks = interval(Duration::from_secs_f64(1.0 / frames_per_sec));
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut buffer = vec![0u8; CONSTANT_FRAME_LEN - HEADER_LEN];
+    loop {
+        ticks.tick().await;
+        // Whatever data is waiting, the rest of the frame is padding
+        let n = match pipe.read(&mut buffer).now_or_never() {
+            Some(read) => match read? {
+                0 => return stream.shutdown().await,
+                n => n,
+            },
+            None => 0,
+        };
+        let padding = buffer.len() - n;
+        stream
+            .write_all(&frame(&mut cipher, &buffer[..n], padding))
+            .await?;
+    }
+}
+
+struct Deframer<R> {
+    reader: R,
+    cipher: ChaCha20,
+    header: [u8; HEADER_LEN],
+    header_read: usize,
+    data_left: usize,
+    padding_left: usize,
+}
+
+impl<R> Deframer<R> {
+    fn new(reader: R, cipher: ChaCha20) -> Self {
+        Self {
+            reader,
+            cipher,
+            header: [0u8; HEADER_LEN],
+            header_read: 0,
+            data_left: 0,
+            padding_left: 0,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Deframer<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        loop {
+            // Data, straight into the caller's buffer
+            if this.data_left > 0 {
+                let target = buf.initialize_unfilled();
+                let limit = cmp::min(this.data_left, target.len());
+                let mut data = ReadBuf::new(&mut target[..limit]);
+                ready!(Pin::new(&mut this.reader).poll_read(cx, &mut data))?;
+                let n = data.filled().len();
+                if n == 0 {
+                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                }
+                this.cipher.apply_keystream(data.filled_mut());
+                this.data_left -= n;
+                buf.advance(n);
+                return Poll::Ready(Ok(()));
+            }
+
+            // Padding, decrypted only to keep the keystream in sync
+            if this.padding_left > 0 {
+                let mut scratch = [0u8; MAX_PADDING];
+                let limit = cmp::min(this.padding_left, scratch.len());
+                let mut padding = ReadBuf::new(&mut scratch[..limit]);
+                ready!(Pin::new(&mut this.reader).poll_read(cx, &mut padding))?;
+                let n = padding.filled().len();
+                if n == 0 {
+                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                }
+                this.cipher.apply_keystream(padding.filled_mut());
+                this.padding_left -= n;
+                continue;
+            }
+
+            // Next frame
+            let mut header = ReadBuf::new(&mut this.header[this.header_read..]);
+            ready!(Pin::new(&mut this.reader).poll_read(cx, &mut header))?;
+            let n = header.filled().len();
+            if n == 0 && this.header_read == 0 {
+                return Poll::Ready(Ok(())); // EOF
+            }
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            this.header_read += n;
+            if this.header_read < HEADER_LEN {
+                continue;
+            }
+            this.header_read = 0;
+            this.cipher.apply_keystream(&mut this.header);
+            this.data_left = u16::from_be_bytes([this.header[0], this.header[1]]) as usize;
+            this.padding_left = u16::from_be_bytes([this.header[2], this.header[3]]) as usize;
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PaddedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().reader).poll_read(cx, buf)
+    }
+}
+
+impl<S> AsyncWrite for PaddedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().writer).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().writer).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().writer).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,98 @@
+use crate::protocol::{encryption::Secret, unix_time};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use subtle::ConstantTimeEq;
+
+// Silent inbound sides send nothing, not even the nonce, before the peer proves to have
+// the secret:
+//
+// outbound -> inbound: right after connecting, [u64 unix time][16 random bytes][proof],
+//                      the proof is the first 16 bytes of SHA-256(silent key, time, random)
+//
+// Anything else, or a hello seen already, gets the connection closed without a word, so
+// scanners can't tell the port from one nobody answers on. The handshake then goes on as
+// usual, resumption tickets included.
+
+const RANDOM_LEN: usize = 16;
+const PROOF_LEN: usize = 16;
+pub const SILENT_HELLO_LEN: usize = 8 + RANDOM_LEN + PROOF_LEN;
+
+// Seconds the clocks of both sides may be apart
+pub const SILENT_WINDOW: u64 = 30;
+
+fn silent_key(secret: &[u8; 32]) -> Secret {
+    let key = Sha256::new()
+        .chain_update(secret)
+        .chain_update(b"veloxid silent")
+        .finalize();
+    Secret::new(key.into())
+}
+
+fn proof(key: &[u8; 32], time: &[u8], random: &[u8]) -> [u8; PROOF_LEN] {
+    let digest = Sha256::new()
+        .chain_update(key)
+        .chain_update(time)
+        .chain_update(random)
+        .finalize();
+    digest[..PROOF_LEN].try_into().unwrap()
+}
+
+// Sent by the outbound side before anything else
+pub fn silent_hello(secret: &[u8; 32]) -> [u8; SILENT_HELLO_LEN] {
+    silent_hello_at(secret, unix_time())
+}
+
+pub fn silent_hello_at(secret: &[u8; 32], time: u64) -> [u8; SILENT_HELLO_LEN] {
+    let mut hello = [0u8; SILENT_HELLO_LEN];
+    let (stamp, rest) = hello.split_at_mut(8);
+    let (random, rest) = rest.split_at_mut(RANDOM_LEN);
+    stamp.copy_from_slice(&time.to_be_bytes());
+    rand::thread_rng().fill(random);
+    rest.copy_from_slice(&proof(&silent_key(secret), stamp, random));
+    hello
+}
+
+// Hellos an inbound side took, each one is taken once
+#[derive(Clone)]
+pub struct Silence {
+    key: Arc<Secret>,
+    // Random part of each hello taken, until it is out of the window
+    taken: Arc<Mutex<HashMap<[u8; RANDOM_LEN], u64>>>,
+}
+
+impl Silence {
+    pub fn new(secret: &[u8; 32]) -> Self {
+        Self {
+            key: Arc::new(silent_key(secret)),
+            taken: Arc::default(),
+        }
+    }
+
+    // Whether the peer may be answered
+    pub fn admit(&self, hello: &[u8; SILENT_HELLO_LEN]) -> bool {
+        self.admit_at(hello, unix_time())
+    }
+
+    pub fn admit_at(&self, hello: &[u8; SILENT_HELLO_LEN], now: u64) -> bool {
+        let (stamp, rest) = hello.split_at(8);
+        let (random, given) = rest.split_at(RANDOM_LEN);
+        let expected = proof(&self.key, stamp, random);
+        if !bool::from(expected.ct_eq(given)) {
+            return false;
+        }
+        let time = u64::from_be_bytes(stamp.try_into().unwrap());
+        if time.abs_diff(now) > SILENT_WINDOW {
+            return false;
+        }
+
+        let mut taken = self.taken.lock().unwrap();
+        taken.retain(|_, expires| *expires >= now);
+        taken
+            .insert(random.try_into().unwrap(), time + SILENT_WINDOW)
+            .is_none()
+    }
+}
@@ -0,0 +1,221 @@
+use crate::protocol::{
+    encryption::{generate_random_nonce, token_matches},
+    handshake::SALT_LEN,
+    unix_time,
+};
+use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit};
+use chacha20::{
+    cipher::{KeyIvInit, StreamCipher},
+    ChaCha20,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+// Resumption tickets, issued by inbound sides once a peer is authenticated so it can skip
+// waiting for the nonce when it connects again:
+//
+// inbound  -> outbound: TICKET control frame, [u32 lifetime in seconds][ticket]
+// outbound -> inbound:  right after connecting, "VXRS", the ticket, a 12 byte nonce of its
+//                       own and "VXRS" encrypted with ChaCha20(resumption key, that nonce)
+//
+// The ticket is the state of the handshake it was issued in, sealed with a key only the
+// inbound side derives, so it keeps nothing but the tickets already taken. Each ticket is
+// taken once, a replayed one is turned away.
+
+pub const RESUME: [u8; 4] = *b"VXRS";
+// expiry, version, cipher, salt, resumption key
+const STATE_LEN: usize = 8 + 1 + 1 + SALT_LEN + 32;
+pub const TICKET_LEN: usize = 12 + STATE_LEN + 16;
+pub const RESUME_LEN: usize = RESUME.len() + TICKET_LEN + 12 + RESUME.len();
+
+// Tickets held by an outbound side, the oldest are dropped
+const MAX_HELD: usize = 64;
+
+// What a ticket restores of the handshake it was issued in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TicketState {
+    pub version: u8,
+    pub cipher: u8,
+    pub salt: [u8; SALT_LEN],
+    pub key: [u8; 32],
+}
+
+// A ticket as held by the outbound side
+#[derive(Clone)]
+pub struct Ticket {
+    pub sealed: [u8; TICKET_LEN],
+    pub state: TicketState,
+    expires: Instant,
+}
+
+// What an inbound side makes of a resuming peer
+#[derive(Debug, PartialEq, Eq)]
+pub enum Redeemed {
+    Valid(TicketState),
+    // Expired or taken already, the peer falls back to the full handshake
+    Stale,
+    // Not sealed by us, or the peer doesn't have the resumption key
+    Forged,
+}
+
+// Sent by the outbound side in place of its auth token
+pub fn resume_hello(ticket: &Ticket) -> [u8; RESUME_LEN] {
+    let nonce = generate_random_nonce();
+    let mut hello = [0u8; RESUME_LEN];
+    let (marker, rest) = hello.split_at_mut(RESUME.len());
+    let (sealed, rest) = rest.split_at_mut(TICKET_LEN);
+    let (client_nonce, proof) = rest.split_at_mut(12);
+    marker.copy_from_slice(&RESUME);
+    sealed.copy_from_slice(&ticket.sealed);
+    client_nonce.copy_from_slice(&nonce);
+    proof.copy_from_slice(&RESUME);
+    ChaCha20::new(&ticket.state.key.into(), &nonce.into()).apply_keystream(proof);
+    hello
+}
+
+// Both sides derive it from the handshake the ticket is issued in, the resuming side
+// proves to have it
+pub fn resumption_key(secret: &[u8; 32], nonce: &[u8; 12], salt: &[u8; SALT_LEN]) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(secret)
+        .chain_update(nonce)
+        .chain_update(salt)
+        .chain_update(b"veloxid resumption")
+        .finalize()
+        .into()
+}
+
+// Tickets of an endpoint: the ones taken for inbound sides, the ones to resume with for
+// outbound sides
+#[derive(Clone)]
+pub struct Tickets {
+    cipher: Aes256Gcm,
+    lifetime: Duration,
+    inner: Arc<Mutex<Inner>>,
+    // Taken ahead of the handshake by reserve, the only one take gives out then
+    reserved: Option<Arc<Mutex<Option<Ticket>>>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    // Seal nonce of each ticket taken, until it expires
+    taken: HashMap<[u8; 12], u64>,
+    held: VecDeque<Ticket>,
+}
+
+impl Tickets {
+    pub fn new(secret: &[u8; 32], lifetime: Duration) -> Self {
+        let key: [u8; 32] = Sha256::new()
+            .chain_update(secret)
+            .chain_update(b"veloxid ticket")
+            .finalize()
+            .into();
+        Self {
+            cipher: Aes256Gcm::new(&key.into()),
+            lifetime,
+            inner: Arc::default(),
+            reserved: None,
+        }
+    }
+
+    // Payload of the TICKET frame
+    pub fn issue(&self, state: &TicketState) -> Vec<u8> {
+        let expires = unix_time() + self.lifetime.as_secs();
+        let mut plaintext = Vec::with_capacity(STATE_LEN);
+        plaintext.extend_from_slice(&expires.to_be_bytes());
+        plaintext.extend_from_slice(&[state.version, state.cipher]);
+        plaintext.extend_from_slice(&state.salt);
+        plaintext.extend_from_slice(&state.key);
+
+        let nonce = generate_random_nonce();
+        let sealed = self
+            .cipher
+            .encrypt(&nonce.into(), plaintext.as_slice())
+            .expect("tickets are far below the size limit");
+        let mut payload = (self.lifetime.as_secs() as u32).to_be_bytes().to_vec();
+        payload.extend_from_slice(&nonce);
+        payload.extend_from_slice(&sealed);
+        payload
+    }
+
+    // The resume hello past its marker: the ticket, the peer's nonce and its proof
+    pub fn redeem(&self, hello: &[u8; RESUME_LEN - RESUME.len()]) -> Redeemed {
+        let (sealed, rest) = hello.split_at(TICKET_LEN);
+        let (client_nonce, proof) = rest.split_at(12);
+        let (nonce, ciphertext) = sealed.split_at(12);
+        let Ok(plaintext) = self.cipher.decrypt(nonce.into(), ciphertext) else {
+            return Redeemed::Forged;
+        };
+        let state = TicketState {
+            version: plaintext[8],
+            cipher: plaintext[9],
+            salt: plaintext[10..10 + SALT_LEN].try_into().unwrap(),
+            key: plaintext[10 + SALT_LEN..].try_into().unwrap(),
+        };
+        let client_nonce: [u8; 12] = client_nonce.try_into().unwrap();
+        let mut proof: [u8; 4] = proof.try_into().unwrap();
+        ChaCha20::new(&state.key.into(), &client_nonce.into()).apply_keystream(&mut proof);
+        if !token_matches(&proof, &RESUME) {
+            return Redeemed::Forged;
+        }
+
+        let expires = u64::from_be_bytes(plaintext[..8].try_into().unwrap());
+        let now = unix_time();
+        if expires <= now {
+            return Redeemed::Stale;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.taken.retain(|_, expires| *expires > now);
+        match inner.taken.insert(nonce.try_into().unwrap(), expires) {
+            Some(_) => Redeemed::Stale,
+            None => Redeemed::Valid(state),
+        }
+    }
+
+    // Hold on to a TICKET frame's payload to resume with, ignored if malformed
+    pub fn keep(&self, payload: &[u8], state: TicketState) {
+        let Some((lifetime, sealed)) = payload.split_first_chunk::<4>() else {
+            return;
+        };
+        let Ok(sealed) = sealed.try_into() else {
+            return;
+        };
+        let lifetime = Duration::from_secs(u32::from_be_bytes(*lifetime).into());
+        let mut inner = self.inner.lock().unwrap();
+        if inner.held.len() == MAX_HELD {
+            inner.held.pop_front();
+        }
+        inner.held.push_back(Ticket {
+            sealed,
+            state,
+            expires: Instant::now() + lifetime,
+        });
+    }
+
+    // Latest ticket still valid, each is used once
+    pub fn take(&self) -> Option<Ticket> {
+        if let Some(reserved) = &self.reserved {
+            return reserved.lock().unwrap().take();
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        inner.held.retain(|ticket| ticket.expires > now);
+        inner.held.pop_back()
+    }
+
+    // Take a ticket before connecting, for connections that must be sure to speak first.
+    // The handshake given the returned tickets resumes with it, the next one is kept with
+    // the others.
+    pub fn reserve(&self) -> Option<Tickets> {
+        let ticket = self.take()?;
+        Some(Self {
+            reserved: Some(Arc::new(Mutex::new(Some(ticket)))),
+            ..self.clone()
+        })
+    }
+}
@@ -0,0 +1,297 @@
+use crate::protocol::{
+    cipher::{CipherKind, SessionKeys},
+    encryption::generate_secret_from_string,
+    handshake::{
+        attach_frame, rekey_attach_frame, service_frame, OutboundEvent, OutboundHandshake,
+        ATTACH_NONCE_LEN, NONCE_LEN, SALT_LEN,
+    },
+    machine::{AcceptEvent, Handshaken, InboundMachine, InboundOptions},
+};
+use anyhow::{anyhow, Result};
+use futures::executor::block_on;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+// Test vectors of the wire protocol, from the nonce to the first bytes of the session, for
+// other implementations and later versions of this one to check theirs against. The byte
+// strings are hex, the randomness of either side is given. Tickets, padding and obfuscation
+// aren't covered. See vectors/wire.json and the interop subcommand.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Vector {
+    pub name: String,
+    // Hashed with SHA-256 into the secret
+    pub passphrase: String,
+    pub version: u8,
+    pub cipher: u8,
+    // Sent by the inbound side
+    pub nonce: String,
+    // Sent with the offer from version 3 on, zeros before
+    pub salt: String,
+    // Sent with ATTACH by rekeyed inbound sides
+    pub attach_nonce: Option<String>,
+    pub service: Option<String>,
+    // Auth token of the outbound side, and its offer
+    pub hello: String,
+    // Announcement of the service, after the hello
+    pub announce: Option<String>,
+    // Sent by the inbound side once the tunnel is attached
+    pub attach: String,
+    pub plaintext: String,
+    // The plaintext as each side sends it in the session
+    pub inbound_wire: String,
+    pub outbound_wire: String,
+}
+
+const PASSPHRASE: &str = "veloxid test vectors";
+const PLAINTEXT: &[u8] = b"The quick brown fox jumps over the lazy dog, both ways.";
+
+// Consecutive bytes from start, the vectors' stand-in for randomness
+fn counting<const N: usize>(start: u8) -> [u8; N] {
+    std::array::from_fn(|i| start.wrapping_add(i as u8))
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn unhex(hex: &str) -> Result<Vec<u8>> {
+    let invalid = || anyhow!("Invalid hex '{}'", hex);
+    if !hex.len().is_multiple_of(2) {
+        return Err(invalid());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(invalid)
+        })
+        .collect()
+}
+
+fn unhex_array<const N: usize>(hex: &str) -> Result<[u8; N]> {
+    unhex(hex)?
+        .try_into()
+        .map_err(|_| anyhow!("'{}' isn't {} bytes", hex, N))
+}
+
+// The plaintext as sent by one side of the session
+pub fn seal(keys: &SessionKeys, plaintext: &[u8]) -> Result<Vec<u8>> {
+    let mut wire = plaintext.to_vec();
+    if let Some(mut keystream) = keys.write_keystream() {
+        keystream.apply_keystream(&mut wire);
+        return Ok(wire);
+    }
+    let Ok(mut sealed) = keys.seal(Vec::new()) else {
+        return Err(anyhow!(
+            "{:?} is neither a stream nor an AEAD cipher",
+            keys.cipher
+        ));
+    };
+    block_on(async {
+        sealed.write_all(plaintext).await?;
+        sealed.flush().await
+    })?;
+    Ok(sealed.into_inner())
+}
+
+// The plaintext of what the peer sent in the session
+pub fn open(keys: &SessionKeys, wire: &[u8]) -> Result<Vec<u8>> {
+    let mut plaintext = wire.to_vec();
+    if let Some(mut keystream) = keys.read_keystream() {
+        keystream.apply_keystream(&mut plaintext);
+        return Ok(plaintext);
+    }
+    let Ok(mut sealed) = keys.seal(wire) else {
+        return Err(anyhow!(
+            "{:?} is neither a stream nor an AEAD cipher",
+            keys.cipher
+        ));
+    };
+    plaintext.clear();
+    block_on(sealed.read_to_end(&mut plaintext))?;
+    Ok(plaintext)
+}
+
+impl Vector {
+    fn build(
+        name: &str,
+        version: u8,
+        cipher: CipherKind,
+        attach_nonce: Option<[u8; ATTACH_NONCE_LEN]>,
+        service: Option<&str>,
+    ) -> Result<Self> {
+        let secret = generate_secret_from_string(PASSPHRASE.to_owned());
+        let nonce: [u8; NONCE_LEN] = counting(0x00);
+        let salt: [u8; SALT_LEN] = match version {
+            3 => counting(0x40),
+            _ => [0u8; SALT_LEN],
+        };
+        let mut handshake = match version {
+            3 => OutboundHandshake::with_salt(secret, cipher.id(), salt),
+            _ => OutboundHandshake::with_version(secret, version),
+        };
+        let Some(OutboundEvent::SendAuth { auth, offer, .. }) = handshake.feed(&nonce) else {
+            return Err(anyhow!("'{}': no auth token for the nonce", name));
+        };
+        let mut hello = auth.to_vec();
+        hello.extend(offer.iter().flatten());
+        let attach = match attach_nonce {
+            Some(attach_nonce) => rekey_attach_frame(attach_nonce).to_vec(),
+            None => attach_frame(version).to_vec(),
+        };
+
+        let keys = |is_inbound| SessionKeys {
+            cipher,
+            secret,
+            nonce,
+            salt,
+            is_inbound,
+            attach: attach_nonce,
+            limits: Default::default(),
+        };
+        Ok(Self {
+            name: name.to_owned(),
+            passphrase: PASSPHRASE.to_owned(),
+            version,
+            cipher: cipher.id(),
+            nonce: hex(&nonce),
+            salt: hex(&salt),
+            attach_nonce: attach_nonce.map(|nonce| hex(&nonce)),
+            service: service.map(str::to_owned),
+            hello: hex(&hello),
+            announce: service.map(|service| hex(&service_frame(&secret, &nonce, service))),
+            attach: hex(&attach),
+            plaintext: hex(PLAINTEXT),
+            inbound_wire: hex(&seal(&keys(true), PLAINTEXT)?),
+            outbound_wire: hex(&seal(&keys(false), PLAINTEXT)?),
+        })
+    }
+
+    pub fn secret(&self) -> [u8; 32] {
+        generate_secret_from_string(self.passphrase.clone())
+    }
+
+    pub fn keys(&self, is_inbound: bool) -> Result<SessionKeys> {
+        Ok(SessionKeys {
+            cipher: CipherKind::from_id(self.cipher)
+                .ok_or(anyhow!("Unknown cipher {}", self.cipher))?,
+            secret: self.secret(),
+            nonce: unhex_array(&self.nonce)?,
+            salt: unhex_array(&self.salt)?,
+            is_inbound,
+            attach: self.attach_nonce.as_deref().map(unhex_array).transpose()?,
+            limits: Default::default(),
+        })
+    }
+
+    // Both sides of this implementation go through the vector: the inbound one reads the
+    // hello and the announcement, the outbound one writes them and reads ATTACH, each reads
+    // the other's session
+    pub fn check(&self) -> Result<()> {
+        let secret = self.secret();
+        let nonce: [u8; NONCE_LEN] = unhex_array(&self.nonce)?;
+        let mismatch = |what: &str| anyhow!("'{}': {} differs from the vector's", self.name, what);
+
+        let options = InboundOptions {
+            cipher: self.cipher,
+            tickets: None,
+            expect_service: self.service.is_some(),
+            silence: None,
+            totp: None,
+        };
+        let mut inbound = InboundMachine::new(secret, nonce, options);
+        if inbound.poll_transmit() != Some(nonce.to_vec()) {
+            return Err(mismatch("the nonce sent"));
+        }
+        let mut sent = unhex(&self.hello)?;
+        sent.extend(unhex(self.announce.as_deref().unwrap_or_default())?);
+        if inbound.handle_input(&sent) != sent.len() || inbound.wants() != 0 {
+            return Err(mismatch("the length of the hello"));
+        }
+        let expected = Handshaken {
+            nonce,
+            version: self.version,
+            salt: unhex_array(&self.salt)?,
+            attach_nonce: None,
+            service: self.service.clone(),
+        };
+        match (inbound.poll_event(), inbound.poll_event()) {
+            (
+                Some(AcceptEvent::Authenticated { resumed: false }),
+                Some(AcceptEvent::Done(done)),
+            ) if done == expected => {}
+            _ => return Err(mismatch("the handshake read")),
+        }
+
+        let mut outbound = match self.version {
+            3 => OutboundHandshake::with_salt(secret, self.cipher, expected.salt),
+            version => OutboundHandshake::with_version(secret, version),
+        };
+        let Some(OutboundEvent::SendAuth { auth, offer, .. }) = outbound.feed(&nonce) else {
+            return Err(mismatch("the nonce read"));
+        };
+        let mut hello = auth.to_vec();
+        hello.extend(offer.iter().flatten());
+        if hex(&hello) != self.hello {
+            return Err(mismatch("the hello sent"));
+        }
+        let announce = self
+            .service
+            .as_ref()
+            .map(|service| hex(&service_frame(&secret, &nonce, service)));
+        if announce != self.announce {
+            return Err(mismatch("the announcement sent"));
+        }
+        let attach_nonce = self.attach_nonce.as_deref().map(unhex_array).transpose()?;
+        if outbound.feed(&unhex(&self.attach)?) != Some(OutboundEvent::Accepted)
+            || outbound.attach_nonce() != attach_nonce
+        {
+            return Err(mismatch("the ATTACH read"));
+        }
+
+        let plaintext = unhex(&self.plaintext)?;
+        if open(&self.keys(false)?, &unhex(&self.inbound_wire)?)? != plaintext {
+            return Err(mismatch("the inbound side's session"));
+        }
+        if open(&self.keys(true)?, &unhex(&self.outbound_wire)?)? != plaintext {
+            return Err(mismatch("the outbound side's session"));
+        }
+        Ok(())
+    }
+}
+
+// The vectors of vectors/wire.json, a version of each, every cipher and the options changing
+// the wire
+pub fn generate() -> Result<Vec<Vector>> {
+    let rekeyed = Some(counting(0x80));
+    [
+        Vector::build("v1-chacha20", 1, CipherKind::ChaCha20, None, None),
+        Vector::build("v2-chacha20", 2, CipherKind::ChaCha20, None, None),
+        Vector::build(
+            "v2-chacha20-rekeyed",
+            2,
+            CipherKind::ChaCha20,
+            rekeyed,
+            None,
+        ),
+        Vector::build(
+            "v2-chacha20-service",
+            2,
+            CipherKind::ChaCha20,
+            None,
+            Some("ssh"),
+        ),
+        Vector::build("v3-xchacha20", 3, CipherKind::XChaCha20, None, None),
+        Vector::build("v3-aes-256-gcm", 3, CipherKind::Aes256Gcm, None, None),
+        Vector::build(
+            "v3-aes-256-gcm-rekeyed",
+            3,
+            CipherKind::Aes256Gcm,
+            rekeyed,
+            None,
+        ),
+    ]
+    .into_iter()
+    .collect()
+}
@@ -0,0 +1,267 @@
+use crate::{
+    relay::tunnel::{SessionOptions, Tunnel},
+    table::TableRequest,
+    transport::{listener, Stream},
+};
+use anyhow::{anyhow, Result};
+use log::{debug, info, warn};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+use tokio::{
+    io::{duplex, AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpSocket, TcpStream},
+    sync::{oneshot, Mutex},
+    task,
+    time::{sleep, timeout, Duration},
+};
+
+const LOG_TARGET: &str = "punch";
+
+// Time a connector waits on the control listener for an agent, the relay then answers without
+// one and the connector asks again
+pub const RENDEZVOUS_WAIT: Duration = Duration::from_secs(60);
+// Time an agent gets to hear where a connector is
+const ASK_TIMEOUT: Duration = Duration::from_secs(10);
+// Time both sides get to reach each other, the agent goes through the relay after that
+const PUNCH_TIMEOUT: Duration = Duration::from_secs(5);
+// Between the connection attempts of a punch, each of them opening the NAT's mapping again
+const ATTEMPT_INTERVAL: Duration = Duration::from_millis(200);
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(1);
+const MAX_OFFER_SIZE: usize = 1024;
+
+// What the relay tells each side of a punch
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Offer {
+    // Where the other side's connection to the relay came from, none when no connector waits
+    pub peer: Option<SocketAddr>,
+}
+
+// A connector's address, and where to send the agent's
+type Waiting = (SocketAddr, oneshot::Sender<SocketAddr>);
+
+// Connectors waiting on the control listener for the agents of the routes they punch for
+pub struct Broker {
+    routes: HashSet<String>,
+    waiting: Mutex<HashMap<String, VecDeque<Waiting>>>,
+}
+
+impl Broker {
+    pub fn new(routes: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            routes: routes.into_iter().collect(),
+            waiting: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Connector side, the address of the agent that came for the route, if one did in time
+    pub async fn wait(&self, route: &str, connector: SocketAddr) -> Result<Option<SocketAddr>> {
+        if !self.routes.contains(route) {
+            return Err(anyhow!("'{}' isn't punched through", route));
+        }
+        let (sender, agent) = oneshot::channel();
+        let mut waiting = self.waiting.lock().await;
+        waiting
+            .entry(route.to_owned())
+            .or_default()
+            .push_back((connector, sender));
+        drop(waiting);
+        Ok(timeout(RENDEZVOUS_WAIT, agent)
+            .await
+            .ok()
+            .and_then(Result::ok))
+    }
+
+    // Agent side, hands the agent's address to a waiting connector and returns the connector's
+    pub async fn offer(&self, route: &str, agent: SocketAddr) -> Option<SocketAddr> {
+        let mut waiting = self.waiting.lock().await;
+        let queue = waiting.get_mut(route)?;
+        // Connectors that gave up waiting dropped their end
+        while let Some((connector, sender)) = queue.pop_front() {
+            if sender.send(agent).is_ok() {
+                return Some(connector);
+            }
+        }
+        None
+    }
+}
+
+// Answers the agents of a route on its punch port with the address of a connector waiting for
+// them, until the listener fails
+pub async fn serve(
+    listener: TcpListener,
+    route: String,
+    secret: [u8; 32],
+    broker: Arc<Broker>,
+) -> Result<()> {
+    let addr = listener.local_addr()?;
+    info!(target: LOG_TARGET, "Brokering punches for '{}' on {}", route, addr);
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                listener::recover(e).await?;
+                continue;
+            }
+        };
+        let (route, broker) = (route.clone(), broker.clone());
+        task::spawn(async move {
+            let answer = timeout(ASK_TIMEOUT, answer(stream, peer, secret, &route, &broker));
+            match answer.await {
+                Ok(Ok(Offer {
+                    peer: Some(connector),
+                })) => {
+                    info!(target: LOG_TARGET, "Put {} in touch with {} for '{}'", peer, connector, route)
+                }
+                Ok(Ok(Offer { peer: None })) => {
+                    info!(target: LOG_TARGET, "No connector waits for '{}', {} goes through the relay", route, peer)
+                }
+                Ok(Err(e)) => warn!(target: LOG_TARGET, "{}: {}", peer, e),
+                Err(_) => warn!(target: LOG_TARGET, "{}: timed out", peer),
+            }
+        });
+    }
+}
+
+// Tells an agent, over an inbound tunnel, where a connector waits
+pub async fn answer<S: Stream>(
+    stream: S,
+    peer: SocketAddr,
+    secret: [u8; 32],
+    route: &str,
+    broker: &Broker,
+) -> Result<Offer> {
+    let tunnel = Tunnel::init(stream, peer.ip(), true, secret).await?;
+    let offer = Offer {
+        peer: broker.offer(route, peer).await,
+    };
+    exchange(tunnel, &serde_json::to_vec(&offer)?).await?;
+    Ok(offer)
+}
+
+// Agent side, asks the relay's punch port where a connector waits. Returns the local address
+// the relay saw the agent at, for the punch to come from.
+pub async fn ask(relay: SocketAddr, secret: [u8; 32]) -> Result<(SocketAddr, Offer)> {
+    timeout(ASK_TIMEOUT, async {
+        let stream = socket(unspecified(relay))?.connect(relay).await?;
+        let local = stream.local_addr()?;
+        let tunnel = Tunnel::init(stream, relay.ip(), false, secret).await?;
+        Ok((
+            local,
+            serde_json::from_slice(&exchange(tunnel, b"").await?)?,
+        ))
+    })
+    .await
+    .map_err(|_| anyhow!("The relay didn't answer within {:?}", ASK_TIMEOUT))?
+}
+
+// Connector side, waits on the relay's control listener for an agent of the route. Returns the
+// local address the relay saw the connector at, for the punch to come from.
+pub async fn rendezvous(
+    control: SocketAddr,
+    secret: [u8; 32],
+    route: &str,
+) -> Result<(SocketAddr, Offer)> {
+    let request = TableRequest {
+        punch: Some(route.to_owned()),
+        ..Default::default()
+    };
+    timeout(RENDEZVOUS_WAIT + ASK_TIMEOUT, async {
+        let stream = socket(unspecified(control))?.connect(control).await?;
+        let local = stream.local_addr()?;
+        let tunnel = Tunnel::init(stream, control.ip(), false, secret).await?;
+        let offer = exchange(tunnel, &serde_json::to_vec(&request)?).await?;
+        Ok((local, serde_json::from_slice(&offer)?))
+    })
+    .await
+    .map_err(|_| anyhow!("The relay didn't answer within {:?}", RENDEZVOUS_WAIT))?
+}
+
+// Sends a message over the tunnel and reads the peer's, each side's ending with its write side
+pub async fn exchange<S: Stream>(tunnel: Tunnel<S>, message: &[u8]) -> Result<Vec<u8>> {
+    let (mut local, tunnel_side) = duplex(64 * 1024);
+    let session = task::spawn(tunnel.run(tunnel_side, SessionOptions::default()));
+
+    local.write_all(message).await?;
+    local.shutdown().await?;
+    let answer = read_limited(&mut local).await?;
+    drop(local);
+
+    session.await??;
+    Ok(answer)
+}
+
+async fn read_limited<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    reader
+        .take(MAX_OFFER_SIZE as u64 + 1)
+        .read_to_end(&mut data)
+        .await?;
+    if data.len() > MAX_OFFER_SIZE {
+        return Err(anyhow!("Punch offer is too large"));
+    }
+    Ok(data)
+}
+
+// Agent side, connects from the address the relay saw to the connector's until it gets through.
+// The connector's attempts open its NAT to the agent's in the meantime.
+pub async fn dial(local: SocketAddr, peer: SocketAddr) -> Result<TcpStream> {
+    timeout(PUNCH_TIMEOUT, async {
+        loop {
+            match timeout(ATTEMPT_TIMEOUT, socket(local)?.connect(peer)).await {
+                Ok(Ok(stream)) => return Ok::<_, io::Error>(stream),
+                Ok(Err(e)) => debug!(target: LOG_TARGET, "Attempt to reach {} failed: {}", peer, e),
+                Err(_) => debug!(target: LOG_TARGET, "Attempt to reach {} timed out", peer),
+            }
+            sleep(ATTEMPT_INTERVAL).await;
+        }
+    })
+    .await
+    .map_err(|_| anyhow!("Couldn't reach {} within {:?}", peer, PUNCH_TIMEOUT))?
+    .map_err(Into::into)
+}
+
+// Connector side, takes the agent's connection on the address the relay saw, or connects to it
+// when both NATs let the SYNs cross
+pub async fn meet(local: SocketAddr, peer: SocketAddr) -> Result<TcpStream> {
+    let listener = socket(local)?.listen(16)?;
+    let accept = async {
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            // The agent's NAT may give the punch another port, but not another address
+            if addr.ip() == peer.ip() {
+                return Ok::<_, io::Error>(stream);
+            }
+            debug!(target: LOG_TARGET, "Dropped {}, waiting for {}", addr, peer);
+        }
+    };
+    tokio::select! {
+        accepted = accept => Ok(accepted?),
+        dialed = dial(local, peer) => dialed,
+    }
+}
+
+// Sockets of a punch share the port of the connection to the relay
+fn socket(local: SocketAddr) -> io::Result<TcpSocket> {
+    let socket = match local {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    #[cfg(unix)]
+    socket.set_reuseport(true)?;
+    socket.bind(local)?;
+    Ok(socket)
+}
+
+fn unspecified(peer: SocketAddr) -> SocketAddr {
+    let ip = match peer {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    SocketAddr::new(ip, 0)
+}
@@ -0,0 +1,1845 @@
+#[cfg(feature = "tap")]
+use crate::tap::Tap;
+#[cfg(feature = "quic")]
+use crate::transport::quic::{self, QuicConnector, QuicQueue};
+#[cfg(feature = "tls")]
+use crate::transport::tls::{self, Origination, Termination, TlsStream};
+use crate::{
+    activation,
+    balance::Balancer,
+    budget::Budget,
+    clients::{ClientLimit, ClientSlot},
+    config::{
+        Affinity, BondMode, CipherKind, ConnectionType, Direction, Endpoint, ObfuscationMode,
+        PaddingMode, TransportKind,
+    },
+    detect::Accepted,
+    error::{ConfigError, StartupError, TunnelError},
+    events::{EventHandler, EventHandlers, SessionInfo, SessionStats},
+    keys::{KeyCommand, TunnelKey},
+    knock::{self, KnockListener, Knocks, DEFAULT_KNOCK_WINDOW},
+    latency::{LatencyHandle, LatencyTable},
+    protocol::{
+        cipher::RekeyLimits,
+        encryption::{generate_secret_from_string, Totp, DEFAULT_TOTP_SKEW},
+        handshake::{REASON_BANNED, REASON_OUTSIDE_SCHEDULE},
+        padding::Padding,
+        silent::Silence,
+        ticket::Tickets,
+    },
+    punch::{self, Offer},
+    relay::{
+        pool::Pool,
+        reconnect::retry_delay,
+        tunnel::{
+            HandshakeOptions, HandshakeTimeouts, Registration, SessionOptions, Traffic, Tunnel,
+        },
+    },
+    scaling::Scaling,
+    schedule::Schedule,
+    services::ServiceQueue,
+    sessions::SessionRegistry,
+    tarpit::Tarpit,
+    transport::{
+        bond::{self, BondQueue},
+        exec::Command,
+        listener::{self, BindOptions, Listener},
+        obfs::Obfuscation,
+        stdio::Stdio,
+        udp::{self, UdpFlow, UdpQueue},
+        Pipe, Stream, Transport,
+    },
+};
+use anyhow::{anyhow, Result};
+use chrono::Local;
+use dashmap::DashMap;
+use log::{debug, error, info, warn};
+#[cfg(target_os = "linux")]
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    collections::HashMap,
+    future::Future,
+    hash::{DefaultHasher, Hash, Hasher},
+    io,
+    net::{IpAddr, Ipv6Addr, SocketAddr, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
+#[cfg(target_os = "linux")]
+use tokio::net::TcpSocket;
+use tokio::{
+    net::TcpStream,
+    sync::{mpsc, watch, Mutex, Semaphore},
+    task,
+    time::{sleep, timeout, Duration, Instant},
+};
+
+pub const BAN_LENGTH: Duration = Duration::from_secs(60 * 5);
+// KiB/s of constant bitrate padding
+const DEFAULT_PADDING_RATE: u32 = 64;
+
+// Unique across all routes of the process
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+// Rejected peers being told why at once, across all routes. Past it they are dropped without
+// a word, rather than each pinning a socket and a task.
+const MAX_REJECTIONS: usize = 256;
+static REJECTIONS: Semaphore = Semaphore::const_new(MAX_REJECTIONS);
+
+#[derive(Clone)]
+pub enum ConnectionData {
+    Inbound {
+        listener: Arc<Listener>,
+        tunnel: Option<TunnelSettings>,
+        obfuscation: Option<Obfuscation>,
+        // Plain endpoints only, connections open at once from each client
+        clients: Option<ClientLimit>,
+        // Tunnels only, peers are let in for a while once they knock on it
+        knocks: Option<Arc<KnockListener>>,
+        // Direct and auto endpoints only, the TLS of their (TLS) clients is terminated
+        #[cfg(feature = "tls")]
+        termination: Option<Termination>,
+    },
+    Outbound {
+        targets: Targets,
+        tunnel: Option<TunnelSettings>,
+        obfuscation: Option<Obfuscation>,
+        // Plain endpoints only, connections opened ahead of the sessions
+        pool: Option<Pool>,
+        // Tunnels sending their first bytes in the SYN, Linux only
+        fast_open: bool,
+        // Tunnels only, the port of the targets to knock on before connecting
+        knock_port: Option<u16>,
+        // Address the knocks are from, when not the one they are sent from
+        knock_source: Option<IpAddr>,
+        // Plain endpoints only, TLS opened to the targets
+        #[cfg(feature = "tls")]
+        origination: Option<Origination>,
+    },
+    // Connections handed over by the dispatcher of an auto endpoint
+    Dispatched {
+        queue: Arc<Mutex<mpsc::Receiver<Accepted>>>,
+        // The auto endpoint's, shared by its routes
+        clients: Option<ClientLimit>,
+    },
+    // Tunnels opened through the ones of another outbound endpoint, whose relay takes them on
+    Chained {
+        via: Box<ConnectionData>,
+        via_name: String,
+        tunnel: TunnelSettings,
+    },
+    // Tunnels of agents punching through, the relay's control listener telling where they are
+    Rendezvous {
+        control: SocketAddr,
+        // Secret of the relay, the tunnel's is the end-to-end one
+        relay: [u8; 32],
+        route: String,
+        tunnel: TunnelSettings,
+    },
+    // Tunnels punched through to the connector the relay's punch port tells of, or opened
+    // through the relay when that fails
+    Punched {
+        broker: SocketAddr,
+        relay: [u8; 32],
+        relayed: Box<ConnectionData>,
+        tunnel: TunnelSettings,
+    },
+    // Tunnels registered for the route's service on a registry endpoint
+    Registered {
+        queue: ServiceQueue,
+    },
+    // Bonded tunnels, spread over several TCP connections
+    BondInbound {
+        queue: BondQueue,
+        tunnel: TunnelSettings,
+    },
+    BondOutbound {
+        paths: Vec<SocketAddr>,
+        mode: BondMode,
+        // Resumption grace period, zero if disabled
+        grace: Duration,
+        tunnel: TunnelSettings,
+    },
+    // Tunnel streams of the QUIC connections accepted on the endpoint
+    #[cfg(feature = "quic")]
+    QuicInbound {
+        queue: QuicQueue,
+        tunnel: TunnelSettings,
+    },
+    #[cfg(feature = "quic")]
+    QuicOutbound {
+        connector: Arc<QuicConnector>,
+        tunnel: TunnelSettings,
+    },
+    // The process's stdin and stdout, for the first session only
+    Stdio {
+        inbound: bool,
+    },
+    // A process run for each session
+    Exec {
+        command: Command,
+    },
+    // Flows of the clients of an inbound UDP endpoint, by their address
+    UdpInbound {
+        queue: UdpQueue,
+    },
+    UdpOutbound {
+        targets: Targets,
+        flow_timeout: Duration,
+    },
+}
+
+impl ConnectionData {
+    // The outbound tunnels of a route announcing its own service instead of the endpoint's,
+    // for routes sharing the endpoint to reach different backends through the same registry
+    pub fn announcing(&self, service: &str) -> ConnectionData {
+        let mut data = self.clone();
+        if let Some(tunnel) = data.outbound_tunnel() {
+            tunnel.registration = Some(Registration::Announce(service.to_owned()));
+        }
+        data
+    }
+
+    // Settings of the outbound tunnels, those of the innermost one when chained
+    fn outbound_tunnel(&mut self) -> Option<&mut TunnelSettings> {
+        match self {
+            ConnectionData::Outbound { tunnel, .. } => tunnel.as_mut(),
+            ConnectionData::BondOutbound { tunnel, .. } => Some(tunnel),
+            ConnectionData::Chained { tunnel, .. } => Some(tunnel),
+            ConnectionData::Punched { relayed, .. } => relayed.outbound_tunnel(),
+            #[cfg(feature = "quic")]
+            ConnectionData::QuicOutbound { tunnel, .. } => Some(tunnel),
+            _ => None,
+        }
+    }
+
+    // Whether its connections are of peers reaching this side, the ones bans are for
+    fn accepts(&self) -> bool {
+        match self {
+            ConnectionData::Inbound { .. }
+            | ConnectionData::Dispatched { .. }
+            | ConnectionData::Registered { .. }
+            | ConnectionData::BondInbound { .. }
+            | ConnectionData::UdpInbound { .. } => true,
+            #[cfg(feature = "quic")]
+            ConnectionData::QuicInbound { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+// Tunnel options of an endpoint, both sides must agree on them
+#[derive(Clone)]
+pub struct TunnelSettings {
+    pub key: TunnelKey,
+    pub cipher: CipherKind,
+    pub padding: Option<Padding>,
+    // Only for this side, the peer can use others
+    pub timeouts: HandshakeTimeouts,
+    // Secret of the far end of an end-to-end session, the peer only forwards it
+    pub end_to_end: Option<[u8; 32]>,
+    pub rekey: bool,
+    pub rekey_limits: RekeyLimits,
+    // Issued by inbound sides, held by outbound ones
+    pub tickets: Option<Tickets>,
+    // Inbound sides take silent hellos with it, outbound sides send one if it is set
+    pub silence: Option<Silence>,
+    // Mixed into the secret of each handshake
+    pub totp: Option<Totp>,
+    // Inbound sides only, between the pings of tunnels waiting to be attached
+    pub heartbeat: Option<Duration>,
+    // Service the tunnels announce, or expect their peers to announce on a registry
+    pub registration: Option<Registration>,
+}
+
+// Addresses of an outbound endpoint, shared by the workers of its routes
+#[derive(Clone)]
+pub struct Targets {
+    addrs: Arc<[SocketAddr]>,
+    next: Arc<AtomicUsize>,
+}
+
+impl Targets {
+    pub fn addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+
+    // Client is the peer on the other side of the session, if it is already connected
+    pub fn pick(&self, affinity: Affinity, client: Option<IpAddr>) -> SocketAddr {
+        let idx = match (affinity, client) {
+            (Affinity::SourceIp, Some(client)) => {
+                let mut hasher = DefaultHasher::new();
+                client.hash(&mut hasher);
+                hasher.finish() as usize
+            }
+            _ => self.next.fetch_add(1, Ordering::Relaxed),
+        };
+        self.addrs[idx % self.addrs.len()]
+    }
+}
+
+// Shared state and settings of a route, cloned into each of its workers
+#[derive(Clone)]
+pub struct RouteContext {
+    // Shared by every route
+    pub ban_list: Arc<DashMap<IpAddr, Instant>>,
+    pub events: EventHandlers,
+    pub registry: SessionRegistry,
+    pub latency: LatencyTable,
+    pub schedule: Option<Schedule>,
+    pub affinity: Affinity,
+    // Copy buffers of the sessions, the default one if unset
+    pub buffer_size: Option<usize>,
+    // See SessionOptions
+    pub write_timeout: Option<Duration>,
+    pub max_in_flight: Option<usize>,
+    // Tunnels are joined without decrypting them
+    pub blind: bool,
+    // Slots of the sessions running at once
+    pub sessions: Arc<Semaphore>,
+    // Sessions running longer are closed
+    pub max_session_duration: Option<Duration>,
+    // Sessions of every route together, shared out by priority
+    pub budget: Option<Budget>,
+    pub priority: u8,
+    // Spreads the clients over the connectors of the first endpoint
+    pub balancer: Option<Balancer>,
+    // Holds banned peers of the inbound tunnels instead of turning them away
+    pub tarpit: Option<Tarpit>,
+    // Workers of an autoscaled route
+    pub scaling: Option<Scaling>,
+    // Name of the route and of its endpoints A and B, for the sessions
+    pub name: String,
+    pub endpoints: [String; 2],
+    #[cfg(feature = "tap")]
+    pub tap: Option<Tap>,
+}
+
+impl RouteContext {
+    fn session_options(&self) -> SessionOptions {
+        SessionOptions {
+            #[cfg(feature = "tap")]
+            tap: self.tap.as_ref().map(Tap::session),
+            traffic: None,
+            buffer_size: self.buffer_size,
+            blind: self.blind,
+            write_timeout: self.write_timeout,
+            max_in_flight: self.max_in_flight,
+        }
+    }
+}
+
+pub enum Connection {
+    Tunnel(Box<Tunnel<Transport>>),
+    Direct(TcpStream),
+    // TLS terminated for a client or opened to a target
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream>),
+    // Stdin and stdout of the process or of one run for the session
+    Pipe(Box<Pipe>),
+    Udp(Box<UdpFlow>),
+}
+
+impl Connection {
+    fn take_latency(&mut self) -> Option<LatencyHandle> {
+        match self {
+            Connection::Tunnel(tunnel) => tunnel.take_latency(),
+            _ => None,
+        }
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Connection::Tunnel(tunnel) => tunnel.stream.peer_addr().ok(),
+            Connection::Direct(stream) => stream.peer_addr().ok(),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => stream.get_ref().peer_addr().ok(),
+            Connection::Pipe(_) => None,
+            Connection::Udp(flow) => Some(flow.peer_addr()),
+        }
+    }
+}
+
+// "host:port", IPv6 addresses like "::" go in brackets
+pub fn join_host_port(host: &str, port: u16) -> String {
+    match host.parse::<Ipv6Addr>() {
+        Ok(_) => format!("[{}]:{}", host, port),
+        Err(_) => format!("{}:{}", host, port),
+    }
+}
+
+// Like get_connection_data, the tunnels of endpoints with a via being opened through the ones
+// of that endpoint, hop after hop
+pub async fn get_chained_connection_data(
+    name: &str,
+    endpoints: &HashMap<String, Endpoint>,
+) -> Result<ConnectionData> {
+    let endpoint = endpoints.get(name).ok_or(ConfigError::EndpointNotFound)?;
+    let data = get_connection_data(endpoint).await?;
+    let Some(via) = &endpoint.via else {
+        return Ok(data);
+    };
+    let ConnectionData::Outbound {
+        tunnel: Some(tunnel),
+        ..
+    } = data
+    else {
+        return Err(ConfigError::ViaNotTunnel.into());
+    };
+    let via_data = Box::pin(get_chained_connection_data(via, endpoints))
+        .await
+        .map_err(|e| e.context(format!("Endpoint '{}'", via)))?;
+    Ok(ConnectionData::Chained {
+        via: Box::new(via_data),
+        via_name: via.clone(),
+        tunnel,
+    })
+}
+
+// Gets endpoint and returns ConnectionData
+pub fn endpoint_addr(endpoint: &Endpoint) -> Result<SocketAddr> {
+    let addr_str = join_host_port(endpoint.host.as_deref().unwrap_or("0.0.0.0"), endpoint.port);
+    resolve(&addr_str)
+}
+
+fn resolve(addr: &str) -> Result<SocketAddr> {
+    match addr
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+    {
+        Some(a) => Ok(a),
+        None => Err(StartupError::Resolve(addr.to_owned()).into()),
+    }
+}
+
+pub async fn get_connection_data(endpoint: &Endpoint) -> Result<ConnectionData> {
+    if let Some(route) = &endpoint.rendezvous {
+        return Ok(ConnectionData::Rendezvous {
+            control: endpoint_addr(endpoint)?,
+            relay: relay_secret(endpoint)?,
+            route: route.clone(),
+            tunnel: punched_tunnel(endpoint)?,
+        });
+    }
+    let mut data = endpoint_data(endpoint).await?;
+    if let Some(port) = endpoint.punch_port {
+        let host = endpoint.host.as_deref().unwrap_or("0.0.0.0");
+        return Ok(ConnectionData::Punched {
+            broker: resolve(&join_host_port(host, port))?,
+            relay: relay_secret(endpoint)?,
+            relayed: Box::new(data),
+            tunnel: punched_tunnel(endpoint)?,
+        });
+    }
+    let Some(hops) = &endpoint.hops else {
+        return Ok(data);
+    };
+
+    // Onion layers, each relay's tunnel inside of the one to the relay before
+    let first = data.outbound_tunnel().ok_or(ConfigError::HopsNotTunnel)?;
+    first.cipher = CipherKind::ChaCha20Poly1305;
+    let registration = first.registration.take();
+    let end_to_end = first.end_to_end.take();
+    for (idx, secret) in hops.iter().enumerate() {
+        let tunnel = TunnelSettings {
+            key: TunnelKey::from(generate_secret_from_string(secret.clone())),
+            cipher: CipherKind::ChaCha20Poly1305,
+            padding: None,
+            timeouts: handshake_timeouts(endpoint),
+            end_to_end: None,
+            rekey: false,
+            rekey_limits: RekeyLimits::default(),
+            tickets: None,
+            silence: None,
+            totp: None,
+            heartbeat: None,
+            registration: None,
+        };
+        data = ConnectionData::Chained {
+            via: Box::new(data),
+            via_name: format!("hop #{}", idx + 1),
+            tunnel,
+        };
+    }
+    // The last relay is the one the service and the end-to-end session are for
+    if let Some(last) = data.outbound_tunnel() {
+        last.registration = registration;
+        last.end_to_end = end_to_end;
+    }
+    Ok(data)
+}
+
+fn relay_secret(endpoint: &Endpoint) -> Result<[u8; 32]> {
+    match &endpoint.secret {
+        Some(secret) => Ok(generate_secret_from_string(secret.to_owned())),
+        None => Err(ConfigError::NoSecret.into()),
+    }
+}
+
+// Tunnels punched through to the far end run with its secret, without the relay's layer
+fn punched_tunnel(endpoint: &Endpoint) -> Result<TunnelSettings> {
+    let secret = endpoint
+        .e2e_secret
+        .clone()
+        .ok_or(ConfigError::PunchNotEndToEnd)?;
+    Ok(TunnelSettings {
+        key: TunnelKey::from(generate_secret_from_string(secret)),
+        cipher: endpoint.cipher.unwrap_or_default(),
+        padding: None,
+        timeouts: handshake_timeouts(endpoint),
+        end_to_end: None,
+        rekey: endpoint.rekey.unwrap_or(false),
+        rekey_limits: rekey_limits(endpoint),
+        tickets: None,
+        silence: None,
+        totp: None,
+        heartbeat: None,
+        registration: None,
+    })
+}
+
+fn rekey_limits(endpoint: &Endpoint) -> RekeyLimits {
+    RekeyLimits {
+        bytes: endpoint.rekey_bytes,
+        interval: endpoint.rekey_interval.map(Duration::from_secs),
+    }
+}
+
+async fn endpoint_data(endpoint: &Endpoint) -> Result<ConnectionData> {
+    match endpoint.kind {
+        ConnectionType::Stdio => {
+            return Ok(ConnectionData::Stdio {
+                inbound: matches!(endpoint.direction, Direction::Inbound),
+            })
+        }
+        ConnectionType::Exec => {
+            let Direction::Outbound = endpoint.direction else {
+                return Err(ConfigError::ExecNotOutbound.into());
+            };
+            let argv = endpoint.command.as_deref().unwrap_or_default();
+            return Ok(ConnectionData::Exec {
+                command: Command::new(argv, endpoint.max_processes),
+            });
+        }
+        _ => {}
+    }
+    let addr = endpoint_addr(endpoint)?;
+
+    if matches!(endpoint.kind, ConnectionType::Udp) {
+        let flow_timeout = endpoint
+            .flow_timeout
+            .map_or(udp::DEFAULT_FLOW_TIMEOUT, Duration::from_secs);
+        return Ok(match endpoint.direction {
+            Direction::Outbound => ConnectionData::UdpOutbound {
+                targets: targets(endpoint, addr)?,
+                flow_timeout,
+            },
+            Direction::Inbound => ConnectionData::UdpInbound {
+                queue: udp::listen(addr, flow_timeout)
+                    .await
+                    .map_err(|e| StartupError::Bind(addr.to_string(), e))?,
+            },
+        });
+    }
+
+    let key_option = match endpoint.kind {
+        ConnectionType::Tunnel => match (&endpoint.secret, &endpoint.key_command) {
+            (Some(secret), _) => Some(TunnelKey::from(generate_secret_from_string(
+                secret.to_owned(),
+            ))),
+            (None, Some(command)) => Some(TunnelKey::Provider(Arc::new(KeyCommand::new(
+                command.clone(),
+            )))),
+            (None, None) => return Err(ConfigError::NoSecret.into()),
+        },
+        ConnectionType::Direct
+        | ConnectionType::Stdio
+        | ConnectionType::Exec
+        | ConnectionType::Udp => None,
+        ConnectionType::Auto => match endpoint.direction {
+            Direction::Inbound => None,
+            Direction::Outbound => return Err(ConfigError::AutoNotInbound.into()),
+        },
+        ConnectionType::Honeypot => match endpoint.direction {
+            Direction::Inbound => None,
+            Direction::Outbound => return Err(ConfigError::HoneypotNotInbound.into()),
+        },
+    };
+
+    let plain_outbound = matches!(endpoint.direction, Direction::Outbound)
+        && endpoint.transport.unwrap_or_default() == TransportKind::Tcp
+        && endpoint.bonding.is_none()
+        && endpoint.resume.is_none();
+    if endpoint.targets.is_some() && !plain_outbound {
+        return Err(ConfigError::TargetsNotOutbound.into());
+    }
+
+    if endpoint.padding.is_some() && key_option.is_none() {
+        return Err(ConfigError::PaddingNotTunnel.into());
+    }
+    let padding = endpoint.padding.map(|mode| match mode {
+        PaddingMode::Random => Padding::Random,
+        PaddingMode::Constant => Padding::Constant {
+            bytes_per_sec: endpoint.padding_rate.unwrap_or(DEFAULT_PADDING_RATE) * 1024,
+        },
+    });
+
+    if endpoint.cipher.is_some() && key_option.is_none() {
+        return Err(ConfigError::CipherNotTunnel.into());
+    }
+    let tunnel = key_option.map(|key| TunnelSettings {
+        // Keyed by the secret itself, refused along with a key command
+        tickets: endpoint
+            .tickets
+            .zip(key.secret())
+            .map(|(secs, secret)| Tickets::new(secret, Duration::from_secs(secs))),
+        silence: key
+            .secret()
+            .filter(|_| endpoint.silent.unwrap_or(false))
+            .map(Silence::new),
+        key,
+        cipher: endpoint.cipher.unwrap_or_default(),
+        padding,
+        timeouts: handshake_timeouts(endpoint),
+        end_to_end: endpoint.e2e_secret.clone().map(generate_secret_from_string),
+        rekey: endpoint.rekey.unwrap_or(false),
+        rekey_limits: rekey_limits(endpoint),
+        totp: endpoint.totp_seed.clone().map(|seed| {
+            let skew = endpoint.totp_skew.unwrap_or(DEFAULT_TOTP_SKEW);
+            Totp::new(generate_secret_from_string(seed), skew)
+        }),
+        heartbeat: endpoint.heartbeat.map(Duration::from_secs),
+        registration: match (endpoint.registry, &endpoint.service) {
+            (Some(true), _) => Some(Registration::Expect),
+            (_, Some(service)) => Some(Registration::Announce(service.clone())),
+            _ => None,
+        },
+    });
+
+    let obfuscation = match endpoint.obfuscation {
+        Some(ObfuscationMode::Tls) => {
+            if tunnel.is_none()
+                || endpoint.transport.unwrap_or_default() != TransportKind::Tcp
+                || endpoint.bonding.is_some()
+                || endpoint.resume.is_some()
+            {
+                return Err(ConfigError::ObfuscationNotTcpTunnel.into());
+            }
+            Some(Obfuscation {
+                server_name: endpoint.server_name.clone(),
+            })
+        }
+        None => None,
+    };
+
+    #[cfg(feature = "tls")]
+    let termination = match (&endpoint.tls_cert, &endpoint.tls_key) {
+        (Some(cert), Some(key)) => Some(Termination::load(cert.as_ref(), key.as_ref())?),
+        _ => None,
+    };
+    #[cfg(feature = "tls")]
+    let origination = match endpoint.tls {
+        Some(true) => {
+            let server_name = match (&endpoint.server_name, &endpoint.host) {
+                (Some(name), _) | (None, Some(name)) => name.as_str(),
+                (None, None) => "localhost",
+            };
+            let pin = endpoint
+                .pinned_sha256
+                .as_deref()
+                .and_then(crate::protocol::encryption::sha256_from_hex);
+            let ca = endpoint.tls_ca.as_deref().map(std::path::Path::new);
+            Some(match pin {
+                Some(pin) => Origination::pinned(server_name, pin)?,
+                None => Origination::new(server_name, ca)?,
+            })
+        }
+        _ => None,
+    };
+    #[cfg(not(feature = "tls"))]
+    if endpoint.tls_cert.is_some() || endpoint.tls == Some(true) {
+        return Err(ConfigError::TlsNotBuilt.into());
+    }
+
+    if endpoint.transport.unwrap_or_default() == TransportKind::Quic {
+        if endpoint.bonding.is_some() {
+            return Err(ConfigError::BondingNotTcpTunnel.into());
+        }
+        if endpoint.resume.is_some() {
+            return Err(ConfigError::ResumeNotTcpTunnel.into());
+        }
+        let Some(tunnel) = tunnel else {
+            return Err(ConfigError::QuicNotTunnel.into());
+        };
+        #[cfg(feature = "quic")]
+        {
+            let secret = *tunnel
+                .key
+                .secret()
+                .ok_or(ConfigError::KeyCommandNotSecret)?;
+            return Ok(match endpoint.direction {
+                Direction::Outbound => ConnectionData::QuicOutbound {
+                    connector: Arc::new(QuicConnector::new(addr, &secret)?),
+                    tunnel,
+                },
+                Direction::Inbound => ConnectionData::QuicInbound {
+                    queue: quic::listen(addr, &secret)?,
+                    tunnel,
+                },
+            });
+        }
+        #[cfg(not(feature = "quic"))]
+        {
+            let _ = tunnel;
+            return Err(ConfigError::QuicNotBuilt.into());
+        }
+    }
+
+    // Resumable tunnels run over a bond, with a single path unless bonding is set
+    if endpoint.bonding.is_some() || endpoint.resume.is_some() {
+        let Some(tunnel) = tunnel else {
+            return Err(match endpoint.bonding {
+                Some(_) => ConfigError::BondingNotTcpTunnel.into(),
+                None => ConfigError::ResumeNotTcpTunnel.into(),
+            });
+        };
+        let mode = endpoint.bonding.unwrap_or(BondMode::Stripe);
+        let grace = Duration::from_secs(endpoint.resume.unwrap_or(0));
+        return Ok(match endpoint.direction {
+            Direction::Outbound => ConnectionData::BondOutbound {
+                paths: match &endpoint.paths {
+                    Some(paths) => resolve_addrs(paths)?,
+                    None => vec![addr],
+                },
+                mode,
+                grace,
+                tunnel,
+            },
+            Direction::Inbound => ConnectionData::BondInbound {
+                queue: bond::listen(Arc::new(listener(endpoint, addr).await?), mode, grace),
+                tunnel,
+            },
+        });
+    }
+
+    Ok(match endpoint.direction {
+        Direction::Outbound => {
+            let targets = targets(endpoint, addr)?;
+            ConnectionData::Outbound {
+                pool: endpoint.pool.map(|size| Pool::start(targets.clone(), size)),
+                targets,
+                tunnel,
+                obfuscation,
+                fast_open: endpoint.fast_open.unwrap_or(false),
+                knock_port: endpoint.knock_port,
+                knock_source: endpoint.knock_source,
+                #[cfg(feature = "tls")]
+                origination,
+            }
+        }
+        Direction::Inbound => ConnectionData::Inbound {
+            listener: Arc::new(listener(endpoint, addr).await?),
+            knocks: match (endpoint.knock_port, &tunnel) {
+                (Some(port), Some(tunnel)) => Some(Arc::new(
+                    knock_listener(endpoint, addr, port, tunnel).await?,
+                )),
+                _ => None,
+            },
+            tunnel,
+            obfuscation,
+            clients: endpoint.max_conns_per_ip.map(ClientLimit::new),
+            #[cfg(feature = "tls")]
+            termination,
+        },
+    })
+}
+
+// The knock port of an inbound tunnel endpoint, on the address of its listener
+async fn knock_listener(
+    endpoint: &Endpoint,
+    addr: SocketAddr,
+    port: u16,
+    tunnel: &TunnelSettings,
+) -> Result<KnockListener> {
+    let window = endpoint.knock_window.unwrap_or(DEFAULT_KNOCK_WINDOW);
+    let secret = tunnel
+        .key
+        .secret()
+        .ok_or(ConfigError::KeyCommandNotSecret)?;
+    let knocks = Knocks::new(secret, Duration::from_secs(window));
+    let addr = SocketAddr::new(addr.ip(), port);
+    KnockListener::bind(addr, knocks)
+        .await
+        .map_err(|e| StartupError::Bind(format!("{} (knock port)", addr), e).into())
+}
+
+// The targets of an outbound endpoint, its own address unless it lists them
+fn targets(endpoint: &Endpoint, addr: SocketAddr) -> Result<Targets> {
+    Ok(Targets {
+        addrs: match &endpoint.targets {
+            Some(targets) if targets.is_empty() => return Err(anyhow!("No targets given!")),
+            Some(targets) => resolve_addrs(targets)?.into(),
+            None => Arc::new([addr]),
+        },
+        next: Arc::new(AtomicUsize::new(0)),
+    })
+}
+
+async fn listener(endpoint: &Endpoint, addr: SocketAddr) -> Result<Listener> {
+    let options = BindOptions {
+        ipv6_only: endpoint.ipv6_only,
+        fast_open: endpoint.fast_open.unwrap_or(false),
+    };
+    // Sockets passed by systemd come with the options of the socket unit
+    if let Some(socket) = activation::sockets().take(addr) {
+        let bound = socket.local_addr().unwrap_or(addr);
+        info!(target: "activation", "Took the socket passed for {}", bound);
+        return Listener::from_std(socket, addr, options)
+            .map_err(|e| StartupError::Bind(addr.to_string(), e).into());
+    }
+    let bound = match endpoint.bind_retry {
+        Some(secs) => Listener::bind_retrying(addr, options, Duration::from_secs(secs)).await,
+        None => Listener::bind_with(addr, options).await,
+    };
+    bound.map_err(|e| StartupError::Bind(addr.to_string(), e).into())
+}
+
+// TCP Fast Open, the SYN carries the first bytes written if the peer handed out a cookie
+// before. Connecting waits for that write, the stream has to be written to first.
+#[cfg(target_os = "linux")]
+async fn connect_fast_open(addr: SocketAddr) -> io::Result<TcpStream> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    listener::set_tcp_option(&socket, libc::TCP_FASTOPEN_CONNECT, 1)?;
+    socket.set_nonblocking(true)?;
+    TcpSocket::from_std_stream(socket.into())
+        .connect(addr)
+        .await
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn connect_fast_open(addr: SocketAddr) -> io::Result<TcpStream> {
+    TcpStream::connect(addr).await
+}
+
+fn handshake_timeouts(endpoint: &Endpoint) -> HandshakeTimeouts {
+    let defaults = HandshakeTimeouts::default();
+    let secs = |value: Option<u64>, default| value.map_or(default, Duration::from_secs);
+    HandshakeTimeouts {
+        auth: secs(endpoint.auth_timeout, defaults.auth),
+        nonce: secs(endpoint.nonce_timeout, defaults.nonce),
+        total: secs(endpoint.handshake_timeout, defaults.total),
+    }
+}
+
+fn resolve_addrs(addrs: &[String]) -> Result<Vec<SocketAddr>> {
+    addrs.iter().map(|addr| resolve(addr)).collect()
+}
+
+// A stream of a plain endpoint, in TLS terminated for its clients or opened to its targets
+async fn plain(data: &ConnectionData, stream: TcpStream) -> Result<Connection> {
+    #[cfg(feature = "tls")]
+    match data {
+        ConnectionData::Inbound {
+            termination: Some(termination),
+            ..
+        } => {
+            let stream = tls::accept(&termination.acceptor(&[]), stream).await?;
+            return Ok(Connection::Tls(Box::new(stream)));
+        }
+        ConnectionData::Outbound {
+            origination: Some(origination),
+            ..
+        } => {
+            return Ok(Connection::Tls(Box::new(
+                origination.connect(stream).await?,
+            )))
+        }
+        _ => {}
+    }
+    #[cfg(not(feature = "tls"))]
+    let _ = data;
+    Ok(Connection::Direct(stream))
+}
+
+// Gets ConnectionData and returns Connection
+pub async fn connect(
+    data: &ConnectionData,
+    ctx: &RouteContext,
+    client: Option<IpAddr>,
+    log_target: &str,
+    endpoint_name: &str,
+) -> Result<Connection> {
+    Ok(match &data {
+        ConnectionData::Inbound {
+            listener,
+            tunnel,
+            obfuscation,
+            knocks,
+            ..
+        } => {
+            info!(target: log_target, "Listening for '{}'", endpoint_name);
+
+            let (stream, addr) = listener.accept().await?;
+            // Closed before a byte is read or sent
+            if let Some(knocks) = knocks {
+                if !knocks.knocks().allows(addr.ip()) {
+                    return Err(TunnelError::NotKnocked(addr.ip()).into());
+                }
+            }
+
+            let conn = match tunnel {
+                Some(tunnel) => {
+                    within_handshake(tunnel, addr.ip(), async {
+                        let stream = match obfuscation {
+                            Some(obfuscation) => {
+                                Transport::Obfuscated(obfuscation.accept(stream).await?)
+                            }
+                            None => Transport::Tcp(stream),
+                        };
+                        let stream = admit(ctx, tunnel, stream, addr.ip())?;
+                        init_tunnel(
+                            stream,
+                            addr.ip(),
+                            true,
+                            tunnel,
+                            ctx,
+                            log_target,
+                            endpoint_name,
+                        )
+                        .await
+                    })
+                    .await?
+                }
+                None => {
+                    check_schedule(ctx, addr.ip())?;
+                    plain(data, stream).await?
+                }
+            };
+
+            debug!(target: log_target, "Connection from '{}'", endpoint_name);
+            conn
+        }
+        ConnectionData::Dispatched { queue, .. } => {
+            info!(target: log_target, "Waiting for '{}'", endpoint_name);
+
+            let (conn, addr) = queue
+                .lock()
+                .await
+                .recv()
+                .await
+                .ok_or(anyhow!("Dispatcher is gone"))?;
+            check_schedule(ctx, addr.ip())?;
+
+            debug!(target: log_target, "Connection from '{}'", endpoint_name);
+            conn
+        }
+        ConnectionData::Chained {
+            via,
+            via_name,
+            tunnel,
+        } => {
+            info!(target: log_target, "Connecting to '{}' through '{}'", endpoint_name, via_name);
+
+            let outer = Box::pin(connect(via, ctx, client, log_target, via_name)).await?;
+            let Connection::Tunnel(outer) = outer else {
+                return Err(ConfigError::ViaNotTunnel.into());
+            };
+            let addr = outer.stream.peer_addr()?;
+            // Carrying the chained tunnel's ciphertext, which is neither tapped nor joined blind
+            let options = SessionOptions {
+                buffer_size: ctx.buffer_size,
+                write_timeout: ctx.write_timeout,
+                max_in_flight: ctx.max_in_flight,
+                ..Default::default()
+            };
+            let stream = Transport::Chained(outer.nested(addr, options));
+
+            let conn = init_tunnel(
+                stream,
+                addr.ip(),
+                false,
+                tunnel,
+                ctx,
+                log_target,
+                endpoint_name,
+            )
+            .await?;
+
+            debug!(target: log_target, "Connected to '{}' through '{}'", endpoint_name, via_name);
+            conn
+        }
+        ConnectionData::Rendezvous {
+            control,
+            relay,
+            route,
+            tunnel,
+        } => {
+            info!(target: log_target, "Waiting for '{}' at the relay", endpoint_name);
+
+            // The relay answers without an agent now and then, and is asked again
+            let (local, peer) = loop {
+                if let (local, Offer { peer: Some(peer) }) =
+                    punch::rendezvous(*control, *relay, route).await?
+                {
+                    break (local, peer);
+                }
+            };
+            debug!(target: log_target, "Punching through to {} for '{}'", peer, endpoint_name);
+            let stream = punch::meet(local, peer).await?;
+
+            let conn = within_handshake(tunnel, peer.ip(), async {
+                let stream = admit(ctx, tunnel, Transport::Tcp(stream), peer.ip())?;
+                init_tunnel(
+                    stream,
+                    peer.ip(),
+                    true,
+                    tunnel,
+                    ctx,
+                    log_target,
+                    endpoint_name,
+                )
+                .await
+            })
+            .await?;
+
+            debug!(target: log_target, "Connection from '{}'", endpoint_name);
+            conn
+        }
+        ConnectionData::Punched {
+            broker,
+            relay,
+            relayed,
+            tunnel,
+        } => {
+            info!(target: log_target, "Punching through to '{}'", endpoint_name);
+
+            match punched(*broker, *relay, tunnel, ctx, log_target, endpoint_name).await {
+                Ok(conn) => {
+                    debug!(target: log_target, "Punched through to '{}'", endpoint_name);
+                    conn
+                }
+                Err(e) => {
+                    info!(target: log_target, "Going through the relay for '{}': {}", endpoint_name, e);
+                    Box::pin(connect(relayed, ctx, client, log_target, endpoint_name)).await?
+                }
+            }
+        }
+        ConnectionData::Registered { queue } => {
+            info!(target: log_target, "Waiting for '{}'", endpoint_name);
+
+            let tunnel = queue
+                .lock()
+                .await
+                .recv()
+                .await
+                .ok_or(anyhow!("Registry is gone"))?;
+            let addr = tunnel.stream.peer_addr()?;
+            if let Err(e) = check_schedule(ctx, addr.ip()) {
+                task::spawn(tunnel.turn_away(REASON_OUTSIDE_SCHEDULE));
+                return Err(e);
+            }
+
+            debug!(target: log_target, "Connection from '{}'", endpoint_name);
+            Connection::Tunnel(tunnel)
+        }
+        ConnectionData::BondInbound { queue, tunnel } => {
+            info!(target: log_target, "Waiting for '{}'", endpoint_name);
+
+            let stream = queue
+                .lock()
+                .await
+                .recv()
+                .await
+                .ok_or(anyhow!("Bond listener is gone"))?;
+            let addr = stream.peer_addr();
+            let stream = admit(ctx, tunnel, Transport::Bonded(stream), addr.ip())?;
+            let conn = within_handshake(
+                tunnel,
+                addr.ip(),
+                init_tunnel(
+                    stream,
+                    addr.ip(),
+                    true,
+                    tunnel,
+                    ctx,
+                    log_target,
+                    endpoint_name,
+                ),
+            )
+            .await?;
+
+            debug!(target: log_target, "Connection from '{}'", endpoint_name);
+            conn
+        }
+        ConnectionData::BondOutbound {
+            paths,
+            mode,
+            grace,
+            tunnel,
+        } => {
+            info!(target: log_target, "Connecting to '{}'", endpoint_name);
+
+            let stream = bond::connect(paths, *mode, *grace).await?;
+            let addr = stream.peer_addr();
+
+            let stream = Transport::Bonded(stream);
+            let conn = init_tunnel(
+                stream,
+                addr.ip(),
+                false,
+                tunnel,
+                ctx,
+                log_target,
+                endpoint_name,
+            )
+            .await?;
+
+            debug!(target: log_target, "Connected to '{}'", endpoint_name);
+            conn
+        }
+        #[cfg(feature = "quic")]
+        ConnectionData::QuicInbound { queue, tunnel } => {
+            info!(target: log_target, "Waiting for '{}'", endpoint_name);
+
+            let stream = queue
+                .lock()
+                .await
+                .recv()
+                .await
+                .ok_or(anyhow!("QUIC endpoint is gone"))?;
+            let addr = stream.peer_addr();
+            let stream = admit(ctx, tunnel, Transport::Quic(stream), addr.ip())?;
+            let conn = within_handshake(
+                tunnel,
+                addr.ip(),
+                init_tunnel(
+                    stream,
+                    addr.ip(),
+                    true,
+                    tunnel,
+                    ctx,
+                    log_target,
+                    endpoint_name,
+                ),
+            )
+            .await?;
+
+            debug!(target: log_target, "Connection from '{}'", endpoint_name);
+            conn
+        }
+        #[cfg(feature = "quic")]
+        ConnectionData::QuicOutbound { connector, tunnel } => {
+            info!(target: log_target, "Connecting to '{}'", endpoint_name);
+
+            let stream = Transport::Quic(connector.open().await?);
+            let addr = connector.addr();
+
+            let conn = init_tunnel(
+                stream,
+                addr.ip(),
+                false,
+                tunnel,
+                ctx,
+                log_target,
+                endpoint_name,
+            )
+            .await?;
+
+            debug!(target: log_target, "Connected to '{}'", endpoint_name);
+            conn
+        }
+        ConnectionData::Outbound {
+            targets,
+            tunnel,
+            obfuscation,
+            pool,
+            fast_open,
+            knock_port,
+            knock_source,
+            ..
+        } => {
+            // Clients sticking to a target connect to it themselves
+            let pooled = match (pool, ctx.affinity, client) {
+                (_, Affinity::SourceIp, Some(_)) => None,
+                (pool, _, _) => pool.as_ref().and_then(Pool::take),
+            };
+            if let Some(stream) = pooled {
+                debug!(target: log_target, "Took a pooled connection to '{}'", endpoint_name);
+                return plain(data, stream).await;
+            }
+
+            let addr = targets.pick(ctx.affinity, client);
+            info!(target: log_target, "Connecting to '{}' at {}", endpoint_name, addr);
+
+            // Fast Open puts connecting off until the first write, only tunnels sure to speak
+            // first get it: obfuscated ones with their ClientHello, silent ones with their
+            // hello, or ones holding a ticket
+            let silent = tunnel
+                .as_ref()
+                .is_some_and(|tunnel| tunnel.silence.is_some());
+            let reserved = match (fast_open, tunnel) {
+                (true, Some(tunnel)) if obfuscation.is_none() && !silent => {
+                    tunnel.tickets.as_ref().and_then(Tickets::reserve)
+                }
+                _ => None,
+            };
+            let speaks_first = obfuscation.is_some() || silent || reserved.is_some();
+            if let (Some(port), Some(tunnel)) = (knock_port, tunnel) {
+                let secret = tunnel
+                    .key
+                    .secret()
+                    .ok_or(ConfigError::KeyCommandNotSecret)?;
+                let knock_addr = SocketAddr::new(addr.ip(), *port);
+                knock::send(knock_addr, secret, *knock_source).await?;
+            }
+            let stream = match *fast_open && speaks_first {
+                true => connect_fast_open(addr).await?,
+                false => TcpStream::connect(addr).await?,
+            };
+
+            let conn = match tunnel {
+                Some(tunnel) => {
+                    let tunnel = &TunnelSettings {
+                        tickets: reserved.or_else(|| tunnel.tickets.clone()),
+                        ..tunnel.clone()
+                    };
+                    let stream = match obfuscation {
+                        Some(obfuscation) => {
+                            Transport::Obfuscated(obfuscation.connect(stream).await?)
+                        }
+                        None => Transport::Tcp(stream),
+                    };
+                    init_tunnel(
+                        stream,
+                        addr.ip(),
+                        false,
+                        tunnel,
+                        ctx,
+                        log_target,
+                        endpoint_name,
+                    )
+                    .await?
+                }
+                None => plain(data, stream).await?,
+            };
+
+            debug!(target: log_target, "Connected to '{}'", endpoint_name);
+            conn
+        }
+        ConnectionData::Stdio { inbound: true } => {
+            info!(target: log_target, "Waiting for '{}'", endpoint_name);
+
+            // The other workers of the route wait for good
+            let Some(stdio) = Stdio::take() else {
+                return std::future::pending().await;
+            };
+
+            debug!(target: log_target, "Connection from '{}'", endpoint_name);
+            Connection::Pipe(Box::new(Pipe::Stdio(stdio)))
+        }
+        ConnectionData::Stdio { inbound: false } => {
+            let stdio = Stdio::take().ok_or(anyhow!("Stdin and stdout are taken already"))?;
+
+            debug!(target: log_target, "Connected to '{}'", endpoint_name);
+            Connection::Pipe(Box::new(Pipe::Stdio(stdio)))
+        }
+        ConnectionData::Exec { command } => {
+            let process = command.spawn(log_target)?;
+
+            debug!(target: log_target, "Started a process for '{}'", endpoint_name);
+            Connection::Pipe(Box::new(Pipe::Process(process)))
+        }
+        ConnectionData::UdpInbound { queue } => {
+            info!(target: log_target, "Waiting for '{}'", endpoint_name);
+
+            let flow = queue
+                .lock()
+                .await
+                .recv()
+                .await
+                .ok_or(anyhow!("UDP endpoint is gone"))?;
+            check_schedule(ctx, flow.peer_addr().ip())?;
+
+            debug!(target: log_target, "Flow from '{}'", endpoint_name);
+            Connection::Udp(Box::new(flow))
+        }
+        ConnectionData::UdpOutbound {
+            targets,
+            flow_timeout,
+        } => {
+            let addr = targets.pick(ctx.affinity, client);
+            info!(target: log_target, "Connecting to '{}' at {}", endpoint_name, addr);
+
+            let flow = UdpFlow::connect(addr, *flow_timeout).await?;
+
+            debug!(target: log_target, "Connected to '{}'", endpoint_name);
+            Connection::Udp(Box::new(flow))
+        }
+    })
+}
+
+// A tunnel straight to the connector waiting on the relay, from the address the relay saw
+async fn punched(
+    broker: SocketAddr,
+    relay: [u8; 32],
+    tunnel: &TunnelSettings,
+    ctx: &RouteContext,
+    log_target: &str,
+    endpoint_name: &str,
+) -> Result<Connection> {
+    let (local, offer) = punch::ask(broker, relay).await?;
+    let peer = offer.peer.ok_or(anyhow!("no connector is waiting"))?;
+    let stream = punch::dial(local, peer).await?;
+    init_tunnel(
+        Transport::Tcp(stream),
+        peer.ip(),
+        false,
+        tunnel,
+        ctx,
+        log_target,
+        endpoint_name,
+    )
+    .await
+}
+
+// Run the tunnel handshake and report its result to the event handlers
+async fn init_tunnel(
+    stream: Transport,
+    peer: IpAddr,
+    is_inbound: bool,
+    settings: &TunnelSettings,
+    ctx: &RouteContext,
+    log_target: &str,
+    endpoint_name: &str,
+) -> Result<Connection> {
+    debug!(target: log_target, "Initializing the tunnel");
+    let (key, cipher, timeouts) = (settings.key.clone(), settings.cipher, settings.timeouts);
+    let options = HandshakeOptions {
+        tickets: settings.tickets.as_ref(),
+        registration: settings.registration.as_ref(),
+        silence: settings.silence.as_ref(),
+        totp: settings.totp.as_ref(),
+    };
+    let tunnel =
+        Tunnel::init_with_options(stream, peer, is_inbound, key, cipher, timeouts, options).await;
+    let tunnel = tunnel
+        .map(|tunnel| match settings.padding {
+            Some(padding) => tunnel.padding(padding),
+            None => tunnel,
+        })
+        .map(|tunnel| match settings.end_to_end {
+            Some(secret) => tunnel.end_to_end(secret),
+            None => tunnel,
+        })
+        .map(|tunnel| match settings.rekey {
+            true => tunnel.rekey(),
+            false => tunnel,
+        })
+        .map(|tunnel| tunnel.rekey_limits(settings.rekey_limits))
+        .map(|tunnel| match settings.heartbeat {
+            Some(interval) => tunnel.heartbeat(interval),
+            None => tunnel,
+        })
+        .map(|tunnel| match is_inbound {
+            true => {
+                let addr = tunnel.stream.peer_addr().ok();
+                tunnel.latency(ctx.latency.track(log_target, endpoint_name, addr))
+            }
+            false => tunnel,
+        });
+    Ok(Connection::Tunnel(Box::new(report_handshake(
+        tunnel,
+        &ctx.events,
+        log_target,
+        endpoint_name,
+        peer,
+    )?)))
+}
+
+// Everything an inbound tunnel does before it is authenticated, bounded so peers trickling
+// their bytes can't hold on to the worker. Running out of time gets them banned.
+async fn within_handshake<T>(
+    settings: &TunnelSettings,
+    peer: IpAddr,
+    handshake: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    match timeout(settings.timeouts.total, handshake).await {
+        Ok(result) => result,
+        Err(_) => Err(TunnelError::Timeout(peer).into()),
+    }
+}
+
+// Schedule and ban checks of an inbound tunnel, rejected peers are told why in the background
+// while there is room for them. Banned ones go to the tarpit while it has room. Silent
+// endpoints send nothing to either.
+fn admit(
+    ctx: &RouteContext,
+    tunnel: &TunnelSettings,
+    stream: Transport,
+    peer: IpAddr,
+) -> Result<Transport> {
+    let (error, reason) = match (check_schedule(ctx, peer), check_ban(ctx, peer)) {
+        (Err(e), _) => (e, REASON_OUTSIDE_SCHEDULE),
+        (_, Err(e)) => (e, REASON_BANNED),
+        _ => return Ok(stream),
+    };
+    if tunnel.silence.is_some() {
+        return Err(error);
+    }
+    let tarpit = ctx.tarpit.as_ref().filter(|_| reason == REASON_BANNED);
+    match tarpit.and_then(|tarpit| Some((tarpit, tarpit.slot()?))) {
+        Some((tarpit, slot)) => {
+            task::spawn(tarpit.clone().hold(stream, peer, slot));
+        }
+        None => {
+            if let Ok(slot) = REJECTIONS.try_acquire() {
+                let timeouts = tunnel.timeouts;
+                task::spawn(async move {
+                    let _slot = slot;
+                    Tunnel::reject(stream, reason, timeouts).await
+                });
+            }
+        }
+    }
+    Err(error)
+}
+
+fn check_schedule(ctx: &RouteContext, peer: IpAddr) -> Result<()> {
+    match &ctx.schedule {
+        Some(schedule) if !schedule.is_open(&Local::now()) => {
+            Err(TunnelError::OutsideSchedule(peer).into())
+        }
+        _ => Ok(()),
+    }
+}
+
+// One of the client's connections, on endpoints limiting them. Clients with as many open
+// already are turned away right after being accepted.
+fn client_slot(data: &ConnectionData, conn: &Connection) -> Result<Option<ClientSlot>> {
+    let (ConnectionData::Inbound {
+        clients: Some(limit),
+        ..
+    }
+    | ConnectionData::Dispatched {
+        clients: Some(limit),
+        ..
+    }) = data
+    else {
+        return Ok(None);
+    };
+    let Some(client) = conn.peer_addr().map(|addr| addr.ip()) else {
+        return Ok(None);
+    };
+    match limit.acquire(client) {
+        Some(slot) => Ok(Some(slot)),
+        None => Err(TunnelError::TooManyConnections(client).into()),
+    }
+}
+
+fn check_ban(ctx: &RouteContext, peer: IpAddr) -> Result<()> {
+    match ctx.ban_list.get(&peer) {
+        Some(time) if *time > Instant::now() => Err(TunnelError::ConnAttemptFromBannedIP.into()),
+        _ => Ok(()),
+    }
+}
+
+// Pass the result of a tunnel handshake to the event handlers
+fn report_handshake<T>(
+    result: Result<T>,
+    events: &EventHandlers,
+    log_target: &str,
+    endpoint_name: &str,
+    peer: IpAddr,
+) -> Result<T> {
+    match &result {
+        Ok(_) => events.on_handshake_success(log_target, endpoint_name, peer),
+        Err(e) => match e.downcast_ref::<TunnelError>() {
+            Some(TunnelError::SecretMismatch(_)) => {
+                events.on_auth_failure(log_target, endpoint_name, peer)
+            }
+            Some(TunnelError::SecretRejected) => {
+                events.on_auth_rejected(log_target, endpoint_name, peer)
+            }
+            _ => {}
+        },
+    }
+    result
+}
+
+// Handle error for the function connect
+pub async fn handle_connection_error(
+    error: anyhow::Error,
+    data: &ConnectionData,
+    ctx: &RouteContext,
+    log_target: &str,
+    endpoint_name: &str,
+) {
+    // The address of an outbound side's error is its own relay's, which is not banned
+    if let Some(TunnelError::SecretMismatch(addr) | TunnelError::Timeout(addr)) = error
+        .downcast_ref::<TunnelError>()
+        .filter(|_| data.accepts())
+    {
+        ctx.ban_list.insert(*addr, Instant::now() + BAN_LENGTH);
+        ctx.events
+            .on_ban(log_target, endpoint_name, *addr, BAN_LENGTH);
+        info!(target: log_target, "{}: {} is banned for {:?}", error, addr, BAN_LENGTH);
+        return;
+    }
+
+    // Expected from abusive clients, counted for the admin socket
+    if let Some(TunnelError::TooManyConnections(_)) = error.downcast_ref::<TunnelError>() {
+        warn!(target: log_target, "{}", error);
+        return;
+    }
+
+    // Scanners mostly, not worth more
+    if let Some(TunnelError::NotKnocked(_)) = error.downcast_ref::<TunnelError>() {
+        debug!(target: log_target, "{}", error);
+        return;
+    }
+
+    if let Some(delay) = retry_delay(&error) {
+        error!(target: log_target, "{}: Sleeping for {:?}...", error, delay);
+        sleep(delay).await;
+        return;
+    }
+
+    error!(target: log_target, "Connection '{}' failed: {}", endpoint_name, error);
+}
+
+// A session between a stream of the process and the connection of an endpoint, outside of any
+// route: the stream is A, and nothing is registered or reported
+pub async fn bridge<S: Stream>(stream: S, conn: Connection, ctx: &RouteContext) -> Result<Traffic> {
+    let options = ctx.session_options();
+    match conn {
+        Connection::Direct(b) => Tunnel::proxy(stream, b, options).await,
+        Connection::Tunnel(b) => b
+            .run(stream, options.reversed())
+            .await
+            .map(Traffic::reversed),
+        #[cfg(feature = "tls")]
+        Connection::Tls(b) => Tunnel::proxy(stream, b, options).await,
+        Connection::Pipe(b) => Tunnel::proxy(stream, b, options).await,
+        Connection::Udp(b) => Tunnel::proxy(stream, b, options).await,
+    }
+}
+
+// Detect if stream exits without writing anything
+async fn watch_stream(conn: &mut Connection, log_target: &str) -> bool {
+    let mut buffer = vec![0u8; 1];
+    let peeked = match conn {
+        // Tunnels with heartbeats time the peer meanwhile
+        Connection::Tunnel(tunnel) if tunnel.has_heartbeat() => {
+            if let Err(e) = tunnel.watch().await {
+                info!(target: log_target, "Dropped while waiting: {}", e);
+            }
+            return true;
+        }
+        Connection::Tunnel(tunnel) => tunnel.stream.peek(&mut buffer).await,
+        Connection::Direct(stream) => stream.peek(&mut buffer).await,
+        // Only used to notice the client leaving, records don't matter for that
+        #[cfg(feature = "tls")]
+        Connection::Tls(stream) => stream.get_ref().peek(&mut buffer).await,
+        // Pipes can't be peeked at, the session is over when they are. Flows end by
+        // themselves once idle.
+        Connection::Pipe(_) | Connection::Udp(_) => return std::future::pending().await,
+    };
+    match peeked {
+        Ok(0) => true,  // EOF
+        Err(_) => true, // Error
+        Ok(_) => false, // Anything is written
+    }
+}
+
+pub async fn route(
+    endpoint_a: ConnectionData,
+    endpoint_b: ConnectionData,
+    ctx: RouteContext,
+    mut enabled: watch::Receiver<bool>,
+    log_target: &str,
+) {
+    loop {
+        // Wait while the route is disabled
+        if !*enabled.borrow_and_update() {
+            debug!(target: log_target, "Route is disabled, waiting");
+            if enabled.wait_for(|e| *e).await.is_err() {
+                return;
+            }
+        }
+
+        // Wait for a free session slot, the route stops accepting while it is full
+        let waiting = ctx.scaling.as_ref().and_then(Scaling::waiting_for_slot);
+        let permit = tokio::select! {
+            Ok(_) = enabled.wait_for(|e| !*e) => continue,
+            permit = ctx.sessions.clone().acquire_owned() => match permit {
+                Ok(permit) => permit,
+                Err(_) => return,
+            },
+        };
+        drop(waiting);
+
+        // Routes of low priority stop accepting first as the budget runs out
+        if let Some(budget) = &ctx.budget {
+            tokio::select! {
+                Ok(_) = enabled.wait_for(|e| !*e) => continue,
+                _ = budget.room(ctx.priority) => {}
+            }
+        }
+
+        // Either the route gets disabled, the worker is retired or Conn A connects
+        let conn_a_result = tokio::select! {
+            Ok(_) = enabled.wait_for(|e| !*e) => continue,
+            Some(scaling) = retired(&ctx.scaling) => {
+                scaling.release(permit);
+                return;
+            }
+            conn_a_result = connect(&endpoint_a, &ctx, None, log_target, "A") => conn_a_result
+        };
+        // Until the session is handed off
+        let _busy = ctx.scaling.as_ref().map(Scaling::working);
+
+        let mut conn_a = match conn_a_result {
+            Ok(conn) => conn,
+            Err(e) => {
+                handle_connection_error(e, &endpoint_a, &ctx, log_target, "A").await;
+                continue;
+            }
+        };
+        let slot_a = match client_slot(&endpoint_a, &conn_a) {
+            Ok(slot) => slot,
+            Err(e) => {
+                drop(conn_a);
+                handle_connection_error(e, &endpoint_a, &ctx, log_target, "A").await;
+                continue;
+            }
+        };
+
+        // Workers pass the check above while idle, the slot is only taken with a client
+        let budget_slot = match &ctx.budget {
+            Some(budget) => tokio::select! {
+                true = watch_stream(&mut conn_a, log_target) => continue,
+                Ok(_) = enabled.wait_for(|e| !*e) => continue,
+                slot = budget.slot(ctx.priority) => Some(slot),
+            },
+            None => None,
+        };
+
+        // Tunnels of a balanced route take turns, the next client goes to the one picked
+        let client = conn_a.peer_addr().map(|a| a.ip());
+        let mut waiter = match (&ctx.balancer, &conn_a, client) {
+            (Some(balancer), Connection::Tunnel(tunnel), Some(connector)) => {
+                Some(balancer.wait(connector, tunnel.rtt()))
+            }
+            _ => None,
+        };
+        let connector = waiter.as_ref().and(client);
+
+        // Either Conn A exits, the route gets disabled or Conn B connects
+        let conn_b_result = tokio::select! {
+            true = watch_stream(&mut conn_a, log_target) => {
+                log::info!(target: log_target, "'{}' exited before '{}' is established!", "A", "B");
+                continue;
+            }
+            Ok(_) = enabled.wait_for(|e| !*e) => continue,
+            conn_b_result = async {
+                if let Some(waiter) = &mut waiter {
+                    waiter.turn().await;
+                }
+                connect(&endpoint_b, &ctx, client, log_target, "B").await
+            } => conn_b_result
+        };
+        drop(waiter);
+
+        let conn_b = match conn_b_result {
+            Ok(conn) => conn,
+            Err(e) => {
+                drop(conn_a);
+                handle_connection_error(e, &endpoint_b, &ctx, log_target, "B").await;
+                continue;
+            }
+        };
+        let slot_b = match client_slot(&endpoint_b, &conn_b) {
+            Ok(slot) => slot,
+            Err(e) => {
+                drop((conn_a, conn_b));
+                handle_connection_error(e, &endpoint_b, &ctx, log_target, "B").await;
+                continue;
+            }
+        };
+
+        // The session runs on its own, the worker goes back to accepting
+        let events = ctx.events.clone();
+        let registry = ctx.registry.clone();
+        let options = ctx.session_options();
+        let balancer = ctx.balancer.clone();
+        let max_duration = ctx.max_session_duration;
+        let session = session_info(&conn_a, &conn_b, &ctx, log_target);
+        task::spawn(async move {
+            let stats = run_session(
+                conn_a,
+                conn_b,
+                session,
+                events,
+                &registry,
+                options,
+                max_duration,
+            )
+            .await;
+            if let (Some(balancer), Some(connector)) = (balancer, connector) {
+                balancer.report(connector, stats.error.is_none());
+            }
+            drop((permit, budget_slot, slot_a, slot_b));
+        });
+    }
+}
+
+// Resolves with the scaling once the worker is retired, never for workers outside of a route
+async fn retired(scaling: &Option<Scaling>) -> Option<&Scaling> {
+    match scaling {
+        Some(scaling) => {
+            scaling.retired().await;
+            Some(scaling)
+        }
+        None => std::future::pending().await,
+    }
+}
+
+fn session_info(
+    conn_a: &Connection,
+    conn_b: &Connection,
+    ctx: &RouteContext,
+    log_target: &str,
+) -> SessionInfo {
+    let connector = match (conn_a, conn_b) {
+        (Connection::Tunnel(_), _) => conn_a.peer_addr(),
+        (_, Connection::Tunnel(_)) => conn_b.peer_addr(),
+        _ => None,
+    };
+    SessionInfo {
+        id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
+        route: log_target.to_owned(),
+        route_name: ctx.name.clone(),
+        endpoints: ctx.endpoints.clone(),
+        connector: connector.map(|addr| addr.ip()),
+        peer_a: conn_a.peer_addr(),
+        peer_b: conn_b.peer_addr(),
+        authenticated: matches!(conn_a, Connection::Tunnel(_))
+            || matches!(conn_b, Connection::Tunnel(_)),
+        started: SystemTime::now(),
+    }
+}
+
+async fn run_session(
+    mut conn_a: Connection,
+    mut conn_b: Connection,
+    session: SessionInfo,
+    events: EventHandlers,
+    registry: &SessionRegistry,
+    options: SessionOptions,
+    max_duration: Option<Duration>,
+) -> SessionStats {
+    let log_target = session.route.as_str();
+    debug!(target: log_target, "Session #{} started", session.id);
+    events.on_session_start(&session);
+    let mut handle = registry.register(session.clone());
+    if let Some(duration) = max_duration {
+        handle = handle.expire_after(duration);
+    }
+    // The tunnels stay in the latency table with their last round trips until the end
+    let _latency = [&mut conn_a, &mut conn_b].map(Connection::take_latency);
+    let options = options.traffic(handle.traffic.clone());
+    let started = Instant::now();
+    let copy = async {
+        match (conn_a, conn_b) {
+            (Connection::Direct(a), Connection::Direct(b)) => Tunnel::proxy(a, b, options).await,
+            (Connection::Tunnel(a), Connection::Tunnel(b)) => a.join(*b, options).await,
+
+            (Connection::Tunnel(a), Connection::Direct(b)) => a.run(b, options).await,
+            (Connection::Direct(a), Connection::Tunnel(b)) => {
+                b.run(a, options.reversed()).await.map(Traffic::reversed)
+            }
+
+            #[cfg(feature = "tls")]
+            (Connection::Tls(a), Connection::Direct(b)) => Tunnel::proxy(a, b, options).await,
+            #[cfg(feature = "tls")]
+            (Connection::Direct(a), Connection::Tls(b)) => Tunnel::proxy(a, b, options).await,
+            #[cfg(feature = "tls")]
+            (Connection::Tls(a), Connection::Tls(b)) => Tunnel::proxy(a, b, options).await,
+            #[cfg(feature = "tls")]
+            (Connection::Tunnel(a), Connection::Tls(b)) => a.run(b, options).await,
+            #[cfg(feature = "tls")]
+            (Connection::Tls(a), Connection::Tunnel(b)) => {
+                b.run(a, options.reversed()).await.map(Traffic::reversed)
+            }
+
+            (Connection::Pipe(a), Connection::Direct(b)) => Tunnel::proxy(a, b, options).await,
+            (Connection::Direct(a), Connection::Pipe(b)) => Tunnel::proxy(a, b, options).await,
+            (Connection::Pipe(a), Connection::Tunnel(b)) => {
+                b.run(a, options.reversed()).await.map(Traffic::reversed)
+            }
+            (Connection::Tunnel(a), Connection::Pipe(b)) => a.run(b, options).await,
+            #[cfg(feature = "tls")]
+            (Connection::Pipe(a), Connection::Tls(b)) => Tunnel::proxy(a, b, options).await,
+            #[cfg(feature = "tls")]
+            (Connection::Tls(a), Connection::Pipe(b)) => Tunnel::proxy(a, b, options).await,
+            (Connection::Pipe(a), Connection::Pipe(b)) => Tunnel::proxy(a, b, options).await,
+
+            (Connection::Udp(a), Connection::Direct(b)) => Tunnel::proxy(a, b, options).await,
+            (Connection::Direct(a), Connection::Udp(b)) => Tunnel::proxy(a, b, options).await,
+            (Connection::Udp(a), Connection::Tunnel(b)) => {
+                b.run(a, options.reversed()).await.map(Traffic::reversed)
+            }
+            (Connection::Tunnel(a), Connection::Udp(b)) => a.run(b, options).await,
+            #[cfg(feature = "tls")]
+            (Connection::Udp(a), Connection::Tls(b)) => Tunnel::proxy(a, b, options).await,
+            #[cfg(feature = "tls")]
+            (Connection::Tls(a), Connection::Udp(b)) => Tunnel::proxy(a, b, options).await,
+            (Connection::Udp(a), Connection::Pipe(b)) => Tunnel::proxy(a, b, options).await,
+            (Connection::Pipe(a), Connection::Udp(b)) => Tunnel::proxy(a, b, options).await,
+            (Connection::Udp(a), Connection::Udp(b)) => Tunnel::proxy(a, b, options).await,
+        }
+    };
+    // Killing drops the copy loops and with them the connections, sessions handed over to
+    // the io_uring threads are dropped there once nobody waits for them
+    let result = tokio::select! {
+        result = copy => result,
+        _ = handle.killed() => Err(anyhow!("Killed")),
+        _ = handle.expired() => Err(anyhow!(
+            "Reached the maximum duration of {:?}",
+            max_duration.unwrap_or_default()
+        )),
+    };
+    // The counters are up to date however the session ended, killed or failed ones included
+    let traffic = handle.traffic();
+    drop(handle);
+
+    let stats = match result {
+        Ok(_) => {
+            info!(
+                target: log_target,
+                "Session #{} closed ({} bytes A->B, {} bytes B->A)",
+                session.id,
+                traffic.a_to_b,
+                traffic.b_to_a
+            );
+            SessionStats {
+                traffic,
+                duration: started.elapsed(),
+                error: None,
+            }
+        }
+        Err(e) => {
+            error!(target: log_target, "Route failed: {}", e);
+            SessionStats {
+                traffic,
+                duration: started.elapsed(),
+                error: Some(e.to_string()),
+            }
+        }
+    };
+    events.on_session_end(&session, &stats);
+    stats
+}
@@ -0,0 +1,163 @@
+#[cfg(feature = "tap")]
+use crate::tap::TapPoint;
+use crate::{error::TunnelError, protocol::cipher::Keystream};
+use anyhow::Result;
+use chacha20::ChaCha20;
+use std::{
+    future::Future,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::{timeout, Duration},
+};
+
+pub const DEFAULT_BUFFER_SIZE: usize = 8192;
+
+// Copies one stream into another, applying the keystreams only to the bytes actually read
+// so the ciphers stay in sync with the peer no matter how the reads are split
+pub struct CipherCopier {
+    ciphers: Vec<Keystream>,
+    buffer: Vec<u8>,
+    // Bytes written so far, for watching the session while it runs
+    counter: Option<Arc<AtomicU64>>,
+    // Time the writer gets to take each chunk before the peer counts as stuck
+    write_timeout: Option<Duration>,
+    #[cfg(feature = "tap")]
+    tap: Option<TapPoint>,
+}
+
+pub struct CopierParts {
+    pub ciphers: Vec<Keystream>,
+    pub buffer_size: usize,
+    pub counter: Option<Arc<AtomicU64>>,
+    pub write_timeout: Option<Duration>,
+}
+
+impl CipherCopier {
+    pub fn new(ciphers: Vec<ChaCha20>) -> Self {
+        Self::with_buffer_size(ciphers, DEFAULT_BUFFER_SIZE)
+    }
+
+    pub fn with_buffer_size(ciphers: Vec<ChaCha20>, buffer_size: usize) -> Self {
+        let keystreams = ciphers.into_iter().map(Keystream::from).collect();
+        Self::with_keystreams(keystreams, buffer_size)
+    }
+
+    // Any session cipher, not just ChaCha20
+    pub fn with_keystreams(ciphers: Vec<Keystream>, buffer_size: usize) -> Self {
+        Self {
+            ciphers,
+            buffer: vec![0u8; buffer_size.max(1)],
+            counter: None,
+            write_timeout: None,
+            #[cfg(feature = "tap")]
+            tap: None,
+        }
+    }
+
+    pub fn count(self, counter: Arc<AtomicU64>) -> Self {
+        Self {
+            counter: Some(counter),
+            ..self
+        }
+    }
+
+    // Fail with TunnelError::WriteStalled instead of waiting forever on a peer that stopped
+    // reading
+    pub fn write_timeout(self, timeout: Duration) -> Self {
+        Self {
+            write_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    #[cfg(feature = "tap")]
+    pub fn tap(self, tap: TapPoint) -> Self {
+        Self {
+            tap: Some(tap),
+            ..self
+        }
+    }
+
+    pub fn is_tapped(&self) -> bool {
+        #[cfg(feature = "tap")]
+        return self.tap.is_some();
+        #[cfg(not(feature = "tap"))]
+        false
+    }
+
+    // What copy loops running elsewhere need to copy the same way
+    pub fn into_parts(self) -> CopierParts {
+        CopierParts {
+            buffer_size: self.buffer.len(),
+            ciphers: self.ciphers,
+            counter: self.counter,
+            write_timeout: self.write_timeout,
+        }
+    }
+
+    // Copy until EOF and pass the half-close along, returns the amount of bytes written
+    pub async fn copy<R, W>(mut self, mut reader: R, mut writer: W) -> Result<u64>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        // Amount of ciphers applied before the data is captured
+        #[cfg(feature = "tap")]
+        let tap_position = self.tap.as_ref().map_or(0, TapPoint::position);
+        #[cfg(not(feature = "tap"))]
+        let tap_position = 0;
+        let tap_position = tap_position.min(self.ciphers.len());
+
+        let mut total = 0u64;
+        loop {
+            // Read
+            let n = reader.read(&mut self.buffer).await?;
+            if n == 0 {
+                // EOF: the other direction keeps running
+                within(self.write_timeout, writer.shutdown()).await?;
+                return Ok(total);
+            }
+
+            // Apply keystreams
+            let chunk = &mut self.buffer[..n];
+            let (before_tap, after_tap) = self.ciphers.split_at_mut(tap_position);
+            for cipher in before_tap {
+                cipher.apply_keystream(chunk);
+            }
+            #[cfg(feature = "tap")]
+            if let Some(tap) = &self.tap {
+                tap.record(chunk).await;
+            }
+            for cipher in after_tap {
+                cipher.apply_keystream(chunk);
+            }
+
+            // Write
+            within(self.write_timeout, writer.write_all(chunk)).await?;
+            total += n as u64;
+            if let Some(counter) = &self.counter {
+                counter.fetch_add(n as u64, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+// A write of the copy loops, bounded by their write timeout if any
+pub async fn within<F, T>(write_timeout: Option<Duration>, write: F) -> Result<T>
+where
+    F: Future<Output = io::Result<T>>,
+{
+    match write_timeout {
+        Some(limit) => match timeout(limit, write).await {
+            Ok(written) => Ok(written?),
+            Err(_) => Err(TunnelError::WriteStalled(limit).into()),
+        },
+        None => Ok(write.await?),
+    }
+}
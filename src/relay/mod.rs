@@ -0,0 +1,9 @@
+// Tunnels over a transport and the routes joining them: handshakes run on streams, copy
+// loops, reconnects and the route workers.
+pub mod connection;
+pub mod copier;
+pub mod pool;
+pub mod reconnect;
+pub mod tunnel;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub mod uring;
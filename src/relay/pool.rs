@@ -0,0 +1,104 @@
+use crate::{config::Affinity, relay::connection::Targets};
+use futures::FutureExt;
+use log::{debug, warn};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    net::TcpStream,
+    sync::Notify,
+    task,
+    time::{sleep, timeout, Duration, Instant},
+};
+
+const LOG_TARGET: &str = "pool";
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// Pause before connecting again after a failure
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+// Idle connections are checked this often, backends tend to close the ones idle for long
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+const MAX_IDLE: Duration = Duration::from_secs(60);
+
+// Connections to the targets of a plain outbound endpoint, opened before the sessions
+// asking for them. Taken ones are replaced in the background.
+#[derive(Clone)]
+pub struct Pool {
+    idle: Arc<Mutex<VecDeque<(TcpStream, Instant)>>>,
+    taken: Arc<Notify>,
+}
+
+impl Pool {
+    // Keeps size connections open until the process exits
+    pub fn start(targets: Targets, size: usize) -> Self {
+        let pool = Self {
+            idle: Arc::default(),
+            taken: Arc::new(Notify::new()),
+        };
+        task::spawn(pool.clone().refill(targets, size));
+        pool
+    }
+
+    pub fn len(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // An open connection, the oldest first. None if there are no healthy ones, the
+    // session connects on its own then.
+    pub fn take(&self) -> Option<TcpStream> {
+        let mut idle = self.idle.lock().unwrap();
+        let stream = std::iter::from_fn(|| idle.pop_front()).find(|(s, since)| healthy(s, *since));
+        self.taken.notify_one();
+        stream.map(|(stream, _)| stream)
+    }
+
+    async fn refill(self, targets: Targets, size: usize) {
+        loop {
+            self.idle
+                .lock()
+                .unwrap()
+                .retain(|(stream, since)| healthy(stream, *since));
+
+            while self.len() < size {
+                let addr = targets.pick(Affinity::RoundRobin, None);
+                match timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await {
+                    Ok(Ok(stream)) => {
+                        debug!(target: LOG_TARGET, "Opened a connection to {}", addr);
+                        self.idle
+                            .lock()
+                            .unwrap()
+                            .push_back((stream, Instant::now()));
+                    }
+                    Ok(Err(e)) => {
+                        warn!(target: LOG_TARGET, "Connecting to {} failed: {}", addr, e);
+                        sleep(RETRY_INTERVAL).await;
+                    }
+                    Err(_) => warn!(target: LOG_TARGET, "Connecting to {} timed out", addr),
+                }
+            }
+
+            tokio::select! {
+                _ = self.taken.notified() => {}
+                _ = sleep(CHECK_INTERVAL) => {}
+            }
+        }
+    }
+}
+
+// Still open and not idle for too long. Data the backend sent first is left for the session.
+fn healthy(stream: &TcpStream, since: Instant) -> bool {
+    if since.elapsed() >= MAX_IDLE {
+        return false;
+    }
+    let mut buffer = [0u8; 1];
+    match stream.peek(&mut buffer).now_or_never() {
+        // Closed, or reset
+        Some(Ok(0)) | Some(Err(_)) => false,
+        Some(Ok(_)) | None => true,
+    }
+}
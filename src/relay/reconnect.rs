@@ -0,0 +1,208 @@
+use crate::{
+    config::CipherKind,
+    error::TunnelError,
+    protocol::ticket::Tickets,
+    relay::tunnel::{HandshakeOptions, HandshakeTimeouts, SessionOptions, Tunnel},
+    transport::Stream,
+};
+use anyhow::Result;
+use log::{info, warn};
+use std::{future::Future, net::SocketAddr};
+use tokio::{
+    net::TcpStream,
+    time::{sleep, Duration, Instant},
+};
+
+const LOG_TARGET: &str = "reconnect";
+
+const CONNREF_TIMEOUT: Duration = Duration::from_secs(5);
+const SECRET_REJECTED_TIMEOUT: Duration = Duration::from_secs(30);
+const CIPHER_MISMATCH_TIMEOUT: Duration = Duration::from_secs(30);
+const NONCE_EARLY_EOF_TIMEOUT: Duration = Duration::from_secs(15);
+// The ban length of the peer, if it runs the defaults
+const BANNED_TIMEOUT: Duration = Duration::from_secs(60 * 5);
+const ROUTE_FULL_TIMEOUT: Duration = Duration::from_secs(1);
+const DRAINING_TIMEOUT: Duration = Duration::from_secs(10);
+const OUTSIDE_SCHEDULE_TIMEOUT: Duration = Duration::from_secs(60);
+// The ticket is gone, the next attempt runs the full handshake
+const TICKET_REJECTED_TIMEOUT: Duration = Duration::ZERO;
+const UNKNOWN_SERVICE_TIMEOUT: Duration = Duration::from_secs(30);
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+const MAX_AUTH_FAILURES: u32 = 5;
+const BREAKER_COOLDOWN: Duration = Duration::from_secs(60 * 10);
+
+// How long to wait before connecting again after an outbound failure, None for
+// failures without a delay of their own
+pub fn retry_delay(error: &anyhow::Error) -> Option<Duration> {
+    #[cfg(feature = "quic")]
+    if error.downcast_ref::<quinn::ConnectionError>().is_some() {
+        return Some(CONNREF_TIMEOUT);
+    }
+
+    if let Some(io_error) = error.downcast_ref::<std::io::Error>() {
+        return (io_error.kind() == std::io::ErrorKind::ConnectionRefused)
+            .then_some(CONNREF_TIMEOUT);
+    }
+
+    match error.downcast_ref::<TunnelError>()? {
+        TunnelError::SecretRejected => Some(SECRET_REJECTED_TIMEOUT),
+        TunnelError::RejectedBanned => Some(BANNED_TIMEOUT),
+        TunnelError::RejectedRouteFull => Some(ROUTE_FULL_TIMEOUT),
+        TunnelError::RejectedDraining => Some(DRAINING_TIMEOUT),
+        TunnelError::RejectedOutsideSchedule => Some(OUTSIDE_SCHEDULE_TIMEOUT),
+        TunnelError::RejectedCipherMismatch => Some(CIPHER_MISMATCH_TIMEOUT),
+        TunnelError::RejectedTicket => Some(TICKET_REJECTED_TIMEOUT),
+        TunnelError::RejectedUnknownService => Some(UNKNOWN_SERVICE_TIMEOUT),
+        TunnelError::NonceEarlyEOF => Some(NONCE_EARLY_EOF_TIMEOUT),
+        _ => None,
+    }
+}
+
+// An outbound tunnel that connects again after each session or failure. Failures
+// are retried with backoff, and after too many authentication failures in a row
+// the circuit breaker stops connecting for a while.
+pub struct ReconnectingTunnel {
+    addr: SocketAddr,
+    secret: [u8; 32],
+    min_backoff: Duration,
+    max_backoff: Duration,
+    backoff: Duration,
+    max_auth_failures: u32,
+    auth_failures: u32,
+    cooldown: Duration,
+    open_until: Option<Instant>,
+    // Resume with the tickets of the previous connections
+    tickets: Option<Tickets>,
+}
+
+impl ReconnectingTunnel {
+    pub fn new(addr: SocketAddr, secret: [u8; 32]) -> Self {
+        Self {
+            addr,
+            secret,
+            min_backoff: MIN_BACKOFF,
+            max_backoff: MAX_BACKOFF,
+            backoff: MIN_BACKOFF,
+            max_auth_failures: MAX_AUTH_FAILURES,
+            auth_failures: 0,
+            cooldown: BREAKER_COOLDOWN,
+            open_until: None,
+            tickets: None,
+        }
+    }
+
+    // Delays of failures without one of their own, doubling from min to max
+    pub fn backoff(mut self, min: Duration, max: Duration) -> Self {
+        self.min_backoff = min;
+        self.max_backoff = max;
+        self.backoff = min;
+        self
+    }
+
+    pub fn circuit_breaker(mut self, max_auth_failures: u32, cooldown: Duration) -> Self {
+        self.max_auth_failures = max_auth_failures.max(1);
+        self.cooldown = cooldown;
+        self
+    }
+
+    // The peer must issue tickets, see HandshakeOptions::tickets
+    pub fn tickets(mut self, tickets: Tickets) -> Self {
+        self.tickets = Some(tickets);
+        self
+    }
+
+    // Connect until a tunnel is attached, fails only while the circuit breaker is open
+    pub async fn connect(&mut self) -> Result<Tunnel> {
+        loop {
+            if let Some(until) = self.open_until {
+                if Instant::now() < until {
+                    return Err(TunnelError::CircuitOpen.into());
+                }
+                // Half open, a single failure opens it again
+                self.open_until = None;
+                self.auth_failures = self.max_auth_failures - 1;
+            }
+
+            let error = match self.attempt().await {
+                Ok(tunnel) => {
+                    self.auth_failures = 0;
+                    self.backoff = self.min_backoff;
+                    return Ok(tunnel);
+                }
+                Err(e) => e,
+            };
+
+            if let Some(TunnelError::SecretRejected) = error.downcast_ref::<TunnelError>() {
+                self.auth_failures += 1;
+                if self.auth_failures >= self.max_auth_failures {
+                    warn!(target: LOG_TARGET, "{}: {} failures in a row, pausing for {:?}", error, self.auth_failures, self.cooldown);
+                    self.open_until = Some(Instant::now() + self.cooldown);
+                    return Err(TunnelError::CircuitOpen.into());
+                }
+            }
+
+            let delay = retry_delay(&error).unwrap_or_else(|| {
+                let delay = self.backoff;
+                self.backoff = (self.backoff * 2).min(self.max_backoff);
+                delay
+            });
+            warn!(target: LOG_TARGET, "Connecting to {} failed: {}, retrying in {:?}", self.addr, error, delay);
+            sleep(delay).await;
+        }
+    }
+
+    async fn attempt(&self) -> Result<Tunnel> {
+        let stream = TcpStream::connect(self.addr).await?;
+        let peer = self.addr.ip();
+        match &self.tickets {
+            Some(tickets) => {
+                let (cipher, timeouts) = (CipherKind::ChaCha20, HandshakeTimeouts::default());
+                let options = HandshakeOptions {
+                    tickets: Some(tickets),
+                    ..Default::default()
+                };
+                Tunnel::init_with_options(
+                    stream,
+                    peer,
+                    false,
+                    self.secret,
+                    cipher,
+                    timeouts,
+                    options,
+                )
+                .await
+            }
+            None => Tunnel::init(stream, peer, false, self.secret).await,
+        }
+    }
+
+    // Run sessions back to back, each attached tunnel is connected to a new stream from
+    // `target`. Returns once the circuit breaker opens.
+    pub async fn run<F, Fut, T>(&mut self, mut target: F) -> Result<()>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+        T: Stream,
+    {
+        loop {
+            let tunnel = self.connect().await?;
+            let stream = match target().await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!(target: LOG_TARGET, "Target failed: {}", e);
+                    continue;
+                }
+            };
+
+            match tunnel.run(stream, SessionOptions::default()).await {
+                Ok(traffic) => info!(
+                    target: LOG_TARGET,
+                    "Session closed ({} bytes in, {} bytes out)", traffic.a_to_b, traffic.b_to_a
+                ),
+                Err(e) => warn!(target: LOG_TARGET, "Session failed: {}", e),
+            }
+        }
+    }
+}
@@ -0,0 +1,989 @@
+#[cfg(feature = "tap")]
+use crate::tap::SessionTap;
+use crate::{
+    config::CipherKind,
+    error::TunnelError,
+    keys::TunnelKey,
+    latency::LatencyHandle,
+    protocol::{
+        cipher::{
+            end_to_end_keystream, export_keying_material, Keystream, RekeyLimits, SessionKeys,
+            END_TO_END_NONCE_LEN,
+        },
+        datagram::TunnelDatagram,
+        encryption::{Nonce, Secret, Totp},
+        handshake::{
+            attach_frame, ping_frame, pong_frame, reject_frame, rekey_attach_frame,
+            ATTACH_NONCE_LEN, HEARTBEAT_FRAME_LEN, NONCE_LEN, REASON_BANNED,
+            REASON_CIPHER_MISMATCH, REASON_DRAINING, REASON_OUTSIDE_SCHEDULE, REASON_ROUTE_FULL,
+            REASON_TICKET_REJECTED, REASON_UNKNOWN_SERVICE, SALT_LEN, VERSION,
+        },
+        machine::{
+            AcceptEvent, ConnectEvent, InboundMachine, InboundOptions, OutboundMachine,
+            OutboundOptions,
+        },
+        padding::{self, Padding},
+        silent::Silence,
+        ticket::Tickets,
+    },
+    relay::copier::{CipherCopier, DEFAULT_BUFFER_SIZE},
+    transport::{chain::ChainedStream, Stream, Transport},
+};
+use anyhow::Result;
+use rand::Rng;
+use socket2::SockRef;
+use std::{
+    any::Any,
+    net::{IpAddr, SocketAddr},
+    sync::{atomic::AtomicU64, Arc},
+};
+use tokio::{
+    io::{duplex, split, AsyncReadExt, AsyncWriteExt, DuplexStream},
+    net::TcpStream,
+    task::{self, JoinHandle},
+    time::{sleep_until, timeout, Duration, Instant},
+};
+
+const AUTH_TIMEOUT: Duration = Duration::from_secs(5);
+const NONCE_TIMEOUT: Duration = Duration::from_secs(5);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// Time the peer gets to answer a heartbeat
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(5);
+// Between the datagrams of an embedding application and its session
+const DATAGRAM_PIPE_SIZE: usize = 128 * 1024;
+// Between a chained tunnel and the session of the one carrying it
+const NESTED_PIPE_SIZE: usize = 128 * 1024;
+
+// How long each side waits for its peer before the tunnel is authenticated
+#[derive(Debug, Clone, Copy)]
+pub struct HandshakeTimeouts {
+    // Inbound side, for the auth token once the nonce is sent
+    pub auth: Duration,
+    // Outbound side, for the nonce
+    pub nonce: Duration,
+    // Inbound side, from accepting the connection until it is authenticated, whatever
+    // runs before the handshake included
+    pub total: Duration,
+}
+
+impl Default for HandshakeTimeouts {
+    fn default() -> Self {
+        Self {
+            auth: AUTH_TIMEOUT,
+            nonce: NONCE_TIMEOUT,
+            total: HANDSHAKE_TIMEOUT,
+        }
+    }
+}
+
+// Bytes transferred in each direction of a session
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Traffic {
+    pub a_to_b: u64,
+    pub b_to_a: u64,
+}
+
+impl Traffic {
+    pub fn reversed(self) -> Self {
+        Self {
+            a_to_b: self.b_to_a,
+            b_to_a: self.a_to_b,
+        }
+    }
+}
+
+// Counters the copy loops keep up to date while the session runs
+#[derive(Debug, Clone, Default)]
+pub struct LiveTraffic {
+    pub a_to_b: Arc<AtomicU64>,
+    pub b_to_a: Arc<AtomicU64>,
+}
+
+impl LiveTraffic {
+    pub fn reversed(self) -> Self {
+        Self {
+            a_to_b: self.b_to_a,
+            b_to_a: self.a_to_b,
+        }
+    }
+}
+
+// Per-session options of the copy loops
+#[derive(Clone, Default)]
+pub struct SessionOptions {
+    #[cfg(feature = "tap")]
+    pub tap: Option<SessionTap>,
+    pub traffic: Option<LiveTraffic>,
+    // Bytes of each direction's copy buffer, DEFAULT_BUFFER_SIZE if unset
+    pub buffer_size: Option<usize>,
+    // Joined tunnels copy the payload as it is, for end-to-end sessions passing through
+    pub blind: bool,
+    // Time each side gets to take a chunk, the session is torn down when one stops reading
+    pub write_timeout: Option<Duration>,
+    // Bytes of each direction held by the relay at once, in its copy buffer and in the
+    // kernel's send buffer of TCP sides
+    pub max_in_flight: Option<usize>,
+}
+
+impl SessionOptions {
+    pub fn traffic(mut self, traffic: LiveTraffic) -> Self {
+        self.traffic = Some(traffic);
+        self
+    }
+
+    // Swap A and B, for sessions whose sides are passed in the opposite order
+    pub fn reversed(self) -> Self {
+        Self {
+            #[cfg(feature = "tap")]
+            tap: self.tap.map(SessionTap::reversed),
+            traffic: self.traffic.map(LiveTraffic::reversed),
+            buffer_size: self.buffer_size,
+            blind: self.blind,
+            write_timeout: self.write_timeout,
+            max_in_flight: self.max_in_flight,
+        }
+    }
+
+    // Copier for one direction of the session. After `plaintext_at` ciphers the data is
+    // plaintext, after `wire_at` ciphers it is as seen on the tunnel.
+    fn copier(
+        &self,
+        ciphers: Vec<Keystream>,
+        a_to_b: bool,
+        plaintext_at: usize,
+        wire_at: usize,
+    ) -> CipherCopier {
+        let buffer_size = self.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE);
+        let buffer_size = buffer_size.min(self.max_in_flight.unwrap_or(usize::MAX));
+        let mut copier = CipherCopier::with_keystreams(ciphers, buffer_size);
+        if let Some(write_timeout) = self.write_timeout {
+            copier = copier.write_timeout(write_timeout);
+        }
+        if let Some(traffic) = &self.traffic {
+            copier = copier.count(match a_to_b {
+                true => traffic.a_to_b.clone(),
+                false => traffic.b_to_a.clone(),
+            });
+        }
+
+        #[cfg(feature = "tap")]
+        if let Some(tap) = &self.tap {
+            return copier.tap(tap.point(a_to_b, plaintext_at, wire_at));
+        }
+        #[cfg(not(feature = "tap"))]
+        let _ = (a_to_b, plaintext_at, wire_at);
+
+        copier
+    }
+
+    // Caps the kernel's send buffer of a TCP side at the in-flight budget, so a peer that
+    // stopped reading stalls the writes early. The kernel may round it up.
+    fn bound_in_flight<S: Stream>(&self, stream: &S) {
+        let Some(budget) = self.max_in_flight else {
+            return;
+        };
+        let stream: &dyn Any = stream;
+        let socket = match (stream.downcast_ref::<TcpStream>(), stream.downcast_ref()) {
+            (Some(socket), _) | (_, Some(Transport::Tcp(socket))) => socket,
+            (_, Some(Transport::Obfuscated(stream))) => stream.get_ref(),
+            _ => return,
+        };
+        // Best effort, the write timeout still catches the peer
+        let _ = SockRef::from(socket).set_send_buffer_size(budget);
+    }
+}
+
+// What a handshake may add to the secret and cipher, both sides must agree on each
+#[derive(Clone, Copy, Default)]
+pub struct HandshakeOptions<'a> {
+    // Inbound sides issue resumption tickets and take them instead of the auth token,
+    // outbound sides resume with the ones they hold
+    pub tickets: Option<&'a Tickets>,
+    // Registers the tunnel for a service: outbound sides announce it, inbound sides take it
+    // from the peer and tell it apart by service()
+    pub registration: Option<&'a Registration>,
+    // Inbound sides take a silent hello before sending the nonce, outbound sides send one
+    pub silence: Option<&'a Silence>,
+    // The secret is mixed with a time-based code
+    pub totp: Option<&'a Totp>,
+}
+
+// Service registration, both sides must agree on it
+#[derive(Debug, Clone)]
+pub enum Registration {
+    // Outbound sides, the service the tunnel is for
+    Announce(String),
+    // Inbound sides, the peer announces one
+    Expect,
+}
+
+pub struct Tunnel<S = TcpStream> {
+    nonce: Nonce,
+    secret: Secret,
+    pub stream: S,
+    is_inbound: bool,
+    // Handshake version spoken with the peer
+    version: u8,
+    cipher: CipherKind,
+    // Outbound side's part of the session keys, zero before version 3
+    salt: [u8; SALT_LEN],
+    // Both sides must agree on it
+    padding: Option<Padding>,
+    // Inner secret shared with the far end, the payload isn't readable by the peer then
+    end_to_end: Option<Secret>,
+    // Fresh session keys for the attachment, both sides must agree on it
+    rekey: bool,
+    // Received with ATTACH by a rekeyed outbound side
+    attach_nonce: Option<[u8; ATTACH_NONCE_LEN]>,
+    // When the session keys are replaced while it runs
+    rekey_limits: RekeyLimits,
+    // Last round trip to the peer, timed by inbound sides
+    rtt: Option<Duration>,
+    heartbeat: Option<Box<Heartbeat>>,
+    latency: Option<LatencyHandle>,
+    // Announced by the peer of a registering inbound side
+    service: Option<String>,
+}
+
+// Heartbeats of an inbound tunnel waiting to be attached, kept on the tunnel so a wait
+// being cancelled loses nothing
+struct Heartbeat {
+    interval: Duration,
+    next_seq: u64,
+    due: Instant,
+    // Ping bytes not written yet
+    unsent: Vec<u8>,
+    // Sequence number and sending time of the ping waiting for its pong
+    waiting: Option<(u64, Instant)>,
+    // Pong received so far
+    pong: Vec<u8>,
+}
+
+impl Heartbeat {
+    fn send(&mut self) {
+        self.unsent = ping_frame(self.next_seq).to_vec();
+        self.waiting = Some((self.next_seq, Instant::now()));
+        self.next_seq += 1;
+    }
+
+    // Round trip of the ping once its pong is complete
+    fn receive(&mut self) -> Result<Option<Duration>> {
+        if self.pong.len() < HEARTBEAT_FRAME_LEN {
+            return Ok(None);
+        }
+        let pong = std::mem::take(&mut self.pong);
+        match self.waiting.take() {
+            Some((seq, sent)) if pong == pong_frame(seq) => {
+                self.due = Instant::now() + self.interval;
+                Ok(Some(sent.elapsed()))
+            }
+            _ => Err(TunnelError::DataBeforeAttach.into()),
+        }
+    }
+}
+
+// A tunnel side with layers of its own (AEAD records, rekeyed frames, padding) is boxed, plain sides keep
+// their type so they can still be handed over whole
+enum Side<S> {
+    Plain(S),
+    Layered(Box<dyn Stream>),
+}
+
+impl<S: Stream> Tunnel<S> {
+    // Initializes the tunnel, peer is used for reporting the errors to ban
+    pub async fn init(stream: S, peer: IpAddr, is_inbound: bool, secret: [u8; 32]) -> Result<Self> {
+        Self::handshake(
+            stream,
+            peer,
+            is_inbound,
+            secret.into(),
+            VERSION,
+            CipherKind::ChaCha20,
+            HandshakeTimeouts::default(),
+            HandshakeOptions::default(),
+        )
+        .await
+    }
+
+    // Outbound sides can speak an older version for inbound sides that don't know the
+    // current one, inbound sides accept every version
+    pub async fn init_with_version(
+        stream: S,
+        peer: IpAddr,
+        is_inbound: bool,
+        secret: [u8; 32],
+        version: u8,
+    ) -> Result<Self> {
+        Self::handshake(
+            stream,
+            peer,
+            is_inbound,
+            secret.into(),
+            version,
+            CipherKind::ChaCha20,
+            HandshakeTimeouts::default(),
+            HandshakeOptions::default(),
+        )
+        .await
+    }
+
+    // Both sides must use the same cipher, inbound sides reject outbound sides asking
+    // for another one
+    pub async fn init_with_cipher(
+        stream: S,
+        peer: IpAddr,
+        is_inbound: bool,
+        secret: [u8; 32],
+        cipher: CipherKind,
+    ) -> Result<Self> {
+        Self::handshake(
+            stream,
+            peer,
+            is_inbound,
+            secret.into(),
+            VERSION,
+            cipher,
+            HandshakeTimeouts::default(),
+            HandshakeOptions::default(),
+        )
+        .await
+    }
+
+    // Like init_with_cipher, waiting on the peer as long as the timeouts allow. Only the
+    // auth and nonce ones apply here, the total one is up to the caller.
+    pub async fn init_with_timeouts(
+        stream: S,
+        peer: IpAddr,
+        is_inbound: bool,
+        secret: [u8; 32],
+        cipher: CipherKind,
+        timeouts: HandshakeTimeouts,
+    ) -> Result<Self> {
+        let options = HandshakeOptions::default();
+        Self::handshake(
+            stream,
+            peer,
+            is_inbound,
+            secret.into(),
+            VERSION,
+            cipher,
+            timeouts,
+            options,
+        )
+        .await
+    }
+
+    // Like init_with_timeouts, with any of the options both sides must agree on: resumption
+    // tickets, service registration... See HandshakeOptions. The key is a secret, or a
+    // provider asked for the key of each handshake (outbound sides send nothing before the
+    // nonce then), see keys::KeyProvider.
+    pub async fn init_with_options(
+        stream: S,
+        peer: IpAddr,
+        is_inbound: bool,
+        key: impl Into<TunnelKey>,
+        cipher: CipherKind,
+        timeouts: HandshakeTimeouts,
+        options: HandshakeOptions<'_>,
+    ) -> Result<Self> {
+        let key = key.into();
+        Self::handshake(
+            stream, peer, is_inbound, key, VERSION, cipher, timeouts, options,
+        )
+        .await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn handshake(
+        mut stream: S,
+        peer: IpAddr,
+        is_inbound: bool,
+        key: TunnelKey,
+        version: u8,
+        cipher: CipherKind,
+        timeouts: HandshakeTimeouts,
+        options: HandshakeOptions<'_>,
+    ) -> Result<Self> {
+        let HandshakeOptions {
+            tickets,
+            registration,
+            silence,
+            totp,
+        } = options;
+        // The secret may be mixed with a time-based code by now
+        let (handshaken, rtt, secret) = match is_inbound {
+            true => {
+                let nonce = crate::protocol::encryption::generate_random_nonce();
+                let secret = key.for_nonce(&nonce).await?;
+                let options = InboundOptions {
+                    cipher: cipher.id(),
+                    tickets: tickets.cloned(),
+                    expect_service: matches!(registration, Some(Registration::Expect)),
+                    silence: silence.cloned(),
+                    totp: totp.cloned(),
+                };
+                let mut machine = InboundMachine::new(*secret, nonce, options);
+                // Send Nonce, silent ones wait for the peer's hello first
+                flush(&mut stream, || machine.poll_transmit()).await?;
+                let sent = Instant::now();
+                // Receive encrypted "AUTH", and the offer of version 3, or a ticket
+                let authenticated = timeout(timeouts.auth, accept(&mut stream, &mut machine)).await;
+                // The auth token answers the nonce, a ticket is sent without waiting for it.
+                // Silent sides send the nonce only once the hello is in.
+                let rtt = matches!(
+                    authenticated,
+                    Ok(Ok(AcceptEvent::Authenticated { resumed: false }))
+                )
+                .then(|| sent.elapsed())
+                .filter(|_| silence.is_none());
+                let Ok(authenticated) = authenticated else {
+                    return Err(TunnelError::Timeout(peer).into());
+                };
+                // The peer is authenticated, what it announces is waited for anew
+                let done = match authenticated? {
+                    AcceptEvent::Authenticated { .. } => {
+                        match timeout(timeouts.auth, accept(&mut stream, &mut machine)).await {
+                            Ok(done) => done?,
+                            Err(_) => return Err(TunnelError::Timeout(peer).into()),
+                        }
+                    }
+                    event => event,
+                };
+                match done {
+                    AcceptEvent::Done(handshaken) => (handshaken, rtt, machine.secret()),
+                    AcceptEvent::Refused { reason } => {
+                        return Err(match reason {
+                            REASON_CIPHER_MISMATCH => TunnelError::CipherMismatch(peer),
+                            REASON_TICKET_REJECTED => TunnelError::TicketRejected(peer),
+                            REASON_UNKNOWN_SERVICE => TunnelError::UnknownService(peer),
+                            _ => TunnelError::SecretMismatch(peer),
+                        }
+                        .into());
+                    }
+                    AcceptEvent::Authenticated { .. } => {
+                        unreachable!("authenticated only once")
+                    }
+                }
+            }
+            false => {
+                let options = OutboundOptions {
+                    version,
+                    cipher: cipher.id(),
+                    tickets: tickets.cloned(),
+                    announce: match registration {
+                        Some(Registration::Announce(service)) => Some(service.clone()),
+                        _ => None,
+                    },
+                    silent: silence.is_some(),
+                    totp: totp.cloned(),
+                };
+                let (mut machine, nonce) = match key {
+                    // The key answers the nonce
+                    TunnelKey::Provider(_) => {
+                        let nonce = receive_nonce(&mut stream, peer, timeouts).await?;
+                        let secret = key.for_nonce(&nonce).await?;
+                        (OutboundMachine::new(*secret, options), nonce)
+                    }
+                    TunnelKey::Secret(secret) => {
+                        let mut machine = OutboundMachine::new(*secret, options);
+                        // Resuming doesn't wait for the nonce, neither does the silent hello
+                        flush(&mut stream, || machine.poll_transmit()).await?;
+                        let nonce = receive_nonce(&mut stream, peer, timeouts).await?;
+                        (machine, nonce)
+                    }
+                };
+                machine.handle_input(&nonce);
+                // Send encrypted "AUTH", wait until the tunnel is attached
+                let handshaken = loop {
+                    flush(&mut stream, || machine.poll_transmit()).await?;
+                    match machine.poll_event() {
+                        Some(ConnectEvent::Accepted(handshaken)) => break handshaken,
+                        Some(ConnectEvent::Rejected { reason }) => {
+                            return Err(rejection(reason).into())
+                        }
+                        None => {}
+                    }
+                    let mut frame = vec![0u8; machine.wants()];
+                    stream.read_exact(&mut frame).await?;
+                    machine.handle_input(&frame);
+                };
+                (handshaken, None, machine.secret())
+            }
+        };
+
+        Ok(Self {
+            nonce: Nonce::new(handshaken.nonce),
+            secret: Secret::new(secret),
+            stream,
+            is_inbound,
+            version: handshaken.version,
+            cipher,
+            salt: handshaken.salt,
+            padding: None,
+            end_to_end: None,
+            rekey: false,
+            attach_nonce: handshaken.attach_nonce,
+            rekey_limits: RekeyLimits::default(),
+            rtt,
+            heartbeat: None,
+            latency: None,
+            service: handshaken.service,
+        })
+    }
+
+    // Send the session as padded frames once attached
+    pub fn padding(self, padding: Padding) -> Self {
+        Self {
+            padding: Some(padding),
+            ..self
+        }
+    }
+
+    // Encrypt the session for the far end instead of the peer, which has to join it blindly
+    // to another tunnel on the way there
+    pub fn end_to_end(self, secret: [u8; 32]) -> Self {
+        Self {
+            end_to_end: Some(Secret::new(secret)),
+            ..self
+        }
+    }
+
+    // Derive the session keys from a nonce the inbound side picks on attaching, rather than
+    // from the handshake alone
+    pub fn rekey(self) -> Self {
+        Self {
+            rekey: true,
+            ..self
+        }
+    }
+
+    // Replace the session keys as the session runs, after the bytes or the time of the limits.
+    // See protocol::cipher::RekeyLimits for what both sides must agree on.
+    pub fn rekey_limits(self, limits: RekeyLimits) -> Self {
+        Self {
+            rekey_limits: limits,
+            ..self
+        }
+    }
+
+    // Time the peer every interval while the tunnel waits to be attached, see watch. Inbound
+    // sides only, the outbound ones answer on their own. Version 1 has no frames for it.
+    pub fn heartbeat(self, interval: Duration) -> Self {
+        if !self.is_inbound || self.version < 2 {
+            return self;
+        }
+        Self {
+            heartbeat: Some(Box::new(Heartbeat {
+                interval,
+                next_seq: 0,
+                due: Instant::now() + interval,
+                unsent: Vec::new(),
+                waiting: None,
+                pong: Vec::new(),
+            })),
+            ..self
+        }
+    }
+
+    // Report the round trips timed to a latency table, the handshake's included
+    pub fn latency(self, handle: LatencyHandle) -> Self {
+        if let Some(rtt) = self.rtt {
+            handle.record(rtt);
+        }
+        Self {
+            latency: Some(handle),
+            ..self
+        }
+    }
+
+    // Last round trip timed, inbound sides only
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    pub fn has_heartbeat(&self) -> bool {
+        self.heartbeat.is_some()
+    }
+
+    // Service announced by the peer, registering inbound sides only
+    pub fn service(&self) -> Option<&str> {
+        self.service.as_deref()
+    }
+
+    // Keying material of the session under the label, the same on both sides once the
+    // handshake is done, for the applications it carries to bind their authentication to it.
+    // See protocol::cipher::export_keying_material.
+    pub fn export_keying_material(&self, label: &[u8], len: usize) -> Result<Vec<u8>> {
+        export_keying_material(&self.secret, &self.nonce, &self.salt, label, len)
+            .ok_or(TunnelError::ExportTooLong.into())
+    }
+
+    // For the tunnel to stay listed in the latency table after it is attached
+    pub fn take_latency(&mut self) -> Option<LatencyHandle> {
+        self.latency.take()
+    }
+
+    // Send the heartbeats while the tunnel waits to be attached. Returns once the peer is
+    // gone, or fails if it misses a heartbeat or sends anything else. Safe to cancel, the
+    // tunnel can be attached afterwards. Never returns without heartbeats.
+    pub async fn watch(&mut self) -> Result<()> {
+        let Some(heartbeat) = &mut self.heartbeat else {
+            return std::future::pending().await;
+        };
+        loop {
+            // A cancelled write may have left some of the ping
+            if !heartbeat.unsent.is_empty() {
+                let written = self.stream.write(&heartbeat.unsent).await?;
+                if written == 0 {
+                    return Err(std::io::Error::from(std::io::ErrorKind::WriteZero).into());
+                }
+                heartbeat.unsent.drain(..written);
+                continue;
+            }
+            self.stream.flush().await?;
+
+            let deadline = match heartbeat.waiting {
+                Some((_, sent)) => sent + HEARTBEAT_TIMEOUT,
+                None => heartbeat.due,
+            };
+            let mut buffer = [0u8; HEARTBEAT_FRAME_LEN];
+            let wanted = HEARTBEAT_FRAME_LEN - heartbeat.pong.len();
+            tokio::select! {
+                read = self.stream.read(&mut buffer[..wanted]) => match read? {
+                    0 => return Ok(()),
+                    _ if heartbeat.waiting.is_none() => {
+                        return Err(TunnelError::DataBeforeAttach.into())
+                    }
+                    read => {
+                        heartbeat.pong.extend_from_slice(&buffer[..read]);
+                        if let Some(rtt) = heartbeat.receive()? {
+                            self.rtt = Some(rtt);
+                            if let Some(latency) = &self.latency {
+                                latency.record(rtt);
+                            }
+                        }
+                    }
+                },
+                _ = sleep_until(deadline) => match heartbeat.waiting {
+                    Some(_) => return Err(TunnelError::HeartbeatMissed.into()),
+                    None => heartbeat.send(),
+                },
+            }
+        }
+    }
+
+    // Turn a peer away without authenticating it. It still gets to send its auth token,
+    // so the rejection isn't lost to a reset connection. The peer gets the endpoint's
+    // timeouts, as long as it would to authenticate and no longer.
+    pub async fn reject(mut stream: S, reason: u8, timeouts: HandshakeTimeouts) -> Result<()> {
+        let rejected = async {
+            stream
+                .write_all(&crate::protocol::encryption::generate_random_nonce())
+                .await?;
+            let mut auth = [0u8; 4];
+            timeout(timeouts.auth, stream.read_exact(&mut auth)).await??;
+            stream.write_all(&reject_frame(reason)).await?;
+            stream.shutdown().await?;
+            Ok(())
+        };
+        timeout(timeouts.total, rejected).await?
+    }
+
+    // Turn an authenticated peer away, inbound sides only. The peer reads the rejection
+    // where it waits for ATTACH.
+    pub async fn turn_away(mut self, reason: u8) -> Result<()> {
+        self.stream.write_all(&reject_frame(reason)).await?;
+        self.stream.shutdown().await?;
+        Ok(())
+    }
+
+    // Attach inbound tunnels and put the session's layers on the stream
+    async fn attach(self) -> Result<(Side<S>, SessionKeys)> {
+        let mut stream = self.stream;
+        // The ping being sent goes out first, its pong is read after ATTACH
+        let mut heartbeat = self.heartbeat;
+        if let Some(heartbeat) = &mut heartbeat {
+            stream.write_all(&heartbeat.unsent).await?;
+        }
+        let attach = match (self.is_inbound, self.rekey) {
+            // Version 1 has no room for the nonce, only outbound sides agreeing on it speak
+            // a later one
+            (true, true) if self.version >= 2 => {
+                let nonce = crate::protocol::encryption::generate_random_nonce();
+                stream.write_all(&rekey_attach_frame(nonce)).await?;
+                Some(nonce)
+            }
+            (true, _) => {
+                stream.write_all(attach_frame(self.version)).await?;
+                None
+            }
+            (false, rekey) if rekey != self.attach_nonce.is_some() => {
+                return Err(TunnelError::RekeyMismatch.into())
+            }
+            (false, _) => self.attach_nonce,
+        };
+        if let Some(mut heartbeat) = heartbeat.filter(|h| h.waiting.is_some()) {
+            let mut pong = vec![0u8; HEARTBEAT_FRAME_LEN - heartbeat.pong.len()];
+            match timeout(HEARTBEAT_TIMEOUT, stream.read_exact(&mut pong)).await {
+                Ok(read) => read?,
+                Err(_) => return Err(TunnelError::HeartbeatMissed.into()),
+            };
+            heartbeat.pong.extend_from_slice(&pong);
+            if let (Some(rtt), Some(latency)) = (heartbeat.receive()?, &self.latency) {
+                latency.record(rtt);
+            }
+        }
+
+        let keys = SessionKeys {
+            cipher: self.cipher,
+            secret: *self.secret,
+            nonce: *self.nonce,
+            salt: self.salt,
+            is_inbound: self.is_inbound,
+            attach,
+            limits: self.rekey_limits,
+        };
+        let side = match self.padding {
+            Some(padding) => {
+                let padded =
+                    padding::wrap(stream, padding, &self.secret, &self.nonce, self.is_inbound);
+                Side::Layered(layer(&keys, padded).unwrap_or_else(|padded| Box::new(padded)))
+            }
+            None => match layer(&keys, stream) {
+                Ok(layered) => Side::Layered(layered),
+                Err(stream) => Side::Plain(stream),
+            },
+        };
+        Ok((side, keys))
+    }
+
+    // Connect the tunnel to another tunnel
+    pub async fn join<O: Stream>(
+        self,
+        other: Tunnel<O>,
+        options: SessionOptions,
+    ) -> Result<Traffic> {
+        if self.end_to_end.is_some() || other.end_to_end.is_some() {
+            return Err(TunnelError::EndToEndJoined.into());
+        }
+        options.bound_in_flight(&self.stream);
+        options.bound_in_flight(&other.stream);
+        let (self_side, self_keys) = self.attach().await?;
+        let (other_side, other_keys) = other.attach().await?;
+
+        if options.blind {
+            let (Side::Plain(a), Side::Plain(b)) = (self_side, other_side) else {
+                return Err(TunnelError::NotBlindable.into());
+            };
+            let a_to_b = options.copier(vec![], true, 0, 0);
+            let b_to_a = options.copier(vec![], false, 0, 0);
+            return pump(a, b, a_to_b, b_to_a).await;
+        }
+
+        // Keystreams, AEAD and framed sides have none as their streams are plaintext already
+        let (self_read, self_write) = (self_keys.read_keystream(), self_keys.write_keystream());
+        let (other_read, other_write) = (other_keys.read_keystream(), other_keys.write_keystream());
+        let a_plaintext_at = self_read.is_some() as usize;
+        let b_plaintext_at = other_read.is_some() as usize;
+
+        pump_sides(
+            self_side,
+            other_side,
+            options.copier(chain([self_read, other_write]), true, a_plaintext_at, 0),
+            options.copier(chain([other_read, self_write]), false, b_plaintext_at, 0),
+        )
+        .await
+    }
+
+    // Connect the tunnel to a plain stream
+    pub async fn run<T: Stream>(self, stream: T, options: SessionOptions) -> Result<Traffic> {
+        let end_to_end = self.end_to_end.clone();
+        options.bound_in_flight(&self.stream);
+        options.bound_in_flight(&stream);
+        let (mut tunnel_side, keys) = self.attach().await?;
+
+        let (read, write) = match end_to_end {
+            Some(secret) => {
+                let Side::Plain(stream) = &mut tunnel_side else {
+                    return Err(TunnelError::NotBlindable.into());
+                };
+                let (read, write) = exchange_nonces(stream).await?;
+                (
+                    Some(end_to_end_keystream(&secret, &read)),
+                    Some(end_to_end_keystream(&secret, &write)),
+                )
+            }
+            None => (keys.read_keystream(), keys.write_keystream()),
+        };
+        let plaintext_at = read.is_some() as usize;
+        let wire_at = write.is_some() as usize;
+
+        pump_sides(
+            tunnel_side,
+            Side::Plain(stream),
+            options.copier(chain([read]), true, plaintext_at, 0),
+            options.copier(chain([write]), false, 0, wire_at),
+        )
+        .await
+    }
+
+    // Run the session against datagrams sent and received by the caller, for applications
+    // embedding the relay. The session ends once both sides have shut down.
+    pub fn datagrams(
+        self,
+        options: SessionOptions,
+    ) -> (TunnelDatagram<DuplexStream>, JoinHandle<Result<Traffic>>) {
+        let (local, session) = duplex(DATAGRAM_PIPE_SIZE);
+        let session = task::spawn(self.run(session, options));
+        (TunnelDatagram::new(local), session)
+    }
+
+    // Run the session against the stream of another tunnel, nesting its handshake and cipher
+    // inside of this one's. The peer, the relay of the first hop, takes it on to the next.
+    pub fn nested(self, peer: SocketAddr, options: SessionOptions) -> ChainedStream {
+        let (local, session) = duplex(NESTED_PIPE_SIZE);
+        task::spawn(self.run(session, options));
+        ChainedStream::new(local, peer)
+    }
+}
+
+impl Tunnel {
+    // Connect a plain stream to another plain stream
+    pub async fn proxy<A: Stream, B: Stream>(
+        a: A,
+        b: B,
+        options: SessionOptions,
+    ) -> Result<Traffic> {
+        options.bound_in_flight(&a);
+        options.bound_in_flight(&b);
+        pump(
+            a,
+            b,
+            options.copier(vec![], true, 0, 0),
+            options.copier(vec![], false, 0, 0),
+        )
+        .await
+    }
+}
+
+// What the inbound side rejected the tunnel for
+pub fn rejection(reason: u8) -> TunnelError {
+    match reason {
+        REASON_BANNED => TunnelError::RejectedBanned,
+        REASON_ROUTE_FULL => TunnelError::RejectedRouteFull,
+        REASON_DRAINING => TunnelError::RejectedDraining,
+        REASON_OUTSIDE_SCHEDULE => TunnelError::RejectedOutsideSchedule,
+        REASON_CIPHER_MISMATCH => TunnelError::RejectedCipherMismatch,
+        REASON_TICKET_REJECTED => TunnelError::RejectedTicket,
+        REASON_UNKNOWN_SERVICE => TunnelError::RejectedUnknownService,
+        // Version 1 inbound sides only reject mismatching secrets
+        _ => TunnelError::SecretRejected,
+    }
+}
+
+// Runs the inbound machine until its next event, sending what it queues
+async fn accept<S: Stream>(
+    stream: &mut S,
+    machine: &mut InboundMachine,
+) -> std::io::Result<AcceptEvent> {
+    loop {
+        flush(stream, || machine.poll_transmit()).await?;
+        if let Some(event) = machine.poll_event() {
+            return Ok(event);
+        }
+        let mut bytes = vec![0u8; machine.wants()];
+        stream.read_exact(&mut bytes).await?;
+        machine.handle_input(&bytes);
+    }
+}
+
+async fn flush<S: Stream>(
+    stream: &mut S,
+    mut transmit: impl FnMut() -> Option<Vec<u8>>,
+) -> std::io::Result<()> {
+    while let Some(bytes) = transmit() {
+        stream.write_all(&bytes).await?;
+    }
+    Ok(())
+}
+
+async fn receive_nonce<S: Stream>(
+    stream: &mut S,
+    peer: IpAddr,
+    timeouts: HandshakeTimeouts,
+) -> Result<[u8; NONCE_LEN]> {
+    let mut nonce = [0u8; NONCE_LEN];
+    match timeout(timeouts.nonce, stream.read_exact(&mut nonce)).await {
+        Ok(Ok(_)) => Ok(nonce),
+        Ok(Err(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+            Err(TunnelError::NonceEarlyEOF.into())
+        }
+        Ok(Err(e)) => Err(e.into()),
+        Err(_) => Err(TunnelError::Timeout(peer).into()),
+    }
+}
+
+// Sends this side's end-to-end nonce and receives the far end's, returned as (read, write)
+async fn exchange_nonces<S: Stream>(
+    stream: &mut S,
+) -> Result<([u8; END_TO_END_NONCE_LEN], [u8; END_TO_END_NONCE_LEN])> {
+    let mut write = [0u8; END_TO_END_NONCE_LEN];
+    rand::thread_rng().fill(&mut write);
+    stream.write_all(&write).await?;
+    let mut read = [0u8; END_TO_END_NONCE_LEN];
+    stream.read_exact(&mut read).await?;
+    Ok((read, write))
+}
+
+// The records or frames of the session's cipher, if it has any, the stream back otherwise
+fn layer<S: Stream>(keys: &SessionKeys, stream: S) -> Result<Box<dyn Stream>, S> {
+    match keys.seal(stream) {
+        Ok(sealed) => Ok(Box::new(sealed)),
+        Err(stream) => keys
+            .frame(stream)
+            .map(|framed| Box::new(framed) as Box<dyn Stream>),
+    }
+}
+
+fn chain<const N: usize>(keystreams: [Option<Keystream>; N]) -> Vec<Keystream> {
+    keystreams.into_iter().flatten().collect()
+}
+
+async fn pump_sides<A: Stream, B: Stream>(
+    a: Side<A>,
+    b: Side<B>,
+    a_to_b: CipherCopier,
+    b_to_a: CipherCopier,
+) -> Result<Traffic> {
+    match (a, b) {
+        (Side::Plain(a), Side::Plain(b)) => pump(a, b, a_to_b, b_to_a).await,
+        (Side::Plain(a), Side::Layered(b)) => pump(a, b, a_to_b, b_to_a).await,
+        (Side::Layered(a), Side::Plain(b)) => pump(a, b, a_to_b, b_to_a).await,
+        (Side::Layered(a), Side::Layered(b)) => pump(a, b, a_to_b, b_to_a).await,
+    }
+}
+
+// Copy both ways between the streams
+async fn pump<A: Stream, B: Stream>(
+    a: A,
+    b: B,
+    a_to_b: CipherCopier,
+    b_to_a: CipherCopier,
+) -> Result<Traffic> {
+    // Plain TCP sockets can be handed over to the io_uring threads
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    let (a, b, a_to_b, b_to_a) = match crate::relay::uring::offload(a, b, a_to_b, b_to_a) {
+        Ok(session) => return session.await,
+        Err(parts) => *parts,
+    };
+
+    let (a_read, a_write) = split(a);
+    let (b_read, b_write) = split(b);
+
+    // Both directions are polled by the session's own task. Each one passes its EOF along
+    // and the other keeps going until its own, a failing one drops the other and with it
+    // both streams.
+    let (a_to_b, b_to_a) =
+        tokio::try_join!(a_to_b.copy(a_read, b_write), b_to_a.copy(b_read, a_write))?;
+    Ok(Traffic { a_to_b, b_to_a })
+}
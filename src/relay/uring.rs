@@ -0,0 +1,213 @@
+use crate::{
+    relay::{
+        copier::{within, CipherCopier},
+        tunnel::Traffic,
+    },
+    transport::{Stream, Transport},
+};
+use anyhow::Result;
+use log::{info, warn};
+use std::{
+    any::Any,
+    future::Future,
+    io,
+    net::Shutdown,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc as std_mpsc, OnceLock,
+    },
+    thread,
+};
+use tokio::{
+    net::TcpStream,
+    sync::{mpsc, oneshot},
+};
+use tokio_uring::buf::IoBuf;
+
+const LOG_TARGET: &str = "uring";
+
+// Streams and copiers of a session that stays on epoll
+type Parts<A, B> = (A, B, CipherCopier, CipherCopier);
+
+// A session between two plain TCP sockets, copied by an io_uring thread
+struct Job {
+    a: std::net::TcpStream,
+    b: std::net::TcpStream,
+    a_to_b: CipherCopier,
+    b_to_a: CipherCopier,
+    reply: oneshot::Sender<Result<Traffic>>,
+}
+
+// One io_uring runtime per thread, sessions are spread round robin
+struct Pool {
+    workers: Vec<mpsc::UnboundedSender<Job>>,
+    next: AtomicUsize,
+}
+
+static POOL: OnceLock<Option<Pool>> = OnceLock::new();
+
+// Started on the first session, None if the kernel doesn't allow io_uring
+fn pool() -> Option<&'static Pool> {
+    POOL.get_or_init(start_pool).as_ref()
+}
+
+fn start_pool() -> Option<Pool> {
+    let threads = thread::available_parallelism().map_or(1, usize::from);
+    let mut workers = Vec::with_capacity(threads);
+
+    for idx in 0..threads {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let (ready_sender, ready) = std_mpsc::sync_channel(1);
+        let spawned = thread::Builder::new()
+            .name(format!("veloxid-uring-{}", idx))
+            .spawn(move || {
+                let runtime = match tokio_uring::Runtime::new(&tokio_uring::builder()) {
+                    Ok(runtime) => runtime,
+                    Err(e) => {
+                        let _ = ready_sender.send(Err(e));
+                        return;
+                    }
+                };
+                let _ = ready_sender.send(Ok(()));
+                runtime.block_on(serve(receiver));
+            });
+
+        let started = match spawned {
+            Ok(_) => ready
+                .recv()
+                .unwrap_or_else(|_| Err(io::ErrorKind::Other.into())),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = started {
+            warn!(target: LOG_TARGET, "io_uring is not available, staying on epoll: {}", e);
+            return None;
+        }
+        workers.push(sender);
+    }
+
+    info!(target: LOG_TARGET, "Copying plain TCP sessions on {} io_uring threads", threads);
+    Some(Pool {
+        workers,
+        next: AtomicUsize::new(0),
+    })
+}
+
+async fn serve(mut receiver: mpsc::UnboundedReceiver<Job>) {
+    while let Some(job) = receiver.recv().await {
+        tokio_uring::spawn(run(job));
+    }
+}
+
+async fn run(job: Job) {
+    let a = tokio_uring::net::TcpStream::from_std(job.a);
+    let b = tokio_uring::net::TcpStream::from_std(job.b);
+
+    // Dropping the other direction on failure tears the session down, so does dropping both
+    // once the session is killed or expires and nobody waits for the reply anymore
+    let mut reply = job.reply;
+    let copy = futures::future::try_join(copy(&a, &b, job.a_to_b), copy(&b, &a, job.b_to_a));
+    let result = tokio::select! {
+        result = copy => Some(result.map(|(a_to_b, b_to_a)| Traffic { a_to_b, b_to_a })),
+        _ = reply.closed() => None,
+    };
+    // Reads still in flight keep the sockets open until they complete, which this makes
+    // them do
+    let _ = a.shutdown(Shutdown::Both);
+    let _ = b.shutdown(Shutdown::Both);
+    if let Some(result) = result {
+        let _ = reply.send(result);
+    }
+}
+
+// Same as CipherCopier::copy, with the buffer owned by the ring while in flight
+async fn copy(
+    reader: &tokio_uring::net::TcpStream,
+    writer: &tokio_uring::net::TcpStream,
+    copier: CipherCopier,
+) -> Result<u64> {
+    let mut parts = copier.into_parts();
+    let mut buffer = Vec::with_capacity(parts.buffer_size);
+
+    let mut total = 0u64;
+    loop {
+        // Read
+        buffer.clear();
+        let (read, returned) = reader.read(buffer).await;
+        buffer = returned;
+        let n = read?;
+        if n == 0 {
+            // EOF: the other direction keeps running
+            writer.shutdown(Shutdown::Write)?;
+            return Ok(total);
+        }
+
+        // Apply keystreams
+        for cipher in &mut parts.ciphers {
+            cipher.apply_keystream(&mut buffer[..n]);
+        }
+
+        // Write
+        // A stalled write is dropped with the ring still owning the buffer, the session ends
+        let (written, returned) = within(parts.write_timeout, async {
+            Ok(writer.write_all(buffer.slice(..n)).await)
+        })
+        .await?;
+        buffer = returned.into_inner();
+        written?;
+        total += n as u64;
+        if let Some(counter) = &parts.counter {
+            counter.fetch_add(n as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+// Hands the session over to the io_uring threads if both sides are plain TCP sockets
+// and nothing is tapped, otherwise the parts are given back
+pub fn offload<A: Stream, B: Stream>(
+    a: A,
+    b: B,
+    a_to_b: CipherCopier,
+    b_to_a: CipherCopier,
+) -> Result<impl Future<Output = Result<Traffic>>, Box<Parts<A, B>>> {
+    let eligible = is_tcp(&a) && is_tcp(&b) && !a_to_b.is_tapped() && !b_to_a.is_tapped();
+    let Some(pool) = pool().filter(|_| eligible) else {
+        return Err(Box::new((a, b, a_to_b, b_to_a)));
+    };
+
+    Ok(async move {
+        let (reply, result) = oneshot::channel();
+        let job = Job {
+            a: into_std(a)?,
+            b: into_std(b)?,
+            a_to_b,
+            b_to_a,
+            reply,
+        };
+        let idx = pool.next.fetch_add(1, Ordering::Relaxed) % pool.workers.len();
+        pool.workers[idx]
+            .send(job)
+            .map_err(|_| io::Error::other("io_uring thread is gone"))?;
+        result.await?
+    })
+}
+
+fn is_tcp<S: Stream>(stream: &S) -> bool {
+    let stream: &dyn Any = stream;
+    stream.is::<TcpStream>() || matches!(stream.downcast_ref(), Some(Transport::Tcp(_)))
+}
+
+fn into_std<S: Stream>(stream: S) -> io::Result<std::net::TcpStream> {
+    let stream: Box<dyn Any> = Box::new(stream);
+    let stream = match stream.downcast::<TcpStream>() {
+        Ok(stream) => *stream,
+        Err(stream) => match stream.downcast::<Transport>().map(|t| *t) {
+            Ok(Transport::Tcp(stream)) => stream,
+            _ => unreachable!("checked by is_tcp"),
+        },
+    };
+
+    // The ring waits for readiness itself, nonblocking sockets would fail with EAGAIN
+    let stream = stream.into_std()?;
+    stream.set_nonblocking(false)?;
+    Ok(stream)
+}
@@ -0,0 +1,95 @@
+use crate::{
+    admin::RouteControl,
+    config::VeloxidConfig,
+    relay::connection::{self, ConnectionData},
+    table,
+};
+use anyhow::Result;
+use log::{error, info, warn};
+use std::collections::HashMap;
+
+const LOG_TARGET: &str = "reload";
+
+// Apply a changed config file to the running endpoints and routes. Listeners of inbound
+// endpoints follow address changes, and routes changing size start or retire workers on the
+// listeners they have. Anything else needs a restart.
+pub async fn reload(
+    path: &str,
+    endpoints: &HashMap<String, ConnectionData>,
+    routes: &[RouteControl],
+) -> Result<()> {
+    let mut config = VeloxidConfig::load(path)?;
+    info!(target: LOG_TARGET, "Reloading '{}'", path);
+    table::add_exports(&mut config)?;
+    table::add_agent(&mut config)?;
+
+    for (name, data) in endpoints {
+        // Imported from the relay's route table, not part of the file
+        if name.starts_with(table::IMPORT_PREFIX) {
+            continue;
+        }
+        let Some(endpoint) = config.endpoints.get(name) else {
+            warn!(target: LOG_TARGET, "'{}' was removed, kept until restart", name);
+            continue;
+        };
+        let ConnectionData::Inbound { listener, .. } = data else {
+            continue;
+        };
+
+        let addr = match connection::endpoint_addr(endpoint) {
+            Ok(addr) => addr,
+            Err(e) => {
+                error!(target: LOG_TARGET, "'{}': {}", name, e);
+                continue;
+            }
+        };
+        let old = listener.addr();
+        if addr == old {
+            continue;
+        }
+        match listener.rebind_to(addr).await {
+            Ok(()) => info!(target: LOG_TARGET, "'{}' moved from {} to {}", name, old, addr),
+            Err(e) => error!(
+                target: LOG_TARGET,
+                "'{}' couldn't move to {}, staying on {}: {}", name, addr, old, e
+            ),
+        }
+    }
+
+    for name in config.endpoints.keys() {
+        if !endpoints.contains_key(name) {
+            warn!(target: LOG_TARGET, "'{}' is new, it needs a restart", name);
+        }
+    }
+
+    // Routes are told apart by name and endpoints, the sessions they run are left alone
+    for (idx, route) in config.routes.iter().enumerate() {
+        let label = route.label(idx);
+        let running = routes
+            .iter()
+            .find(|r| r.name == label && r.endpoints == route.endpoints);
+        let Some(running) = running else {
+            warn!(target: LOG_TARGET, "'{}' is new, it needs a restart", label);
+            continue;
+        };
+        let (old, new) = (running.scaling.size(), route.workers());
+        if old != new {
+            running.scaling.resize(new.0, new.1);
+            info!(
+                target: LOG_TARGET,
+                "'{}' resized from {} to {} workers",
+                label,
+                size(old),
+                size(new)
+            );
+        }
+    }
+    Ok(())
+}
+
+fn size((min, max): (usize, usize)) -> String {
+    match min == max {
+        true => min.to_string(),
+        false => format!("{}-{}", min, max),
+    }
+}
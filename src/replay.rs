@@ -0,0 +1,143 @@
+use crate::tap::Record;
+use anyhow::{anyhow, Context, Result};
+use std::{collections::BTreeMap, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    task,
+    time::{sleep, timeout},
+};
+
+// Time the target gets to finish answering once everything is sent
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub const USAGE: &str =
+    "veloxid replay <capture.vtap> <host:port> [--session <id>] [--reverse] [--fast]";
+
+// Replays one side of the sessions of a plaintext capture against a target, to reproduce what
+// a tunneled application was sent. The other side's answers are read and counted.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ReplayArgs {
+    pub file: String,
+    pub target: String,
+    // Every session of the capture, one after another, when unset
+    pub session: Option<u64>,
+    // Send the B->A side instead, for routes listing the client's endpoint second
+    pub reverse: bool,
+    // Send without the pauses of the capture
+    pub fast: bool,
+}
+
+impl ReplayArgs {
+    // The arguments after "replay"
+    pub fn parse(args: &[String]) -> Result<Self> {
+        let mut positional = Vec::new();
+        let (mut session, mut reverse, mut fast) = (None, false, false);
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--session" => {
+                    let id = args
+                        .next()
+                        .ok_or_else(|| anyhow!("--session needs an id"))?;
+                    session = Some(id.parse().context("--session")?);
+                }
+                "--reverse" => reverse = true,
+                "--fast" => fast = true,
+                flag if flag.starts_with("--") => return Err(anyhow!("Unknown option '{}'", flag)),
+                _ => positional.push(arg.clone()),
+            }
+        }
+        let [file, target] =
+            <[String; 2]>::try_from(positional).map_err(|_| anyhow!("Usage: {}", USAGE))?;
+        Ok(Self {
+            file,
+            target,
+            session,
+            reverse,
+            fast,
+        })
+    }
+}
+
+// Bytes of a replayed session
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Replayed {
+    pub sent: u64,
+    pub received: u64,
+}
+
+// The records of each session, by session id
+pub fn sessions(records: Vec<Record>) -> BTreeMap<u64, Vec<Record>> {
+    let mut sessions: BTreeMap<u64, Vec<Record>> = BTreeMap::new();
+    for record in records {
+        sessions.entry(record.session).or_default().push(record);
+    }
+    sessions
+}
+
+// Send a session's side to the target, paced like the capture unless fast
+pub async fn replay(
+    records: &[Record],
+    target: &str,
+    reverse: bool,
+    fast: bool,
+) -> Result<Replayed> {
+    let stream = TcpStream::connect(target)
+        .await
+        .with_context(|| format!("Couldn't connect to '{}'", target))?;
+    let (mut reader, mut writer) = stream.into_split();
+    let answers = task::spawn(async move {
+        let mut buffer = vec![0u8; 8192];
+        let mut received = 0u64;
+        while let Ok(Ok(n)) = timeout(DRAIN_TIMEOUT, reader.read(&mut buffer)).await {
+            if n == 0 {
+                break;
+            }
+            received += n as u64;
+        }
+        received
+    });
+
+    let mut sent = 0u64;
+    let mut last = None;
+    for record in records.iter().filter(|record| record.a_to_b != reverse) {
+        if let (false, Some(last)) = (fast, last) {
+            sleep(record.time.duration_since(last).unwrap_or_default()).await;
+        }
+        last = Some(record.time);
+        writer.write_all(&record.data).await?;
+        sent += record.data.len() as u64;
+    }
+    writer.shutdown().await?;
+
+    Ok(Replayed {
+        sent,
+        received: answers.await?,
+    })
+}
+
+// The "replay" subcommand
+pub async fn run(args: &[String]) -> Result<()> {
+    let args = ReplayArgs::parse(args)?;
+    let file = std::fs::File::open(&args.file)
+        .with_context(|| format!("Couldn't open '{}'", args.file))?;
+    let records = crate::tap::read(std::io::BufReader::new(file))
+        .with_context(|| format!("Couldn't read '{}'", args.file))?;
+
+    let mut sessions = sessions(records);
+    if let Some(id) = args.session {
+        sessions.retain(|session, _| *session == id);
+        if sessions.is_empty() {
+            return Err(anyhow!("No session {} in '{}'", id, args.file));
+        }
+    }
+    for (id, records) in sessions {
+        let replayed = replay(&records, &args.target, args.reverse, args.fast).await?;
+        println!(
+            "Session {}: sent {} bytes, received {} bytes",
+            id, replayed.sent, replayed.received
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,263 @@
+use crate::{
+    config::{SandboxConfig, SeccompMode},
+    error::StartupError,
+};
+use anyhow::Result;
+use landlock::{
+    path_beneath_rules, Access, AccessFs, BitFlags, Ruleset, RulesetAttr, RulesetCreatedAttr,
+    RulesetStatus, ABI,
+};
+use log::{info, warn};
+use seccompiler::{
+    BpfProgram, SeccompAction, SeccompCmpArgLen, SeccompCmpOp, SeccompCondition, SeccompFilter,
+    SeccompRule, TargetArch,
+};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Barrier},
+};
+use tokio::{runtime::Handle, task};
+
+// System files still read after startup, by name resolution on reload among others, and the
+// descriptors counted against [limits]
+const SYSTEM_READ: &[&str] = &["/etc", "/usr", "/lib", "/lib64", "/proc/self/fd"];
+
+// Landlock ABI asked for, older kernels enforce what they know of it
+const ABI_VERSION: ABI = ABI::V5;
+
+// Restrict the process once every listener is bound and privileges are dropped, so a parser
+// bug can't be turned into files read or programs run. Landlock covers the runtime's threads
+// and the ones they start, seccomp every thread.
+pub async fn apply(config: &SandboxConfig, config_path: &str) -> Result<()> {
+    let failed = |e: anyhow::Error| StartupError::Sandbox(format!("{:#}", e));
+    if config.landlock == Some(true) {
+        let rules = FileRules::new(config, config_path);
+        match restrict_runtime(rules).await.map_err(failed)? {
+            RulesetStatus::FullyEnforced => info!("Landlock enforced"),
+            RulesetStatus::PartiallyEnforced => {
+                warn!("Landlock only partly enforced, the kernel is older than the rules")
+            }
+            RulesetStatus::NotEnforced => {
+                warn!("Landlock isn't supported by the kernel, files aren't restricted")
+            }
+        }
+    }
+    if let Some(mode) = config.seccomp {
+        filter_syscalls(mode).map_err(failed)?;
+        info!("seccomp filter installed, other syscalls are {:?}", mode);
+    }
+    Ok(())
+}
+
+// Paths under which files can be read, and written
+#[derive(Clone)]
+struct FileRules {
+    read: Vec<PathBuf>,
+    write: Vec<PathBuf>,
+}
+
+impl FileRules {
+    fn new(config: &SandboxConfig, config_path: &str) -> Self {
+        // The config and its includes, read again on reload
+        let config_dir = match Path::new(config_path).parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_owned(),
+            _ => PathBuf::from("."),
+        };
+        let system = SYSTEM_READ
+            .iter()
+            .map(PathBuf::from)
+            .filter(|path| path.exists());
+        let mut read: Vec<PathBuf> = system.chain([config_dir]).collect();
+        read.extend(config.read.iter().map(PathBuf::from));
+        Self {
+            read,
+            write: config.write.iter().map(PathBuf::from).collect(),
+        }
+    }
+
+    // Restrict the calling thread and those it starts later. Nothing is executable.
+    fn restrict_thread(&self) -> Result<RulesetStatus> {
+        let read: BitFlags<AccessFs> = AccessFs::ReadFile | AccessFs::ReadDir;
+        let write = AccessFs::from_write(ABI_VERSION) | read;
+        let status = Ruleset::default()
+            .handle_access(AccessFs::from_all(ABI_VERSION))?
+            .create()?
+            .add_rules(path_beneath_rules(&self.read, read))?
+            .add_rules(path_beneath_rules(&self.write, write))?
+            .restrict_self()?;
+        Ok(status.ruleset)
+    }
+}
+
+// The runtime's workers are already running, each restricts itself from a task holding it
+// until every worker has, so no worker takes two of them. Then the thread running this.
+async fn restrict_runtime(rules: FileRules) -> Result<RulesetStatus> {
+    let workers = Handle::current().metrics().num_workers();
+    let barrier = Arc::new(Barrier::new(workers));
+    let tasks: Vec<_> = (0..workers)
+        .map(|_| {
+            let (rules, barrier) = (rules.clone(), barrier.clone());
+            task::spawn(async move {
+                let status = rules.restrict_thread();
+                barrier.wait();
+                status
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await??;
+    }
+    rules.restrict_thread()
+}
+
+fn filter_syscalls(mode: SeccompMode) -> Result<()> {
+    let action = match mode {
+        SeccompMode::Deny => SeccompAction::Errno(libc::EPERM as u32),
+        SeccompMode::Kill => SeccompAction::KillProcess,
+        SeccompMode::Log => SeccompAction::Log,
+    };
+    let arch: TargetArch = std::env::consts::ARCH.try_into()?;
+
+    let mut rules: BTreeMap<i64, Vec<SeccompRule>> = SYSCALLS
+        .iter()
+        .chain(ARCH_SYSCALLS)
+        .map(|&syscall| (syscall, Vec::new()))
+        .collect();
+    // Threads, never processes
+    let thread = libc::CLONE_THREAD as u64;
+    let flags = SeccompCondition::new(
+        0,
+        SeccompCmpArgLen::Qword,
+        SeccompCmpOp::MaskedEq(thread),
+        thread,
+    )?;
+    rules.insert(libc::SYS_clone, vec![SeccompRule::new(vec![flags])?]);
+    let allowed = SeccompFilter::new(rules, action, SeccompAction::Allow, arch)?;
+
+    // The flags of clone3 can't be checked, its absence makes glibc fall back to clone. The
+    // strictest answer of the two filters is the one taken.
+    let no_clone3 = SeccompFilter::new(
+        BTreeMap::from([(libc::SYS_clone3, Vec::new())]),
+        SeccompAction::Allow,
+        SeccompAction::Errno(libc::ENOSYS as u32),
+        arch,
+    )?;
+
+    for filter in [no_clone3, allowed] {
+        let program: BpfProgram = filter.try_into()?;
+        seccompiler::apply_filter_all_threads(&program)?;
+    }
+    Ok(())
+}
+
+// Syscalls of the relay once it is running: memory, threads, time, the sockets and the few
+// files it writes. Neither exec nor fork is among them.
+const SYSCALLS: &[i64] = &[
+    // Memory
+    libc::SYS_brk,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    // Threads and signals
+    libc::SYS_clone3,
+    libc::SYS_futex,
+    libc::SYS_set_robust_list,
+    libc::SYS_rseq,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_sigaltstack,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_restart_syscall,
+    libc::SYS_tgkill,
+    libc::SYS_prctl,
+    libc::SYS_getpid,
+    libc::SYS_gettid,
+    libc::SYS_uname,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    // Time and randomness
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_getres,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep,
+    libc::SYS_gettimeofday,
+    libc::SYS_getrandom,
+    // Readiness
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_epoll_pwait2,
+    libc::SYS_eventfd2,
+    libc::SYS_ppoll,
+    libc::SYS_pipe2,
+    libc::SYS_io_uring_setup,
+    libc::SYS_io_uring_enter,
+    libc::SYS_io_uring_register,
+    // Sockets
+    libc::SYS_socket,
+    libc::SYS_socketpair,
+    libc::SYS_bind,
+    libc::SYS_listen,
+    libc::SYS_accept4,
+    libc::SYS_connect,
+    libc::SYS_shutdown,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_getsockopt,
+    libc::SYS_setsockopt,
+    libc::SYS_sendto,
+    libc::SYS_recvfrom,
+    libc::SYS_sendmsg,
+    libc::SYS_recvmsg,
+    libc::SYS_sendmmsg,
+    libc::SYS_recvmmsg,
+    // Files
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_openat,
+    libc::SYS_close,
+    libc::SYS_lseek,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_faccessat,
+    libc::SYS_faccessat2,
+    libc::SYS_readlinkat,
+    libc::SYS_getdents64,
+    libc::SYS_prlimit64,
+    libc::SYS_fcntl,
+    libc::SYS_ioctl,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_ftruncate,
+    libc::SYS_renameat2,
+    libc::SYS_unlinkat,
+];
+
+// Older syscalls glibc still makes on x86_64
+#[cfg(target_arch = "x86_64")]
+const ARCH_SYSCALLS: &[i64] = &[
+    libc::SYS_accept,
+    libc::SYS_epoll_wait,
+    libc::SYS_poll,
+    libc::SYS_access,
+    libc::SYS_stat,
+    libc::SYS_lstat,
+    libc::SYS_readlink,
+    libc::SYS_rename,
+    libc::SYS_unlink,
+];
+
+#[cfg(not(target_arch = "x86_64"))]
+const ARCH_SYSCALLS: &[i64] = &[];
@@ -0,0 +1,220 @@
+use futures::future::BoxFuture;
+use log::info;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use tokio::{
+    sync::{Notify, OwnedSemaphorePermit, Semaphore},
+    task::JoinSet,
+    time::{sleep, Duration, Instant},
+};
+
+// Looked at again at least this often, and whenever a worker gets busy
+const SCALE_INTERVAL: Duration = Duration::from_secs(1);
+// Spare workers idle this long are retired, one at a time
+pub const SCALE_DOWN_AFTER: Duration = Duration::from_secs(30);
+
+// Workers of a route, between min_size and max_size, both the size of routes that aren't
+// autoscaled. A worker is busy once it holds a connection until the session is handed off, or
+// while it waits for a session slot that would come with another worker. One more is started
+// whenever none is left idle, and one is retired while more than one has stayed idle for
+// SCALE_DOWN_AFTER. Resizing starts or retires workers at once, retired ones finish handing off
+// their connection first.
+#[derive(Clone)]
+pub struct Scaling {
+    inner: Arc<Inner>,
+    scale_down_after: Duration,
+}
+
+struct Inner {
+    min: AtomicUsize,
+    max: AtomicUsize,
+    workers: AtomicUsize,
+    busy: AtomicUsize,
+    // Asked of idle workers, taken by the first one to see it
+    retiring: AtomicUsize,
+    retire: Notify,
+    // Some worker got busy
+    pressure: Notify,
+    // Session slots of the route when they follow the workers, max_sessions being unset
+    slots: Option<Arc<Semaphore>>,
+}
+
+// Counts its worker as busy until dropped
+pub struct Busy {
+    inner: Arc<Inner>,
+}
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        self.inner.busy.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Scaling {
+    pub fn new(min: usize, max: usize, slots: Option<Arc<Semaphore>>) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                min: AtomicUsize::new(min),
+                max: AtomicUsize::new(max),
+                workers: AtomicUsize::new(0),
+                busy: AtomicUsize::new(0),
+                retiring: AtomicUsize::new(0),
+                retire: Notify::new(),
+                pressure: Notify::new(),
+                slots,
+            }),
+            scale_down_after: SCALE_DOWN_AFTER,
+        }
+    }
+
+    // Retire spare workers sooner, for tests
+    pub fn scale_down_after(mut self, after: Duration) -> Self {
+        self.scale_down_after = after;
+        self
+    }
+
+    pub fn workers(&self) -> usize {
+        self.inner.workers.load(Ordering::Relaxed)
+    }
+
+    pub fn busy(&self) -> usize {
+        self.inner.busy.load(Ordering::Relaxed)
+    }
+
+    // Bounds of the workers, the same for routes that aren't autoscaled
+    pub fn size(&self) -> (usize, usize) {
+        let inner = &self.inner;
+        (
+            inner.min.load(Ordering::Relaxed),
+            inner.max.load(Ordering::Relaxed),
+        )
+    }
+
+    // New bounds, applied at once
+    pub fn resize(&self, min: usize, max: usize) {
+        self.inner.min.store(min, Ordering::Relaxed);
+        self.inner.max.store(max, Ordering::Relaxed);
+        self.inner.pressure.notify_one();
+    }
+
+    // The worker has a connection to set a session up with
+    pub fn working(&self) -> Busy {
+        self.inner.busy.fetch_add(1, Ordering::Relaxed);
+        self.inner.pressure.notify_one();
+        Busy {
+            inner: self.inner.clone(),
+        }
+    }
+
+    // The worker waits for a session slot, which another worker would add
+    pub fn waiting_for_slot(&self) -> Option<Busy> {
+        self.inner.slots.as_ref().map(|_| self.working())
+    }
+
+    // Resolves once the worker is to stop, for idle workers to wait on
+    pub async fn retired(&self) {
+        loop {
+            // Asked before the worker got here, or while it waits
+            let notified = self.inner.retire.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            let taken =
+                self.inner
+                    .retiring
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+            if taken.is_ok() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    // The session slot of a retired worker goes with it when the slots follow the workers
+    pub fn release(&self, permit: OwnedSemaphorePermit) {
+        match &self.inner.slots {
+            Some(_) => permit.forget(),
+            None => drop(permit),
+        }
+    }
+
+    // Runs the workers made by spawn, which gets the index of each, for as long as the route
+    pub async fn run<F>(self, log_target: String, mut spawn: F)
+    where
+        F: FnMut(usize) -> BoxFuture<'static, ()>,
+    {
+        let inner = &self.inner;
+        let mut workers = JoinSet::new();
+        let mut next_idx = 0;
+        let mut start = |workers: &mut JoinSet<()>| {
+            workers.spawn(spawn(next_idx));
+            next_idx += 1;
+            inner.workers.fetch_add(1, Ordering::Relaxed);
+        };
+        for _ in 0..inner.min.load(Ordering::Relaxed) {
+            start(&mut workers);
+        }
+
+        let mut spare_since = None;
+        // Retirements asked for, the other workers only stop with the route
+        let mut asked = 0;
+        loop {
+            tokio::select! {
+                _ = inner.pressure.notified() => {}
+                _ = sleep(SCALE_INTERVAL) => {}
+                Some(_) = workers.join_next() => {
+                    if asked == 0 {
+                        return;
+                    }
+                    asked -= 1;
+                    let count = inner.workers.fetch_sub(1, Ordering::Relaxed) - 1;
+                    info!(target: &log_target, "Scaled down to {} workers", count);
+                }
+            }
+            let count = inner.workers.load(Ordering::Relaxed);
+            let busy = inner.busy.load(Ordering::Relaxed);
+            let (min, max) = self.size();
+
+            // Resized, or no idle worker left
+            let wanted = match count < min {
+                true => min - count,
+                false => (busy >= count && count < max) as usize,
+            };
+            if wanted > 0 {
+                if let Some(slots) = &inner.slots {
+                    slots.add_permits(wanted);
+                }
+                for _ in 0..wanted {
+                    start(&mut workers);
+                }
+                spare_since = None;
+                info!(target: &log_target, "Scaled up to {} workers", count + wanted);
+                continue;
+            }
+
+            // Resized below the workers, retired as soon as they are idle
+            let excess = count.saturating_sub(max).saturating_sub(asked);
+            if excess > 0 {
+                asked += excess;
+                inner.retiring.fetch_add(excess, Ordering::Relaxed);
+                inner.retire.notify_waiters();
+                spare_since = None;
+                continue;
+            }
+
+            // More than one idle, and none being retired yet
+            if count.saturating_sub(busy) < 2 || count <= min || asked > 0 {
+                spare_since = None;
+                continue;
+            }
+            let since = *spare_since.get_or_insert_with(Instant::now);
+            if since.elapsed() >= self.scale_down_after {
+                asked += 1;
+                inner.retiring.fetch_add(1, Ordering::Relaxed);
+                inner.retire.notify_waiters();
+                spare_since = None;
+            }
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use crate::error::ConfigError;
+use chrono::{DateTime, Datelike, NaiveTime, TimeZone, Timelike, Weekday};
+
+// Time windows a route accepts clients in, in local time
+// Format: comma separated windows of "[Day[-Day]] HH:MM-HH:MM"
+// e.g. "Mon-Fri 08:00-18:00, Sat 10:00-14:00" or "22:00-06:00" (every day, over midnight)
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct Schedule {
+    windows: Vec<Window>,
+}
+
+#[derive(Debug, Clone)]
+struct Window {
+    days: [bool; 7], // Indexed by days from Monday
+    start: NaiveTime,
+    end: NaiveTime, // A window with end <= start continues on the next day
+}
+
+impl Schedule {
+    pub fn is_open<Tz: TimeZone>(&self, now: &DateTime<Tz>) -> bool {
+        let day = now.weekday().num_days_from_monday() as usize;
+        let yesterday = (day + 6) % 7;
+        let time = now.time().with_nanosecond(0).unwrap_or(now.time());
+
+        self.windows.iter().any(|w| match w.start < w.end {
+            true => w.days[day] && w.start <= time && time < w.end,
+            false => (w.days[day] && time >= w.start) || (w.days[yesterday] && time < w.end),
+        })
+    }
+}
+
+impl TryFrom<String> for Schedule {
+    type Error = ConfigError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let windows = value
+            .split(',')
+            .map(|w| {
+                parse_window(w.trim()).ok_or(ConfigError::InvalidSchedule(w.trim().to_owned()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { windows })
+    }
+}
+
+fn parse_window(window: &str) -> Option<Window> {
+    let (days, times) = match window.split_once(' ') {
+        Some((days, times)) => (parse_days(days)?, times.trim()),
+        None => ([true; 7], window),
+    };
+
+    let (start, end) = times.split_once('-')?;
+    Some(Window {
+        days,
+        start: parse_time(start)?,
+        end: parse_time(end)?,
+    })
+}
+
+fn parse_days(days: &str) -> Option<[bool; 7]> {
+    let (first, last) = match days.split_once('-') {
+        Some((first, last)) => (
+            first.parse::<Weekday>().ok()?,
+            last.parse::<Weekday>().ok()?,
+        ),
+        None => {
+            let day = days.parse::<Weekday>().ok()?;
+            (day, day)
+        }
+    };
+
+    // Ranges may wrap around the week (e.g. "Fri-Mon")
+    let mut result = [false; 7];
+    let mut day = first;
+    loop {
+        result[day.num_days_from_monday() as usize] = true;
+        if day == last {
+            return Some(result);
+        }
+        day = day.succ();
+    }
+}
+
+fn parse_time(time: &str) -> Option<NaiveTime> {
+    match time.trim() {
+        "24:00" => Some(NaiveTime::MIN),
+        time => NaiveTime::parse_from_str(time, "%H:%M").ok(),
+    }
+}
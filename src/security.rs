@@ -0,0 +1,62 @@
+use crate::{events::EventHandler, logfile::LineFile};
+use chrono::{DateTime, Local};
+use log::warn;
+use std::{io, net::IpAddr, time::Duration};
+
+const LOG_TARGET: &str = "security";
+
+// Peers failing to authenticate to this side and bans of the peers of inbound endpoints (the
+// relays outbound ones reach are never banned, so never logged), one line each in a format kept
+// stable for fail2ban's failregex, so the host firewall can drop what the ban list only turns away:
+// <time> veloxid[<pid>]: auth failure from <ip> endpoint="<name>" route="<route>"
+// <time> veloxid[<pid>]: ban of <ip> for <seconds>s endpoint="<name>" route="<route>"
+pub struct SecurityLog {
+    // The operational log when unset
+    file: Option<LineFile>,
+}
+
+impl SecurityLog {
+    pub fn open(path: Option<&str>) -> io::Result<Self> {
+        let file = match path {
+            Some(path) => Some(LineFile::open(path, LOG_TARGET)?),
+            None => None,
+        };
+        Ok(Self { file })
+    }
+
+    fn write(&self, message: &str) {
+        match &self.file {
+            Some(file) => file.write_line(&line(Local::now(), message)),
+            None => warn!(target: LOG_TARGET, "{}", message),
+        }
+    }
+}
+
+// Prefixed like syslog lines, with a timestamp fail2ban recognizes
+pub fn line(time: DateTime<Local>, message: &str) -> String {
+    format!(
+        "{} veloxid[{}]: {}",
+        time.format("%Y-%m-%dT%H:%M:%S%z"),
+        std::process::id(),
+        message
+    )
+}
+
+impl EventHandler for SecurityLog {
+    fn on_auth_failure(&self, route: &str, endpoint: &str, peer: IpAddr) {
+        self.write(&format!(
+            "auth failure from {} endpoint={:?} route={:?}",
+            peer, endpoint, route
+        ));
+    }
+
+    fn on_ban(&self, route: &str, endpoint: &str, peer: IpAddr, length: Duration) {
+        self.write(&format!(
+            "ban of {} for {}s endpoint={:?} route={:?}",
+            peer,
+            length.as_secs(),
+            endpoint,
+            route
+        ));
+    }
+}
@@ -0,0 +1,208 @@
+use crate::{
+    config::{ConnectionType, Direction, Endpoint, VeloxidConfig},
+    connect,
+    error::StartupError,
+    relay::connection::{self, ConnectionData},
+    table,
+};
+use anyhow::{anyhow, Result};
+use log::LevelFilter;
+use rand::Rng;
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+    task::JoinSet,
+    time::{timeout, Duration, Instant},
+};
+
+pub const USAGE: &str = "veloxid selftest";
+
+const LOOPBACK: &str = "127.0.0.1";
+// Sent through each tunnel, and expected back as it was
+const PAYLOAD_LEN: usize = 1024 * 1024;
+const CASE_TIMEOUT: Duration = Duration::from_secs(15);
+
+// A tunnel of the config, tested with both of its sides on loopback
+pub struct Case {
+    pub name: String,
+    pub tunnel: Endpoint,
+}
+
+// The tunnel endpoints by name, and the tunnels of the relay table
+pub fn cases(config: &VeloxidConfig) -> Vec<Case> {
+    let mut names: Vec<&String> = config
+        .endpoints
+        .iter()
+        .filter(|(_, endpoint)| matches!(endpoint.kind, ConnectionType::Tunnel))
+        .map(|(name, _)| name)
+        .collect();
+    names.sort();
+    let mut cases: Vec<Case> = names
+        .into_iter()
+        .map(|name| Case {
+            name: name.clone(),
+            tunnel: side(&config.endpoints[name], 0, Direction::Inbound),
+        })
+        .collect();
+
+    let table_secrets = [
+        ("[control]", config.control.as_ref().map(|c| &c.secret)),
+        ("[relay]", config.relay.as_ref().map(|r| &r.secret)),
+    ];
+    for (name, secret) in table_secrets {
+        if let Some(secret) = secret {
+            let mut tunnel = loopback(0, ConnectionType::Tunnel, Direction::Inbound);
+            tunnel.secret = Some(secret.clone());
+            cases.push(Case {
+                name: name.to_owned(),
+                tunnel,
+            });
+        }
+    }
+    cases
+}
+
+fn loopback(port: u16, kind: ConnectionType, direction: Direction) -> Endpoint {
+    table::endpoint(Some(LOOPBACK.to_owned()), port, kind, direction)
+}
+
+// One side of the tunnel on loopback, with what both sides have to agree on. Transports other
+// than TCP, bonding and end-to-end secrets are left out.
+fn side(tunnel: &Endpoint, port: u16, direction: Direction) -> Endpoint {
+    let mut side = loopback(port, ConnectionType::Tunnel, direction);
+    side.secret = tunnel.secret.clone();
+    side.cipher = tunnel.cipher;
+    side.obfuscation = tunnel.obfuscation;
+    side.padding = tunnel.padding;
+    side.padding_rate = tunnel.padding_rate;
+    side.rekey = tunnel.rekey;
+    side.tickets = tunnel.tickets;
+    side
+}
+
+fn port(data: &ConnectionData) -> u16 {
+    match data {
+        ConnectionData::Inbound { listener, .. } => listener.addr().port(),
+        _ => 0,
+    }
+}
+
+// An echo server, a relay and a connector in this process, like a deployment of the tunnel.
+// Returns the time one session took to send the payload through them and get it back.
+pub async fn run_case(case: &Case) -> Result<Duration> {
+    // Everything is stopped once the case is over, the connector first so it doesn't see the
+    // relay go away
+    let (mut tasks, mut connector) = (JoinSet::new(), JoinSet::new());
+    let echo = TcpListener::bind((LOOPBACK, 0)).await?;
+    let echo_port = echo.local_addr()?.port();
+    tasks.spawn(echo_server(echo));
+
+    let public = loopback(0, ConnectionType::Direct, Direction::Inbound);
+    let public = connection::get_connection_data(&public).await?;
+    let tunnel_in = side(&case.tunnel, 0, Direction::Inbound);
+    let tunnel_in = connection::get_connection_data(&tunnel_in).await?;
+    let tunnel_out = side(&case.tunnel, port(&tunnel_in), Direction::Outbound);
+    let tunnel_out = connection::get_connection_data(&tunnel_out).await?;
+    let target = loopback(echo_port, ConnectionType::Direct, Direction::Outbound);
+    let target = connection::get_connection_data(&target).await?;
+    let public_port = port(&public);
+
+    let enabled = watch::Sender::new(true);
+    let routes = [
+        (&mut tasks, "relay", public, tunnel_in),
+        (&mut connector, "connector", tunnel_out, target),
+    ];
+    for (tasks, role, endpoint_a, endpoint_b) in routes {
+        let ctx = connect::context(&case.tunnel, Default::default());
+        let enabled = enabled.subscribe();
+        let log_target = format!("selftest '{}' {}", case.name, role);
+        tasks.spawn(async move {
+            connection::route(endpoint_a, endpoint_b, ctx, enabled, &log_target).await;
+        });
+    }
+
+    let echoed = timeout(CASE_TIMEOUT, echo_session(public_port)).await;
+    connector.shutdown().await;
+    echoed.map_err(|_| anyhow!("No echo within {:?}", CASE_TIMEOUT))?
+}
+
+async fn echo_server(listener: TcpListener) {
+    let mut sessions = JoinSet::new();
+    while let Ok((mut stream, _)) = listener.accept().await {
+        sessions.spawn(async move {
+            let (mut reader, mut writer) = stream.split();
+            io::copy(&mut reader, &mut writer).await?;
+            writer.shutdown().await
+        });
+    }
+}
+
+async fn echo_session(port: u16) -> Result<Duration> {
+    let mut payload = vec![0u8; PAYLOAD_LEN];
+    rand::thread_rng().fill(&mut payload[..]);
+    let started = Instant::now();
+    let stream = TcpStream::connect((LOOPBACK, port)).await?;
+    let (mut reader, mut writer) = stream.into_split();
+    let sending = async {
+        writer.write_all(&payload).await?;
+        writer.shutdown().await
+    };
+    let mut echoed = Vec::with_capacity(PAYLOAD_LEN);
+    tokio::try_join!(sending, reader.read_to_end(&mut echoed))?;
+    let elapsed = started.elapsed();
+
+    if echoed.len() != payload.len() {
+        return Err(anyhow!(
+            "{} bytes came back of the {} sent",
+            echoed.len(),
+            payload.len()
+        ));
+    }
+    if let Some(at) = echoed.iter().zip(&payload).position(|(a, b)| a != b) {
+        return Err(anyhow!(
+            "What came back differs from what was sent at byte {}",
+            at
+        ));
+    }
+    Ok(elapsed)
+}
+
+// Runs every case and prints how each went, fails if any did. A sanity check after upgrades.
+pub async fn run(args: &[String]) -> Result<()> {
+    if !args.is_empty() {
+        return Err(anyhow!("Usage: {}", USAGE));
+    }
+    let config_path = &std::env::var("VELOXID_CONFIG").unwrap_or("veloxid.toml".to_owned());
+    let mut config = VeloxidConfig::load(config_path).map_err(StartupError::Config)?;
+    table::add_agent(&mut config).map_err(StartupError::Config)?;
+
+    // The results go to stdout, the log to stderr
+    env_logger::builder()
+        .filter_level(LevelFilter::Warn)
+        .target(env_logger::Target::Stderr)
+        .init();
+
+    let cases = cases(&config);
+    if cases.is_empty() {
+        return Err(anyhow!("No tunnels in '{}' to test", config_path));
+    }
+    let mut failed = 0;
+    for case in &cases {
+        let cipher = case.tunnel.cipher.unwrap_or_default();
+        match run_case(case).await {
+            Ok(elapsed) => println!(
+                "'{}' ({:?}): {} bytes echoed in {:.1?}",
+                case.name, cipher, PAYLOAD_LEN, elapsed
+            ),
+            Err(e) => {
+                failed += 1;
+                println!("'{}' ({:?}): failed: {:#}", case.name, cipher, e);
+            }
+        }
+    }
+    match failed {
+        0 => Ok(()),
+        failed => Err(anyhow!("{} of {} tunnels failed", failed, cases.len())),
+    }
+}
@@ -0,0 +1,108 @@
+use crate::{
+    protocol::handshake::{REASON_ROUTE_FULL, REASON_UNKNOWN_SERVICE},
+    relay::{
+        connection::{self, Connection, ConnectionData, RouteContext},
+        tunnel::Tunnel,
+    },
+    transport::Transport,
+};
+use log::{debug, warn};
+use std::{collections::HashMap, sync::Arc};
+use tokio::{
+    sync::{mpsc, Mutex},
+    task,
+};
+
+// Registered tunnels waiting for a worker of their service's routes
+pub const QUEUE_SIZE: usize = 16;
+
+// Authenticated, waiting to be attached
+pub type Registered = Box<Tunnel<Transport>>;
+pub type ServiceQueue = Arc<Mutex<mpsc::Receiver<Registered>>>;
+
+// Services of a registry endpoint, the routes serving each one take its tunnels from the
+// same queue. Connectors announce the service of each tunnel they open.
+#[derive(Clone, Default)]
+pub struct ServiceTable {
+    services: HashMap<String, (mpsc::Sender<Registered>, ServiceQueue)>,
+}
+
+impl ServiceTable {
+    // Queue of the service's tunnels, shared by the routes asking for it
+    pub fn queue(&mut self, service: &str) -> ServiceQueue {
+        let (_, queue) = self.services.entry(service.to_owned()).or_insert_with(|| {
+            let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+            (sender, Arc::new(Mutex::new(receiver)))
+        });
+        queue.clone()
+    }
+
+    // Every service with the tunnels registered and not taken by a worker yet, by name
+    pub fn list(&self) -> Vec<(String, usize)> {
+        let mut services: Vec<_> = self
+            .services
+            .iter()
+            .map(|(name, (sender, _))| (name.clone(), sender.max_capacity() - sender.capacity()))
+            .collect();
+        services.sort();
+        services
+    }
+
+    // Queue the tunnel for its service. Tunnels of unknown services, or of services whose
+    // routes can't keep up, are turned away.
+    fn register(&self, tunnel: Registered, log_target: &str) {
+        let service = tunnel.service().unwrap_or_default().to_owned();
+        let Some((sender, _)) = self.services.get(&service) else {
+            warn!(target: log_target, "No route serves '{}'", service);
+            task::spawn(tunnel.turn_away(REASON_UNKNOWN_SERVICE));
+            return;
+        };
+        match sender.try_send(tunnel) {
+            Ok(()) => debug!(target: log_target, "Registered a tunnel for '{}'", service),
+            Err(e) => {
+                warn!(target: log_target, "Too many tunnels waiting for '{}'", service);
+                task::spawn(e.into_inner().turn_away(REASON_ROUTE_FULL));
+            }
+        }
+    }
+}
+
+// Accept tunnels on a registry endpoint and queue them for their services, with as many
+// handshakes at once as there are workers. Runs until the process exits.
+pub async fn serve(
+    name: String,
+    endpoint: ConnectionData,
+    table: ServiceTable,
+    ctx: RouteContext,
+    size: usize,
+) {
+    let table = Arc::new(table);
+    for worker_idx in 0..size {
+        task::spawn({
+            let (endpoint, table, ctx) = (endpoint.clone(), table.clone(), ctx.clone());
+            let name = name.clone();
+            let log_target = format!("registry '{}' worker #{}", name, worker_idx);
+            async move {
+                loop {
+                    if let Some(budget) = &ctx.budget {
+                        budget.headroom().await;
+                    }
+                    match connection::connect(&endpoint, &ctx, None, &log_target, &name).await {
+                        Ok(Connection::Tunnel(tunnel)) => table.register(tunnel, &log_target),
+                        Ok(_) => {}
+                        Err(e) => {
+                            connection::handle_connection_error(
+                                e,
+                                &endpoint,
+                                &ctx,
+                                &log_target,
+                                &name,
+                            )
+                            .await
+                        }
+                    }
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,222 @@
+use crate::{
+    events::SessionInfo,
+    relay::tunnel::{LiveTraffic, Traffic},
+};
+use dashmap::DashMap;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
+use tokio::{
+    sync::Notify,
+    time::{sleep_until, Duration, Instant},
+};
+
+// A running session, as seen from outside of it
+pub struct ActiveSession {
+    pub info: SessionInfo,
+    pub traffic: LiveTraffic,
+    // When it is closed if it runs that long
+    pub deadline: Option<SystemTime>,
+    kill: Arc<Notify>,
+}
+
+impl ActiveSession {
+    // Bytes transferred so far
+    pub fn traffic(&self) -> Traffic {
+        Traffic {
+            a_to_b: self.traffic.a_to_b.load(Ordering::Relaxed),
+            b_to_a: self.traffic.b_to_a.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn snapshot(&self) -> SessionSnapshot {
+        SessionSnapshot {
+            info: self.info.clone(),
+            traffic: self.traffic(),
+            deadline: self.deadline,
+        }
+    }
+}
+
+// A running session at one point in time
+#[derive(Debug, Clone)]
+pub struct SessionSnapshot {
+    pub info: SessionInfo,
+    pub traffic: Traffic,
+    pub deadline: Option<SystemTime>,
+}
+
+impl SessionSnapshot {
+    pub fn running(&self) -> Duration {
+        let started = self.info.started;
+        SystemTime::now()
+            .duration_since(started)
+            .unwrap_or_default()
+    }
+}
+
+// Sessions over since the start, and their bytes
+#[derive(Default)]
+struct Finished {
+    sessions: AtomicU64,
+    a_to_b: AtomicU64,
+    b_to_a: AtomicU64,
+}
+
+// Sessions running right now, shared by every route
+#[derive(Clone, Default)]
+pub struct SessionRegistry {
+    sessions: Arc<DashMap<u64, ActiveSession>>,
+    finished: Arc<Finished>,
+}
+
+impl SessionRegistry {
+    // The session stays listed until the handle is dropped
+    pub fn register(&self, info: SessionInfo) -> SessionHandle {
+        let traffic = LiveTraffic::default();
+        let kill = Arc::new(Notify::new());
+        let id = info.id;
+        self.sessions.insert(
+            id,
+            ActiveSession {
+                info,
+                traffic: traffic.clone(),
+                deadline: None,
+                kill: kill.clone(),
+            },
+        );
+        SessionHandle {
+            registry: self.clone(),
+            id,
+            traffic,
+            kill,
+            deadline: None,
+        }
+    }
+
+    // Ends the session, false if there is none with this id
+    pub fn kill(&self, id: u64) -> bool {
+        match self.sessions.get(&id) {
+            Some(session) => {
+                session.kill.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    // Sessions of one route, by the "<route> worker #<idx>" log target of its workers
+    pub fn route_sessions(&self, route: &str) -> usize {
+        let prefix = format!("{} worker #", route);
+        self.sessions
+            .iter()
+            .filter(|s| s.info.route.starts_with(&prefix))
+            .count()
+    }
+
+    pub fn get(&self, id: u64) -> Option<SessionSnapshot> {
+        self.sessions.get(&id).map(|session| session.snapshot())
+    }
+
+    // Every session, by id
+    pub fn snapshot(&self) -> Vec<SessionSnapshot> {
+        let mut sessions: Vec<_> = self.sessions.iter().map(|s| s.snapshot()).collect();
+        sessions.sort_by_key(|s| s.info.id);
+        sessions
+    }
+
+    // Bytes of the sessions running right now
+    pub fn traffic(&self) -> Traffic {
+        self.sessions
+            .iter()
+            .map(|s| s.traffic())
+            .fold(Traffic::default(), |total, traffic| Traffic {
+                a_to_b: total.a_to_b + traffic.a_to_b,
+                b_to_a: total.b_to_a + traffic.b_to_a,
+            })
+    }
+
+    // Sessions since the start, running ones included, and their bytes so far
+    pub fn totals(&self) -> (u64, Traffic) {
+        let running = self.traffic();
+        let finished = &self.finished;
+        let sessions = finished.sessions.load(Ordering::Relaxed) + self.len() as u64;
+        let traffic = Traffic {
+            a_to_b: finished.a_to_b.load(Ordering::Relaxed) + running.a_to_b,
+            b_to_a: finished.b_to_a.load(Ordering::Relaxed) + running.b_to_a,
+        };
+        (sessions, traffic)
+    }
+
+    // Calls f on every session, in no particular order
+    pub fn for_each(&self, mut f: impl FnMut(&ActiveSession)) {
+        for session in self.sessions.iter() {
+            f(&session);
+        }
+    }
+}
+
+// Held by the session while it runs
+pub struct SessionHandle {
+    registry: SessionRegistry,
+    id: u64,
+    pub traffic: LiveTraffic,
+    kill: Arc<Notify>,
+    deadline: Option<Instant>,
+}
+
+impl SessionHandle {
+    // Bytes transferred so far, however the session ends
+    pub fn traffic(&self) -> Traffic {
+        Traffic {
+            a_to_b: self.traffic.a_to_b.load(Ordering::Relaxed),
+            b_to_a: self.traffic.b_to_a.load(Ordering::Relaxed),
+        }
+    }
+
+    // Close the session once it has run for duration, see expired
+    pub fn expire_after(mut self, duration: Duration) -> Self {
+        if let Some(mut session) = self.registry.sessions.get_mut(&self.id) {
+            session.deadline = Some(SystemTime::now() + duration);
+        }
+        self.deadline = Some(Instant::now() + duration);
+        self
+    }
+
+    // Resolves once the session is killed
+    pub async fn killed(&self) {
+        self.kill.notified().await
+    }
+
+    // Resolves once the session has run out of time, never without a deadline
+    pub async fn expired(&self) {
+        match self.deadline {
+            Some(deadline) => sleep_until(deadline).await,
+            None => std::future::pending().await,
+        }
+    }
+}
+
+impl Drop for SessionHandle {
+    fn drop(&mut self) {
+        if let Some((_, session)) = self.registry.sessions.remove(&self.id) {
+            let traffic = session.traffic();
+            let finished = &self.registry.finished;
+            finished.sessions.fetch_add(1, Ordering::Relaxed);
+            finished.a_to_b.fetch_add(traffic.a_to_b, Ordering::Relaxed);
+            finished.b_to_a.fetch_add(traffic.b_to_a, Ordering::Relaxed);
+        }
+    }
+}
@@ -0,0 +1,240 @@
+use anyhow::{anyhow, Result};
+use dashmap::DashMap;
+use log::debug;
+use rand::Rng;
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+use tokio::{
+    net::{lookup_host, UdpSocket},
+    time::{timeout, Duration, Instant},
+};
+
+const LOG_TARGET: &str = "stun";
+
+// RFC 5389
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const FAMILY_IPV4: u8 = 0x01;
+const FAMILY_IPV6: u8 = 0x02;
+pub const HEADER_LEN: usize = 20;
+pub const TRANSACTION_LEN: usize = 12;
+
+// Requests sent to a server before giving up on it, each one waited for twice as long
+const ATTEMPTS: u32 = 3;
+const FIRST_WAIT: Duration = Duration::from_millis(500);
+const MAX_RESPONSE_SIZE: usize = 1024;
+
+// How the NAT maps the connector's UDP ports, from what the STUN servers saw
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NatKind {
+    // The servers saw the local address, there is no NAT
+    None,
+    // Every server saw the same address, punching usually gets through
+    EndpointIndependent,
+    // Each server saw another one (a symmetric NAT), punching rarely gets through
+    EndpointDependent,
+    // A single server answered
+    Unknown,
+}
+
+impl NatKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NatKind::None => "none",
+            NatKind::EndpointIndependent => "endpoint-independent",
+            NatKind::EndpointDependent => "endpoint-dependent",
+            NatKind::Unknown => "unknown",
+        }
+    }
+}
+
+// What a connector learned of its public address, sent to the relay with its table request
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct NatReport {
+    pub local: SocketAddr,
+    // Addresses the servers that answered saw, in the order they were given
+    pub mapped: Vec<SocketAddr>,
+    pub kind: NatKind,
+}
+
+// NAT reports by where they came from: the connectors' addresses on a relay, "self" for the
+// connector's own
+#[derive(Clone, Default)]
+pub struct NatTable {
+    reports: Arc<DashMap<String, (NatReport, Instant)>>,
+}
+
+impl NatTable {
+    pub fn record(&self, source: String, report: NatReport) {
+        self.reports.insert(source, (report, Instant::now()));
+    }
+
+    // By source, with the time since each report
+    pub fn list(&self) -> Vec<(String, NatReport, Duration)> {
+        let mut reports: Vec<_> = self
+            .reports
+            .iter()
+            .map(|entry| {
+                let (report, at) = entry.value();
+                (entry.key().clone(), report.clone(), at.elapsed())
+            })
+            .collect();
+        reports.sort_by(|a, b| a.0.cmp(&b.0));
+        reports
+    }
+}
+
+pub fn binding_request(transaction: &[u8; TRANSACTION_LEN]) -> [u8; HEADER_LEN] {
+    let mut request = [0u8; HEADER_LEN];
+    request[0..2].copy_from_slice(&BINDING_REQUEST.to_be_bytes());
+    // No attributes, the length stays 0
+    request[4..8].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    request[8..].copy_from_slice(transaction);
+    request
+}
+
+// The address of a binding response to the transaction, XOR-MAPPED-ADDRESS preferred over the
+// MAPPED-ADDRESS of older servers
+pub fn parse_response(packet: &[u8], transaction: &[u8; TRANSACTION_LEN]) -> Result<SocketAddr> {
+    if packet.len() < HEADER_LEN {
+        return Err(anyhow!("STUN response is too short"));
+    }
+    let kind = u16::from_be_bytes([packet[0], packet[1]]);
+    let len = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+    if packet[4..8] != MAGIC_COOKIE.to_be_bytes() || packet[8..HEADER_LEN] != transaction[..] {
+        return Err(anyhow!("Not a response to the request"));
+    }
+    if kind != BINDING_SUCCESS {
+        return Err(anyhow!("Binding failed (message type {:#06x})", kind));
+    }
+    let mut attributes = packet
+        .get(HEADER_LEN..HEADER_LEN + len)
+        .ok_or(anyhow!("STUN response is truncated"))?;
+
+    let mut mapped = None;
+    while attributes.len() >= 4 {
+        let kind = u16::from_be_bytes([attributes[0], attributes[1]]);
+        let len = u16::from_be_bytes([attributes[2], attributes[3]]) as usize;
+        let value = attributes
+            .get(4..4 + len)
+            .ok_or(anyhow!("STUN attribute is truncated"))?;
+        match kind {
+            ATTR_XOR_MAPPED_ADDRESS => return address(value, Some(transaction)),
+            ATTR_MAPPED_ADDRESS => mapped = Some(address(value, None)?),
+            _ => {}
+        }
+        // Values are padded to 4 bytes
+        let padded = (4 + len).next_multiple_of(4);
+        attributes = attributes.get(padded..).unwrap_or_default();
+    }
+    mapped.ok_or(anyhow!("No mapped address in the response"))
+}
+
+// An address attribute, XORed with the cookie and transaction when given one
+fn address(value: &[u8], xor: Option<&[u8; TRANSACTION_LEN]>) -> Result<SocketAddr> {
+    let invalid = || anyhow!("Invalid address attribute");
+    let (family, port) = match value {
+        [_, family, port @ ..] if port.len() >= 2 => (*family, [port[0], port[1]]),
+        _ => return Err(invalid()),
+    };
+    let mut mask = [0u8; 16];
+    if let Some(transaction) = xor {
+        mask[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        mask[4..].copy_from_slice(transaction);
+    }
+    let port = u16::from_be_bytes(port) ^ u16::from_be_bytes([mask[0], mask[1]]);
+    let ip = match (family, &value[4..]) {
+        (FAMILY_IPV4, &[a, b, c, d]) => {
+            let ip = [a ^ mask[0], b ^ mask[1], c ^ mask[2], d ^ mask[3]];
+            IpAddr::V4(Ipv4Addr::from(ip))
+        }
+        (FAMILY_IPV6, bytes) if bytes.len() == 16 => {
+            let mut ip = [0u8; 16];
+            for (idx, byte) in bytes.iter().enumerate() {
+                ip[idx] = byte ^ mask[idx];
+            }
+            IpAddr::V6(Ipv6Addr::from(ip))
+        }
+        _ => return Err(invalid()),
+    };
+    Ok(SocketAddr::new(ip, port))
+}
+
+// The address a server sees the socket's datagrams come from, asking again when the request or
+// the response is lost
+pub async fn binding(socket: &UdpSocket, server: SocketAddr) -> Result<SocketAddr> {
+    let mut transaction = [0u8; TRANSACTION_LEN];
+    rand::thread_rng().fill(&mut transaction);
+    let request = binding_request(&transaction);
+
+    let mut wait = FIRST_WAIT;
+    for _ in 0..ATTEMPTS {
+        socket.send_to(&request, server).await?;
+        let answer = timeout(wait, async {
+            let mut packet = [0u8; MAX_RESPONSE_SIZE];
+            loop {
+                let (len, from) = socket.recv_from(&mut packet).await?;
+                // Late answers to earlier servers or attempts are dropped
+                match parse_response(&packet[..len], &transaction) {
+                    Ok(mapped) if from == server => return Ok::<_, anyhow::Error>(mapped),
+                    _ => debug!(target: LOG_TARGET, "Dropped a datagram from {}", from),
+                }
+            }
+        });
+        if let Ok(mapped) = answer.await {
+            return mapped;
+        }
+        wait *= 2;
+    }
+    Err(anyhow!("{} didn't answer", server))
+}
+
+// Asks every server ("host:port") from the same socket and tells the kind of NAT from the
+// addresses they saw
+pub async fn discover(servers: &[String]) -> Result<NatReport> {
+    let mut addrs = Vec::new();
+    for server in servers {
+        match lookup_host(server).await?.find(SocketAddr::is_ipv4) {
+            Some(addr) => addrs.push(addr),
+            None => return Err(anyhow!("{} has no IPv4 address", server)),
+        }
+    }
+    let first = *addrs.first().ok_or(anyhow!("No STUN servers given"))?;
+
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    // The address of the interface the servers are reached over, the socket's is unspecified
+    let probe = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    probe.connect(first).await?;
+    let local = SocketAddr::new(probe.local_addr()?.ip(), socket.local_addr()?.port());
+
+    let mut mapped = Vec::new();
+    for server in addrs {
+        match binding(&socket, server).await {
+            Ok(addr) => mapped.push(addr),
+            Err(e) => debug!(target: LOG_TARGET, "{}", e),
+        }
+    }
+    if mapped.is_empty() {
+        return Err(anyhow!("No STUN server answered"));
+    }
+    Ok(NatReport {
+        local,
+        kind: classify(local, &mapped),
+        mapped,
+    })
+}
+
+pub fn classify(local: SocketAddr, mapped: &[SocketAddr]) -> NatKind {
+    match mapped {
+        [first, ..] if *first == local => NatKind::None,
+        [] | [_] => NatKind::Unknown,
+        [first, rest @ ..] if rest.iter().all(|addr| addr == first) => NatKind::EndpointIndependent,
+        _ => NatKind::EndpointDependent,
+    }
+}
@@ -0,0 +1,761 @@
+use crate::{
+    config::{
+        ConnectionType, ControlConfig, Direction, Endpoint, Export, Expose, RelayConfig, Route,
+        Service, VeloxidConfig,
+    },
+    error::ConfigError,
+    events::EventHandlers,
+    latency::LatencyTable,
+    protocol::encryption::generate_secret_from_string,
+    punch::{Broker, Offer, RENDEZVOUS_WAIT},
+    relay::{
+        connection::{self, ConnectionData, RouteContext},
+        tunnel::{SessionOptions, Tunnel},
+    },
+    sessions::SessionRegistry,
+    stun::{self, NatReport, NatTable},
+    tarpit::Tarpit,
+    transport::{listener, Stream},
+};
+use anyhow::{anyhow, Result};
+use dashmap::DashMap;
+use log::{error, info, warn};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+use tokio::{
+    io::{duplex, AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{watch, Mutex, Semaphore},
+    task,
+    time::{sleep, timeout, Duration, Instant},
+};
+
+const LOG_TARGET: &str = "table";
+
+// Largest route table a connector accepts
+const MAX_TABLE_SIZE: usize = 1024 * 1024;
+
+// Workers of a route exposed at a connector's request
+const MAX_EXPOSED_SIZE: usize = 32;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+const FETCH_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+// Names of the endpoints generated for exports on the relay, imports on the connector and
+// services of the agent
+const EXPORT_PREFIX: &str = "export:";
+pub const IMPORT_PREFIX: &str = "relay:";
+pub const AGENT_PREFIX: &str = "agent:";
+
+// One route of the table, as the connectors see it
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct TableEntry {
+    pub name: String,
+    // Relay port the connector's tunnels go to
+    pub tunnel_port: u16,
+    // Address ("host:port") of the service, as seen from the connector
+    pub target: String,
+    // Workers, so the amount of tunnels kept waiting on the relay
+    pub size: usize,
+    // Port clients reach the service at on the relay
+    #[serde(default)]
+    pub public_port: Option<u16>,
+    // Reached by agents, the connector's tunnels are encrypted end-to-end for them
+    #[serde(default)]
+    pub end_to_end: bool,
+    // Agents punch through to the connectors, which wait for them on the control listener
+    #[serde(default)]
+    pub punch: bool,
+}
+
+// What a connector asks of the relay, sent before the table
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct TableRequest {
+    #[serde(default)]
+    pub expose: Vec<Expose>,
+    // Waits for an agent of the route to punch through instead of fetching the table
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub punch: Option<String>,
+    // The connector's public address, as STUN servers saw it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nat: Option<NatReport>,
+}
+
+impl From<&Export> for TableEntry {
+    fn from(export: &Export) -> Self {
+        TableEntry {
+            name: export.name.clone(),
+            tunnel_port: export.tunnel_port,
+            target: export.target.clone(),
+            size: export.size,
+            public_port: Some(export.port),
+            end_to_end: export.agent_secret.is_some(),
+            punch: export.punch_port.is_some(),
+        }
+    }
+}
+
+pub fn endpoint(
+    host: Option<String>,
+    port: u16,
+    kind: ConnectionType,
+    direction: Direction,
+) -> Endpoint {
+    Endpoint {
+        host,
+        port,
+        kind,
+        direction,
+        secret: None,
+        key_command: None,
+        cipher: None,
+        transport: None,
+        bonding: None,
+        paths: None,
+        resume: None,
+        targets: None,
+        obfuscation: None,
+        server_name: None,
+        padding: None,
+        padding_rate: None,
+        buffer_size: None,
+        write_timeout: None,
+        max_in_flight: None,
+        ipv6_only: None,
+        bind_retry: None,
+        pool: None,
+        fast_open: None,
+        rekey: None,
+        rekey_bytes: None,
+        rekey_interval: None,
+        tickets: None,
+        silent: None,
+        totp_seed: None,
+        totp_skew: None,
+        heartbeat: None,
+        registry: None,
+        service: None,
+        via: None,
+        hops: None,
+        punch_port: None,
+        knock_port: None,
+        knock_window: None,
+        knock_source: None,
+        rendezvous: None,
+        advertise: None,
+        advertise_type: None,
+        max_conns_per_ip: None,
+        capture: None,
+        command: None,
+        max_processes: None,
+        flow_timeout: None,
+        tls_cert: None,
+        tls_key: None,
+        tls: None,
+        tls_ca: None,
+        pinned_sha256: None,
+        auth_timeout: None,
+        nonce_timeout: None,
+        handshake_timeout: None,
+        e2e_secret: None,
+        port_mapping: None,
+        mapping_lease: None,
+        external_port: None,
+        mapping_gateway: None,
+    }
+}
+
+fn route(name: &str, endpoints: [String; 2], size: usize) -> Route {
+    Route {
+        name: Some(name.to_owned()),
+        log_level: None,
+        endpoints,
+        size,
+        min_size: None,
+        max_size: None,
+        max_sessions: None,
+        max_session_duration: None,
+        priority: None,
+        schedule: None,
+        protocol: None,
+        sni: None,
+        alpn: None,
+        tap: None,
+        tap_mode: None,
+        affinity: None,
+        blind: None,
+        balance: None,
+        weights: None,
+        service: None,
+    }
+}
+
+fn split_host_port(addr: &str) -> Option<(String, u16)> {
+    let (host, port) = addr.rsplit_once(':')?;
+    Some((host.to_owned(), port.parse().ok()?))
+}
+
+fn insert(config: &mut VeloxidConfig, name: String, endpoint: Endpoint) -> Result<()> {
+    if config.endpoints.contains_key(&name) {
+        return Err(ConfigError::DuplicateTableEntry(name).into());
+    }
+    config.endpoints.insert(name, endpoint);
+    Ok(())
+}
+
+// Relay side: every export becomes a public endpoint and a tunnel endpoint for the connectors,
+// joined by a route
+pub fn add_exports(config: &mut VeloxidConfig) -> Result<()> {
+    let Some(control) = &config.control else {
+        return Ok(());
+    };
+    let (host, secret, exports) = (
+        control.host.clone(),
+        control.secret.clone(),
+        control.exports.clone(),
+    );
+
+    for export in exports {
+        let public = format!("{}{}", EXPORT_PREFIX, export.name);
+        let tunnel = format!("{}:tunnel", public);
+
+        let mut tunnel_endpoint = endpoint(
+            host.clone(),
+            export.tunnel_port,
+            ConnectionType::Tunnel,
+            Direction::Inbound,
+        );
+        tunnel_endpoint.secret = Some(secret.clone());
+
+        // Agents' tunnels are joined to the connectors' ones as they are
+        let public_endpoint = match &export.agent_secret {
+            Some(agent_secret) => {
+                let mut agents = endpoint(
+                    host.clone(),
+                    export.port,
+                    ConnectionType::Tunnel,
+                    Direction::Inbound,
+                );
+                agents.secret = Some(agent_secret.clone());
+                agents
+            }
+            None => endpoint(
+                host.clone(),
+                export.port,
+                ConnectionType::Direct,
+                Direction::Inbound,
+            ),
+        };
+
+        insert(config, public.clone(), public_endpoint)?;
+        insert(config, tunnel.clone(), tunnel_endpoint)?;
+        let mut route = route(&public, [public.clone(), tunnel], export.size);
+        route.blind = export.agent_secret.is_some().then_some(true);
+        config.routes.push(route);
+    }
+    Ok(())
+}
+
+fn serves(service: &Service, entry: &TableEntry) -> bool {
+    match (&service.name, service.port) {
+        (Some(name), _) => *name == entry.name,
+        (None, port) => port.is_some() && port == entry.public_port,
+    }
+}
+
+// The routes of the table the connector follows, with their target and size
+fn imports(relay: &RelayConfig, table: &[TableEntry]) -> Vec<(TableEntry, String, usize)> {
+    for service in &relay.services {
+        if !table.iter().any(|entry| serves(service, entry)) {
+            warn!(
+                target: LOG_TARGET,
+                "No route on the relay for the service at {}", service.target
+            );
+        }
+    }
+
+    let mut imports = Vec::new();
+    for entry in table {
+        let exposed = relay.expose.iter().any(|e| e.name == entry.name);
+        match relay.services.iter().find(|s| serves(s, entry)) {
+            Some(service) => imports.push((
+                entry.clone(),
+                service.target.clone(),
+                service.size.unwrap_or(entry.size),
+            )),
+            None if relay.services.is_empty() || exposed => {
+                imports.push((entry.clone(), entry.target.clone(), entry.size))
+            }
+            None => info!(target: LOG_TARGET, "'{}' isn't among the services", entry.name),
+        }
+    }
+    imports
+}
+
+// Connector side: every followed route of the relay's table becomes a tunnel to the relay and
+// the service it leads to, joined by a route
+pub fn add_imports(config: &mut VeloxidConfig, table: &[TableEntry]) -> Result<()> {
+    let Some(relay) = &config.relay else {
+        return Ok(());
+    };
+    let (host, secret, control_port) = (relay.host.clone(), relay.secret.clone(), relay.port);
+    let e2e_secret = relay.e2e_secret.clone();
+
+    for (entry, target, size) in imports(relay, table) {
+        if entry.end_to_end && e2e_secret.is_none() {
+            return Err(anyhow!(
+                "'{}' is end-to-end, relay.e2e_secret is needed",
+                entry.name
+            ));
+        }
+        let (target_host, target_port) = split_host_port(&target)
+            .ok_or_else(|| anyhow!("'{}' has an invalid target: {}", entry.name, target))?;
+
+        let service = format!("{}{}", IMPORT_PREFIX, entry.name);
+        let tunnel = format!("{}:tunnel", service);
+
+        let mut tunnel_endpoint = endpoint(
+            Some(host.clone()),
+            entry.tunnel_port,
+            ConnectionType::Tunnel,
+            Direction::Outbound,
+        );
+        tunnel_endpoint.secret = Some(secret.clone());
+        if entry.end_to_end {
+            tunnel_endpoint.e2e_secret = e2e_secret.clone();
+        }
+
+        insert(config, tunnel.clone(), tunnel_endpoint)?;
+        insert(
+            config,
+            service.clone(),
+            endpoint(
+                Some(target_host),
+                target_port,
+                ConnectionType::Direct,
+                Direction::Outbound,
+            ),
+        )?;
+        config
+            .routes
+            .push(route(&service, [tunnel, service.clone()], size));
+
+        // Tunnels of the agents punching through, taken on the relay's control listener
+        if entry.punch {
+            let punch = format!("{}:punch", service);
+            let mut punch_endpoint = endpoint(
+                Some(host.clone()),
+                control_port,
+                ConnectionType::Tunnel,
+                Direction::Inbound,
+            );
+            punch_endpoint.secret = Some(secret.clone());
+            punch_endpoint.e2e_secret = e2e_secret.clone();
+            punch_endpoint.rendezvous = Some(entry.name.clone());
+            insert(config, punch.clone(), punch_endpoint)?;
+            config
+                .routes
+                .push(route(&punch, [punch.clone(), service.clone()], size));
+        }
+    }
+    Ok(())
+}
+
+// Agent side: every service becomes a local port and a tunnel to the export on the relay,
+// encrypted for the connectors behind it
+pub fn add_agent(config: &mut VeloxidConfig) -> Result<()> {
+    let Some(agent) = config.agent.clone() else {
+        return Ok(());
+    };
+
+    for service in agent.services {
+        let (listen_host, listen_port) = split_host_port(&service.listen)
+            .ok_or_else(|| anyhow!("'{}' has an invalid listen address", service.name))?;
+
+        let local = format!("{}{}", AGENT_PREFIX, service.name);
+        let tunnel = format!("{}:tunnel", local);
+
+        let mut tunnel_endpoint = endpoint(
+            Some(agent.host.clone()),
+            service.port,
+            ConnectionType::Tunnel,
+            Direction::Outbound,
+        );
+        tunnel_endpoint.secret = Some(agent.secret.clone());
+        tunnel_endpoint.e2e_secret = Some(agent.e2e_secret.clone());
+        tunnel_endpoint.punch_port = service.punch_port;
+
+        insert(
+            config,
+            local.clone(),
+            endpoint(
+                Some(listen_host),
+                listen_port,
+                ConnectionType::Direct,
+                Direction::Inbound,
+            ),
+        )?;
+        insert(config, tunnel.clone(), tunnel_endpoint)?;
+        config
+            .routes
+            .push(route(&local, [local.clone(), tunnel], service.size));
+    }
+    Ok(())
+}
+
+// Reads to EOF, up to the size of a table
+async fn read_limited<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    reader
+        .take(MAX_TABLE_SIZE as u64 + 1)
+        .read_to_end(&mut data)
+        .await?;
+    if data.len() > MAX_TABLE_SIZE {
+        return Err(anyhow!("Route table is too large"));
+    }
+    Ok(data)
+}
+
+// Answers a connector over an inbound tunnel: its request, then the table, and the
+// connection ends. Exposures are refused without the pool, and punches without the broker.
+// NAT reports are kept by the connector's address.
+pub async fn send_table<S: Stream>(
+    stream: S,
+    peer: SocketAddr,
+    secret: [u8; 32],
+    table: &[TableEntry],
+    exposures: Option<&Exposures>,
+    broker: Option<&Broker>,
+    nat: &NatTable,
+) -> Result<()> {
+    let tunnel = Tunnel::init(stream, peer.ip(), true, secret).await?;
+    let (mut local, tunnel_side) = duplex(64 * 1024);
+    let session = task::spawn(tunnel.run(tunnel_side, SessionOptions::default()));
+
+    let request = read_limited(&mut local).await?;
+    let request: TableRequest = match request.is_empty() {
+        true => TableRequest::default(),
+        false => serde_json::from_slice(&request)?,
+    };
+
+    // The connector waits for an agent instead, and is told where it is
+    if let Some(route) = &request.punch {
+        let broker = broker.ok_or_else(|| anyhow!("punching isn't allowed"))?;
+        let offer = Offer {
+            peer: broker.wait(route, peer).await?,
+        };
+        local.write_all(&serde_json::to_vec(&offer)?).await?;
+        local.shutdown().await?;
+        session.await??;
+        return Ok(());
+    }
+    if let Some(report) = request.nat {
+        info!(
+            target: LOG_TARGET,
+            "{} is behind a NAT of kind {}, seen at {}",
+            peer,
+            report.kind.as_str(),
+            joined(&report.mapped)
+        );
+        nat.record(peer.ip().to_string(), report);
+    }
+    let peer = peer.ip();
+
+    let mut table = table.to_vec();
+    for expose in &request.expose {
+        let result = match exposures {
+            Some(exposures) => exposures.expose(expose, &table).await,
+            None => Err(anyhow!("exposing routes isn't allowed")),
+        };
+        match result {
+            Ok(entry) => table.push(entry),
+            Err(e) => warn!(target: LOG_TARGET, "{} can't expose '{}': {}", peer, expose.name, e),
+        }
+    }
+
+    local.write_all(&serde_json::to_vec(&table)?).await?;
+    local.shutdown().await?;
+
+    session.await??;
+    info!(target: LOG_TARGET, "Sent the route table to {}", peer);
+    Ok(())
+}
+
+// Reads the table over an outbound tunnel, after asking for the exposures
+pub async fn fetch_table<S: Stream>(
+    stream: S,
+    peer: IpAddr,
+    secret: [u8; 32],
+    request: &TableRequest,
+) -> Result<Vec<TableEntry>> {
+    let tunnel = Tunnel::init(stream, peer, false, secret).await?;
+    let (mut local, tunnel_side) = duplex(64 * 1024);
+    let session = task::spawn(tunnel.run(tunnel_side, SessionOptions::default()));
+
+    local.write_all(&serde_json::to_vec(request)?).await?;
+    local.shutdown().await?;
+    let table = read_limited(&mut local).await?;
+    drop(local);
+
+    session.await??;
+    Ok(serde_json::from_slice(&table)?)
+}
+
+// Routes exposed on ephemeral ports at the connectors' request. They last until the relay
+// restarts, asking for the same name again gets the same ports.
+pub struct Exposures {
+    host: Option<String>,
+    secret: String,
+    limit: usize,
+    ban_list: Arc<DashMap<IpAddr, Instant>>,
+    events: EventHandlers,
+    registry: SessionRegistry,
+    latency: LatencyTable,
+    tarpit: Option<Tarpit>,
+    routes: Mutex<HashMap<String, (TableEntry, watch::Sender<bool>)>>,
+}
+
+impl Exposures {
+    pub fn new(
+        control: &ControlConfig,
+        limit: usize,
+        ban_list: Arc<DashMap<IpAddr, Instant>>,
+        events: EventHandlers,
+        registry: SessionRegistry,
+        latency: LatencyTable,
+        tarpit: Option<Tarpit>,
+    ) -> Self {
+        Self {
+            host: control.host.clone(),
+            secret: control.secret.clone(),
+            limit,
+            ban_list,
+            events,
+            registry,
+            latency,
+            tarpit,
+            routes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn expose(&self, request: &Expose, table: &[TableEntry]) -> Result<TableEntry> {
+        if table.iter().any(|entry| entry.name == request.name) {
+            return Err(anyhow!("the name is taken"));
+        }
+        let mut routes = self.routes.lock().await;
+        if let Some((entry, _)) = routes.get(&request.name) {
+            return Ok(TableEntry {
+                target: request.target.clone(),
+                ..entry.clone()
+            });
+        }
+        if routes.len() >= self.limit {
+            return Err(anyhow!("no more than {} routes can be exposed", self.limit));
+        }
+
+        // Both listeners on ports of the system's choice
+        let mut tunnel_endpoint = endpoint(
+            self.host.clone(),
+            0,
+            ConnectionType::Tunnel,
+            Direction::Inbound,
+        );
+        tunnel_endpoint.secret = Some(self.secret.clone());
+        let public = connection::get_connection_data(&endpoint(
+            self.host.clone(),
+            0,
+            ConnectionType::Direct,
+            Direction::Inbound,
+        ))
+        .await?;
+        let tunnel = connection::get_connection_data(&tunnel_endpoint).await?;
+        let port = |data: &ConnectionData| match data {
+            ConnectionData::Inbound { listener, .. } => listener.addr().port(),
+            _ => 0,
+        };
+
+        let size = request.size.clamp(1, MAX_EXPOSED_SIZE);
+        let entry = TableEntry {
+            name: request.name.clone(),
+            tunnel_port: port(&tunnel),
+            target: request.target.clone(),
+            size,
+            public_port: Some(port(&public)),
+            end_to_end: false,
+            punch: false,
+        };
+
+        let ctx = RouteContext {
+            ban_list: self.ban_list.clone(),
+            events: self.events.clone(),
+            registry: self.registry.clone(),
+            latency: self.latency.clone(),
+            schedule: None,
+            affinity: Default::default(),
+            buffer_size: None,
+            write_timeout: None,
+            max_in_flight: None,
+            blind: false,
+            sessions: Arc::new(Semaphore::new(size)),
+            max_session_duration: None,
+            // Outside of the [limits] budget, which is the configured routes'
+            budget: None,
+            priority: 0,
+            balancer: None,
+            tarpit: self.tarpit.clone(),
+            scaling: None,
+            name: format!("exposed '{}'", request.name),
+            endpoints: ["public".to_owned(), "tunnel".to_owned()],
+            #[cfg(feature = "tap")]
+            tap: None,
+        };
+        let enabled = watch::Sender::new(true);
+        for worker_idx in 0..size {
+            task::spawn({
+                let (public, tunnel) = (public.clone(), tunnel.clone());
+                let ctx = ctx.clone();
+                let enabled = enabled.subscribe();
+                let log_target = format!("exposed '{}' worker #{}", request.name, worker_idx);
+                async move {
+                    connection::route(public, tunnel, ctx, enabled, &log_target).await;
+                }
+            });
+        }
+
+        info!(
+            target: LOG_TARGET,
+            "Exposed '{}' on port {}, tunnels on port {}",
+            entry.name,
+            entry.public_port.unwrap_or_default(),
+            entry.tunnel_port
+        );
+        routes.insert(request.name.clone(), (entry.clone(), enabled));
+        Ok(entry)
+    }
+}
+
+// Address of the control listener
+pub fn control_addr(control: &ControlConfig) -> String {
+    connection::join_host_port(control.host.as_deref().unwrap_or("0.0.0.0"), control.port)
+}
+
+// Address of the punch listener of an export
+pub fn punch_addr(control: &ControlConfig, port: u16) -> String {
+    connection::join_host_port(control.host.as_deref().unwrap_or("0.0.0.0"), port)
+}
+
+// Serves the exports to the connectors until the listener fails
+pub async fn serve(
+    listener: TcpListener,
+    control: &ControlConfig,
+    exposures: Option<Exposures>,
+    broker: Option<Arc<Broker>>,
+    nat: NatTable,
+) -> Result<()> {
+    let addr = listener.local_addr()?;
+    let secret = generate_secret_from_string(control.secret.clone());
+    let table: Arc<Vec<TableEntry>> =
+        Arc::new(control.exports.iter().map(TableEntry::from).collect());
+    let exposures = Arc::new(exposures);
+    info!(target: LOG_TARGET, "Serving {} routes on {}", table.len(), addr);
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                listener::recover(e).await?;
+                continue;
+            }
+        };
+        let (table, exposures, broker) = (table.clone(), exposures.clone(), broker.clone());
+        let nat = nat.clone();
+        task::spawn(async move {
+            let answer = send_table(
+                stream,
+                peer,
+                secret,
+                &table,
+                exposures.as_ref().as_ref(),
+                broker.as_deref(),
+                &nat,
+            );
+            // Connectors waiting for an agent take up to RENDEZVOUS_WAIT more
+            let result = timeout(FETCH_TIMEOUT + RENDEZVOUS_WAIT, answer).await;
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => warn!(target: LOG_TARGET, "{}: {}", peer, e),
+                Err(_) => warn!(target: LOG_TARGET, "{}: timed out", peer),
+            }
+        });
+    }
+}
+
+fn joined(addrs: &[SocketAddr]) -> String {
+    let addrs: Vec<String> = addrs.iter().map(ToString::to_string).collect();
+    addrs.join(",")
+}
+
+// Learns the connector's public address from the STUN servers, recorded as its own
+async fn discover(relay: &RelayConfig, nat: &NatTable) -> Option<NatReport> {
+    if relay.stun.is_empty() {
+        return None;
+    }
+    match stun::discover(&relay.stun).await {
+        Ok(report) => {
+            info!(
+                target: LOG_TARGET,
+                "Seen at {} by the STUN servers, NAT of kind {}",
+                joined(&report.mapped),
+                report.kind.as_str()
+            );
+            nat.record("self".to_owned(), report.clone());
+            Some(report)
+        }
+        Err(e) => {
+            warn!(target: LOG_TARGET, "Couldn't learn the public address: {}", e);
+            None
+        }
+    }
+}
+
+// Fetches the table from the relay, retrying until it answers. The STUN servers are asked
+// first, for the relay to know the connector's NAT.
+pub async fn fetch(relay: &RelayConfig, nat: &NatTable) -> Vec<TableEntry> {
+    let addr = format!("{}:{}", relay.host, relay.port);
+    let secret = generate_secret_from_string(relay.secret.clone());
+    let request = TableRequest {
+        expose: relay.expose.clone(),
+        punch: None,
+        nat: discover(relay, nat).await,
+    };
+
+    loop {
+        let result = timeout(FETCH_TIMEOUT, async {
+            let stream = TcpStream::connect(&addr).await?;
+            let peer = stream.peer_addr()?.ip();
+            fetch_table(stream, peer, secret, &request).await
+        })
+        .await;
+        match result {
+            Ok(Ok(table)) => {
+                info!(target: LOG_TARGET, "Got {} routes from {}", table.len(), addr);
+                for entry in &table {
+                    if let Some(port) = entry.public_port {
+                        info!(target: LOG_TARGET, "'{}' is reachable at {}:{}", entry.name, relay.host, port);
+                    }
+                }
+                return table;
+            }
+            Ok(Err(e)) => {
+                error!(target: LOG_TARGET, "Couldn't fetch the route table from {}: {}", addr, e)
+            }
+            Err(_) => {
+                error!(target: LOG_TARGET, "Couldn't fetch the route table from {}: timed out", addr)
+            }
+        }
+        sleep(FETCH_RETRY_DELAY).await;
+    }
+}
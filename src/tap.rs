@@ -0,0 +1,225 @@
+use crate::config::TapMode;
+use log::error;
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, ErrorKind, Read, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::mpsc;
+
+// Debug capture of session traffic into rotating files
+//
+// File format: MAGIC, then records of
+// [u64 unix time in microseconds][u64 session id][u8 direction: 0 = A->B, 1 = B->A][u32 length][data]
+// all big-endian. Files are rotated as <prefix>.vtap, <prefix>.vtap.1, ... <prefix>.vtap.4
+
+const MAGIC: &[u8] = b"VELOXID-TAP 1\n";
+const FILE_SIZE: u64 = 64 * 1024 * 1024;
+const FILES: usize = 5;
+const QUEUE_SIZE: usize = 1024;
+
+pub struct Record {
+    pub time: SystemTime,
+    pub session: u64,
+    pub a_to_b: bool,
+    pub data: Vec<u8>,
+}
+
+// Records of a capture file. The last one is left out if it was cut short, by a capture still
+// being written to.
+pub fn read(mut file: impl Read) -> io::Result<Vec<Record>> {
+    let mut magic = [0u8; MAGIC.len()];
+    if file.read_exact(&mut magic).is_err() || magic != MAGIC {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "not a veloxid capture",
+        ));
+    }
+
+    let mut records = Vec::new();
+    let mut header = [0u8; 8 + 8 + 1 + 4];
+    loop {
+        match file.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(records),
+            Err(e) => return Err(e),
+        }
+        let micros = u64::from_be_bytes(header[0..8].try_into().unwrap());
+        let session = u64::from_be_bytes(header[8..16].try_into().unwrap());
+        let len = u32::from_be_bytes(header[17..21].try_into().unwrap());
+        let mut data = vec![0u8; len as usize];
+        match file.read_exact(&mut data) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(records),
+            Err(e) => return Err(e),
+        }
+        records.push(Record {
+            time: UNIX_EPOCH + Duration::from_micros(micros),
+            session,
+            a_to_b: header[16] == 0,
+            data,
+        });
+    }
+}
+
+#[derive(Clone)]
+pub struct Tap {
+    sender: mpsc::Sender<Record>,
+    mode: TapMode,
+    next_session: Arc<AtomicU64>,
+}
+
+impl Tap {
+    // Open the files and start the writer thread
+    pub fn open(prefix: &str, mode: TapMode) -> io::Result<Self> {
+        let mut writer = TapWriter::open(prefix)?;
+        let (sender, mut receiver) = mpsc::channel::<Record>(QUEUE_SIZE);
+        std::thread::spawn(move || {
+            while let Some(record) = receiver.blocking_recv() {
+                if let Err(e) = writer.write(&record) {
+                    error!(target: "tap", "Capture to '{}' stopped: {}", writer.path.display(), e);
+                    return;
+                }
+            }
+        });
+
+        Ok(Self {
+            sender,
+            mode,
+            next_session: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    pub fn session(&self) -> SessionTap {
+        SessionTap {
+            tap: self.clone(),
+            id: self.next_session.fetch_add(1, Ordering::Relaxed),
+            reversed: false,
+        }
+    }
+}
+
+// Tap of a single session
+#[derive(Clone)]
+pub struct SessionTap {
+    tap: Tap,
+    id: u64,
+    reversed: bool,
+}
+
+impl SessionTap {
+    // Swap A and B, for sessions whose sides are passed in the opposite order
+    pub fn reversed(self) -> Self {
+        Self {
+            reversed: !self.reversed,
+            ..self
+        }
+    }
+
+    // Where to capture in a copier, given after how many ciphers the data is plaintext
+    // and after how many it is as seen on the tunnel
+    pub fn point(&self, a_to_b: bool, plaintext_at: usize, wire_at: usize) -> TapPoint {
+        TapPoint {
+            tap: self.clone(),
+            a_to_b: a_to_b != self.reversed,
+            position: match self.tap.mode {
+                TapMode::Plaintext => plaintext_at,
+                TapMode::Ciphertext => wire_at,
+            },
+        }
+    }
+}
+
+// A capture point inside a copier
+pub struct TapPoint {
+    tap: SessionTap,
+    a_to_b: bool,
+    position: usize,
+}
+
+impl TapPoint {
+    // Amount of ciphers applied before capturing
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub async fn record(&self, data: &[u8]) {
+        let record = Record {
+            time: SystemTime::now(),
+            session: self.tap.id,
+            a_to_b: self.a_to_b,
+            data: data.to_vec(),
+        };
+        // The writer is gone only after an error which is already logged
+        let _ = self.tap.tap.sender.send(record).await;
+    }
+}
+
+struct TapWriter {
+    path: PathBuf,
+    file: BufWriter<File>,
+    size: u64,
+}
+
+impl TapWriter {
+    fn open(prefix: &str) -> io::Result<Self> {
+        let path = PathBuf::from(format!("{}.vtap", prefix));
+        let mut writer = Self {
+            file: BufWriter::new(File::create(&path)?),
+            path,
+            size: 0,
+        };
+        writer.header()?;
+        Ok(writer)
+    }
+
+    fn header(&mut self) -> io::Result<()> {
+        self.file.write_all(MAGIC)?;
+        self.size = MAGIC.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let name = |idx: usize| match idx {
+            0 => self.path.clone(),
+            idx => PathBuf::from(format!("{}.{}", self.path.display(), idx)),
+        };
+        for idx in (1..FILES).rev() {
+            if name(idx - 1).exists() {
+                fs::rename(name(idx - 1), name(idx))?;
+            }
+        }
+
+        self.file = BufWriter::new(File::create(&self.path)?);
+        self.header()
+    }
+
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        let len = 8 + 8 + 1 + 4 + record.data.len() as u64;
+        if self.size + len > FILE_SIZE {
+            self.rotate()?;
+        }
+
+        let micros = record
+            .time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        self.file.write_all(&micros.to_be_bytes())?;
+        self.file.write_all(&record.session.to_be_bytes())?;
+        self.file.write_all(&[u8::from(!record.a_to_b)])?;
+        self.file
+            .write_all(&(record.data.len() as u32).to_be_bytes())?;
+        self.file.write_all(&record.data)?;
+        self.size += len;
+
+        // Keep the capture readable while the session is still running
+        self.file.flush()
+    }
+}
@@ -0,0 +1,63 @@
+use dashmap::DashMap;
+use log::debug;
+use std::{net::IpAddr, sync::Arc};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::{sleep, Duration, Instant},
+};
+
+const LOG_TARGET: &str = "tarpit";
+
+// Time between the bytes when the config doesn't set it
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+
+// Banned peers kept connected rather than turned away, fed a random byte now and then for as
+// long as they wait, so their scanners spend sockets and time on it. Past max sockets held,
+// banned peers are turned away as usual.
+#[derive(Clone)]
+pub struct Tarpit {
+    slots: Arc<Semaphore>,
+    interval: Duration,
+    ban_list: Arc<DashMap<IpAddr, Instant>>,
+}
+
+impl Tarpit {
+    pub fn new(max: usize, interval: Duration, ban_list: Arc<DashMap<IpAddr, Instant>>) -> Self {
+        Self {
+            slots: Arc::new(Semaphore::new(max)),
+            interval,
+            ban_list,
+        }
+    }
+
+    // None once max sockets are held
+    pub fn slot(&self) -> Option<OwnedSemaphorePermit> {
+        self.slots.clone().try_acquire_owned().ok()
+    }
+
+    // Trickle bytes to the peer until it leaves or its ban is over
+    pub async fn hold<S: AsyncWrite + Unpin>(
+        self,
+        mut stream: S,
+        peer: IpAddr,
+        _slot: OwnedSemaphorePermit,
+    ) {
+        debug!(target: LOG_TARGET, "Holding {}", peer);
+        let started = Instant::now();
+        while self.is_banned(peer) {
+            sleep(self.interval).await;
+            let byte = [rand::random::<u8>()];
+            if stream.write_all(&byte).await.is_err() || stream.flush().await.is_err() {
+                break;
+            }
+        }
+        debug!(target: LOG_TARGET, "Released {} after {:?}", peer, started.elapsed());
+    }
+
+    fn is_banned(&self, peer: IpAddr) -> bool {
+        self.ban_list
+            .get(&peer)
+            .is_some_and(|until| *until > Instant::now())
+    }
+}
@@ -0,0 +1,580 @@
+use crate::{config::BondMode, transport::listener::Listener};
+use anyhow::Result;
+use futures::future::join_all;
+use log::{debug, error, info};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{
+        duplex, split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, DuplexStream,
+        ReadBuf,
+    },
+    net::{tcp::OwnedWriteHalf, TcpStream},
+    sync::{mpsc, Mutex},
+    task,
+    time::{sleep, sleep_until, timeout, Duration, Instant},
+};
+
+const LOG_TARGET: &str = "bond";
+
+// Every path starts with the id of the bond it belongs to
+const BOND_ID_LEN: usize = 16;
+const MAX_PATHS: usize = 8;
+
+// Frame: [u8 kind][u64 seq][u32 len][data]
+const HEADER_LEN: usize = 13;
+const FRAME_DATA: u8 = 0;
+const FRAME_ACK: u8 = 1;
+const FRAME_FIN: u8 = 2;
+const FRAME_PING: u8 = 3;
+const MAX_FRAME: usize = 16 * 1024;
+
+// Frames in flight before reading from the tunnel pauses
+const WINDOW: usize = 256;
+// Enough for a whole window plus its acks, so a live path never drops frames
+const PATH_QUEUE: usize = WINDOW * 2;
+const PIPE_SIZE: usize = 64 * 1024;
+const QUEUE_SIZE: usize = 16;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const JOIN_TIMEOUT: Duration = Duration::from_secs(5);
+// Idle paths are pinged, paths silent for PATH_TIMEOUT are considered lost
+const PING_INTERVAL: Duration = Duration::from_secs(5);
+const PATH_TIMEOUT: Duration = Duration::from_secs(15);
+// Pause between attempts to reconnect a lost path of a resumable bond
+const REDIAL_INTERVAL: Duration = Duration::from_secs(1);
+
+pub type BondQueue = Arc<Mutex<mpsc::Receiver<BondedStream>>>;
+
+// One stream carried over several TCP connections (paths). The bond itself runs in a
+// driver task, this is the tunnel's end of it.
+pub struct BondedStream {
+    stream: DuplexStream,
+    peer: SocketAddr,
+}
+
+impl BondedStream {
+    // Peer of the first path
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+}
+
+impl AsyncRead for BondedStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for BondedStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+enum Frame {
+    Data(u64, Vec<u8>),
+    Ack(u64),
+    Fin(u64),
+    Ping,
+}
+
+fn encode(kind: u8, seq: u64, data: &[u8]) -> Arc<[u8]> {
+    let mut frame = Vec::with_capacity(HEADER_LEN + data.len());
+    frame.push(kind);
+    frame.extend_from_slice(&seq.to_be_bytes());
+    frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    frame.extend_from_slice(data);
+    frame.into()
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Frame> {
+    let mut header = [0u8; HEADER_LEN];
+    reader.read_exact(&mut header).await?;
+    let seq = u64::from_be_bytes(header[1..9].try_into().unwrap());
+    let len = u32::from_be_bytes(header[9..13].try_into().unwrap()) as usize;
+    if len > MAX_FRAME {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Frame too large",
+        ));
+    }
+
+    let mut data = vec![0u8; len];
+    reader.read_exact(&mut data).await?;
+    Ok(match header[0] {
+        FRAME_DATA => Frame::Data(seq, data),
+        FRAME_ACK => Frame::Ack(seq),
+        FRAME_FIN => Frame::Fin(seq),
+        FRAME_PING => Frame::Ping,
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Unknown frame")),
+    })
+}
+
+enum PathEvent {
+    Frame(Frame),
+    Closed(usize),
+    // Another connection of the same bond was accepted
+    Joined(TcpStream),
+}
+
+// Spawn the reader and writer of a path, returns the queue of frames to send on it
+fn spawn_path(
+    idx: usize,
+    stream: TcpStream,
+    events: mpsc::Sender<PathEvent>,
+) -> mpsc::Sender<Arc<[u8]>> {
+    let (read, write) = stream.into_split();
+    let (sender, receiver) = mpsc::channel(PATH_QUEUE);
+    task::spawn(write_path(write, receiver));
+
+    task::spawn(async move {
+        let mut read = BufReader::new(read);
+        loop {
+            let event = match timeout(PATH_TIMEOUT, read_frame(&mut read)).await {
+                Ok(Ok(Frame::Ping)) => continue,
+                Ok(Ok(frame)) => PathEvent::Frame(frame),
+                _ => PathEvent::Closed(idx),
+            };
+            let closed = matches!(event, PathEvent::Closed(_));
+            if events.send(event).await.is_err() || closed {
+                return;
+            }
+        }
+    });
+
+    sender
+}
+
+// Ends when the bond is gone or the path is stuck, the bond notices the closed queue
+async fn write_path(mut write: OwnedWriteHalf, mut frames: mpsc::Receiver<Arc<[u8]>>) {
+    let ping = encode(FRAME_PING, 0, &[]);
+    loop {
+        let frame = match timeout(PING_INTERVAL, frames.recv()).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return,
+            Err(_) => ping.clone(),
+        };
+        match timeout(PATH_TIMEOUT, write.write_all(&frame)).await {
+            Ok(Ok(())) => {}
+            _ => return,
+        }
+    }
+}
+
+struct Bond {
+    mode: BondMode,
+    // Lost paths stay as None, their indices are never reused
+    paths: Vec<Option<mpsc::Sender<Arc<[u8]>>>>,
+    next_path: usize,
+    events: mpsc::Sender<PathEvent>,
+    // How long the bond survives without paths, zero if it can't be resumed
+    grace: Duration,
+    lost_since: Option<Instant>,
+    // Outbound bonds reconnect their lost paths while resumable
+    redial: Option<Redial>,
+    // Sending side, frames are kept until acknowledged
+    next_seq: u64,
+    unacked: VecDeque<(u64, Arc<[u8]>)>,
+    fin_sent: bool,
+    // Receiving side, None is the end of the stream. Frames are acknowledged once
+    // written to the tunnel, so the peer's window also bounds what is buffered here.
+    expected: u64,
+    delivered: u64,
+    pending: BTreeMap<u64, Option<Vec<u8>>>,
+    in_order: VecDeque<Option<Vec<u8>>>,
+    fin_received: bool,
+}
+
+// Where the paths of an outbound bond were connected to
+struct Redial {
+    id: [u8; BOND_ID_LEN],
+    addrs: Vec<Option<SocketAddr>>,
+}
+
+impl Bond {
+    fn new(mode: BondMode, grace: Duration, events: mpsc::Sender<PathEvent>) -> Self {
+        Self {
+            mode,
+            paths: Vec::new(),
+            next_path: 0,
+            events,
+            grace,
+            lost_since: None,
+            redial: None,
+            next_seq: 0,
+            unacked: VecDeque::new(),
+            fin_sent: false,
+            expected: 0,
+            delivered: 0,
+            pending: BTreeMap::new(),
+            in_order: VecDeque::new(),
+            fin_received: false,
+        }
+    }
+
+    fn add_path(&mut self, stream: TcpStream) {
+        if self.paths.iter().flatten().count() >= MAX_PATHS {
+            return;
+        }
+        let idx = self.paths.len();
+        let addr = stream.peer_addr();
+        debug!(target: LOG_TARGET, "Path #{} added ({:?})", idx, addr);
+        if let Some(redial) = &mut self.redial {
+            redial.addrs.push(addr.ok());
+        }
+
+        let resumed = self.lost_since.take().is_some();
+        self.paths
+            .push(Some(spawn_path(idx, stream, self.events.clone())));
+
+        // Nothing sent while all paths were gone has arrived
+        if resumed {
+            info!(target: LOG_TARGET, "Resumed on path #{}", idx);
+            let unacked: Vec<_> = self.unacked.iter().map(|(_, f)| f.clone()).collect();
+            for frame in unacked {
+                self.send(frame);
+            }
+        }
+    }
+
+    fn is_alive(&self) -> bool {
+        self.paths.iter().any(Option::is_some)
+    }
+
+    fn queue(&mut self, kind: u8, data: &[u8]) {
+        let frame = encode(kind, self.next_seq, data);
+        self.unacked.push_back((self.next_seq, frame.clone()));
+        self.next_seq += 1;
+        self.send(frame);
+    }
+
+    fn send(&mut self, frame: Arc<[u8]>) {
+        match self.mode {
+            BondMode::Duplicate => {
+                for idx in 0..self.paths.len() {
+                    self.send_on(idx, frame.clone());
+                }
+            }
+            // Round robin, skipping paths that are gone
+            BondMode::Stripe => {
+                for _ in 0..self.paths.len() {
+                    let idx = self.next_path % self.paths.len();
+                    self.next_path += 1;
+                    if self.send_on(idx, frame.clone()) {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    fn send_on(&mut self, idx: usize, frame: Arc<[u8]>) -> bool {
+        let Some(path) = &self.paths[idx] else {
+            return false;
+        };
+        match path.try_send(frame) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Full(_)) => false,
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                self.close_path(idx);
+                false
+            }
+        }
+    }
+
+    // Frames sent on a lost path may never arrive, send everything unacknowledged again
+    fn close_path(&mut self, idx: usize) {
+        if self.paths[idx].take().is_none() {
+            return;
+        }
+        info!(target: LOG_TARGET, "Path #{} lost", idx);
+
+        if let Some((redial, Some(addr))) = self.redial.as_ref().map(|r| (r, r.addrs[idx])) {
+            if !self.grace.is_zero() {
+                task::spawn(redial_path(
+                    addr,
+                    redial.id,
+                    self.events.clone(),
+                    Instant::now() + self.grace,
+                ));
+            }
+        }
+        if !self.is_alive() {
+            self.lost_since = Some(Instant::now());
+            if !self.grace.is_zero() {
+                info!(target: LOG_TARGET, "All paths lost, waiting {:?} to resume", self.grace);
+            }
+            return;
+        }
+
+        if self.mode == BondMode::Stripe {
+            let unacked: Vec<_> = self.unacked.iter().map(|(_, f)| f.clone()).collect();
+            for frame in unacked {
+                self.send(frame);
+            }
+        }
+    }
+
+    fn acknowledge(&mut self, seq: u64) {
+        while self.unacked.front().is_some_and(|(s, _)| *s < seq) {
+            self.unacked.pop_front();
+        }
+    }
+
+    fn receive(&mut self, seq: u64, data: Option<Vec<u8>>) {
+        // A frame seen before, its acknowledgement may have been lost with a path.
+        // Duplicate mode sends acknowledgements on every path already.
+        if seq < self.expected {
+            if self.mode == BondMode::Stripe {
+                self.acknowledge_delivered();
+            }
+            return;
+        }
+        // Frames beyond the window are dropped
+        if seq < self.delivered + PATH_QUEUE as u64 {
+            self.pending.entry(seq).or_insert(data);
+        }
+        while let Some(data) = self.pending.remove(&self.expected) {
+            self.in_order.push_back(data);
+            self.expected += 1;
+        }
+    }
+
+    fn mark_delivered(&mut self) {
+        self.delivered += 1;
+        self.acknowledge_delivered();
+    }
+
+    fn acknowledge_delivered(&mut self) {
+        self.send(encode(FRAME_ACK, self.delivered, &[]));
+    }
+
+    fn is_done(&self) -> bool {
+        self.fin_sent && self.unacked.is_empty() && self.fin_received
+    }
+}
+
+async fn drive(mut bond: Bond, local: DuplexStream, mut events: mpsc::Receiver<PathEvent>) {
+    let (mut local_read, mut local_write) = split(local);
+    let mut buffer = vec![0u8; MAX_FRAME];
+    // Frame being written to the tunnel, and how much of it is
+    let mut writing: Option<Vec<u8>> = None;
+    let mut written = 0;
+
+    while !bond.is_done() {
+        // Without paths the bond only lasts for its grace period
+        let resume_until = bond.lost_since.map(|lost| lost + bond.grace);
+        if resume_until.is_some_and(|until| Instant::now() >= until) {
+            break;
+        }
+
+        if writing.is_none() {
+            match bond.in_order.pop_front() {
+                Some(Some(data)) => {
+                    writing = Some(data);
+                    written = 0;
+                }
+                Some(None) => {
+                    let _ = local_write.shutdown().await;
+                    bond.fin_received = true;
+                    bond.mark_delivered();
+                    continue;
+                }
+                None => {}
+            }
+        }
+
+        let window_open = !bond.fin_sent && bond.unacked.len() < WINDOW;
+        tokio::select! {
+            read = local_read.read(&mut buffer), if window_open => match read {
+                Ok(0) | Err(_) => {
+                    bond.queue(FRAME_FIN, &[]);
+                    bond.fin_sent = true;
+                }
+                Ok(n) => bond.queue(FRAME_DATA, &buffer[..n]),
+            },
+            write = async { local_write.write(&writing.as_ref().unwrap()[written..]).await },
+                if writing.is_some() => match write {
+                Ok(n) if n > 0 => {
+                    written += n;
+                    if writing.as_ref().is_some_and(|data| written == data.len()) {
+                        writing = None;
+                        bond.mark_delivered();
+                    }
+                }
+                // The tunnel is gone
+                _ => return,
+            },
+            event = events.recv() => match event {
+                Some(PathEvent::Frame(Frame::Data(seq, data))) => bond.receive(seq, Some(data)),
+                Some(PathEvent::Frame(Frame::Fin(seq))) => bond.receive(seq, None),
+                Some(PathEvent::Frame(Frame::Ack(seq))) => bond.acknowledge(seq),
+                Some(PathEvent::Frame(Frame::Ping)) => {}
+                Some(PathEvent::Closed(idx)) => bond.close_path(idx),
+                Some(PathEvent::Joined(stream)) => bond.add_path(stream),
+                None => return,
+            },
+            _ = async { sleep_until(resume_until.unwrap()).await }, if resume_until.is_some() => {}
+        }
+    }
+
+    if !bond.is_alive() {
+        error!(target: LOG_TARGET, "All paths lost");
+    }
+}
+
+// Run a bond over the given paths, the returned sender adds paths to it
+fn start(
+    mode: BondMode,
+    grace: Duration,
+    redial_id: Option<[u8; BOND_ID_LEN]>,
+    paths: Vec<TcpStream>,
+    peer: SocketAddr,
+) -> (BondedStream, mpsc::Sender<PathEvent>) {
+    let (stream, local) = duplex(PIPE_SIZE);
+    let (events_sender, events) = mpsc::channel(PATH_QUEUE);
+
+    let mut bond = Bond::new(mode, grace, events_sender.clone());
+    bond.redial = redial_id.map(|id| Redial {
+        id,
+        addrs: Vec::new(),
+    });
+    for path in paths {
+        bond.add_path(path);
+    }
+    task::spawn(drive(bond, local, events));
+
+    (BondedStream { stream, peer }, events_sender)
+}
+
+async fn open_path(addr: SocketAddr, id: &[u8; BOND_ID_LEN]) -> io::Result<TcpStream> {
+    let mut stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(addr))
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+    stream.write_all(id).await?;
+    Ok(stream)
+}
+
+// Reconnect a lost path until the bond is gone or can't be resumed anymore
+async fn redial_path(
+    addr: SocketAddr,
+    id: [u8; BOND_ID_LEN],
+    events: mpsc::Sender<PathEvent>,
+    until: Instant,
+) {
+    while Instant::now() < until && !events.is_closed() {
+        sleep(REDIAL_INTERVAL).await;
+        match open_path(addr, &id).await {
+            Ok(stream) => {
+                let _ = events.send(PathEvent::Joined(stream)).await;
+                return;
+            }
+            Err(e) => debug!(target: LOG_TARGET, "Reconnecting to {} failed: {}", addr, e),
+        }
+    }
+}
+
+// Open a bond with a path to each address, as long as one of them can be reached. With a
+// grace period the bond outlives its paths for that long, reconnecting them meanwhile.
+pub async fn connect(
+    addrs: &[SocketAddr],
+    mode: BondMode,
+    grace: Duration,
+) -> Result<BondedStream> {
+    let id: [u8; BOND_ID_LEN] = rand::random();
+    let attempts = addrs.iter().map(|addr| open_path(*addr, &id));
+
+    let mut paths = Vec::new();
+    let mut last_error = None;
+    for (addr, result) in addrs.iter().zip(join_all(attempts).await) {
+        match result {
+            Ok(stream) => paths.push(stream),
+            Err(e) => {
+                debug!(target: LOG_TARGET, "Path to {} failed: {}", addr, e);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    let Some(peer) = paths.first().and_then(|p| p.peer_addr().ok()) else {
+        return Err(last_error
+            .unwrap_or(io::Error::from(io::ErrorKind::NotConnected))
+            .into());
+    };
+    Ok(start(mode, grace, Some(id), paths, peer).0)
+}
+
+// Accept bonds on the listener, paths of a known bond join it, new ones are queued. With a
+// grace period bonds wait that long for a path after losing all of them.
+pub fn listen(listener: Arc<Listener>, mode: BondMode, grace: Duration) -> BondQueue {
+    let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+    let bonds: Arc<Mutex<HashMap<[u8; BOND_ID_LEN], mpsc::Sender<PathEvent>>>> = Arc::default();
+
+    task::spawn(async move {
+        loop {
+            let (mut stream, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!(target: LOG_TARGET, "Accept failed: {}", e);
+                    continue;
+                }
+            };
+
+            task::spawn({
+                let sender = sender.clone();
+                let bonds = bonds.clone();
+                async move {
+                    let mut id = [0u8; BOND_ID_LEN];
+                    match timeout(JOIN_TIMEOUT, stream.read_exact(&mut id)).await {
+                        Ok(Ok(_)) => {}
+                        _ => {
+                            debug!(target: LOG_TARGET, "No bond id from {}", addr);
+                            return;
+                        }
+                    }
+
+                    let mut bonds = bonds.lock().await;
+                    bonds.retain(|_, events| !events.is_closed());
+                    match bonds.get(&id) {
+                        Some(events) => {
+                            let events = events.clone();
+                            drop(bonds);
+                            let _ = events.send(PathEvent::Joined(stream)).await;
+                        }
+                        None => {
+                            let (bonded, events) = start(mode, grace, None, vec![stream], addr);
+                            bonds.insert(id, events);
+                            drop(bonds);
+                            let _ = sender.send(bonded).await;
+                        }
+                    }
+                }
+            });
+        }
+    });
+
+    Arc::new(Mutex::new(receiver))
+}
@@ -0,0 +1,148 @@
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use std::{
+    io,
+    pin::Pin,
+    process::Stdio,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    process::{Child, ChildStdin, ChildStdout},
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task,
+    time::{timeout, Duration},
+};
+
+// Time a process gets to exit once its session is over, it is killed after that
+const EXIT_GRACE: Duration = Duration::from_secs(5);
+
+// The program an exec endpoint runs for each session, like inetd
+#[derive(Clone)]
+pub struct Command {
+    argv: Arc<[String]>,
+    // Processes running at once over every route of the endpoint, and how many may
+    processes: Option<(Arc<Semaphore>, usize)>,
+}
+
+impl Command {
+    pub fn new(argv: &[String], max_processes: Option<usize>) -> Self {
+        Self {
+            argv: argv.into(),
+            processes: max_processes.map(|max| (Arc::new(Semaphore::new(max)), max)),
+        }
+    }
+
+    // Its stderr is the process's own
+    pub fn spawn(&self, log_target: &str) -> Result<Process> {
+        let permit = match &self.processes {
+            Some((processes, max)) => Some(
+                processes
+                    .clone()
+                    .try_acquire_owned()
+                    .map_err(|_| anyhow!("Reached the limit of {} processes", max))?,
+            ),
+            None => None,
+        };
+        let (program, args) = self
+            .argv
+            .split_first()
+            .ok_or(anyhow!("No program to run"))?;
+        let mut child = tokio::process::Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| anyhow!("Couldn't run '{}': {}", program, e))?;
+        let (stdin, stdout) = (child.stdin.take(), child.stdout.take());
+        let (Some(stdin), Some(stdout)) = (stdin, stdout) else {
+            return Err(anyhow!("'{}' was started without its pipes", program));
+        };
+        Ok(Process {
+            stdin: Some(stdin),
+            stdout,
+            child: Some(child),
+            log_target: log_target.to_owned(),
+            permit,
+        })
+    }
+}
+
+// A running process as a stream: its stdin written to and its stdout read from. Shutting the
+// stream down closes stdin, the process is waited for and its exit status logged once dropped.
+pub struct Process {
+    stdin: Option<ChildStdin>,
+    stdout: ChildStdout,
+    child: Option<Child>,
+    log_target: String,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let Some(mut child) = self.child.take() else {
+            return;
+        };
+        // Without stdin the process can tell its session is over
+        self.stdin = None;
+        let (log_target, permit) = (self.log_target.clone(), self.permit.take());
+        task::spawn(async move {
+            let pid = child.id().unwrap_or_default();
+            match timeout(EXIT_GRACE, child.wait()).await {
+                Ok(Ok(status)) if status.success() => {
+                    info!(target: &log_target, "Process {} exited ({})", pid, status)
+                }
+                Ok(Ok(status)) => warn!(target: &log_target, "Process {} failed ({})", pid, status),
+                Ok(Err(e)) => warn!(target: &log_target, "Process {} is lost: {}", pid, e),
+                Err(_) => {
+                    warn!(
+                        target: &log_target,
+                        "Process {} still runs after {:?}, killing it", pid, EXIT_GRACE
+                    );
+                    let _ = child.kill().await;
+                }
+            }
+            drop(permit);
+        });
+    }
+}
+
+impl AsyncRead for Process {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdout).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Process {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.stdin {
+            Some(stdin) => Pin::new(stdin).poll_write(cx, buf),
+            None => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.stdin {
+            Some(stdin) => Pin::new(stdin).poll_flush(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    // Pipes have no half-close of their own, stdin is closed instead
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(stdin) = &mut self.stdin {
+            ready!(Pin::new(stdin).poll_flush(cx))?;
+        }
+        self.stdin = None;
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,361 @@
+use log::{debug, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot, watch},
+    task,
+    time::{self, Duration, Instant},
+};
+
+const LOG_TARGET: &str = "listener";
+
+// Between attempts of a retried bind
+const BIND_RETRY_INTERVAL: Duration = Duration::from_millis(500);
+// Accepting again after running out of descriptors or memory or after other failures, rather
+// than spinning on them
+const EXHAUSTED_BACKOFF: Duration = Duration::from_millis(250);
+// Connections whose SYN carried data, waiting to be accepted
+#[cfg(target_os = "linux")]
+const FAST_OPEN_QUEUE: libc::c_int = 256;
+
+// Socket options set before binding
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BindOptions {
+    // IPV6_V6ONLY of IPv6 sockets, the system default when unset
+    pub ipv6_only: Option<bool>,
+    // TCP Fast Open for the clients asking for it, Linux only
+    pub fast_open: bool,
+}
+
+// What an accept() error says about the listener
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcceptError {
+    // The connection was gone before it was accepted, the next one is unaffected
+    Transient,
+    // Out of descriptors or memory, accepting again right away fails the same way
+    Exhausted,
+    // Anything else, the listener itself may be broken
+    Failed,
+}
+
+impl AcceptError {
+    pub fn classify(error: &io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock => return Self::Transient,
+            io::ErrorKind::OutOfMemory => return Self::Exhausted,
+            _ => {}
+        }
+        #[cfg(target_os = "linux")]
+        match error.raw_os_error() {
+            Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM) => {
+                return Self::Exhausted
+            }
+            // Network errors pending on the new socket, passed on by accept(2)
+            Some(
+                libc::EPROTO
+                | libc::ENETDOWN
+                | libc::ENOPROTOOPT
+                | libc::EHOSTDOWN
+                | libc::ENONET
+                | libc::EHOSTUNREACH
+                | libc::EOPNOTSUPP
+                | libc::ENETUNREACH
+                | libc::EPERM,
+            ) => return Self::Transient,
+            _ => {}
+        }
+        Self::Failed
+    }
+}
+
+// accept() errors of a listener so far, by what they were
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AcceptErrors {
+    pub transient: u64,
+    pub exhausted: u64,
+    pub failed: u64,
+}
+
+#[derive(Default)]
+struct ErrorCounters {
+    transient: AtomicU64,
+    exhausted: AtomicU64,
+    failed: AtomicU64,
+    // Warned about until an accept goes through again
+    exhausting: AtomicBool,
+}
+
+// Continues an accept loop after error: right away for a transient one, after a pause when out
+// of resources. Other errors are returned.
+pub async fn recover(error: io::Error) -> io::Result<()> {
+    match AcceptError::classify(&error) {
+        AcceptError::Transient => Ok(()),
+        AcceptError::Exhausted => {
+            time::sleep(EXHAUSTED_BACKOFF).await;
+            Ok(())
+        }
+        AcceptError::Failed => Err(error),
+    }
+}
+
+type Accepted = io::Result<(TcpStream, SocketAddr)>;
+
+// A TcpListener that can be unbound and bound again while workers are waiting on it. One task
+// accepts on the socket for all of them, a connection for each worker waiting in turn, so a
+// new connection wakes a single worker and the others stay in the kernel's backlog.
+pub struct Listener {
+    addr: Arc<Mutex<SocketAddr>>,
+    options: BindOptions,
+    current: watch::Sender<Option<Arc<TcpListener>>>,
+    errors: Arc<ErrorCounters>,
+    // Workers waiting for a connection, answered in order by the accept task
+    waiting: mpsc::UnboundedSender<oneshot::Sender<Accepted>>,
+}
+
+impl Listener {
+    pub async fn bind(addr: SocketAddr) -> io::Result<Self> {
+        Self::bind_with(addr, BindOptions::default()).await
+    }
+
+    pub async fn bind_with(addr: SocketAddr, options: BindOptions) -> io::Result<Self> {
+        let listener = open(addr, options).await?;
+        // The port the system picked, if it was left to it
+        let addr = listener.local_addr()?;
+        Ok(Self::new(listener, addr, options))
+    }
+
+    // A socket bound and listening already, like the ones of socket activation. It stands for
+    // addr, which it may be bound more narrowly than. Unbinding closes it, binding again
+    // opens a socket of our own on addr.
+    pub fn from_std(
+        listener: std::net::TcpListener,
+        addr: SocketAddr,
+        options: BindOptions,
+    ) -> io::Result<Self> {
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        Ok(Self::new(listener, addr, options))
+    }
+
+    fn new(listener: TcpListener, addr: SocketAddr, options: BindOptions) -> Self {
+        let current = watch::Sender::new(Some(Arc::new(listener)));
+        let (waiting, requests) = mpsc::unbounded_channel();
+        let listener = Self {
+            addr: Arc::new(Mutex::new(addr)),
+            options,
+            current,
+            errors: Arc::default(),
+            waiting,
+        };
+        task::spawn(serve(
+            requests,
+            listener.current.subscribe(),
+            listener.errors.clone(),
+            listener.addr.clone(),
+        ));
+        listener
+    }
+
+    // Keeps trying while the address is in use, for a restart racing the old process or
+    // another service letting go of the port. The last error is returned once within is up.
+    pub async fn bind_retrying(
+        addr: SocketAddr,
+        options: BindOptions,
+        within: Duration,
+    ) -> io::Result<Self> {
+        let deadline = Instant::now() + within;
+        loop {
+            match Self::bind_with(addr, options).await {
+                Err(e) if e.kind() == io::ErrorKind::AddrInUse && Instant::now() < deadline => {
+                    time::sleep(BIND_RETRY_INTERVAL.min(deadline - Instant::now())).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        *self.addr.lock().unwrap()
+    }
+
+    pub fn is_bound(&self) -> bool {
+        self.current.borrow().is_some()
+    }
+
+    // Close the socket, pending accepts keep waiting until it is bound again
+    pub fn unbind(&self) {
+        self.current.send_replace(None);
+    }
+
+    pub async fn rebind(&self) -> io::Result<()> {
+        if !self.is_bound() {
+            let listener = open(self.addr(), self.options).await?;
+            self.current.send_replace(Some(Arc::new(listener)));
+        }
+        Ok(())
+    }
+
+    // Move to another address, pending accepts continue on the new socket. The old one is
+    // only closed once the new one is bound, an unbound listener just remembers the address.
+    pub async fn rebind_to(&self, addr: SocketAddr) -> io::Result<()> {
+        if self.is_bound() {
+            let listener = open(addr, self.options).await?;
+            self.current.send_replace(Some(Arc::new(listener)));
+        }
+        *self.addr.lock().unwrap() = addr;
+        Ok(())
+    }
+
+    pub fn errors(&self) -> AcceptErrors {
+        AcceptErrors {
+            transient: self.errors.transient.load(Ordering::Relaxed),
+            exhausted: self.errors.exhausted.load(Ordering::Relaxed),
+            failed: self.errors.failed.load(Ordering::Relaxed),
+        }
+    }
+
+    // Transient errors are retried right away and running out of resources after a pause, only
+    // other errors are returned, after the same pause for loops accepting again. Every one is
+    // counted. Safe to cancel, a connection accepted meanwhile goes to the next worker.
+    pub async fn accept(&self) -> Accepted {
+        let (reply, accepted) = oneshot::channel();
+        // The accept task lives as long as self
+        let _ = self.waiting.send(reply);
+        accepted
+            .await
+            .unwrap_or_else(|_| Err(io::ErrorKind::BrokenPipe.into()))
+    }
+}
+
+// The accept task of a listener, until the listener is dropped
+async fn serve(
+    mut requests: mpsc::UnboundedReceiver<oneshot::Sender<Accepted>>,
+    mut current: watch::Receiver<Option<Arc<TcpListener>>>,
+    errors: Arc<ErrorCounters>,
+    addr: Arc<Mutex<SocketAddr>>,
+) {
+    // Accepted for a worker that stopped waiting
+    let mut kept = None;
+    while let Some(mut reply) = requests.recv().await {
+        let accepted = match kept.take() {
+            Some(accepted) => Ok(accepted),
+            None => tokio::select! {
+                accepted = accept(&mut current, &errors, &addr) => accepted,
+                // Nobody to hand it to, the connection stays in the backlog
+                _ = reply.closed() => continue,
+            },
+        };
+        if let Err(Ok(accepted)) = reply.send(accepted) {
+            kept = Some(accepted);
+        }
+    }
+}
+
+async fn accept(
+    current: &mut watch::Receiver<Option<Arc<TcpListener>>>,
+    errors: &ErrorCounters,
+    addr: &Mutex<SocketAddr>,
+) -> Accepted {
+    loop {
+        let error = match accept_once(current).await {
+            Ok(accepted) => {
+                errors.exhausting.store(false, Ordering::Relaxed);
+                return Ok(accepted);
+            }
+            Err(e) => e,
+        };
+        let addr = *addr.lock().unwrap();
+        match AcceptError::classify(&error) {
+            AcceptError::Transient => {
+                errors.transient.fetch_add(1, Ordering::Relaxed);
+                debug!(target: LOG_TARGET, "Accept on {} failed: {}", addr, error);
+            }
+            AcceptError::Exhausted => {
+                errors.exhausted.fetch_add(1, Ordering::Relaxed);
+                if !errors.exhausting.swap(true, Ordering::Relaxed) {
+                    warn!(
+                        target: LOG_TARGET,
+                        "Accept on {} failed: {}, retrying every {:?}",
+                        addr,
+                        error,
+                        EXHAUSTED_BACKOFF
+                    );
+                }
+                time::sleep(EXHAUSTED_BACKOFF).await;
+            }
+            AcceptError::Failed => {
+                errors.failed.fetch_add(1, Ordering::Relaxed);
+                time::sleep(EXHAUSTED_BACKOFF).await;
+                return Err(error);
+            }
+        }
+    }
+}
+
+async fn accept_once(current: &mut watch::Receiver<Option<Arc<TcpListener>>>) -> Accepted {
+    loop {
+        let listener = current.borrow_and_update().clone();
+        let changed = match listener {
+            Some(listener) => tokio::select! {
+                result = listener.accept() => return result,
+                // Drop our handle so an unbound socket really gets closed
+                changed = current.changed() => changed,
+            },
+            None => current.changed().await,
+        };
+        // The listener is gone, along with the workers waiting on it
+        if changed.is_err() {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+    }
+}
+
+// Same as TcpListener::bind, with the options set before binding. SO_REUSEADDR lets a
+// restart bind while connections of the old process linger in TIME_WAIT.
+async fn open(addr: SocketAddr, options: BindOptions) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if let (Some(ipv6_only), true) = (options.ipv6_only, addr.is_ipv6()) {
+        socket.set_only_v6(ipv6_only)?;
+    }
+    #[cfg(target_os = "linux")]
+    if options.fast_open {
+        set_tcp_option(&socket, libc::TCP_FASTOPEN, FAST_OPEN_QUEUE)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+// TCP options socket2 has no setter for
+#[cfg(target_os = "linux")]
+pub fn set_tcp_option(socket: &Socket, option: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    // SAFETY: the socket is open for as long as the borrow, and value is the c_int the
+    // TCP options are read as
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            option,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    match result {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
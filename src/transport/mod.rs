@@ -0,0 +1,177 @@
+// The streams tunnels run over: TCP, bonds of several connections, TLS look-alikes, QUIC and
+// the sessions of other tunnels, TLS terminated for the clients of inbound endpoints, the pipes of processes and UDP flows
+pub mod bond;
+pub mod chain;
+pub mod exec;
+pub mod listener;
+pub mod obfs;
+#[cfg(feature = "quic")]
+pub mod quic;
+pub mod stdio;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod udp;
+
+#[cfg(feature = "quic")]
+use crate::transport::quic::QuicStream;
+use crate::transport::{
+    bond::BondedStream, chain::ChainedStream, exec::Process, obfs::ObfsStream, stdio::Stdio,
+};
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+
+// Anything a tunnel can run over (TcpStream, or an in-memory duplex in tests)
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Stream for T {}
+
+// What a tunnel endpoint runs over
+pub enum Transport {
+    Tcp(TcpStream),
+    Bonded(BondedStream),
+    Obfuscated(ObfsStream<TcpStream>),
+    #[cfg(feature = "quic")]
+    Quic(QuicStream),
+    // Inside of another tunnel, reaching the next relay through the first one
+    Chained(ChainedStream),
+}
+
+impl Transport {
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Transport::Tcp(stream) => stream.peer_addr(),
+            Transport::Bonded(stream) => Ok(stream.peer_addr()),
+            Transport::Obfuscated(stream) => stream.get_ref().peer_addr(),
+            #[cfg(feature = "quic")]
+            Transport::Quic(stream) => Ok(stream.peer_addr()),
+            Transport::Chained(stream) => Ok(stream.peer_addr()),
+        }
+    }
+
+    pub async fn peek(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Transport::Tcp(stream) => stream.peek(buffer).await,
+            // Only used to notice the peer leaving, records don't matter for that
+            Transport::Obfuscated(stream) => stream.get_ref().peek(buffer).await,
+            // A bond outlives its paths, it is only known to be gone once read
+            Transport::Bonded(_) => std::future::pending().await,
+            // Neither can the session carrying a chained tunnel
+            Transport::Chained(_) => std::future::pending().await,
+            // QUIC streams can't be peeked, only a lost connection is noticed
+            #[cfg(feature = "quic")]
+            Transport::Quic(stream) => {
+                stream.closed().await;
+                Ok(0)
+            }
+        }
+    }
+}
+
+impl AsyncRead for Transport {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Transport::Bonded(stream) => Pin::new(stream).poll_read(cx, buf),
+            Transport::Obfuscated(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(feature = "quic")]
+            Transport::Quic(stream) => Pin::new(stream).poll_read(cx, buf),
+            Transport::Chained(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Transport {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Transport::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Transport::Bonded(stream) => Pin::new(stream).poll_write(cx, buf),
+            Transport::Obfuscated(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "quic")]
+            Transport::Quic(stream) => Pin::new(stream).poll_write(cx, buf),
+            Transport::Chained(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Transport::Bonded(stream) => Pin::new(stream).poll_flush(cx),
+            Transport::Obfuscated(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "quic")]
+            Transport::Quic(stream) => Pin::new(stream).poll_flush(cx),
+            Transport::Chained(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Transport::Bonded(stream) => Pin::new(stream).poll_shutdown(cx),
+            Transport::Obfuscated(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(feature = "quic")]
+            Transport::Quic(stream) => Pin::new(stream).poll_shutdown(cx),
+            Transport::Chained(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+// The stdin and stdout of this process, or of one run for the session
+pub enum Pipe {
+    Stdio(Stdio),
+    Process(Process),
+}
+
+impl AsyncRead for Pipe {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Pipe::Stdio(stream) => Pin::new(stream).poll_read(cx, buf),
+            Pipe::Process(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Pipe {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Pipe::Stdio(stream) => Pin::new(stream).poll_write(cx, buf),
+            Pipe::Process(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Pipe::Stdio(stream) => Pin::new(stream).poll_flush(cx),
+            Pipe::Process(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Pipe::Stdio(stream) => Pin::new(stream).poll_shutdown(cx),
+            Pipe::Process(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
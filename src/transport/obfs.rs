@@ -0,0 +1,368 @@
+use crate::transport::Stream;
+use anyhow::Result;
+use rand::{Rng, RngCore};
+use std::{
+    cmp, io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    time::{timeout, Duration},
+};
+
+// Tunnel connections made to look like TLS 1.3: hellos made of random values, then the
+// tunnel's bytes wrapped in application data records. It hides the fixed sizes of the
+// tunnel handshake, the tunnel's own encryption is what protects the data.
+const RECORD_CHANGE_CIPHER_SPEC: u8 = 0x14;
+const RECORD_HANDSHAKE: u8 = 0x16;
+const RECORD_APPLICATION_DATA: u8 = 0x17;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const HANDSHAKE_SERVER_HELLO: u8 = 0x02;
+const HEADER_LEN: usize = 5;
+const MAX_RECORD: usize = 16 * 1024;
+// Encrypted records may carry a bit more than their plaintext
+const MAX_RECORD_READ: usize = MAX_RECORD + 256;
+const SESSION_ID_LEN: usize = 32;
+const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+
+const CIPHER_SUITES: [u16; 9] = [
+    0x1301, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8,
+];
+const GROUP_X25519: u16 = 0x001d;
+const EXT_SERVER_NAME: u16 = 0x0000;
+const EXT_SUPPORTED_GROUPS: u16 = 0x000a;
+const EXT_SIGNATURE_ALGORITHMS: u16 = 0x000d;
+const EXT_ALPN: u16 = 0x0010;
+const EXT_PADDING: u16 = 0x0015;
+const EXT_SUPPORTED_VERSIONS: u16 = 0x002b;
+const EXT_KEY_SHARE: u16 = 0x0033;
+
+// Obfuscation of the tunnel connections of an endpoint
+#[derive(Clone)]
+pub struct Obfuscation {
+    // Shown in the outbound side's ClientHello
+    pub server_name: Option<String>,
+}
+
+impl Obfuscation {
+    pub async fn connect<S: Stream>(&self, mut stream: S) -> Result<ObfsStream<S>> {
+        stream
+            .write_all(&client_hello(self.server_name.as_deref()))
+            .await?;
+
+        // ServerHello, ChangeCipherSpec and the "encrypted" rest of the server's handshake
+        timeout(HELLO_TIMEOUT, async {
+            read_record(&mut stream, RECORD_HANDSHAKE).await?;
+            read_record(&mut stream, RECORD_CHANGE_CIPHER_SPEC).await?;
+            read_record(&mut stream, RECORD_APPLICATION_DATA).await
+        })
+        .await??;
+
+        // ChangeCipherSpec and "Finished"
+        let mut finish = record(RECORD_CHANGE_CIPHER_SPEC, &[1]);
+        finish.extend(record(RECORD_APPLICATION_DATA, &random_bytes(53)));
+        stream.write_all(&finish).await?;
+
+        Ok(ObfsStream::new(stream, 0))
+    }
+
+    pub async fn accept<S: Stream>(&self, mut stream: S) -> Result<ObfsStream<S>> {
+        let hello = timeout(HELLO_TIMEOUT, read_record(&mut stream, RECORD_HANDSHAKE)).await??;
+        let session_id = parse_client_hello(&hello).ok_or(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Not a ClientHello",
+        ))?;
+
+        let mut reply = record(RECORD_HANDSHAKE, &server_hello(session_id));
+        reply.extend(record(RECORD_CHANGE_CIPHER_SPEC, &[1]));
+        let handshake_len = rand::thread_rng().gen_range(1000..3000);
+        reply.extend(record(
+            RECORD_APPLICATION_DATA,
+            &random_bytes(handshake_len),
+        ));
+        stream.write_all(&reply).await?;
+
+        // The client's "Finished" is skipped
+        Ok(ObfsStream::new(stream, 1))
+    }
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; len];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes
+}
+
+fn record(kind: u8, payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(HEADER_LEN + payload.len());
+    record.extend_from_slice(&[kind, 0x03, 0x03]);
+    record.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    record.extend_from_slice(payload);
+    record
+}
+
+async fn read_record<S: Stream>(stream: &mut S, kind: u8) -> io::Result<Vec<u8>> {
+    let mut header = [0u8; HEADER_LEN];
+    stream.read_exact(&mut header).await?;
+    let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+    if header[0] != kind || len > MAX_RECORD_READ {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Unexpected record",
+        ));
+    }
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload).await?;
+    Ok(payload)
+}
+
+// [type][u24 len][body]
+fn handshake_message(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut message = vec![kind];
+    message.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+    message.extend_from_slice(body);
+    message
+}
+
+fn extension(message: &mut Vec<u8>, kind: u16, data: &[u8]) {
+    message.extend_from_slice(&kind.to_be_bytes());
+    message.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    message.extend_from_slice(data);
+}
+
+fn client_hello(server_name: Option<&str>) -> Vec<u8> {
+    let mut extensions = Vec::new();
+    if let Some(name) = server_name {
+        let mut data = Vec::new();
+        data.extend_from_slice(&(name.len() as u16 + 3).to_be_bytes());
+        data.push(0); // host_name
+        data.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        data.extend_from_slice(name.as_bytes());
+        extension(&mut extensions, EXT_SERVER_NAME, &data);
+    }
+    extension(
+        &mut extensions,
+        EXT_SUPPORTED_GROUPS,
+        &[0x00, 0x02, 0x00, 0x1d],
+    );
+    extension(
+        &mut extensions,
+        EXT_SIGNATURE_ALGORITHMS,
+        &[0x00, 0x06, 0x04, 0x03, 0x08, 0x04, 0x04, 0x01],
+    );
+    extension(&mut extensions, EXT_ALPN, b"\x00\x0c\x02h2\x08http/1.1");
+    extension(&mut extensions, EXT_SUPPORTED_VERSIONS, &[0x02, 0x03, 0x04]);
+    extension(&mut extensions, EXT_KEY_SHARE, &key_share(true));
+    // Varies the size of the hello
+    let padding = rand::thread_rng().gen_range(0..256);
+    extension(&mut extensions, EXT_PADDING, &vec![0u8; padding]);
+
+    let mut body = vec![0x03, 0x03];
+    body.extend(random_bytes(32));
+    body.push(SESSION_ID_LEN as u8);
+    body.extend(random_bytes(SESSION_ID_LEN));
+    body.extend_from_slice(&(CIPHER_SUITES.len() as u16 * 2).to_be_bytes());
+    for suite in CIPHER_SUITES {
+        body.extend_from_slice(&suite.to_be_bytes());
+    }
+    body.extend_from_slice(&[0x01, 0x00]); // null compression
+    body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+    body.extend(extensions);
+
+    let mut hello = record(
+        RECORD_HANDSHAKE,
+        &handshake_message(HANDSHAKE_CLIENT_HELLO, &body),
+    );
+    // ClientHellos go out with the TLS 1.0 record version
+    hello[2] = 0x01;
+    hello
+}
+
+fn server_hello(session_id: &[u8]) -> Vec<u8> {
+    let mut extensions = Vec::new();
+    extension(&mut extensions, EXT_SUPPORTED_VERSIONS, &[0x03, 0x04]);
+    extension(&mut extensions, EXT_KEY_SHARE, &key_share(false));
+
+    let mut body = vec![0x03, 0x03];
+    body.extend(random_bytes(32));
+    body.push(session_id.len() as u8);
+    body.extend_from_slice(session_id);
+    body.extend_from_slice(&CIPHER_SUITES[0].to_be_bytes());
+    body.push(0x00);
+    body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+    body.extend(extensions);
+
+    handshake_message(HANDSHAKE_SERVER_HELLO, &body)
+}
+
+// A random "public key", the client sends it as a list
+fn key_share(list: bool) -> Vec<u8> {
+    let mut share = Vec::new();
+    if list {
+        share.extend_from_slice(&36u16.to_be_bytes());
+    }
+    share.extend_from_slice(&GROUP_X25519.to_be_bytes());
+    share.extend_from_slice(&32u16.to_be_bytes());
+    share.extend(random_bytes(32));
+    share
+}
+
+// The session id, echoed back by the server
+fn parse_client_hello(hello: &[u8]) -> Option<&[u8]> {
+    // [type][u24 len][u16 version][32 random][session id]
+    if *hello.first()? != HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+    let session_id_len = *hello.get(38)? as usize;
+    if session_id_len > SESSION_ID_LEN {
+        return None;
+    }
+    hello.get(39..39 + session_id_len)
+}
+
+// The tunnel's end of an obfuscated connection
+pub struct ObfsStream<S> {
+    stream: S,
+    // Reading side: header of the next record, bytes left of the current one
+    header: [u8; HEADER_LEN],
+    header_read: usize,
+    remaining: usize,
+    discard: bool,
+    // Application data records to skip, the peer's handshake leftovers
+    skip: usize,
+    // Writing side: record being written
+    pending: Vec<u8>,
+    written: usize,
+}
+
+impl<S> ObfsStream<S> {
+    fn new(stream: S, skip: usize) -> Self {
+        Self {
+            stream,
+            header: [0u8; HEADER_LEN],
+            header_read: 0,
+            remaining: 0,
+            discard: false,
+            skip,
+            pending: Vec::new(),
+            written: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+}
+
+impl<S: AsyncWrite + Unpin> ObfsStream<S> {
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.pending.len() {
+            let n =
+                ready!(Pin::new(&mut self.stream).poll_write(cx, &self.pending[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ObfsStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        loop {
+            // Next record
+            if this.remaining == 0 {
+                while this.header_read < HEADER_LEN {
+                    let mut header = ReadBuf::new(&mut this.header[this.header_read..]);
+                    ready!(Pin::new(&mut this.stream).poll_read(cx, &mut header))?;
+                    let n = header.filled().len();
+                    if n == 0 && this.header_read == 0 {
+                        return Poll::Ready(Ok(())); // EOF
+                    }
+                    if n == 0 {
+                        return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                    }
+                    this.header_read += n;
+                }
+                this.header_read = 0;
+                this.remaining = u16::from_be_bytes([this.header[3], this.header[4]]) as usize;
+                if this.remaining > MAX_RECORD_READ {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Record too large",
+                    )));
+                }
+                this.discard = this.header[0] != RECORD_APPLICATION_DATA || this.skip > 0;
+                if this.header[0] == RECORD_APPLICATION_DATA && this.skip > 0 {
+                    this.skip -= 1;
+                }
+                continue;
+            }
+
+            // Payload, application data goes straight into the caller's buffer
+            let mut scratch = [0u8; 256];
+            let target = match this.discard {
+                true => &mut scratch[..],
+                false => buf.initialize_unfilled(),
+            };
+            let limit = cmp::min(this.remaining, target.len());
+            let mut payload = ReadBuf::new(&mut target[..limit]);
+            ready!(Pin::new(&mut this.stream).poll_read(cx, &mut payload))?;
+            let n = payload.filled().len();
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            this.remaining -= n;
+            if !this.discard {
+                buf.advance(n);
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ObfsStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let n = cmp::min(buf.len(), MAX_RECORD);
+        this.pending = record(RECORD_APPLICATION_DATA, &buf[..n]);
+        this.written = 0;
+        // The record is ours now, what doesn't go out here does on the next write or flush
+        if let Poll::Ready(Err(e)) = this.poll_pending(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
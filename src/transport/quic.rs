@@ -0,0 +1,298 @@
+use anyhow::Result;
+use log::debug;
+use quinn::{
+    crypto::rustls::{QuicClientConfig, QuicServerConfig},
+    ClientConfig, Connection, Endpoint, RecvStream, SendStream, ServerConfig, TransportConfig,
+};
+use rcgen::{CertificateParams, KeyPair, PKCS_ED25519};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{ring, verify_tls13_signature_with_raw_key, CryptoProvider},
+    pki_types::{
+        CertificateDer, PrivatePkcs8KeyDer, ServerName, SubjectPublicKeyInfoDer, UnixTime,
+    },
+    DigitallySignedStruct, SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::{mpsc, Mutex},
+    task,
+    time::{timeout, Duration},
+};
+
+const LOG_TARGET: &str = "quic";
+const ALPN: &[u8] = b"veloxid";
+const SERVER_NAME: &str = "veloxid";
+const KEY_CONTEXT: &[u8] = b"veloxid-quic-key";
+// PKCS#8 v1 header of a raw Ed25519 private key
+const ED25519_PKCS8_PREFIX: [u8; 16] = [
+    0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20,
+];
+// SubjectPublicKeyInfo header of a raw Ed25519 public key
+const ED25519_SPKI_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+// Streams are invisible to the peer until something is written, the connector opens
+// them with this byte since the relay speaks first
+const OPEN_MARKER: u8 = 0;
+const OPEN_TIMEOUT: Duration = Duration::from_secs(5);
+const KEEP_ALIVE: Duration = Duration::from_secs(10);
+const QUEUE_SIZE: usize = 16;
+
+pub type QuicQueue = Arc<Mutex<mpsc::Receiver<QuicStream>>>;
+
+// One bidirectional stream of a QUIC connection
+pub struct QuicStream {
+    connection: Connection,
+    send: SendStream,
+    recv: RecvStream,
+}
+
+impl QuicStream {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.connection.remote_address()
+    }
+
+    pub async fn closed(&self) {
+        self.connection.closed().await;
+    }
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncWrite::poll_write(Pin::new(&mut self.get_mut().send), cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().send).poll_shutdown(cx)
+    }
+}
+
+// The relay's key pair is derived from the secret, so only peers knowing the secret can
+// impersonate it. Clients are still authenticated by the tunnel handshake on each stream.
+fn key_pair(secret: &[u8; 32]) -> Result<KeyPair> {
+    let seed = Sha256::new()
+        .chain_update(KEY_CONTEXT)
+        .chain_update(secret)
+        .finalize();
+    let pkcs8 = [&ED25519_PKCS8_PREFIX[..], &seed[..]].concat();
+    Ok(KeyPair::from_pkcs8_der_and_sign_algo(
+        &PrivatePkcs8KeyDer::from(pkcs8),
+        &PKCS_ED25519,
+    )?)
+}
+
+fn server_config(secret: &[u8; 32]) -> Result<ServerConfig> {
+    let key_pair = key_pair(secret)?;
+    let cert = CertificateParams::new(vec![SERVER_NAME.to_owned()])?.self_signed(&key_pair)?;
+
+    let mut crypto =
+        rustls::ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![cert.der().clone()],
+                PrivatePkcs8KeyDer::from(key_pair.serialize_der()).into(),
+            )?;
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+
+    Ok(ServerConfig::with_crypto(Arc::new(
+        QuicServerConfig::try_from(crypto)?,
+    )))
+}
+
+fn client_config(secret: &[u8; 32]) -> Result<ClientConfig> {
+    let provider = Arc::new(ring::default_provider());
+    let verifier = SecretVerifier {
+        spki: [&ED25519_SPKI_PREFIX[..], key_pair(secret)?.public_key_raw()].concat(),
+        provider: provider.clone(),
+    };
+
+    let mut crypto = rustls::ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+
+    // Keep idle sessions from timing out
+    let mut transport = TransportConfig::default();
+    transport.keep_alive_interval(Some(KEEP_ALIVE));
+
+    let mut config = ClientConfig::new(Arc::new(QuicClientConfig::try_from(crypto)?));
+    config.transport_config(Arc::new(transport));
+    Ok(config)
+}
+
+// Accepts the relay if it proves to own the key derived from the secret, the certificate
+// itself carries no trust
+#[derive(Debug)]
+struct SecretVerifier {
+    spki: Vec<u8>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for SecretVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Err(rustls::Error::General(
+            "TLS 1.2 is not supported".to_owned(),
+        ))
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        _cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature_with_raw_key(
+            message,
+            &SubjectPublicKeyInfoDer::from(self.spki.as_slice()),
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        vec![SignatureScheme::ED25519]
+    }
+}
+
+// Accept QUIC connections on addr, their streams are queued for the workers
+pub fn listen(addr: SocketAddr, secret: &[u8; 32]) -> Result<QuicQueue> {
+    let endpoint = Endpoint::server(server_config(secret)?, addr)?;
+    let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+
+    task::spawn(async move {
+        while let Some(incoming) = endpoint.accept().await {
+            let sender = sender.clone();
+            task::spawn(async move {
+                match incoming.await {
+                    Ok(connection) => accept_streams(connection, sender).await,
+                    Err(e) => debug!(target: LOG_TARGET, "Handshake failed: {}", e),
+                }
+            });
+        }
+    });
+
+    Ok(Arc::new(Mutex::new(receiver)))
+}
+
+async fn accept_streams(connection: Connection, sender: mpsc::Sender<QuicStream>) {
+    let peer = connection.remote_address();
+    debug!(target: LOG_TARGET, "Connection from {}", peer);
+
+    while let Ok((send, mut recv)) = connection.accept_bi().await {
+        let mut marker = [0u8; 1];
+        match timeout(OPEN_TIMEOUT, recv.read_exact(&mut marker)).await {
+            Ok(Ok(())) if marker[0] == OPEN_MARKER => {}
+            _ => {
+                debug!(target: LOG_TARGET, "Bad stream opening from {}", peer);
+                continue;
+            }
+        }
+
+        let stream = QuicStream {
+            connection: connection.clone(),
+            send,
+            recv,
+        };
+        if sender.send(stream).await.is_err() {
+            return;
+        }
+    }
+    debug!(target: LOG_TARGET, "Connection from {} closed", peer);
+}
+
+// Opens streams to a relay, sessions share one connection while it is alive
+pub struct QuicConnector {
+    endpoint: Endpoint,
+    addr: SocketAddr,
+    connection: Mutex<Option<Connection>>,
+}
+
+impl QuicConnector {
+    pub fn new(addr: SocketAddr, secret: &[u8; 32]) -> Result<Self> {
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let mut endpoint = Endpoint::client(local)?;
+        endpoint.set_default_client_config(client_config(secret)?);
+
+        Ok(Self {
+            endpoint,
+            addr,
+            connection: Mutex::new(None),
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub async fn open(&self) -> Result<QuicStream> {
+        let connection = {
+            let mut current = self.connection.lock().await;
+            match current.as_ref().filter(|c| c.close_reason().is_none()) {
+                Some(connection) => connection.clone(),
+                None => {
+                    let connection = self.endpoint.connect(self.addr, SERVER_NAME)?.await?;
+                    debug!(target: LOG_TARGET, "Connected to {}", self.addr);
+                    *current = Some(connection.clone());
+                    connection
+                }
+            }
+        };
+
+        let (mut send, recv) = connection.open_bi().await?;
+        send.write_all(&[OPEN_MARKER]).await?;
+        Ok(QuicStream {
+            connection,
+            send,
+            recv,
+        })
+    }
+}
@@ -0,0 +1,80 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{ready, Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf, Stdin, Stdout},
+    sync::Notify,
+};
+
+// The process has a single stdin and stdout, for the first session taking them
+static TAKEN: AtomicBool = AtomicBool::new(false);
+// That session is over, stdout shut down or the stream dropped
+static CLOSED: Notify = Notify::const_new();
+
+// Stdin and stdout as one stream
+pub struct Stdio {
+    stdin: Stdin,
+    stdout: Stdout,
+}
+
+impl Stdio {
+    // None once taken
+    pub fn take() -> Option<Self> {
+        if TAKEN.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+        Some(Self {
+            stdin: tokio::io::stdin(),
+            stdout: tokio::io::stdout(),
+        })
+    }
+}
+
+pub fn taken() -> bool {
+    TAKEN.load(Ordering::Acquire)
+}
+
+// Reading stdin can't be given up on, the session is over once stdout is shut down even if
+// stdin is still open
+pub async fn closed() {
+    CLOSED.notified().await
+}
+
+impl Drop for Stdio {
+    fn drop(&mut self) {
+        CLOSED.notify_one();
+    }
+}
+
+impl AsyncRead for Stdio {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdin).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Stdio {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stdout).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdout).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(Pin::new(&mut self.stdout).poll_shutdown(cx))?;
+        CLOSED.notify_one();
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,239 @@
+use anyhow::{anyhow, Context as _, Result};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{ring, verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig,
+    SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    io,
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+use subtle::ConstantTimeEq;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+    time::{timeout, Duration},
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use webpki::EndEntityCert;
+
+// Handshakes with clients, once their ClientHello is in, and with targets take at most this long
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// TLS clients of an inbound endpoint talk to veloxid, the routes carry the plaintext
+#[derive(Clone)]
+pub struct Termination {
+    config: Arc<ServerConfig>,
+}
+
+impl Termination {
+    // The certificate chain and its key, PEM files
+    pub fn load(cert: &Path, key: &Path) -> Result<Self> {
+        let chain = CertificateDer::pem_file_iter(cert)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .with_context(|| format!("Couldn't read certificates from '{}'", cert.display()))?;
+        if chain.is_empty() {
+            return Err(anyhow!("No certificate in '{}'", cert.display()));
+        }
+        let key = PrivateKeyDer::from_pem_file(key)
+            .with_context(|| format!("Couldn't read a private key from '{}'", key.display()))?;
+        let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(chain, key)?;
+        Ok(Self {
+            config: Arc::new(config),
+        })
+    }
+
+    // Acceptor negotiating one of these ALPN protocols, the first of them the client offers.
+    // None are negotiated when empty.
+    pub fn acceptor(&self, alpn: &[String]) -> TlsAcceptor {
+        let mut config = (*self.config).clone();
+        config.alpn_protocols = alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+        TlsAcceptor::from(Arc::new(config))
+    }
+}
+
+// Server side of the handshake, bounded in time
+pub async fn accept(acceptor: &TlsAcceptor, stream: TcpStream) -> Result<TlsStream> {
+    timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+        .await
+        .map_err(|_| anyhow!("TLS handshake timed out"))?
+        .map(|stream| TlsStream(stream.into()))
+        .map_err(|e| anyhow!("TLS handshake failed: {}", e))
+}
+
+// TLS veloxid opens to the targets of an outbound endpoint, carrying the plaintext of the route
+#[derive(Clone)]
+pub struct Origination {
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+}
+
+impl Origination {
+    // Targets are checked against the CA certificates of a PEM file, or the system's
+    pub fn new(server_name: &str, ca: Option<&Path>) -> Result<Self> {
+        let mut roots = RootCertStore::empty();
+        match ca {
+            Some(ca) => {
+                let certs = CertificateDer::pem_file_iter(ca)
+                    .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                    .with_context(|| {
+                        format!("Couldn't read certificates from '{}'", ca.display())
+                    })?;
+                roots.add_parsable_certificates(certs);
+            }
+            None => {
+                roots.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+            }
+        }
+        if roots.is_empty() {
+            return Err(anyhow!("No CA certificate to check the targets against"));
+        }
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(Self {
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name: ServerName::try_from(server_name.to_owned())
+                .map_err(|_| anyhow!("Invalid server name '{}'", server_name))?,
+        })
+    }
+
+    // Targets are taken by the SHA-256 of their public key (SPKI) instead, whoever signed their
+    // certificate and whatever name it is for. For self-hosted ones with self-signed certificates.
+    pub fn pinned(server_name: &str, spki_sha256: [u8; 32]) -> Result<Self> {
+        let provider = Arc::new(ring::default_provider());
+        let verifier = PinnedKey {
+            spki_sha256,
+            algorithms: provider.signature_verification_algorithms,
+        };
+        let config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth();
+        Ok(Self {
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name: ServerName::try_from(server_name.to_owned())
+                .map_err(|_| anyhow!("Invalid server name '{}'", server_name))?,
+        })
+    }
+
+    // Client side of the handshake, bounded in time
+    pub async fn connect(&self, stream: TcpStream) -> Result<TlsStream> {
+        let handshake = self.connector.connect(self.server_name.clone(), stream);
+        timeout(HANDSHAKE_TIMEOUT, handshake)
+            .await
+            .map_err(|_| anyhow!("TLS handshake timed out"))?
+            .map(|stream| TlsStream(stream.into()))
+            .map_err(|e| anyhow!("TLS handshake failed: {}", e))
+    }
+}
+
+// Takes the certificate with the pinned public key, the handshake is still checked to be signed
+// with it
+#[derive(Debug)]
+struct PinnedKey {
+    spki_sha256: [u8; 32],
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for PinnedKey {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let cert = EndEntityCert::try_from(end_entity)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+        let spki = cert.subject_public_key_info();
+        let digest: [u8; 32] = Sha256::digest(spki.as_ref()).into();
+        match bool::from(digest.ct_eq(&self.spki_sha256)) {
+            true => Ok(ServerCertVerified::assertion()),
+            false => Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            )),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+// TLS terminated for a client or opened to a target. Most peers close the connection without a
+// close_notify, which is taken as the end of the stream rather than an error.
+pub struct TlsStream(tokio_rustls::TlsStream<TcpStream>);
+
+impl TlsStream {
+    pub fn get_ref(&self) -> &TcpStream {
+        self.0.get_ref().0
+    }
+
+    // The ALPN protocol agreed on with the peer
+    pub fn alpn(&self) -> Option<String> {
+        let protocol = self.0.get_ref().1.alpn_protocol()?;
+        Some(String::from_utf8_lossy(protocol).into_owned())
+    }
+}
+
+impl AsyncRead for TlsStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match ready!(Pin::new(&mut self.0).poll_read(cx, buf)) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Poll::Ready(Ok(())),
+            result => Poll::Ready(result),
+        }
+    }
+}
+
+impl AsyncWrite for TlsStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,262 @@
+use crate::protocol::datagram::{frame_len, LENGTH_LEN, MAX_DATAGRAM};
+use dashmap::DashMap;
+use log::debug;
+use socket2::SockRef;
+use std::{
+    future::Future,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll, Waker},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::UdpSocket,
+    sync::{mpsc, Mutex},
+    task,
+    time::{sleep, Duration, Instant, Sleep},
+};
+
+const LOG_TARGET: &str = "udp";
+// Flows end after this long without a datagram either way
+pub const DEFAULT_FLOW_TIMEOUT: Duration = Duration::from_secs(60);
+// New flows waiting for a worker, and datagrams of a flow waiting to be read. Any more are
+// dropped, as on a busy link.
+const QUEUE_SIZE: usize = 16;
+const FLOW_QUEUE_SIZE: usize = 64;
+
+pub type UdpQueue = Arc<Mutex<mpsc::Receiver<UdpFlow>>>;
+
+// Flows of an inbound endpoint by the address of their client, like a NAT's
+type FlowTable = Arc<DashMap<SocketAddr, mpsc::Sender<Vec<u8>>>>;
+
+// Binds the endpoint's socket, each client address sending to it is a flow of its own
+pub async fn listen(addr: SocketAddr, flow_timeout: Duration) -> io::Result<UdpQueue> {
+    let socket = Arc::new(UdpSocket::bind(addr).await?);
+    let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+    task::spawn(demux(socket, Default::default(), sender, flow_timeout));
+    Ok(Arc::new(Mutex::new(receiver)))
+}
+
+// Passes the datagrams to the flows of their clients, new clients start a flow
+async fn demux(
+    socket: Arc<UdpSocket>,
+    table: FlowTable,
+    flows: mpsc::Sender<UdpFlow>,
+    flow_timeout: Duration,
+) {
+    let mut buffer = vec![0u8; MAX_DATAGRAM];
+    while !flows.is_closed() {
+        let (len, client) = match socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            // ICMP errors of earlier datagrams sent, among others
+            Err(e) => {
+                debug!(target: LOG_TARGET, "Receiving failed: {}", e);
+                continue;
+            }
+        };
+        let datagram = buffer[..len].to_vec();
+        if let Some(flow) = table.get(&client) {
+            let _ = flow.try_send(datagram);
+            continue;
+        }
+
+        let (sender, receiver) = mpsc::channel(FLOW_QUEUE_SIZE);
+        let _ = sender.try_send(datagram);
+        table.insert(client, sender);
+        let mut flow = UdpFlow::new(socket.clone(), client, flow_timeout);
+        flow.incoming = Some(receiver);
+        flow.table = Some(table.clone());
+        // Dropped with the table entry when no worker can take it
+        if flows.try_send(flow).is_err() {
+            debug!(target: LOG_TARGET, "No room for a flow from {}", client);
+        }
+    }
+}
+
+// The datagrams exchanged with one peer, as a stream of length-prefixed datagrams. Reading
+// ends once nothing was sent or received for the flow timeout, or once it is shut down.
+pub struct UdpFlow {
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    // Inbound flows get their datagrams from the endpoint's socket, outbound ones have a
+    // connected socket of their own
+    incoming: Option<mpsc::Receiver<Vec<u8>>>,
+    table: Option<FlowTable>,
+    // A datagram read after its length, and how much of it was taken
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    read_waker: Option<Waker>,
+    // Bytes written that aren't a whole datagram yet
+    write_buf: Vec<u8>,
+    idle: Pin<Box<Sleep>>,
+    flow_timeout: Duration,
+    closed: bool,
+}
+
+impl UdpFlow {
+    fn new(socket: Arc<UdpSocket>, peer: SocketAddr, flow_timeout: Duration) -> Self {
+        Self {
+            socket,
+            peer,
+            incoming: None,
+            table: None,
+            read_buf: Vec::new(),
+            read_pos: 0,
+            read_waker: None,
+            write_buf: Vec::new(),
+            idle: Box::pin(sleep(flow_timeout)),
+            flow_timeout,
+            closed: false,
+        }
+    }
+
+    // A socket of its own for the flow, its replies come from the target only
+    pub async fn connect(addr: SocketAddr, flow_timeout: Duration) -> io::Result<Self> {
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(addr).await?;
+        Ok(Self::new(Arc::new(socket), addr, flow_timeout))
+    }
+
+    // The client of an inbound flow, the target of an outbound one
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    fn touch(&mut self) {
+        let deadline = Instant::now() + self.flow_timeout;
+        self.idle.as_mut().reset(deadline);
+    }
+
+    // The next datagram into read_buf, after its length. None at the end of the flow.
+    fn poll_datagram(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<Option<()>>> {
+        let len = match &mut self.incoming {
+            Some(incoming) => {
+                let Some(datagram) = ready!(incoming.poll_recv(cx)) else {
+                    return Poll::Ready(Ok(None));
+                };
+                self.read_buf.clear();
+                self.read_buf
+                    .extend_from_slice(&(datagram.len() as u16).to_be_bytes());
+                self.read_buf.extend_from_slice(&datagram);
+                self.read_pos = 0;
+                return Poll::Ready(Ok(Some(())));
+            }
+            None => loop {
+                self.read_buf.resize(LENGTH_LEN + MAX_DATAGRAM, 0);
+                let mut buf = ReadBuf::new(&mut self.read_buf[LENGTH_LEN..]);
+                let received = self.socket.poll_recv(cx, &mut buf);
+                let len = buf.filled().len();
+                match received {
+                    Poll::Ready(Ok(())) => break len,
+                    // Nobody listens at the target (yet), as far as ICMP tells
+                    Poll::Ready(Err(e)) if e.kind() == io::ErrorKind::ConnectionRefused => continue,
+                    // Nothing was read
+                    received => {
+                        self.read_buf.clear();
+                        self.read_pos = 0;
+                        return received.map_ok(|()| None);
+                    }
+                }
+            },
+        };
+        self.read_buf[..LENGTH_LEN].copy_from_slice(&(len as u16).to_be_bytes());
+        self.read_buf.truncate(LENGTH_LEN + len);
+        self.read_pos = 0;
+        Poll::Ready(Ok(Some(())))
+    }
+
+    // Sends the whole datagrams written so far. Those the socket has no room for, or that
+    // fail, are dropped as on a busy link.
+    fn send_written(&mut self) {
+        while let Some(len) = frame_len(&self.write_buf) {
+            let datagram = &self.write_buf[LENGTH_LEN..len];
+            // Straight to the socket, tokio's own try_send waits for the reactor to have seen
+            // it writable once
+            let socket = SockRef::from(&*self.socket);
+            let sent = match self.incoming {
+                Some(_) => socket.send_to(datagram, &self.peer.into()),
+                None => socket.send(datagram),
+            };
+            if let Err(e) = sent {
+                debug!(target: LOG_TARGET, "Dropped a datagram to {}: {}", self.peer, e);
+            }
+            self.write_buf.drain(..len);
+            self.touch();
+        }
+    }
+}
+
+impl Drop for UdpFlow {
+    fn drop(&mut self) {
+        if let Some(table) = &self.table {
+            table.remove(&self.peer);
+        }
+    }
+}
+
+impl AsyncRead for UdpFlow {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.read_pos == this.read_buf.len() && !this.closed {
+            match this.poll_datagram(cx)? {
+                Poll::Ready(Some(())) => this.touch(),
+                Poll::Ready(None) => this.closed = true,
+                Poll::Pending => match this.idle.as_mut().poll(cx) {
+                    Poll::Ready(()) => {
+                        debug!(target: LOG_TARGET, "Flow of {} timed out", this.peer);
+                        this.closed = true;
+                    }
+                    Poll::Pending => {
+                        this.read_waker = Some(cx.waker().clone());
+                        return Poll::Pending;
+                    }
+                },
+            }
+        }
+        if this.closed && this.read_pos == this.read_buf.len() {
+            return Poll::Ready(Ok(()));
+        }
+        let available = &this.read_buf[this.read_pos..];
+        let len = available.len().min(buf.remaining());
+        buf.put_slice(&available[..len]);
+        this.read_pos += len;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for UdpFlow {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.write_buf.extend_from_slice(buf);
+        this.send_written();
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    // Datagrams have no half-close, the flow is over once the other side is
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.closed = true;
+        if let Some(waker) = this.read_waker.take() {
+            waker.wake();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
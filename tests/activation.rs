@@ -0,0 +1,44 @@
+use std::net::{SocketAddr, TcpListener};
+use veloxid::{
+    activation::Activated,
+    transport::listener::{BindOptions, Listener},
+};
+
+#[test]
+fn sockets_go_to_the_endpoint_of_their_address() {
+    let socket = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    let activated = Activated::new(vec![socket]);
+
+    let elsewhere: SocketAddr = format!("127.0.0.2:{}", addr.port()).parse().unwrap();
+    assert!(activated.take(elsewhere).is_none());
+    assert_eq!(activated.remaining(), vec![addr]);
+
+    assert!(activated.take(addr).is_some());
+    assert!(activated.take(addr).is_none());
+    assert!(activated.remaining().is_empty());
+}
+
+#[test]
+fn endpoints_on_every_address_match_by_port() {
+    let socket = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = socket.local_addr().unwrap().port();
+    let activated = Activated::new(vec![socket]);
+
+    let any: SocketAddr = format!("0.0.0.0:{}", port).parse().unwrap();
+    assert!(activated.take(any).is_some());
+}
+
+#[tokio::test]
+async fn passed_sockets_accept_without_binding() {
+    let socket = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    let any: SocketAddr = format!("0.0.0.0:{}", addr.port()).parse().unwrap();
+    let listener = Listener::from_std(socket, any, BindOptions::default()).unwrap();
+    // Reloads compare it with the endpoint's address
+    assert_eq!(listener.addr(), any);
+
+    let client = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let (_, peer) = listener.accept().await.unwrap();
+    assert_eq!(peer, client.local_addr().unwrap());
+}
@@ -0,0 +1,282 @@
+use std::{collections::HashMap, net::SocketAddr, time::SystemTime};
+use tokio::{sync::watch, time::Duration};
+use veloxid::{
+    admin::{self, AdminState, RouteControl},
+    budget::Budget,
+    config::VeloxidConfig,
+    events::SessionInfo,
+    latency::LatencyTable,
+    relay::connection,
+    scaling::Scaling,
+    services::ServiceTable,
+    sessions::SessionRegistry,
+    stun::{NatKind, NatReport, NatTable},
+};
+
+fn state() -> AdminState {
+    let route = |name: &str, endpoints: [&str; 2]| RouteControl {
+        name: name.to_owned(),
+        endpoints: endpoints.map(str::to_owned),
+        enabled: watch::Sender::new(true),
+        scaling: Scaling::new(2, 2, None),
+    };
+    AdminState {
+        routes: vec![
+            route("ssh-home", ["ssh-in", "ssh"]),
+            route("route #1", ["web-in", "web"]),
+        ],
+        endpoints: HashMap::new(),
+        latency: LatencyTable::default(),
+        services: HashMap::new(),
+        registry: SessionRegistry::default(),
+        budget: None,
+        nat: NatTable::default(),
+    }
+}
+
+#[tokio::test]
+async fn routes_are_listed_by_name() {
+    let output = admin::execute(&state(), "routes").await.unwrap();
+    assert_eq!(
+        output,
+        "0 ssh-home enabled ssh-in -> ssh\n1 route #1 enabled web-in -> web\n"
+    );
+}
+
+#[tokio::test]
+async fn routes_are_found_by_name_or_index() {
+    let state = state();
+    admin::execute(&state, "disable ssh-home").await.unwrap();
+    assert!(!*state.routes[0].enabled.borrow());
+
+    admin::execute(&state, "disable #1").await.unwrap();
+    assert!(!*state.routes[1].enabled.borrow());
+
+    assert!(admin::execute(&state, "enable ssh-away").await.is_err());
+}
+
+#[tokio::test]
+async fn latency_lists_the_tunnels_with_their_round_trips() {
+    let state = state();
+    let peer: SocketAddr = "192.0.2.1:40000".parse().unwrap();
+    let timed = state.latency.track("ssh-home worker #0", "A", Some(peer));
+    timed.record(Duration::from_millis(40));
+    timed.record(Duration::from_millis(20));
+    let _untimed = state.latency.track("ssh-home worker #1", "A", None);
+
+    let output = admin::execute(&state, "latency").await.unwrap();
+    assert_eq!(
+        output,
+        "ssh-home worker #0 A 192.0.2.1:40000 20.0ms/37.5ms/20.0ms 2\n\
+         ssh-home worker #1 A - -/-/- 0\n"
+    );
+
+    drop(timed);
+    assert_eq!(state.latency.len(), 1);
+}
+
+#[tokio::test]
+async fn services_are_listed_by_endpoint() {
+    let mut state = state();
+    let mut table = ServiceTable::default();
+    table.queue("web");
+    table.queue("ssh");
+    state.services.insert("registry".to_owned(), table);
+
+    let output = admin::execute(&state, "services").await.unwrap();
+    assert_eq!(output, "registry ssh 0\nregistry web 0\n");
+}
+
+#[tokio::test]
+async fn clients_lists_the_limited_endpoints() {
+    let config = VeloxidConfig::parse(
+        r#"
+[endpoints.limited]
+host = "127.0.0.1"
+port = 0
+type = "direct"
+direction = "inbound"
+max_conns_per_ip = 4
+
+[endpoints.open]
+host = "127.0.0.1"
+port = 0
+type = "direct"
+direction = "inbound"
+"#,
+    )
+    .unwrap();
+    let mut state = state();
+    for (name, endpoint) in &config.endpoints {
+        let data = connection::get_connection_data(endpoint).await.unwrap();
+        state.endpoints.insert(name.clone(), data);
+    }
+
+    let output = admin::execute(&state, "clients").await.unwrap();
+    assert_eq!(output, "limited 4 0 0\n");
+}
+
+#[tokio::test]
+async fn sessions_are_listed_and_killed_by_id() {
+    let state = state();
+    let handle = state.registry.register(SessionInfo {
+        id: 7,
+        route: "ssh-home worker #0".to_owned(),
+        route_name: String::new(),
+        endpoints: Default::default(),
+        connector: None,
+        peer_a: Some("192.0.2.1:40000".parse().unwrap()),
+        peer_b: None,
+        authenticated: true,
+        started: SystemTime::now(),
+    });
+    handle
+        .traffic
+        .a_to_b
+        .fetch_add(5, std::sync::atomic::Ordering::Relaxed);
+
+    let output = admin::execute(&state, "sessions").await.unwrap();
+    assert_eq!(output, "7 192.0.2.1:40000 - 0 5 0 ssh-home worker #0\n");
+
+    assert!(admin::execute(&state, "kill 8").await.is_err());
+    assert!(admin::execute(&state, "kill seven").await.is_err());
+    admin::execute(&state, "kill 7").await.unwrap();
+    tokio::time::timeout(Duration::from_secs(1), handle.killed())
+        .await
+        .expect("kill wasn't passed on");
+}
+
+#[tokio::test]
+async fn limits_show_the_budget() {
+    let mut state = state();
+    assert!(admin::execute(&state, "limits").await.is_err());
+
+    let budget = Budget::new(Some(10), 1, [], state.registry.clone());
+    let _slot = budget.slot(0).await;
+    state.budget = Some(budget);
+    let output = admin::execute(&state, "limits").await.unwrap();
+    let lines: Vec<_> = output.lines().collect();
+    assert_eq!(lines[0], "sessions 1 10");
+    assert!(lines[1].starts_with("descriptors "));
+    assert_eq!(lines[2], "pauses 0");
+}
+
+#[tokio::test]
+async fn listeners_show_their_accept_errors() {
+    let config = VeloxidConfig::parse(
+        r#"
+[endpoints.web-in]
+host = "127.0.0.1"
+port = 0
+type = "direct"
+direction = "inbound"
+
+[endpoints.web]
+host = "127.0.0.1"
+port = 80
+type = "direct"
+direction = "outbound"
+"#,
+    )
+    .unwrap();
+    let mut state = state();
+    for (name, endpoint) in &config.endpoints {
+        let data = connection::get_connection_data(endpoint).await.unwrap();
+        state.endpoints.insert(name.clone(), data);
+    }
+
+    let output = admin::execute(&state, "listeners").await.unwrap();
+    let addr = state.listeners()[0].1.addr();
+    assert_eq!(output, format!("web-in {} bound 0 0 0\n", addr));
+}
+
+#[tokio::test]
+async fn statistics_add_up_the_sessions_and_list_the_bans() {
+    let state = state();
+    let info = |id| SessionInfo {
+        id,
+        route: "ssh-home worker #0".to_owned(),
+        route_name: "ssh-home".to_owned(),
+        endpoints: Default::default(),
+        connector: None,
+        peer_a: None,
+        peer_b: None,
+        authenticated: false,
+        started: SystemTime::now(),
+    };
+    let add = |handle: &veloxid::sessions::SessionHandle, bytes| {
+        handle
+            .traffic
+            .b_to_a
+            .fetch_add(bytes, std::sync::atomic::Ordering::Relaxed);
+    };
+    let finished = state.registry.register(info(1));
+    add(&finished, 100);
+    drop(finished);
+    let running = state.registry.register(info(2));
+    add(&running, 20);
+
+    let ban_list = dashmap::DashMap::new();
+    let now = tokio::time::Instant::now();
+    ban_list.insert("192.0.2.9".parse().unwrap(), now + Duration::from_secs(600));
+    // Expired, not listed
+    ban_list.insert("192.0.2.8".parse().unwrap(), now);
+
+    let output = admin::statistics(&state, &ban_list);
+    let lines: Vec<_> = output.lines().collect();
+    assert_eq!(lines[0], "Sessions: 1 running, 2 since the start");
+    assert_eq!(
+        lines[1],
+        "Traffic: 0 bytes A->B, 120 bytes B->A since the start"
+    );
+    assert_eq!(
+        lines[2],
+        "Route 'ssh-home' (ssh-in -> ssh): enabled, 1 sessions, 0 workers (0 busy)"
+    );
+    assert_eq!(lines[4], "Bans: 1");
+    assert!(lines[5].starts_with("  192.0.2.9 for 59"), "{}", lines[5]);
+    drop(running);
+}
+
+#[tokio::test]
+async fn nat_lists_the_reports() {
+    let state = state();
+    let local: SocketAddr = "10.0.0.2:5000".parse().unwrap();
+    let mapped: SocketAddr = "203.0.113.1:6000".parse().unwrap();
+    state.nat.record(
+        "198.51.100.1".to_owned(),
+        NatReport {
+            local,
+            mapped: vec![mapped, mapped],
+            kind: NatKind::EndpointIndependent,
+        },
+    );
+    let output = admin::execute(&state, "nat").await.unwrap();
+    assert_eq!(
+        output,
+        "198.51.100.1 endpoint-independent 10.0.0.2:5000 203.0.113.1:6000,203.0.113.1:6000 0\n"
+    );
+}
+
+#[tokio::test]
+async fn only_stale_sockets_are_replaced() {
+    let path = std::env::temp_dir().join(format!("veloxid-admin-{}.sock", std::process::id()));
+    let path = path.to_str().unwrap();
+    let _ = std::fs::remove_file(path);
+
+    // Another instance's socket stays its own
+    let running = admin::bind(path).unwrap();
+    assert!(admin::bind(path).is_err());
+
+    // Left behind once it is gone
+    drop(running);
+    let listener = admin::bind(path).unwrap();
+    drop(listener);
+
+    // Anything else is no socket to replace
+    std::fs::remove_file(path).unwrap();
+    std::fs::write(path, "not a socket").unwrap();
+    assert!(admin::bind(path).is_err());
+    assert_eq!(std::fs::read_to_string(path).unwrap(), "not a socket");
+    std::fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,69 @@
+use futures::FutureExt;
+use std::{collections::HashMap, net::IpAddr};
+use tokio::time::Duration;
+use veloxid::balance::{Balancer, Waiter};
+
+fn connector(last: u8) -> IpAddr {
+    IpAddr::from([192, 0, 2, last])
+}
+
+fn has_turn(waiter: &mut Waiter) -> bool {
+    waiter.turn().now_or_never().is_some()
+}
+
+#[test]
+fn turns_follow_the_weights() {
+    let (a, b) = (connector(1), connector(2));
+    let balancer = Balancer::new(HashMap::from([(a, 3)]));
+
+    // Two tunnels of each connector wait, every one taking a client is replaced
+    let mut waiting: Vec<(IpAddr, Waiter)> = [a, a, b, b]
+        .into_iter()
+        .map(|c| (c, balancer.wait(c, None)))
+        .collect();
+    let mut taken_by_a = 0;
+    for _ in 0..4000 {
+        let idx = waiting
+            .iter_mut()
+            .position(|(_, waiter)| has_turn(waiter))
+            .expect("a waiting tunnel has the turn");
+        let (taken, waiter) = waiting.remove(idx);
+        drop(waiter);
+        if taken == a {
+            taken_by_a += 1;
+        }
+        waiting.push((taken, balancer.wait(taken, None)));
+    }
+    assert!((2800..3200).contains(&taken_by_a), "{}", taken_by_a);
+}
+
+#[test]
+fn the_turn_passes_on_when_the_tunnel_is_gone() {
+    let balancer = Balancer::new(HashMap::new());
+    let mut first = balancer.wait(connector(1), None);
+    let mut second = balancer.wait(connector(2), None);
+    assert!(has_turn(&mut first));
+    assert!(!has_turn(&mut second));
+
+    drop(first);
+    assert!(has_turn(&mut second));
+}
+
+#[test]
+fn slow_and_failing_connectors_get_less() {
+    let (fast, slow) = (connector(1), connector(2));
+    let balancer = Balancer::new(HashMap::from([(fast, 2)]));
+    let _fast = balancer.wait(fast, Some(Duration::from_millis(20)));
+    let _slow = balancer.wait(slow, Some(Duration::from_millis(200)));
+    assert_eq!(balancer.weight(fast), 2.0);
+    assert!((balancer.weight(slow) - 0.1).abs() < 1e-9);
+
+    for _ in 0..10 {
+        balancer.report(fast, false);
+    }
+    assert!(balancer.weight(fast) < 1.0);
+    for _ in 0..50 {
+        balancer.report(fast, true);
+    }
+    assert!(balancer.weight(fast) > 1.9);
+}
@@ -0,0 +1,98 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{copy_bidirectional, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::{self, JoinHandle},
+    time::{timeout, Duration},
+};
+use veloxid::{
+    config::BondMode,
+    transport::{bond, listener::Listener},
+};
+
+const GRACE: Duration = Duration::from_secs(10);
+
+async fn free_addr() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap()
+}
+
+// Forwards connections to addr, dropping all of them when the link "goes down"
+struct Link {
+    addr: SocketAddr,
+    connections: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl Link {
+    async fn new(to: SocketAddr) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections: Arc<Mutex<Vec<JoinHandle<()>>>> = Arc::default();
+        task::spawn({
+            let connections = connections.clone();
+            async move {
+                while let Ok((mut client, _)) = listener.accept().await {
+                    let connection = task::spawn(async move {
+                        let mut server = TcpStream::connect(to).await.unwrap();
+                        let _ = copy_bidirectional(&mut client, &mut server).await;
+                    });
+                    connections.lock().unwrap().push(connection);
+                }
+            }
+        });
+        Self { addr, connections }
+    }
+
+    fn drop_connections(&self) {
+        for connection in self.connections.lock().unwrap().drain(..) {
+            connection.abort();
+        }
+    }
+}
+
+#[tokio::test]
+async fn session_resumes_after_the_only_path_drops() {
+    let relay = free_addr().await;
+    let listener = Listener::bind(relay).await.unwrap();
+    let queue = bond::listen(Arc::new(listener), BondMode::Stripe, GRACE);
+    let link = Link::new(relay).await;
+
+    let mut outbound = bond::connect(&[link.addr], BondMode::Stripe, GRACE)
+        .await
+        .unwrap();
+    let mut inbound = queue.lock().await.recv().await.unwrap();
+
+    let mut received = [0u8; 5];
+    outbound.write_all(b"hello").await.unwrap();
+    inbound.read_exact(&mut received).await.unwrap();
+    assert_eq!(&received, b"hello");
+
+    link.drop_connections();
+    outbound.write_all(b"again").await.unwrap();
+    timeout(GRACE, inbound.read_exact(&mut received))
+        .await
+        .expect("session wasn't resumed")
+        .unwrap();
+    assert_eq!(&received, b"again");
+}
+
+#[tokio::test]
+async fn bond_without_grace_ends_with_its_paths() {
+    let relay = free_addr().await;
+    let listener = Listener::bind(relay).await.unwrap();
+    let queue = bond::listen(Arc::new(listener), BondMode::Stripe, Duration::ZERO);
+    let link = Link::new(relay).await;
+
+    let _outbound = bond::connect(&[link.addr], BondMode::Stripe, Duration::ZERO)
+        .await
+        .unwrap();
+    let mut inbound = queue.lock().await.recv().await.unwrap();
+
+    link.drop_connections();
+    let mut buffer = [0u8; 1];
+    let read = timeout(GRACE, inbound.read(&mut buffer)).await.unwrap();
+    assert!(matches!(read, Ok(0) | Err(_)));
+}
@@ -0,0 +1,89 @@
+use std::time::SystemTime;
+use tokio::time::{timeout, Duration};
+use veloxid::{budget::Budget, events::SessionInfo, sessions::SessionRegistry};
+
+const WAIT: Duration = Duration::from_millis(100);
+
+fn routes() -> [(String, u8); 2] {
+    [("bulk".to_owned(), 0), ("ssh".to_owned(), 10)]
+}
+
+fn info(id: u64, route: &str) -> SessionInfo {
+    SessionInfo {
+        id,
+        route: route.to_owned(),
+        route_name: String::new(),
+        endpoints: Default::default(),
+        connector: None,
+        peer_a: None,
+        peer_b: None,
+        authenticated: false,
+        started: SystemTime::now(),
+    }
+}
+
+#[tokio::test]
+async fn low_priorities_stop_accepting_first() {
+    let budget = Budget::new(Some(4), 1, routes(), SessionRegistry::default());
+    assert_eq!(budget.ceiling(0), Some(3));
+    assert_eq!(budget.ceiling(10), Some(4));
+
+    let mut slots = Vec::new();
+    for _ in 0..3 {
+        slots.push(budget.slot(0).await);
+    }
+    assert!(timeout(WAIT, budget.room(0)).await.is_err());
+    assert!(timeout(WAIT, budget.slot(0)).await.is_err());
+    timeout(WAIT, budget.room(10)).await.unwrap();
+
+    // The last session is the high priority's, ended ones make room again
+    slots.push(budget.slot(10).await);
+    assert!(timeout(WAIT, budget.room(10)).await.is_err());
+    let waiting = tokio::spawn({
+        let budget = budget.clone();
+        async move { budget.room(10).await }
+    });
+    slots.pop();
+    timeout(WAIT, waiting).await.unwrap().unwrap();
+    assert_eq!(budget.used(), 3);
+}
+
+#[tokio::test]
+async fn idle_sessions_of_lower_priorities_are_reclaimed() {
+    let registry = SessionRegistry::default();
+    let budget = Budget::new(Some(2), 0, routes(), registry.clone()).reclaim_idle(WAIT);
+    let bulk = registry.register(info(1, "bulk worker #0"));
+    let ssh = registry.register(info(2, "ssh worker #0"));
+    let _slots = [budget.slot(0).await, budget.slot(10).await];
+
+    // Routes of the same priority only wait
+    assert!(timeout(WAIT * 3, budget.room(0)).await.is_err());
+    assert!(timeout(WAIT, bulk.killed()).await.is_err());
+
+    // Higher ones close the idle session below them
+    let reclaiming = tokio::spawn({
+        let budget = budget.clone();
+        async move { budget.room(10).await }
+    });
+    timeout(WAIT * 3, bulk.killed()).await.unwrap();
+    reclaiming.abort();
+    assert!(timeout(WAIT, ssh.killed()).await.is_err());
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn accepting_pauses_without_descriptors_to_spare() {
+    let (open, limit) = veloxid::budget::descriptors().unwrap();
+    assert!(open > 0 && open < limit);
+
+    let spare = Budget::new(None, 0, routes(), SessionRegistry::default()).fd_headroom(1);
+    timeout(WAIT, spare.headroom()).await.unwrap();
+    timeout(WAIT, spare.slot(0)).await.unwrap();
+
+    // More than the limit leaves, every accept loop waits and the pause is counted
+    let starved = Budget::new(None, 0, routes(), SessionRegistry::default()).fd_headroom(limit);
+    assert!(timeout(WAIT, starved.headroom()).await.is_err());
+    assert!(timeout(WAIT, starved.slot(10)).await.is_err());
+    let stats = starved.stats();
+    assert_eq!((stats.sessions, stats.pauses), (0, 2));
+}
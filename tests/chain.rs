@@ -0,0 +1,185 @@
+use std::net::SocketAddr;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+    task,
+    time::{timeout, Duration},
+};
+use veloxid::{
+    config::VeloxidConfig,
+    connect,
+    relay::connection::{self, ConnectionData},
+};
+
+// Runs a worker of the route between endpoints a and b of the config, returning the addresses
+// of the inbound ones
+async fn route(config: &str, a: &str, b: &str) -> Vec<SocketAddr> {
+    let config = VeloxidConfig::parse(config).unwrap();
+    let mut addrs = Vec::new();
+    let mut data = Vec::new();
+    for name in [a, b] {
+        let endpoint = connection::get_chained_connection_data(name, &config.endpoints)
+            .await
+            .unwrap();
+        if let ConnectionData::Inbound { listener, .. } = &endpoint {
+            addrs.push(listener.addr());
+        }
+        data.push(endpoint);
+    }
+    let [endpoint_a, endpoint_b] = <[_; 2]>::try_from(data).ok().unwrap();
+    let ctx = connect::context(&config.endpoints[a], Default::default());
+    let enabled = watch::Sender::new(true);
+    let receiver = enabled.subscribe();
+    task::spawn(async move {
+        connection::route(endpoint_a, endpoint_b, ctx, receiver, "chain").await;
+        drop(enabled);
+    });
+    addrs
+}
+
+async fn echo_server() -> u16 {
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = server.local_addr().unwrap().port();
+    task::spawn(async move {
+        while let Ok((mut stream, _)) = server.accept().await {
+            task::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                tokio::io::copy(&mut reader, &mut writer).await
+            });
+        }
+    });
+    port
+}
+
+// Two relays, the first one taking its tunnels' sessions to the second one's tunnel port and
+// the second one its clients to the tunnel opened through the first one. Returns the first
+// relay's tunnel port and the second one's public address.
+async fn relays(cipher: &str) -> (u16, SocketAddr) {
+    let relay2 = route(
+        &format!(
+            r#"
+            [endpoints.tunnel-in]
+            host = "127.0.0.1"
+            type = "tunnel"
+            direction = "inbound"
+            secret = "inner"
+            {}
+
+            [endpoints.public]
+            host = "127.0.0.1"
+            type = "direct"
+            direction = "inbound"
+            "#,
+            cipher
+        ),
+        "tunnel-in",
+        "public",
+    )
+    .await;
+    let [tunnel2, public] = relay2[..] else {
+        panic!("relay 2 listens twice");
+    };
+
+    // The first relay only sees the outer tunnel, its sessions go to the second relay
+    let relay1 = route(
+        &format!(
+            r#"
+            [endpoints.tunnel-in]
+            host = "127.0.0.1"
+            type = "tunnel"
+            direction = "inbound"
+            secret = "outer"
+            {}
+
+            [endpoints.next]
+            host = "127.0.0.1"
+            port = {}
+            type = "direct"
+            direction = "outbound"
+            "#,
+            cipher,
+            tunnel2.port()
+        ),
+        "tunnel-in",
+        "next",
+    )
+    .await;
+    (relay1[0].port(), public)
+}
+
+async fn ping(public: SocketAddr) {
+    let mut client = TcpStream::connect(public).await.unwrap();
+    client.write_all(b"two hops").await.unwrap();
+    let mut echo = [0u8; 8];
+    timeout(Duration::from_secs(10), client.read_exact(&mut echo))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&echo, b"two hops");
+}
+
+#[tokio::test]
+async fn chained_tunnels_reach_the_second_relay_through_the_first() {
+    let server_port = echo_server().await;
+    let (relay1, public) = relays("").await;
+    route(
+        &format!(
+            r#"
+            [endpoints.hop1]
+            host = "127.0.0.1"
+            port = {}
+            type = "tunnel"
+            direction = "outbound"
+            secret = "outer"
+
+            [endpoints.hop2]
+            type = "tunnel"
+            direction = "outbound"
+            secret = "inner"
+            via = "hop1"
+
+            [endpoints.server]
+            host = "127.0.0.1"
+            port = {}
+            type = "direct"
+            direction = "outbound"
+            "#,
+            relay1, server_port
+        ),
+        "hop2",
+        "server",
+    )
+    .await;
+    ping(public).await;
+}
+
+#[tokio::test]
+async fn hops_nest_a_sealed_layer_for_each_relay() {
+    let server_port = echo_server().await;
+    let (relay1, public) = relays("cipher = \"chacha20-poly1305\"").await;
+    route(
+        &format!(
+            r#"
+            [endpoints.onion]
+            host = "127.0.0.1"
+            port = {}
+            type = "tunnel"
+            direction = "outbound"
+            secret = "outer"
+            hops = ["inner"]
+
+            [endpoints.server]
+            host = "127.0.0.1"
+            port = {}
+            type = "direct"
+            direction = "outbound"
+            "#,
+            relay1, server_port
+        ),
+        "onion",
+        "server",
+    )
+    .await;
+    ping(public).await;
+}
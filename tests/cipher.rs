@@ -0,0 +1,372 @@
+mod common;
+
+use common::{secret, PEER, PIPE_SIZE};
+use tokio::{
+    io::{duplex, AsyncReadExt, AsyncWriteExt},
+    task,
+    time::{timeout, Duration},
+};
+use veloxid::{
+    config::CipherKind,
+    error::TunnelError,
+    protocol::cipher::{RekeyLimits, SessionKeys},
+    relay::tunnel::{Traffic, Tunnel},
+};
+
+// Relay and connector using the cipher, returns the traffic seen by the relay
+async fn echo_session(cipher: CipherKind, request: &[u8]) -> Traffic {
+    let (inbound_stream, outbound_stream) = duplex(PIPE_SIZE);
+    let outbound = task::spawn(Tunnel::init_with_cipher(
+        outbound_stream,
+        PEER,
+        false,
+        secret("1234"),
+        cipher,
+    ));
+    let inbound = Tunnel::init_with_cipher(inbound_stream, PEER, true, secret("1234"), cipher)
+        .await
+        .unwrap();
+
+    let (mut client, relay_side) = duplex(PIPE_SIZE);
+    let (connector_side, mut server) = duplex(PIPE_SIZE);
+    let relay = task::spawn(inbound.run(relay_side, Default::default()));
+    task::spawn(async move {
+        let outbound = outbound.await.unwrap().unwrap();
+        outbound.run(connector_side, Default::default()).await
+    });
+    task::spawn(async move {
+        let mut received = Vec::new();
+        server.read_to_end(&mut received).await.unwrap();
+        server.write_all(&received).await.unwrap();
+        server.shutdown().await.unwrap();
+    });
+
+    client.write_all(request).await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut reply = Vec::new();
+    client.read_to_end(&mut reply).await.unwrap();
+    assert_eq!(reply, request);
+
+    relay.await.unwrap().unwrap()
+}
+
+#[tokio::test]
+async fn every_cipher_carries_a_session() {
+    let request: Vec<u8> = (0..100 * 1024).map(|i| (i % 251) as u8).collect();
+    for cipher in [
+        CipherKind::ChaCha20,
+        CipherKind::XChaCha20,
+        CipherKind::Aes256Gcm,
+        CipherKind::ChaCha20Poly1305,
+    ] {
+        let traffic = echo_session(cipher, &request).await;
+        assert_eq!(traffic.a_to_b, request.len() as u64, "{:?}", cipher);
+        assert_eq!(traffic.b_to_a, request.len() as u64, "{:?}", cipher);
+    }
+}
+
+#[tokio::test]
+async fn sealed_records_go_out_through_small_pipes() {
+    let reply: Vec<u8> = (0..4000).map(|i| (i % 251) as u8).collect();
+    for cipher in [CipherKind::Aes256Gcm, CipherKind::ChaCha20Poly1305] {
+        // The tunnel's pipe takes less than a record at once
+        let (inbound_stream, outbound_stream) = duplex(1024);
+        let outbound = task::spawn(Tunnel::init_with_cipher(
+            outbound_stream,
+            PEER,
+            false,
+            secret("1234"),
+            cipher,
+        ));
+        let inbound = Tunnel::init_with_cipher(inbound_stream, PEER, true, secret("1234"), cipher)
+            .await
+            .unwrap();
+
+        let (mut client, relay_side) = duplex(PIPE_SIZE);
+        let (connector_side, mut server) = duplex(PIPE_SIZE);
+        task::spawn(inbound.run(relay_side, Default::default()));
+        task::spawn(async move {
+            let outbound = outbound.await.unwrap().unwrap();
+            outbound.run(connector_side, Default::default()).await
+        });
+
+        // The server replies and keeps the connection open, no write comes after the reply's
+        client.write_all(b"request").await.unwrap();
+        let mut request = [0u8; 7];
+        timeout(Duration::from_secs(5), server.read_exact(&mut request))
+            .await
+            .unwrap()
+            .unwrap();
+        server.write_all(&reply).await.unwrap();
+        let mut received = vec![0u8; reply.len()];
+        timeout(Duration::from_secs(5), client.read_exact(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received, reply, "{:?}", cipher);
+    }
+}
+
+#[tokio::test]
+async fn cipher_mismatch_is_rejected() {
+    let (inbound_stream, outbound_stream) = duplex(PIPE_SIZE);
+    let outbound = task::spawn(Tunnel::init_with_cipher(
+        outbound_stream,
+        PEER,
+        false,
+        secret("1234"),
+        CipherKind::Aes256Gcm,
+    ));
+    let inbound = Tunnel::init(inbound_stream, PEER, true, secret("1234")).await;
+
+    assert!(matches!(
+        inbound.err().unwrap().downcast_ref::<TunnelError>(),
+        Some(TunnelError::CipherMismatch(ip)) if *ip == PEER
+    ));
+    assert!(matches!(
+        outbound
+            .await
+            .unwrap()
+            .err()
+            .unwrap()
+            .downcast_ref::<TunnelError>(),
+        Some(TunnelError::RejectedCipherMismatch)
+    ));
+}
+
+#[tokio::test]
+async fn tampered_records_are_refused() {
+    let keys = |is_inbound| SessionKeys {
+        cipher: CipherKind::Aes256Gcm,
+        secret: secret("1234"),
+        nonce: [1; 12],
+        salt: [2; 12],
+        is_inbound,
+        attach: None,
+        limits: Default::default(),
+    };
+    let (writer_stream, mut wire) = duplex(PIPE_SIZE);
+    let (mut tampered, reader_stream) = duplex(PIPE_SIZE);
+    let mut writer = keys(false).seal(writer_stream).ok().unwrap();
+    let mut reader = keys(true).seal(reader_stream).ok().unwrap();
+
+    writer.write_all(b"hello").await.unwrap();
+    let mut record = vec![0u8; 2 + 5 + 16];
+    wire.read_exact(&mut record).await.unwrap();
+    record[4] ^= 1;
+    tampered.write_all(&record).await.unwrap();
+
+    let mut received = [0u8; 5];
+    assert!(reader.read_exact(&mut received).await.is_err());
+}
+
+fn keys(cipher: CipherKind, is_inbound: bool, limits: RekeyLimits) -> SessionKeys {
+    SessionKeys {
+        cipher,
+        secret: secret("1234"),
+        nonce: [1; 12],
+        salt: [2; 12],
+        is_inbound,
+        attach: None,
+        limits,
+    }
+}
+
+#[tokio::test]
+async fn sealed_sessions_cut_over_to_the_next_keys() {
+    for limits in [
+        RekeyLimits {
+            bytes: Some(5),
+            interval: None,
+        },
+        RekeyLimits {
+            bytes: None,
+            interval: Some(Duration::ZERO),
+        },
+    ] {
+        let (writer_stream, mut wire) = duplex(PIPE_SIZE);
+        let mut writer = keys(CipherKind::ChaCha20Poly1305, false, limits)
+            .seal(writer_stream)
+            .ok()
+            .unwrap();
+        // The reader follows the writer's key updates without limits of its own
+        let (mut relayed, reader_stream) = duplex(PIPE_SIZE);
+        let mut reader = keys(CipherKind::ChaCha20Poly1305, true, RekeyLimits::default())
+            .seal(reader_stream)
+            .ok()
+            .unwrap();
+
+        for chunk in [b"first chunk".as_slice(), b"second", b"third"] {
+            writer.write_all(chunk).await.unwrap();
+        }
+        writer.shutdown().await.unwrap();
+        let mut records = Vec::new();
+        wire.read_to_end(&mut records).await.unwrap();
+        // A key update record after each of the first two chunks
+        let (mut updates, mut at) = (0, 0);
+        while at < records.len() {
+            let len = u16::from_be_bytes([records[at], records[at + 1]]);
+            updates += (len & 0x8000 != 0) as usize;
+            at += 2 + (len & 0x7fff) as usize;
+        }
+        assert_eq!(updates, 2, "{:?}", limits);
+
+        relayed.write_all(&records).await.unwrap();
+        relayed.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"first chunksecondthird");
+    }
+}
+
+#[tokio::test]
+async fn framed_sessions_wait_for_key_updates_to_be_acknowledged() {
+    for cipher in [CipherKind::ChaCha20, CipherKind::XChaCha20] {
+        let (writer_stream, mut wire) = duplex(PIPE_SIZE);
+        let limits = RekeyLimits {
+            bytes: Some(5),
+            interval: None,
+        };
+        let mut writer = keys(cipher, false, limits)
+            .frame(writer_stream)
+            .ok()
+            .unwrap();
+        // Framed as well, with limits of its own it doesn't reach
+        let (mut relayed, reader_stream) = duplex(PIPE_SIZE);
+        let limits = RekeyLimits {
+            bytes: Some(1024),
+            interval: None,
+        };
+        let mut reader = keys(cipher, true, limits)
+            .frame(reader_stream)
+            .ok()
+            .unwrap();
+
+        // A key update before the second chunk, none before the third as it isn't acknowledged
+        for chunk in [b"first chunk".as_slice(), b"second", b"third"] {
+            writer.write_all(chunk).await.unwrap();
+        }
+        let mut frames = [0u8; 2 + 11 + 2 + 32 + 2 + 6 + 2 + 5];
+        wire.read_exact(&mut frames).await.unwrap();
+        relayed.write_all(&frames).await.unwrap();
+        let mut received = [0u8; 22];
+        reader.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"first chunksecondthird");
+
+        // The acknowledgement goes back ahead of the reply
+        reader.write_all(b"reply").await.unwrap();
+        let mut frames = [0u8; 2 + 32 + 2 + 5];
+        relayed.read_exact(&mut frames).await.unwrap();
+        wire.write_all(&frames).await.unwrap();
+        let mut reply = [0u8; 5];
+        writer.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"reply");
+
+        // Acknowledged, the next chunk gets a key update again
+        writer.write_all(b"fourth").await.unwrap();
+        writer.shutdown().await.unwrap();
+        let mut frames = Vec::new();
+        wire.read_to_end(&mut frames).await.unwrap();
+        assert_eq!(frames.len(), 2 + 32 + 2 + 6, "{:?}", cipher);
+        relayed.write_all(&frames).await.unwrap();
+        relayed.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"fourth");
+    }
+}
+
+#[tokio::test]
+async fn key_update_flags_are_sealed_with_the_records() {
+    let limits = RekeyLimits {
+        bytes: Some(1),
+        interval: None,
+    };
+    let (writer_stream, mut wire) = duplex(PIPE_SIZE);
+    let mut writer = keys(CipherKind::Aes256Gcm, false, limits)
+        .seal(writer_stream)
+        .ok()
+        .unwrap();
+    let (mut tampered, reader_stream) = duplex(PIPE_SIZE);
+    let mut reader = keys(CipherKind::Aes256Gcm, true, RekeyLimits::default())
+        .seal(reader_stream)
+        .ok()
+        .unwrap();
+
+    // The key update before the second record passed off as an empty record, the rest cut
+    writer.write_all(b"a").await.unwrap();
+    writer.write_all(b"b").await.unwrap();
+    let mut records = [0u8; 2 + 1 + 16 + 2 + 16];
+    wire.read_exact(&mut records).await.unwrap();
+    records[2 + 1 + 16] ^= 0x80;
+    tampered.write_all(&records).await.unwrap();
+    tampered.shutdown().await.unwrap();
+
+    let mut received = Vec::new();
+    assert!(reader.read_to_end(&mut received).await.is_err());
+}
+
+#[tokio::test]
+async fn frames_go_out_through_small_pipes() {
+    let limits = RekeyLimits {
+        bytes: Some(5),
+        interval: None,
+    };
+    // The pipe takes less than a frame at once, and nothing is written after the frame
+    let (writer_stream, mut wire) = duplex(8);
+    let mut writer = keys(CipherKind::ChaCha20, false, limits)
+        .frame(writer_stream)
+        .ok()
+        .unwrap();
+    let writer = task::spawn(async move {
+        writer.write_all(&[7u8; 100]).await.unwrap();
+        writer
+    });
+    let mut frame = [0u8; 2 + 100];
+    timeout(Duration::from_secs(5), wire.read_exact(&mut frame))
+        .await
+        .unwrap()
+        .unwrap();
+    writer.await.unwrap();
+
+    let (mut relayed, reader_stream) = duplex(PIPE_SIZE);
+    let mut reader = keys(CipherKind::ChaCha20, true, limits)
+        .frame(reader_stream)
+        .ok()
+        .unwrap();
+    relayed.write_all(&frame).await.unwrap();
+    let mut received = [0u8; 100];
+    reader.read_exact(&mut received).await.unwrap();
+    assert_eq!(received, [7u8; 100]);
+}
+
+#[tokio::test]
+async fn forged_key_updates_are_refused() {
+    let limits = RekeyLimits {
+        bytes: Some(1024),
+        interval: None,
+    };
+    let (writer_stream, mut wire) = duplex(PIPE_SIZE);
+    let mut writer = keys(CipherKind::ChaCha20, false, limits)
+        .frame(writer_stream)
+        .ok()
+        .unwrap();
+    let (mut tampered, reader_stream) = duplex(PIPE_SIZE);
+    let mut reader = keys(CipherKind::ChaCha20, true, limits)
+        .frame(reader_stream)
+        .ok()
+        .unwrap();
+
+    // The header of a data frame turned into a key update's, its payload taken for the tag
+    writer.write_all(&[7u8; 40]).await.unwrap();
+    let mut frame = [0u8; 2 + 40];
+    wire.read_exact(&mut frame).await.unwrap();
+    let flip = (40u16 ^ 0x8000).to_be_bytes();
+    frame[0] ^= flip[0];
+    frame[1] ^= flip[1];
+    tampered.write_all(&frame).await.unwrap();
+
+    let mut received = [0u8; 8];
+    let error = reader.read_exact(&mut received).await.unwrap_err();
+    assert_eq!(error.to_string(), "Key update failed authentication");
+}
@@ -0,0 +1,183 @@
+use chacha20::{
+    cipher::{KeyIvInit, StreamCipher},
+    ChaCha20,
+};
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::Duration,
+};
+use veloxid::{error::TunnelError, relay::copier::CipherCopier};
+
+const KEY: [u8; 32] = [7u8; 32];
+const NONCE: [u8; 12] = [3u8; 12];
+
+fn cipher() -> ChaCha20 {
+    ChaCha20::new(&KEY.into(), &NONCE.into())
+}
+
+fn plaintext(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 31 % 251) as u8).collect()
+}
+
+// Yields the data in chunks of cycling sizes to simulate short reads
+struct ChunkedReader {
+    data: Vec<u8>,
+    pos: usize,
+    chunks: Vec<usize>,
+    turn: usize,
+}
+
+impl ChunkedReader {
+    fn new(data: Vec<u8>, chunks: Vec<usize>) -> Self {
+        Self {
+            data,
+            pos: 0,
+            chunks,
+            turn: 0,
+        }
+    }
+}
+
+impl AsyncRead for ChunkedReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let chunk = self.chunks[self.turn % self.chunks.len()];
+        self.turn += 1;
+        let n = chunk.min(buf.remaining()).min(self.data.len() - self.pos);
+        buf.put_slice(&self.data[self.pos..self.pos + n]);
+        self.pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+// Accepts at most `limit` bytes per write and records whether it was shut down
+struct ShortWriter {
+    data: Vec<u8>,
+    limit: usize,
+    shut_down: bool,
+}
+
+impl ShortWriter {
+    fn new(limit: usize) -> Self {
+        Self {
+            data: Vec::new(),
+            limit,
+            shut_down: false,
+        }
+    }
+}
+
+impl AsyncWrite for ShortWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let n = buf.len().min(self.limit);
+        self.data.extend_from_slice(&buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.shut_down = true;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[tokio::test]
+async fn short_reads_keep_keystream_in_sync() {
+    let data = plaintext(10_000);
+    let mut expected = data.clone();
+    cipher().apply_keystream(&mut expected);
+
+    let reader = ChunkedReader::new(data.clone(), vec![1, 7, 512, 3, 4096, 13]);
+    let mut writer = ShortWriter::new(usize::MAX);
+    let copied = CipherCopier::new(vec![cipher()])
+        .copy(reader, &mut writer)
+        .await
+        .unwrap();
+
+    assert_eq!(copied, data.len() as u64);
+    assert_eq!(writer.data, expected);
+}
+
+#[tokio::test]
+async fn short_writes_and_small_buffer() {
+    let data = plaintext(4_321);
+    let mut expected = data.clone();
+    cipher().apply_keystream(&mut expected);
+
+    let reader = ChunkedReader::new(data.clone(), vec![100]);
+    let mut writer = ShortWriter::new(5);
+    CipherCopier::with_buffer_size(vec![cipher()], 17)
+        .copy(reader, &mut writer)
+        .await
+        .unwrap();
+
+    assert_eq!(writer.data, expected);
+}
+
+#[tokio::test]
+async fn encrypt_then_decrypt_roundtrip() {
+    let data = plaintext(3_000);
+
+    let mut encrypted = ShortWriter::new(usize::MAX);
+    CipherCopier::new(vec![cipher()])
+        .copy(
+            ChunkedReader::new(data.clone(), vec![9, 1, 250]),
+            &mut encrypted,
+        )
+        .await
+        .unwrap();
+    assert_ne!(encrypted.data, data);
+
+    let mut decrypted = ShortWriter::new(usize::MAX);
+    CipherCopier::new(vec![cipher()])
+        .copy(
+            ChunkedReader::new(encrypted.data, vec![2, 777]),
+            &mut decrypted,
+        )
+        .await
+        .unwrap();
+    assert_eq!(decrypted.data, data);
+}
+
+#[tokio::test]
+async fn eof_is_propagated_as_shutdown() {
+    let mut writer = ShortWriter::new(usize::MAX);
+    let copied = CipherCopier::new(vec![])
+        .copy(ChunkedReader::new(vec![], vec![1]), &mut writer)
+        .await
+        .unwrap();
+
+    assert_eq!(copied, 0);
+    assert!(writer.shut_down);
+}
+
+#[tokio::test]
+async fn stalled_writers_time_out() {
+    // Nobody reads the other end once the pipe is full
+    let (writer, _stalled) = tokio::io::duplex(64);
+    let reader = ChunkedReader::new(plaintext(4_096), vec![512]);
+    let error = CipherCopier::new(vec![cipher()])
+        .write_timeout(Duration::from_millis(50))
+        .copy(reader, writer)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        error.downcast_ref(),
+        Some(TunnelError::WriteStalled(_))
+    ));
+}
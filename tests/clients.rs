@@ -0,0 +1,35 @@
+use std::net::IpAddr;
+use veloxid::clients::ClientLimit;
+
+const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+const OTHER: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 2));
+
+#[test]
+fn clients_get_up_to_max_connections() {
+    let limit = ClientLimit::new(2);
+    let first = limit.acquire(CLIENT).unwrap();
+    let _second = limit.acquire(CLIENT).unwrap();
+    assert!(limit.acquire(CLIENT).is_none());
+    assert_eq!(limit.open(CLIENT), 2);
+    assert_eq!(limit.rejected(), 1);
+
+    // Others have slots of their own
+    let _other = limit.acquire(OTHER).unwrap();
+    assert_eq!(limit.clients(), 2);
+
+    drop(first);
+    assert!(limit.acquire(CLIENT).is_some());
+}
+
+#[test]
+fn clients_are_forgotten_with_their_last_connection() {
+    let limit = ClientLimit::new(1);
+    let slot = limit.acquire(CLIENT).unwrap();
+    let clone = limit.clone();
+    assert!(clone.acquire(CLIENT).is_none());
+
+    drop(slot);
+    assert_eq!(limit.open(CLIENT), 0);
+    assert_eq!(limit.clients(), 0);
+    assert_eq!(clone.rejected(), 1);
+}
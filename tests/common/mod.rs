@@ -0,0 +1,81 @@
+// Helpers for running tunnels over in-memory duplex pipes instead of real sockets
+#![allow(dead_code)]
+
+use anyhow::Result;
+use std::net::{IpAddr, Ipv4Addr};
+use tokio::{
+    io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream},
+    task::{self, JoinHandle},
+};
+use veloxid::{
+    protocol::encryption::generate_secret_from_string,
+    relay::tunnel::{SessionOptions, Traffic, Tunnel},
+};
+
+pub const PIPE_SIZE: usize = 64 * 1024;
+pub const PEER: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+pub fn secret(s: &str) -> [u8; 32] {
+    generate_secret_from_string(s.to_owned())
+}
+
+// A tunnel handshake over a duplex pipe: the inbound side is returned once it has
+// authenticated the peer, the outbound side keeps waiting for its starting byte until
+// the inbound tunnel is attached
+pub struct Handshake {
+    pub inbound: Result<Tunnel<DuplexStream>>,
+    pub outbound: JoinHandle<Result<Tunnel<DuplexStream>>>,
+}
+
+pub async fn handshake(inbound_secret: &str, outbound_secret: &str) -> Handshake {
+    let (inbound_stream, outbound_stream) = duplex(PIPE_SIZE);
+
+    let outbound_secret = secret(outbound_secret);
+    let outbound = task::spawn(Tunnel::init(outbound_stream, PEER, false, outbound_secret));
+    let inbound = Tunnel::init(inbound_stream, PEER, true, secret(inbound_secret)).await;
+
+    Handshake { inbound, outbound }
+}
+
+// Relay and connector over a duplex pipe: client <-> inbound tunnel <-> outbound tunnel <-> server
+// The returned streams are the client's and the server's ends
+pub struct Session {
+    pub client: DuplexStream,
+    pub server: DuplexStream,
+    pub relay: JoinHandle<Result<Traffic>>,
+    pub connector: JoinHandle<Result<Traffic>>,
+}
+
+pub async fn session(secret: &str) -> Session {
+    let Handshake { inbound, outbound } = handshake(secret, secret).await;
+    let inbound = inbound.expect("inbound handshake failed");
+
+    let (client, relay_side) = duplex(PIPE_SIZE);
+    let (server, connector_side) = duplex(PIPE_SIZE);
+
+    let relay = task::spawn(inbound.run(relay_side, SessionOptions::default()));
+    let connector = task::spawn(async move {
+        let outbound = outbound.await??;
+        outbound
+            .run(connector_side, SessionOptions::default())
+            .await
+    });
+
+    Session {
+        client,
+        server,
+        relay,
+        connector,
+    }
+}
+
+pub async fn read_to_end(stream: &mut DuplexStream) -> Vec<u8> {
+    let mut data = Vec::new();
+    stream.read_to_end(&mut data).await.unwrap();
+    data
+}
+
+pub async fn write_and_close(stream: &mut DuplexStream, data: &[u8]) {
+    stream.write_all(data).await.unwrap();
+    stream.shutdown().await.unwrap();
+}
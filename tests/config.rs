@@ -0,0 +1,1362 @@
+use veloxid::config::{PortMapping, VeloxidConfig};
+
+const ENDPOINTS: &str = r#"
+[endpoints.client]
+port = 8000
+type = "direct"
+direction = "inbound"
+
+[endpoints.server]
+port = 8888
+type = "direct"
+direction = "outbound"
+"#;
+
+#[test]
+fn example_config_loads() {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/veloxid.toml");
+    VeloxidConfig::load(path).unwrap();
+}
+
+#[test]
+fn unknown_keys_are_refused() {
+    let config = ENDPOINTS.replace(
+        "direction = \"outbound\"",
+        "direction = \"outbound\"\nsecrret = \"1234\"",
+    );
+    let error = VeloxidConfig::parse(&config).unwrap_err().to_string();
+    assert!(error.contains("secrret"), "{}", error);
+}
+
+#[test]
+fn routes_need_workers() {
+    let config = format!(
+        "{}\n[[routes]]\nendpoints = [\"client\", \"server\"]\nsize = 0\n",
+        ENDPOINTS
+    );
+    let error = VeloxidConfig::parse(&config).unwrap_err().to_string();
+    assert_eq!(error, "routes[0].size: must be greater than 0");
+}
+
+#[test]
+fn autoscaled_routes_take_a_range_of_workers() {
+    let route = |sizes: &str| {
+        format!(
+            "{}\n[[routes]]\nendpoints = [\"client\", \"server\"]\n{}\n",
+            ENDPOINTS, sizes
+        )
+    };
+    let error = |sizes: &str| VeloxidConfig::parse(&route(sizes)).unwrap_err().to_string();
+    assert_eq!(error("max_size = 4"), "routes[0].max_size: needs min_size");
+    assert_eq!(error("min_size = 1"), "routes[0].min_size: needs max_size");
+    assert_eq!(
+        error("size = 2\nmin_size = 1\nmax_size = 4"),
+        "routes[0].size: autoscaled routes take min_size and max_size instead"
+    );
+    assert_eq!(
+        error("min_size = 0\nmax_size = 4"),
+        "routes[0].min_size: must be greater than 0"
+    );
+    assert_eq!(
+        error("min_size = 4\nmax_size = 2"),
+        "routes[0].max_size: must be at least min_size"
+    );
+
+    let config = VeloxidConfig::parse(&route("min_size = 1\nmax_size = 4")).unwrap();
+    assert_eq!(config.routes[0].workers(), (1, 4));
+    let config = VeloxidConfig::parse(&route("size = 3")).unwrap();
+    assert_eq!(config.routes[0].workers(), (3, 3));
+}
+
+#[test]
+fn routes_name_missing_endpoints() {
+    let config = format!(
+        "{}\n[[routes]]\nendpoints = [\"client\", \"sever\"]\nsize = 1\n",
+        ENDPOINTS
+    );
+    let error = VeloxidConfig::parse(&config).unwrap_err().to_string();
+    assert_eq!(error, "routes[0].endpoints: no endpoint named 'sever'");
+}
+
+#[test]
+fn route_names_are_unique() {
+    let route = "\n[[routes]]\nname = \"proxy\"\nendpoints = [\"client\", \"server\"]\nsize = 1\n";
+    let config = format!("{}{}{}", ENDPOINTS, route, route);
+    let error = VeloxidConfig::parse(&config).unwrap_err().to_string();
+    assert_eq!(error, "routes[1].name: is used by another route");
+}
+
+#[test]
+fn endpoints_inherit_from_defaults_and_each_other() {
+    let config = VeloxidConfig::parse(
+        r#"
+        [defaults]
+        host = "10.0.0.1"
+        direction = "outbound"
+        secret = "1234"
+        buffer_size = 65536
+
+        [endpoints.ssh-tunnel]
+        port = 9022
+        type = "tunnel"
+        cipher = "xchacha20"
+
+        [endpoints.web-tunnel]
+        extends = "ssh-tunnel"
+        port = 9080
+
+        [endpoints.ssh]
+        host = "127.0.0.1"
+        port = 22
+        type = "direct"
+        "#,
+    )
+    .unwrap();
+
+    let web = &config.endpoints["web-tunnel"];
+    assert_eq!(web.port, 9080);
+    assert_eq!(web.host.as_deref(), Some("10.0.0.1"));
+    assert_eq!(web.secret.as_deref(), Some("1234"));
+    assert!(web.cipher.is_some());
+    assert_eq!(web.buffer_size, Some(65536));
+
+    // Tunnel settings only go to tunnels
+    let ssh = &config.endpoints["ssh"];
+    assert_eq!(ssh.host.as_deref(), Some("127.0.0.1"));
+    assert!(ssh.secret.is_none());
+}
+
+#[test]
+fn inheritance_loops_are_refused() {
+    let error = VeloxidConfig::parse(
+        r#"
+        [endpoints.a]
+        extends = "b"
+
+        [endpoints.b]
+        extends = "a"
+        "#,
+    )
+    .unwrap_err()
+    .to_string();
+    assert!(error.contains("loops back"), "{}", error);
+}
+
+// A config directory of its own under the system's temporary directory
+fn config_dir(name: &str, files: &[(&str, &str)]) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("veloxid-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("conf.d")).unwrap();
+    for (path, content) in files {
+        std::fs::write(dir.join(path), content).unwrap();
+    }
+    dir
+}
+
+#[test]
+fn included_files_add_endpoints_and_routes() {
+    let dir = config_dir(
+        "include",
+        &[
+            (
+                "veloxid.toml",
+                "include = [\"conf.d/*.toml\"]\n[defaults]\nsecret = \"1234\"\n",
+            ),
+            ("conf.d/a.toml", ENDPOINTS),
+            (
+                "conf.d/b.toml",
+                "[[routes]]\nendpoints = [\"client\", \"server\"]\nsize = 1\n",
+            ),
+        ],
+    );
+
+    let config = VeloxidConfig::load(dir.join("veloxid.toml").to_str().unwrap()).unwrap();
+    assert_eq!(config.endpoints.len(), 2);
+    assert_eq!(config.routes.len(), 1);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn included_endpoints_are_unique() {
+    let dir = config_dir(
+        "include-twice",
+        &[
+            (
+                "veloxid.toml",
+                &format!("include = [\"conf.d/*.toml\"]\n{}", ENDPOINTS),
+            ),
+            ("conf.d/a.toml", ENDPOINTS),
+        ],
+    );
+
+    let error = VeloxidConfig::load(dir.join("veloxid.toml").to_str().unwrap())
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("is defined twice"), "{}", error);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn ipv6_only_is_for_inbound_endpoints() {
+    let error = VeloxidConfig::parse(
+        r#"
+        [endpoints.out]
+        host = "::1"
+        port = 80
+        type = "direct"
+        direction = "outbound"
+        ipv6_only = true
+        "#,
+    )
+    .unwrap_err()
+    .to_string();
+    assert_eq!(error, "endpoints.out.ipv6_only: inbound TCP endpoints only");
+}
+
+#[test]
+fn bind_retry_is_for_inbound_endpoints() {
+    let error = VeloxidConfig::parse(
+        r#"
+        [endpoints.out]
+        host = "127.0.0.1"
+        port = 80
+        type = "direct"
+        direction = "outbound"
+        bind_retry = 10
+        "#,
+    )
+    .unwrap_err()
+    .to_string();
+    assert_eq!(
+        error,
+        "endpoints.out.bind_retry: inbound TCP endpoints only"
+    );
+}
+
+#[test]
+fn heartbeats_are_for_inbound_tunnels() {
+    let error = VeloxidConfig::parse(
+        r#"
+        [endpoints.out]
+        host = "127.0.0.1"
+        port = 80
+        type = "tunnel"
+        direction = "outbound"
+        secret = "1234"
+        heartbeat = 10
+        "#,
+    )
+    .unwrap_err()
+    .to_string();
+    assert_eq!(
+        error,
+        "endpoints.out.heartbeat: inbound tunnel endpoints only"
+    );
+}
+
+#[test]
+fn balanced_routes_start_with_an_inbound_tunnel() {
+    let route = |first: &str| {
+        format!(
+            r#"{}
+            [endpoints.connectors]
+            port = 9000
+            type = "tunnel"
+            direction = "inbound"
+            secret = "1234"
+
+            [[routes]]
+            endpoints = ["{}", "client"]
+            size = 4
+            balance = true
+            weights = {{ "192.0.2.1" = 3 }}
+            "#,
+            ENDPOINTS, first
+        )
+    };
+    let config = VeloxidConfig::parse(&route("connectors")).unwrap();
+    let weights = config.routes[0].weights.as_ref().unwrap();
+    assert_eq!(weights[&"192.0.2.1".parse().unwrap()], 3);
+
+    let error = VeloxidConfig::parse(&route("server"))
+        .unwrap_err()
+        .to_string();
+    assert_eq!(
+        error,
+        "routes[0].balance: the first endpoint must be an inbound tunnel"
+    );
+}
+
+#[test]
+fn ipv6_hosts_need_no_brackets() {
+    let config = VeloxidConfig::parse(
+        r#"
+        [endpoints.any]
+        host = "::"
+        port = 8000
+        type = "direct"
+        direction = "inbound"
+        "#,
+    )
+    .unwrap();
+    let addr = veloxid::relay::connection::endpoint_addr(&config.endpoints["any"]).unwrap();
+    assert_eq!(addr, "[::]:8000".parse().unwrap());
+}
+
+#[test]
+fn handshake_timeouts_are_for_tunnels() {
+    let config = ENDPOINTS.replace("port = 8000", "port = 8000\nhandshake_timeout = 3");
+    let error = VeloxidConfig::parse(&config).unwrap_err().to_string();
+    assert_eq!(
+        error,
+        "endpoints.client.handshake_timeout: tunnel endpoints only"
+    );
+}
+
+#[test]
+fn blind_routes_join_tunnels() {
+    let config = format!(
+        "{}\n[[routes]]\nendpoints = [\"client\", \"server\"]\nsize = 1\nblind = true\n",
+        ENDPOINTS
+    );
+    let error = VeloxidConfig::parse(&config).unwrap_err().to_string();
+    assert_eq!(error, "routes[0].blind: both endpoints must be tunnels");
+}
+
+#[test]
+fn rekey_is_for_tunnels() {
+    let error = VeloxidConfig::parse(
+        r#"
+        [endpoints.plain]
+        port = 8000
+        type = "direct"
+        direction = "inbound"
+        rekey = true
+        "#,
+    )
+    .unwrap_err()
+    .to_string();
+    assert_eq!(error, "endpoints.plain.rekey: tunnel endpoints only");
+}
+
+#[test]
+fn tickets_are_for_tunnels() {
+    let error = VeloxidConfig::parse(
+        r#"
+        [endpoints.plain]
+        port = 8000
+        type = "direct"
+        direction = "inbound"
+        tickets = 600
+        "#,
+    )
+    .unwrap_err()
+    .to_string();
+    assert_eq!(error, "endpoints.plain.tickets: tunnel endpoints only");
+}
+
+#[test]
+fn silent_handshakes_are_for_tunnels() {
+    let error = VeloxidConfig::parse(
+        r#"
+        [endpoints.plain]
+        port = 8000
+        type = "direct"
+        direction = "inbound"
+        silent = true
+        "#,
+    )
+    .unwrap_err()
+    .to_string();
+    assert_eq!(error, "endpoints.plain.silent: tunnel endpoints only");
+
+    // Silent outbound sides speak first
+    VeloxidConfig::parse(
+        r#"
+        [endpoints.out]
+        host = "127.0.0.1"
+        port = 8000
+        type = "tunnel"
+        direction = "outbound"
+        secret = "1234"
+        silent = true
+        fast_open = true
+        "#,
+    )
+    .unwrap();
+}
+
+#[test]
+fn totp_seeds_are_for_tunnels_without_tickets() {
+    let error = |endpoint: &str| {
+        VeloxidConfig::parse(&format!("[endpoints.coded]\n{}", endpoint))
+            .unwrap_err()
+            .to_string()
+    };
+    let direct = r#"
+        port = 8000
+        type = "direct"
+        direction = "inbound"
+        totp_seed = "abcd"
+        "#;
+    assert_eq!(
+        error(direct),
+        "endpoints.coded.totp_seed: tunnel endpoints only"
+    );
+
+    let resumed = r#"
+        port = 8000
+        type = "tunnel"
+        direction = "inbound"
+        secret = "1234"
+        totp_seed = "abcd"
+        tickets = 3600
+        "#;
+    assert_eq!(
+        error(resumed),
+        "endpoints.coded.totp_seed: can't be used with tickets"
+    );
+
+    let unseeded = r#"
+        port = 8000
+        type = "tunnel"
+        direction = "inbound"
+        secret = "1234"
+        totp_skew = 2
+        "#;
+    assert_eq!(
+        error(unseeded),
+        "endpoints.coded.totp_skew: needs totp_seed"
+    );
+
+    VeloxidConfig::parse(
+        r#"
+        [endpoints.coded]
+        port = 8000
+        type = "tunnel"
+        direction = "inbound"
+        secret = "1234"
+        totp_seed = "abcd"
+        totp_skew = 2
+        "#,
+    )
+    .unwrap();
+}
+
+#[test]
+fn key_commands_replace_the_secret() {
+    let error = |endpoint: &str| {
+        VeloxidConfig::parse(&format!("[endpoints.keyed]\n{}", endpoint))
+            .unwrap_err()
+            .to_string()
+    };
+    let direct = r#"
+        port = 8000
+        type = "direct"
+        direction = "inbound"
+        key_command = ["tpm2_hmac", "-c", "key.ctx"]
+        "#;
+    assert_eq!(
+        error(direct),
+        "endpoints.keyed.key_command: tunnel endpoints only"
+    );
+
+    let empty = r#"
+        port = 8000
+        type = "tunnel"
+        direction = "inbound"
+        key_command = []
+        "#;
+    assert_eq!(
+        error(empty),
+        "endpoints.keyed.key_command: must start with a program"
+    );
+
+    let both = r#"
+        port = 8000
+        type = "tunnel"
+        direction = "inbound"
+        secret = "1234"
+        key_command = ["tpm2_hmac", "-c", "key.ctx"]
+        "#;
+    assert_eq!(
+        error(both),
+        "endpoints.keyed.key_command: can't be used with secret"
+    );
+
+    let silent = r#"
+        port = 8000
+        type = "tunnel"
+        direction = "inbound"
+        key_command = ["tpm2_hmac", "-c", "key.ctx"]
+        silent = true
+        "#;
+    assert_eq!(
+        error(silent),
+        "endpoints.keyed.key_command: can't be used with silent"
+    );
+
+    VeloxidConfig::parse(
+        r#"
+        [endpoints.keyed]
+        port = 8000
+        type = "tunnel"
+        direction = "inbound"
+        key_command = ["tpm2_hmac", "-c", "key.ctx"]
+        "#,
+    )
+    .unwrap();
+}
+
+#[test]
+fn knocks_are_for_tcp_tunnels() {
+    let error = |endpoint: &str| {
+        VeloxidConfig::parse(&format!("[endpoints.knocked]\n{}", endpoint))
+            .unwrap_err()
+            .to_string()
+    };
+    let direct = r#"
+        port = 8000
+        type = "direct"
+        direction = "inbound"
+        knock_port = 62201
+        "#;
+    assert_eq!(
+        error(direct),
+        "endpoints.knocked.knock_port: TCP tunnel endpoints only"
+    );
+
+    let outbound = r#"
+        host = "127.0.0.1"
+        port = 8000
+        type = "tunnel"
+        direction = "outbound"
+        secret = "1234"
+        knock_port = 62201
+        knock_window = 60
+        "#;
+    assert_eq!(
+        error(outbound),
+        "endpoints.knocked.knock_window: inbound endpoints with a knock_port only"
+    );
+
+    let inbound = r#"
+        port = 8000
+        type = "tunnel"
+        direction = "inbound"
+        secret = "1234"
+        knock_port = 0
+        "#;
+    assert_eq!(
+        error(inbound),
+        "endpoints.knocked.knock_port: must be greater than 0"
+    );
+
+    let sourced = r#"
+        port = 8000
+        type = "tunnel"
+        direction = "inbound"
+        secret = "1234"
+        knock_port = 62201
+        knock_source = "198.51.100.7"
+        "#;
+    assert_eq!(
+        error(sourced),
+        "endpoints.knocked.knock_source: outbound endpoints with a knock_port only"
+    );
+}
+
+#[test]
+fn pools_are_for_direct_outbound_endpoints() {
+    let error = VeloxidConfig::parse(
+        r#"
+        [endpoints.client]
+        port = 8000
+        type = "direct"
+        direction = "inbound"
+        pool = 4
+        "#,
+    )
+    .unwrap_err()
+    .to_string();
+    assert_eq!(
+        error,
+        "endpoints.client.pool: direct outbound endpoints only"
+    );
+}
+
+#[test]
+fn fast_open_needs_a_tunnel_speaking_first() {
+    let error = VeloxidConfig::parse(
+        r#"
+        [endpoints.out]
+        host = "127.0.0.1"
+        port = 8000
+        type = "tunnel"
+        direction = "outbound"
+        secret = "1234"
+        fast_open = true
+        "#,
+    )
+    .unwrap_err()
+    .to_string();
+    assert_eq!(
+        error,
+        "endpoints.out.fast_open: outbound tunnels with obfuscation, tickets or silent only"
+    );
+}
+
+#[test]
+fn registry_routes_name_their_service() {
+    let config = |service: &str| {
+        format!(
+            r#"{}
+            [endpoints.registry]
+            port = 9000
+            type = "tunnel"
+            direction = "inbound"
+            secret = "1234"
+            registry = true
+
+            [[routes]]
+            endpoints = ["registry", "client"]
+            size = 4
+            {}
+            "#,
+            ENDPOINTS, service
+        )
+    };
+    let parsed = VeloxidConfig::parse(&config("service = \"ssh\"")).unwrap();
+    assert_eq!(parsed.routes[0].service.as_deref(), Some("ssh"));
+
+    let error = VeloxidConfig::parse(&config("")).unwrap_err().to_string();
+    assert_eq!(
+        error,
+        "routes[0].service: routes on a registry endpoint need one"
+    );
+
+    let error = VeloxidConfig::parse(&config("service = \"ssh\"").replace("registry = true", ""))
+        .unwrap_err()
+        .to_string();
+    assert_eq!(
+        error,
+        "routes[0].service: needs a registry endpoint or an outbound tunnel"
+    );
+}
+
+#[test]
+fn services_are_announced_by_outbound_tunnels() {
+    let config = ENDPOINTS.replace("port = 8888", "port = 8888\nservice = \"ssh\"");
+    let error = VeloxidConfig::parse(&config).unwrap_err().to_string();
+    assert_eq!(
+        error,
+        "endpoints.server.service: outbound tunnel endpoints only"
+    );
+}
+
+#[test]
+fn routes_sharing_an_outbound_tunnel_announce_their_own_service() {
+    let config = |service: &str| {
+        format!(
+            r#"{}
+            [endpoints.tunnel-out]
+            port = 9000
+            type = "tunnel"
+            direction = "outbound"
+            secret = "1234"
+
+            [[routes]]
+            endpoints = ["tunnel-out", "server"]
+            size = 2
+            service = "web"
+
+            [[routes]]
+            endpoints = ["tunnel-out", "client"]
+            size = 2
+            service = "{}"
+            "#,
+            ENDPOINTS.replace("inbound", "outbound"),
+            service
+        )
+    };
+    let parsed = VeloxidConfig::parse(&config("ssh")).unwrap();
+    assert_eq!(parsed.routes[0].service.as_deref(), Some("web"));
+    assert_eq!(parsed.routes[1].service.as_deref(), Some("ssh"));
+
+    let error = VeloxidConfig::parse(&config(&"s".repeat(256)))
+        .unwrap_err()
+        .to_string();
+    assert_eq!(error, "routes[1].service: must be 1 to 255 bytes");
+}
+
+#[test]
+fn chained_tunnels_go_through_outbound_tunnels() {
+    let config = |via: &str| {
+        format!(
+            r#"{}
+            [endpoints.hop1]
+            port = 9000
+            type = "tunnel"
+            direction = "outbound"
+            secret = "1234"
+
+            [endpoints.hop2]
+            type = "tunnel"
+            direction = "outbound"
+            secret = "5678"
+            via = "{}"
+            "#,
+            ENDPOINTS, via
+        )
+    };
+    let parsed = VeloxidConfig::parse(&config("hop1")).unwrap();
+    assert_eq!(parsed.endpoints["hop2"].via.as_deref(), Some("hop1"));
+
+    let error = VeloxidConfig::parse(&config("server"))
+        .unwrap_err()
+        .to_string();
+    assert_eq!(
+        error,
+        "endpoints.hop2.via: 'server' isn't an outbound tunnel"
+    );
+    let error = VeloxidConfig::parse(&config("hop2"))
+        .unwrap_err()
+        .to_string();
+    assert_eq!(error, "endpoints.hop2.via: loops back through 'hop2'");
+}
+
+#[test]
+fn hops_are_sealed_with_chacha20_poly1305() {
+    let config = |keys: &str| {
+        format!(
+            r#"{}
+            [endpoints.onion]
+            port = 9000
+            type = "tunnel"
+            direction = "outbound"
+            secret = "1234"
+            {}
+            "#,
+            ENDPOINTS, keys
+        )
+    };
+    let parsed = VeloxidConfig::parse(&config("hops = [\"5678\", \"9012\"]")).unwrap();
+    assert_eq!(parsed.endpoints["onion"].hops.as_ref().unwrap().len(), 2);
+
+    let error = |keys: &str| VeloxidConfig::parse(&config(keys)).unwrap_err().to_string();
+    assert_eq!(
+        error("hops = []"),
+        "endpoints.onion.hops: must be a list of secrets"
+    );
+    assert_eq!(
+        error("hops = [\"5678\"]\ncipher = \"aes-256-gcm\""),
+        "endpoints.onion.cipher: hops are sealed with chacha20-poly1305"
+    );
+}
+
+#[test]
+fn punched_tunnels_run_with_the_e2e_secret() {
+    let config = |direction: &str, keys: &str| {
+        format!(
+            r#"{}
+            [endpoints.punched]
+            port = 9000
+            type = "tunnel"
+            direction = "{}"
+            secret = "1234"
+            {}
+            "#,
+            ENDPOINTS, direction, keys
+        )
+    };
+    let parsed = VeloxidConfig::parse(&config(
+        "outbound",
+        "e2e_secret = \"5678\"\npunch_port = 9122",
+    ))
+    .unwrap();
+    assert_eq!(parsed.endpoints["punched"].punch_port, Some(9122));
+    let parsed = VeloxidConfig::parse(&config(
+        "inbound",
+        "e2e_secret = \"5678\"\nrendezvous = \"ssh\"",
+    ))
+    .unwrap();
+    assert_eq!(
+        parsed.endpoints["punched"].rendezvous.as_deref(),
+        Some("ssh")
+    );
+
+    let error = |direction: &str, keys: &str| {
+        VeloxidConfig::parse(&config(direction, keys))
+            .unwrap_err()
+            .to_string()
+    };
+    assert_eq!(
+        error("outbound", "punch_port = 9122"),
+        "endpoints.punched.punch_port: needs e2e_secret"
+    );
+    assert_eq!(
+        error("inbound", "e2e_secret = \"5678\"\npunch_port = 9122"),
+        "endpoints.punched.punch_port: outbound TCP tunnel endpoints only"
+    );
+    assert_eq!(
+        error("outbound", "e2e_secret = \"5678\"\nrendezvous = \"ssh\""),
+        "endpoints.punched.rendezvous: inbound TCP tunnel endpoints only"
+    );
+}
+
+#[test]
+fn advertised_listeners_face_the_clients() {
+    let config = ENDPOINTS.replace("port = 8000", "port = 8000\nadvertise = \"Home SSH\"");
+    let parsed = VeloxidConfig::parse(&config).unwrap();
+    assert_eq!(
+        parsed.endpoints["client"].advertise.as_deref(),
+        Some("Home SSH")
+    );
+
+    let config = ENDPOINTS.replace("port = 8888", "port = 8888\nadvertise = \"Home SSH\"");
+    let error = VeloxidConfig::parse(&config).unwrap_err().to_string();
+    assert_eq!(
+        error,
+        "endpoints.server.advertise: inbound direct or auto endpoints only"
+    );
+}
+
+#[test]
+fn advertised_types_are_dns_sd_ones() {
+    let config = |ty: &str| {
+        ENDPOINTS.replace(
+            "port = 8000",
+            &format!(
+                "port = 8000\nadvertise = \"ssh\"\nadvertise_type = \"{}\"",
+                ty
+            ),
+        )
+    };
+    VeloxidConfig::parse(&config("_ssh._tcp")).unwrap();
+    for ty in ["ssh", "_ssh._udp", "_._tcp", "_s h._tcp"] {
+        let error = VeloxidConfig::parse(&config(ty)).unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "endpoints.client.advertise_type: must look like \"_name._tcp\""
+        );
+    }
+}
+
+#[test]
+fn port_mappings_are_asked_for_inbound_endpoints() {
+    let config = ENDPOINTS.replace(
+        "port = 8000",
+        "port = 8000\nport_mapping = \"nat-pmp\"\nexternal_port = 18000",
+    );
+    let parsed = VeloxidConfig::parse(&config).unwrap();
+    assert_eq!(
+        parsed.endpoints["client"].port_mapping,
+        Some(PortMapping::NatPmp)
+    );
+    assert_eq!(parsed.endpoints["client"].external_port, Some(18000));
+
+    let error = |from: &str, to: &str| {
+        VeloxidConfig::parse(&ENDPOINTS.replace(from, to))
+            .unwrap_err()
+            .to_string()
+    };
+    assert_eq!(
+        error("port = 8888", "port = 8888\nport_mapping = \"upnp\""),
+        "endpoints.server.port_mapping: inbound endpoints only"
+    );
+    assert_eq!(
+        error("port = 8000", "port = 8000\nmapping_lease = 600"),
+        "endpoints.client.mapping_lease: needs port_mapping"
+    );
+    assert_eq!(
+        error(
+            "port = 8000",
+            "port = 8000\nport_mapping = \"auto\"\nmapping_lease = 10"
+        ),
+        "endpoints.client.mapping_lease: must be at least 60"
+    );
+}
+
+#[test]
+fn sessions_are_rekeyed_by_time_with_aead_ciphers_only() {
+    let config = |keys: &str| {
+        format!(
+            r#"
+[endpoints.tunnel-in]
+port = 8080
+type = "tunnel"
+direction = "inbound"
+secret = "1234"
+{}
+"#,
+            keys
+        )
+    };
+    let parsed = VeloxidConfig::parse(&config(
+        "cipher = \"aes-256-gcm\"\nrekey_bytes = 1073741824\nrekey_interval = 3600",
+    ))
+    .unwrap();
+    assert_eq!(parsed.endpoints["tunnel-in"].rekey_interval, Some(3600));
+    VeloxidConfig::parse(&config("rekey_bytes = 1073741824")).unwrap();
+
+    let error = VeloxidConfig::parse(&config("rekey_interval = 3600"))
+        .unwrap_err()
+        .to_string();
+    assert_eq!(
+        error,
+        "endpoints.tunnel-in.rekey_interval: needs cipher aes-256-gcm or chacha20-poly1305"
+    );
+    let error =
+        VeloxidConfig::parse(&ENDPOINTS.replace("port = 8000", "port = 8000\nrekey_bytes = 1"))
+            .unwrap_err()
+            .to_string();
+    assert_eq!(error, "endpoints.client.rekey_bytes: tunnel endpoints only");
+}
+
+#[test]
+fn users_to_run_as_are_named() {
+    let error = VeloxidConfig::parse(&format!("user = \"\"\n{}", ENDPOINTS))
+        .unwrap_err()
+        .to_string();
+    assert_eq!(error, "user: must not be empty");
+}
+
+#[test]
+fn sandbox_paths_need_landlock() {
+    let sandbox = "[sandbox]\nseccomp = \"deny\"\nread = [\"/srv\"]\n";
+    let error = VeloxidConfig::parse(&format!("{}{}", ENDPOINTS, sandbox))
+        .unwrap_err()
+        .to_string();
+    assert_eq!(error, "sandbox.read: needs landlock");
+
+    let sandbox = "[sandbox]\nlandlock = true\nwrite = [\"/var/log\", \"logs\"]\n";
+    let error = VeloxidConfig::parse(&format!("{}{}", ENDPOINTS, sandbox))
+        .unwrap_err()
+        .to_string();
+    assert_eq!(error, "sandbox.write[1]: must be an absolute path");
+}
+
+#[test]
+fn connections_per_ip_are_limited_on_client_listeners_only() {
+    let config = ENDPOINTS.replace("port = 8888\n", "port = 8888\nmax_conns_per_ip = 4\n");
+    let error = VeloxidConfig::parse(&config).unwrap_err().to_string();
+    assert_eq!(
+        error,
+        "endpoints.server.max_conns_per_ip: inbound direct or auto endpoints only"
+    );
+
+    let config = ENDPOINTS.replace("port = 8000\n", "port = 8000\nmax_conns_per_ip = 0\n");
+    let error = VeloxidConfig::parse(&config).unwrap_err().to_string();
+    assert_eq!(
+        error,
+        "endpoints.client.max_conns_per_ip: must be greater than 0"
+    );
+}
+
+#[test]
+fn ban_commands_cant_run_sandboxed() {
+    let security = "[security.ban]\ncommand = [\"nft\", \"{ip}\"]\n";
+    VeloxidConfig::parse(&format!("{}{}", ENDPOINTS, security)).unwrap();
+
+    let sandbox = "[sandbox]\nseccomp = \"deny\"\n";
+    let error = VeloxidConfig::parse(&format!("{}{}{}", ENDPOINTS, security, sandbox))
+        .unwrap_err()
+        .to_string();
+    assert_eq!(
+        error,
+        "security.ban.command: can't be run with landlock or seccomp"
+    );
+
+    let security = "[security.ban]\ncommand = []\n";
+    let error = VeloxidConfig::parse(&format!("{}{}", ENDPOINTS, security))
+        .unwrap_err()
+        .to_string();
+    assert_eq!(error, "security.ban.command: must start with a program");
+
+    let security = "[security.ban]\ncommand = [\"nft\"]\nunban_command = [\"\"]\n";
+    let error = VeloxidConfig::parse(&format!("{}{}", ENDPOINTS, security))
+        .unwrap_err()
+        .to_string();
+    assert_eq!(
+        error,
+        "security.ban.unban_command: must start with a program"
+    );
+}
+
+#[test]
+fn tarpits_hold_some_sockets() {
+    let security = "[security.tarpit]\nmax = 16\n";
+    VeloxidConfig::parse(&format!("{}{}", ENDPOINTS, security)).unwrap();
+
+    let security = "[security.tarpit]\nmax = 0\n";
+    let error = VeloxidConfig::parse(&format!("{}{}", ENDPOINTS, security))
+        .unwrap_err()
+        .to_string();
+    assert_eq!(error, "security.tarpit.max: must be greater than 0");
+}
+
+#[test]
+fn honeypots_stay_out_of_routes() {
+    let honeypot = "[endpoints.trap]\nport = 2323\ntype = \"honeypot\"\ndirection = \"inbound\"\ncapture = 64\n";
+    VeloxidConfig::parse(&format!("{}{}", ENDPOINTS, honeypot)).unwrap();
+
+    let route = "[[routes]]\nendpoints = [\"trap\", \"server\"]\nsize = 1\n";
+    let error = VeloxidConfig::parse(&format!("{}{}{}", ENDPOINTS, honeypot, route))
+        .unwrap_err()
+        .to_string();
+    assert_eq!(
+        error,
+        "routes[0].endpoints: honeypot endpoints take no routes"
+    );
+
+    let config = ENDPOINTS.replace("port = 8000\n", "port = 8000\ncapture = 64\n");
+    let error = VeloxidConfig::parse(&config).unwrap_err().to_string();
+    assert_eq!(error, "endpoints.client.capture: honeypot endpoints only");
+}
+
+#[test]
+fn stdio_is_one_end_of_a_single_route() {
+    let stdio = "[endpoints.pipe]\ntype = \"stdio\"\ndirection = \"inbound\"\n";
+    let route =
+        |a: &str, b: &str| format!("[[routes]]\nendpoints = [\"{}\", \"{}\"]\nsize = 1\n", a, b);
+    let config = format!("{}{}{}", ENDPOINTS, stdio, route("pipe", "server"));
+    VeloxidConfig::parse(&config).unwrap();
+
+    let error = VeloxidConfig::parse(&format!("{}{}", config, route("client", "pipe")))
+        .unwrap_err()
+        .to_string();
+    assert_eq!(
+        error,
+        "routes[1].endpoints: stdin and stdout are one end of a single route"
+    );
+
+    let addressed = stdio.replace("type", "port = 2222\ntype");
+    let error = VeloxidConfig::parse(&format!("{}{}", ENDPOINTS, addressed))
+        .unwrap_err()
+        .to_string();
+    assert_eq!(
+        error,
+        "endpoints.pipe.port: stdio endpoints don't use the network"
+    );
+}
+
+#[test]
+fn exec_endpoints_run_a_command_for_outbound_sessions() {
+    let exec = "[endpoints.handler]\ntype = \"exec\"\ndirection = \"outbound\"\n";
+    let config = format!(
+        "{}{}command = [\"cat\"]\nmax_processes = 4\n",
+        ENDPOINTS, exec
+    );
+    let handler = &VeloxidConfig::parse(&config).unwrap().endpoints["handler"];
+    assert_eq!(handler.command.as_deref(), Some(&["cat".to_owned()][..]));
+
+    let error = VeloxidConfig::parse(&format!("{}{}", ENDPOINTS, exec))
+        .unwrap_err()
+        .to_string();
+    assert_eq!(error, "endpoints.handler.command: must be given");
+
+    let config = format!("{}{}command = []\n", ENDPOINTS, exec);
+    let error = VeloxidConfig::parse(&config).unwrap_err().to_string();
+    assert_eq!(
+        error,
+        "endpoints.handler.command: must start with a program"
+    );
+
+    let inbound = exec.replace("outbound", "inbound");
+    let config = format!("{}{}command = [\"cat\"]\n", ENDPOINTS, inbound);
+    let error = VeloxidConfig::parse(&config).unwrap_err().to_string();
+    assert_eq!(
+        error,
+        "endpoints.handler.direction: exec endpoints are outbound only"
+    );
+
+    let config = ENDPOINTS.replace("port = 8888\n", "port = 8888\ncommand = [\"cat\"]\n");
+    let error = VeloxidConfig::parse(&config).unwrap_err().to_string();
+    assert_eq!(error, "endpoints.server.command: exec endpoints only");
+}
+
+#[test]
+fn udp_endpoints_have_a_flow_timeout_and_no_tcp_options() {
+    let udp = "[endpoints.dns]\nport = 5353\ntype = \"udp\"\ndirection = \"inbound\"\n";
+    let config = format!("{}{}flow_timeout = 30\n", ENDPOINTS, udp);
+    let dns = &VeloxidConfig::parse(&config).unwrap().endpoints["dns"];
+    assert_eq!(dns.flow_timeout, Some(30));
+
+    let config = format!("{}{}flow_timeout = 0\n", ENDPOINTS, udp);
+    let error = VeloxidConfig::parse(&config).unwrap_err().to_string();
+    assert_eq!(error, "endpoints.dns.flow_timeout: must be greater than 0");
+
+    let config = format!("{}{}fast_open = true\n", ENDPOINTS, udp);
+    let error = VeloxidConfig::parse(&config).unwrap_err().to_string();
+    assert_eq!(error, "endpoints.dns.fast_open: TCP endpoints only");
+
+    let config = ENDPOINTS.replace("port = 8888\n", "port = 8888\nflow_timeout = 30\n");
+    let error = VeloxidConfig::parse(&config).unwrap_err().to_string();
+    assert_eq!(error, "endpoints.server.flow_timeout: UDP endpoints only");
+}
+
+#[test]
+fn write_timeouts_and_budgets_are_positive() {
+    let config = ENDPOINTS.replace("port = 8888\n", "port = 8888\nwrite_timeout = 0\n");
+    let error = VeloxidConfig::parse(&config).unwrap_err().to_string();
+    assert_eq!(
+        error,
+        "endpoints.server.write_timeout: must be greater than 0"
+    );
+
+    let config = ENDPOINTS.replace("port = 8000\n", "port = 8000\nmax_in_flight = 0\n");
+    let error = VeloxidConfig::parse(&config).unwrap_err().to_string();
+    assert_eq!(
+        error,
+        "endpoints.client.max_in_flight: must be greater than 0"
+    );
+
+    let config = ENDPOINTS.replace(
+        "port = 8000\n",
+        "port = 8000\nwrite_timeout = 30\nmax_in_flight = 65536\n",
+    );
+    let client = &VeloxidConfig::parse(&config).unwrap().endpoints["client"];
+    assert_eq!(client.write_timeout, Some(30));
+    assert_eq!(client.max_in_flight, Some(65536));
+}
+
+#[test]
+fn session_durations_are_positive() {
+    let route = "\n[[routes]]\nendpoints = [\"client\", \"server\"]\nsize = 1\n";
+    let config = format!("{}{}max_session_duration = 0\n", ENDPOINTS, route);
+    let error = VeloxidConfig::parse(&config).unwrap_err().to_string();
+    assert_eq!(
+        error,
+        "routes[0].max_session_duration: must be greater than 0"
+    );
+
+    let config = format!("{}{}max_session_duration = 3600\n", ENDPOINTS, route);
+    let routes = VeloxidConfig::parse(&config).unwrap().routes;
+    assert_eq!(routes[0].max_session_duration, Some(3600));
+}
+
+#[test]
+fn limits_leave_sessions_to_every_priority() {
+    let routes = "\n[[routes]]\nendpoints = [\"client\", \"server\"]\nsize = 1\n\
+                  \n[[routes]]\nendpoints = [\"client\", \"server\"]\nsize = 1\npriority = 10\n";
+    let config = format!("{}{}\n[limits]\nmax_sessions = 0\n", ENDPOINTS, routes);
+    let error = VeloxidConfig::parse(&config).unwrap_err().to_string();
+    assert_eq!(error, "limits.max_sessions: must be greater than 0");
+
+    let config = format!(
+        "{}{}\n[limits]\nmax_sessions = 4\nreserved = 4\n",
+        ENDPOINTS, routes
+    );
+    let error = VeloxidConfig::parse(&config).unwrap_err().to_string();
+    assert_eq!(
+        error,
+        "limits.reserved: must leave sessions to the lowest priority"
+    );
+
+    let config = format!(
+        "{}{}\n[limits]\nmax_sessions = 4\nreserved = 3\n",
+        ENDPOINTS, routes
+    );
+    let config = VeloxidConfig::parse(&config).unwrap();
+    assert_eq!(config.routes[1].priority, Some(10));
+    assert_eq!(config.limits.unwrap().reserved, Some(3));
+}
+
+#[test]
+fn limits_may_leave_sessions_unlimited() {
+    let config = format!("{}\n[limits]\nfd_headroom = 0\n", ENDPOINTS);
+    let error = VeloxidConfig::parse(&config).unwrap_err().to_string();
+    assert_eq!(error, "limits.fd_headroom: must be greater than 0");
+
+    let config = format!("{}\n[limits]\nfd_headroom = 64\nreserved = 2\n", ENDPOINTS);
+    let error = VeloxidConfig::parse(&config).unwrap_err().to_string();
+    assert_eq!(error, "limits.reserved: needs max_sessions");
+
+    let config = format!("{}\n[limits]\nfd_headroom = 64\n", ENDPOINTS);
+    let limits = VeloxidConfig::parse(&config).unwrap().limits.unwrap();
+    assert_eq!((limits.max_sessions, limits.fd_headroom), (None, Some(64)));
+}
+
+#[test]
+fn endpoints_listening_on_the_same_address_are_refused() {
+    let other = |host: &str, extra: &str| {
+        format!(
+            "{}\n[endpoints.other]\nhost = \"{}\"\nport = 8000\ndirection = \"inbound\"\n{}",
+            ENDPOINTS, host, extra
+        )
+    };
+    let direct = "type = \"direct\"";
+    let error = VeloxidConfig::parse(&other("127.0.0.1", direct)).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "endpoints.other.port: listens where 'client' does, share it with an endpoint of type \"auto\""
+    );
+    assert!(VeloxidConfig::parse(&other("::", direct)).is_err());
+
+    // IPv6 only, or over QUIC, they don't meet
+    let ipv6_only = format!("{}\nipv6_only = true", direct);
+    VeloxidConfig::parse(&other("::", &ipv6_only)).unwrap();
+    let quic = "type = \"tunnel\"\nsecret = \"1234\"\ntransport = \"quic\"";
+    VeloxidConfig::parse(&other("0.0.0.0", quic)).unwrap();
+}
+
+#[test]
+fn server_names_pick_routes_on_auto_endpoints_only() {
+    let shared = "\n[endpoints.shared]\nport = 443\ntype = \"auto\"\ndirection = \"inbound\"\n";
+    let route = |endpoint: &str, extra: &str| {
+        format!(
+            "{}{}\n[[routes]]\nendpoints = [\"{}\", \"server\"]\nsize = 1\n{}",
+            ENDPOINTS, shared, endpoint, extra
+        )
+    };
+    let error = VeloxidConfig::parse(&route("client", "sni = [\"example.com\"]")).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "routes[0].sni: routes on auto endpoints only"
+    );
+    let error = VeloxidConfig::parse(&route("shared", "alpn = []")).unwrap_err();
+    assert_eq!(error.to_string(), "routes[0].alpn: must be a list of names");
+    let ssh = "protocol = \"ssh\"\nsni = [\"example.com\"]";
+    let error = VeloxidConfig::parse(&route("shared", ssh)).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "routes[0].sni: TLS clients only, protocol must be tls or unset"
+    );
+
+    let config = route("shared", "sni = [\"*.example.com\"]\nalpn = [\"h2\"]");
+    let rule = VeloxidConfig::parse(&config).unwrap().routes[0].dispatch_rule();
+    assert_eq!(rule.sni, ["*.example.com"]);
+    assert_eq!(rule.alpn, ["h2"]);
+}
+
+#[test]
+fn tls_termination_needs_a_certificate_and_its_key() {
+    let shared = |extra: &str| {
+        format!(
+            "{}\n[endpoints.shared]\nport = 443\ntype = \"auto\"\ndirection = \"inbound\"\n{}",
+            ENDPOINTS, extra
+        )
+    };
+    let error = VeloxidConfig::parse(&shared("tls_cert = \"cert.pem\"")).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "endpoints.shared.tls_cert: needs tls_key"
+    );
+    let error = VeloxidConfig::parse(&shared("tls_key = \"\"")).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "endpoints.shared.tls_key: must not be empty"
+    );
+    VeloxidConfig::parse(&shared("tls_cert = \"cert.pem\"\ntls_key = \"key.pem\"")).unwrap();
+
+    let with = |endpoint: &str, extra: &str| {
+        let header = format!("[endpoints.{}]\n", endpoint);
+        ENDPOINTS.replace(&header, &format!("{}{}\n", header, extra))
+    };
+    let pair = "tls_cert = \"cert.pem\"\ntls_key = \"key.pem\"";
+    VeloxidConfig::parse(&with("client", pair)).unwrap();
+    let error = VeloxidConfig::parse(&with("server", pair)).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "endpoints.server.tls_cert: inbound direct or auto endpoints only"
+    );
+}
+
+#[test]
+fn tls_is_opened_by_direct_outbound_endpoints_only() {
+    let with = |endpoint: &str, extra: &str| {
+        let header = format!("[endpoints.{}]\n", endpoint);
+        ENDPOINTS.replace(&header, &format!("{}{}\n", header, extra))
+    };
+    let error = VeloxidConfig::parse(&with("client", "tls = true")).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "endpoints.client.tls: direct outbound endpoints only"
+    );
+    let error = VeloxidConfig::parse(&with("server", "tls_ca = \"ca.pem\"")).unwrap_err();
+    assert_eq!(error.to_string(), "endpoints.server.tls_ca: needs tls");
+    let config = VeloxidConfig::parse(&with("server", "tls = true\ntls_ca = \"ca.pem\"")).unwrap();
+    assert_eq!(config.endpoints["server"].tls, Some(true));
+}
+
+#[test]
+fn otel_needs_an_http_collector() {
+    let config = format!(
+        "{}\n[otel]\nendpoint = \"https://otel.example.com\"\n",
+        ENDPOINTS
+    );
+    let error = VeloxidConfig::parse(&config).unwrap_err().to_string();
+    assert_eq!(error, "otel.endpoint: must be an http:// URL");
+
+    let config = format!(
+        "{}\n[otel]\nendpoint = \"http://127.0.0.1:4318\"\ninterval = 0\n",
+        ENDPOINTS
+    );
+    let error = VeloxidConfig::parse(&config).unwrap_err().to_string();
+    assert_eq!(error, "otel.interval: must be greater than 0");
+}
+
+#[test]
+fn log_files_keep_some_rotated_files() {
+    let config = format!(
+        "[log]\nfile = \"/tmp/veloxid.log\"\nkeep = 0\n{}",
+        ENDPOINTS
+    );
+    let error = VeloxidConfig::parse(&config).unwrap_err().to_string();
+    assert_eq!(error, "log.keep: must be greater than 0");
+
+    let config = format!(
+        "[log]\nfile = \"/tmp/veloxid.log\"\nrotate = \"daily\"\ncompress = true\n{}",
+        ENDPOINTS
+    );
+    let log = VeloxidConfig::parse(&config).unwrap().log.unwrap();
+    assert_eq!(log.rotate, Some(veloxid::config::RotationPeriod::Daily));
+}
+
+#[test]
+fn dashboards_off_loopback_need_a_password() {
+    let dashboard = |section: &str| VeloxidConfig::parse(&format!("[dashboard]\n{}", section));
+    let error = dashboard(r#"listen = "0.0.0.0:8090""#)
+        .unwrap_err()
+        .to_string();
+    assert_eq!(
+        error,
+        "dashboard.listen: needs a password unless on loopback"
+    );
+    let error = dashboard("listen = \"0.0.0.0:8090\"\npassword = \"\"")
+        .unwrap_err()
+        .to_string();
+    assert_eq!(error, "dashboard.password: must not be empty");
+
+    dashboard(r#"listen = "127.0.0.1:8090""#).unwrap();
+    dashboard(r#"listen = "[::1]:8090""#).unwrap();
+    dashboard(r#"listen = "localhost:8090""#).unwrap();
+    dashboard("listen = \"0.0.0.0:8090\"\npassword = \"change-me\"").unwrap();
+}
+
+#[test]
+fn pins_are_for_tls_targets() {
+    let error = |endpoint: &str| {
+        VeloxidConfig::parse(&format!("[endpoints.backend]\n{}", endpoint))
+            .unwrap_err()
+            .to_string()
+    };
+    let pin = "ab".repeat(32);
+    let plain = format!(
+        "host = \"127.0.0.1\"\nport = 443\ntype = \"direct\"\ndirection = \"outbound\"\n\
+         pinned_sha256 = \"{}\"",
+        pin
+    );
+    assert_eq!(error(&plain), "endpoints.backend.pinned_sha256: needs tls");
+    let with_ca = format!("{}\ntls = true\ntls_ca = \"ca.pem\"", plain);
+    assert_eq!(
+        error(&with_ca),
+        "endpoints.backend.pinned_sha256: can't be used with tls_ca"
+    );
+    let short = plain.replace(&pin, "abcd") + "\ntls = true";
+    assert_eq!(
+        error(&short),
+        "endpoints.backend.pinned_sha256: must be a SHA-256 digest in hex"
+    );
+    VeloxidConfig::parse(&format!("[endpoints.backend]\n{}\ntls = true", plain)).unwrap();
+}
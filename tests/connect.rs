@@ -0,0 +1,91 @@
+use veloxid::{
+    config::VeloxidConfig,
+    connect::{endpoints, find_route},
+    table,
+};
+
+const CONFIG: &str = r#"
+[endpoints.client]
+port = 8000
+type = "direct"
+direction = "inbound"
+
+[endpoints.server]
+port = 8888
+type = "direct"
+direction = "outbound"
+
+[endpoints.tunnel]
+port = 9000
+type = "tunnel"
+direction = "inbound"
+secret = "1234"
+
+[[routes]]
+name = "web"
+endpoints = ["client", "server"]
+size = 1
+
+[[routes]]
+endpoints = ["tunnel", "server"]
+size = 1
+
+[endpoints.pipe]
+type = "stdio"
+direction = "outbound"
+
+[[routes]]
+name = "pipe"
+endpoints = ["client", "pipe"]
+size = 1
+
+[agent]
+host = "203.0.113.1"
+secret = "5678"
+e2e_secret = "9012"
+
+[[agent.services]]
+name = "ssh"
+listen = "127.0.0.1:2222"
+port = 2222
+size = 1
+"#;
+
+fn config() -> VeloxidConfig {
+    let mut config = VeloxidConfig::parse(CONFIG).unwrap();
+    table::add_agent(&mut config).unwrap();
+    config
+}
+
+#[test]
+fn routes_are_found_by_label_index_or_service() {
+    let config = config();
+    assert_eq!(find_route(&config, "web").unwrap(), 0);
+    assert_eq!(find_route(&config, "1").unwrap(), 1);
+    assert_eq!(find_route(&config, "#1").unwrap(), 1);
+    assert_eq!(find_route(&config, "ssh").unwrap(), 3);
+    assert_eq!(find_route(&config, "agent:ssh").unwrap(), 3);
+
+    let error = find_route(&config, "4").unwrap_err().to_string();
+    assert_eq!(error, "No such route '4'");
+    assert!(find_route(&config, "mail").is_err());
+}
+
+#[test]
+fn only_routes_from_clients_to_outbound_endpoints_connect() {
+    let config = config();
+    assert_eq!(endpoints(&config, 0).unwrap(), ["client", "server"]);
+    assert_eq!(
+        endpoints(&config, 3).unwrap(),
+        ["agent:ssh", "agent:ssh:tunnel"]
+    );
+
+    let error = endpoints(&config, 1).unwrap_err().to_string();
+    assert_eq!(
+        error,
+        "'route #1' doesn't take clients to an outbound endpoint"
+    );
+
+    // Stdin and stdout are the client already
+    assert!(endpoints(&config, 2).is_err());
+}
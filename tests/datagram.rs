@@ -0,0 +1,54 @@
+mod common;
+
+use common::{handshake, PIPE_SIZE};
+use tokio::io::{duplex, AsyncWriteExt};
+use veloxid::{error::TunnelError, protocol::datagram::TunnelDatagram};
+
+#[tokio::test]
+async fn datagrams_keep_their_boundaries_over_a_tunnel() {
+    let tunnels = handshake("1234", "1234").await;
+    let inbound = tunnels.inbound.unwrap();
+    let (mut relay, relay_session) = inbound.datagrams(Default::default());
+    let outbound = tunnels.outbound.await.unwrap().unwrap();
+    let (mut connector, connector_session) = outbound.datagrams(Default::default());
+
+    let large = vec![7u8; 65535];
+    for datagram in [&b"one"[..], b"", &large] {
+        relay.send_datagram(datagram).await.unwrap();
+    }
+    connector.send_datagram(b"two").await.unwrap();
+    assert_eq!(connector.recv_datagram().await.unwrap().unwrap(), b"one");
+    assert_eq!(connector.recv_datagram().await.unwrap().unwrap(), b"");
+    assert_eq!(connector.recv_datagram().await.unwrap().unwrap(), large);
+    assert_eq!(relay.recv_datagram().await.unwrap().unwrap(), b"two");
+
+    let error = relay.send_datagram(&[0u8; 65536]).await.unwrap_err();
+    assert!(matches!(
+        error.downcast_ref(),
+        Some(TunnelError::DatagramTooLarge(65536))
+    ));
+
+    // Each side's shutdown ends the other's datagrams, then the session
+    relay.shutdown().await.unwrap();
+    assert!(connector.recv_datagram().await.unwrap().is_none());
+    connector.shutdown().await.unwrap();
+    assert!(relay.recv_datagram().await.unwrap().is_none());
+    // A is the tunnel, B the caller's datagrams
+    let traffic = relay_session.await.unwrap().unwrap();
+    assert_eq!(traffic.a_to_b, 2 + 3);
+    assert_eq!(traffic.b_to_a, 3 * 2 + 3 + 65535);
+    connector_session.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn streams_ending_within_a_datagram_are_errors() {
+    let (mut peer, stream) = duplex(PIPE_SIZE);
+    let mut datagrams = TunnelDatagram::new(stream);
+    peer.write_all(&[0, 5, b'a', b'b']).await.unwrap();
+    peer.shutdown().await.unwrap();
+    let error = datagrams.recv_datagram().await.unwrap_err();
+    assert!(matches!(
+        error.downcast_ref(),
+        Some(TunnelError::DatagramCut)
+    ));
+}
@@ -0,0 +1,98 @@
+use veloxid::{
+    config::Protocol,
+    detect::{self, ClientHello, DispatchRule},
+};
+
+// A TLS record holding a ClientHello with these extensions
+fn client_hello(server_name: Option<&str>, alpn: &[&str]) -> Vec<u8> {
+    let vec16 = |data: &[u8]| [&(data.len() as u16).to_be_bytes()[..], data].concat();
+    let mut extensions = Vec::new();
+    if let Some(name) = server_name {
+        let entry = [&[0u8][..], &vec16(name.as_bytes())].concat();
+        extensions.extend([0, 0]);
+        extensions.extend(vec16(&vec16(&entry)));
+    }
+    if !alpn.is_empty() {
+        let list: Vec<u8> = alpn
+            .iter()
+            .flat_map(|p| [&[p.len() as u8][..], p.as_bytes()].concat())
+            .collect();
+        extensions.extend([0, 0x10]);
+        extensions.extend(vec16(&vec16(&list)));
+    }
+    // Some other extension, skipped
+    extensions.extend([0, 0x2b, 0, 3, 2, 3, 4]);
+
+    let mut body = vec![3, 3];
+    body.extend([7; 32]);
+    body.extend([0]); // session id
+    body.extend(vec16(&[0x13, 0x01]));
+    body.extend([1, 0]); // compression
+    body.extend(vec16(&extensions));
+    let len = (body.len() as u32).to_be_bytes();
+    let handshake = [&[1][..], &len[1..], &body].concat();
+    [&[0x16, 3, 1][..], &vec16(&handshake)].concat()
+}
+
+fn rule(protocol: Option<Protocol>, sni: &[&str], alpn: &[&str]) -> DispatchRule {
+    DispatchRule {
+        protocol,
+        sni: sni.iter().map(|s| s.to_string()).collect(),
+        alpn: alpn.iter().map(|s| s.to_string()).collect(),
+    }
+}
+
+#[test]
+fn client_hellos_give_their_server_name_and_alpn() {
+    let record = client_hello(Some("www.example.com"), &["h2", "http/1.1"]);
+    assert_eq!(
+        detect::client_hello(&record),
+        Some(ClientHello {
+            server_name: Some("www.example.com".to_owned()),
+            alpn: vec!["h2".to_owned(), "http/1.1".to_owned()],
+        })
+    );
+    assert_eq!(
+        detect::client_hello(&client_hello(None, &[])),
+        Some(ClientHello::default())
+    );
+
+    // Cut short or not TLS
+    assert_eq!(detect::client_hello(&record[..record.len() - 1]), None);
+    assert_eq!(detect::client_hello(b"SSH-2.0-OpenSSH_9.6\r\n"), None);
+}
+
+#[test]
+fn the_closest_fitting_route_is_picked() {
+    let table = [
+        (rule(None, &[], &[]), "fallback"),
+        (rule(Some(Protocol::Tls), &[], &[]), "tls"),
+        (rule(None, &["*.example.com"], &[]), "example"),
+        (rule(None, &["*.example.com"], &["h2"]), "example h2"),
+        (rule(Some(Protocol::Ssh), &[], &[]), "ssh"),
+    ];
+    let pick = |protocol, hello: Option<ClientHello>| {
+        detect::pick(&table, protocol, hello.as_ref()).map(|idx| table[idx].1)
+    };
+    let hello = |name: &str, alpn: &[&str]| detect::client_hello(&client_hello(Some(name), alpn));
+
+    assert_eq!(
+        pick(Protocol::Tls, hello("a.example.com", &["h2"])),
+        Some("example h2")
+    );
+    assert_eq!(
+        pick(Protocol::Tls, hello("A.Example.com", &["http/1.1"])),
+        Some("example")
+    );
+    // Only one label under it
+    assert_eq!(
+        pick(Protocol::Tls, hello("a.b.example.com", &[])),
+        Some("tls")
+    );
+    assert_eq!(pick(Protocol::Tls, hello("example.com", &[])), Some("tls"));
+    assert_eq!(pick(Protocol::Tls, None), Some("tls"));
+    assert_eq!(pick(Protocol::Ssh, None), Some("ssh"));
+    assert_eq!(pick(Protocol::Http, None), Some("fallback"));
+
+    assert_eq!(detect::pick(&table[1..2], Protocol::Http, None), None);
+}
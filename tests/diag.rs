@@ -0,0 +1,117 @@
+mod common;
+
+use common::{secret, PEER};
+use std::net::SocketAddr;
+use tokio::{net::TcpListener, task, time::Duration};
+use veloxid::{
+    config::VeloxidConfig,
+    diag::{self, Outcome, Probe, Report},
+    protocol::handshake::REASON_ROUTE_FULL,
+    relay::tunnel::Tunnel,
+};
+
+async fn diagnose(kind: &str, addr: SocketAddr) -> anyhow::Result<Report> {
+    let secret = match kind {
+        "tunnel" => "secret = \"1234\"\n",
+        _ => "",
+    };
+    let config = format!(
+        "[endpoints.server]\nhost = \"127.0.0.1\"\nport = {}\ntype = \"{}\"\n\
+         direction = \"outbound\"\n{}",
+        addr.port(),
+        kind,
+        secret
+    );
+    let config = VeloxidConfig::parse(&config).unwrap();
+    diag::diagnose("server", &config.endpoints["server"]).await
+}
+
+#[tokio::test]
+async fn direct_endpoints_get_payloads_of_growing_sizes() {
+    // Holds the connections without reading them
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    task::spawn(async move {
+        let mut held = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            held.push(stream);
+        }
+    });
+
+    let report = diagnose("direct", addr).await.unwrap();
+    assert_eq!(report.connects.len(), report.probes.len());
+    assert!(report.handshakes.is_empty());
+    for probe in &report.probes {
+        assert_eq!(probe.target, addr);
+        #[cfg(target_os = "linux")]
+        assert!(matches!(probe.outcome, Outcome::Acked(_)));
+    }
+    assert!(!report.blackholed());
+    assert!(report
+        .to_string()
+        .starts_with(&format!("'server' at {} (direct)", addr)));
+}
+
+#[tokio::test]
+async fn tunnel_endpoints_are_only_authenticated() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    task::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let tunnel = Tunnel::init(stream, PEER, true, secret("1234")).await;
+            // Closed by the peer before it is attached
+            let tunnel = tunnel.unwrap();
+            let mut byte = [0u8; 1];
+            assert_eq!(tunnel.stream.peek(&mut byte).await.unwrap(), 0);
+        }
+    });
+
+    let report = diagnose("tunnel", addr).await.unwrap();
+    assert_eq!((report.connects.len(), report.handshakes.len()), (3, 3));
+    assert!(report.probes.is_empty());
+
+    // Rejections are waited for
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    task::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            Tunnel::reject(stream, REASON_ROUTE_FULL, Default::default())
+                .await
+                .unwrap();
+        }
+    });
+    let error = diagnose("tunnel", addr).await.err().unwrap();
+    assert_eq!(
+        format!("{:#}", error),
+        format!(
+            "Handshake with {}: Rejected, the peer's route is full",
+            addr
+        )
+    );
+}
+
+#[test]
+fn stalls_of_large_payloads_only_are_blackholes() {
+    let target: SocketAddr = "127.0.0.1:1".parse().unwrap();
+    let probe = |size, outcome| Probe {
+        size,
+        target,
+        outcome,
+    };
+    let mut report = Report {
+        endpoint: "server".to_owned(),
+        targets: vec![target],
+        tunnel: false,
+        connects: vec![Duration::from_millis(1)],
+        handshakes: Vec::new(),
+        probes: vec![
+            probe(256, Outcome::Acked(Duration::from_millis(1))),
+            probe(4096, Outcome::Stalled(2800)),
+        ],
+    };
+    assert!(report.blackholed());
+    assert!(report.to_string().contains("MTU blackhole"));
+
+    report.probes[0].outcome = Outcome::Stalled(256);
+    assert!(!report.blackholed());
+}
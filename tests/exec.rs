@@ -0,0 +1,42 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use veloxid::transport::exec::Command;
+
+fn command(argv: &[&str], max_processes: Option<usize>) -> Command {
+    let argv: Vec<String> = argv.iter().map(|arg| arg.to_string()).collect();
+    Command::new(&argv, max_processes)
+}
+
+#[tokio::test]
+async fn sessions_are_the_stdin_and_stdout_of_a_process() {
+    let upper = command(&["tr", "a-z", "A-Z"], None);
+    let mut process = upper.spawn("exec").unwrap();
+    process.write_all(b"hello").await.unwrap();
+
+    // Shutting down closes stdin, the process ends and so does its stdout
+    process.shutdown().await.unwrap();
+    let mut output = Vec::new();
+    process.read_to_end(&mut output).await.unwrap();
+    assert_eq!(output, b"HELLO");
+    assert!(process.write_all(b"more").await.is_err());
+}
+
+#[tokio::test]
+async fn processes_running_at_once_are_limited() {
+    let cat = command(&["cat"], Some(1));
+    let running = cat.spawn("exec").unwrap();
+    let error = cat.spawn("exec").err().unwrap().to_string();
+    assert_eq!(error, "Reached the limit of 1 processes");
+
+    // The slot is given back once the process has exited
+    drop(running);
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+    cat.spawn("exec").unwrap();
+
+    let missing = command(&["/nonexistent/handler"], None);
+    let error = missing.spawn("exec").err().unwrap().to_string();
+    assert!(
+        error.starts_with("Couldn't run '/nonexistent/handler'"),
+        "{}",
+        error
+    );
+}
@@ -0,0 +1,91 @@
+use std::{net::IpAddr, sync::Arc, time::Duration};
+use veloxid::{
+    events::{EventHandler, EventHandlers},
+    firewall::BanCommand,
+};
+
+const V4: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+const V6: IpAddr = IpAddr::V6(std::net::Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+const LENGTH: Duration = Duration::from_secs(300);
+
+fn nft() -> BanCommand {
+    let args = "nft add element inet filter veloxid_{family} { {ip} timeout {seconds}s }";
+    let unban = "nft delete element inet filter veloxid_{family} { {ip} }";
+    BanCommand::new(
+        args.split(' ').map(str::to_owned).collect(),
+        Some(unban.split(' ').map(str::to_owned).collect()),
+    )
+}
+
+#[test]
+fn placeholders_are_replaced_in_every_argument() {
+    assert_eq!(
+        nft().expand(V4, LENGTH).join(" "),
+        "nft add element inet filter veloxid_ip { 192.0.2.1 timeout 300s }"
+    );
+    assert_eq!(
+        nft().expand(V6, LENGTH).join(" "),
+        "nft add element inet filter veloxid_ip6 { 2001:db8::1 timeout 300s }"
+    );
+}
+
+#[test]
+fn unban_commands_get_the_same_placeholders() {
+    assert_eq!(
+        nft().expand_unban(V6).unwrap().join(" "),
+        "nft delete element inet filter veloxid_ip6 { 2001:db8::1 }"
+    );
+    let command = BanCommand::new(vec!["nft".to_owned()], None);
+    assert_eq!(command.expand_unban(V4), None);
+}
+
+#[tokio::test]
+async fn commands_are_run_with_the_ban() {
+    let path = std::env::temp_dir().join(format!("veloxid-ban-{}", std::process::id()));
+    let script = format!("echo \"$0 $1\" > {}", path.display());
+    let command = BanCommand::new(
+        ["sh", "-c", &script, "{ip}", "{seconds}"]
+            .map(str::to_owned)
+            .to_vec(),
+        None,
+    );
+    command.run(V4, LENGTH).await.unwrap();
+    let output = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(output, "192.0.2.1 300\n");
+}
+
+#[tokio::test]
+async fn failing_commands_are_errors() {
+    let command = BanCommand::new(["sh", "-c", "exit 3"].map(str::to_owned).to_vec(), None);
+    let error = command.run(V4, LENGTH).await.unwrap_err().to_string();
+    assert_eq!(error, "'sh' exited with exit status: 3");
+
+    let command = BanCommand::new(vec!["/nonexistent/nft".to_owned()], None);
+    assert!(command.run(V4, LENGTH).await.is_err());
+}
+
+#[tokio::test]
+async fn lifted_bans_run_the_unban_command() {
+    let path = std::env::temp_dir().join(format!("veloxid-unban-{}", std::process::id()));
+    let script = format!("echo \"$0 $1\" > {}", path.display());
+    let command = BanCommand::new(
+        vec!["true".to_owned()],
+        Some(
+            ["sh", "-c", &script, "{ip}", "{family}"]
+                .map(str::to_owned)
+                .to_vec(),
+        ),
+    );
+    let mut events = EventHandlers::default();
+    events.register(Arc::new(command));
+    events.on_unban(V4);
+    let output = loop {
+        match std::fs::read_to_string(&path) {
+            Ok(output) if output.ends_with('\n') => break output,
+            _ => tokio::time::sleep(Duration::from_millis(10)).await,
+        }
+    };
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(output, "192.0.2.1 ip\n");
+}
@@ -0,0 +1,182 @@
+use chacha20::{
+    cipher::{KeyIvInit, StreamCipher},
+    ChaCha20,
+};
+use veloxid::protocol::encryption::token_matches;
+use veloxid::protocol::handshake::{
+    attach_frame, ping_frame, pong_frame, reject_frame, rekey_attach_frame, service_cipher,
+    service_frame, InboundEvent, InboundHandshake, OutboundEvent, OutboundHandshake,
+    ATTACH_NONCE_LEN, AUTH, AUTH_V2, AUTH_V3, CIPHER_CHACHA20, CIPHER_XCHACHA20, CONTROL_ATTACH,
+    CONTROL_PING, OFFER_LEN, REASON_SECRET_MISMATCH,
+};
+
+const SECRET: [u8; 32] = [0x42; 32];
+const NONCE: [u8; 12] = [0x24; 12];
+
+fn encrypt(mut token: [u8; 4]) -> [u8; 4] {
+    ChaCha20::new(&SECRET.into(), &NONCE.into()).apply_keystream(&mut token);
+    token
+}
+
+// Feed the nonce, returning the auth token the outbound side answers with
+fn send_nonce(outbound: &mut OutboundHandshake) -> [u8; 4] {
+    match outbound.feed(&NONCE) {
+        Some(OutboundEvent::SendAuth { auth, .. }) => auth,
+        event => panic!("expected SendAuth, got {:?}", event),
+    }
+}
+
+#[test]
+fn inbound_accepts_both_versions() {
+    let mut v1 = InboundHandshake::new(SECRET, NONCE);
+    assert_eq!(
+        v1.feed(&encrypt(AUTH)),
+        Some(InboundEvent::Authenticated { version: 1 })
+    );
+
+    let mut v2 = InboundHandshake::new(SECRET, NONCE);
+    assert_eq!(
+        v2.feed(&encrypt(AUTH_V2)),
+        Some(InboundEvent::Authenticated { version: 2 })
+    );
+}
+
+#[test]
+fn tokens_off_by_any_byte_are_mismatches() {
+    for token in [AUTH, AUTH_V2, AUTH_V3] {
+        for i in 0..token.len() {
+            let mut wrong = token;
+            wrong[i] ^= 0x80;
+            let mut inbound = InboundHandshake::new(SECRET, NONCE);
+            assert_eq!(
+                inbound.feed(&encrypt(wrong)),
+                Some(InboundEvent::SecretMismatch)
+            );
+        }
+    }
+    assert!(token_matches(&AUTH, &AUTH));
+    assert!(!token_matches(&AUTH, &AUTH_V2));
+}
+
+#[test]
+fn version_1_outbound_reads_a_starting_byte() {
+    let mut outbound = OutboundHandshake::with_version(SECRET, 1);
+    assert_eq!(send_nonce(&mut outbound), encrypt(AUTH));
+
+    assert_eq!(outbound.remaining(), 1);
+    assert_eq!(
+        outbound.feed(attach_frame(1)),
+        Some(OutboundEvent::Accepted)
+    );
+}
+
+#[test]
+fn control_frames_skip_pings_and_unknown_kinds() {
+    let mut outbound = OutboundHandshake::new(SECRET);
+    assert_eq!(send_nonce(&mut outbound), encrypt(AUTH_V2));
+
+    assert_eq!(outbound.feed(&[CONTROL_PING, 0]), Some(OutboundEvent::Ping));
+    assert_eq!(outbound.feed(&[0x7f, 2, 0xaa, 0xbb]), None);
+    assert_eq!(outbound.remaining(), 1);
+    assert_eq!(
+        outbound.feed(&[CONTROL_ATTACH, 0]),
+        Some(OutboundEvent::Accepted)
+    );
+    assert_eq!(outbound.remaining(), 0);
+}
+
+#[test]
+fn pings_with_a_payload_are_answered() {
+    let mut outbound = OutboundHandshake::new(SECRET);
+    send_nonce(&mut outbound);
+
+    assert_eq!(outbound.feed(&[CONTROL_PING, 0]), Some(OutboundEvent::Ping));
+    assert_eq!(outbound.take_pong(), None);
+    assert_eq!(outbound.feed(&ping_frame(7)), Some(OutboundEvent::Ping));
+    assert_eq!(outbound.take_pong(), Some(pong_frame(7).to_vec()));
+    assert_eq!(outbound.take_pong(), None);
+}
+
+#[test]
+fn attach_frames_carry_the_nonce_of_rekeyed_tunnels() {
+    let mut outbound = OutboundHandshake::new(SECRET);
+    send_nonce(&mut outbound);
+    assert_eq!(
+        outbound.feed(&rekey_attach_frame([7; ATTACH_NONCE_LEN])),
+        Some(OutboundEvent::Accepted)
+    );
+    assert_eq!(outbound.attach_nonce(), Some([7; ATTACH_NONCE_LEN]));
+
+    let mut outbound = OutboundHandshake::new(SECRET);
+    send_nonce(&mut outbound);
+    outbound.feed(&[CONTROL_ATTACH, 0]);
+    assert_eq!(outbound.attach_nonce(), None);
+}
+
+#[test]
+fn reject_frames_are_understood_by_both_versions() {
+    let frame = reject_frame(REASON_SECRET_MISMATCH);
+
+    let mut v2 = OutboundHandshake::new(SECRET);
+    send_nonce(&mut v2);
+    assert_eq!(
+        v2.feed(&frame),
+        Some(OutboundEvent::Rejected {
+            reason: REASON_SECRET_MISMATCH
+        })
+    );
+
+    // Only the first byte is read
+    let mut v1 = OutboundHandshake::with_version(SECRET, 1);
+    send_nonce(&mut v1);
+    assert_eq!(
+        v1.push(frame[0]),
+        Some(OutboundEvent::Rejected {
+            reason: REASON_SECRET_MISMATCH
+        })
+    );
+}
+
+#[test]
+fn default_cipher_is_not_offered() {
+    let mut outbound = OutboundHandshake::with_cipher(SECRET, CIPHER_CHACHA20);
+    match outbound.feed(&NONCE) {
+        Some(OutboundEvent::SendAuth { auth, offer, .. }) => {
+            assert_eq!(auth, encrypt(AUTH_V2));
+            assert_eq!(offer, None);
+        }
+        event => panic!("expected SendAuth, got {:?}", event),
+    }
+}
+
+#[test]
+fn inbound_reads_the_offer_of_version_3() {
+    let mut outbound = OutboundHandshake::with_cipher(SECRET, CIPHER_XCHACHA20);
+    let (auth, offer) = match outbound.feed(&NONCE) {
+        Some(OutboundEvent::SendAuth { auth, offer, .. }) => (auth, offer.unwrap()),
+        event => panic!("expected SendAuth, got {:?}", event),
+    };
+    assert_eq!(auth, encrypt(AUTH_V3));
+
+    let mut inbound = InboundHandshake::new(SECRET, NONCE);
+    assert_eq!(inbound.feed(&auth), None);
+    assert_eq!(inbound.remaining(), OFFER_LEN);
+    assert_eq!(
+        inbound.feed(&offer),
+        Some(InboundEvent::Authenticated { version: 3 })
+    );
+    assert_eq!(inbound.cipher(), CIPHER_XCHACHA20);
+    assert_eq!(inbound.salt(), outbound.salt());
+}
+
+#[test]
+fn service_frames_hide_the_name() {
+    let frame = service_frame(&SECRET, &NONCE, "ssh");
+    assert_eq!(frame.len(), 4);
+    assert_ne!(&frame[1..], b"ssh");
+
+    let mut decrypted = frame.clone();
+    service_cipher(&SECRET, &NONCE).apply_keystream(&mut decrypted);
+    assert_eq!(decrypted, b"\x03ssh");
+    assert_ne!(service_frame(&SECRET, &[0x25; 12], "ssh"), frame);
+}
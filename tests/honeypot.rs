@@ -0,0 +1,64 @@
+use dashmap::DashMap;
+use std::sync::Arc;
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    time::{sleep, timeout, Duration, Instant},
+};
+use veloxid::{
+    events::EventHandlers,
+    honeypot::{self, Honeypot},
+    transport::listener::Listener,
+};
+
+#[tokio::test]
+async fn peers_are_banned_as_they_connect() {
+    let listener = Arc::new(
+        Listener::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap(),
+    );
+    let addr = listener.addr();
+    let ban_list = Arc::new(DashMap::new());
+    let honeypot = Honeypot {
+        name: "trap".to_owned(),
+        capture: 0,
+        ban_list: ban_list.clone(),
+        events: EventHandlers::default(),
+    };
+    tokio::spawn(honeypot::serve(listener, honeypot));
+
+    let _stream = TcpStream::connect(addr).await.unwrap();
+    let peer = "127.0.0.1".parse().unwrap();
+    timeout(Duration::from_secs(1), async {
+        while !ban_list.contains_key(&peer) {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert!(*ban_list.get(&peer).unwrap() > Instant::now());
+}
+
+#[tokio::test]
+async fn captures_stop_at_the_length() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (stream, _) = listener.accept().await.unwrap();
+    client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+    assert_eq!(honeypot::capture(stream, 5).await, b"GET /");
+}
+
+#[tokio::test]
+async fn captures_end_with_the_peer() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (stream, _) = listener.accept().await.unwrap();
+    client.write_all(b"SSH-2.0").await.unwrap();
+    drop(client);
+    assert_eq!(honeypot::capture(stream, 64).await, b"SSH-2.0");
+}
@@ -0,0 +1,115 @@
+mod common;
+
+use common::{read_to_end, write_and_close, PEER, PIPE_SIZE};
+use std::sync::Arc;
+use tokio::{
+    io::duplex,
+    task,
+    time::{sleep, Duration},
+};
+use veloxid::{
+    config::CipherKind,
+    keys::{handshake_key, parse_mac, KeyCommand, KeyProvider, MemoryKey, TunnelKey},
+    relay::tunnel::{HandshakeOptions, HandshakeTimeouts, SessionOptions, Tunnel},
+};
+
+const KEY: [u8; 32] = [0x42; 32];
+
+fn command(script: &str) -> KeyCommand {
+    KeyCommand::new(vec!["sh".into(), "-c".into(), script.into()])
+}
+
+#[test]
+fn macs_are_raw_or_hex() {
+    assert_eq!(parse_mac(&[0x24; 32]), Some([0x24; 32]));
+    let hex = "24".repeat(32);
+    assert_eq!(parse_mac(format!("{}\n", hex).as_bytes()), Some([0x24; 32]));
+    let openssl = format!("HMAC-SHA2-256(stdin)= {}\n", hex);
+    assert_eq!(parse_mac(openssl.as_bytes()), Some([0x24; 32]));
+
+    assert_eq!(parse_mac(b""), None);
+    assert_eq!(parse_mac("24".repeat(31).as_bytes()), None);
+    assert_eq!(parse_mac("zz".repeat(32).as_bytes()), None);
+}
+
+#[tokio::test]
+async fn key_commands_answer_on_stdout() {
+    // The data goes to stdin, cat hands 32 bytes of it back as they are
+    let data = [0x24; 32];
+    assert_eq!(command("cat").hmac(&data).await.unwrap(), data);
+
+    let hex = format!("cat > /dev/null; echo {}", "ab".repeat(32));
+    assert_eq!(command(&hex).hmac(b"nonce").await.unwrap(), [0xab; 32]);
+
+    let error = command("exit 3").hmac(b"nonce").await.unwrap_err();
+    assert!(error.to_string().contains("exited with"), "{}", error);
+    let error = command("echo nothing").hmac(b"nonce").await.unwrap_err();
+    assert!(error.to_string().contains("printed no HMAC"), "{}", error);
+}
+
+#[tokio::test]
+async fn key_commands_past_the_limit_are_refused() {
+    let script = format!("sleep 1; echo {}", "ab".repeat(32));
+    let limited = KeyCommand::with_limit(vec!["sh".into(), "-c".into(), script], 1);
+    let running = task::spawn({
+        let limited = limited.clone();
+        async move { limited.hmac(b"nonce").await }
+    });
+    sleep(Duration::from_millis(200)).await;
+    let error = limited.hmac(b"nonce").await.unwrap_err();
+    assert!(error.to_string().contains("Too many"), "{}", error);
+    assert_eq!(running.await.unwrap().unwrap(), [0xab; 32]);
+    // Free again once it is done
+    assert_eq!(limited.hmac(b"nonce").await.unwrap(), [0xab; 32]);
+}
+
+#[tokio::test]
+async fn provided_keys_have_no_secret() {
+    let provided = TunnelKey::Provider(Arc::new(MemoryKey::new(KEY)));
+    assert!(provided.secret().is_none());
+    let nonce = [0x01; 12];
+    let expected = handshake_key(&MemoryKey::new(KEY), &nonce).await.unwrap();
+    assert_eq!(*provided.for_nonce(&nonce).await.unwrap(), expected);
+
+    let secret = TunnelKey::from(KEY);
+    assert_eq!(secret.secret(), Some(&KEY));
+    assert_eq!(*secret.for_nonce(&nonce).await.unwrap(), KEY);
+}
+
+#[tokio::test]
+async fn handshake_keys_answer_the_nonce() {
+    let key = MemoryKey::new(KEY);
+    let first = handshake_key(&key, &[0x01; 12]).await.unwrap();
+    let second = handshake_key(&key, &[0x02; 12]).await.unwrap();
+    assert_ne!(first, second);
+    assert_ne!(first, KEY);
+    let other = handshake_key(&MemoryKey::new([0x24; 32]), &[0x01; 12]).await;
+    assert_ne!(other.unwrap(), first);
+}
+
+#[tokio::test]
+async fn tunnels_keyed_by_providers_agree() {
+    let init = |stream, is_inbound, key: [u8; 32]| async move {
+        let key = TunnelKey::Provider(Arc::new(MemoryKey::new(key)));
+        let (cipher, timeouts) = (CipherKind::ChaCha20, HandshakeTimeouts::default());
+        let options = HandshakeOptions::default();
+        Tunnel::init_with_options(stream, PEER, is_inbound, key, cipher, timeouts, options).await
+    };
+
+    let (inbound_stream, outbound_stream) = duplex(PIPE_SIZE);
+    let outbound = task::spawn(init(outbound_stream, false, KEY));
+    let inbound = init(inbound_stream, true, KEY).await.unwrap();
+    let (mut client, relay_side) = duplex(PIPE_SIZE);
+    let (mut server, connector_side) = duplex(PIPE_SIZE);
+    task::spawn(inbound.run(relay_side, SessionOptions::default()));
+    let outbound = outbound.await.unwrap().unwrap();
+    task::spawn(outbound.run(connector_side, SessionOptions::default()));
+    write_and_close(&mut client, b"keyed").await;
+    assert_eq!(read_to_end(&mut server).await, b"keyed");
+
+    // Another key is a wrong secret
+    let (inbound_stream, outbound_stream) = duplex(PIPE_SIZE);
+    let outbound = task::spawn(init(outbound_stream, false, [0x24; 32]));
+    assert!(init(inbound_stream, true, KEY).await.is_err());
+    drop(outbound);
+}
@@ -0,0 +1,193 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::watch,
+    task,
+    time::{sleep, timeout, Duration},
+};
+use veloxid::{
+    config::VeloxidConfig,
+    connect,
+    knock::{self, knock_at, KnockListener, Knocks, KNOCK_SKEW},
+    relay::connection::{self, ConnectionData},
+};
+
+const SECRET: [u8; 32] = [0x42; 32];
+const PEER: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+const NOW: u64 = 1_700_000_000;
+const WINDOW: Duration = Duration::from_secs(30);
+
+#[test]
+fn knocks_let_their_sender_in_once() {
+    let knocks = Knocks::new(&SECRET, WINDOW);
+    assert!(!knocks.allows(PEER));
+
+    let packet = knock::knock(&SECRET, PEER);
+    assert!(knocks.admit(PEER, &packet));
+    assert!(knocks.allows(PEER));
+    assert!(!knocks.allows(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2))));
+    // Replayed from anywhere
+    assert!(!knocks.admit(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 3)), &packet));
+    assert!(!knocks.admit(PEER, &packet));
+}
+
+#[test]
+fn knocks_are_bound_to_their_source() {
+    let knocks = Knocks::new(&SECRET, WINDOW);
+    let other = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+    assert!(!knocks.admit(other, &knock::knock(&SECRET, PEER)));
+    assert!(!knocks.allows(other));
+    assert!(!knocks.allows(PEER));
+
+    // IPv4 peers of dual-stack sockets are mapped
+    let mapped = "::ffff:192.0.2.1".parse().unwrap();
+    assert!(knocks.admit(mapped, &knock::knock(&SECRET, PEER)));
+}
+
+#[test]
+fn knocks_need_the_secret_and_a_close_clock() {
+    let knocks = Knocks::new(&SECRET, WINDOW);
+    assert!(!knocks.admit(PEER, &knock::knock(&[0x24; 32], PEER)));
+    assert!(!knocks.admit(PEER, &[0; 64]));
+
+    let mut forged = knock::knock(&SECRET, PEER);
+    forged[20] ^= 0x01;
+    assert!(!knocks.admit(PEER, &forged));
+
+    let early = knock_at(&SECRET, PEER, NOW + KNOCK_SKEW + 1);
+    let late = knock_at(&SECRET, PEER, NOW - KNOCK_SKEW - 1);
+    assert!(!knocks.admit_at(PEER, &early, NOW));
+    assert!(!knocks.admit_at(PEER, &late, NOW));
+    assert!(!knocks.allows(PEER));
+    assert!(knocks.admit_at(PEER, &knock_at(&SECRET, PEER, NOW - KNOCK_SKEW), NOW));
+}
+
+#[tokio::test]
+async fn knocks_are_taken_on_the_knock_port() {
+    let knocks = Knocks::new(&SECRET, WINDOW);
+    let addr = "127.0.0.1:0".parse().unwrap();
+    let listener = KnockListener::bind(addr, knocks.clone()).await.unwrap();
+    let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    // Never answered, good or not
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket.send_to(b"hello", listener.addr()).await.unwrap();
+    knock::send(listener.addr(), &SECRET, None).await.unwrap();
+    timeout(Duration::from_secs(1), async {
+        while !knocks.allows(localhost) {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    let mut answer = [0u8; 1];
+    let answered = timeout(Duration::from_millis(100), socket.recv(&mut answer)).await;
+    assert!(answered.is_err());
+}
+
+// A free UDP port on localhost
+async fn udp_port() -> u16 {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket.local_addr().unwrap().port()
+}
+
+// Runs a worker of the route between endpoints a and b of the config, returning the address
+// of the first one
+async fn route(config: &str, a: &str, b: &str) -> SocketAddr {
+    let config = VeloxidConfig::parse(config).unwrap();
+    let endpoint_a = connection::get_connection_data(&config.endpoints[a])
+        .await
+        .unwrap();
+    let endpoint_b = connection::get_connection_data(&config.endpoints[b])
+        .await
+        .unwrap();
+    let ConnectionData::Inbound { listener, .. } = &endpoint_a else {
+        panic!("'{}' doesn't listen", a);
+    };
+    let addr = listener.addr();
+    let ctx = connect::context(&config.endpoints[a], Default::default());
+    let enabled = watch::Sender::new(true);
+    let receiver = enabled.subscribe();
+    task::spawn(async move {
+        connection::route(endpoint_a, endpoint_b, ctx, receiver, "knock").await;
+        drop(enabled);
+    });
+    addr
+}
+
+#[tokio::test]
+async fn tunnels_close_on_peers_that_didnt_knock() {
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server_port = server.local_addr().unwrap().port();
+    task::spawn(async move {
+        while let Ok((mut stream, _)) = server.accept().await {
+            task::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                tokio::io::copy(&mut reader, &mut writer).await
+            });
+        }
+    });
+
+    let knock_port = udp_port().await;
+    let relay = route(
+        &format!(
+            r#"
+            [endpoints.tunnel-in]
+            host = "127.0.0.1"
+            type = "tunnel"
+            direction = "inbound"
+            secret = "1234"
+            knock_port = {}
+
+            [endpoints.server]
+            host = "127.0.0.1"
+            port = {}
+            type = "direct"
+            direction = "outbound"
+            "#,
+            knock_port, server_port
+        ),
+        "tunnel-in",
+        "server",
+    )
+    .await;
+
+    // Not even the nonce is sent
+    let mut stranger = TcpStream::connect(relay).await.unwrap();
+    let mut received = Vec::new();
+    let closed = timeout(Duration::from_secs(5), stranger.read_to_end(&mut received)).await;
+    assert!(matches!(closed, Ok(Ok(0)) | Ok(Err(_))));
+
+    let local = route(
+        &format!(
+            r#"
+            [endpoints.local]
+            host = "127.0.0.1"
+            type = "direct"
+            direction = "inbound"
+
+            [endpoints.tunnel-out]
+            host = "127.0.0.1"
+            port = {}
+            type = "tunnel"
+            direction = "outbound"
+            secret = "1234"
+            knock_port = {}
+            "#,
+            relay.port(),
+            knock_port
+        ),
+        "local",
+        "tunnel-out",
+    )
+    .await;
+    let mut client = TcpStream::connect(local).await.unwrap();
+    client.write_all(b"knocked").await.unwrap();
+    let mut echo = [0u8; 7];
+    timeout(Duration::from_secs(10), client.read_exact(&mut echo))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&echo, b"knocked");
+}
@@ -0,0 +1,182 @@
+use std::{io, net::SocketAddr, sync::Arc};
+use tokio::{
+    net::TcpStream,
+    task,
+    time::{sleep, timeout, Duration, Instant},
+};
+use veloxid::transport::listener::{self, AcceptError, AcceptErrors, BindOptions, Listener};
+
+async fn free_addr() -> SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap()
+}
+
+#[tokio::test]
+async fn pending_accepts_move_to_the_new_address() {
+    let old = free_addr().await;
+    let listener = Arc::new(Listener::bind(old).await.unwrap());
+    let accept = task::spawn({
+        let listener = listener.clone();
+        async move { listener.accept().await.map(|(_, peer)| peer) }
+    });
+
+    let new = free_addr().await;
+    listener.rebind_to(new).await.unwrap();
+    assert_eq!(listener.addr(), new);
+
+    assert!(TcpStream::connect(old).await.is_err());
+    let client = TcpStream::connect(new).await.unwrap();
+    assert_eq!(accept.await.unwrap().unwrap(), client.local_addr().unwrap());
+}
+
+#[tokio::test]
+async fn waiting_workers_each_get_a_connection() {
+    let listener = Arc::new(Listener::bind(free_addr().await).await.unwrap());
+    // A worker that stops waiting takes no connection with it
+    let gave_up = timeout(Duration::from_millis(50), listener.accept()).await;
+    assert!(gave_up.is_err());
+
+    let accepts: Vec<_> = (0..8)
+        .map(|_| {
+            let listener = listener.clone();
+            task::spawn(async move { listener.accept().await.map(|(_, peer)| peer) })
+        })
+        .collect();
+    let mut clients = Vec::new();
+    for _ in 0..8 {
+        let client = TcpStream::connect(listener.addr()).await.unwrap();
+        clients.push(client.local_addr().unwrap());
+    }
+    let mut peers = Vec::new();
+    for accept in accepts {
+        peers.push(accept.await.unwrap().unwrap());
+    }
+    peers.sort();
+    clients.sort();
+    assert_eq!(peers, clients);
+}
+
+#[tokio::test]
+async fn failed_rebind_keeps_the_old_socket() {
+    let old = free_addr().await;
+    let listener = Listener::bind(old).await.unwrap();
+    let taken = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+    assert!(listener
+        .rebind_to(taken.local_addr().unwrap())
+        .await
+        .is_err());
+    assert_eq!(listener.addr(), old);
+    assert!(TcpStream::connect(old).await.is_ok());
+}
+
+fn ipv6_only(ipv6_only: bool) -> BindOptions {
+    BindOptions {
+        ipv6_only: Some(ipv6_only),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn ipv6_only_decides_on_ipv4_clients() {
+    let any = "[::]:0".parse().unwrap();
+
+    let dual = Listener::bind_with(any, ipv6_only(false)).await.unwrap();
+    let port = dual.addr().port();
+    let client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let (_, peer) = dual.accept().await.unwrap();
+    assert_eq!(peer.port(), client.local_addr().unwrap().port());
+
+    let v6 = Listener::bind_with(any, ipv6_only(true)).await.unwrap();
+    let port = v6.addr().port();
+    assert!(TcpStream::connect(("127.0.0.1", port)).await.is_err());
+    assert!(TcpStream::connect(("::1", port)).await.is_ok());
+}
+
+#[tokio::test]
+async fn retried_binds_wait_for_the_port() {
+    let taken = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = taken.local_addr().unwrap();
+    task::spawn(async move {
+        sleep(Duration::from_millis(300)).await;
+        drop(taken);
+    });
+
+    let listener = Listener::bind_retrying(addr, BindOptions::default(), Duration::from_secs(5))
+        .await
+        .unwrap();
+    assert_eq!(listener.addr(), addr);
+}
+
+#[tokio::test]
+async fn retried_binds_give_up_in_time() {
+    let taken = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = taken.local_addr().unwrap();
+
+    let error = Listener::bind_retrying(addr, BindOptions::default(), Duration::from_millis(300))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(error.kind(), std::io::ErrorKind::AddrInUse);
+}
+
+#[tokio::test]
+async fn fast_open_listeners_accept_plain_clients() {
+    let options = BindOptions {
+        fast_open: true,
+        ..Default::default()
+    };
+    let listener = Listener::bind_with(free_addr().await, options)
+        .await
+        .unwrap();
+    let client = TcpStream::connect(listener.addr()).await.unwrap();
+    let (_, peer) = listener.accept().await.unwrap();
+    assert_eq!(peer, client.local_addr().unwrap());
+}
+
+#[test]
+fn accept_errors_are_classified() {
+    let aborted = io::Error::from(io::ErrorKind::ConnectionAborted);
+    assert_eq!(AcceptError::classify(&aborted), AcceptError::Transient);
+    let other = io::Error::other("broken");
+    assert_eq!(AcceptError::classify(&other), AcceptError::Failed);
+
+    #[cfg(target_os = "linux")]
+    for (code, kind) in [
+        (libc::EMFILE, AcceptError::Exhausted),
+        (libc::ENFILE, AcceptError::Exhausted),
+        (libc::ENOBUFS, AcceptError::Exhausted),
+        (libc::EPROTO, AcceptError::Transient),
+        (libc::EBADF, AcceptError::Failed),
+    ] {
+        let error = io::Error::from_raw_os_error(code);
+        assert_eq!(AcceptError::classify(&error), kind, "{}", error);
+    }
+}
+
+#[tokio::test]
+async fn accept_loops_recover_from_passing_errors() {
+    let started = Instant::now();
+    listener::recover(io::ErrorKind::ConnectionReset.into())
+        .await
+        .unwrap();
+    assert!(started.elapsed() < Duration::from_millis(50));
+
+    // Out of resources, the loop backs off instead of spinning
+    #[cfg(target_os = "linux")]
+    {
+        let started = Instant::now();
+        listener::recover(io::Error::from_raw_os_error(libc::EMFILE))
+            .await
+            .unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+
+    assert!(listener::recover(io::Error::other("broken")).await.is_err());
+}
+
+#[tokio::test]
+async fn fresh_listeners_count_no_errors() {
+    let listener = Listener::bind(free_addr().await).await.unwrap();
+    assert_eq!(listener.errors(), AcceptErrors::default());
+}
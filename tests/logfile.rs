@@ -0,0 +1,92 @@
+use flate2::read::GzDecoder;
+use std::{
+    fs::{self, File},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+use veloxid::{config::LogConfig, logfile::LogFile};
+
+// A directory of its own under the system's temporary directory
+fn log_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("veloxid-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn config(path: &Path, keep: Option<usize>, compress: bool) -> LogConfig {
+    LogConfig {
+        file: path.to_str().unwrap().to_owned(),
+        max_bytes: Some(250),
+        rotate: None,
+        keep,
+        compress: Some(compress),
+    }
+}
+
+// Records of 100 bytes, numbered
+fn record(idx: usize) -> String {
+    format!("{:<99}\n", format!("record {}", idx))
+}
+
+#[test]
+fn files_are_rotated_by_size_and_compressed() {
+    let dir = log_dir("logfile-gz");
+    let path = dir.join("veloxid.log");
+    let mut log = LogFile::open(&config(&path, Some(2), true)).unwrap();
+    for idx in 0..10 {
+        log.write_all(record(idx).as_bytes()).unwrap();
+    }
+    log.wait();
+
+    let gunzip = |name: &str| {
+        let mut content = String::new();
+        GzDecoder::new(File::open(dir.join(name)).unwrap())
+            .read_to_string(&mut content)
+            .unwrap();
+        content
+    };
+    assert_eq!(fs::read_to_string(&path).unwrap(), record(8) + &record(9));
+    assert_eq!(gunzip("veloxid.log.1.gz"), record(6) + &record(7));
+    assert_eq!(gunzip("veloxid.log.2.gz"), record(4) + &record(5));
+
+    // Past keep, and no plain copies left behind
+    let mut names: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    assert_eq!(
+        names,
+        ["veloxid.log", "veloxid.log.1.gz", "veloxid.log.2.gz"]
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn existing_files_are_appended_to_and_shifted() {
+    let dir = log_dir("logfile");
+    let path = dir.join("veloxid.log");
+    fs::write(&path, record(0)).unwrap();
+
+    // The size of the existing file counts
+    let mut log = LogFile::open(&config(&path, None, false)).unwrap();
+    log.write_all(record(1).as_bytes()).unwrap();
+    log.write_all(record(2).as_bytes()).unwrap();
+    assert_eq!(
+        fs::read_to_string(dir.join("veloxid.log.1")).unwrap(),
+        record(0) + &record(1)
+    );
+
+    log.rotate().unwrap();
+    assert_eq!(
+        fs::read_to_string(dir.join("veloxid.log.2")).unwrap(),
+        record(0) + &record(1)
+    );
+    assert_eq!(
+        fs::read_to_string(dir.join("veloxid.log.1")).unwrap(),
+        record(2)
+    );
+    assert_eq!(fs::read_to_string(&path).unwrap(), "");
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,407 @@
+use tokio::time::Duration;
+use veloxid::protocol::{
+    encryption::Totp,
+    handshake::{
+        attach_frame, ping_frame, rekey_attach_frame, CIPHER_AES_256_GCM, CIPHER_CHACHA20,
+        CONTROL_PONG, REASON_CIPHER_MISMATCH, REASON_SECRET_MISMATCH, REASON_TICKET_REJECTED,
+        REASON_UNKNOWN_SERVICE, VERSION,
+    },
+    machine::{
+        AcceptEvent, ConnectEvent, Handshaken, InboundMachine, InboundOptions, OutboundMachine,
+        OutboundOptions,
+    },
+    silent::{silent_hello, Silence},
+    ticket::Tickets,
+};
+
+const SECRET: [u8; 32] = [0x42; 32];
+const NONCE: [u8; 12] = [0x24; 12];
+const LIFETIME: Duration = Duration::from_secs(60);
+
+// Both machines wired together, bytes move until neither has any to send
+#[derive(Default)]
+struct Wire {
+    // Sent by each side and not taken yet
+    to_outbound: Vec<u8>,
+    to_inbound: Vec<u8>,
+    accepted: Vec<AcceptEvent>,
+    connected: Vec<ConnectEvent>,
+}
+
+impl Wire {
+    fn pump(&mut self, inbound: &mut InboundMachine, outbound: &mut OutboundMachine) {
+        loop {
+            while let Some(bytes) = inbound.poll_transmit() {
+                self.to_outbound.extend(bytes);
+            }
+            while let Some(bytes) = outbound.poll_transmit() {
+                self.to_inbound.extend(bytes);
+            }
+            let taken = outbound.handle_input(&self.to_outbound);
+            self.to_outbound.drain(..taken);
+            let given = inbound.handle_input(&self.to_inbound);
+            self.to_inbound.drain(..given);
+            self.accepted
+                .extend(std::iter::from_fn(|| inbound.poll_event()));
+            self.connected
+                .extend(std::iter::from_fn(|| outbound.poll_event()));
+            if taken == 0 && given == 0 {
+                return;
+            }
+        }
+    }
+}
+
+fn inbound(cipher: u8) -> InboundMachine {
+    InboundMachine::new(
+        SECRET,
+        NONCE,
+        InboundOptions {
+            cipher,
+            ..Default::default()
+        },
+    )
+}
+
+fn outbound(cipher: u8) -> OutboundMachine {
+    OutboundMachine::new(
+        SECRET,
+        OutboundOptions {
+            cipher,
+            ..Default::default()
+        },
+    )
+}
+
+fn done(wire: &Wire) -> &Handshaken {
+    match wire.accepted.last() {
+        Some(AcceptEvent::Done(handshaken)) => handshaken,
+        event => panic!("expected Done, got {:?}", event),
+    }
+}
+
+#[test]
+fn machines_agree_on_the_handshake() {
+    for cipher in [CIPHER_CHACHA20, CIPHER_AES_256_GCM] {
+        let (mut inbound, mut outbound) = (inbound(cipher), outbound(cipher));
+        let mut wire = Wire::default();
+        wire.pump(&mut inbound, &mut outbound);
+        assert_eq!(inbound.wants(), 0);
+        assert_eq!(
+            wire.accepted[0],
+            AcceptEvent::Authenticated { resumed: false }
+        );
+        let handshaken = done(&wire).clone();
+        assert_eq!(handshaken.nonce, NONCE);
+        assert!(wire.connected.is_empty());
+
+        // Attaching is the inbound side's call, made once it has somewhere to send the tunnel
+        wire.to_outbound.extend(attach_frame(handshaken.version));
+        wire.pump(&mut inbound, &mut outbound);
+        assert_eq!(outbound.wants(), 0);
+        assert_eq!(wire.connected, [ConnectEvent::Accepted(handshaken)]);
+    }
+}
+
+#[test]
+fn wrong_secret_is_refused() {
+    let mut inbound = inbound(CIPHER_CHACHA20);
+    let mut outbound = OutboundMachine::new([0x43; 32], OutboundOptions::default());
+    let mut wire = Wire::default();
+    wire.pump(&mut inbound, &mut outbound);
+    let reason = REASON_SECRET_MISMATCH;
+    assert_eq!(wire.accepted, [AcceptEvent::Refused { reason }]);
+    assert_eq!(wire.connected, [ConnectEvent::Rejected { reason }]);
+}
+
+#[test]
+fn other_cipher_is_refused() {
+    let (mut inbound, mut outbound) = (inbound(CIPHER_AES_256_GCM), outbound(CIPHER_CHACHA20));
+    let mut wire = Wire::default();
+    wire.pump(&mut inbound, &mut outbound);
+    let reason = REASON_CIPHER_MISMATCH;
+    assert_eq!(wire.accepted, [AcceptEvent::Refused { reason }]);
+    assert_eq!(wire.connected, [ConnectEvent::Rejected { reason }]);
+}
+
+#[test]
+fn older_versions_are_spoken() {
+    for version in [1, VERSION] {
+        let mut inbound = inbound(CIPHER_CHACHA20);
+        let options = OutboundOptions {
+            version,
+            ..Default::default()
+        };
+        let mut outbound = OutboundMachine::new(SECRET, options);
+        let mut wire = Wire::default();
+        wire.pump(&mut inbound, &mut outbound);
+        assert_eq!(done(&wire).version, version);
+    }
+}
+
+#[test]
+fn services_are_announced() {
+    let expecting = || InboundOptions {
+        expect_service: true,
+        ..Default::default()
+    };
+    let announcing = |service: &str| OutboundOptions {
+        announce: Some(service.to_string()),
+        ..Default::default()
+    };
+
+    let mut inbound = InboundMachine::new(SECRET, NONCE, expecting());
+    let mut outbound = OutboundMachine::new(SECRET, announcing("ssh"));
+    let mut wire = Wire::default();
+    wire.pump(&mut inbound, &mut outbound);
+    assert_eq!(done(&wire).service.as_deref(), Some("ssh"));
+
+    // An empty name is no service, the peer is authenticated all the same
+    let mut inbound = InboundMachine::new(SECRET, NONCE, expecting());
+    let mut outbound = OutboundMachine::new(SECRET, announcing(""));
+    let mut wire = Wire::default();
+    wire.pump(&mut inbound, &mut outbound);
+    let reason = REASON_UNKNOWN_SERVICE;
+    assert_eq!(
+        wire.accepted,
+        [
+            AcceptEvent::Authenticated { resumed: false },
+            AcceptEvent::Refused { reason }
+        ]
+    );
+    assert_eq!(wire.connected, [ConnectEvent::Rejected { reason }]);
+}
+
+#[test]
+fn tickets_resume_once() {
+    let issuer = Tickets::new(&SECRET, LIFETIME);
+    let handshake = |holder: &Tickets| {
+        let mut inbound = InboundMachine::new(
+            SECRET,
+            NONCE,
+            InboundOptions {
+                tickets: Some(issuer.clone()),
+                ..Default::default()
+            },
+        );
+        let mut outbound = OutboundMachine::new(
+            SECRET,
+            OutboundOptions {
+                tickets: Some(holder.clone()),
+                ..Default::default()
+            },
+        );
+        let mut wire = Wire::default();
+        wire.pump(&mut inbound, &mut outbound);
+        wire.to_outbound.extend(attach_frame(VERSION));
+        wire.pump(&mut inbound, &mut outbound);
+        wire
+    };
+
+    // The ticket frame comes ahead of ATTACH
+    let holder = Tickets::new(&SECRET, LIFETIME);
+    let wire = handshake(&holder);
+    assert_eq!(
+        wire.accepted[0],
+        AcceptEvent::Authenticated { resumed: false }
+    );
+    assert!(matches!(wire.connected[..], [ConnectEvent::Accepted(_)]));
+
+    // Held twice, the copy is refused once the ticket is taken
+    let ticket = holder.take().unwrap();
+    let payload = [&LIFETIME.as_secs().to_be_bytes()[4..], &ticket.sealed[..]].concat();
+    let copy = Tickets::new(&SECRET, LIFETIME);
+    holder.keep(&payload, ticket.state);
+    copy.keep(&payload, ticket.state);
+
+    let wire = handshake(&holder);
+    assert_eq!(
+        wire.accepted[0],
+        AcceptEvent::Authenticated { resumed: true }
+    );
+    assert!(matches!(wire.connected[..], [ConnectEvent::Accepted(_)]));
+
+    let wire = handshake(&copy);
+    let reason = REASON_TICKET_REJECTED;
+    assert_eq!(wire.accepted, [AcceptEvent::Refused { reason }]);
+    assert_eq!(wire.connected, [ConnectEvent::Rejected { reason }]);
+}
+
+#[test]
+fn pings_are_answered_while_waiting() {
+    let (mut inbound, mut outbound) = (inbound(CIPHER_CHACHA20), outbound(CIPHER_CHACHA20));
+    let mut wire = Wire::default();
+    wire.pump(&mut inbound, &mut outbound);
+    let handshaken = done(&wire).clone();
+
+    assert_eq!(outbound.handle_input(&ping_frame(7)), 10);
+    let pong = outbound.poll_transmit().unwrap();
+    assert_eq!(pong[0], CONTROL_PONG);
+    assert_eq!(pong[2..], 7u64.to_be_bytes());
+    assert_eq!(outbound.poll_event(), None);
+
+    // The bytes after ATTACH are the session's
+    let mut input = rekey_attach_frame([5; 12]).to_vec();
+    input.extend(b"session");
+    assert_eq!(outbound.handle_input(&input), input.len() - 7);
+    assert_eq!(
+        outbound.poll_event(),
+        Some(ConnectEvent::Accepted(Handshaken {
+            attach_nonce: Some([5; 12]),
+            ..handshaken
+        }))
+    );
+    assert_eq!(outbound.handle_input(b"more"), 0);
+}
+
+#[test]
+fn silent_sides_wait_for_the_hello() {
+    let silent = || InboundOptions {
+        silence: Some(Silence::new(&SECRET)),
+        ..Default::default()
+    };
+
+    // Nothing goes out before the hello, the handshake goes on as usual then
+    let mut inbound = InboundMachine::new(SECRET, NONCE, silent());
+    assert_eq!(inbound.poll_transmit(), None);
+    let mut outbound = OutboundMachine::new(
+        SECRET,
+        OutboundOptions {
+            silent: true,
+            ..Default::default()
+        },
+    );
+    let mut wire = Wire::default();
+    wire.pump(&mut inbound, &mut outbound);
+    wire.to_outbound.extend(attach_frame(VERSION));
+    wire.pump(&mut inbound, &mut outbound);
+    assert_eq!(done(&wire).version, VERSION);
+
+    // Peers without the secret are refused without a word
+    let mut inbound = InboundMachine::new(SECRET, NONCE, silent());
+    let mut outbound = OutboundMachine::new(
+        [0x24; 32],
+        OutboundOptions {
+            silent: true,
+            ..Default::default()
+        },
+    );
+    let mut wire = Wire::default();
+    wire.pump(&mut inbound, &mut outbound);
+    let reason = REASON_SECRET_MISMATCH;
+    assert_eq!(wire.accepted, [AcceptEvent::Refused { reason }]);
+    assert!(wire.connected.is_empty());
+    assert_eq!(inbound.poll_transmit(), None);
+
+    // Nor is anything sent to a replayed hello
+    let hello = silent_hello(&SECRET);
+    let silence = Silence::new(&SECRET);
+    assert!(silence.admit(&hello));
+    let options = InboundOptions {
+        silence: Some(silence),
+        ..Default::default()
+    };
+    let mut inbound = InboundMachine::new(SECRET, NONCE, options);
+    assert_eq!(inbound.handle_input(&hello), hello.len());
+    assert_eq!(inbound.poll_transmit(), None);
+    assert_eq!(inbound.poll_event(), Some(AcceptEvent::Refused { reason }));
+}
+
+#[test]
+fn totp_codes_are_mixed_into_the_secret() {
+    let totp = Totp::new([0x11; 32], 1);
+    let outbound_with = |totp: &Totp| {
+        OutboundMachine::new(
+            SECRET,
+            OutboundOptions {
+                totp: Some(totp.clone()),
+                ..Default::default()
+            },
+        )
+    };
+    let inbound_at = |nonce| {
+        InboundMachine::new(
+            SECRET,
+            nonce,
+            InboundOptions {
+                totp: Some(totp.clone()),
+                ..Default::default()
+            },
+        )
+    };
+
+    // Both sides end up with the secret of the step
+    let (mut inbound, mut outbound) = (inbound_at(NONCE), outbound_with(&totp));
+    let mut wire = Wire::default();
+    wire.pump(&mut inbound, &mut outbound);
+    done(&wire);
+    assert_eq!(inbound.secret(), outbound.secret());
+    assert_ne!(inbound.secret(), SECRET);
+
+    // The same hello against the same nonce doesn't get in twice, a handshake of its own does
+    let (mut replayed, mut outbound) = (inbound_at(NONCE), outbound_with(&totp));
+    let mut wire = Wire::default();
+    wire.pump(&mut replayed, &mut outbound);
+    let reason = REASON_SECRET_MISMATCH;
+    assert_eq!(wire.accepted, [AcceptEvent::Refused { reason }]);
+    let (mut inbound, mut outbound) = (inbound_at([0x25; 12]), outbound_with(&totp));
+    let mut wire = Wire::default();
+    wire.pump(&mut inbound, &mut outbound);
+    done(&wire);
+
+    // Silent sides check the hello before picking a step
+    let mut inbound = InboundMachine::new(
+        SECRET,
+        [0x26; 12],
+        InboundOptions {
+            silence: Some(Silence::new(&SECRET)),
+            totp: Some(totp.clone()),
+            ..Default::default()
+        },
+    );
+    let mut outbound = OutboundMachine::new(
+        SECRET,
+        OutboundOptions {
+            silent: true,
+            totp: Some(totp.clone()),
+            ..Default::default()
+        },
+    );
+    let mut wire = Wire::default();
+    wire.pump(&mut inbound, &mut outbound);
+    done(&wire);
+
+    // The secret alone doesn't get a peer in
+    let mut inbound = InboundMachine::new(
+        SECRET,
+        NONCE,
+        InboundOptions {
+            totp: Some(totp.clone()),
+            ..Default::default()
+        },
+    );
+    let mut outbound = outbound_with(&Totp::new([0x12; 32], 1));
+    let mut wire = Wire::default();
+    wire.pump(&mut inbound, &mut outbound);
+    let reason = REASON_SECRET_MISMATCH;
+    assert_eq!(wire.accepted, [AcceptEvent::Refused { reason }]);
+}
+
+#[test]
+fn totp_steps_stay_within_the_skew() {
+    let totp = Totp::new([0x11; 32], 1);
+    let now = 1_000 * 30;
+    let steps = |steps: [u64; 3]| steps.map(|step| (step, totp.mix(&SECRET, step)));
+    assert_eq!(totp.candidates_at(&SECRET, now), steps([999, 1_000, 1_001]));
+    assert_eq!(
+        totp.candidates_at(&SECRET, now + 30),
+        steps([1_000, 1_001, 1_002])
+    );
+    assert_ne!(totp.mix(&SECRET, 999), totp.mix(&SECRET, 1_000));
+
+    // Each step is taken once per handshake, any peer may take any step within the skew
+    assert!(totp.accept_at(1_000, [1; 16], now));
+    assert!(!totp.accept_at(1_000, [1; 16], now));
+    assert!(totp.accept_at(999, [1; 16], now));
+    assert!(totp.accept_at(999, [2; 16], now));
+}
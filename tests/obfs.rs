@@ -0,0 +1,80 @@
+mod common;
+
+use common::{secret, PEER, PIPE_SIZE};
+use tokio::{
+    io::{duplex, AsyncReadExt, AsyncWriteExt},
+    task,
+};
+use veloxid::{relay::tunnel::Tunnel, transport::obfs::Obfuscation};
+
+fn obfuscation() -> Obfuscation {
+    Obfuscation {
+        server_name: Some("www.example.com".to_owned()),
+    }
+}
+
+#[tokio::test]
+async fn outbound_side_opens_with_a_client_hello() {
+    let (outbound_stream, mut inbound_stream) = duplex(PIPE_SIZE);
+    let _outbound = task::spawn(async move { obfuscation().connect(outbound_stream).await });
+
+    let mut header = [0u8; 6];
+    inbound_stream.read_exact(&mut header).await.unwrap();
+    // Handshake record, ClientHello
+    assert_eq!(&header[..3], &[0x16, 0x03, 0x01]);
+    assert_eq!(header[5], 0x01);
+
+    let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+    let mut hello = vec![0u8; len - 1];
+    inbound_stream.read_exact(&mut hello).await.unwrap();
+    assert!(hello
+        .windows("www.example.com".len())
+        .any(|name| name == b"www.example.com"));
+}
+
+#[tokio::test]
+async fn tunnel_runs_over_obfuscated_streams() {
+    let (inbound_stream, outbound_stream) = duplex(PIPE_SIZE);
+
+    let outbound = task::spawn(async move {
+        let stream = obfuscation().connect(outbound_stream).await?;
+        Tunnel::init(stream, PEER, false, secret("1234")).await
+    });
+    let stream = obfuscation().accept(inbound_stream).await.unwrap();
+    let inbound = Tunnel::init(stream, PEER, true, secret("1234"))
+        .await
+        .unwrap();
+
+    let (mut client, relay_side) = duplex(PIPE_SIZE);
+    let (mut server, connector_side) = duplex(PIPE_SIZE);
+    let relay = task::spawn(inbound.run(relay_side, Default::default()));
+    let connector = task::spawn(async move {
+        let outbound = outbound.await.unwrap().unwrap();
+        outbound.run(connector_side, Default::default()).await
+    });
+
+    // Larger than a record
+    let request: Vec<u8> = (0..100 * 1024).map(|i| (i % 251) as u8).collect();
+    client.write_all(&request).await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut received = Vec::new();
+    server.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, request);
+
+    server.write_all(b"done").await.unwrap();
+    server.shutdown().await.unwrap();
+    let mut reply = Vec::new();
+    client.read_to_end(&mut reply).await.unwrap();
+    assert_eq!(reply, b"done");
+
+    relay.await.unwrap().unwrap();
+    connector.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn non_tls_peer_is_refused() {
+    let (inbound_stream, mut probe) = duplex(PIPE_SIZE);
+    probe.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+
+    assert!(obfuscation().accept(inbound_stream).await.is_err());
+}
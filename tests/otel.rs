@@ -0,0 +1,147 @@
+#![cfg(feature = "otel")]
+
+use serde_json::Value;
+use std::time::{Duration, SystemTime};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    task,
+};
+use veloxid::{
+    config::OtelConfig,
+    events::{EventHandler, SessionInfo, SessionStats},
+    otel::Exporter,
+    relay::tunnel::Traffic,
+};
+
+fn session(id: u64) -> SessionInfo {
+    SessionInfo {
+        id,
+        route: "ssh worker #0".to_owned(),
+        route_name: "ssh".to_owned(),
+        endpoints: ["tunnel-in".to_owned(), "client".to_owned()],
+        connector: Some("198.51.100.7".parse().unwrap()),
+        peer_a: Some("198.51.100.7:40000".parse().unwrap()),
+        peer_b: None,
+        authenticated: true,
+        started: SystemTime::now(),
+    }
+}
+
+fn stats(bytes: u64, error: Option<&str>) -> SessionStats {
+    SessionStats {
+        traffic: Traffic {
+            a_to_b: bytes,
+            b_to_a: 2 * bytes,
+        },
+        duration: Duration::from_millis(250),
+        error: error.map(str::to_owned),
+    }
+}
+
+fn exporter(endpoint: &str) -> Exporter {
+    Exporter::new(&OtelConfig {
+        endpoint: endpoint.to_owned(),
+        service_name: None,
+        interval: None,
+    })
+    .unwrap()
+}
+
+// Attribute of an OTLP JSON item by key
+fn attribute<'a>(item: &'a Value, key: &str) -> &'a Value {
+    let attributes = item["attributes"].as_array().unwrap();
+    let attribute = attributes.iter().find(|a| a["key"] == key).unwrap();
+    &attribute["value"]["stringValue"]
+}
+
+#[test]
+fn sessions_are_counted_by_their_labels() {
+    let exporter = exporter("http://127.0.0.1:4318");
+    assert!(exporter.traces().is_none() && exporter.metrics().is_none());
+
+    exporter.on_session_end(&session(1), &stats(10, None));
+    exporter.on_session_end(&session(2), &stats(5, Some("Killed")));
+
+    let traces = exporter.traces().unwrap();
+    let resource = &traces["resourceSpans"][0];
+    assert_eq!(attribute(&resource["resource"], "service.name"), "veloxid");
+    let spans = resource["scopeSpans"][0]["spans"].as_array().unwrap();
+    assert_eq!(spans.len(), 2);
+    assert_eq!(attribute(&spans[0], "veloxid.route"), "ssh");
+    assert_eq!(attribute(&spans[0], "veloxid.endpoint.a"), "tunnel-in");
+    assert_eq!(attribute(&spans[0], "veloxid.connector"), "198.51.100.7");
+    assert_eq!(spans[0]["status"]["code"], 0);
+    assert_eq!(spans[1]["status"]["message"], "Killed");
+    assert_eq!(spans[0]["traceId"].as_str().unwrap().len(), 32);
+    // Taken by the export
+    assert!(exporter.traces().is_none());
+
+    // Counters stay cumulative
+    let metrics = exporter.metrics().unwrap();
+    let metrics = metrics["resourceMetrics"][0]["scopeMetrics"][0]["metrics"]
+        .as_array()
+        .unwrap();
+    let points = |name: &str| {
+        let metric = metrics.iter().find(|m| m["name"] == name).unwrap();
+        assert_eq!(metric["sum"]["isMonotonic"], true);
+        metric["sum"]["dataPoints"].as_array().unwrap().clone()
+    };
+    assert_eq!(points("veloxid.sessions")[0]["asInt"], "2");
+    assert_eq!(points("veloxid.session.errors")[0]["asInt"], "1");
+    let bytes = points("veloxid.bytes");
+    let a_to_b = bytes
+        .iter()
+        .find(|p| attribute(p, "veloxid.direction") == "a_to_b")
+        .unwrap();
+    assert_eq!(a_to_b["asInt"], "15");
+    assert_eq!(attribute(a_to_b, "veloxid.endpoint.b"), "client");
+    assert!(exporter.metrics().is_some());
+}
+
+#[tokio::test]
+async fn signals_are_posted_to_the_collector() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let collector = task::spawn(async move {
+        let mut requests = Vec::new();
+        for _ in 0..2 {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            // Up to the end of the body, the exporter leaves the connection open for the answer
+            loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request).into_owned();
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let length = head
+                        .lines()
+                        .find_map(|l| l.strip_prefix("Content-Length: "))
+                        .unwrap();
+                    if body.len() == length.parse::<usize>().unwrap() {
+                        requests.push((head.lines().next().unwrap().to_owned(), body.to_owned()));
+                        break;
+                    }
+                }
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                .await
+                .unwrap();
+        }
+        requests
+    });
+
+    let exporter = exporter(&format!("http://127.0.0.1:{}/otlp/", port));
+    exporter.on_session_end(&session(1), &stats(10, None));
+    exporter.export().await;
+
+    let requests = collector.await.unwrap();
+    assert_eq!(requests[0].0, "POST /otlp/v1/traces HTTP/1.1");
+    assert_eq!(requests[1].0, "POST /otlp/v1/metrics HTTP/1.1");
+    let traces: Value = serde_json::from_str(&requests[0].1).unwrap();
+    assert!(traces["resourceSpans"][0]["scopeSpans"][0]["spans"][0].is_object());
+    let metrics: Value = serde_json::from_str(&requests[1].1).unwrap();
+    assert!(metrics["resourceMetrics"].is_array());
+}
@@ -0,0 +1,91 @@
+mod common;
+
+use common::{secret, PEER, PIPE_SIZE};
+use tokio::{
+    io::{duplex, AsyncReadExt, AsyncWriteExt},
+    task,
+    time::{timeout, Duration},
+};
+use veloxid::{
+    protocol::padding::{self, Padding},
+    relay::tunnel::Tunnel,
+};
+
+const NONCE: [u8; 12] = [7; 12];
+
+#[tokio::test]
+async fn padded_frames_carry_the_data_both_ways() {
+    let (inbound_stream, outbound_stream) = duplex(PIPE_SIZE);
+    let key = secret("1234");
+    let mut inbound = padding::wrap(inbound_stream, Padding::Random, &key, &NONCE, true);
+    let mut outbound = padding::wrap(outbound_stream, Padding::Random, &key, &NONCE, false);
+
+    let request: Vec<u8> = (0..100 * 1024).map(|i| (i % 251) as u8).collect();
+    let writer = task::spawn({
+        let request = request.clone();
+        async move {
+            outbound.write_all(&request).await.unwrap();
+            outbound.shutdown().await.unwrap();
+            let mut reply = Vec::new();
+            outbound.read_to_end(&mut reply).await.unwrap();
+            reply
+        }
+    });
+
+    let mut received = Vec::new();
+    inbound.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, request);
+    inbound.write_all(b"done").await.unwrap();
+    inbound.shutdown().await.unwrap();
+    assert_eq!(writer.await.unwrap(), b"done");
+}
+
+#[tokio::test]
+async fn constant_padding_keeps_sending_while_idle() {
+    let (stream, mut wire) = duplex(PIPE_SIZE);
+    let key = secret("1234");
+    let padding = Padding::Constant {
+        bytes_per_sec: 64 * 1024,
+    };
+    let _padded = padding::wrap(stream, padding, &key, &NONCE, true);
+
+    // Frames of one size, without any data written
+    let mut frames = [0u8; 4 * 1024];
+    timeout(Duration::from_secs(1), wire.read_exact(&mut frames))
+        .await
+        .expect("no frames while idle")
+        .unwrap();
+}
+
+#[tokio::test]
+async fn tunnel_session_runs_padded() {
+    let (inbound_stream, outbound_stream) = duplex(PIPE_SIZE);
+    let outbound = task::spawn(Tunnel::init(outbound_stream, PEER, false, secret("1234")));
+    let inbound = Tunnel::init(inbound_stream, PEER, true, secret("1234"))
+        .await
+        .unwrap()
+        .padding(Padding::Random);
+
+    let (mut client, relay_side) = duplex(PIPE_SIZE);
+    let (mut server, connector_side) = duplex(PIPE_SIZE);
+    let relay = task::spawn(inbound.run(relay_side, Default::default()));
+    let connector = task::spawn(async move {
+        let outbound = outbound.await.unwrap().unwrap().padding(Padding::Random);
+        outbound.run(connector_side, Default::default()).await
+    });
+
+    client.write_all(b"hello").await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut received = Vec::new();
+    server.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, b"hello");
+
+    server.shutdown().await.unwrap();
+    let mut reply = Vec::new();
+    client.read_to_end(&mut reply).await.unwrap();
+    assert!(reply.is_empty());
+
+    let traffic = relay.await.unwrap().unwrap();
+    assert_eq!(traffic.b_to_a, 5);
+    connector.await.unwrap().unwrap();
+}
@@ -0,0 +1,67 @@
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    time::{sleep, timeout, Duration},
+};
+use veloxid::{
+    config::VeloxidConfig,
+    relay::{
+        connection::{self, ConnectionData},
+        pool::Pool,
+    },
+};
+
+async fn pool(backend: &TcpListener, size: usize) -> Pool {
+    let config = VeloxidConfig::parse(&format!(
+        r#"
+        [endpoints.server]
+        host = "127.0.0.1"
+        port = {}
+        type = "direct"
+        direction = "outbound"
+        pool = {}
+        "#,
+        backend.local_addr().unwrap().port(),
+        size
+    ))
+    .unwrap();
+    match connection::get_connection_data(&config.endpoints["server"]).await {
+        Ok(ConnectionData::Outbound {
+            pool: Some(pool), ..
+        }) => pool,
+        _ => panic!("no pool"),
+    }
+}
+
+#[tokio::test]
+async fn connections_are_opened_ahead_and_replaced() {
+    let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let pool = pool(&backend, 2).await;
+
+    let (mut first, _) = backend.accept().await.unwrap();
+    let (_second, _) = backend.accept().await.unwrap();
+
+    let mut taken = pool.take().unwrap();
+    taken.write_all(b"ping").await.unwrap();
+    let mut received = [0u8; 4];
+    first.read_exact(&mut received).await.unwrap();
+    assert_eq!(&received, b"ping");
+
+    // Taking one opens another
+    timeout(Duration::from_secs(1), backend.accept())
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn closed_connections_are_not_handed_out() {
+    let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let pool = pool(&backend, 2).await;
+
+    for _ in 0..2 {
+        drop(backend.accept().await.unwrap());
+    }
+    sleep(Duration::from_millis(100)).await;
+    assert!(pool.take().is_none());
+}
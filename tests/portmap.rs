@@ -0,0 +1,163 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, UdpSocket},
+    task,
+    time::Duration,
+};
+use veloxid::portmap::{self, nat_pmp, Gateway, IpProtocol, Mapping, Upnp};
+
+const PUBLIC: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 7);
+
+// A NAT-PMP router mapping each port to the next one, refusing port 1
+async fn nat_pmp_router() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    task::spawn(async move {
+        let mut request = [0u8; 12];
+        while let Ok((len, from)) = socket.recv_from(&mut request).await {
+            let mut response = vec![0, request[1] | 128, 0, 0, 0, 0, 0, 1];
+            match (request[1], len) {
+                (0, 2) => response.extend_from_slice(&PUBLIC.octets()),
+                (1 | 2, 12) => {
+                    let internal = u16::from_be_bytes([request[4], request[5]]);
+                    if internal == 1 {
+                        response[3] = 2;
+                    }
+                    let external = u16::from_be_bytes([request[6], request[7]]);
+                    let external = match external {
+                        0 => 0,
+                        port => port + 1,
+                    };
+                    response.extend_from_slice(&request[4..6]);
+                    response.extend_from_slice(&external.to_be_bytes());
+                    response.extend_from_slice(&request[8..12]);
+                }
+                _ => continue,
+            }
+            socket.send_to(&response, from).await.unwrap();
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn nat_pmp_maps_ports_on_the_public_address() {
+    let router = nat_pmp_router().await;
+    assert_eq!(nat_pmp::external_address(router).await.unwrap(), PUBLIC);
+
+    let lease = Duration::from_secs(600);
+    let mapping = nat_pmp::map(router, IpProtocol::Tcp, 8000, 8000, lease)
+        .await
+        .unwrap();
+    assert_eq!(
+        mapping,
+        Mapping {
+            external: SocketAddr::new(PUBLIC.into(), 8001),
+            lease,
+        }
+    );
+
+    let removed = nat_pmp::map(router, IpProtocol::Tcp, 8000, 0, Duration::ZERO)
+        .await
+        .unwrap();
+    assert_eq!(removed.lease, Duration::ZERO);
+
+    let error = nat_pmp::map(router, IpProtocol::Udp, 1, 1, lease)
+        .await
+        .unwrap_err();
+    assert!(error.to_string().contains("not authorized"), "{}", error);
+}
+
+// A UPnP router serving its description and the connection service's actions, keeping the
+// bodies of the actions called
+async fn upnp_router(actions: Arc<Mutex<Vec<String>>>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    task::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            // The requests are small enough for the test to wait for all of them at once
+            loop {
+                let read = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..read]);
+                let text = String::from_utf8_lossy(&request);
+                if text.starts_with("GET") && text.ends_with("\r\n\r\n")
+                    || text.ends_with("</s:Envelope>")
+                    || read == 0
+                {
+                    break;
+                }
+            }
+            let request = String::from_utf8(request).unwrap();
+            let body = match request.starts_with("GET /rootDesc.xml") {
+                true => "<root><device><serviceList>\
+                    <service><serviceType>urn:schemas-upnp-org:service:WANCommonInterfaceConfig:1</serviceType>\
+                    <controlURL>/common</controlURL></service>\
+                    <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+                    <controlURL>/ctl/IPConn</controlURL></service>\
+                    </serviceList></device></root>"
+                    .to_owned(),
+                false => {
+                    assert!(request.starts_with("POST /ctl/IPConn"), "{}", request);
+                    actions.lock().unwrap().push(request.clone());
+                    "<s:Envelope><s:Body><u:GetExternalIPAddressResponse>\
+                        <NewExternalIPAddress>203.0.113.7</NewExternalIPAddress>\
+                        </u:GetExternalIPAddressResponse></s:Body></s:Envelope>"
+                        .to_owned()
+                }
+            };
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+    });
+    format!("http://{}/rootDesc.xml", addr)
+}
+
+#[tokio::test]
+async fn upnp_maps_ports_through_the_connection_service() {
+    let actions = Arc::new(Mutex::new(Vec::new()));
+    let location = upnp_router(actions.clone()).await;
+    let gateway = Gateway::Upnp(Upnp::from_location(&location).await.unwrap());
+
+    let internal: SocketAddr = "0.0.0.0:8000".parse().unwrap();
+    let lease = Duration::from_secs(600);
+    let mapping = gateway
+        .map(IpProtocol::Tcp, internal, 18000, lease, "veloxid web-in")
+        .await
+        .unwrap();
+    assert_eq!(mapping.external, SocketAddr::new(PUBLIC.into(), 18000));
+    gateway
+        .unmap(IpProtocol::Tcp, internal, 18000)
+        .await
+        .unwrap();
+
+    let actions = actions.lock().unwrap();
+    assert!(actions[0].contains("#AddPortMapping\""));
+    // The router forwards to the address it was reached from
+    assert!(actions[0].contains("<NewInternalClient>127.0.0.1</NewInternalClient>"));
+    assert!(actions[0].contains("<NewInternalPort>8000</NewInternalPort>"));
+    assert!(actions[0].contains("<NewLeaseDuration>600</NewLeaseDuration>"));
+    assert!(actions[1].contains("#GetExternalIPAddress\""));
+    assert!(actions[2].contains("#DeletePortMapping\""));
+}
+
+#[test]
+fn ssdp_answers_give_the_description() {
+    let answer = "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\n\
+        Location: http://192.168.1.1:5000/rootDesc.xml\r\n\
+        ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n";
+    assert_eq!(
+        portmap::location(answer),
+        Some("http://192.168.1.1:5000/rootDesc.xml")
+    );
+    assert_eq!(portmap::location("HTTP/1.1 200 OK\r\n\r\n"), None);
+}
@@ -0,0 +1,22 @@
+use veloxid::privileges;
+
+// Lookups fail before anything is changed, the test process keeps its ids
+#[cfg(target_os = "linux")]
+#[test]
+fn unknown_users_and_groups_are_refused() {
+    let error = privileges::drop_to(Some("no-such-veloxid-user"), None)
+        .unwrap_err()
+        .to_string();
+    assert_eq!(
+        error,
+        "Couldn't drop privileges: no user 'no-such-veloxid-user'"
+    );
+
+    let error = privileges::drop_to(None, Some("no-such-veloxid-group"))
+        .unwrap_err()
+        .to_string();
+    assert_eq!(
+        error,
+        "Couldn't drop privileges: no group 'no-such-veloxid-group'"
+    );
+}
@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+use tokio::net::TcpListener;
+use veloxid::{
+    config::VeloxidConfig,
+    probes,
+    relay::connection::{self, ConnectionData},
+};
+
+async fn free_port() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+async fn endpoints(inbound_port: u16, outbound_port: u16) -> HashMap<String, ConnectionData> {
+    let config: VeloxidConfig = toml::from_str(&format!(
+        r#"
+        [endpoints.client]
+        host = "127.0.0.1"
+        port = {}
+        type = "direct"
+        direction = "inbound"
+
+        [endpoints.server]
+        host = "127.0.0.1"
+        port = {}
+        type = "direct"
+        direction = "outbound"
+        "#,
+        inbound_port, outbound_port
+    ))
+    .unwrap();
+
+    let mut endpoints = HashMap::new();
+    for (name, endpoint) in &config.endpoints {
+        let data = connection::get_connection_data(endpoint).await.unwrap();
+        endpoints.insert(name.clone(), data);
+    }
+    endpoints
+}
+
+#[tokio::test]
+async fn ready_with_bound_listeners_and_reachable_targets() {
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server_port = server.local_addr().unwrap().port();
+    let endpoints = endpoints(free_port().await, server_port).await;
+
+    assert!(probes::check(&endpoints).await.is_empty());
+}
+
+#[tokio::test]
+async fn not_ready_with_unbound_listeners_or_unreachable_targets() {
+    let endpoints = endpoints(free_port().await, free_port().await).await;
+    if let ConnectionData::Inbound { listener, .. } = &endpoints["client"] {
+        listener.unbind();
+    }
+
+    let problems = probes::check(&endpoints).await;
+    assert_eq!(problems.len(), 2);
+    assert!(problems[0].starts_with("'client' isn't bound"));
+    assert_eq!(problems[1], "'server' is unreachable");
+}
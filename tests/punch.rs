@@ -0,0 +1,235 @@
+use std::{net::SocketAddr, sync::Arc};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+    task,
+    time::{timeout, Duration},
+};
+use veloxid::{
+    config::VeloxidConfig,
+    connect,
+    protocol::encryption::generate_secret_from_string,
+    punch::{self, Broker},
+    relay::connection::{self, ConnectionData},
+    stun::NatTable,
+    table,
+};
+
+// Runs a worker of the route between endpoints a and b of the config, returning the address
+// of the first one if it listens
+async fn route(config: &str, a: &str, b: &str) -> Option<SocketAddr> {
+    let config = VeloxidConfig::parse(config).unwrap();
+    let endpoint_a = connection::get_connection_data(&config.endpoints[a])
+        .await
+        .unwrap();
+    let endpoint_b = connection::get_connection_data(&config.endpoints[b])
+        .await
+        .unwrap();
+    let addr = match &endpoint_a {
+        ConnectionData::Inbound { listener, .. } => Some(listener.addr()),
+        _ => None,
+    };
+    let ctx = connect::context(&config.endpoints[a], Default::default());
+    let enabled = watch::Sender::new(true);
+    let receiver = enabled.subscribe();
+    task::spawn(async move {
+        connection::route(endpoint_a, endpoint_b, ctx, receiver, "punch").await;
+        drop(enabled);
+    });
+    addr
+}
+
+async fn echo_server() -> u16 {
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = server.local_addr().unwrap().port();
+    task::spawn(async move {
+        while let Ok((mut stream, _)) = server.accept().await {
+            task::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                tokio::io::copy(&mut reader, &mut writer).await
+            });
+        }
+    });
+    port
+}
+
+// The relay's control listener and the punch port of its "echo" route, returning their ports
+async fn relay() -> (u16, u16) {
+    let config = VeloxidConfig::parse(
+        r#"
+        [control]
+        host = "127.0.0.1"
+        port = 9000
+        secret = "control"
+        "#,
+    )
+    .unwrap();
+    let control = config.control.unwrap();
+    let broker = Arc::new(Broker::new(["echo".to_owned()]));
+
+    let control_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let control_port = control_listener.local_addr().unwrap().port();
+    task::spawn({
+        let broker = broker.clone();
+        async move {
+            table::serve(
+                control_listener,
+                &control,
+                None,
+                Some(broker),
+                NatTable::default(),
+            )
+            .await
+        }
+    });
+
+    let punch_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let punch_port = punch_listener.local_addr().unwrap().port();
+    let secret = generate_secret_from_string("agents".to_owned());
+    task::spawn(punch::serve(
+        punch_listener,
+        "echo".to_owned(),
+        secret,
+        broker,
+    ));
+    (control_port, punch_port)
+}
+
+// The agent's local port, its tunnels punching through or going to the relay's port
+async fn agent(punch_port: u16, relay_port: u16) -> SocketAddr {
+    route(
+        &format!(
+            r#"
+            [endpoints.local]
+            host = "127.0.0.1"
+            type = "direct"
+            direction = "inbound"
+
+            [endpoints.tunnel]
+            host = "127.0.0.1"
+            port = {}
+            type = "tunnel"
+            direction = "outbound"
+            secret = "agents"
+            e2e_secret = "inner"
+            punch_port = {}
+            "#,
+            relay_port, punch_port
+        ),
+        "local",
+        "tunnel",
+    )
+    .await
+    .unwrap()
+}
+
+async fn ping(addr: SocketAddr) {
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    client.write_all(b"punched").await.unwrap();
+    let mut echo = [0u8; 7];
+    timeout(Duration::from_secs(10), client.read_exact(&mut echo))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&echo, b"punched");
+}
+
+#[tokio::test]
+async fn agents_punch_through_to_waiting_connectors() {
+    let server_port = echo_server().await;
+    let (control_port, punch_port) = relay().await;
+    route(
+        &format!(
+            r#"
+            [endpoints.punch]
+            host = "127.0.0.1"
+            port = {}
+            type = "tunnel"
+            direction = "inbound"
+            secret = "control"
+            e2e_secret = "inner"
+            rendezvous = "echo"
+
+            [endpoints.server]
+            host = "127.0.0.1"
+            port = {}
+            type = "direct"
+            direction = "outbound"
+            "#,
+            control_port, server_port
+        ),
+        "punch",
+        "server",
+    )
+    .await;
+
+    // Nothing listens on the relay's port, the session only gets through punched
+    let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let relay_port = closed.local_addr().unwrap().port();
+    drop(closed);
+
+    // The connector's worker has to be waiting before the agent asks
+    let local = agent(punch_port, relay_port).await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    ping(local).await;
+}
+
+#[tokio::test]
+async fn agents_go_through_the_relay_without_a_waiting_connector() {
+    let server_port = echo_server().await;
+    let (_, punch_port) = relay().await;
+
+    // Stands in for the relay and the connector behind it
+    let relayed = route(
+        &format!(
+            r#"
+            [endpoints.tunnel-in]
+            host = "127.0.0.1"
+            type = "tunnel"
+            direction = "inbound"
+            secret = "agents"
+            e2e_secret = "inner"
+
+            [endpoints.server]
+            host = "127.0.0.1"
+            port = {}
+            type = "direct"
+            direction = "outbound"
+            "#,
+            server_port
+        ),
+        "tunnel-in",
+        "server",
+    )
+    .await
+    .unwrap();
+
+    let local = agent(punch_port, relayed.port()).await;
+    ping(local).await;
+}
+
+#[tokio::test]
+async fn broker_pairs_agents_with_waiting_connectors() {
+    let broker = Arc::new(Broker::new(["echo".to_owned()]));
+    let (connector, agent): (SocketAddr, SocketAddr) = (
+        "192.0.2.1:4000".parse().unwrap(),
+        "198.51.100.1:5000".parse().unwrap(),
+    );
+
+    assert_eq!(broker.offer("echo", agent).await, None);
+    assert!(broker.wait("other", connector).await.is_err());
+
+    let waiting = task::spawn({
+        let broker = broker.clone();
+        async move { broker.wait("echo", connector).await }
+    });
+    let offered = loop {
+        if let Some(offered) = broker.offer("echo", agent).await {
+            break offered;
+        }
+        task::yield_now().await;
+    };
+    assert_eq!(offered, connector);
+    assert_eq!(waiting.await.unwrap().unwrap(), Some(agent));
+}
@@ -0,0 +1,78 @@
+mod common;
+
+use common::{secret, PIPE_SIZE};
+use tokio::{
+    io::{duplex, AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    task,
+    time::Duration,
+};
+use veloxid::{
+    error::TunnelError,
+    relay::{
+        reconnect::{retry_delay, ReconnectingTunnel},
+        tunnel::{SessionOptions, Tunnel},
+    },
+};
+
+#[tokio::test]
+async fn circuit_opens_after_authentication_failures() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    task::spawn(async move {
+        while let Ok((stream, peer)) = listener.accept().await {
+            let _ = Tunnel::init(stream, peer.ip(), true, secret("1234")).await;
+        }
+    });
+
+    let mut tunnel =
+        ReconnectingTunnel::new(addr, secret("4321")).circuit_breaker(1, Duration::from_secs(60));
+
+    for _ in 0..2 {
+        let error = tunnel.connect().await.err().unwrap();
+        assert!(matches!(
+            error.downcast_ref::<TunnelError>(),
+            Some(TunnelError::CircuitOpen)
+        ));
+    }
+}
+
+#[tokio::test]
+async fn reconnects_after_a_session_ends() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut tunnel = ReconnectingTunnel::new(addr, secret("1234"));
+
+    for round in 0u8..2 {
+        let relay = task::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            let inbound = Tunnel::init(stream, peer.ip(), true, secret("1234"))
+                .await
+                .unwrap();
+            let (mut client, relay_side) = duplex(PIPE_SIZE);
+            let session = task::spawn(inbound.run(relay_side, SessionOptions::default()));
+            client.write_all(&[round]).await.unwrap();
+            client.shutdown().await.unwrap();
+            session.await.unwrap().unwrap();
+            listener
+        });
+
+        let outbound = tunnel.connect().await.unwrap();
+        let (mut server, connector_side) = duplex(PIPE_SIZE);
+        let session = task::spawn(outbound.run(connector_side, SessionOptions::default()));
+        let mut data = Vec::new();
+        server.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, [round]);
+        drop(server);
+        let _ = session.await;
+
+        listener = relay.await.unwrap();
+    }
+}
+
+#[test]
+fn rejections_carry_their_own_delay() {
+    assert!(retry_delay(&TunnelError::RejectedRouteFull.into()).is_some());
+    assert!(retry_delay(&TunnelError::SecretRejected.into()).is_some());
+    assert!(retry_delay(&TunnelError::CircuitOpen.into()).is_none());
+}
@@ -0,0 +1,50 @@
+use std::collections::HashMap;
+use tokio::sync::watch;
+use veloxid::{admin::RouteControl, reload, scaling::Scaling};
+
+const CONFIG: &str = r#"
+[endpoints.client]
+port = 8000
+type = "direct"
+direction = "inbound"
+
+[endpoints.server]
+port = 8888
+type = "direct"
+direction = "outbound"
+"#;
+
+#[tokio::test]
+async fn routes_changing_size_are_resized() {
+    let path = std::env::temp_dir().join(format!("veloxid-reload-{}.toml", std::process::id()));
+    let route = |name: &str, sizes: &str| {
+        format!(
+            "\n[[routes]]\nname = \"{}\"\nendpoints = [\"client\", \"server\"]\n{}\n",
+            name, sizes
+        )
+    };
+    std::fs::write(
+        &path,
+        format!(
+            "{}{}{}",
+            CONFIG,
+            route("web", "size = 2"),
+            route("ssh", "min_size = 1\nmax_size = 8")
+        ),
+    )
+    .unwrap();
+
+    let control = |name: &str, size| RouteControl {
+        name: name.to_owned(),
+        endpoints: ["client".to_owned(), "server".to_owned()],
+        enabled: watch::Sender::new(true),
+        scaling: Scaling::new(size, size, None),
+    };
+    let routes = [control("web", 5), control("ssh", 4)];
+    reload::reload(path.to_str().unwrap(), &HashMap::new(), &routes)
+        .await
+        .unwrap();
+    assert_eq!(routes[0].scaling.size(), (2, 2));
+    assert_eq!(routes[1].scaling.size(), (1, 8));
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,118 @@
+#![cfg(feature = "tap")]
+
+use std::time::{Duration, SystemTime};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    time::{sleep, timeout},
+};
+use veloxid::{
+    config::TapMode,
+    replay::{self, ReplayArgs, Replayed},
+    tap::{self, Record, Tap},
+};
+
+fn record(session: u64, a_to_b: bool, data: &[u8]) -> Record {
+    Record {
+        time: SystemTime::now(),
+        session,
+        a_to_b,
+        data: data.to_vec(),
+    }
+}
+
+fn args(args: &str) -> Vec<String> {
+    args.split(' ').map(str::to_owned).collect()
+}
+
+#[tokio::test]
+async fn captures_are_read_back() {
+    let prefix = std::env::temp_dir().join(format!("veloxid-replay-{}", std::process::id()));
+    let tap = Tap::open(prefix.to_str().unwrap(), TapMode::Plaintext).unwrap();
+    let session = tap.session();
+    session.point(true, 0, 0).record(b"hello").await;
+    session.point(false, 0, 0).record(b"world").await;
+
+    let path = format!("{}.vtap", prefix.display());
+    let records = timeout(Duration::from_secs(1), async {
+        loop {
+            // The header is only written out with the first record
+            let records = tap::read(std::fs::File::open(&path).unwrap()).unwrap_or_default();
+            if records.len() == 2 {
+                return records;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(records[0].data, b"hello");
+    assert!(records[0].a_to_b);
+    assert_eq!(records[1].data, b"world");
+    assert!(!records[1].a_to_b);
+    assert_eq!(records[0].session, records[1].session);
+}
+
+#[test]
+fn records_cut_short_are_left_out() {
+    let mut file = b"VELOXID-TAP 1\n".to_vec();
+    file.extend_from_slice(&[0u8; 8 + 8 + 1]);
+    file.extend_from_slice(&3u32.to_be_bytes());
+    file.extend_from_slice(b"abc");
+    file.extend_from_slice(&[0u8; 8 + 8 + 1]);
+    file.extend_from_slice(&3u32.to_be_bytes());
+    file.extend_from_slice(b"d");
+    let records = tap::read(&file[..]).unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].data, b"abc");
+
+    assert!(tap::read(&b"GIF89a"[..]).is_err());
+}
+
+#[tokio::test]
+async fn one_side_is_sent_to_the_target() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target = listener.local_addr().unwrap().to_string();
+    let server = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        stream.read_to_end(&mut request).await.unwrap();
+        stream.write_all(b"pong").await.unwrap();
+        request
+    });
+
+    let records = [
+        record(0, true, b"ping "),
+        record(0, false, b"ignored"),
+        record(0, true, b"ping"),
+    ];
+    let replayed = replay::replay(&records, &target, false, true)
+        .await
+        .unwrap();
+    assert_eq!(server.await.unwrap(), b"ping ping");
+    assert_eq!(
+        replayed,
+        Replayed {
+            sent: 9,
+            received: 4
+        }
+    );
+}
+
+#[test]
+fn arguments_are_parsed() {
+    assert_eq!(
+        ReplayArgs::parse(&args("proxy.vtap 127.0.0.1:80 --session 3 --fast")).unwrap(),
+        ReplayArgs {
+            file: "proxy.vtap".to_owned(),
+            target: "127.0.0.1:80".to_owned(),
+            session: Some(3),
+            reverse: false,
+            fast: true,
+        }
+    );
+    assert!(ReplayArgs::parse(&args("proxy.vtap")).is_err());
+    assert!(ReplayArgs::parse(&args("proxy.vtap 127.0.0.1:80 --loop")).is_err());
+}
@@ -0,0 +1,148 @@
+use std::{net::SocketAddr, sync::Arc};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{watch, Semaphore},
+    task,
+    time::{sleep, Duration, Instant},
+};
+use veloxid::{
+    config::VeloxidConfig,
+    connect,
+    relay::connection::{self, ConnectionData},
+    scaling::Scaling,
+};
+
+async fn until(what: &str, done: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !done() {
+        assert!(Instant::now() < deadline, "{}", what);
+        sleep(Duration::from_millis(20)).await;
+    }
+}
+
+// A route from a public listener to an echo server, run by the workers of scaling
+async fn echo_route(sessions: Arc<Semaphore>, scaling: Scaling) -> SocketAddr {
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server_port = server.local_addr().unwrap().port();
+    task::spawn(async move {
+        while let Ok((mut stream, _)) = server.accept().await {
+            task::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                tokio::io::copy(&mut reader, &mut writer).await
+            });
+        }
+    });
+
+    let config = VeloxidConfig::parse(&format!(
+        "[endpoints.public]\nhost = \"127.0.0.1\"\nport = 0\ntype = \"direct\"\n\
+         direction = \"inbound\"\n\
+         [endpoints.server]\nhost = \"127.0.0.1\"\nport = {}\ntype = \"direct\"\n\
+         direction = \"outbound\"\n",
+        server_port
+    ))
+    .unwrap();
+    let public = connection::get_connection_data(&config.endpoints["public"])
+        .await
+        .unwrap();
+    let ConnectionData::Inbound { listener, .. } = &public else {
+        panic!("not inbound");
+    };
+    let addr = listener.addr();
+    let server = connection::get_connection_data(&config.endpoints["server"])
+        .await
+        .unwrap();
+
+    let mut ctx = connect::context(&config.endpoints["public"], Default::default());
+    ctx.sessions = sessions;
+    ctx.scaling = Some(scaling.clone());
+    let enabled = watch::Sender::new(true);
+    let receiver = enabled.subscribe();
+    task::spawn(async move {
+        scaling
+            .run("scaled".to_owned(), move |_| {
+                let (public, server, ctx) = (public.clone(), server.clone(), ctx.clone());
+                let enabled = receiver.clone();
+                Box::pin(async move {
+                    connection::route(public, server, ctx, enabled, "scaled").await;
+                })
+            })
+            .await;
+        drop(enabled);
+    });
+    addr
+}
+
+async fn ping(addr: SocketAddr) -> TcpStream {
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    echo(&mut client).await;
+    client
+}
+
+async fn echo(client: &mut TcpStream) {
+    client.write_all(b"ping").await.unwrap();
+    let mut echo = [0u8; 4];
+    client.read_exact(&mut echo).await.unwrap();
+    assert_eq!(&echo, b"ping");
+}
+
+#[tokio::test]
+async fn workers_follow_the_sessions() {
+    // The session slots follow the workers, from 1 to 3
+    let sessions = Arc::new(Semaphore::new(1));
+    let scaling =
+        Scaling::new(1, 3, Some(sessions.clone())).scale_down_after(Duration::from_millis(300));
+    let addr = echo_route(sessions.clone(), scaling.clone()).await;
+    until("one worker to start", || scaling.workers() == 1).await;
+
+    let mut clients = Vec::new();
+    for _ in 0..3 {
+        clients.push(ping(addr).await);
+    }
+    until("a worker per session", || scaling.workers() == 3).await;
+    // None past max_size, the fourth client waits
+    let mut waiting = TcpStream::connect(addr).await.unwrap();
+    waiting.write_all(b"ping").await.unwrap();
+    sleep(Duration::from_millis(200)).await;
+    assert_eq!(scaling.workers(), 3);
+    drop(clients);
+    let mut echo = [0u8; 4];
+    waiting.read_exact(&mut echo).await.unwrap();
+    drop(waiting);
+
+    // Spare workers are retired down to min_size, with their session slots
+    until("the spare workers to retire", || scaling.workers() == 1).await;
+    // The one left holds the one slot left while it waits
+    assert_eq!(sessions.available_permits(), 0);
+    ping(addr).await;
+}
+
+#[tokio::test]
+async fn resizing_keeps_the_listener_and_the_sessions() {
+    let sessions = Arc::new(Semaphore::new(3));
+    let scaling = Scaling::new(3, 3, Some(sessions.clone()));
+    let addr = echo_route(sessions.clone(), scaling.clone()).await;
+    until("three workers to start", || scaling.workers() == 3).await;
+    let mut running = ping(addr).await;
+
+    // Idle workers go at once, the session keeps going
+    scaling.resize(1, 1);
+    until("two workers to retire", || scaling.workers() == 1).await;
+    echo(&mut running).await;
+    // The worker left waits for the running session's slot
+    let mut waiting = TcpStream::connect(addr).await.unwrap();
+    waiting.write_all(b"ping").await.unwrap();
+    sleep(Duration::from_millis(200)).await;
+    drop(running);
+    let mut echo_bytes = [0u8; 4];
+    waiting.read_exact(&mut echo_bytes).await.unwrap();
+    drop(waiting);
+
+    // New workers take clients on the same listener, a slot each
+    scaling.resize(4, 4);
+    until("four workers to start", || scaling.workers() == 4).await;
+    let mut clients = Vec::new();
+    for _ in 0..4 {
+        clients.push(ping(addr).await);
+    }
+}
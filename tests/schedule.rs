@@ -0,0 +1,122 @@
+use chrono::{DateTime, FixedOffset, NaiveDate, Utc};
+use veloxid::{error::ConfigError, schedule::Schedule};
+
+fn schedule(windows: &str) -> Schedule {
+    Schedule::try_from(windows.to_owned()).unwrap()
+}
+
+// 2024-01-01 is a Monday
+fn at(day: u32, hour: u32, min: u32) -> DateTime<Utc> {
+    NaiveDate::from_ymd_opt(2024, 1, day)
+        .unwrap()
+        .and_hms_opt(hour, min, 0)
+        .unwrap()
+        .and_utc()
+}
+
+#[test]
+fn windows_parse() {
+    let windows = schedule("Mon-Fri 08:00-18:00, Sat 10:00-14:00, 22:00-06:00");
+    // Weekdays, Saturday's window and every night
+    assert!(windows.is_open(&at(3, 12, 0)));
+    assert!(windows.is_open(&at(6, 11, 0)));
+    assert!(!windows.is_open(&at(6, 16, 0)));
+    assert!(!windows.is_open(&at(7, 12, 0)));
+    assert!(windows.is_open(&at(7, 23, 0)));
+    assert!(windows.is_open(&at(7, 5, 0)));
+
+    // 24:00 is the end of the day
+    let evening = schedule("08:00-24:00");
+    assert!(evening.is_open(&at(2, 23, 59)));
+    assert!(!evening.is_open(&at(2, 0, 0)));
+}
+
+#[test]
+fn day_ranges_wrap_around_the_week() {
+    let weekend = schedule("Fri-Mon 00:00-24:00");
+    for (day, open) in [
+        (1, true),
+        (2, false),
+        (3, false),
+        (4, false),
+        (5, true),
+        (6, true),
+        (7, true),
+    ] {
+        assert_eq!(weekend.is_open(&at(day, 12, 0)), open, "{}", day);
+    }
+}
+
+#[test]
+fn malformed_windows_are_refused() {
+    for windows in [
+        "",
+        "Mon",
+        "08:00",
+        "08:00-",
+        "25:00-26:00",
+        "8-18",
+        "Mon-Funday 08:00-18:00",
+        "Mon 08:00-18:00,",
+    ] {
+        let error = Schedule::try_from(windows.to_owned()).unwrap_err();
+        assert!(
+            matches!(error, ConfigError::InvalidSchedule(_)),
+            "{}",
+            windows
+        );
+    }
+}
+
+#[test]
+fn windows_include_their_start_not_their_end() {
+    let office = schedule("Mon-Fri 08:00-18:00");
+    assert!(!office.is_open(&at(1, 7, 59)));
+    assert!(office.is_open(&at(1, 8, 0)));
+    assert!(office.is_open(&at(5, 17, 59)));
+    assert!(!office.is_open(&at(5, 18, 0)));
+    // Saturday and Sunday
+    assert!(!office.is_open(&at(6, 12, 0)));
+    assert!(!office.is_open(&at(7, 12, 0)));
+}
+
+#[test]
+fn windows_over_midnight_belong_to_the_day_they_start() {
+    let night = schedule("Fri 22:00-06:00");
+    assert!(night.is_open(&at(5, 22, 0)));
+    assert!(night.is_open(&at(5, 23, 59)));
+    // Saturday morning, still Friday's window
+    assert!(night.is_open(&at(6, 0, 0)));
+    assert!(night.is_open(&at(6, 5, 59)));
+    assert!(!night.is_open(&at(6, 6, 0)));
+    assert!(!night.is_open(&at(6, 22, 0)));
+    // Friday morning would be Thursday's
+    assert!(!night.is_open(&at(5, 1, 0)));
+
+    // The night of Sunday goes on to Monday
+    let sunday = schedule("Sun 23:00-01:00");
+    assert!(sunday.is_open(&at(8, 0, 30)));
+    assert!(!sunday.is_open(&at(7, 0, 30)));
+
+    let evening = schedule("Mon 20:00-24:00");
+    assert!(evening.is_open(&at(1, 23, 59)));
+    assert!(!evening.is_open(&at(2, 0, 0)));
+}
+
+#[test]
+fn windows_are_in_the_time_zone_of_the_clock() {
+    let office = schedule("Mon 09:00-10:00");
+    // 08:30 UTC is 09:30 an hour east, 07:30 an hour west
+    let now = at(1, 8, 30);
+    let east = FixedOffset::east_opt(3600).unwrap();
+    let west = FixedOffset::west_opt(3600).unwrap();
+    assert!(!office.is_open(&now));
+    assert!(office.is_open(&now.with_timezone(&east)));
+    assert!(!office.is_open(&now.with_timezone(&west)));
+
+    // Monday 00:30 UTC is still Sunday 10 hours west
+    let pacific = FixedOffset::west_opt(10 * 3600).unwrap();
+    let sunday = schedule("Sun 14:00-15:00");
+    assert!(sunday.is_open(&at(1, 0, 30).with_timezone(&pacific)));
+    assert!(!sunday.is_open(&at(1, 0, 30)));
+}
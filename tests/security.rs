@@ -0,0 +1,43 @@
+use chrono::{DateTime, Local, NaiveDateTime};
+use std::{net::IpAddr, time::Duration};
+use veloxid::{events::EventHandler, security::SecurityLog};
+
+const PEER: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+
+#[test]
+fn failures_and_bans_are_logged_one_per_line() {
+    let path = std::env::temp_dir().join(format!("veloxid-security-{}.log", std::process::id()));
+    let log = SecurityLog::open(path.to_str()).unwrap();
+    log.on_auth_failure("route #0 worker #1", "A", PEER);
+    log.on_ban("route #0 worker #1", "A", PEER, Duration::from_secs(300));
+    let content = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let prefix = format!("veloxid[{}]: ", std::process::id());
+    let messages: Vec<&str> = content
+        .lines()
+        .map(|line| {
+            let (time, rest) = line.split_once(' ').unwrap();
+            DateTime::parse_from_str(time, "%Y-%m-%dT%H:%M:%S%z").unwrap();
+            rest.strip_prefix(&prefix).unwrap()
+        })
+        .collect();
+    assert_eq!(
+        messages,
+        [
+            "auth failure from 192.0.2.1 endpoint=\"A\" route=\"route #0 worker #1\"",
+            "ban of 192.0.2.1 for 300s endpoint=\"A\" route=\"route #0 worker #1\"",
+        ]
+    );
+}
+
+#[test]
+fn lines_start_with_a_local_timestamp() {
+    let time = NaiveDateTime::parse_from_str("2026-01-31 12:00:00", "%Y-%m-%d %H:%M:%S")
+        .unwrap()
+        .and_local_timezone(Local)
+        .unwrap();
+    let line = veloxid::security::line(time, "ban of 192.0.2.1 for 300s");
+    assert!(line.starts_with("2026-01-31T12:00:00"));
+    assert!(line.ends_with("]: ban of 192.0.2.1 for 300s"));
+}
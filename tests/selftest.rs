@@ -0,0 +1,33 @@
+use veloxid::{config::VeloxidConfig, selftest};
+
+#[tokio::test]
+async fn tunnels_of_the_config_echo_on_loopback() {
+    let config: VeloxidConfig = toml::from_str(
+        r#"
+        [relay]
+        host = "relay.example.com"
+        port = 9000
+        secret = "5678"
+
+        [endpoints.web]
+        port = 8080
+        type = "direct"
+        direction = "inbound"
+
+        [endpoints.tunnel]
+        port = 9000
+        type = "tunnel"
+        direction = "inbound"
+        secret = "1234"
+        cipher = "aes-256-gcm"
+        padding = "random"
+        "#,
+    )
+    .unwrap();
+    let cases = selftest::cases(&config);
+    let names: Vec<&str> = cases.iter().map(|case| case.name.as_str()).collect();
+    assert_eq!(names, ["tunnel", "[relay]"]);
+    for case in &cases {
+        selftest::run_case(case).await.unwrap();
+    }
+}
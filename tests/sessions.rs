@@ -0,0 +1,181 @@
+mod common;
+
+use common::{read_to_end, PIPE_SIZE};
+use std::{sync::atomic::Ordering, time::SystemTime};
+use tokio::{
+    io::{duplex, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task,
+    time::{sleep, timeout, Duration},
+};
+use veloxid::{
+    error::TunnelError,
+    events::SessionInfo,
+    relay::tunnel::{LiveTraffic, SessionOptions, Tunnel},
+    sessions::SessionRegistry,
+};
+
+fn info(id: u64) -> SessionInfo {
+    SessionInfo {
+        id,
+        route: "route #0 worker #0".to_owned(),
+        route_name: String::new(),
+        endpoints: Default::default(),
+        connector: None,
+        peer_a: None,
+        peer_b: None,
+        authenticated: false,
+        started: SystemTime::now(),
+    }
+}
+
+#[tokio::test]
+async fn sessions_are_listed_while_their_handle_lives() {
+    let registry = SessionRegistry::default();
+    let handle = registry.register(info(1));
+    assert_eq!(registry.len(), 1);
+
+    // Killing wakes the session up
+    assert!(registry.kill(1));
+    assert!(!registry.kill(2));
+    timeout(Duration::from_secs(1), handle.killed())
+        .await
+        .expect("kill wasn't noticed");
+
+    drop(handle);
+    assert!(registry.is_empty());
+}
+
+#[tokio::test]
+async fn traffic_is_counted_while_the_session_runs() {
+    let (mut client, a) = duplex(PIPE_SIZE);
+    let (b, mut server) = duplex(PIPE_SIZE);
+    let traffic = LiveTraffic::default();
+    let options = SessionOptions::default().traffic(traffic.clone());
+    let session = task::spawn(Tunnel::proxy(a, b, options));
+
+    client.write_all(b"hello").await.unwrap();
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(traffic.a_to_b.load(Ordering::Relaxed), 5);
+    assert_eq!(traffic.b_to_a.load(Ordering::Relaxed), 0);
+
+    client.shutdown().await.unwrap();
+    server.shutdown().await.unwrap();
+    assert_eq!(read_to_end(&mut server).await, b"hello");
+    session.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn killed_sessions_keep_their_traffic() {
+    let registry = SessionRegistry::default();
+    let handle = registry.register(info(1));
+    let (mut client, a) = duplex(PIPE_SIZE);
+    let (b, _server) = duplex(PIPE_SIZE);
+    let options = SessionOptions::default().traffic(handle.traffic.clone());
+    let session = task::spawn(Tunnel::proxy(a, b, options));
+
+    client.write_all(b"hello").await.unwrap();
+    sleep(Duration::from_millis(50)).await;
+    assert!(registry.kill(1));
+    session.abort();
+    let traffic = handle.traffic();
+    assert_eq!((traffic.a_to_b, traffic.b_to_a), (5, 0));
+}
+
+#[tokio::test]
+async fn dropped_session_closes_both_sides() {
+    let (mut client, a) = duplex(PIPE_SIZE);
+    let (b, mut server) = duplex(PIPE_SIZE);
+    let session = task::spawn(Tunnel::proxy(a, b, SessionOptions::default()));
+    sleep(Duration::from_millis(50)).await;
+
+    // As a killed session is
+    session.abort();
+    let client_eof = timeout(Duration::from_secs(1), read_to_end(&mut client));
+    let server_eof = timeout(Duration::from_secs(1), read_to_end(&mut server));
+    assert!(client_eof.await.expect("client side left open").is_empty());
+    assert!(server_eof.await.expect("server side left open").is_empty());
+}
+
+// Both ends of a loopback TCP connection
+async fn tcp_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let connect = TcpStream::connect(listener.local_addr().unwrap());
+    let (connected, accepted) = tokio::join!(connect, listener.accept());
+    (connected.unwrap(), accepted.unwrap().0)
+}
+
+#[tokio::test]
+async fn stuck_peers_tear_the_session_down() {
+    let (mut client, a) = tcp_pair().await;
+    let (b, _server) = tcp_pair().await;
+    let options = SessionOptions {
+        write_timeout: Some(Duration::from_millis(200)),
+        max_in_flight: Some(16 * 1024),
+        ..Default::default()
+    };
+    let session = task::spawn(Tunnel::proxy(a, b, options));
+
+    // The server never reads, the client writes until the relay gives up on it
+    let chunk = vec![7u8; 64 * 1024];
+    let flood = task::spawn(async move { while client.write_all(&chunk).await.is_ok() {} });
+    let error = timeout(Duration::from_secs(10), session)
+        .await
+        .expect("stuck peer wasn't noticed")
+        .unwrap()
+        .unwrap_err();
+    assert!(matches!(
+        error.downcast_ref(),
+        Some(TunnelError::WriteStalled(_))
+    ));
+    timeout(Duration::from_secs(5), flood)
+        .await
+        .expect("client side left open")
+        .unwrap();
+}
+
+#[tokio::test]
+async fn sessions_run_on_their_own_task_only() {
+    let metrics = tokio::runtime::Handle::current().metrics();
+    let (mut client, a) = duplex(PIPE_SIZE);
+    let (b, mut server) = duplex(PIPE_SIZE);
+    let session = task::spawn(Tunnel::proxy(a, b, SessionOptions::default()));
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(metrics.num_alive_tasks(), 1);
+
+    // The client is done sending, the answer still flows back
+    client.write_all(b"request").await.unwrap();
+    client.shutdown().await.unwrap();
+    assert_eq!(read_to_end(&mut server).await, b"request");
+    server.write_all(b"response").await.unwrap();
+    server.shutdown().await.unwrap();
+    assert_eq!(read_to_end(&mut client).await, b"response");
+
+    let traffic = session.await.unwrap().unwrap();
+    assert_eq!((traffic.a_to_b, traffic.b_to_a), (7, 8));
+}
+
+#[tokio::test]
+async fn sessions_are_looked_up_with_their_deadline() {
+    let registry = SessionRegistry::default();
+    let _second = registry.register(info(2));
+    let first = registry
+        .register(info(1))
+        .expire_after(Duration::from_millis(100));
+    first.traffic.a_to_b.fetch_add(3, Ordering::Relaxed);
+    _second.traffic.b_to_a.fetch_add(4, Ordering::Relaxed);
+
+    let ids: Vec<_> = registry.snapshot().iter().map(|s| s.info.id).collect();
+    assert_eq!(ids, [1, 2]);
+    let snapshot = registry.get(1).unwrap();
+    assert_eq!(snapshot.traffic.a_to_b, 3);
+    assert!(snapshot.deadline.unwrap() > snapshot.info.started);
+    assert!(registry.get(2).unwrap().deadline.is_none());
+    assert!(registry.get(3).is_none());
+    let total = registry.traffic();
+    assert_eq!((total.a_to_b, total.b_to_a), (3, 4));
+
+    timeout(Duration::from_secs(1), first.expired())
+        .await
+        .expect("deadline wasn't noticed");
+}
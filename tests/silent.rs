@@ -0,0 +1,40 @@
+use veloxid::protocol::silent::{silent_hello, silent_hello_at, Silence, SILENT_WINDOW};
+
+const SECRET: [u8; 32] = [0x42; 32];
+const NOW: u64 = 1_700_000_000;
+
+#[test]
+fn hellos_are_admitted_once() {
+    let silence = Silence::new(&SECRET);
+    let hello = silent_hello(&SECRET);
+    assert!(silence.admit(&hello));
+    assert!(!silence.admit(&hello));
+    // Every hello is another one
+    assert!(silence.admit(&silent_hello(&SECRET)));
+}
+
+#[test]
+fn hellos_need_the_secret() {
+    let silence = Silence::new(&SECRET);
+    assert!(!silence.admit(&silent_hello(&[0x24; 32])));
+
+    let mut hello = silent_hello(&SECRET);
+    let last = hello.len() - 1;
+    hello[last] ^= 0x01;
+    assert!(!silence.admit(&hello));
+    assert!(!silence.admit(&[0; 40]));
+}
+
+#[test]
+fn hellos_out_of_the_window_are_turned_away() {
+    let silence = Silence::new(&SECRET);
+    let early = silent_hello_at(&SECRET, NOW + SILENT_WINDOW + 1);
+    let late = silent_hello_at(&SECRET, NOW - SILENT_WINDOW - 1);
+    assert!(!silence.admit_at(&early, NOW));
+    assert!(!silence.admit_at(&late, NOW));
+
+    let edge = silent_hello_at(&SECRET, NOW - SILENT_WINDOW);
+    assert!(silence.admit_at(&edge, NOW));
+    // Forgotten once out of the window, where it is turned away all the same
+    assert!(!silence.admit_at(&edge, NOW + 1));
+}
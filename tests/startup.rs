@@ -0,0 +1,32 @@
+use std::io;
+use veloxid::error::{
+    exit_status, ConfigError, StartupError, EXIT_BIND, EXIT_CONFIG, EXIT_RESOLVE,
+};
+
+fn bind_error() -> anyhow::Error {
+    let error = io::Error::from(io::ErrorKind::AddrInUse);
+    anyhow::Error::from(StartupError::Bind("127.0.0.1:80".to_owned(), error)).context("Endpoint")
+}
+
+#[test]
+fn failures_exit_by_their_kind() {
+    assert_eq!(exit_status(&ConfigError::RouteToSelf.into()), EXIT_CONFIG);
+    assert_eq!(exit_status(&bind_error()), EXIT_BIND);
+    let resolve = StartupError::Resolve("nowhere:80".to_owned());
+    assert_eq!(exit_status(&resolve.into()), EXIT_RESOLVE);
+    assert_eq!(exit_status(&anyhow::anyhow!("anything else")), 1);
+}
+
+#[test]
+fn several_failures_exit_by_the_most_fundamental() {
+    let failures = StartupError::Failed(vec![
+        bind_error(),
+        StartupError::Resolve("nowhere:80".to_owned()).into(),
+    ]);
+    assert_eq!(exit_status(&failures.into()), EXIT_RESOLVE);
+
+    let failures = StartupError::Failed(vec![bind_error(), anyhow::anyhow!("anything else")]);
+    let error = anyhow::Error::from(failures);
+    assert_eq!(exit_status(&error), EXIT_BIND);
+    assert!(error.to_string().starts_with("2 failures:\n  Endpoint: "));
+}
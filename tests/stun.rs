@@ -0,0 +1,93 @@
+use std::net::SocketAddr;
+use tokio::{net::UdpSocket, task};
+use veloxid::stun::{self, NatKind, NatTable, HEADER_LEN, TRANSACTION_LEN};
+
+const TRANSACTION: [u8; TRANSACTION_LEN] = [
+    0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae,
+];
+
+fn addr(addr: &str) -> SocketAddr {
+    addr.parse().unwrap()
+}
+
+// The IPv4 response of RFC 5769, with only its XOR-MAPPED-ADDRESS
+fn response(transaction: &[u8; TRANSACTION_LEN]) -> Vec<u8> {
+    let mut packet = vec![0x01, 0x01, 0x00, 0x0c, 0x21, 0x12, 0xa4, 0x42];
+    packet.extend_from_slice(transaction);
+    packet.extend_from_slice(&[
+        0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43,
+    ]);
+    packet
+}
+
+#[test]
+fn responses_are_parsed_like_rfc_5769() {
+    let mapped = stun::parse_response(&response(&TRANSACTION), &TRANSACTION).unwrap();
+    assert_eq!(mapped, addr("192.0.2.1:32853"));
+
+    let mut other = TRANSACTION;
+    other[0] ^= 1;
+    assert!(stun::parse_response(&response(&TRANSACTION), &other).is_err());
+    assert!(stun::parse_response(&response(&TRANSACTION)[..HEADER_LEN + 4], &TRANSACTION).is_err());
+}
+
+#[test]
+fn requests_carry_the_transaction() {
+    let request = stun::binding_request(&TRANSACTION);
+    assert_eq!(
+        &request[..8],
+        &[0x00, 0x01, 0x00, 0x00, 0x21, 0x12, 0xa4, 0x42]
+    );
+    assert_eq!(&request[8..], &TRANSACTION);
+}
+
+#[test]
+fn nats_are_told_apart_by_the_mapped_addresses() {
+    let local = addr("10.0.0.2:5000");
+    let (a, b) = (addr("203.0.113.1:6000"), addr("203.0.113.1:6001"));
+    assert_eq!(stun::classify(local, &[local, local]), NatKind::None);
+    assert_eq!(stun::classify(local, &[a]), NatKind::Unknown);
+    assert_eq!(stun::classify(local, &[a, a]), NatKind::EndpointIndependent);
+    assert_eq!(stun::classify(local, &[a, b]), NatKind::EndpointDependent);
+}
+
+// Answers every binding request with the address it came from
+async fn server() -> String {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    task::spawn(async move {
+        let mut packet = [0u8; 64];
+        while let Ok((len, from)) = socket.recv_from(&mut packet).await {
+            let transaction: [u8; TRANSACTION_LEN] = packet[8..len].try_into().unwrap();
+            let SocketAddr::V4(from) = from else { continue };
+            let mut answer = vec![0x01, 0x01, 0x00, 0x0c, 0x21, 0x12, 0xa4, 0x42];
+            answer.extend_from_slice(&transaction);
+            answer.extend_from_slice(&[0x00, 0x20, 0x00, 0x08, 0x00, 0x01]);
+            answer.extend_from_slice(&(from.port() ^ 0x2112).to_be_bytes());
+            let ip = u32::from(*from.ip()) ^ 0x2112_a442;
+            answer.extend_from_slice(&ip.to_be_bytes());
+            socket.send_to(&answer, from).await.unwrap();
+        }
+    });
+    addr.to_string()
+}
+
+#[tokio::test]
+async fn loopback_has_no_nat() {
+    let servers = [server().await, server().await];
+    let report = stun::discover(&servers).await.unwrap();
+    assert_eq!(report.kind, NatKind::None);
+    assert_eq!(report.mapped, vec![report.local, report.local]);
+
+    let table = NatTable::default();
+    table.record("self".to_owned(), report.clone());
+    let listed = table.list();
+    assert_eq!((listed[0].0.as_str(), &listed[0].1), ("self", &report));
+}
+
+#[tokio::test]
+async fn discovery_fails_without_an_answer() {
+    let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let servers = [silent.local_addr().unwrap().to_string()];
+    assert!(stun::discover(&servers).await.is_err());
+}
@@ -0,0 +1,284 @@
+mod common;
+
+use common::{secret, PEER, PIPE_SIZE};
+use std::net::SocketAddr;
+use tokio::{io::duplex, task};
+use veloxid::{
+    config::{Expose, VeloxidConfig},
+    stun::NatTable,
+    table::{self, TableEntry, TableRequest},
+};
+
+// Where the relay sees the connector's connection come from
+const CONNECTOR: SocketAddr = SocketAddr::new(PEER, 40000);
+
+fn entries() -> Vec<TableEntry> {
+    vec![
+        TableEntry {
+            name: "ssh".to_owned(),
+            tunnel_port: 9022,
+            target: "127.0.0.1:22".to_owned(),
+            size: 2,
+            public_port: Some(2222),
+            end_to_end: false,
+            punch: false,
+        },
+        TableEntry {
+            name: "web".to_owned(),
+            tunnel_port: 9080,
+            target: "localhost:8080".to_owned(),
+            size: 4,
+            public_port: None,
+            end_to_end: false,
+            punch: false,
+        },
+    ]
+}
+
+#[tokio::test]
+async fn table_is_sent_over_a_tunnel() {
+    let (relay_stream, connector_stream) = duplex(PIPE_SIZE);
+    let sent = entries();
+    let relay = task::spawn(async move {
+        table::send_table(
+            relay_stream,
+            CONNECTOR,
+            secret("1234"),
+            &sent,
+            None,
+            None,
+            &NatTable::default(),
+        )
+        .await
+    });
+
+    let request = TableRequest::default();
+    let received = table::fetch_table(connector_stream, PEER, secret("1234"), &request)
+        .await
+        .unwrap();
+    assert_eq!(received, entries());
+    relay.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn table_is_refused_with_another_secret() {
+    let (relay_stream, connector_stream) = duplex(PIPE_SIZE);
+    let relay = task::spawn(async move {
+        table::send_table(
+            relay_stream,
+            CONNECTOR,
+            secret("1234"),
+            &entries(),
+            None,
+            None,
+            &NatTable::default(),
+        )
+        .await
+    });
+
+    let request = TableRequest::default();
+    assert!(
+        table::fetch_table(connector_stream, PEER, secret("4321"), &request)
+            .await
+            .is_err()
+    );
+    assert!(relay.await.unwrap().is_err());
+}
+
+#[tokio::test]
+async fn exposing_is_refused_without_a_limit() {
+    let (relay_stream, connector_stream) = duplex(PIPE_SIZE);
+    let relay = task::spawn(async move {
+        table::send_table(
+            relay_stream,
+            CONNECTOR,
+            secret("1234"),
+            &entries(),
+            None,
+            None,
+            &NatTable::default(),
+        )
+        .await
+    });
+
+    let request = TableRequest {
+        expose: vec![Expose {
+            name: "db".to_owned(),
+            target: "127.0.0.1:5432".to_owned(),
+            size: 1,
+        }],
+        ..Default::default()
+    };
+    let received = table::fetch_table(connector_stream, PEER, secret("1234"), &request)
+        .await
+        .unwrap();
+    assert_eq!(received, entries());
+    relay.await.unwrap().unwrap();
+}
+
+#[test]
+fn exports_and_imports_become_routes() {
+    let mut relay: VeloxidConfig = toml::from_str(
+        r#"
+        [control]
+        port = 9000
+        secret = "1234"
+
+        [[control.exports]]
+        name = "ssh"
+        port = 2222
+        tunnel_port = 9022
+        target = "127.0.0.1:22"
+        size = 2
+        "#,
+    )
+    .unwrap();
+    table::add_exports(&mut relay).unwrap();
+    assert_eq!(relay.routes.len(), 1);
+    assert_eq!(
+        relay.routes[0].endpoints,
+        ["export:ssh", "export:ssh:tunnel"]
+    );
+    assert_eq!(relay.endpoints["export:ssh"].port, 2222);
+    assert_eq!(relay.endpoints["export:ssh:tunnel"].port, 9022);
+
+    let mut connector: VeloxidConfig = toml::from_str(
+        r#"
+        [relay]
+        host = "relay.example.com"
+        port = 9000
+        secret = "1234"
+        "#,
+    )
+    .unwrap();
+    table::add_imports(&mut connector, &entries()).unwrap();
+    assert_eq!(connector.routes.len(), 2);
+    let tunnel = &connector.endpoints["relay:web:tunnel"];
+    assert_eq!(tunnel.host.as_deref(), Some("relay.example.com"));
+    assert_eq!(tunnel.port, 9080);
+    let service = &connector.endpoints["relay:web"];
+    assert_eq!(service.host.as_deref(), Some("localhost"));
+    assert_eq!(service.port, 8080);
+}
+
+#[test]
+fn services_pick_and_retarget_routes() {
+    let mut connector: VeloxidConfig = toml::from_str(
+        r#"
+        [relay]
+        host = "relay.example.com"
+        port = 9000
+        secret = "1234"
+
+        [[relay.services]]
+        port = 2222
+        target = "10.0.0.5:22"
+        size = 8
+        "#,
+    )
+    .unwrap();
+    table::add_imports(&mut connector, &entries()).unwrap();
+
+    // web isn't among the services
+    assert_eq!(connector.routes.len(), 1);
+    assert_eq!(connector.routes[0].size, 8);
+    let service = &connector.endpoints["relay:ssh"];
+    assert_eq!(service.host.as_deref(), Some("10.0.0.5"));
+    assert_eq!(service.port, 22);
+}
+
+#[test]
+fn agents_reach_connectors_through_blind_exports() {
+    let mut relay: VeloxidConfig = toml::from_str(
+        r#"
+        [control]
+        port = 9000
+        secret = "1234"
+
+        [[control.exports]]
+        name = "ssh"
+        port = 2222
+        tunnel_port = 9022
+        target = "127.0.0.1:22"
+        size = 2
+        agent_secret = "agents"
+        "#,
+    )
+    .unwrap();
+    table::add_exports(&mut relay).unwrap();
+    assert_eq!(relay.routes[0].blind, Some(true));
+    let agents = &relay.endpoints["export:ssh"];
+    assert_eq!(agents.secret.as_deref(), Some("agents"));
+
+    let mut connector: VeloxidConfig = toml::from_str(
+        r#"
+        [relay]
+        host = "relay.example.com"
+        port = 9000
+        secret = "1234"
+        "#,
+    )
+    .unwrap();
+    let entries: Vec<TableEntry> = relay
+        .control
+        .iter()
+        .flat_map(|c| &c.exports)
+        .map(TableEntry::from)
+        .collect();
+    assert!(table::add_imports(&mut connector, &entries).is_err());
+    connector.relay.as_mut().unwrap().e2e_secret = Some("inner".to_owned());
+    table::add_imports(&mut connector, &entries).unwrap();
+    let tunnel = &connector.endpoints["relay:ssh:tunnel"];
+    assert_eq!(tunnel.e2e_secret.as_deref(), Some("inner"));
+
+    let mut agent: VeloxidConfig = toml::from_str(
+        r#"
+        [agent]
+        host = "relay.example.com"
+        secret = "agents"
+        e2e_secret = "inner"
+
+        [[agent.services]]
+        name = "ssh"
+        listen = "127.0.0.1:2222"
+        port = 2222
+        size = 2
+        "#,
+    )
+    .unwrap();
+    table::add_agent(&mut agent).unwrap();
+    assert_eq!(agent.routes[0].endpoints, ["agent:ssh", "agent:ssh:tunnel"]);
+    let tunnel = &agent.endpoints["agent:ssh:tunnel"];
+    assert_eq!(tunnel.host.as_deref(), Some("relay.example.com"));
+    assert_eq!(tunnel.port, 2222);
+    assert_eq!(tunnel.e2e_secret.as_deref(), Some("inner"));
+}
+
+#[test]
+fn punched_exports_wait_for_agents_on_the_control_listener() {
+    let mut connector: VeloxidConfig = toml::from_str(
+        r#"
+        [relay]
+        host = "relay.example.com"
+        port = 9000
+        secret = "1234"
+        e2e_secret = "inner"
+        "#,
+    )
+    .unwrap();
+    let entries = [TableEntry {
+        end_to_end: true,
+        punch: true,
+        ..entries().remove(0)
+    }];
+    table::add_imports(&mut connector, &entries).unwrap();
+    assert_eq!(connector.routes.len(), 2);
+    assert_eq!(
+        connector.routes[1].endpoints,
+        ["relay:ssh:punch", "relay:ssh"]
+    );
+    let punch = &connector.endpoints["relay:ssh:punch"];
+    assert_eq!(punch.port, 9000);
+    assert_eq!(punch.rendezvous.as_deref(), Some("ssh"));
+    assert_eq!(punch.e2e_secret.as_deref(), Some("inner"));
+}
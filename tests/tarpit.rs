@@ -0,0 +1,57 @@
+use dashmap::DashMap;
+use std::{net::IpAddr, sync::Arc};
+use tokio::{
+    io::{duplex, AsyncReadExt},
+    time::{timeout, Duration, Instant},
+};
+use veloxid::tarpit::Tarpit;
+
+const PEER: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+const INTERVAL: Duration = Duration::from_millis(20);
+
+#[test]
+fn sockets_past_max_arent_held() {
+    let tarpit = Tarpit::new(2, INTERVAL, Arc::new(DashMap::new()));
+    let slots = [tarpit.slot().unwrap(), tarpit.slot().unwrap()];
+    assert!(tarpit.slot().is_none());
+    drop(slots);
+    assert!(tarpit.slot().is_some());
+}
+
+#[tokio::test]
+async fn banned_peers_get_bytes_until_the_ban_is_over() {
+    let ban_list = Arc::new(DashMap::new());
+    ban_list.insert(PEER, Instant::now() + Duration::from_secs(60));
+    let tarpit = Tarpit::new(1, INTERVAL, ban_list.clone());
+    let (ours, mut theirs) = duplex(64);
+    let slot = tarpit.slot().unwrap();
+    let held = tokio::spawn(tarpit.clone().hold(ours, PEER, slot));
+
+    let mut bytes = [0u8; 2];
+    theirs.read_exact(&mut bytes).await.unwrap();
+    assert!(tarpit.slot().is_none());
+
+    // Let go once the ban is lifted, the slot with it
+    ban_list.remove(&PEER);
+    timeout(Duration::from_secs(1), held)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(tarpit.slot().is_some());
+}
+
+#[tokio::test]
+async fn peers_leaving_are_let_go() {
+    let ban_list = Arc::new(DashMap::new());
+    ban_list.insert(PEER, Instant::now() + Duration::from_secs(60));
+    let tarpit = Tarpit::new(1, INTERVAL, ban_list);
+    let (ours, theirs) = duplex(64);
+    drop(theirs);
+    let slot = tarpit.slot().unwrap();
+    timeout(
+        Duration::from_secs(1),
+        tarpit.clone().hold(ours, PEER, slot),
+    )
+    .await
+    .unwrap();
+}
@@ -0,0 +1,63 @@
+use tokio::time::Duration;
+use veloxid::protocol::ticket::{resume_hello, Redeemed, TicketState, Tickets, RESUME};
+
+const LIFETIME: Duration = Duration::from_secs(60);
+
+fn state() -> TicketState {
+    TicketState {
+        version: 3,
+        cipher: 1,
+        salt: [7; 12],
+        key: [9; 32],
+    }
+}
+
+// A ticket issued by `issuer` and held by a fresh outbound side, as its resume hello
+fn hello(issuer: &Tickets, state: TicketState) -> Vec<u8> {
+    let holder = Tickets::new(&[0; 32], LIFETIME);
+    holder.keep(&issuer.issue(&state), state);
+    resume_hello(&holder.take().unwrap()).to_vec()
+}
+
+#[test]
+fn tickets_are_taken_once() {
+    let issuer = Tickets::new(&[1; 32], LIFETIME);
+    let hello = hello(&issuer, state());
+    assert_eq!(hello[..4], RESUME);
+
+    let rest = hello[4..].try_into().unwrap();
+    assert_eq!(issuer.redeem(rest), Redeemed::Valid(state()));
+    assert_eq!(issuer.redeem(rest), Redeemed::Stale);
+}
+
+#[test]
+fn tickets_of_other_secrets_are_forged() {
+    let issuer = Tickets::new(&[1; 32], LIFETIME);
+    let other = Tickets::new(&[2; 32], LIFETIME);
+    let hello = hello(&other, state());
+    assert_eq!(
+        issuer.redeem(hello[4..].try_into().unwrap()),
+        Redeemed::Forged
+    );
+}
+
+#[test]
+fn resuming_takes_the_resumption_key() {
+    let issuer = Tickets::new(&[1; 32], LIFETIME);
+    let mut hello = hello(&issuer, state());
+    // The proof, last on the hello
+    *hello.last_mut().unwrap() ^= 1;
+    assert_eq!(
+        issuer.redeem(hello[4..].try_into().unwrap()),
+        Redeemed::Forged
+    );
+}
+
+#[test]
+fn held_tickets_are_used_once() {
+    let issuer = Tickets::new(&[1; 32], LIFETIME);
+    let holder = Tickets::new(&[1; 32], LIFETIME);
+    holder.keep(&issuer.issue(&state()), state());
+    assert!(holder.take().is_some());
+    assert!(holder.take().is_none());
+}
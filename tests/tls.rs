@@ -0,0 +1,216 @@
+#![cfg(feature = "tls")]
+
+use rcgen::{generate_simple_self_signed, CertifiedKey};
+use rustls::{
+    crypto::ring,
+    pki_types::{pem::PemObject, ServerName, SubjectPublicKeyInfoDer},
+    ClientConfig, RootCertStore,
+};
+use sha2::{Digest, Sha256};
+use std::{path::PathBuf, sync::Arc};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    task,
+};
+use tokio_rustls::TlsConnector;
+use veloxid::{
+    config::VeloxidConfig,
+    detect::{self, Accepted, DispatchRule},
+    relay::connection::{self, Connection},
+    transport::tls::{self, Origination, Termination},
+};
+
+async fn free_port() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+// A certificate for localhost in PEM files, and the client config trusting it
+fn certificate(name: &str) -> (PathBuf, PathBuf, Arc<ClientConfig>) {
+    let CertifiedKey { cert, signing_key } =
+        generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+    let dir = std::env::temp_dir().join(format!("veloxid-tls-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+    std::fs::write(&cert_path, cert.pem()).unwrap();
+    std::fs::write(&key_path, signing_key.serialize_pem()).unwrap();
+
+    let mut roots = RootCertStore::empty();
+    roots.add(cert.der().clone()).unwrap();
+    let client = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    (cert_path, key_path, Arc::new(client))
+}
+
+fn rule(alpn: &[&str]) -> DispatchRule {
+    DispatchRule {
+        alpn: alpn.iter().map(|p| p.to_string()).collect(),
+        ..Default::default()
+    }
+}
+
+// Connects over TLS offering these protocols, returns the one agreed on
+async fn connect(port: u16, client: &Arc<ClientConfig>, alpn: &[&str]) -> Option<String> {
+    let mut client = (**client).clone();
+    client.alpn_protocols = alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+    let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let name = ServerName::try_from("localhost").unwrap();
+    let mut stream = TlsConnector::from(Arc::new(client))
+        .connect(name, stream)
+        .await
+        .unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    stream.flush().await.unwrap();
+    let agreed = stream.get_ref().1.alpn_protocol();
+    agreed.map(|p| String::from_utf8_lossy(p).into_owned())
+}
+
+// What the route got, decrypted
+async fn received(queue: &mut mpsc::Receiver<Accepted>) -> Vec<u8> {
+    let (conn, _) = queue.recv().await.unwrap();
+    let Connection::Tls(mut stream) = conn else {
+        panic!("TLS wasn't terminated");
+    };
+    let mut data = [0u8; 5];
+    stream.read_exact(&mut data).await.unwrap();
+    data.to_vec()
+}
+
+#[tokio::test]
+async fn terminated_clients_are_routed_by_the_protocol_agreed_on() {
+    let (cert, key, client) = certificate("alpn");
+    let port = free_port().await;
+    let config: VeloxidConfig = toml::from_str(&format!(
+        r#"
+        [endpoints.shared]
+        host = "127.0.0.1"
+        port = {}
+        type = "auto"
+        direction = "inbound"
+        tls_cert = "{}"
+        tls_key = "{}"
+        "#,
+        port,
+        cert.display(),
+        key.display()
+    ))
+    .unwrap();
+    let data = connection::get_connection_data(&config.endpoints["shared"])
+        .await
+        .unwrap();
+
+    let (h2, mut h2_queue) = mpsc::channel(1);
+    let (ssh, mut ssh_queue) = mpsc::channel(1);
+    let (fallback, mut fallback_queue) = mpsc::channel(1);
+    let table = vec![
+        (rule(&["h2", "http/1.1"]), h2),
+        (rule(&["ssh"]), ssh),
+        (rule(&[]), fallback),
+    ];
+    task::spawn(detect::dispatch("shared".to_owned(), data, table));
+
+    // The route's own order wins over the client's
+    let agreed = connect(port, &client, &["http/1.1", "h2"]).await;
+    assert_eq!(agreed.as_deref(), Some("h2"));
+    assert_eq!(received(&mut h2_queue).await, b"hello");
+
+    let agreed = connect(port, &client, &["ssh"]).await;
+    assert_eq!(agreed.as_deref(), Some("ssh"));
+    assert_eq!(received(&mut ssh_queue).await, b"hello");
+
+    // Offering none the routes serve, the fallback takes it without ALPN
+    assert_eq!(connect(port, &client, &["imap"]).await, None);
+    assert_eq!(received(&mut fallback_queue).await, b"hello");
+
+    // Clients of other protocols are passed on as they are
+    let mut plain = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    plain.write_all(b"SSH-2.0-test\r\n").await.unwrap();
+    let (conn, _) = fallback_queue.recv().await.unwrap();
+    assert!(matches!(conn, Connection::Direct(_)));
+}
+
+// Both ends of a loopback TCP connection
+async fn socket_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let connecting = TcpStream::connect(listener.local_addr().unwrap());
+    let (accepted, connected) = tokio::join!(listener.accept(), connecting);
+    (accepted.unwrap().0, connected.unwrap())
+}
+
+#[tokio::test]
+async fn targets_are_checked_against_the_ca() {
+    let (cert, key, _) = certificate("origination");
+    let termination = Termination::load(&cert, &key).unwrap();
+
+    let (server, client) = socket_pair().await;
+    let acceptor = termination.acceptor(&[]);
+    let accepting = task::spawn(async move { tls::accept(&acceptor, server).await });
+    let origination = Origination::new("localhost", Some(&cert)).unwrap();
+    let mut client = origination.connect(client).await.unwrap();
+    let mut server = accepting.await.unwrap().unwrap();
+
+    client.write_all(b"hello").await.unwrap();
+    client.flush().await.unwrap();
+    let mut data = [0u8; 5];
+    server.read_exact(&mut data).await.unwrap();
+    assert_eq!(&data, b"hello");
+
+    // Leaving without a close_notify is the end of the stream
+    drop(server);
+    assert_eq!(client.read(&mut data).await.unwrap(), 0);
+
+    // Another name than the certificate's, or a CA that didn't sign it
+    let (server, client) = socket_pair().await;
+    let acceptor = termination.acceptor(&[]);
+    task::spawn(async move { tls::accept(&acceptor, server).await });
+    let origination = Origination::new("example.com", Some(&cert)).unwrap();
+    assert!(origination.connect(client).await.is_err());
+
+    let (other, _, _) = certificate("other-ca");
+    let (server, client) = socket_pair().await;
+    let acceptor = termination.acceptor(&[]);
+    task::spawn(async move { tls::accept(&acceptor, server).await });
+    let origination = Origination::new("localhost", Some(&other)).unwrap();
+    assert!(origination.connect(client).await.is_err());
+}
+
+#[tokio::test]
+async fn pinned_targets_are_taken_by_their_key() {
+    let CertifiedKey { cert, signing_key } =
+        generate_simple_self_signed(vec!["relay.internal".to_owned()]).unwrap();
+    let dir = std::env::temp_dir().join(format!("veloxid-tls-pinned-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+    std::fs::write(&cert_path, cert.pem()).unwrap();
+    std::fs::write(&key_path, signing_key.serialize_pem()).unwrap();
+    let termination = Termination::load(&cert_path, &key_path).unwrap();
+    let spki = SubjectPublicKeyInfoDer::from_pem_slice(signing_key.public_key_pem().as_bytes());
+    let pin: [u8; 32] = Sha256::digest(spki.unwrap()).into();
+
+    // Self-signed and for another name, the key is what counts
+    let (server, client) = socket_pair().await;
+    let acceptor = termination.acceptor(&[]);
+    let accepting = task::spawn(async move { tls::accept(&acceptor, server).await });
+    let origination = Origination::pinned("localhost", pin).unwrap();
+    let mut client = origination.connect(client).await.unwrap();
+    let mut server = accepting.await.unwrap().unwrap();
+    client.write_all(b"hello").await.unwrap();
+    client.flush().await.unwrap();
+    let mut data = [0u8; 5];
+    server.read_exact(&mut data).await.unwrap();
+    assert_eq!(&data, b"hello");
+
+    // Any other key is refused
+    let (server, client) = socket_pair().await;
+    let acceptor = termination.acceptor(&[]);
+    task::spawn(async move { tls::accept(&acceptor, server).await });
+    let mut other = pin;
+    other[0] ^= 0x01;
+    let origination = Origination::pinned("localhost", other).unwrap();
+    assert!(origination.connect(client).await.is_err());
+}
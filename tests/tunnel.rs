@@ -0,0 +1,682 @@
+mod common;
+
+use chacha20::{
+    cipher::{KeyIvInit, StreamCipher},
+    ChaCha20,
+};
+use common::{
+    handshake, read_to_end, secret, session, write_and_close, Handshake, PEER, PIPE_SIZE,
+};
+use tokio::{
+    io::{duplex, AsyncReadExt, AsyncWriteExt},
+    task,
+    time::{timeout, Duration, Instant},
+};
+use veloxid::{
+    config::CipherKind,
+    error::TunnelError,
+    latency::LatencyTable,
+    protocol::{
+        cipher::RekeyLimits,
+        handshake::{CONTROL_ATTACH, REASON_BANNED, REASON_UNKNOWN_SERVICE},
+        silent::Silence,
+        ticket::Tickets,
+    },
+    relay::tunnel::{HandshakeOptions, HandshakeTimeouts, Registration, SessionOptions, Tunnel},
+};
+
+#[tokio::test]
+async fn handshake_succeeds_with_matching_secrets() {
+    let handshake = handshake("1234", "1234").await;
+    assert!(handshake.inbound.is_ok());
+}
+
+#[tokio::test]
+async fn secret_mismatch_is_reported_on_both_sides() {
+    let handshake = handshake("1234", "4321").await;
+
+    let inbound_err = handshake.inbound.err().unwrap();
+    assert!(matches!(
+        inbound_err.downcast_ref::<TunnelError>(),
+        Some(TunnelError::SecretMismatch(ip)) if *ip == PEER
+    ));
+
+    let outbound_err = handshake.outbound.await.unwrap().err().unwrap();
+    assert!(matches!(
+        outbound_err.downcast_ref::<TunnelError>(),
+        Some(TunnelError::SecretRejected)
+    ));
+}
+
+#[tokio::test]
+async fn rejection_reason_reaches_the_outbound_side() {
+    let (inbound_stream, outbound_stream) = duplex(PIPE_SIZE);
+
+    let outbound = task::spawn(Tunnel::init(outbound_stream, PEER, false, secret("1234")));
+    Tunnel::reject(inbound_stream, REASON_BANNED, Default::default())
+        .await
+        .unwrap();
+
+    let outbound_err = outbound.await.unwrap().err().unwrap();
+    assert!(matches!(
+        outbound_err.downcast_ref::<TunnelError>(),
+        Some(TunnelError::RejectedBanned)
+    ));
+}
+
+#[tokio::test]
+async fn silent_peers_time_out_as_configured() {
+    let timeouts = HandshakeTimeouts {
+        auth: Duration::from_millis(100),
+        nonce: Duration::from_millis(200),
+        ..Default::default()
+    };
+    let handshake = |is_inbound| {
+        // Kept open, but nothing is ever sent on it
+        let (stream, peer_stream) = duplex(PIPE_SIZE);
+        async move {
+            let started = Instant::now();
+            let result = Tunnel::init_with_timeouts(
+                stream,
+                PEER,
+                is_inbound,
+                secret("1234"),
+                CipherKind::ChaCha20,
+                timeouts,
+            )
+            .await;
+            drop(peer_stream);
+            (result.err().unwrap(), started.elapsed())
+        }
+    };
+
+    let (error, waited) = handshake(true).await;
+    assert!(matches!(
+        error.downcast_ref(),
+        Some(TunnelError::Timeout(PEER))
+    ));
+    assert!(waited >= timeouts.auth && waited < timeouts.nonce);
+
+    let (error, waited) = handshake(false).await;
+    assert!(matches!(
+        error.downcast_ref(),
+        Some(TunnelError::Timeout(PEER))
+    ));
+    assert!(waited >= timeouts.nonce && waited < timeouts.total);
+}
+
+#[tokio::test]
+async fn rejected_peers_time_out_as_configured() {
+    // A peer that never sends its auth token
+    let (stream, _peer) = duplex(PIPE_SIZE);
+    let timeouts = HandshakeTimeouts {
+        auth: Duration::from_millis(100),
+        ..Default::default()
+    };
+    let started = Instant::now();
+    assert!(Tunnel::reject(stream, REASON_BANNED, timeouts)
+        .await
+        .is_err());
+    assert!(started.elapsed() < Duration::from_secs(1));
+
+    // Nor one that doesn't even take the nonce, it gets the handshake's time and no more
+    let (stream, _peer) = duplex(4);
+    let timeouts = HandshakeTimeouts {
+        total: Duration::from_millis(100),
+        ..Default::default()
+    };
+    let started = Instant::now();
+    assert!(Tunnel::reject(stream, REASON_BANNED, timeouts)
+        .await
+        .is_err());
+    assert!(started.elapsed() < Duration::from_secs(1));
+}
+
+#[tokio::test]
+async fn version_1_outbound_is_still_accepted() {
+    let (inbound_stream, outbound_stream) = duplex(PIPE_SIZE);
+    let key = secret("1234");
+
+    let outbound = task::spawn(Tunnel::init_with_version(
+        outbound_stream,
+        PEER,
+        false,
+        key,
+        1,
+    ));
+    let inbound = Tunnel::init(inbound_stream, PEER, true, key).await.unwrap();
+
+    let (_client, relay_side) = duplex(PIPE_SIZE);
+    task::spawn(inbound.run(relay_side, SessionOptions::default()));
+    assert!(outbound.await.unwrap().is_ok());
+}
+
+#[tokio::test]
+async fn data_flows_both_ways_through_the_tunnel() {
+    let mut session = session("1234").await;
+
+    session.client.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    session.server.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+
+    session.server.write_all(b"pong").await.unwrap();
+    session.client.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"pong");
+}
+
+#[tokio::test]
+async fn eof_propagates_per_direction() {
+    let mut session = session("1234").await;
+    let request = vec![42u8; 100_000];
+    let response = vec![7u8; 50_000];
+
+    // The client half-closes, the server still answers afterwards
+    write_and_close(&mut session.client, &request).await;
+    assert_eq!(read_to_end(&mut session.server).await, request);
+    write_and_close(&mut session.server, &response).await;
+    assert_eq!(read_to_end(&mut session.client).await, response);
+
+    let relay = session.relay.await.unwrap().unwrap();
+    assert_eq!(relay.a_to_b, response.len() as u64);
+    assert_eq!(relay.b_to_a, request.len() as u64);
+
+    let connector = session.connector.await.unwrap().unwrap();
+    assert_eq!(connector.a_to_b, request.len() as u64);
+    assert_eq!(connector.b_to_a, response.len() as u64);
+}
+
+#[tokio::test]
+async fn payload_is_encrypted_on_the_wire() {
+    let (inbound_stream, mut peer) = duplex(PIPE_SIZE);
+    let key = secret("1234");
+
+    // Act as the outbound side by hand
+    let inbound = task::spawn(Tunnel::init(inbound_stream, PEER, true, key));
+    let mut nonce = [0u8; 12];
+    peer.read_exact(&mut nonce).await.unwrap();
+    let mut cipher = ChaCha20::new(&key.into(), &nonce.into());
+    let mut auth = *b"AUTH";
+    cipher.apply_keystream(&mut auth);
+    peer.write_all(&auth).await.unwrap();
+
+    let (mut client, relay_side) = duplex(PIPE_SIZE);
+    let tunnel = inbound.await.unwrap().unwrap();
+    task::spawn(tunnel.run(relay_side, SessionOptions::default()));
+
+    assert_eq!(peer.read_u8().await.unwrap(), 1u8);
+    client.write_all(b"plaintext").await.unwrap();
+    let mut wire = [0u8; 9];
+    peer.read_exact(&mut wire).await.unwrap();
+    assert_ne!(&wire, b"plaintext");
+
+    ChaCha20::new(&key.into(), &nonce.into()).apply_keystream(&mut wire);
+    assert_eq!(&wire, b"plaintext");
+}
+
+#[tokio::test]
+async fn joined_tunnels_reencrypt_between_secrets() {
+    let Handshake {
+        inbound: left_in,
+        outbound: left_out,
+    } = handshake("left", "left").await;
+    let Handshake {
+        inbound: right_in,
+        outbound: right_out,
+    } = handshake("right", "right").await;
+
+    let (mut client, left_side) = duplex(PIPE_SIZE);
+    let (mut server, right_side) = duplex(PIPE_SIZE);
+    task::spawn(
+        left_in
+            .unwrap()
+            .join(right_in.unwrap(), SessionOptions::default()),
+    );
+    task::spawn(async move {
+        left_out
+            .await??
+            .run(left_side, SessionOptions::default())
+            .await
+    });
+    task::spawn(async move {
+        right_out
+            .await??
+            .run(right_side, SessionOptions::default())
+            .await
+    });
+
+    write_and_close(&mut client, b"through two hops").await;
+    assert_eq!(read_to_end(&mut server).await, b"through two hops");
+}
+
+#[tokio::test]
+async fn blind_relays_forward_end_to_end_sessions() {
+    let Handshake {
+        inbound: left_in,
+        outbound: left_out,
+    } = handshake("left", "left").await;
+    let Handshake {
+        inbound: right_in,
+        outbound: right_out,
+    } = handshake("right", "right").await;
+
+    let (mut client, left_side) = duplex(PIPE_SIZE);
+    let (mut server, right_side) = duplex(PIPE_SIZE);
+    let blind = SessionOptions {
+        blind: true,
+        ..Default::default()
+    };
+    task::spawn(left_in.unwrap().join(right_in.unwrap(), blind));
+    for (tunnel, side) in [(left_out, left_side), (right_out, right_side)] {
+        task::spawn(async move {
+            tunnel
+                .await??
+                .end_to_end(secret("inner"))
+                .run(side, SessionOptions::default())
+                .await
+        });
+    }
+
+    write_and_close(&mut client, b"through a blind relay").await;
+    assert_eq!(read_to_end(&mut server).await, b"through a blind relay");
+}
+
+#[tokio::test]
+async fn end_to_end_sessions_are_unreadable_to_the_peer() {
+    let Handshake { inbound, outbound } = handshake("1234", "1234").await;
+
+    // The peer decrypts what it can, as a relay joining the tunnel to another one would
+    let (mut relay, relay_side) = duplex(PIPE_SIZE);
+    let (mut client, client_side) = duplex(PIPE_SIZE);
+    task::spawn(inbound.unwrap().run(relay_side, SessionOptions::default()));
+    task::spawn(async move {
+        outbound
+            .await??
+            .end_to_end(secret("inner"))
+            .run(client_side, SessionOptions::default())
+            .await
+    });
+
+    // Stands in for the far end's nonce
+    write_and_close(&mut relay, &[0u8; 24]).await;
+    write_and_close(&mut client, b"for the far end only").await;
+    let seen = read_to_end(&mut relay).await;
+    // The nonce, then the session
+    assert_eq!(seen.len(), 24 + 20);
+    assert!(!seen.windows(8).any(|w| w == b"far end "));
+}
+
+#[tokio::test]
+async fn end_to_end_tunnels_are_not_joined() {
+    let left = handshake("left", "left").await;
+    let right = handshake("right", "right").await;
+
+    let error = left
+        .inbound
+        .unwrap()
+        .end_to_end(secret("inner"))
+        .join(right.inbound.unwrap(), SessionOptions::default())
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        error.downcast_ref(),
+        Some(TunnelError::EndToEndJoined)
+    ));
+}
+
+#[tokio::test]
+async fn rekeyed_sessions_flow_both_ways() {
+    let Handshake { inbound, outbound } = handshake("1234", "1234").await;
+
+    let (mut client, relay_side) = duplex(PIPE_SIZE);
+    let (mut server, connector_side) = duplex(PIPE_SIZE);
+    let options = SessionOptions::default();
+    task::spawn(inbound.unwrap().rekey().run(relay_side, options.clone()));
+    task::spawn(async move { outbound.await??.rekey().run(connector_side, options).await });
+
+    client.write_all(b"request").await.unwrap();
+    let mut request = [0u8; 7];
+    server.read_exact(&mut request).await.unwrap();
+    assert_eq!(&request, b"request");
+    write_and_close(&mut server, b"response").await;
+    client.shutdown().await.unwrap();
+    assert_eq!(read_to_end(&mut client).await, b"response");
+}
+
+#[tokio::test]
+async fn sessions_replace_their_keys_as_they_run() {
+    let Handshake { inbound, outbound } = handshake("1234", "1234").await;
+    let limits = RekeyLimits {
+        bytes: Some(3),
+        interval: None,
+    };
+
+    let (mut client, relay_side) = duplex(PIPE_SIZE);
+    let (mut server, connector_side) = duplex(PIPE_SIZE);
+    let options = SessionOptions::default();
+    let inbound = inbound.unwrap().rekey_limits(limits);
+    task::spawn(inbound.run(relay_side, options.clone()));
+    task::spawn(async move {
+        let outbound = outbound.await??.rekey_limits(limits);
+        outbound.run(connector_side, options).await
+    });
+
+    client.write_all(b"request").await.unwrap();
+    let mut request = [0u8; 7];
+    server.read_exact(&mut request).await.unwrap();
+    assert_eq!(&request, b"request");
+    write_and_close(&mut server, b"response").await;
+    client.shutdown().await.unwrap();
+    assert_eq!(read_to_end(&mut client).await, b"response");
+}
+
+#[tokio::test]
+async fn rekeyed_sessions_leave_the_handshake_keystream() {
+    let (inbound_stream, mut peer) = duplex(PIPE_SIZE);
+    let key = secret("1234");
+
+    // Act as the outbound side by hand
+    let inbound = task::spawn(Tunnel::init(inbound_stream, PEER, true, key));
+    let mut nonce = [0u8; 12];
+    peer.read_exact(&mut nonce).await.unwrap();
+    let mut auth = *b"AUT2";
+    ChaCha20::new(&key.into(), &nonce.into()).apply_keystream(&mut auth);
+    peer.write_all(&auth).await.unwrap();
+
+    let (mut client, relay_side) = duplex(PIPE_SIZE);
+    let tunnel = inbound.await.unwrap().unwrap().rekey();
+    task::spawn(tunnel.run(relay_side, SessionOptions::default()));
+
+    let mut attach = [0u8; 14];
+    peer.read_exact(&mut attach).await.unwrap();
+    assert_eq!(attach[..2], [CONTROL_ATTACH, 12]);
+    assert_ne!(attach[2..], nonce);
+
+    client.write_all(b"plaintext").await.unwrap();
+    let mut wire = [0u8; 9];
+    peer.read_exact(&mut wire).await.unwrap();
+    ChaCha20::new(&key.into(), &nonce.into()).apply_keystream(&mut wire);
+    assert_ne!(&wire, b"plaintext");
+}
+
+#[tokio::test]
+async fn rekeying_needs_both_sides() {
+    for inbound_rekeys in [true, false] {
+        let Handshake { inbound, outbound } = handshake("1234", "1234").await;
+        let inbound = inbound.unwrap();
+        let inbound = match inbound_rekeys {
+            true => inbound.rekey(),
+            false => inbound,
+        };
+        let (_client, relay_side) = duplex(PIPE_SIZE);
+        task::spawn(inbound.run(relay_side, SessionOptions::default()));
+
+        let outbound = outbound.await.unwrap().unwrap();
+        let outbound = match inbound_rekeys {
+            true => outbound,
+            false => outbound.rekey(),
+        };
+        let (_server, connector_side) = duplex(PIPE_SIZE);
+        let error = outbound
+            .run(connector_side, SessionOptions::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(TunnelError::RekeyMismatch)
+        ));
+    }
+}
+
+#[tokio::test]
+async fn tickets_let_reconnects_skip_the_nonce() {
+    let key = secret("1234");
+    let inbound_tickets = Tickets::new(&key, Duration::from_secs(60));
+    let outbound_tickets = Tickets::new(&key, Duration::from_secs(60));
+    let init = |stream, is_inbound, tickets: &Tickets| {
+        let tickets = tickets.clone();
+        async move {
+            let (cipher, timeouts) = (CipherKind::ChaCha20, HandshakeTimeouts::default());
+            let options = HandshakeOptions {
+                tickets: Some(&tickets),
+                ..Default::default()
+            };
+            Tunnel::init_with_options(stream, PEER, is_inbound, key, cipher, timeouts, options)
+                .await
+        }
+    };
+
+    // Full handshakes, each hands out a ticket
+    for _ in 0..2 {
+        let (inbound_stream, outbound_stream) = duplex(PIPE_SIZE);
+        let outbound = task::spawn(init(outbound_stream, false, &outbound_tickets));
+        let inbound = init(inbound_stream, true, &inbound_tickets).await.unwrap();
+        let (_client, relay_side) = duplex(PIPE_SIZE);
+        task::spawn(inbound.run(relay_side, SessionOptions::default()));
+        outbound.await.unwrap().unwrap();
+    }
+
+    // The resuming side speaks first
+    let (mut peer, outbound_stream) = duplex(PIPE_SIZE);
+    task::spawn(init(outbound_stream, false, &outbound_tickets));
+    let mut marker = [0u8; 4];
+    peer.read_exact(&mut marker).await.unwrap();
+    assert_eq!(&marker, b"VXRS");
+
+    // And resumes with the other ticket
+    let (inbound_stream, outbound_stream) = duplex(PIPE_SIZE);
+    let outbound = task::spawn(init(outbound_stream, false, &outbound_tickets));
+    let inbound = init(inbound_stream, true, &inbound_tickets).await.unwrap();
+    let (mut client, relay_side) = duplex(PIPE_SIZE);
+    let (mut server, connector_side) = duplex(PIPE_SIZE);
+    task::spawn(inbound.run(relay_side, SessionOptions::default()));
+    let outbound = outbound.await.unwrap().unwrap();
+    task::spawn(outbound.run(connector_side, SessionOptions::default()));
+
+    write_and_close(&mut client, b"resumed").await;
+    assert_eq!(read_to_end(&mut server).await, b"resumed");
+}
+
+#[tokio::test]
+async fn silent_sides_say_nothing_to_strangers() {
+    let key = secret("1234");
+    let silence = Silence::new(&key);
+    let init = |stream, is_inbound, silence: &Silence| {
+        let silence = silence.clone();
+        async move {
+            let (cipher, timeouts) = (CipherKind::ChaCha20, HandshakeTimeouts::default());
+            let options = HandshakeOptions {
+                silence: Some(&silence),
+                ..Default::default()
+            };
+            Tunnel::init_with_options(stream, PEER, is_inbound, key, cipher, timeouts, options)
+                .await
+        }
+    };
+
+    // Peers with the secret get through
+    let (inbound_stream, outbound_stream) = duplex(PIPE_SIZE);
+    let outbound = task::spawn(init(outbound_stream, false, &silence));
+    let inbound = init(inbound_stream, true, &silence).await.unwrap();
+    let (mut client, relay_side) = duplex(PIPE_SIZE);
+    let (mut server, connector_side) = duplex(PIPE_SIZE);
+    task::spawn(inbound.run(relay_side, SessionOptions::default()));
+    let outbound = outbound.await.unwrap().unwrap();
+    task::spawn(outbound.run(connector_side, SessionOptions::default()));
+    write_and_close(&mut client, b"silent").await;
+    assert_eq!(read_to_end(&mut server).await, b"silent");
+
+    // Others don't even get the nonce, the connection is closed
+    let (inbound_stream, mut stranger) = duplex(PIPE_SIZE);
+    let inbound = task::spawn(init(inbound_stream, true, &silence));
+    stranger.write_all(&[0x42; 40]).await.unwrap();
+    let mut received = Vec::new();
+    stranger.read_to_end(&mut received).await.unwrap();
+    assert!(received.is_empty());
+    let error = inbound.await.unwrap().err().unwrap();
+    assert!(matches!(
+        error.downcast_ref(),
+        Some(TunnelError::SecretMismatch(ip)) if *ip == PEER
+    ));
+}
+
+#[tokio::test]
+async fn heartbeats_time_the_peer_while_it_waits() {
+    let Handshake { inbound, outbound } = handshake("1234", "1234").await;
+    let latency = LatencyTable::default();
+    let mut inbound = inbound
+        .unwrap()
+        .heartbeat(Duration::from_millis(20))
+        .latency(latency.track("route", "A", None));
+    assert!(inbound.rtt().is_some());
+
+    // Still waiting to be attached when the wait is given up
+    assert!(timeout(Duration::from_millis(200), inbound.watch())
+        .await
+        .is_err());
+    assert!(latency.list()[0].samples > 1);
+
+    // Pongs left on the wire don't reach the session
+    let (mut client, relay_side) = duplex(PIPE_SIZE);
+    let (mut server, connector_side) = duplex(PIPE_SIZE);
+    task::spawn(inbound.run(relay_side, SessionOptions::default()));
+    task::spawn(async move {
+        outbound
+            .await??
+            .run(connector_side, SessionOptions::default())
+            .await
+    });
+    client.write_all(b"request").await.unwrap();
+    let mut request = [0u8; 7];
+    server.read_exact(&mut request).await.unwrap();
+    assert_eq!(&request, b"request");
+    write_and_close(&mut server, b"response").await;
+    client.shutdown().await.unwrap();
+    assert_eq!(read_to_end(&mut client).await, b"response");
+}
+
+#[tokio::test]
+async fn heartbeats_end_with_the_peer() {
+    let Handshake { inbound, outbound } = handshake("1234", "1234").await;
+    let mut inbound = inbound.unwrap().heartbeat(Duration::from_millis(20));
+    outbound.abort();
+    timeout(Duration::from_secs(1), inbound.watch())
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+// Registering handshake of each side over a pipe, the outbound one in the background
+async fn register(
+    service: &str,
+) -> (
+    anyhow::Result<Tunnel<tokio::io::DuplexStream>>,
+    task::JoinHandle<anyhow::Result<Tunnel<tokio::io::DuplexStream>>>,
+) {
+    let key = secret("1234");
+    let (cipher, timeouts) = (CipherKind::ChaCha20, HandshakeTimeouts::default());
+    let (inbound_stream, outbound_stream) = duplex(PIPE_SIZE);
+    let announce = Registration::Announce(service.to_owned());
+    let outbound = task::spawn(async move {
+        let options = HandshakeOptions {
+            registration: Some(&announce),
+            ..Default::default()
+        };
+        Tunnel::init_with_options(outbound_stream, PEER, false, key, cipher, timeouts, options)
+            .await
+    });
+    let options = HandshakeOptions {
+        registration: Some(&Registration::Expect),
+        ..Default::default()
+    };
+    let inbound =
+        Tunnel::init_with_options(inbound_stream, PEER, true, key, cipher, timeouts, options).await;
+    (inbound, outbound)
+}
+
+#[tokio::test]
+async fn registered_tunnels_carry_their_service() {
+    let (inbound, outbound) = register("ssh").await;
+    let inbound = inbound.unwrap();
+    assert_eq!(inbound.service(), Some("ssh"));
+
+    let (mut client, relay_side) = duplex(PIPE_SIZE);
+    let (mut server, connector_side) = duplex(PIPE_SIZE);
+    task::spawn(inbound.run(relay_side, SessionOptions::default()));
+    let outbound = outbound.await.unwrap().unwrap();
+    assert_eq!(outbound.service(), None);
+    task::spawn(outbound.run(connector_side, SessionOptions::default()));
+
+    write_and_close(&mut client, b"registered").await;
+    assert_eq!(read_to_end(&mut server).await, b"registered");
+}
+
+#[tokio::test]
+async fn unknown_services_are_turned_away() {
+    let (inbound, outbound) = register("ftp").await;
+    inbound
+        .unwrap()
+        .turn_away(REASON_UNKNOWN_SERVICE)
+        .await
+        .unwrap();
+    let error = outbound.await.unwrap().err().unwrap();
+    assert!(matches!(
+        error.downcast_ref(),
+        Some(TunnelError::RejectedUnknownService)
+    ));
+
+    // Nameless announcements don't make it past the handshake
+    let (inbound, outbound) = register("").await;
+    assert!(matches!(
+        inbound.err().unwrap().downcast_ref(),
+        Some(TunnelError::UnknownService(_))
+    ));
+    assert!(matches!(
+        outbound.await.unwrap().err().unwrap().downcast_ref(),
+        Some(TunnelError::RejectedUnknownService)
+    ));
+}
+
+#[tokio::test]
+async fn both_sides_export_the_same_keying_material() {
+    let Handshake { inbound, outbound } = handshake("1234", "1234").await;
+    let inbound = inbound.unwrap();
+
+    let exported = inbound.export_keying_material(b"app auth", 32).unwrap();
+    assert_eq!(exported.len(), 32);
+    assert_ne!(
+        inbound.export_keying_material(b"other", 32).unwrap(),
+        exported
+    );
+    // Longer exports extend shorter ones under the same label
+    assert_eq!(
+        inbound.export_keying_material(b"app auth", 64).unwrap()[..32],
+        exported[..]
+    );
+    let error = inbound
+        .export_keying_material(b"app auth", 255 * 32 + 1)
+        .unwrap_err();
+    assert!(matches!(
+        error.downcast_ref::<TunnelError>(),
+        Some(TunnelError::ExportTooLong)
+    ));
+
+    // The outbound side's handshake ends with ATTACH
+    let (_client, relay_side) = duplex(PIPE_SIZE);
+    task::spawn(inbound.run(relay_side, SessionOptions::default()));
+    let outbound = outbound.await.unwrap().unwrap();
+    assert_eq!(
+        outbound.export_keying_material(b"app auth", 32).unwrap(),
+        exported
+    );
+
+    // Sessions with the same secret get their own
+    let Handshake { inbound: other, .. } = handshake("1234", "1234").await;
+    assert_ne!(
+        other
+            .unwrap()
+            .export_keying_material(b"app auth", 32)
+            .unwrap(),
+        exported
+    );
+}
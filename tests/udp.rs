@@ -0,0 +1,94 @@
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UdpSocket,
+    task,
+    time::Duration,
+};
+use veloxid::transport::udp::{self, UdpFlow};
+
+// Datagrams on the stream of a session, after their length
+fn frame(datagram: &[u8]) -> Vec<u8> {
+    let mut frame = (datagram.len() as u16).to_be_bytes().to_vec();
+    frame.extend_from_slice(datagram);
+    frame
+}
+
+async fn read_frame(flow: &mut UdpFlow) -> Vec<u8> {
+    let mut len = [0u8; 2];
+    flow.read_exact(&mut len).await.unwrap();
+    let mut datagram = vec![0u8; u16::from_be_bytes(len) as usize];
+    flow.read_exact(&mut datagram).await.unwrap();
+    datagram
+}
+
+// Answers every datagram with the same one, prefixed
+async fn echo_server() -> std::net::SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    task::spawn(async move {
+        let mut buffer = vec![0u8; 65535];
+        while let Ok((len, peer)) = socket.recv_from(&mut buffer).await {
+            let reply = [b"echo:", &buffer[..len]].concat();
+            socket.send_to(&reply, peer).await.unwrap();
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn outbound_flows_carry_datagrams_to_the_target() {
+    let target = echo_server().await;
+    let mut flow = UdpFlow::connect(target, Duration::from_secs(5))
+        .await
+        .unwrap();
+
+    // Frames may be written in pieces, each whole one is a datagram
+    let frames = [frame(b"one"), frame(b"two")].concat();
+    flow.write_all(&frames[..4]).await.unwrap();
+    flow.write_all(&frames[4..]).await.unwrap();
+    assert_eq!(read_frame(&mut flow).await, b"echo:one");
+    assert_eq!(read_frame(&mut flow).await, b"echo:two");
+
+    let large = vec![7u8; 60000];
+    flow.write_all(&frame(&large)).await.unwrap();
+    assert_eq!(read_frame(&mut flow).await, [b"echo:", &large[..]].concat());
+}
+
+#[tokio::test]
+async fn inbound_flows_are_kept_apart_by_client_address() {
+    let addr = std::net::UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let flows = udp::listen(addr, Duration::from_millis(500)).await.unwrap();
+    let (first, second) = (
+        UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+        UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+    );
+    first.send_to(b"one", addr).await.unwrap();
+    second.send_to(b"two", addr).await.unwrap();
+
+    let mut flow = flows.lock().await.recv().await.unwrap();
+    assert_eq!(flow.peer_addr(), first.local_addr().unwrap());
+    assert_eq!(read_frame(&mut flow).await, b"one");
+    let mut other = flows.lock().await.recv().await.unwrap();
+    assert_eq!(other.peer_addr(), second.local_addr().unwrap());
+    assert_eq!(read_frame(&mut other).await, b"two");
+
+    // Later datagrams of a client go to its flow, replies go back to that client only
+    first.send_to(b"three", addr).await.unwrap();
+    assert_eq!(read_frame(&mut flow).await, b"three");
+    flow.write_all(&frame(b"reply")).await.unwrap();
+    let mut buffer = [0u8; 16];
+    let (len, from) = first.recv_from(&mut buffer).await.unwrap();
+    assert_eq!((&buffer[..len], from), (&b"reply"[..], addr));
+
+    // Idle flows end, and a client sending again starts a new one
+    let mut rest = Vec::new();
+    flow.read_to_end(&mut rest).await.unwrap();
+    assert!(rest.is_empty());
+    drop(flow);
+    first.send_to(b"four", addr).await.unwrap();
+    let mut flow = flows.lock().await.recv().await.unwrap();
+    assert_eq!(read_frame(&mut flow).await, b"four");
+}
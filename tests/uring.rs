@@ -0,0 +1,82 @@
+#![cfg(all(target_os = "linux", feature = "io-uring"))]
+
+use std::sync::atomic::Ordering;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task,
+    time::{sleep, timeout, Duration},
+};
+use veloxid::relay::tunnel::{LiveTraffic, SessionOptions, Tunnel};
+
+// Both ends of a loopback TCP connection
+async fn socket_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let connecting = TcpStream::connect(listener.local_addr().unwrap());
+    let (accepted, connected) = tokio::join!(listener.accept(), connecting);
+    (accepted.unwrap().0, connected.unwrap())
+}
+
+#[tokio::test]
+async fn plain_tcp_session_is_copied_both_ways() {
+    let (mut client, a) = socket_pair().await;
+    let (b, mut server) = socket_pair().await;
+    let session = task::spawn(Tunnel::proxy(a, b, SessionOptions::default()));
+
+    let request: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let writer = task::spawn({
+        let request = request.clone();
+        async move {
+            client.write_all(&request).await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut reply = Vec::new();
+            client.read_to_end(&mut reply).await.unwrap();
+            reply
+        }
+    });
+
+    let mut received = Vec::new();
+    server.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, request);
+    server.write_all(b"done").await.unwrap();
+    server.shutdown().await.unwrap();
+
+    assert_eq!(writer.await.unwrap(), b"done");
+    let traffic = session.await.unwrap().unwrap();
+    assert_eq!(traffic.a_to_b, request.len() as u64);
+    assert_eq!(traffic.b_to_a, 4);
+}
+
+#[tokio::test]
+async fn offloaded_sessions_are_counted_and_dropped() {
+    let (mut client, a) = socket_pair().await;
+    let (b, mut server) = socket_pair().await;
+    let traffic = LiveTraffic::default();
+    let options = SessionOptions::default().traffic(traffic.clone());
+    let session = task::spawn(Tunnel::proxy(a, b, options));
+
+    client.write_all(b"hello").await.unwrap();
+    let mut received = [0u8; 5];
+    server.read_exact(&mut received).await.unwrap();
+    // Counted on the ring's thread once the write completes
+    let counted = async {
+        while traffic.a_to_b.load(Ordering::Relaxed) != 5 {
+            sleep(Duration::from_millis(10)).await;
+        }
+    };
+    timeout(Duration::from_secs(1), counted)
+        .await
+        .expect("traffic wasn't counted");
+
+    // As a killed session is, the ring lets go of both sockets
+    session.abort();
+    let mut rest = Vec::new();
+    timeout(Duration::from_secs(1), client.read_to_end(&mut rest))
+        .await
+        .expect("client side left open")
+        .unwrap();
+    timeout(Duration::from_secs(1), server.read_to_end(&mut rest))
+        .await
+        .expect("server side left open")
+        .unwrap();
+}
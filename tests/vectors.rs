@@ -0,0 +1,70 @@
+mod common;
+
+use common::{PEER, PIPE_SIZE};
+use tokio::{
+    io::{duplex, AsyncReadExt, AsyncWriteExt},
+    task,
+};
+use veloxid::{
+    interop,
+    protocol::vectors::{self, unhex, Vector},
+    relay::tunnel::{SessionOptions, Tunnel},
+};
+
+fn vector(name: &str) -> Vector {
+    let vectors = vectors::generate().unwrap();
+    vectors.into_iter().find(|v| v.name == name).unwrap()
+}
+
+#[test]
+fn the_published_vectors_are_the_protocols() {
+    let published: Vec<Vector> =
+        serde_json::from_str(include_str!("../vectors/wire.json")).unwrap();
+    assert_eq!(published, vectors::generate().unwrap());
+    for vector in &published {
+        vector.check().unwrap();
+    }
+
+    // A vector that doesn't hold is told apart
+    let mut broken = vector("v3-xchacha20");
+    broken.outbound_wire.replace_range(..2, "00");
+    let error = broken.check().unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "'v3-xchacha20': the outbound side's session differs from the vector's"
+    );
+}
+
+#[tokio::test]
+async fn interop_sides_play_the_vectors_against_each_other() {
+    for vector in vectors::generate().unwrap() {
+        let (inbound, outbound) = duplex(PIPE_SIZE);
+        let peer = vector.clone();
+        let inbound = task::spawn(async move { interop::inbound(inbound, &peer).await });
+        interop::outbound(outbound, &vector).await.unwrap();
+        inbound.await.unwrap().unwrap();
+    }
+}
+
+#[tokio::test]
+async fn tunnels_speak_the_vectors() {
+    // The inbound side's randomness is the vector's, the outbound side's a tunnel's own
+    let vector = vector("v2-chacha20");
+    let (inbound, outbound) = duplex(PIPE_SIZE);
+    let peer = vector.clone();
+    let inbound = task::spawn(async move { interop::inbound(inbound, &peer).await });
+
+    let tunnel = Tunnel::init(outbound, PEER, false, vector.secret())
+        .await
+        .unwrap();
+    let (mut local, session) = duplex(PIPE_SIZE);
+    let session = task::spawn(tunnel.run(session, SessionOptions::default()));
+    let plaintext = unhex(&vector.plaintext).unwrap();
+    local.write_all(&plaintext).await.unwrap();
+    local.shutdown().await.unwrap();
+    let mut received = Vec::new();
+    local.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, plaintext);
+    inbound.await.unwrap().unwrap();
+    session.await.unwrap().unwrap();
+}